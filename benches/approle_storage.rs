@@ -0,0 +1,249 @@
+//! Benchmarks of the approle secret_id storage path, run against an in-memory physical backend
+//! behind a real barrier, so encryption is part of every measurement.
+//!
+//! The inputs are deterministic, so results can be compared across commits with criterion's
+//! baselines:
+//!
+//! ```text
+//! cargo bench --bench approle_storage -- --save-baseline main
+//! cargo bench --bench approle_storage -- --baseline main
+//! ```
+//!
+//! `flush_role_secrets_round_trips` runs the flush strategies against a simulated remote backend
+//! and prints the number of round trips each one makes.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    thread,
+    time::Duration,
+};
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use rusty_vault::{
+    core::Core,
+    errors::RvError,
+    modules::credential::approle::{
+        validation::{create_hmac, FlushStrategy, SecretIdStorageEntry},
+        AppRoleBackendInner, SecretIdScope,
+    },
+    storage::{
+        self, barrier::SecurityBarrier, barrier_aes_gcm::AESGCMBarrier, barrier_view::BarrierView, Storage,
+        StorageEntry,
+    },
+    utils::{salt::Salt, ttl::LeaseTtl},
+};
+
+const HMAC_KEY: &str = "benchhmackey";
+const ROLE_NAME: &str = "role1";
+const SCALES: [usize; 2] = [100, 10_000];
+// The latency of a round trip to a remote backend, see RemoteStorage
+const ROUND_TRIP_LATENCY: Duration = Duration::from_micros(200);
+
+// RemoteStorage stands for a backend where every call is a round trip, a
+// batch included. It waits ROUND_TRIP_LATENCY per call and counts the calls.
+struct RemoteStorage {
+    inner: Arc<dyn Storage>,
+    round_trips: AtomicUsize,
+}
+
+impl RemoteStorage {
+    fn new(inner: Arc<dyn Storage>) -> Self {
+        Self { inner, round_trips: AtomicUsize::new(0) }
+    }
+
+    fn round_trip(&self) {
+        self.round_trips.fetch_add(1, Ordering::Relaxed);
+        thread::sleep(ROUND_TRIP_LATENCY);
+    }
+}
+
+impl Storage for RemoteStorage {
+    fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+        self.round_trip();
+        self.inner.list(prefix)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+        self.round_trip();
+        self.inner.get(key)
+    }
+
+    fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+        self.round_trip();
+        self.inner.put(entry)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RvError> {
+        self.round_trip();
+        self.inner.delete(key)
+    }
+
+    fn delete_batch(&self, keys: &[String]) -> Result<(), RvError> {
+        self.round_trip();
+        self.inner.delete_batch(keys)
+    }
+}
+
+fn setup() -> (AppRoleBackendInner, Arc<dyn Storage>) {
+    let physical = storage::new_backend("inmem", &HashMap::new()).unwrap();
+    let barrier = Arc::new(AESGCMBarrier::new(Arc::clone(&physical)));
+    let key = barrier.generate_key().unwrap();
+    barrier.init(key.as_slice()).unwrap();
+    barrier.unseal(key.as_slice()).unwrap();
+
+    let storage: Arc<dyn Storage> = Arc::new(BarrierView::new(barrier.clone(), "auth/approle/"));
+    let core = Arc::new(RwLock::new(Core { physical, barrier, ..Default::default() }));
+    let inner = AppRoleBackendInner {
+        salt: RwLock::new(Some(Salt::new(Some(storage.as_ref()), None).unwrap())),
+        ..AppRoleBackendInner::new(core)
+    };
+
+    (inner, storage)
+}
+
+fn register(inner: &AppRoleBackendInner, storage: &dyn Storage, secret_id: &str) {
+    let mut entry = SecretIdStorageEntry { secret_id_ttl: LeaseTtl::from_secs(3600), ..Default::default() };
+    inner
+        .register_secret_id_entry(storage, ROLE_NAME, secret_id, HMAC_KEY, SecretIdScope::Global, 0, &mut entry)
+        .unwrap();
+}
+
+fn populate(inner: &AppRoleBackendInner, storage: &dyn Storage, count: usize) {
+    for i in 0..count {
+        register(inner, storage, &format!("secret-{}", i));
+    }
+}
+
+fn bench_register_secret_id_entry(c: &mut Criterion) {
+    let mut group = c.benchmark_group("register_secret_id_entry");
+    for scale in SCALES {
+        let (inner, storage) = setup();
+        populate(&inner, storage.as_ref(), scale);
+
+        let mut next = scale;
+        group.bench_with_input(BenchmarkId::from_parameter(scale), &scale, |b, _| {
+            b.iter(|| {
+                register(&inner, storage.as_ref(), &format!("secret-{}", next));
+                next += 1;
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_get_secret_id_storage_entry(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get_secret_id_storage_entry");
+    for scale in SCALES {
+        let (inner, storage) = setup();
+        populate(&inner, storage.as_ref(), scale);
+
+        let role_name_hmac = create_hmac(HMAC_KEY, ROLE_NAME).unwrap();
+        let secret_id_hmac = create_hmac(HMAC_KEY, &format!("secret-{}", scale / 2)).unwrap();
+        group.bench_with_input(BenchmarkId::from_parameter(scale), &scale, |b, _| {
+            b.iter(|| {
+                inner
+                    .get_secret_id_storage_entry(
+                        storage.as_ref(),
+                        SecretIdScope::Global,
+                        &role_name_hmac,
+                        &secret_id_hmac,
+                    )
+                    .unwrap()
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_flush_role_secrets(c: &mut Criterion) {
+    let mut group = c.benchmark_group("flush_role_secrets");
+    // Every iteration has to repopulate the role, keep the sample count low
+    group.sample_size(10);
+    for scale in SCALES {
+        group.throughput(Throughput::Elements(scale as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(scale), &scale, |b, &scale| {
+            b.iter_batched(
+                || {
+                    let (inner, storage) = setup();
+                    populate(&inner, storage.as_ref(), scale);
+                    (inner, storage)
+                },
+                |(inner, storage)| {
+                    inner.flush_role_secrets(storage.as_ref(), ROLE_NAME, HMAC_KEY, SecretIdScope::Global).unwrap()
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+// The round trips of a flush against a remote backend, per strategy. The
+// counts are printed once per input, as criterion only reports the time.
+fn bench_flush_role_secrets_round_trips(c: &mut Criterion) {
+    let mut group = c.benchmark_group("flush_role_secrets_round_trips");
+    group.sample_size(10);
+    let scale = SCALES[0];
+    for (name, strategy) in [("sequential", FlushStrategy::Sequential), ("batched", FlushStrategy::default())] {
+        let prepare = || {
+            let (inner, storage) = setup();
+            populate(&inner, storage.as_ref(), scale);
+            let remote = RemoteStorage::new(storage);
+            (inner, remote)
+        };
+
+        let (inner, remote) = prepare();
+        inner.flush_role_secrets_with(&remote, ROLE_NAME, HMAC_KEY, SecretIdScope::Global, strategy).unwrap();
+        println!("flush_role_secrets/{}/{}: {} round trips", name, scale, remote.round_trips.load(Ordering::Relaxed));
+
+        group.bench_with_input(BenchmarkId::new(name, scale), &strategy, |b, &strategy| {
+            b.iter_batched(
+                prepare,
+                |(inner, remote)| {
+                    inner
+                        .flush_role_secrets_with(&remote, ROLE_NAME, HMAC_KEY, SecretIdScope::Global, strategy)
+                        .unwrap()
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn bench_create_hmac(c: &mut Criterion) {
+    let mut group = c.benchmark_group("create_hmac");
+    for len in [16, 256] {
+        let value = "s".repeat(len);
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(len), &value, |b, value| {
+            b.iter(|| create_hmac(HMAC_KEY, value).unwrap())
+        });
+    }
+    group.finish();
+}
+
+// role_name_hmac against the HMAC it memoizes, on a hit of its cache.
+fn bench_role_name_hmac(c: &mut Criterion) {
+    let mut group = c.benchmark_group("role_name_hmac");
+    let (inner, _storage) = setup();
+    group.bench_function("uncached", |b| b.iter(|| create_hmac(HMAC_KEY, ROLE_NAME).unwrap()));
+    group.bench_function("cached", |b| b.iter(|| inner.role_name_hmac(HMAC_KEY, ROLE_NAME).unwrap()));
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_register_secret_id_entry,
+    bench_get_secret_id_storage_entry,
+    bench_flush_role_secrets,
+    bench_flush_role_secrets_round_trips,
+    bench_create_hmac,
+    bench_role_name_hmac
+);
+criterion_main!(benches);
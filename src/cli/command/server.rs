@@ -148,12 +148,16 @@ impl Server {
 
         let server = actix_rt::System::new();
 
+        let metrics_manager = Arc::new(RwLock::new(MetricsManager::new(config.collection_interval)));
+        let system_metrics = Arc::clone(&metrics_manager.read().unwrap().system_metrics);
+        let storage_metrics = Arc::clone(&metrics_manager.read().unwrap().storage_metrics);
+
         let backend = storage::new_backend(storage.stype.as_str(), &storage.config).unwrap();
+        let backend: Arc<dyn storage::Backend> =
+            Arc::new(storage::metered::MeteredStorage::new(backend, storage_metrics));
 
         let barrier = storage::barrier_aes_gcm::AESGCMBarrier::new(Arc::clone(&backend));
-
-        let metrics_manager = Arc::new(RwLock::new(MetricsManager::new(config.collection_interval)));
-        let system_metrics = Arc::clone(&metrics_manager.read().unwrap().system_metrics);
+        barrier.set_max_value_size(config.max_storage_value_size)?;
 
         let core = Arc::new(RwLock::new(Core { physical: backend, barrier: Arc::new(barrier), ..Default::default() }));
 
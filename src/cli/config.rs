@@ -11,7 +11,7 @@ use serde::{
 };
 use serde_json::Value;
 
-use crate::errors::RvError;
+use crate::{errors::RvError, storage::DEFAULT_MAX_VALUE_SIZE};
 
 /// A struct that contains several configurable options of RustyVault server
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -40,6 +40,8 @@ pub struct Config {
     pub collection_interval: u64,
     #[serde(default = "default_hmac_level")]
     pub mount_entry_hmac_level: MountEntryHMACLevel,
+    #[serde(default = "default_max_storage_value_size")]
+    pub max_storage_value_size: usize,
 }
 
 #[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
@@ -58,6 +60,10 @@ fn default_collection_interval() -> u64 {
     15
 }
 
+fn default_max_storage_value_size() -> usize {
+    DEFAULT_MAX_VALUE_SIZE
+}
+
 /// A struct that contains several configurable options for networking stuffs
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Listener {
@@ -101,7 +107,7 @@ pub struct Storage {
     pub config: HashMap<String, Value>,
 }
 
-static STORAGE_TYPE_KEYWORDS: &[&str] = &["file", "mysql"];
+static STORAGE_TYPE_KEYWORDS: &[&str] = &["file", "mysql", "s3"];
 
 fn default_bool_true() -> bool {
     true
@@ -243,6 +249,10 @@ impl Config {
         if other.mount_entry_hmac_level != MountEntryHMACLevel::None {
             self.mount_entry_hmac_level = other.mount_entry_hmac_level;
         }
+
+        if other.max_storage_value_size != DEFAULT_MAX_VALUE_SIZE {
+            self.max_storage_value_size = other.max_storage_value_size;
+        }
     }
 }
 
@@ -418,6 +428,7 @@ mod test {
         assert_eq!(json_config.log_level.as_str(), "debug");
         assert_eq!(json_config.pid_file.as_str(), "/tmp/rusty_vault.pid");
         assert_eq!(json_config.work_dir.as_str(), "");
+        assert_eq!(json_config.max_storage_value_size, DEFAULT_MAX_VALUE_SIZE);
         assert_eq!(json_config.daemon, false);
         assert_eq!(json_config.daemon_user.as_str(), "");
         assert_eq!(json_config.daemon_group.as_str(), "");
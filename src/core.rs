@@ -162,6 +162,12 @@ impl Core {
         self.barrier.inited()
     }
 
+    /// Checks that the storage behind the barrier is reachable. It works whether or not the core is
+    /// sealed.
+    pub fn storage_health(&self) -> Result<(), RvError> {
+        self.barrier.as_storage().health()
+    }
+
     pub fn init(&mut self, seal_config: &SealConfig) -> Result<InitResult, RvError> {
         let inited = self.inited()?;
         if inited {
@@ -367,6 +373,19 @@ impl Core {
         // Unseal the barrier
         barrier.unseal(master_key.as_slice())?;
 
+        // Make sure the key material matches the stored data before serving requests. Barriers
+        // initialized before the canary existed have nothing to check against.
+        match barrier.verify_integrity() {
+            Ok(()) => {}
+            Err(RvError::ErrBarrierCanaryNotFound) => {
+                log::warn!("barrier integrity canary not found, skipping the integrity check");
+            }
+            Err(e) => {
+                barrier.seal()?;
+                return Err(e);
+            }
+        }
+
         // Perform initial setup
         self.post_unseal()?;
 
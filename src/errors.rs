@@ -57,6 +57,16 @@ pub enum RvError {
     ErrPhysicalBackendPrefixInvalid,
     #[error("Physical backend key is invalid.")]
     ErrPhysicalBackendKeyInvalid,
+    #[error("Storage key is invalid, {0}")]
+    ErrStorageKeyInvalid(String),
+    #[error("Storage delete was not confirmed, the key is still visible: {0}")]
+    ErrStorageDeleteNotConfirmed(String),
+    #[error("Storage operation timed out: {0}")]
+    ErrStorageTimeout(String),
+    #[error("Storage backend does not support snapshots.")]
+    ErrStorageSnapshotUnsupported,
+    #[error("Storage snapshot is read-only.")]
+    ErrStorageSnapshotReadOnly,
     #[error("RustyVault key sanity check failed.")]
     ErrBarrierKeySanityCheckFailed,
     #[error("RustyVault is already initialized.")]
@@ -75,8 +85,24 @@ pub enum RvError {
     ErrBarrierEpochMismatch,
     #[error("RustyVault barrier version do not match.")]
     ErrBarrierVersionMismatch,
+    #[error("RustyVault barrier ciphertext is not in a known format.")]
+    ErrBarrierUnknownFormat,
     #[error("RustyVault barrier key generation failed.")]
     ErrBarrierKeyGenerationFailed,
+    #[error("RustyVault barrier integrity canary not found.")]
+    ErrBarrierCanaryNotFound,
+    #[error("RustyVault barrier integrity check failed, the encryption key does not match the stored data.")]
+    ErrBarrierIntegrityCheckFailed,
+    #[error("RustyVault barrier entry is not bound to its key, rebind it before reading it.")]
+    ErrBarrierEntryUnbound,
+    #[error("RustyVault barrier stream is truncated.")]
+    ErrBarrierStreamTruncated,
+    #[error("RustyVault barrier stream frame is invalid.")]
+    ErrBarrierStreamFrameInvalid,
+    #[error("Algorithm {0} is not permitted in FIPS mode")]
+    ErrAlgorithmNotPermitted(String),
+    #[error("Storage value is too large, size: {size}, max: {max}")]
+    ErrValueTooLarge { size: usize, max: usize },
     #[error("Router mount conflict.")]
     ErrRouterMountConflict,
     #[error("Router mount not found.")]
@@ -117,6 +143,10 @@ pub enum RvError {
     ErrHandlerDefault,
     #[error("Module kv data field is missing.")]
     ErrModuleKvDataFieldMissing,
+    #[error("Module {0} is not initialized.")]
+    ErrModuleNotInitialized(&'static str),
+    #[error("Audit log hash chain is broken at line {0}.")]
+    ErrAuditChainBroken(usize),
     #[error("Rust downcast failed.")]
     ErrRustDowncastFailed,
     #[error("Shamir share count invalid.")]
@@ -208,6 +238,11 @@ pub enum RvError {
         #[from]
         source: hex::FromHexError,
     },
+    #[error("Some base64 decode error happened, {:?}", .source)]
+    Base64Decode {
+        #[from]
+        source: base64::DecodeError,
+    },
     #[error("Some hcl error happened, {:?}", .source)]
     Hcl {
         #[from]
@@ -315,8 +350,20 @@ pub enum RvError {
     },
     ///
     /// Database Errors End
+    #[cfg(feature = "storage_s3")]
+    #[error("S3 request failed, {:?}", .source)]
+    ErrS3 {
+        #[from]
+        source: s3::error::S3Error,
+    },
     #[error(transparent)]
     ErrOther(#[from] anyhow::Error),
+    #[error("secret_id is already registered")]
+    ErrSecretIdAlreadyExists { accessor: Option<String> },
+    #[error("secret_id is not bound to the presented role_id")]
+    ErrSecretIdRoleMismatch,
+    #[error("too many concurrent secret_id registrations for the role, retry later")]
+    ErrRoleRegistrationBusy,
     #[error("Some error happend, response text: {0}")]
     ErrResponse(String),
     #[error("Some error happend, status: {0}, response text: {1}")]
@@ -340,8 +387,13 @@ impl RvError {
             | RvError::ErrRequestInvalid
             | RvError::ErrRequestClientTokenMissing
             | RvError::ErrRequestFieldNotFound
-            | RvError::ErrRequestFieldInvalid => StatusCode::BAD_REQUEST,
+            | RvError::ErrRequestFieldInvalid
+            | RvError::ErrSecretIdAlreadyExists { .. }
+            | RvError::ErrSecretIdRoleMismatch => StatusCode::BAD_REQUEST,
             RvError::ErrBarrierSealed => StatusCode::SERVICE_UNAVAILABLE,
+            RvError::ErrRoleRegistrationBusy => StatusCode::TOO_MANY_REQUESTS,
+            RvError::ErrValueTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            RvError::ErrModuleNotInitialized(_) => StatusCode::INTERNAL_SERVER_ERROR,
             RvError::ErrPermissionDenied => StatusCode::FORBIDDEN,
             RvError::ErrRouterMountNotFound => StatusCode::NOT_FOUND,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
@@ -379,7 +431,15 @@ impl PartialEq for RvError {
             | (RvError::ErrBarrierUnsealFailed, RvError::ErrBarrierUnsealFailed)
             | (RvError::ErrBarrierEpochMismatch, RvError::ErrBarrierEpochMismatch)
             | (RvError::ErrBarrierVersionMismatch, RvError::ErrBarrierVersionMismatch)
+            | (RvError::ErrBarrierUnknownFormat, RvError::ErrBarrierUnknownFormat)
             | (RvError::ErrBarrierKeyGenerationFailed, RvError::ErrBarrierKeyGenerationFailed)
+            | (RvError::ErrBarrierCanaryNotFound, RvError::ErrBarrierCanaryNotFound)
+            | (RvError::ErrBarrierIntegrityCheckFailed, RvError::ErrBarrierIntegrityCheckFailed)
+            | (RvError::ErrBarrierEntryUnbound, RvError::ErrBarrierEntryUnbound)
+            | (RvError::ErrStorageSnapshotUnsupported, RvError::ErrStorageSnapshotUnsupported)
+            | (RvError::ErrStorageSnapshotReadOnly, RvError::ErrStorageSnapshotReadOnly)
+            | (RvError::ErrBarrierStreamTruncated, RvError::ErrBarrierStreamTruncated)
+            | (RvError::ErrBarrierStreamFrameInvalid, RvError::ErrBarrierStreamFrameInvalid)
             | (RvError::ErrRouterMountConflict, RvError::ErrRouterMountConflict)
             | (RvError::ErrRouterMountNotFound, RvError::ErrRouterMountNotFound)
             | (RvError::ErrMountFailed, RvError::ErrMountFailed)
@@ -434,10 +494,24 @@ impl PartialEq for RvError {
             | (RvError::ErrPkiInternal, RvError::ErrPkiInternal)
             | (RvError::ErrCredentailInvalid, RvError::ErrCredentailInvalid)
             | (RvError::ErrCredentailNotConfig, RvError::ErrCredentailNotConfig)
+            | (RvError::ErrSecretIdRoleMismatch, RvError::ErrSecretIdRoleMismatch)
+            | (RvError::ErrRoleRegistrationBusy, RvError::ErrRoleRegistrationBusy)
             | (RvError::ErrUnknown, RvError::ErrUnknown) => true,
             (RvError::ErrResponse(a), RvError::ErrResponse(b)) => a == b,
             (RvError::ErrResponseStatus(sa, ta), RvError::ErrResponseStatus(sb, tb)) => sa == sb && ta == tb,
             (RvError::ErrString(a), RvError::ErrString(b)) => a == b,
+            (RvError::ErrStorageKeyInvalid(a), RvError::ErrStorageKeyInvalid(b)) => a == b,
+            (RvError::ErrStorageDeleteNotConfirmed(a), RvError::ErrStorageDeleteNotConfirmed(b)) => a == b,
+            (RvError::ErrStorageTimeout(a), RvError::ErrStorageTimeout(b)) => a == b,
+            (RvError::ErrModuleNotInitialized(a), RvError::ErrModuleNotInitialized(b)) => a == b,
+            (RvError::ErrAuditChainBroken(a), RvError::ErrAuditChainBroken(b)) => a == b,
+            (RvError::ErrAlgorithmNotPermitted(a), RvError::ErrAlgorithmNotPermitted(b)) => a == b,
+            (RvError::ErrValueTooLarge { size: sa, max: ma }, RvError::ErrValueTooLarge { size: sb, max: mb }) => {
+                sa == sb && ma == mb
+            }
+            (RvError::ErrSecretIdAlreadyExists { accessor: a }, RvError::ErrSecretIdAlreadyExists { accessor: b }) => {
+                a == b
+            }
             _ => false,
         }
     }
@@ -37,6 +37,8 @@ struct LogicalResponse {
     lease_duration: u64,
     auth: Option<Auth>,
     data: HashMap<String, Value>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<String>,
 }
 
 async fn logical_request_handler(
@@ -137,6 +139,11 @@ fn response_logical(resp: &Response, path: &str) -> Result<HttpResponse, RvError
         no_content = false;
     }
 
+    if !resp.warnings.is_empty() {
+        logical_resp.warnings.clone_from(&resp.warnings);
+        no_content = false;
+    }
+
     if no_content {
         Ok(response_ok(cookie, None))
     } else {
@@ -12,6 +12,7 @@ use crate::{
         handle_request,
         request_auth,
         response_error,
+        response_json,
         response_json_ok,
         response_ok,
     },
@@ -43,6 +44,13 @@ pub struct SealStatusResponse {
     pub progress: usize,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthResponse {
+    pub initialized: bool,
+    pub sealed: bool,
+    pub storage_reachable: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct MountRequest {
     #[serde(rename = "type")]
@@ -120,6 +128,29 @@ async fn sys_seal_status_request_handler(
     response_seal_status(core)
 }
 
+async fn sys_health_request_handler(
+    _req: HttpRequest,
+    core: web::Data<Arc<RwLock<Core>>>,
+) -> Result<HttpResponse, RvError> {
+    let core = core.read()?;
+
+    let storage_reachable = match core.storage_health() {
+        Ok(()) => true,
+        Err(err) => {
+            log::warn!("storage health check failed, err: {}", err);
+            false
+        }
+    };
+    let initialized = storage_reachable && core.inited()?;
+    let resp = HealthResponse { initialized, sealed: core.sealed(), storage_reachable };
+
+    if !storage_reachable {
+        return Ok(response_json(StatusCode::SERVICE_UNAVAILABLE, None, resp));
+    }
+
+    Ok(response_json_ok(None, resp))
+}
+
 async fn sys_seal_request_handler(
     _req: HttpRequest,
     core: web::Data<Arc<RwLock<Core>>>,
@@ -410,6 +441,7 @@ pub fn init_sys_service(cfg: &mut web::ServiceConfig) {
                     .route(web::put().to(sys_init_put_request_handler)),
             )
             .service(web::resource("/seal-status").route(web::get().to(sys_seal_status_request_handler)))
+            .service(web::resource("/health").route(web::get().to(sys_health_request_handler)))
             .service(
                 web::resource("/seal")
                     .route(web::post().to(sys_seal_request_handler))
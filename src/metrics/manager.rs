@@ -3,13 +3,14 @@ use std::sync::{Arc, Mutex};
 
 use prometheus_client::registry::Registry;
 
-use crate::metrics::{http_metrics::HttpMetrics, system_metrics::SystemMetrics};
+use crate::metrics::{http_metrics::HttpMetrics, storage_metrics::StorageMetrics, system_metrics::SystemMetrics};
 
 #[derive(Clone)]
 pub struct MetricsManager {
     pub registry: Arc<Mutex<Registry>>,
     pub system_metrics: Arc<SystemMetrics>,
     pub http_metrics: Arc<HttpMetrics>,
+    pub storage_metrics: Arc<StorageMetrics>,
 }
 
 impl MetricsManager {
@@ -17,6 +18,7 @@ impl MetricsManager {
         let registry = Arc::new(Mutex::new(Registry::default()));
         let system_metrics = Arc::new(SystemMetrics::new(&mut registry.lock().unwrap(), collection_interval));
         let http_metrics = Arc::new(HttpMetrics::new(&mut registry.lock().unwrap()));
-        MetricsManager { registry, system_metrics, http_metrics }
+        let storage_metrics = Arc::new(StorageMetrics::new(&mut registry.lock().unwrap()));
+        MetricsManager { registry, system_metrics, http_metrics, storage_metrics }
     }
 }
@@ -72,4 +72,5 @@
 pub mod http_metrics;
 pub mod manager;
 pub mod middleware;
+pub mod storage_metrics;
 pub mod system_metrics;
@@ -0,0 +1,140 @@
+//! Define and implement storage metrics, recorded by the `MeteredStorage` wrapper.
+use std::{fmt::Write, time::Duration};
+
+use prometheus_client::{
+    encoding::{text::encode, EncodeLabelSet, EncodeLabelValue, LabelValueEncoder},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        histogram::{exponential_buckets, Histogram},
+    },
+    registry::Registry,
+};
+
+pub const STORAGE_OPERATIONS: &str = "rustyvault_storage_operations";
+pub const STORAGE_OPERATIONS_HELP: &str = "Number of storage operations, labeled by operation";
+pub const STORAGE_ERRORS: &str = "rustyvault_storage_errors";
+pub const STORAGE_ERRORS_HELP: &str = "Number of failed storage operations, labeled by operation";
+pub const STORAGE_OPERATION_DURATION_SECONDS: &str = "rustyvault_storage_operation_duration_seconds";
+pub const STORAGE_OPERATION_DURATION_SECONDS_HELP: &str = "Duration of storage operations, labeled by operation";
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum StorageOp {
+    List,
+    Get,
+    Put,
+    Delete,
+    DeleteBatch,
+    Exists,
+    Count,
+}
+
+impl StorageOp {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StorageOp::List => "list",
+            StorageOp::Get => "get",
+            StorageOp::Put => "put",
+            StorageOp::Delete => "delete",
+            StorageOp::DeleteBatch => "delete_batch",
+            StorageOp::Exists => "exists",
+            StorageOp::Count => "count",
+        }
+    }
+}
+
+impl EncodeLabelValue for StorageOp {
+    fn encode(&self, writer: &mut LabelValueEncoder<'_>) -> Result<(), std::fmt::Error> {
+        writer.write_str(&escape_label_value(self.as_str()))
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct StorageLabel {
+    pub op: StorageOp,
+}
+
+/// Escapes a label value for the text exposition format, in which backslashes, double quotes and
+/// line feeds must be escaped.
+pub fn escape_label_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[derive(Clone)]
+pub struct StorageMetrics {
+    operations: Family<StorageLabel, Counter>,
+    errors: Family<StorageLabel, Counter>,
+    histogram: Family<StorageLabel, Histogram>,
+}
+
+impl StorageMetrics {
+    pub fn new(registry: &mut Registry) -> Self {
+        let metrics = Self {
+            operations: Family::<StorageLabel, Counter>::default(),
+            errors: Family::<StorageLabel, Counter>::default(),
+            histogram: Family::<StorageLabel, Histogram>::new_with_constructor(|| {
+                Histogram::new(exponential_buckets(0.0005, 2.0, 12))
+            }),
+        };
+
+        metrics.register(registry);
+
+        metrics
+    }
+
+    pub fn observe(&self, op: StorageOp, duration: Duration, failed: bool) {
+        let label = StorageLabel { op };
+        self.operations.get_or_create(&label).inc();
+        if failed {
+            self.errors.get_or_create(&label).inc();
+        }
+        self.histogram.get_or_create(&label).observe(duration.as_secs_f64());
+    }
+
+    /// Renders the storage metrics alone in the Prometheus text format, for scraping them without
+    /// the rest of the registry.
+    pub fn render_prometheus(&self) -> String {
+        let mut registry = Registry::default();
+        self.register(&mut registry);
+
+        let mut buffer = String::new();
+        if let Err(e) = encode(&mut buffer, &registry) {
+            log::error!("failed to encode storage metrics, err: {}", e);
+            return String::new();
+        }
+
+        buffer
+    }
+
+    // The families are shared by their clones, so the same metrics can be
+    // registered in several registries.
+    fn register(&self, registry: &mut Registry) {
+        registry.register(STORAGE_OPERATIONS, STORAGE_OPERATIONS_HELP, self.operations.clone());
+        registry.register(STORAGE_ERRORS, STORAGE_ERRORS_HELP, self.errors.clone());
+        registry.register(
+            STORAGE_OPERATION_DURATION_SECONDS,
+            STORAGE_OPERATION_DURATION_SECONDS_HELP,
+            self.histogram.clone(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_label_value() {
+        assert_eq!(escape_label_value("get"), "get");
+        assert_eq!(escape_label_value("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
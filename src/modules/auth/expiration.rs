@@ -137,8 +137,8 @@ impl ExpirationManager {
             return Err(RvError::ErrBarrierSealed);
         }
 
-        let id_view = core.system_view.as_ref().unwrap().new_sub_view(LEASE_VIEW_PREFIX);
-        let token_view = core.system_view.as_ref().unwrap().new_sub_view(TOKEN_VIEW_PREFIX);
+        let id_view = core.system_view.as_ref().unwrap().sub_view(LEASE_VIEW_PREFIX);
+        let token_view = core.system_view.as_ref().unwrap().sub_view(TOKEN_VIEW_PREFIX);
 
         let expiration = ExpirationManager {
             self_ptr: Weak::new(),
@@ -405,7 +405,7 @@ impl ExpirationManager {
             prefix += "/";
         }
 
-        let sub = self.id_view.new_sub_view(&prefix);
+        let sub = self.id_view.sub_view(&prefix);
         let existing = sub.get_keys()?;
         for suffix in existing.iter() {
             let lease_id = format!("{}{}", prefix, suffix);
@@ -136,7 +136,7 @@ impl TokenStore {
             return Err(RvError::ErrBarrierSealed);
         }
 
-        let view = core.system_view.as_ref().unwrap().new_sub_view(TOKEN_SUB_PATH);
+        let view = core.system_view.as_ref().unwrap().sub_view(TOKEN_SUB_PATH);
         let salt = view.get(TOKEN_SALT_LOCATION)?;
 
         let mut token_store = TokenStore {
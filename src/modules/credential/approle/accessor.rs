@@ -0,0 +1,178 @@
+//! The format of the generated secret_id accessors.
+//!
+//! Accessors are UUIDs by default. `AccessorFormat::PrefixedBase32` produces a shorter token, an
+//! optional namespacing prefix followed by random bytes in lowercase base32. Either way the
+//! accessor is only ever stored salted, so the storage indices do not depend on the format, and
+//! the accessors created under a previous format keep resolving after it changes.
+//!
+//! An accessor must not be guessable and must not collide with a live one. A format is therefore
+//! required to draw at least `MIN_ACCESSOR_ENTROPY_BITS` random bits: by the birthday bound, the
+//! probability that any two of n accessors collide is at most n^2 / 2^(bits + 1), which for 96 bits
+//! and 2^32 accessors is 2^-33. `create_secret_id_accessor_entry` still checks that the new
+//! accessor is unused before writing it, and draws another one if it is not.
+
+use crate::{errors::RvError, utils::entropy::EntropySource};
+
+// MIN_ACCESSOR_ENTROPY_BITS is the least number of random bits an accessor
+// format may draw.
+pub const MIN_ACCESSOR_ENTROPY_BITS: usize = 96;
+
+// MAX_ACCESSOR_PREFIX_LEN bounds the prefix of a PrefixedBase32 accessor.
+pub const MAX_ACCESSOR_PREFIX_LEN: usize = 32;
+
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum AccessorFormat {
+    // A random UUID, 128 bits.
+    #[default]
+    Uuid,
+    // The prefix followed by bytes random bytes in unpadded lowercase base32,
+    // e.g. "app-" and 16 bytes give "app-" and 26 characters.
+    PrefixedBase32 {
+        prefix: String,
+        bytes: usize,
+    },
+}
+
+impl AccessorFormat {
+    // validate rejects a format drawing too few random bits, or whose prefix
+    // is not made of ASCII letters, digits, '-', '_' and '.'.
+    pub fn validate(&self) -> Result<(), RvError> {
+        if self.entropy_bits() < MIN_ACCESSOR_ENTROPY_BITS {
+            return Err(RvError::ErrResponse(format!(
+                "accessor format draws {} random bits, at least {} are required",
+                self.entropy_bits(),
+                MIN_ACCESSOR_ENTROPY_BITS
+            )));
+        }
+
+        if let AccessorFormat::PrefixedBase32 { prefix, .. } = self {
+            if prefix.len() > MAX_ACCESSOR_PREFIX_LEN {
+                return Err(RvError::ErrResponse(format!(
+                    "accessor prefix is longer than {} characters",
+                    MAX_ACCESSOR_PREFIX_LEN
+                )));
+            }
+
+            if !prefix.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')) {
+                return Err(RvError::ErrResponse(format!("invalid accessor prefix: {}", prefix)));
+            }
+        }
+
+        Ok(())
+    }
+
+    // entropy_bits is the number of random bits in an accessor of this format.
+    pub fn entropy_bits(&self) -> usize {
+        match self {
+            AccessorFormat::Uuid => 128,
+            AccessorFormat::PrefixedBase32 { bytes, .. } => bytes.saturating_mul(8),
+        }
+    }
+
+    // generate draws a new accessor of this format from source.
+    pub fn generate(&self, source: &dyn EntropySource) -> Result<String, RvError> {
+        match self {
+            AccessorFormat::Uuid => crate::utils::entropy::generate_uuid_with(source),
+            AccessorFormat::PrefixedBase32 { prefix, bytes } => {
+                let mut buf = vec![0u8; *bytes];
+                source.fill_bytes(&mut buf)?;
+                Ok(format!("{}{}", prefix, base32_encode(&buf)))
+            }
+        }
+    }
+}
+
+// base32_encode encodes data in lowercase RFC 4648 base32, without padding.
+fn base32_encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity((data.len() * 8).div_ceil(5));
+    let mut buffer: u16 = 0;
+    let mut bits = 0;
+    for byte in data {
+        buffer = (buffer << 8) | u16::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[usize::from((buffer >> bits) & 0x1f)] as char);
+        }
+    }
+
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[usize::from((buffer << (5 - bits)) & 0x1f)] as char);
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::utils::entropy::{OsEntropy, SeededEntropy};
+
+    #[test]
+    fn test_base32_encode() {
+        // The RFC 4648 test vectors, lowercased and without padding
+        for (data, encoded) in [
+            ("", ""),
+            ("f", "my"),
+            ("fo", "mzxq"),
+            ("foo", "mzxw6"),
+            ("foob", "mzxw6yq"),
+            ("fooba", "mzxw6ytb"),
+            ("foobar", "mzxw6ytboi"),
+        ] {
+            assert_eq!(base32_encode(data.as_bytes()), encoded);
+        }
+    }
+
+    #[test]
+    fn test_accessor_format() {
+        assert!(AccessorFormat::default().validate().is_ok());
+        assert_eq!(AccessorFormat::default().generate(&OsEntropy).unwrap().len(), 36);
+
+        let format = AccessorFormat::PrefixedBase32 { prefix: "app-".to_string(), bytes: 16 };
+        assert!(format.validate().is_ok());
+        let accessor = format.generate(&OsEntropy).unwrap();
+        assert_eq!(accessor.len(), 4 + 26);
+        assert!(accessor.starts_with("app-"));
+        assert!(accessor[4..].bytes().all(|c| BASE32_ALPHABET.contains(&c)));
+
+        // Too few random bits, or a prefix that could be mistaken for a path
+        assert!(AccessorFormat::PrefixedBase32 { prefix: "".to_string(), bytes: 11 }.validate().is_err());
+        assert!(AccessorFormat::PrefixedBase32 { prefix: "".to_string(), bytes: 12 }.validate().is_ok());
+        assert!(AccessorFormat::PrefixedBase32 { prefix: "a/b".to_string(), bytes: 16 }.validate().is_err());
+        let prefix = "a".repeat(MAX_ACCESSOR_PREFIX_LEN + 1);
+        assert!(AccessorFormat::PrefixedBase32 { prefix, bytes: 16 }.validate().is_err());
+    }
+
+    #[test]
+    fn test_accessor_format_collisions() {
+        // The bound stated in the module doc: 2^32 accessors of the minimum
+        // entropy collide with a probability of at most 2^-33.
+        let bound = |n: f64, bits: usize| n * n / 2f64.powi(bits as i32 + 1);
+        assert!(bound(2f64.powi(32), MIN_ACCESSOR_ENTROPY_BITS) <= 2f64.powi(-33));
+
+        // Every random byte reaches the accessor, so distinct draws give
+        // distinct accessors, and a large sample of them has no duplicate.
+        let format = AccessorFormat::PrefixedBase32 { prefix: "app-".to_string(), bytes: 12 };
+        let source = SeededEntropy::new(1);
+        let accessors: HashSet<String> = (0..100_000).map(|_| format.generate(&source).unwrap()).collect();
+        assert_eq!(accessors.len(), 100_000);
+
+        // Each base32 character carries 5 bits, and over the sample every one
+        // of them takes every symbol about as often as the others.
+        let len = accessors.iter().next().unwrap().len();
+        for position in 4..len - 1 {
+            let mut counts = [0usize; 32];
+            for accessor in accessors.iter() {
+                let symbol = accessor.as_bytes()[position];
+                counts[BASE32_ALPHABET.iter().position(|c| *c == symbol).unwrap()] += 1;
+            }
+            let expected = accessors.len() / 32;
+            assert!(counts.iter().all(|count| count.abs_diff(expected) < expected / 5), "{:?}", counts);
+        }
+    }
+}
@@ -0,0 +1,364 @@
+//! Audit events of the approle secret_id lifecycle.
+//!
+//! Every creation, login and destruction of a secret_id is reported to an `AuditSink`. The events
+//! only ever carry the HMAC of a secret_id together with its accessor and the HMAC of the role
+//! name, the plaintext secret_id must never be passed into an event.
+//!
+//! The `FileAuditSink` can chain its records: every line then embeds, as `prev_hash`, the
+//! HMAC-SHA512 of the line before it, so deleting, reordering or editing a record breaks the chain,
+//! which `verify_chain` detects. A chained file starts with a `chain_start` record carrying the hash
+//! the chain continues from, which after a rotation is the last hash of the previous file. The last
+//! record of a file is only protected by the records written after it.
+
+use std::{
+    fmt, fs,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, Write},
+    path::Path,
+    sync::{Mutex, MutexGuard},
+    time::SystemTime,
+};
+
+use openssl::hash::MessageDigest;
+use serde::{Deserialize, Serialize};
+
+use super::validation::create_hmac_with;
+use crate::{
+    errors::RvError,
+    utils::{deserialize_system_time, serialize_system_time},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEventType {
+    SecretIdCreate,
+    // Follows the SecretIdCreate of a secret_id that never expires
+    SecretIdNoExpiry,
+    SecretIdDelete,
+    LoginSuccess,
+    LoginFailure,
+    ChainStart,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    #[serde(serialize_with = "serialize_system_time", deserialize_with = "deserialize_system_time")]
+    pub time: SystemTime,
+    #[serde(rename = "type")]
+    pub event_type: AuditEventType,
+    #[serde(skip_serializing_if = "String::is_empty", default)]
+    pub role_name_hmac: String,
+    #[serde(skip_serializing_if = "String::is_empty", default)]
+    pub secret_id_hmac: String,
+    #[serde(skip_serializing_if = "String::is_empty", default)]
+    pub secret_id_accessor: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub error: Option<String>,
+    // Set by a chaining sink, the hash of the previous record
+    #[serde(skip_serializing_if = "String::is_empty", default)]
+    pub prev_hash: String,
+}
+
+impl AuditEvent {
+    pub fn new(event_type: AuditEventType, time: SystemTime) -> Self {
+        Self {
+            time,
+            event_type,
+            role_name_hmac: String::new(),
+            secret_id_hmac: String::new(),
+            secret_id_accessor: String::new(),
+            error: None,
+            prev_hash: String::new(),
+        }
+    }
+}
+
+pub trait AuditSink: Send + Sync {
+    fn log(&self, event: AuditEvent);
+}
+
+/// The default sink, which drops every event.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopAuditSink;
+
+impl AuditSink for NoopAuditSink {
+    fn log(&self, _event: AuditEvent) {}
+}
+
+/// Appends the events to a file as newline-delimited JSON, optionally hash chained.
+pub struct FileAuditSink {
+    state: Mutex<FileAuditState>,
+    // Set in chained mode
+    chain_key: Option<String>,
+}
+
+struct FileAuditState {
+    file: File,
+    prev_hash: String,
+}
+
+impl FileAuditSink {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, RvError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { state: Mutex::new(FileAuditState { file, prev_hash: String::new() }), chain_key: None })
+    }
+
+    /// Opens a sink chaining its records with the given HMAC key. Appending to an existing chained
+    /// file continues its chain.
+    pub fn new_chained<P: AsRef<Path>>(path: P, key: &str) -> Result<Self, RvError> {
+        if key.is_empty() {
+            return Err(RvError::ErrResponse("invalid hmac key".to_string()));
+        }
+
+        let prev_hash = last_chain_hash(path.as_ref(), key)?;
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let sink = Self {
+            state: Mutex::new(FileAuditState { file, prev_hash: prev_hash.clone().unwrap_or_default() }),
+            chain_key: Some(key.to_string()),
+        };
+
+        if prev_hash.is_none() {
+            sink.write_event(&mut sink.lock_state(), AuditEvent::new(AuditEventType::ChainStart, SystemTime::now()))?;
+        }
+
+        Ok(sink)
+    }
+
+    /// Switches to a new file. In chained mode, the new file starts with a `chain_start` record
+    /// carrying the last hash of the previous file.
+    pub fn rotate<P: AsRef<Path>>(&self, path: P) -> Result<(), RvError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        let mut state = self.lock_state();
+        state.file = file;
+        if self.chain_key.is_some() {
+            self.write_event(&mut state, AuditEvent::new(AuditEventType::ChainStart, SystemTime::now()))?;
+        }
+
+        Ok(())
+    }
+
+    // The state stays consistent even if a writer panicked, at worst the chain
+    // is broken, which verify_chain reports.
+    fn lock_state(&self) -> MutexGuard<'_, FileAuditState> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(err) => err.into_inner(),
+        }
+    }
+
+    fn write_event(&self, state: &mut FileAuditState, mut event: AuditEvent) -> Result<(), RvError> {
+        if self.chain_key.is_some() {
+            event.prev_hash.clone_from(&state.prev_hash);
+        }
+
+        let line = serde_json::to_string(&event)?;
+        state.file.write_all(format!("{}\n", line).as_bytes())?;
+
+        if let Some(key) = self.chain_key.as_ref() {
+            state.prev_hash = create_hmac_with(MessageDigest::sha512(), key, &line)?;
+        }
+
+        Ok(())
+    }
+}
+
+// The chain key is a secret, so only whether the sink is chained is printed.
+impl fmt::Debug for FileAuditSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileAuditSink").field("chained", &self.chain_key.is_some()).finish()
+    }
+}
+
+impl AuditSink for FileAuditSink {
+    fn log(&self, event: AuditEvent) {
+        let mut state = self.lock_state();
+        if let Err(err) = self.write_event(&mut state, event) {
+            log::error!("failed to write approle audit event, err: {}", err);
+        }
+    }
+}
+
+// last_chain_hash returns the hash of the last record of an existing file, or
+// None if there is no record yet.
+fn last_chain_hash(path: &Path, key: &str) -> Result<Option<String>, RvError> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    match content.lines().last() {
+        Some(line) => Ok(Some(create_hmac_with(MessageDigest::sha512(), key, line)?)),
+        None => Ok(None),
+    }
+}
+
+/// Checks the hash chain of a file written by a chained `FileAuditSink`. Returns
+/// `ErrAuditChainBroken` with the number of the first line, counting from 1, that does not follow
+/// from the ones before it.
+pub fn verify_chain(reader: impl BufRead, key: &str) -> Result<(), RvError> {
+    let mut prev_hash: Option<String> = None;
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let broken = RvError::ErrAuditChainBroken(i + 1);
+
+        let Ok(event) = serde_json::from_str::<AuditEvent>(&line) else {
+            return Err(broken);
+        };
+
+        match prev_hash.as_ref() {
+            None if event.event_type != AuditEventType::ChainStart => return Err(broken),
+            Some(expected) if *expected != event.prev_hash => return Err(broken),
+            _ => {}
+        }
+
+        prev_hash = Some(create_hmac_with(MessageDigest::sha512(), key, &line)?);
+    }
+
+    // Not even the chain_start record is left
+    if prev_hash.is_none() {
+        return Err(RvError::ErrAuditChainBroken(1));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, io::BufReader, sync::Arc};
+
+    use as_any::Downcast;
+    use serde_json::json;
+
+    use super::{
+        super::{
+            test::{generate_secret_id, test_login},
+            AppRoleModule,
+        },
+        *,
+    };
+    use crate::test_utils::{test_mount_auth_api, test_read_api, test_rusty_vault_init, test_write_api, TEST_DIR};
+
+    #[maybe_async::test(feature = "sync_handler", async(all(not(feature = "sync_handler")), tokio::test))]
+    async fn test_approle_audit_secret_id_lifecycle() {
+        let (root_token, core) = test_rusty_vault_init("test_approle_audit_secret_id_lifecycle");
+        let core = core.read().unwrap();
+
+        // Mount approle auth to path: auth/approle
+        test_mount_auth_api(&core, &root_token, "approle", "approle/").await;
+
+        let audit_path = env::temp_dir().join(*TEST_DIR).join("test_approle_audit_secret_id_lifecycle.log");
+        let _ = fs::remove_file(&audit_path);
+        {
+            let module = core.module_manager.get_module("approle").unwrap();
+            let approle_mod = module.read().unwrap();
+            let approle_module = approle_mod.as_ref().downcast_ref::<AppRoleModule>().unwrap();
+            approle_module.set_audit_sink(Arc::new(FileAuditSink::new(&audit_path).unwrap())).unwrap();
+        }
+
+        let resp = test_write_api(&core, &root_token, "auth/approle/role/audit", true, None).await;
+        assert!(resp.is_ok());
+        let resp = test_read_api(&core, &root_token, "auth/approle/role/audit/role-id", true).await;
+        let role_id = resp.unwrap().unwrap().data.unwrap()["role_id"].as_str().unwrap().to_string();
+
+        let (secret_id, secret_id_accessor) = generate_secret_id(&core, &root_token, "approle", "audit").await;
+
+        let _ = test_login(&core, "approle", &role_id, &secret_id, true).await;
+        let _ = test_login(&core, "approle", &role_id, "wrong-secret-id", false).await;
+
+        let data = json!({ "secret_id": secret_id }).as_object().unwrap().clone();
+        let resp =
+            test_write_api(&core, &root_token, "auth/approle/role/audit/secret-id/destroy", true, Some(data)).await;
+        assert!(resp.is_ok());
+
+        let content = fs::read_to_string(&audit_path).unwrap();
+        assert!(!content.contains(&secret_id));
+        assert!(content.contains(&secret_id_accessor));
+
+        let events: Vec<AuditEvent> = content.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        let types: Vec<AuditEventType> = events.iter().map(|e| e.event_type).collect();
+        assert_eq!(
+            types,
+            vec![
+                AuditEventType::SecretIdCreate,
+                AuditEventType::SecretIdNoExpiry,
+                AuditEventType::LoginSuccess,
+                AuditEventType::LoginFailure,
+                AuditEventType::SecretIdDelete
+            ]
+        );
+        assert!(events.iter().all(|e| !e.role_name_hmac.is_empty()));
+        assert_eq!(events[1].secret_id_accessor, secret_id_accessor);
+        assert!(events[3].error.is_some());
+    }
+
+    #[test]
+    fn test_approle_audit_hash_chain() {
+        let dir = env::temp_dir().join(*TEST_DIR);
+        fs::create_dir_all(&dir).unwrap();
+        let audit_path = dir.join("test_approle_audit_hash_chain.log");
+        let rotated_path = dir.join("test_approle_audit_hash_chain.1.log");
+        let _ = fs::remove_file(&audit_path);
+        let _ = fs::remove_file(&rotated_path);
+
+        let key = "testauditkey";
+        let verify = |content: &str| verify_chain(BufReader::new(content.as_bytes()), key);
+        let event = |accessor: &str| {
+            let mut event = AuditEvent::new(AuditEventType::SecretIdCreate, SystemTime::UNIX_EPOCH);
+            event.secret_id_accessor = accessor.to_string();
+            event
+        };
+
+        let sink = FileAuditSink::new_chained(&audit_path, key).unwrap();
+        for accessor in ["accessor1", "accessor2", "accessor3"] {
+            sink.log(event(accessor));
+        }
+        drop(sink);
+
+        // Reopening the file continues the chain
+        let sink = FileAuditSink::new_chained(&audit_path, key).unwrap();
+        sink.log(event("accessor4"));
+
+        let content = fs::read_to_string(&audit_path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(verify(&content).is_ok());
+
+        // The chain depends on the key
+        assert_eq!(verify_chain(BufReader::new(content.as_bytes()), "otherkey"), Err(RvError::ErrAuditChainBroken(2)));
+
+        // Deleting, reordering, editing or truncating a record in the middle is detected
+        let join = |lines: &[&str]| lines.iter().map(|line| format!("{}\n", line)).collect::<String>();
+        assert_eq!(verify(&join(&[lines[0], lines[1], lines[3], lines[4]])), Err(RvError::ErrAuditChainBroken(3)));
+        assert_eq!(
+            verify(&join(&[lines[0], lines[2], lines[1], lines[3], lines[4]])),
+            Err(RvError::ErrAuditChainBroken(2))
+        );
+        let edited = lines[2].replace("accessor2", "accessor9");
+        assert_eq!(
+            verify(&join(&[lines[0], lines[1], &edited, lines[3], lines[4]])),
+            Err(RvError::ErrAuditChainBroken(4))
+        );
+        let truncated = &lines[2][..lines[2].len() / 2];
+        assert_eq!(
+            verify(&join(&[lines[0], lines[1], truncated, lines[3], lines[4]])),
+            Err(RvError::ErrAuditChainBroken(3))
+        );
+
+        // So is losing the head of the file
+        assert_eq!(verify(&join(&lines[1..])), Err(RvError::ErrAuditChainBroken(1)));
+        assert_eq!(verify(""), Err(RvError::ErrAuditChainBroken(1)));
+
+        // A rotated file carries the last hash over
+        assert!(sink.rotate(&rotated_path).is_ok());
+        sink.log(event("accessor5"));
+        let rotated = fs::read_to_string(&rotated_path).unwrap();
+        assert!(verify(&rotated).is_ok());
+        let header: AuditEvent = serde_json::from_str(rotated.lines().next().unwrap()).unwrap();
+        assert_eq!(header.event_type, AuditEventType::ChainStart);
+        assert_eq!(header.prev_hash, create_hmac_with(MessageDigest::sha512(), key, lines[4]).unwrap());
+        assert_eq!(fs::read_to_string(&audit_path).unwrap(), content);
+    }
+}
@@ -0,0 +1,229 @@
+//! Limiting of concurrent secret_id registrations per role.
+//!
+//! Every registration takes locks and does storage I/O in the area of its role, so a stampede of
+//! registrations for one hot role can starve the others. The `RegistrationLimiter` bounds the
+//! number of registrations in flight for each role to `max_concurrent`. A registration over the
+//! bound either waits for a slot or is rejected with the retryable `ErrRoleRegistrationBusy`,
+//! depending on `on_limit`.
+//!
+//! The slots are kept in memory only. The map of roles is bounded by `max_entries`: when it is
+//! full, the roles with no registration in flight or waiting are evicted. Roles that are busy are
+//! never evicted, so the map can briefly outgrow the bound by the number of busy roles.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Condvar, Mutex, PoisonError, RwLock},
+};
+
+use crate::errors::RvError;
+
+const DEFAULT_MAX_ENTRIES: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnLimit {
+    // Wait until a registration of the role completes
+    #[default]
+    Queue,
+    // Fail with ErrRoleRegistrationBusy
+    Reject,
+}
+
+#[derive(Debug, Clone)]
+pub struct RegistrationLimiterConfig {
+    /// Number of registrations of a role allowed in flight at once. Zero disables the limit.
+    pub max_concurrent: usize,
+    pub on_limit: OnLimit,
+    /// Maximum number of roles tracked at the same time.
+    pub max_entries: usize,
+}
+
+impl Default for RegistrationLimiterConfig {
+    fn default() -> Self {
+        Self { max_concurrent: 0, on_limit: OnLimit::default(), max_entries: DEFAULT_MAX_ENTRIES }
+    }
+}
+
+#[derive(Debug, Default)]
+struct RoleSlots {
+    in_flight: Mutex<usize>,
+    released: Condvar,
+}
+
+// RegistrationPermit holds a registration slot of a role, and gives it back
+// when dropped.
+#[derive(Debug)]
+pub struct RegistrationPermit {
+    slots: Option<Arc<RoleSlots>>,
+}
+
+impl Drop for RegistrationPermit {
+    fn drop(&mut self) {
+        if let Some(slots) = self.slots.take() {
+            let mut in_flight = slots.in_flight.lock().unwrap_or_else(PoisonError::into_inner);
+            *in_flight -= 1;
+            slots.released.notify_one();
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RegistrationLimiter {
+    config: RwLock<RegistrationLimiterConfig>,
+    roles: Mutex<HashMap<String, Arc<RoleSlots>>>,
+}
+
+impl RegistrationLimiter {
+    pub fn new(config: RegistrationLimiterConfig) -> Self {
+        Self { config: RwLock::new(config), roles: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn config(&self) -> Result<RegistrationLimiterConfig, RvError> {
+        Ok(self.config.read()?.clone())
+    }
+
+    pub fn set_config(&self, config: RegistrationLimiterConfig) -> Result<(), RvError> {
+        *self.config.write()? = config;
+        Ok(())
+    }
+
+    // acquire takes a registration slot of the role, waiting for one or failing
+    // if the role is at its limit.
+    pub fn acquire(&self, role_name: &str) -> Result<RegistrationPermit, RvError> {
+        let config = self.config()?;
+        if config.max_concurrent == 0 {
+            return Ok(RegistrationPermit { slots: None });
+        }
+
+        let slots = self.slots(role_name, config.max_entries);
+        {
+            let mut in_flight = slots.in_flight.lock().unwrap_or_else(PoisonError::into_inner);
+            while *in_flight >= config.max_concurrent {
+                if config.on_limit == OnLimit::Reject {
+                    return Err(RvError::ErrRoleRegistrationBusy);
+                }
+                in_flight = slots.released.wait(in_flight).unwrap_or_else(PoisonError::into_inner);
+            }
+            *in_flight += 1;
+        }
+
+        Ok(RegistrationPermit { slots: Some(slots) })
+    }
+
+    // len returns the number of roles tracked.
+    pub fn len(&self) -> usize {
+        self.roles.lock().unwrap_or_else(PoisonError::into_inner).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // slots returns the slots of the role, making room for them by evicting
+    // the idle roles if the map is full. A role is idle when nothing but the
+    // map refers to its slots, as every permit and waiter holds a reference.
+    fn slots(&self, role_name: &str, max_entries: usize) -> Arc<RoleSlots> {
+        let mut roles = self.roles.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(slots) = roles.get(role_name) {
+            return Arc::clone(slots);
+        }
+
+        if roles.len() >= max_entries {
+            roles.retain(|_, slots| Arc::strong_count(slots) > 1);
+        }
+
+        let slots = Arc::new(RoleSlots::default());
+        roles.insert(role_name.to_string(), Arc::clone(&slots));
+        slots
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+
+    fn new_limiter(max_concurrent: usize, on_limit: OnLimit) -> Arc<RegistrationLimiter> {
+        Arc::new(RegistrationLimiter::new(RegistrationLimiterConfig { max_concurrent, on_limit, max_entries: 2 }))
+    }
+
+    #[test]
+    fn test_registration_limiter_bound() {
+        let limiter = new_limiter(2, OnLimit::Queue);
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let (limiter, in_flight, max_in_flight) =
+                    (Arc::clone(&limiter), Arc::clone(&in_flight), Arc::clone(&max_in_flight));
+                thread::spawn(move || {
+                    for _ in 0..5 {
+                        let _permit = limiter.acquire("role1").unwrap();
+                        let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                        max_in_flight.fetch_max(current, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(2));
+                        in_flight.fetch_sub(1, Ordering::SeqCst);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert!(max_in_flight.load(Ordering::SeqCst) <= 2);
+        assert!(max_in_flight.load(Ordering::SeqCst) >= 1);
+        assert_eq!(in_flight.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_registration_limiter_reject() {
+        let limiter = new_limiter(2, OnLimit::Reject);
+
+        let first = limiter.acquire("role1").unwrap();
+        let _second = limiter.acquire("role1").unwrap();
+        assert_eq!(limiter.acquire("role1").unwrap_err(), RvError::ErrRoleRegistrationBusy);
+
+        // Other roles have their own slots
+        assert!(limiter.acquire("role2").is_ok());
+
+        // A completed registration frees its slot
+        drop(first);
+        assert!(limiter.acquire("role1").is_ok());
+
+        // No limit, no tracking
+        limiter.set_config(RegistrationLimiterConfig::default()).unwrap();
+        let permits: Vec<_> = (0..10).map(|_| limiter.acquire("role3").unwrap()).collect();
+        assert_eq!(permits.len(), 10);
+        assert_eq!(limiter.len(), 2);
+    }
+
+    #[test]
+    fn test_registration_limiter_eviction() {
+        let limiter = new_limiter(1, OnLimit::Reject);
+
+        // Idle roles make room for new ones
+        for role in ["role1", "role2", "role3", "role4"] {
+            drop(limiter.acquire(role).unwrap());
+            assert!(limiter.len() <= 2);
+        }
+
+        // Busy roles are kept, and keep their slots
+        let busy1 = limiter.acquire("role5").unwrap();
+        let busy2 = limiter.acquire("role6").unwrap();
+        let busy3 = limiter.acquire("role7").unwrap();
+        assert_eq!(limiter.len(), 3);
+        for role in ["role5", "role6", "role7"] {
+            assert_eq!(limiter.acquire(role).unwrap_err(), RvError::ErrRoleRegistrationBusy);
+        }
+
+        drop((busy1, busy2, busy3));
+        assert!(limiter.acquire("role8").is_ok());
+        assert_eq!(limiter.len(), 1);
+    }
+}
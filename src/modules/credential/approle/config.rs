@@ -0,0 +1,139 @@
+//! Tunable limits of the approle backend.
+//!
+//! The `AppRoleConfig` is loaded from the system storage when the module is initialized. Every
+//! field has a default identical to the limit the backend used before it was configurable.
+
+use std::time::Duration;
+
+use better_default::Default;
+use serde::{Deserialize, Serialize};
+
+use super::throttle::{LoginThrottleConfig, DEFAULT_COOLDOWN, DEFAULT_MAX_ENTRIES, DEFAULT_WINDOW};
+use crate::{
+    errors::RvError,
+    modules::auth::expiration::MAX_LEASE_DURATION_SECS,
+    storage::{Storage, StorageEntry},
+    utils::{deserialize_duration, serialize_duration},
+};
+
+// The storage path of the approle config, relative to the system view.
+pub const APPROLE_CONFIG_PATH: &str = "approle/config";
+
+// A missing config, or a config missing some of the fields, falls back to the
+// defaults.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AppRoleConfig {
+    // secret_id TTLs longer than this are clamped
+    #[serde(serialize_with = "serialize_duration", deserialize_with = "deserialize_duration")]
+    #[default(MAX_LEASE_DURATION_SECS)]
+    pub max_secret_id_ttl: Duration,
+
+    // The TTL of a secret_id registered without one, unless its role opts
+    // into secret_ids that never expire. Zero keeps such secret_ids from
+    // expiring.
+    #[serde(serialize_with = "serialize_duration", deserialize_with = "deserialize_duration")]
+    pub default_secret_id_ttl: Duration,
+
+    // Limits on the metadata attached to a secret_id
+    #[default(64)]
+    pub max_metadata_pairs: usize,
+    #[default(128)]
+    pub max_metadata_key_length: usize,
+    #[default(512)]
+    pub max_metadata_value_length: usize,
+
+    // The number of distinct CIDR blocks a secret_id can be bound to, in
+    // cidr_list and token_cidr_list each. Every login checks cidr_list
+    // against the role, so this bounds its cost. Zero means unlimited.
+    #[default(64)]
+    pub max_secret_id_cidr_blocks: usize,
+
+    // Whether a secret_id can be registered without any TTL, either because
+    // its role opts into secret_ids that never expire or because there is no
+    // default_secret_id_ttl. When disallowed, such registrations are rejected.
+    // An allowed one is flagged with a warning and an audit event.
+    #[default(true)]
+    pub allow_infinite_secret_id_ttl: bool,
+
+    // Whether a secret_id registered without a cidr_list inherits the
+    // secret_id_bound_cidrs of its role, rather than being usable from
+    // anywhere the role is.
+    pub inherit_role_secret_id_cidrs: bool,
+
+    // The live secret_id limit of the roles that do not set their own
+    // secret_id_count_limit. Zero means unlimited.
+    pub default_secret_id_count_limit: i64,
+
+    // The expiration of a new secret_id is pushed back by a random offset of
+    // up to this percentage of its TTL, so that a burst of registrations does
+    // not expire all at once. Zero disables the jitter.
+    pub expiration_jitter_percent: u32,
+
+    // A secret_id stays valid until this long past its expiration_time, so
+    // that a clock running ahead of the one that set it does not expire it
+    // early. Tidy also leaves alone the secret_ids created further than this
+    // in the future, as the clock went back since. Zero requires the clocks
+    // to agree.
+    #[serde(serialize_with = "serialize_duration", deserialize_with = "deserialize_duration")]
+    pub clock_skew_tolerance: Duration,
+
+    // The upper bounds, in seconds, of the buckets secret_id_ttl_histogram
+    // sorts the remaining TTLs into. A last bucket collects the TTLs above the
+    // largest bound.
+    #[default(vec![60, 3600, 86400, 7 * 86400, 30 * 86400])]
+    pub secret_id_ttl_histogram_bucket_secs: Vec<u64>,
+
+    // The number of roles the backend holds, past which the creation of a
+    // role is rejected. Updating an existing role is always allowed. Zero
+    // means unlimited.
+    pub max_roles: usize,
+
+    // Once a role_id has login_throttle_max_failures failed logins within
+    // login_throttle_window, its logins are rejected for
+    // login_throttle_cooldown. Zero failures, the default, disables the
+    // throttle. At most login_throttle_max_entries role_ids are tracked at the
+    // same time.
+    pub login_throttle_max_failures: usize,
+    #[serde(serialize_with = "serialize_duration", deserialize_with = "deserialize_duration")]
+    #[default(DEFAULT_WINDOW)]
+    pub login_throttle_window: Duration,
+    #[serde(serialize_with = "serialize_duration", deserialize_with = "deserialize_duration")]
+    #[default(DEFAULT_COOLDOWN)]
+    pub login_throttle_cooldown: Duration,
+    #[default(DEFAULT_MAX_ENTRIES)]
+    pub login_throttle_max_entries: usize,
+}
+
+impl AppRoleConfig {
+    // load reads the config from storage, falling back to the defaults if it
+    // was never written.
+    pub fn load(storage: &dyn Storage) -> Result<Self, RvError> {
+        match storage.get(APPROLE_CONFIG_PATH)? {
+            Some(entry) => Ok(serde_json::from_slice(entry.value.as_slice())?),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn save(&self, storage: &dyn Storage) -> Result<(), RvError> {
+        storage.put(&StorageEntry::new(APPROLE_CONFIG_PATH, self)?)
+    }
+
+    pub fn login_throttle_config(&self) -> LoginThrottleConfig {
+        LoginThrottleConfig {
+            max_failures: self.login_throttle_max_failures,
+            window: self.login_throttle_window,
+            cooldown: self.login_throttle_cooldown,
+            max_entries: self.login_throttle_max_entries,
+        }
+    }
+
+    // secret_id_count_limit returns the live secret_id limit of a role.
+    pub fn secret_id_count_limit(&self, role_secret_id_count_limit: i64) -> i64 {
+        if role_secret_id_count_limit > 0 {
+            role_secret_id_count_limit
+        } else {
+            self.default_secret_id_count_limit
+        }
+    }
+}
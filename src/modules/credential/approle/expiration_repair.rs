@@ -0,0 +1,151 @@
+//! Repair of the secret_ids registered with a TTL but without an expiration.
+//!
+//! Before the expiration_time of a secret_id became optional, a secret_id could be stored with its
+//! expiration_time left at its creation_time, the sentinel of a never set expiration, even though
+//! it had a `secret_id_ttl`. Such an entry does not expire when its TTL says it should.
+//! `repair_secret_id_expiration` finds them in a scope and sets their expiration_time to their
+//! creation_time plus their TTL. `migrate_secret_id_expiration` runs it over every scope once per
+//! mount, recording that it did under `MIGRATION_PREFIX`, and is called by the tidy routine.
+
+use super::{AppRoleBackendInner, SecretIdScope, MIGRATION_PREFIX};
+use crate::{
+    errors::RvError,
+    storage::{Storage, StorageEntry},
+};
+
+// The key marking that migrate_secret_id_expiration ran
+const SECRET_ID_EXPIRATION_MIGRATION: &str = "secret_id_expiration";
+
+impl AppRoleBackendInner {
+    // repair_secret_id_expiration sets the expiration_time of the secret_ids of
+    // the scope that have a TTL but an expiration_time equal to their
+    // creation_time, returning how many were repaired. Corrupt entries are
+    // skipped, tidy takes care of them.
+    pub fn repair_secret_id_expiration(&self, storage: &dyn Storage, scope: SecretIdScope) -> Result<usize, RvError> {
+        let mut repaired = 0;
+
+        storage.walk(scope.prefix(), &mut |key: &str| {
+            let Some((role_name_hmac, secret_id_hmac)) = key[scope.prefix().len()..].split_once('/') else {
+                return Ok(());
+            };
+
+            let lock_entry = self.secret_id_locks.get_lock(secret_id_hmac);
+            let _locked = lock_entry.write()?;
+
+            let mut entry = match self.get_secret_id_storage_entry(storage, scope, role_name_hmac, secret_id_hmac) {
+                Ok(Some(entry)) => entry,
+                Ok(None) => return Ok(()),
+                Err(RvError::SerdeJson { .. }) => {
+                    log::warn!("skipping corrupt approle entry, key: {}", key);
+                    return Ok(());
+                }
+                Err(err) => return Err(err),
+            };
+
+            if entry.secret_id_ttl.is_zero() || entry.expiration_time != entry.creation_time {
+                return Ok(());
+            }
+
+            let Some(expiration_time) = entry.creation_time.checked_add(entry.secret_id_ttl.as_duration()) else {
+                log::warn!("skipping secret ID with an unrepresentable expiration, secret_id_hmac: {}", secret_id_hmac);
+                return Ok(());
+            };
+
+            log::warn!(
+                "repairing the expiration of secret ID, secret_id_hmac: {}, expiration_time: {:?}",
+                secret_id_hmac,
+                expiration_time
+            );
+            entry.expiration_time = expiration_time;
+            self.set_secret_id_storage_entry(storage, scope, role_name_hmac, secret_id_hmac, &entry)?;
+            repaired += 1;
+
+            Ok(())
+        })?;
+
+        Ok(repaired)
+    }
+
+    // migrate_secret_id_expiration runs repair_secret_id_expiration over every
+    // scope, unless it already completed on this storage. It returns the number
+    // of secret_ids repaired, or None if there was nothing left to migrate.
+    pub fn migrate_secret_id_expiration(&self, storage: &dyn Storage) -> Result<Option<usize>, RvError> {
+        let marker_key = format!("{}{}", MIGRATION_PREFIX, SECRET_ID_EXPIRATION_MIGRATION);
+        if storage.get(&marker_key)?.is_some() {
+            return Ok(None);
+        }
+
+        let mut repaired = 0;
+        for scope in SecretIdScope::ALL {
+            repaired += self.repair_secret_id_expiration(storage, scope)?;
+        }
+
+        // Only recorded once every scope went through, a failed run is retried
+        storage.put(&StorageEntry::new(&marker_key, &self.clock.now())?)?;
+
+        Ok(Some(repaired))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use super::{
+        super::{
+            test::{new_test_inner, register_test_secret_id},
+            validation::SecretIdStorageEntry,
+        },
+        *,
+    };
+    use crate::utils::{clock::MockClock, ttl::LeaseTtl};
+
+    #[test]
+    fn test_approle_repair_secret_id_expiration() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = Arc::new(MockClock::new(start));
+
+        let (mut inner, storage) = new_test_inner("test_approle_repair_secret_id_expiration");
+        inner.clock = clock.clone();
+        let scope = SecretIdScope::Global;
+
+        let role_name_hmac = inner.role_name_hmac("testhmackey", "role1").unwrap();
+        let register = |secret_id: &str, ttl: u64| -> String {
+            let mut entry = SecretIdStorageEntry { secret_id_ttl: LeaseTtl::from_secs(ttl), ..Default::default() };
+            register_test_secret_id(&inner, storage.as_ref(), "role1", secret_id, scope, &mut entry);
+            inner.find_secret_id_hmac(storage.as_ref(), scope, &role_name_hmac, "testhmackey", secret_id).unwrap()
+        };
+        let load = |secret_id_hmac: &str| {
+            inner
+                .get_secret_id_storage_entry(storage.as_ref(), scope, &role_name_hmac, secret_id_hmac)
+                .unwrap()
+                .unwrap()
+        };
+
+        // Seed a secret_id with a TTL whose expiration_time was never set,
+        // next to a healthy one and one that never expires
+        let broken = register("secret1", 600);
+        let mut entry = load(&broken);
+        entry.expiration_time = entry.creation_time;
+        inner.set_secret_id_storage_entry(storage.as_ref(), scope, &role_name_hmac, &broken, &entry).unwrap();
+        let healthy = register("secret2", 60);
+        let healthy_before = load(&healthy);
+        let never = register("secret3", 0);
+        let never_before = load(&never);
+
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(inner.migrate_secret_id_expiration(storage.as_ref()).unwrap(), Some(1));
+
+        // The repaired expiration is derived from the creation_time, not from now
+        assert_eq!(load(&broken).expiration_time, start + Duration::from_secs(600));
+        assert_eq!(load(&healthy), healthy_before);
+        assert_eq!(load(&never), never_before);
+
+        // The migration only runs once, the repair itself has nothing left to do
+        assert_eq!(inner.migrate_secret_id_expiration(storage.as_ref()).unwrap(), None);
+        assert_eq!(inner.repair_secret_id_expiration(storage.as_ref(), scope).unwrap(), 0);
+    }
+}
@@ -0,0 +1,102 @@
+//! Notifications of the secret_ids about to expire, so that integrations can rotate them ahead of
+//! time.
+//!
+//! Once an `ExpiringNotifier` is set with `set_expiring_hook`, every tidy pass calls its hook for
+//! the live secret_ids expiring within the lead time. Like the audit events, a
+//! `SecretIdExpiringEvent` identifies the secret_id by its accessor and the HMAC of its role name,
+//! never by the secret_id itself.
+//!
+//! The hook is called once per secret_id and expiration time: a secret_id still in its window at
+//! the next pass is not reported again, while one whose expiration moved is. What was reported is
+//! only kept in memory, so after a restart the secret_ids in their window are reported once more.
+//! The hook runs on the tidy task after the locks of the pass are released, and should hand the
+//! event off rather than block.
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::{Duration, SystemTime},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecretIdExpiringEvent {
+    pub role_name_hmac: String,
+    pub secret_id_accessor: String,
+    pub expiration_time: SystemTime,
+    // The time left until expiration_time when the event was raised
+    pub remaining: Duration,
+}
+
+pub type ExpiringHook = Box<dyn Fn(&SecretIdExpiringEvent) + Send + Sync>;
+
+pub struct ExpiringNotifier {
+    lead_time: Duration,
+    hook: ExpiringHook,
+    // The expiration time each secret_id, by HMAC, was last reported for
+    notified: Mutex<HashMap<String, SystemTime>>,
+}
+
+impl ExpiringNotifier {
+    pub fn new(lead_time: Duration, hook: ExpiringHook) -> Self {
+        Self { lead_time, hook, notified: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn lead_time(&self) -> Duration {
+        self.lead_time
+    }
+
+    // check returns the event to raise for a secret_id expiring at
+    // expiration_time, if it is within the lead time of now and was not
+    // reported for that expiration time yet. It is only recorded as reported
+    // by notify, so an event dropped by a failing pass is raised by the next.
+    pub fn check(
+        &self,
+        role_name_hmac: &str,
+        secret_id_hmac: &str,
+        secret_id_accessor: &str,
+        expiration_time: SystemTime,
+        now: SystemTime,
+    ) -> Option<SecretIdExpiringEvent> {
+        let remaining = expiration_time.duration_since(now).ok()?;
+        if remaining.is_zero() || remaining > self.lead_time {
+            return None;
+        }
+
+        let notified = self.notified.lock().unwrap_or_else(PoisonError::into_inner);
+        if notified.get(secret_id_hmac) == Some(&expiration_time) {
+            return None;
+        }
+
+        Some(SecretIdExpiringEvent {
+            role_name_hmac: role_name_hmac.to_string(),
+            secret_id_accessor: secret_id_accessor.to_string(),
+            expiration_time,
+            remaining,
+        })
+    }
+
+    // notify records the events, keyed by secret_id HMAC, as reported and
+    // calls the hook with each of them.
+    pub fn notify(&self, events: Vec<(String, SecretIdExpiringEvent)>) {
+        let mut notified = self.notified.lock().unwrap_or_else(PoisonError::into_inner);
+        let events: Vec<SecretIdExpiringEvent> = events
+            .into_iter()
+            .filter_map(|(secret_id_hmac, event)| {
+                let previous = notified.insert(secret_id_hmac, event.expiration_time);
+                (previous != Some(event.expiration_time)).then_some(event)
+            })
+            .collect();
+        drop(notified);
+
+        events.iter().for_each(|event| (self.hook)(event));
+    }
+
+    // forget_expired drops the record of the secret_ids expired at now, which
+    // tidy deletes.
+    pub fn forget_expired(&self, now: SystemTime) {
+        self.notified
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|_, expiration_time| *expiration_time > now);
+    }
+}
@@ -0,0 +1,97 @@
+//! Generation of the secret_ids by the backend.
+//!
+//! `register_secret_id_entry` stores a secret_id supplied by the client. `generate_secret_id` is
+//! the other way of creating one: the secret_id is drawn from the entropy source of the backend,
+//! registered with the given `SecretIdOptions`, and handed back in plaintext exactly once.
+
+use std::{collections::HashMap, time::SystemTime};
+
+use super::{
+    validation::{create_hmac, validate_secret_id_metadata, validate_secret_id_name, SecretIdStorageEntry},
+    AppRoleBackendInner, SecretIdScope,
+};
+use crate::{errors::RvError, storage::Storage, utils::ttl::LeaseTtl};
+
+// SecretIdOptions are the properties given to a secret_id created by
+// generate_secret_id.
+#[derive(Debug, Clone, Default)]
+pub struct SecretIdOptions {
+    // Zero means unlimited uses
+    pub num_uses: i64,
+    // Zero means the configured default TTL
+    pub ttl: LeaseTtl,
+    pub metadata: HashMap<String, String>,
+    pub cidr_list: Vec<String>,
+    pub token_cidr_list: Vec<String>,
+    pub name: Option<String>,
+    // The role_id to bind the secret_id to, empty binds it to the role_id of
+    // the role
+    pub role_id: String,
+    // Zero means unlimited
+    pub secret_id_count_limit: i64,
+}
+
+// SecretIdRegistration describes a secret_id that was just registered, with
+// everything but the secret_id itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecretIdRegistration {
+    pub secret_id_accessor: String,
+    pub secret_id_num_uses: i64,
+    // Zero when the secret_id never expires
+    pub secret_id_ttl: LeaseTtl,
+    pub expiration_time: SystemTime,
+}
+
+impl AppRoleBackendInner {
+    // generate_secret_id creates a secret_id from the entropy source of the
+    // backend and registers it with the given options. The plaintext secret_id
+    // is returned here and nowhere else, it cannot be recovered afterwards.
+    pub fn generate_secret_id(
+        &self,
+        storage: &dyn Storage,
+        role_name: &str,
+        hmac_key: &str,
+        scope: SecretIdScope,
+        opts: SecretIdOptions,
+    ) -> Result<(String, SecretIdRegistration), RvError> {
+        if opts.num_uses < 0 {
+            return Err(RvError::ErrResponse("num_uses cannot be negative".to_string()));
+        }
+        if let Some(name) = opts.name.as_ref() {
+            validate_secret_id_name(name)?;
+        }
+        validate_secret_id_metadata(&opts.metadata, &self.config()?)?;
+
+        let role_id_hmac = if opts.role_id.is_empty() { String::new() } else { create_hmac(hmac_key, &opts.role_id)? };
+        let mut entry = SecretIdStorageEntry {
+            secret_id_num_uses: opts.num_uses,
+            secret_id_ttl: opts.ttl,
+            metadata: opts.metadata,
+            cidr_list: opts.cidr_list,
+            token_cidr_list: opts.token_cidr_list,
+            name: opts.name,
+            role_id_hmac,
+            ..Default::default()
+        };
+
+        let secret_id = self.generate_uuid()?;
+        self.register_secret_id_entry(
+            storage,
+            role_name,
+            &secret_id,
+            hmac_key,
+            scope,
+            opts.secret_id_count_limit,
+            &mut entry,
+        )?;
+
+        let registration = SecretIdRegistration {
+            secret_id_accessor: entry.secret_id_accessor,
+            secret_id_num_uses: entry.secret_id_num_uses,
+            secret_id_ttl: entry.secret_id_ttl,
+            expiration_time: entry.expiration_time,
+        };
+
+        Ok((secret_id, registration))
+    }
+}
@@ -0,0 +1,372 @@
+//! Versioned hashing of the secret_id indices.
+//!
+//! A secret_id is stored under the HMAC of its value, so changing how that HMAC is computed would
+//! make every existing secret_id unreachable. The HMAC is therefore versioned: version 1 is the
+//! original HMAC-SHA256, stored as bare hex, and every later version embeds its tag as a prefix of
+//! the index, e.g. `v2-` for HMAC-SHA512. The tag is part of the last key segment rather than a
+//! `v2/` directory, so that listing the secret_ids of a role still returns every one of them,
+//! whatever its version.
+//!
+//! The `HashStrategy` of the backend picks the version new secret_ids are indexed with. Looking a
+//! secret_id up tries that version first, then falls back to the older ones, newest first. With
+//! `read_repair` set, a secret_id found under an older version is moved to the configured one on
+//! the way, along with its accessor and metadata index entries, so a deployment migrates as its
+//! secret_ids get used. With a strategy on version 1, the default, nothing changes and lookups do
+//! not cost an extra read.
+
+use openssl::hash::MessageDigest;
+
+use super::{
+    path_role::RoleEntry,
+    validation::{create_hmac, create_hmac_with, secret_id_entry_index, SecretIdAccessorStorageEntry},
+    AppRoleBackendInner, SecretIdScope, HMAC_INPUT_LEN_MAX,
+};
+use crate::{
+    errors::RvError,
+    storage::{Storage, StorageEntry},
+};
+
+const HASH_VERSION_V2_TAG: &str = "v2-";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum HashVersion {
+    // HMAC-SHA256, as bare hex
+    #[default]
+    V1,
+    // HMAC-SHA512, tagged with "v2-"
+    V2,
+}
+
+impl HashVersion {
+    // ALL lists the versions from the oldest to the newest.
+    pub const ALL: [HashVersion; 2] = [HashVersion::V1, HashVersion::V2];
+
+    // of tells the version an index was computed with.
+    pub fn of(hmac: &str) -> HashVersion {
+        if hmac.starts_with(HASH_VERSION_V2_TAG) {
+            HashVersion::V2
+        } else {
+            HashVersion::V1
+        }
+    }
+
+    // hmac computes the index of value under this version.
+    pub fn hmac(&self, key: &str, value: &str) -> Result<String, RvError> {
+        match self {
+            HashVersion::V1 => create_hmac(key, value),
+            HashVersion::V2 => {
+                if value.len() > HMAC_INPUT_LEN_MAX {
+                    return Err(RvError::ErrResponse(format!(
+                        "value is longer than maximum of {} bytes",
+                        HMAC_INPUT_LEN_MAX
+                    )));
+                }
+
+                Ok(format!("{}{}", HASH_VERSION_V2_TAG, create_hmac_with(MessageDigest::sha512(), key, value)?))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct HashStrategy {
+    // The version new secret_ids are indexed with
+    pub version: HashVersion,
+    // Whether the secret_ids found under an older version are moved to it
+    pub read_repair: bool,
+}
+
+impl HashStrategy {
+    // secret_id_hmac computes the index of a secret_id under the configured
+    // version.
+    pub fn secret_id_hmac(&self, hmac_key: &str, secret_id: &str) -> Result<String, RvError> {
+        if secret_id.is_empty() {
+            return Err(RvError::ErrResponse("missing secret_id".to_string()));
+        }
+
+        self.version.hmac(hmac_key, secret_id)
+    }
+
+    // candidates returns the indices a secret_id may be stored under, the
+    // configured version first, then the older ones from the newest.
+    pub fn candidates(&self, hmac_key: &str, secret_id: &str) -> Result<Vec<String>, RvError> {
+        let mut candidates = vec![self.secret_id_hmac(hmac_key, secret_id)?];
+        for version in HashVersion::ALL.iter().rev().filter(|version| **version < self.version) {
+            candidates.push(version.hmac(hmac_key, secret_id)?);
+        }
+
+        Ok(candidates)
+    }
+}
+
+impl AppRoleBackendInner {
+    pub fn hash_strategy(&self) -> Result<HashStrategy, RvError> {
+        Ok(self.hash_strategy.read()?.clone())
+    }
+
+    pub fn set_hash_strategy(&self, strategy: HashStrategy) -> Result<(), RvError> {
+        *self.hash_strategy.write()? = strategy;
+        Ok(())
+    }
+
+    // find_secret_id_hmac returns the index the secret_id is stored under in
+    // the role, trying the versions in the order of HashStrategy::candidates.
+    // If it is stored under none, the index under the configured version is
+    // returned, which is where a new entry is to be written.
+    pub fn find_secret_id_hmac(
+        &self,
+        storage: &dyn Storage,
+        scope: SecretIdScope,
+        role_name_hmac: &str,
+        hmac_key: &str,
+        secret_id: &str,
+    ) -> Result<String, RvError> {
+        let mut candidates = self.hash_strategy()?.candidates(hmac_key, secret_id)?;
+        if candidates.len() > 1 {
+            for (i, hmac) in candidates.iter().enumerate() {
+                if storage.exists(&secret_id_entry_index(scope, role_name_hmac, hmac)?)? {
+                    return Ok(candidates.swap_remove(i));
+                }
+            }
+        }
+
+        Ok(candidates.swap_remove(0))
+    }
+
+    // resolve_secret_id_hmac is find_secret_id_hmac, additionally moving a
+    // secret_id found under an older version to the configured one if the
+    // strategy asks for read repair. Every older entry left is moved, even if
+    // the configured one exists, which completes an interrupted move. The
+    // caller takes the secret_id lock of the returned index.
+    pub fn resolve_secret_id_hmac(
+        &self,
+        storage: &dyn Storage,
+        scope: SecretIdScope,
+        role_name_hmac: &str,
+        hmac_key: &str,
+        secret_id: &str,
+    ) -> Result<String, RvError> {
+        let strategy = self.hash_strategy()?;
+        if !strategy.read_repair {
+            return self.find_secret_id_hmac(storage, scope, role_name_hmac, hmac_key, secret_id);
+        }
+
+        let mut candidates = strategy.candidates(hmac_key, secret_id)?;
+        let upgraded = candidates.remove(0);
+        for old_hmac in candidates.iter() {
+            if storage.exists(&secret_id_entry_index(scope, role_name_hmac, old_hmac)?)? {
+                self.upgrade_secret_id_hmac(storage, scope, role_name_hmac, old_hmac, &upgraded)?;
+            }
+        }
+
+        Ok(upgraded)
+    }
+
+    // resolve_role_secret_id_hmac is resolve_secret_id_hmac for a secret_id of
+    // role, also looking it up under the previous hmac_key of the role after a
+    // rotation. A secret_id found under the previous key is moved under the
+    // current one, see rotate_hmac_key.
+    pub fn resolve_role_secret_id_hmac(
+        &self,
+        storage: &dyn Storage,
+        role: &RoleEntry,
+        role_name_hmac: &str,
+        secret_id: &str,
+    ) -> Result<String, RvError> {
+        let scope = role.secret_id_scope()?;
+        let secret_id_hmac = self.resolve_secret_id_hmac(storage, scope, role_name_hmac, &role.hmac_key, secret_id)?;
+        if role.previous_hmac_key.is_empty()
+            || storage.exists(&secret_id_entry_index(scope, role_name_hmac, &secret_id_hmac)?)?
+        {
+            return Ok(secret_id_hmac);
+        }
+
+        let previous_hmac =
+            self.find_secret_id_hmac(storage, scope, role_name_hmac, &role.previous_hmac_key, secret_id)?;
+        if storage.exists(&secret_id_entry_index(scope, role_name_hmac, &previous_hmac)?)? {
+            self.upgrade_secret_id_hmac(storage, scope, role_name_hmac, &previous_hmac, &secret_id_hmac)?;
+        }
+
+        Ok(secret_id_hmac)
+    }
+
+    // upgrade_secret_id_hmac moves the secret_id stored under old_hmac to
+    // new_hmac, and repoints its accessor and metadata index entries. The new
+    // entry is written before the old one is deleted, so the secret_id stays
+    // reachable throughout. If the move is interrupted, lookups find the new
+    // entry first, and the old one is left until resolve_secret_id_hmac calls
+    // this again: the new entry, which may have been used since, is then kept
+    // and only the indices and the old entry are dealt with.
+    pub fn upgrade_secret_id_hmac(
+        &self,
+        storage: &dyn Storage,
+        scope: SecretIdScope,
+        role_name_hmac: &str,
+        old_hmac: &str,
+        new_hmac: &str,
+    ) -> Result<(), RvError> {
+        let _locked = self.secret_id_locks.write_all(&[old_hmac, new_hmac])?;
+
+        // Moved or deleted by someone else meanwhile
+        let Some(entry) = self.get_secret_id_storage_entry(storage, scope, role_name_hmac, old_hmac)? else {
+            return Ok(());
+        };
+
+        if self.get_secret_id_storage_entry(storage, scope, role_name_hmac, new_hmac)?.is_none() {
+            self.set_secret_id_storage_entry(storage, scope, role_name_hmac, new_hmac, &entry)?;
+        }
+
+        if !entry.secret_id_accessor.is_empty() {
+            let (accessor_index, lock_entry) = self.accessor_index(&entry.secret_id_accessor, scope)?;
+            let _accessor_locked = lock_entry.write()?;
+            let accessor_entry = SecretIdAccessorStorageEntry { secret_id_hmac: new_hmac.to_string() };
+            storage.put(&StorageEntry::new(&accessor_index, &accessor_entry)?)?;
+        }
+
+        self.index_secret_id_metadata(storage, scope, role_name_hmac, new_hmac, &entry.metadata)?;
+        self.unindex_secret_id_metadata(storage, scope, role_name_hmac, old_hmac, &entry.metadata)?;
+
+        storage.delete(&secret_id_entry_index(scope, role_name_hmac, old_hmac)?)?;
+
+        log::info!("upgraded secret ID index, from: {}, to: {}", old_hmac, new_hmac);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        super::{
+            test::{new_test_inner, register_test_secret_id},
+            validation::SecretIdStorageEntry,
+        },
+        *,
+    };
+    use crate::utils::ttl::LeaseTtl;
+
+    #[test]
+    fn test_approle_hash_strategy() {
+        let v1 = HashVersion::V1.hmac("testhmackey", "secret1").unwrap();
+        let v2 = HashVersion::V2.hmac("testhmackey", "secret1").unwrap();
+        assert_eq!(v1, create_hmac("testhmackey", "secret1").unwrap());
+        assert!(v2.starts_with(HASH_VERSION_V2_TAG));
+        assert_eq!((HashVersion::of(&v1), HashVersion::of(&v2)), (HashVersion::V1, HashVersion::V2));
+
+        let strategy = HashStrategy::default();
+        assert_eq!(strategy.candidates("testhmackey", "secret1").unwrap(), vec![v1.clone()]);
+        let strategy = HashStrategy { version: HashVersion::V2, read_repair: false };
+        assert_eq!(strategy.candidates("testhmackey", "secret1").unwrap(), vec![v2, v1]);
+        assert!(strategy.secret_id_hmac("testhmackey", "").is_err());
+        assert!(strategy.secret_id_hmac("testhmackey", &"a".repeat(HMAC_INPUT_LEN_MAX + 1)).is_err());
+    }
+
+    #[test]
+    fn test_approle_hash_strategy_upgrade() {
+        let (inner, storage) = new_test_inner("test_approle_hash_strategy_upgrade");
+        let scope = SecretIdScope::Global;
+        let role_name_hmac = create_hmac("testhmackey", "role1").unwrap();
+
+        let register = |secret_id: &str| -> Result<SecretIdStorageEntry, RvError> {
+            let mut entry = SecretIdStorageEntry {
+                secret_id_ttl: LeaseTtl::from_secs(600),
+                metadata: [("env".to_string(), "prod".to_string())].into(),
+                ..Default::default()
+            };
+            inner.register_secret_id_entry(
+                storage.as_ref(),
+                "role1",
+                secret_id,
+                "testhmackey",
+                scope,
+                0,
+                &mut entry,
+            )?;
+            Ok(entry)
+        };
+        let resolve = || {
+            inner.resolve_secret_id_hmac(storage.as_ref(), scope, &role_name_hmac, "testhmackey", "secret1").unwrap()
+        };
+        let exists =
+            |hmac: &str| storage.exists(&secret_id_entry_index(scope, &role_name_hmac, hmac).unwrap()).unwrap();
+        let v1 = HashVersion::V1.hmac("testhmackey", "secret1").unwrap();
+        let v2 = HashVersion::V2.hmac("testhmackey", "secret1").unwrap();
+
+        // secret1 is indexed under v1, the default
+        let secret1 = register("secret1").unwrap();
+        assert!(exists(&v1));
+        assert_eq!(resolve(), v1);
+
+        // Under a v2 strategy without read repair it still resolves, where it is
+        assert!(inner.set_hash_strategy(HashStrategy { version: HashVersion::V2, read_repair: false }).is_ok());
+        assert_eq!(resolve(), v1);
+        assert!(exists(&v1) && !exists(&v2));
+        let status = inner.probe_secret_id(storage.as_ref(), "role1", "secret1", "testhmackey", scope).unwrap();
+        assert!(status.exists);
+
+        // Registering it again is still a duplicate, and new ones go under v2
+        assert!(register("secret1").is_err());
+        assert!(register("secret2").is_ok());
+        assert!(exists(&HashVersion::V2.hmac("testhmackey", "secret2").unwrap()));
+
+        // With read repair, resolving it moves it to v2 along with its indices
+        assert!(inner.set_hash_strategy(HashStrategy { version: HashVersion::V2, read_repair: true }).is_ok());
+        assert_eq!(resolve(), v2);
+        assert!(!exists(&v1) && exists(&v2));
+        assert_eq!(resolve(), v2);
+
+        let accessor_entry =
+            inner.get_secret_id_accessor_entry(storage.as_ref(), &secret1.secret_id_accessor, scope).unwrap();
+        assert_eq!(accessor_entry.unwrap().secret_id_hmac, v2);
+        let indexed = inner.indexed_secret_id_hmacs(storage.as_ref(), scope, &role_name_hmac, "env", "prod").unwrap();
+        assert_eq!(indexed.len(), 2);
+        assert!(indexed.contains(&v2) && !indexed.contains(&v1));
+
+        let entry = inner.get_secret_id_storage_entry(storage.as_ref(), scope, &role_name_hmac, &v2).unwrap().unwrap();
+        assert_eq!(entry.secret_id_accessor, secret1.secret_id_accessor);
+        assert_eq!(entry.expiration_time, secret1.expiration_time);
+        assert!(inner.reconcile(storage.as_ref(), scope).unwrap().is_consistent());
+    }
+
+    #[test]
+    fn test_approle_hash_strategy_interrupted_upgrade() {
+        let (inner, storage) = new_test_inner("test_approle_hash_strategy_interrupted_upgrade");
+        let scope = SecretIdScope::Global;
+        let role_name_hmac = create_hmac("testhmackey", "role1").unwrap();
+        let exists =
+            |hmac: &str| storage.exists(&secret_id_entry_index(scope, &role_name_hmac, hmac).unwrap()).unwrap();
+        let v1 = HashVersion::V1.hmac("testhmackey", "secret1").unwrap();
+        let v2 = HashVersion::V2.hmac("testhmackey", "secret1").unwrap();
+
+        let mut entry = SecretIdStorageEntry { secret_id_ttl: LeaseTtl::from_secs(600), ..Default::default() };
+        register_test_secret_id(&inner, storage.as_ref(), "role1", "secret1", scope, &mut entry);
+
+        // A move interrupted right after the new entry was written: the
+        // accessor still points at the old one, which is still there
+        assert!(inner.set_hash_strategy(HashStrategy { version: HashVersion::V2, read_repair: true }).is_ok());
+        let mut moved =
+            inner.get_secret_id_storage_entry(storage.as_ref(), scope, &role_name_hmac, &v1).unwrap().unwrap();
+        moved.secret_id_num_uses = 7;
+        inner.set_secret_id_storage_entry(storage.as_ref(), scope, &role_name_hmac, &v2, &moved).unwrap();
+        assert!(exists(&v1) && exists(&v2));
+
+        // Lookups find the new entry, and resolving it completes the move
+        assert_eq!(
+            inner.find_secret_id_hmac(storage.as_ref(), scope, &role_name_hmac, "testhmackey", "secret1").unwrap(),
+            v2
+        );
+        assert_eq!(
+            inner.resolve_secret_id_hmac(storage.as_ref(), scope, &role_name_hmac, "testhmackey", "secret1").unwrap(),
+            v2
+        );
+        assert!(!exists(&v1) && exists(&v2));
+
+        // The new entry is the one kept, and the accessor follows it
+        let kept = inner.get_secret_id_storage_entry(storage.as_ref(), scope, &role_name_hmac, &v2).unwrap().unwrap();
+        assert_eq!(kept.secret_id_num_uses, 7);
+        let accessor_entry =
+            inner.get_secret_id_accessor_entry(storage.as_ref(), &entry.secret_id_accessor, scope).unwrap();
+        assert_eq!(accessor_entry.unwrap().secret_id_hmac, v2);
+        assert!(inner.reconcile(storage.as_ref(), scope).unwrap().is_consistent());
+    }
+}
@@ -0,0 +1,140 @@
+//! The distribution of the remaining TTLs of the live secret_ids, for operators planning the load
+//! of tidy.
+//!
+//! `secret_id_ttl_histogram` walks the secret_ids of a scope and sorts each live one into the first
+//! bucket whose bound its remaining TTL does not exceed. The bounds come from
+//! `AppRoleConfig::secret_id_ttl_histogram_bucket_secs`, and a last bucket, bounded by
+//! `Duration::MAX`, collects the TTLs above the largest bound along with the secret_ids that never
+//! expire. The expired secret_ids are left out, and nothing is written: corrupt entries are skipped
+//! whatever the `OnCorrupt` policy, rather than quarantined.
+
+use std::time::Duration;
+
+use super::{AppRoleBackendInner, SecretIdScope};
+use crate::{errors::RvError, storage::Storage};
+
+impl AppRoleBackendInner {
+    // secret_id_ttl_histogram returns the bound of every bucket, in increasing
+    // order, with the number of live secret_ids of the scope in it.
+    pub fn secret_id_ttl_histogram(
+        &self,
+        storage: &dyn Storage,
+        scope: SecretIdScope,
+    ) -> Result<Vec<(Duration, usize)>, RvError> {
+        let mut bounds: Vec<Duration> =
+            self.config()?.secret_id_ttl_histogram_bucket_secs.iter().map(|secs| Duration::from_secs(*secs)).collect();
+        bounds.sort();
+        bounds.dedup();
+        if bounds.last() != Some(&Duration::MAX) {
+            bounds.push(Duration::MAX);
+        }
+
+        let mut histogram: Vec<(Duration, usize)> = bounds.into_iter().map(|bound| (bound, 0)).collect();
+        let now = self.clock.now();
+
+        storage.walk(scope.prefix(), &mut |key: &str| {
+            let Some((role_name_hmac, secret_id_hmac)) = key[scope.prefix().len()..].split_once('/') else {
+                return Ok(());
+            };
+
+            let lock_entry = self.secret_id_locks.get_lock(secret_id_hmac);
+            let _locked = lock_entry.read()?;
+
+            let entry = match self.get_secret_id_storage_entry(storage, scope, role_name_hmac, secret_id_hmac) {
+                Ok(Some(entry)) => entry,
+                Ok(None) => return Ok(()),
+                Err(RvError::SerdeJson { .. }) => {
+                    log::warn!("skipping corrupt approle entry, key: {}", key);
+                    return Ok(());
+                }
+                Err(err) => return Err(err),
+            };
+
+            let remaining = if entry.secret_id_ttl.is_zero() {
+                Duration::MAX
+            } else if self.is_past_expiration(entry.expiration_time, now)? {
+                return Ok(());
+            } else {
+                entry.expiration_time.duration_since(now).unwrap_or(Duration::ZERO)
+            };
+
+            if let Some(bucket) = histogram.iter_mut().find(|(bound, _)| remaining <= *bound) {
+                bucket.1 += 1;
+            }
+
+            Ok(())
+        })?;
+
+        Ok(histogram)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::SystemTime};
+
+    use super::{
+        super::{
+            config::AppRoleConfig,
+            test::{new_test_inner, register_test_secret_id},
+            validation::SecretIdStorageEntry,
+        },
+        *,
+    };
+    use crate::utils::{clock::MockClock, ttl::LeaseTtl};
+
+    #[test]
+    fn test_approle_secret_id_ttl_histogram() {
+        let clock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+
+        let (mut inner, storage) = new_test_inner("test_approle_secret_id_ttl_histogram");
+        inner.clock = clock.clone();
+        let config = AppRoleConfig { secret_id_ttl_histogram_bucket_secs: vec![3600, 60, 86400], ..Default::default() };
+        assert!(inner.set_config(config).is_ok());
+        let scope = SecretIdScope::Global;
+
+        let register = |role_name: &str, secret_id: &str, ttl: u64| {
+            let mut entry = SecretIdStorageEntry { secret_id_ttl: LeaseTtl::from_secs(ttl), ..Default::default() };
+            register_test_secret_id(&inner, storage.as_ref(), role_name, secret_id, scope, &mut entry);
+        };
+
+        // Two secret_ids under a minute, across two roles, one under an hour,
+        // three under a day, and two beyond the largest bound, one of which
+        // never expires
+        register("role1", "secret1", 30);
+        register("role2", "secret2", 60);
+        register("role1", "secret3", 1800);
+        for (i, ttl) in [7200, 43200, 86400].iter().enumerate() {
+            register("role2", &format!("secret{}", i + 4), *ttl);
+        }
+        register("role1", "secret7", 30 * 86400);
+        register("role1", "secret8", 0);
+
+        let histogram = inner.secret_id_ttl_histogram(storage.as_ref(), scope).unwrap();
+        let expected = vec![
+            (Duration::from_secs(60), 2),
+            (Duration::from_secs(3600), 1),
+            (Duration::from_secs(86400), 3),
+            (Duration::MAX, 2),
+        ];
+        assert_eq!(histogram, expected);
+
+        // The histogram is read-only, and the local scope has none of them
+        assert_eq!(inner.secret_id_ttl_histogram(storage.as_ref(), scope).unwrap(), expected);
+        let local = inner.secret_id_ttl_histogram(storage.as_ref(), SecretIdScope::Local).unwrap();
+        assert!(local.iter().all(|(_, count)| *count == 0));
+
+        // The expired secret_ids drop out and the others move down the buckets
+        clock.advance(Duration::from_secs(3000));
+        let histogram = inner.secret_id_ttl_histogram(storage.as_ref(), scope).unwrap();
+        assert_eq!(
+            histogram,
+            vec![
+                (Duration::from_secs(60), 0),
+                (Duration::from_secs(3600), 0),
+                (Duration::from_secs(86400), 3),
+                (Duration::MAX, 2),
+            ]
+        );
+    }
+}
@@ -0,0 +1,94 @@
+//! Text encodings of the HMACs.
+//!
+//! approle indexes its entries with hex encoded HMACs, see `create_hmac`. Services exchanging HMACs
+//! with it may expect another encoding, so `create_hmac_encoded` produces the HMAC in a chosen
+//! `HmacEncoding`, and `verify_hmac_encoded` checks one given in that encoding.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use openssl::memcmp;
+
+use super::validation::hmac_sha256;
+use crate::errors::RvError;
+
+// HmacEncoding is the text encoding of an HMAC. Hex is what approle indexes
+// its entries with, Base64 (url-safe, without padding) matches the services
+// that exchange HMACs in that form.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HmacEncoding {
+    #[default]
+    Hex,
+    Base64,
+}
+
+impl HmacEncoding {
+    pub fn encode(&self, hmac: &[u8]) -> String {
+        match self {
+            HmacEncoding::Hex => hex::encode(hmac),
+            HmacEncoding::Base64 => URL_SAFE_NO_PAD.encode(hmac),
+        }
+    }
+
+    pub fn decode(&self, hmac: &str) -> Result<Vec<u8>, RvError> {
+        match self {
+            HmacEncoding::Hex => Ok(hex::decode(hmac)?),
+            HmacEncoding::Base64 => Ok(URL_SAFE_NO_PAD.decode(hmac)?),
+        }
+    }
+}
+
+// create_hmac_encoded computes the HMAC-SHA256 of value in the given
+// encoding. create_hmac is the HmacEncoding::Hex form of it.
+pub fn create_hmac_encoded(key: &str, value: &str, enc: HmacEncoding) -> Result<String, RvError> {
+    Ok(enc.encode(&hmac_sha256(key, value)?))
+}
+
+// verify_hmac_encoded reports whether hmac, in the given encoding, is the
+// HMAC of value. The comparison is on the decoded bytes, in constant time, so
+// it neither depends on the case of a hex HMAC nor leaks how much of it
+// matches. An HMAC that is not valid in the encoding is an error.
+pub fn verify_hmac_encoded(key: &str, value: &str, hmac: &str, enc: HmacEncoding) -> Result<bool, RvError> {
+    let expected = enc.decode(hmac)?;
+    let actual = hmac_sha256(key, value)?;
+    Ok(expected.len() == actual.len() && memcmp::eq(&expected, &actual))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        super::{validation::create_hmac, HMAC_INPUT_LEN_MAX},
+        *,
+    };
+
+    #[test]
+    fn test_approle_create_hmac_encoded() {
+        let hex_hmac = create_hmac_encoded("testhmackey", "role1", HmacEncoding::Hex).unwrap();
+        let base64_hmac = create_hmac_encoded("testhmackey", "role1", HmacEncoding::Base64).unwrap();
+
+        // create_hmac stays the hex form, the base64 one is url-safe without padding
+        assert_eq!(hex_hmac, create_hmac("testhmackey", "role1").unwrap());
+        assert_eq!(hex_hmac.len(), 64);
+        assert_eq!(base64_hmac.len(), 43);
+        assert!(base64_hmac.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+
+        // Both encodings decode to the same HMAC
+        let hex_bytes = HmacEncoding::Hex.decode(&hex_hmac).unwrap();
+        assert_eq!(hex_bytes.len(), 32);
+        assert_eq!(hex_bytes, HmacEncoding::Base64.decode(&base64_hmac).unwrap());
+        assert_eq!(HmacEncoding::Base64.encode(&hex_bytes), base64_hmac);
+
+        // The verification decodes with the given encoding, regardless of the case of a hex HMAC
+        assert!(verify_hmac_encoded("testhmackey", "role1", &hex_hmac, HmacEncoding::Hex).unwrap());
+        assert!(verify_hmac_encoded("testhmackey", "role1", &hex_hmac.to_uppercase(), HmacEncoding::Hex).unwrap());
+        assert!(verify_hmac_encoded("testhmackey", "role1", &base64_hmac, HmacEncoding::Base64).unwrap());
+        assert!(!verify_hmac_encoded("testhmackey", "role2", &base64_hmac, HmacEncoding::Base64).unwrap());
+        assert!(!verify_hmac_encoded("testhmackey", "role1", &hex_hmac[..32], HmacEncoding::Hex).unwrap());
+        assert!(verify_hmac_encoded("testhmackey", "role1", &base64_hmac, HmacEncoding::Hex).is_err());
+        assert!(
+            verify_hmac_encoded("testhmackey", "role1", &format!("{}=", base64_hmac), HmacEncoding::Base64).is_err()
+        );
+
+        // The key and the length of the value are checked whatever the encoding
+        assert!(create_hmac_encoded("", "role1", HmacEncoding::Base64).is_err());
+        assert!(create_hmac_encoded("testhmackey", &"a".repeat(HMAC_INPUT_LEN_MAX + 1), HmacEncoding::Base64).is_err());
+    }
+}
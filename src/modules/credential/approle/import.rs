@@ -0,0 +1,233 @@
+//! Bulk import of secret_ids, for migrating them from another system.
+//!
+//! Each imported secret_id goes through `register_secret_id_entry`, so it is locked, counted and
+//! given an accessor exactly like one created through the API. Accessors are always newly
+//! generated, the ones of the source system are not carried over. The secret_ids already
+//! registered are skipped, which makes re-running an interrupted import safe.
+
+use std::collections::HashMap;
+
+use super::{
+    config::AppRoleConfig,
+    validation::{validate_secret_id_metadata, SecretIdStorageEntry},
+    AppRoleBackendInner, SecretIdScope,
+};
+use crate::{
+    errors::RvError,
+    storage::Storage,
+    utils::{cidr::validate_cidrs, ttl::LeaseTtl},
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct SecretIdImport {
+    pub secret_id: String,
+    pub secret_id_num_uses: i64,
+    pub secret_id_ttl: LeaseTtl,
+    pub metadata: HashMap<String, String>,
+    pub cidr_list: Vec<String>,
+    pub token_cidr_list: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImportOutcome {
+    // The secret_id was registered under this accessor
+    Imported(String),
+    // The secret_id was already registered and was left untouched
+    Duplicate,
+    // The secret_id could not be registered, with the reason
+    Failed(String),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    // One outcome per imported entry, in the order they were given
+    pub outcomes: Vec<ImportOutcome>,
+}
+
+impl ImportReport {
+    pub fn imported(&self) -> usize {
+        self.outcomes.iter().filter(|outcome| matches!(outcome, ImportOutcome::Imported(_))).count()
+    }
+
+    pub fn duplicates(&self) -> usize {
+        self.outcomes.iter().filter(|outcome| matches!(outcome, ImportOutcome::Duplicate)).count()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.outcomes.iter().filter(|outcome| matches!(outcome, ImportOutcome::Failed(_))).count()
+    }
+}
+
+impl AppRoleBackendInner {
+    // import_secret_ids registers the given secret_ids for the role. An entry
+    // failing does not stop the import, its outcome is reported instead. The
+    // role's secret_id count limit is not enforced, migrated secret_ids were
+    // already live in the source system. Only storage and lock errors abort
+    // the whole import.
+    pub fn import_secret_ids(
+        &self,
+        storage: &dyn Storage,
+        role_name: &str,
+        hmac_key: &str,
+        scope: SecretIdScope,
+        entries: Vec<SecretIdImport>,
+    ) -> Result<ImportReport, RvError> {
+        let config = self.config()?;
+        let mut report = ImportReport { outcomes: Vec::with_capacity(entries.len()) };
+
+        for import in entries.into_iter() {
+            if let Err(err) = validate_import(&import, &config) {
+                report.outcomes.push(ImportOutcome::Failed(err.to_string()));
+                continue;
+            }
+
+            let mut secret_entry = SecretIdStorageEntry {
+                secret_id_num_uses: import.secret_id_num_uses,
+                secret_id_ttl: import.secret_id_ttl,
+                metadata: import.metadata,
+                cidr_list: import.cidr_list,
+                token_cidr_list: import.token_cidr_list,
+                ..Default::default()
+            };
+
+            let outcome = match self.register_secret_id_entry(
+                storage,
+                role_name,
+                &import.secret_id,
+                hmac_key,
+                scope,
+                0,
+                &mut secret_entry,
+            ) {
+                Ok(()) => ImportOutcome::Imported(secret_entry.secret_id_accessor),
+                Err(RvError::ErrSecretIdAlreadyExists { .. }) => ImportOutcome::Duplicate,
+                Err(RvError::ErrResponse(msg)) => ImportOutcome::Failed(msg),
+                Err(err) => return Err(err),
+            };
+            report.outcomes.push(outcome);
+        }
+
+        log::info!(
+            "imported secret IDs, role: {}, imported: {}, duplicates: {}, failed: {}",
+            role_name,
+            report.imported(),
+            report.duplicates(),
+            report.failed()
+        );
+
+        Ok(report)
+    }
+}
+
+fn validate_import(import: &SecretIdImport, config: &AppRoleConfig) -> Result<(), RvError> {
+    if import.secret_id.is_empty() {
+        return Err(RvError::ErrResponse("missing secret_id".to_string()));
+    }
+
+    if import.secret_id_num_uses < 0 {
+        return Err(RvError::ErrResponse("num_uses cannot be negative".to_string()));
+    }
+
+    validate_secret_id_metadata(&import.metadata, config)?;
+
+    for cidrs in [&import.cidr_list, &import.token_cidr_list] {
+        let cidrs: Vec<&str> = cidrs.iter().map(String::as_str).collect();
+        if !cidrs.is_empty() {
+            validate_cidrs(&cidrs)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        super::{
+            test::{new_test_inner, register_test_secret_id},
+            validation::create_hmac,
+        },
+        *,
+    };
+
+    #[test]
+    fn test_approle_import_secret_ids() {
+        let (inner, storage) = new_test_inner("test_approle_import_secret_ids");
+
+        let import = |secret_id: &str| SecretIdImport {
+            secret_id: secret_id.to_string(),
+            secret_id_num_uses: 5,
+            secret_id_ttl: LeaseTtl::from_secs(600),
+            metadata: HashMap::from([("source".to_string(), "legacy".to_string())]),
+            cidr_list: vec!["127.0.0.1/32".to_string()],
+            ..Default::default()
+        };
+
+        // secret2 already exists before the import
+        let mut existing = SecretIdStorageEntry { secret_id_ttl: LeaseTtl::from_secs(600), ..Default::default() };
+        register_test_secret_id(&inner, storage.as_ref(), "role1", "secret2", SecretIdScope::Global, &mut existing);
+
+        let invalid = SecretIdImport { cidr_list: vec!["not-a-cidr".to_string()], ..import("secret4") };
+        let report = inner
+            .import_secret_ids(
+                storage.as_ref(),
+                "role1",
+                "testhmackey",
+                SecretIdScope::Global,
+                vec![import("secret1"), import("secret2"), import("secret3"), invalid],
+            )
+            .unwrap();
+
+        assert_eq!(report.outcomes.len(), 4);
+        assert!(matches!(report.outcomes[0], ImportOutcome::Imported(_)));
+        assert_eq!(report.outcomes[1], ImportOutcome::Duplicate);
+        assert!(matches!(report.outcomes[2], ImportOutcome::Imported(_)));
+        assert!(matches!(report.outcomes[3], ImportOutcome::Failed(_)));
+        assert_eq!((report.imported(), report.duplicates(), report.failed()), (2, 1, 1));
+
+        // The imported secret_id keeps its settings and has a working accessor
+        let ImportOutcome::Imported(ref accessor) = report.outcomes[0] else { unreachable!() };
+        let role_name_hmac = create_hmac("testhmackey", "role1").unwrap();
+        let secret_id_hmac = create_hmac("testhmackey", "secret1").unwrap();
+        let entry = inner
+            .get_secret_id_storage_entry(storage.as_ref(), SecretIdScope::Global, &role_name_hmac, &secret_id_hmac)
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.secret_id_accessor, *accessor);
+        assert_eq!(entry.secret_id_num_uses, 5);
+        assert_eq!(entry.metadata.get("source").map(String::as_str), Some("legacy"));
+        assert_eq!(entry.cidr_list, vec!["127.0.0.1/32".to_string()]);
+        assert!(inner
+            .resolve_accessor(storage.as_ref(), accessor, SecretIdScope::Global, &role_name_hmac)
+            .unwrap()
+            .is_some());
+
+        // The duplicate was left untouched and the invalid entry was not written
+        let entry = inner
+            .get_secret_id_storage_entry(
+                storage.as_ref(),
+                SecretIdScope::Global,
+                &role_name_hmac,
+                &create_hmac("testhmackey", "secret2").unwrap(),
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.secret_id_accessor, existing.secret_id_accessor);
+        assert_eq!(entry.secret_id_num_uses, 0);
+        assert!(inner
+            .get_secret_id_storage_entry(
+                storage.as_ref(),
+                SecretIdScope::Global,
+                &role_name_hmac,
+                &create_hmac("testhmackey", "secret4").unwrap(),
+            )
+            .unwrap()
+            .is_none());
+
+        // Running the import again only finds duplicates
+        let report = inner
+            .import_secret_ids(storage.as_ref(), "role1", "testhmackey", SecretIdScope::Global, vec![import("secret1")])
+            .unwrap();
+        assert_eq!(report.outcomes, vec![ImportOutcome::Duplicate]);
+    }
+}
@@ -0,0 +1,255 @@
+//! Integrity scan of the approle secret_id storage, for operators to run from a CLI or an HTTP
+//! endpoint.
+//!
+//! `scan_approle_integrity` goes over every secret_id and accessor entry of a scope, checks that
+//! each one parses and that the secret_ids and accessors point at each other, as `reconcile` does,
+//! and counts the secret_ids that expired but were not tidied yet. The scan is read-only. With
+//! `repair` set, `scan_approle_integrity_with` then quarantines the corrupt entries under the
+//! corrupt/ prefix and runs `reconcile_with`, recreating the missing accessors. Expired secret_ids
+//! are left to tidy.
+
+use std::collections::HashSet;
+
+use serde::Serialize;
+
+use super::{validation::SecretIdAccessorStorageEntry, AppRoleBackendInner, SecretIdScope};
+use crate::{errors::RvError, storage::Storage};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct IntegrityReport {
+    // The number of secret_id and accessor entries scanned
+    pub secret_ids: usize,
+    pub accessors: usize,
+    // The salted accessors whose secret_id does not exist
+    pub orphaned_accessors: Vec<String>,
+    // The HMACs of the secret_ids without a matching accessor
+    pub orphaned_secret_ids: Vec<String>,
+    // The storage keys of the entries that can not be deserialized
+    pub corrupt_entries: Vec<String>,
+    // The secret_ids past their expiration that tidy has not deleted yet
+    pub expired_secret_ids: usize,
+    // Whether the defects found were repaired
+    pub repaired: bool,
+}
+
+impl IntegrityReport {
+    // is_healthy tells whether the scan found no defect. Expired secret_ids
+    // are not defects, tidy deletes them in time.
+    pub fn is_healthy(&self) -> bool {
+        self.orphaned_accessors.is_empty() && self.orphaned_secret_ids.is_empty() && self.corrupt_entries.is_empty()
+    }
+}
+
+impl AppRoleBackendInner {
+    // scan_approle_integrity checks the secret_ids of the given scope and their
+    // accessors without changing anything.
+    pub fn scan_approle_integrity(
+        &self,
+        storage: &dyn Storage,
+        scope: SecretIdScope,
+    ) -> Result<IntegrityReport, RvError> {
+        self.scan_approle_integrity_with(storage, scope, false)
+    }
+
+    // scan_approle_integrity_with is scan_approle_integrity, additionally
+    // repairing the defects found when repair is set. The report describes the
+    // storage as it was before the repair.
+    pub fn scan_approle_integrity_with(
+        &self,
+        storage: &dyn Storage,
+        scope: SecretIdScope,
+        repair: bool,
+    ) -> Result<IntegrityReport, RvError> {
+        let mut report = IntegrityReport::default();
+        let now = self.clock.now();
+
+        let mut live_secret_id_hmacs: HashSet<String> = HashSet::new();
+        for item in storage.list(scope.prefix())?.iter() {
+            let role_name_hmac = item.trim_end_matches('/');
+            let key = format!("{}{}/", scope.prefix(), role_name_hmac);
+            for secret_id_hmac in storage.list(&key)?.iter() {
+                live_secret_id_hmacs.insert(secret_id_hmac.clone());
+                report.secret_ids += 1;
+
+                let lock_entry = self.secret_id_locks.get_lock(secret_id_hmac);
+                let _locked = lock_entry.read()?;
+
+                let entry = match self.get_secret_id_storage_entry(storage, scope, role_name_hmac, secret_id_hmac) {
+                    Ok(Some(entry)) => entry,
+                    Ok(None) => continue,
+                    Err(RvError::SerdeJson { .. }) => {
+                        report.corrupt_entries.push(format!("{}{}", key, secret_id_hmac));
+                        continue;
+                    }
+                    Err(err) => return Err(err),
+                };
+
+                if !entry.secret_id_ttl.is_zero() && self.is_past_expiration(entry.expiration_time, now)? {
+                    report.expired_secret_ids += 1;
+                }
+
+                // A corrupt accessor does not point back at its secret_id either,
+                // it is reported along with the other corrupt entries below
+                let accessor_entry = if entry.secret_id_accessor.is_empty() {
+                    None
+                } else {
+                    match self.get_secret_id_accessor_entry(storage, &entry.secret_id_accessor, scope) {
+                        Ok(accessor_entry) => accessor_entry,
+                        Err(RvError::SerdeJson { .. }) => None,
+                        Err(err) => return Err(err),
+                    }
+                };
+                if !accessor_entry.is_some_and(|accessor_entry| accessor_entry.secret_id_hmac == *secret_id_hmac) {
+                    report.orphaned_secret_ids.push(secret_id_hmac.clone());
+                }
+            }
+        }
+
+        let accessor_prefix = scope.accessor_prefix();
+        for accessor_hash in storage.list(accessor_prefix)?.iter() {
+            let entry_index = format!("{}{}", accessor_prefix, accessor_hash);
+            let Some(storage_entry) = storage.get(&entry_index)? else {
+                continue;
+            };
+            report.accessors += 1;
+
+            let Ok(accessor_entry) = serde_json::from_slice::<SecretIdAccessorStorageEntry>(&storage_entry.value)
+            else {
+                report.corrupt_entries.push(entry_index);
+                continue;
+            };
+
+            if live_secret_id_hmacs.contains(&accessor_entry.secret_id_hmac) {
+                continue;
+            }
+
+            // A registration may have written the accessor after the secret_ids
+            // were listed, check again under the secret_id lock.
+            let lock_entry = self.secret_id_locks.get_lock(&accessor_entry.secret_id_hmac);
+            let _locked = lock_entry.read()?;
+
+            if !self.secret_id_exists_in_any_role(storage, scope, &accessor_entry.secret_id_hmac)? {
+                report.orphaned_accessors.push(accessor_hash.clone());
+            }
+        }
+
+        if repair && !report.is_healthy() {
+            // The corrupt entries are moved away first, so that reconcile does
+            // not trip over them whatever the on_corrupt policy is
+            for key in report.corrupt_entries.iter() {
+                let secret_id_hmac = key.rsplit('/').next().unwrap_or_default();
+                let lock_entry = self.secret_id_locks.get_lock(secret_id_hmac);
+                let _locked = lock_entry.write()?;
+                if let Some(quarantined) = self.quarantine_entry(storage, key)? {
+                    log::warn!("quarantined corrupt approle entry, key: {}, moved to: {}", key, quarantined);
+                }
+            }
+
+            self.reconcile_with(storage, scope, true)?;
+            report.repaired = true;
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use super::{
+        super::{
+            test::{new_test_inner, register_test_secret_id},
+            validation::{accessor_entry_index, create_hmac, secret_id_entry_index, SecretIdStorageEntry},
+            CORRUPT_PREFIX,
+        },
+        *,
+    };
+    use crate::{
+        storage::StorageEntry,
+        utils::{clock::MockClock, ttl::LeaseTtl},
+    };
+
+    #[test]
+    fn test_approle_scan_integrity() {
+        let clock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+
+        let (mut inner, storage) = new_test_inner("test_approle_scan_integrity");
+        inner.clock = clock.clone();
+        let scope = SecretIdScope::Global;
+
+        let register = |secret_id: &str, ttl: u64| -> SecretIdStorageEntry {
+            let mut entry = SecretIdStorageEntry { secret_id_ttl: LeaseTtl::from_secs(ttl), ..Default::default() };
+            register_test_secret_id(&inner, storage.as_ref(), "role1", secret_id, scope, &mut entry);
+            entry
+        };
+
+        let role_name_hmac = create_hmac("testhmackey", "role1").unwrap();
+        let secret_id_hmac = |secret_id: &str| create_hmac("testhmackey", secret_id).unwrap();
+
+        register("secret1", 600);
+        let secret2 = register("secret2", 600);
+        let secret3 = register("secret3", 600);
+        register("secret4", 60);
+
+        let report = inner.scan_approle_integrity(storage.as_ref(), scope).unwrap();
+        assert!(report.is_healthy());
+        assert_eq!((report.secret_ids, report.accessors, report.expired_secret_ids), (4, 4, 0));
+
+        // secret2 loses its secret_id entry, secret3 its accessor, secret4
+        // expires, and a corrupt secret_id and a corrupt accessor show up
+        let secret2_index = secret_id_entry_index(scope, &role_name_hmac, &secret_id_hmac("secret2")).unwrap();
+        assert!(storage.delete(&secret2_index).is_ok());
+        let (secret3_accessor_index, _) = inner.accessor_index(&secret3.secret_id_accessor, scope).unwrap();
+        assert!(storage.delete(&secret3_accessor_index).is_ok());
+        clock.advance(Duration::from_secs(61));
+
+        let corrupt_secret_id = secret_id_entry_index(scope, &role_name_hmac, "corrupthmac").unwrap();
+        let corrupt_accessor = accessor_entry_index(scope, "corruptsaltid").unwrap();
+        for key in [&corrupt_secret_id, &corrupt_accessor] {
+            assert!(storage.put(&StorageEntry { key: key.clone(), value: b"{not json".to_vec() }).is_ok());
+        }
+
+        let (secret2_accessor_index, _) = inner.accessor_index(&secret2.secret_id_accessor, scope).unwrap();
+        let check = |report: &IntegrityReport| {
+            assert!(!report.is_healthy());
+            assert_eq!((report.secret_ids, report.accessors), (4, 4));
+            assert_eq!(report.orphaned_accessors.len(), 1);
+            assert!(secret2_accessor_index.ends_with(&report.orphaned_accessors[0]));
+            assert_eq!(report.orphaned_secret_ids, vec![secret_id_hmac("secret3")]);
+            let mut corrupt_entries = report.corrupt_entries.clone();
+            corrupt_entries.sort();
+            let mut expected = vec![corrupt_accessor.clone(), corrupt_secret_id.clone()];
+            expected.sort();
+            assert_eq!(corrupt_entries, expected);
+            assert_eq!(report.expired_secret_ids, 1);
+        };
+
+        // The scan is read-only, scanning again finds the same defects
+        let report = inner.scan_approle_integrity(storage.as_ref(), scope).unwrap();
+        check(&report);
+        assert!(!report.repaired);
+        assert_eq!(inner.scan_approle_integrity(storage.as_ref(), scope).unwrap(), report);
+        assert!(storage.get(&secret2_accessor_index).unwrap().is_some());
+        assert!(storage.get(&corrupt_secret_id).unwrap().is_some());
+
+        // Repairing reports what it found, then fixes it all but the expiry
+        let report = inner.scan_approle_integrity_with(storage.as_ref(), scope, true).unwrap();
+        check(&report);
+        assert!(report.repaired);
+
+        let report = inner.scan_approle_integrity(storage.as_ref(), scope).unwrap();
+        assert!(report.is_healthy());
+        assert_eq!((report.secret_ids, report.accessors, report.expired_secret_ids), (3, 3, 1));
+        assert!(storage.get(&secret2_accessor_index).unwrap().is_none());
+        assert!(storage.get(&format!("{}{}", CORRUPT_PREFIX, corrupt_secret_id)).unwrap().is_some());
+        assert!(storage.get(&format!("{}{}", CORRUPT_PREFIX, corrupt_accessor)).unwrap().is_some());
+
+        // The report is meant to be handed to a CLI or an HTTP client as is
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["expired_secret_ids"], 1);
+    }
+}
@@ -0,0 +1,152 @@
+//! Classification of the approle storage keys, for tooling walking the keyspace.
+//!
+//! The keys of a secret_id, of its accessor or of a role can not be told apart by their value
+//! without knowing its type. `classify_key` sorts a key, relative to the approle mount, into a
+//! `KeyKind` from its prefix and its shape, and `list_classified` walks a prefix and returns every
+//! key found with its kind. A key under a known prefix but with an unexpected shape, such as a
+//! secret_id entry not nested under a role HMAC, is `Unknown`, as are the keys under no known
+//! prefix.
+
+use serde::Serialize;
+
+use super::{
+    CORRUPT_PREFIX, METADATA_INDEX_PREFIX, MIGRATION_PREFIX, ROLE_ID_PREFIX, SECRET_ID_ACCESSOR_LOCAL_PREFIX,
+    SECRET_ID_ACCESSOR_PREFIX, SECRET_ID_COUNT_PREFIX, SECRET_ID_LOCAL_PREFIX, SECRET_ID_PREFIX,
+};
+use crate::{
+    errors::RvError,
+    storage::{wal::WAL_PREFIX, Storage},
+};
+
+const ROLE_PREFIX: &str = "role/";
+
+// The prefixes of the backend's own bookkeeping: counters, quarantined
+// entries, indices, migration markers and write-ahead log records
+const INTERNAL_PREFIXES: [&str; 5] =
+    [SECRET_ID_COUNT_PREFIX, CORRUPT_PREFIX, METADATA_INDEX_PREFIX, MIGRATION_PREFIX, WAL_PREFIX];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub enum KeyKind {
+    // role/<role_name>
+    RoleConfig,
+    // secret_id/<role_name_hmac>/<secret_id_hmac>, or under secret_id_local/
+    SecretId,
+    // accessor/<salted accessor>, or under accessor_local/
+    Accessor,
+    // role_id/<salted role_id>
+    RoleIdIndex,
+    // Any key under one of the INTERNAL_PREFIXES
+    Internal,
+    Unknown,
+}
+
+// classify_key returns the kind of the entry stored at key.
+pub fn classify_key(key: &str) -> KeyKind {
+    let segments = |prefix: &str| -> Option<Vec<&str>> {
+        let rest = key.strip_prefix(prefix)?;
+        let segments: Vec<&str> = rest.split('/').collect();
+        if segments.iter().any(|segment| segment.is_empty()) {
+            return None;
+        }
+        Some(segments)
+    };
+    let has_segments = |prefix: &str, count: usize| segments(prefix).map_or(false, |segments| segments.len() == count);
+
+    if INTERNAL_PREFIXES.iter().any(|prefix| key.starts_with(prefix)) {
+        KeyKind::Internal
+    } else if has_segments(ROLE_PREFIX, 1) {
+        KeyKind::RoleConfig
+    } else if has_segments(SECRET_ID_PREFIX, 2) || has_segments(SECRET_ID_LOCAL_PREFIX, 2) {
+        KeyKind::SecretId
+    } else if has_segments(SECRET_ID_ACCESSOR_PREFIX, 1) || has_segments(SECRET_ID_ACCESSOR_LOCAL_PREFIX, 1) {
+        KeyKind::Accessor
+    } else if has_segments(ROLE_ID_PREFIX, 1) {
+        KeyKind::RoleIdIndex
+    } else {
+        KeyKind::Unknown
+    }
+}
+
+// list_classified returns every key under prefix, at any depth, with its
+// kind, sorted by key.
+pub fn list_classified(storage: &dyn Storage, prefix: &str) -> Result<Vec<(String, KeyKind)>, RvError> {
+    let mut keys = Vec::new();
+    storage.walk(prefix, &mut |key: &str| {
+        keys.push((key.to_string(), classify_key(key)));
+        Ok(())
+    })?;
+
+    keys.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::{super::test::generate_secret_id, *};
+    use crate::test_utils::{test_mount_auth_api, test_rusty_vault_init, test_write_api};
+
+    #[test]
+    fn test_approle_classify_key() {
+        let cases = [
+            ("role/role1", KeyKind::RoleConfig),
+            ("role/", KeyKind::Unknown),
+            ("role/role1/extra", KeyKind::Unknown),
+            ("secret_id/rolehmac/secretidhmac", KeyKind::SecretId),
+            ("secret_id_local/rolehmac/secretidhmac", KeyKind::SecretId),
+            ("secret_id/rolehmac", KeyKind::Unknown),
+            ("secret_id/rolehmac/", KeyKind::Unknown),
+            ("secret_id/rolehmac/secretidhmac/extra", KeyKind::Unknown),
+            ("accessor/saltedaccessor", KeyKind::Accessor),
+            ("accessor_local/saltedaccessor", KeyKind::Accessor),
+            ("accessor/saltedaccessor/extra", KeyKind::Unknown),
+            ("role_id/saltedroleid", KeyKind::RoleIdIndex),
+            ("role_id/", KeyKind::Unknown),
+            ("secret_id_count/rolehmac", KeyKind::Internal),
+            ("corrupt/secret_id/rolehmac/secretidhmac", KeyKind::Internal),
+            ("metadata_index/secret_id/rolehmac/env/prod/secretidhmac", KeyKind::Internal),
+            ("migration/secret_id_expiration", KeyKind::Internal),
+            ("wal/record", KeyKind::Internal),
+            ("salt", KeyKind::Unknown),
+            ("roles/role1", KeyKind::Unknown),
+            ("", KeyKind::Unknown),
+        ];
+
+        for (key, kind) in cases.iter() {
+            assert_eq!(classify_key(key), *kind, "key: {}", key);
+        }
+    }
+
+    #[maybe_async::test(feature = "sync_handler", async(all(not(feature = "sync_handler")), tokio::test))]
+    async fn test_approle_list_classified() {
+        let (root_token, core) = test_rusty_vault_init("test_approle_list_classified");
+        let core = core.read().unwrap();
+
+        // Mount approle auth to path: auth/approle
+        test_mount_auth_api(&core, &root_token, "approle", "approle").await;
+
+        let resp = test_write_api(&core, &root_token, "auth/approle/role/role1", true, None).await;
+        assert!(resp.is_ok());
+        let _ = generate_secret_id(&core, &root_token, "approle", "role1").await;
+
+        let storage: Arc<dyn Storage> = core.router.matching_view("auth/approle/").unwrap().unwrap();
+        let keys = list_classified(storage.as_ref(), "").unwrap();
+
+        // Every key of the mount is recognized
+        let count = |kind: KeyKind| keys.iter().filter(|(_, key_kind)| *key_kind == kind).count();
+        assert_eq!(count(KeyKind::RoleConfig), 1);
+        assert_eq!(count(KeyKind::RoleIdIndex), 1);
+        assert_eq!(count(KeyKind::SecretId), 1);
+        assert_eq!(count(KeyKind::Accessor), 1);
+        assert_eq!(count(KeyKind::Unknown), 0);
+        assert!(keys.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+        // A prefix narrows the listing down
+        assert_eq!(
+            list_classified(storage.as_ref(), "role/").unwrap(),
+            vec![("role/role1".to_string(), KeyKind::RoleConfig)]
+        );
+        assert!(list_classified(storage.as_ref(), "secret_id_local/").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,182 @@
+//! Reverse index from the metadata of the secret_ids to the secret_ids carrying it.
+//!
+//! Every `key=value` pair of the metadata of a secret_id gets an empty marker entry at
+//! `metadata_index/<secret_id prefix><role_name_hmac>/<pair hash>/<secret_id_hmac>`, so that the
+//! secret_ids of a role carrying a pair are one `list` away rather than a scan of all the
+//! secret_ids of the role. The pairs are salted and hashed like the accessors, the metadata does
+//! not show in the storage keys.
+//!
+//! The markers of a new secret_id are written under the same write-ahead log as its entry. When a
+//! secret_id is deleted or its metadata changes, the markers are removed after the entry is
+//! written, so a failure in between leaves stale markers behind, never missing ones. Lookups check
+//! every secret_id they find against its entry, and `rebuild_metadata_index`, run by `reconcile`,
+//! brings the index of a scope back in line with the secret_id entries.
+
+use std::collections::HashMap;
+
+use super::{AppRoleBackendInner, SecretIdScope, METADATA_INDEX_PREFIX};
+use crate::{
+    errors::RvError,
+    storage::{Storage, StorageEntry},
+};
+
+// metadata_index_prefix returns the prefix of the index of a role.
+pub fn metadata_index_prefix(scope: SecretIdScope, role_name_hmac: &str) -> String {
+    format!("{}{}{}/", METADATA_INDEX_PREFIX, scope.prefix(), role_name_hmac)
+}
+
+impl AppRoleBackendInner {
+    // metadata_pair_hash returns the salted hash a metadata pair is indexed
+    // under. The key length is hashed along, so that a `=` in the key or the
+    // value can not make two pairs collide.
+    fn metadata_pair_hash(&self, key: &str, value: &str) -> Result<String, RvError> {
+        let salt = self.salt.read()?;
+        let Some(salt) = salt.as_ref() else {
+            return Err(RvError::ErrModuleNotInitialized("approle"));
+        };
+
+        salt.salt_id(&format!("{}:{}={}", key.len(), key, value))
+    }
+
+    fn metadata_marker_keys(
+        &self,
+        scope: SecretIdScope,
+        role_name_hmac: &str,
+        secret_id_hmac: &str,
+        metadata: &HashMap<String, String>,
+    ) -> Result<Vec<String>, RvError> {
+        let prefix = metadata_index_prefix(scope, role_name_hmac);
+        metadata
+            .iter()
+            .map(|(key, value)| Ok(format!("{}{}/{}", prefix, self.metadata_pair_hash(key, value)?, secret_id_hmac)))
+            .collect()
+    }
+
+    // index_secret_id_metadata writes the markers of every metadata pair of a
+    // secret_id. The caller should hold the secret_id lock.
+    pub fn index_secret_id_metadata(
+        &self,
+        storage: &dyn Storage,
+        scope: SecretIdScope,
+        role_name_hmac: &str,
+        secret_id_hmac: &str,
+        metadata: &HashMap<String, String>,
+    ) -> Result<(), RvError> {
+        for key in self.metadata_marker_keys(scope, role_name_hmac, secret_id_hmac, metadata)? {
+            storage.put(&StorageEntry { key, value: Vec::new() })?;
+        }
+
+        Ok(())
+    }
+
+    // unindex_secret_id_metadata deletes the markers of the given metadata pairs
+    // of a secret_id. The caller should hold the secret_id lock.
+    pub fn unindex_secret_id_metadata(
+        &self,
+        storage: &dyn Storage,
+        scope: SecretIdScope,
+        role_name_hmac: &str,
+        secret_id_hmac: &str,
+        metadata: &HashMap<String, String>,
+    ) -> Result<(), RvError> {
+        for key in self.metadata_marker_keys(scope, role_name_hmac, secret_id_hmac, metadata)? {
+            storage.delete(&key)?;
+        }
+
+        Ok(())
+    }
+
+    // indexed_secret_id_hmacs returns the HMACs of the secret_ids of the role
+    // indexed under the pair. They may include secret_ids that no longer carry
+    // it, the caller checks them against their entries.
+    pub fn indexed_secret_id_hmacs(
+        &self,
+        storage: &dyn Storage,
+        scope: SecretIdScope,
+        role_name_hmac: &str,
+        key: &str,
+        value: &str,
+    ) -> Result<Vec<String>, RvError> {
+        let prefix = metadata_index_prefix(scope, role_name_hmac);
+        storage.list(&format!("{}{}/", prefix, self.metadata_pair_hash(key, value)?))
+    }
+
+    // delete_metadata_index deletes the whole index of a role, once all of its
+    // secret_ids are gone.
+    pub fn delete_metadata_index(
+        &self,
+        storage: &dyn Storage,
+        scope: SecretIdScope,
+        role_name_hmac: &str,
+    ) -> Result<(), RvError> {
+        let mut keys = Vec::new();
+        storage.walk(&metadata_index_prefix(scope, role_name_hmac), &mut |key: &str| {
+            keys.push(key.to_string());
+            Ok(())
+        })?;
+
+        storage.delete_batch(&keys)
+    }
+
+    // rebuild_metadata_index writes the markers missing from the index of the
+    // scope and deletes the stale ones, and returns how many it fixed. A stale
+    // marker is checked again under its secret_id lock before it is deleted, as
+    // a registration may have written it after the secret_ids were read.
+    pub fn rebuild_metadata_index(&self, storage: &dyn Storage, scope: SecretIdScope) -> Result<usize, RvError> {
+        let mut repairs = 0;
+
+        for item in storage.list(scope.prefix())?.iter() {
+            let role_name_hmac = item.trim_end_matches('/');
+            let key = format!("{}{}/", scope.prefix(), role_name_hmac);
+            for secret_id_hmac in storage.list(&key)?.iter() {
+                let lock_entry = self.secret_id_locks.get_lock(secret_id_hmac);
+                let _locked = lock_entry.write()?;
+
+                let entry = match self.get_secret_id_storage_entry(storage, scope, role_name_hmac, secret_id_hmac) {
+                    Ok(Some(entry)) => entry,
+                    Ok(None) => continue,
+                    Err(err @ RvError::SerdeJson { .. }) => {
+                        self.handle_corrupt_entry(storage, &format!("{}{}", key, secret_id_hmac), err)?;
+                        continue;
+                    }
+                    Err(err) => return Err(err),
+                };
+
+                for marker in self.metadata_marker_keys(scope, role_name_hmac, secret_id_hmac, &entry.metadata)? {
+                    if !storage.exists(&marker)? {
+                        storage.put(&StorageEntry { key: marker, value: Vec::new() })?;
+                        repairs += 1;
+                    }
+                }
+            }
+        }
+
+        let index_prefix = format!("{}{}", METADATA_INDEX_PREFIX, scope.prefix());
+        for item in storage.list(&index_prefix)?.iter() {
+            let role_name_hmac = item.trim_end_matches('/');
+            let prefix = metadata_index_prefix(scope, role_name_hmac);
+            for pair_hash in storage.list(&prefix)?.iter() {
+                let pair_prefix = format!("{}{}", prefix, pair_hash);
+                for secret_id_hmac in storage.list(&pair_prefix)?.iter() {
+                    let lock_entry = self.secret_id_locks.get_lock(secret_id_hmac);
+                    let _locked = lock_entry.write()?;
+
+                    let indexed = match self.get_secret_id_storage_entry(storage, scope, role_name_hmac, secret_id_hmac)
+                    {
+                        Ok(Some(entry)) => self
+                            .metadata_marker_keys(scope, role_name_hmac, secret_id_hmac, &entry.metadata)?
+                            .contains(&format!("{}{}", pair_prefix, secret_id_hmac)),
+                        Ok(None) | Err(RvError::SerdeJson { .. }) => false,
+                        Err(err) => return Err(err),
+                    };
+                    if !indexed {
+                        storage.delete(&format!("{}{}", pair_prefix, secret_id_hmac))?;
+                        repairs += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(repairs)
+    }
+}
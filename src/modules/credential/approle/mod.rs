@@ -41,23 +41,72 @@
 //! For example, `secret_id_bound_cidrs` will only allow logins coming from IP addresses belonging
 //! to configured CIDR blocks on the AppRole.
 
-use std::sync::{atomic::AtomicU32, Arc, RwLock};
+use std::{
+    collections::HashMap,
+    sync::{atomic::AtomicU32, Arc, Mutex, RwLock},
+    time::Duration,
+};
 
 use as_any::Downcast;
 use derive_more::Deref;
-
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+use self::{
+    accessor::AccessorFormat,
+    audit::{AuditEvent, AuditSink, NoopAuditSink},
+    concurrency::RegistrationLimiter,
+    config::AppRoleConfig,
+    expiring::{ExpiringHook, ExpiringNotifier},
+    hashing::HashStrategy,
+    throttle::LoginThrottle,
+    usage::UsageCounter,
+    validation::OnCorrupt,
+};
 use crate::{
     core::Core,
     errors::RvError,
     logical::{Backend, LogicalBackend, Request, Response},
-    modules::{auth::AuthModule, Module},
+    modules::{
+        auth::{AuthModule, AUTH_ROUTER_PREFIX},
+        Module,
+    },
     new_logical_backend, new_logical_backend_internal,
-    utils::{locks::Locks, salt::Salt},
+    storage::wal::{self, WAL_PREFIX},
+    utils::{
+        clock::{Clock, SystemClock},
+        entropy::{self, EntropySource, OsEntropy},
+        locks::Locks,
+        lru::ShardedLruCache,
+        salt::Salt,
+    },
 };
 
+pub mod accessor;
+pub mod audit;
+pub mod concurrency;
+pub mod config;
+pub mod expiration_repair;
+pub mod expiring;
+pub mod generate;
+pub mod hashing;
+pub mod histogram;
+pub mod hmac_encoding;
+pub mod import;
+pub mod integrity;
+pub mod keyspace;
+pub mod metadata_index;
 pub mod path_login;
 pub mod path_role;
 pub mod path_tidy_secret_id;
+pub mod probe;
+pub mod reconcile;
+pub mod remaining_ttl;
+pub mod renew;
+pub mod role_name_repair;
+pub mod secret_id_metadata;
+pub mod throttle;
+pub mod trace;
+pub mod usage;
 pub mod validation;
 
 const HMAC_INPUT_LEN_MAX: usize = 4096;
@@ -66,6 +115,96 @@ const SECRET_ID_PREFIX: &str = "secret_id/";
 const SECRET_ID_LOCAL_PREFIX: &str = "secret_id_local/";
 const SECRET_ID_ACCESSOR_PREFIX: &str = "accessor/";
 const SECRET_ID_ACCESSOR_LOCAL_PREFIX: &str = "accessor_local/";
+const SECRET_ID_COUNT_PREFIX: &str = "secret_id_count/";
+const CORRUPT_PREFIX: &str = "corrupt/";
+const ROLE_ID_PREFIX: &str = "role_id/";
+const METADATA_INDEX_PREFIX: &str = "metadata_index/";
+const MIGRATION_PREFIX: &str = "migration/";
+
+// RESERVED_PREFIXES are the prefixes of the backend's internal indices. A
+// storage key derived from user supplied data, such as a role name, must never
+// fall under one of them, or it could overwrite an index entry.
+const RESERVED_PREFIXES: [&str; 10] = [
+    ROLE_ID_PREFIX,
+    SECRET_ID_PREFIX,
+    SECRET_ID_LOCAL_PREFIX,
+    SECRET_ID_ACCESSOR_PREFIX,
+    SECRET_ID_ACCESSOR_LOCAL_PREFIX,
+    SECRET_ID_COUNT_PREFIX,
+    CORRUPT_PREFIX,
+    METADATA_INDEX_PREFIX,
+    MIGRATION_PREFIX,
+    WAL_PREFIX,
+];
+
+// check_unreserved_key rejects a storage key computed from user supplied data
+// that falls under a reserved prefix. Keys with `.` or `..` segments are
+// rejected too, as a backend resolving them could land under one.
+fn check_unreserved_key(key: &str) -> Result<(), RvError> {
+    if key.split('/').any(|segment| segment == "." || segment == "..") {
+        return Err(RvError::ErrResponse(format!("storage key {} contains a relative path segment", key)));
+    }
+
+    if let Some(prefix) = RESERVED_PREFIXES.iter().find(|prefix| key.starts_with(*prefix)) {
+        return Err(RvError::ErrResponse(format!("storage key {} falls under the reserved prefix {}", key, prefix)));
+    }
+
+    Ok(())
+}
+
+const DEFAULT_ROLE_NAME_HMAC_CACHE_SIZE: usize = 1024;
+
+// SecretIdScope tells whether the secret_ids of a role are replicated or cluster
+// local, which decides the prefixes they and their accessors are stored under.
+// The helpers working on secret_id entries take a scope rather than a raw
+// prefix, so they can not be handed a prefix that does not exist.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SecretIdScope {
+    Global,
+    Local,
+}
+
+impl SecretIdScope {
+    pub const ALL: [SecretIdScope; 2] = [SecretIdScope::Global, SecretIdScope::Local];
+
+    // from_prefix maps the secret_id prefix recorded in a role entry back to
+    // its scope.
+    pub fn from_prefix(prefix: &str) -> Result<Self, RvError> {
+        match prefix {
+            SECRET_ID_PREFIX => Ok(SecretIdScope::Global),
+            SECRET_ID_LOCAL_PREFIX => Ok(SecretIdScope::Local),
+            _ => Err(RvError::ErrResponse(format!("invalid secret id prefix: {}", prefix))),
+        }
+    }
+
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            SecretIdScope::Global => SECRET_ID_PREFIX,
+            SecretIdScope::Local => SECRET_ID_LOCAL_PREFIX,
+        }
+    }
+
+    pub fn accessor_prefix(&self) -> &'static str {
+        match self {
+            SecretIdScope::Global => SECRET_ID_ACCESSOR_PREFIX,
+            SecretIdScope::Local => SECRET_ID_ACCESSOR_LOCAL_PREFIX,
+        }
+    }
+}
+
+// Write-ahead log records younger than this may belong to registrations still
+// in flight, so the tidy operation leaves them alone.
+const WAL_RECOVERY_MIN_AGE: Duration = Duration::from_secs(60);
+
+// The lock ordering of the backend. A lock may only be acquired while holding
+// locks of lower levels, i.e. the role lock first, then the secret_id lock,
+// then the secret_id count lock and the accessor lock last. Debug builds panic
+// on violations, see utils::locks.
+const ROLE_LOCK_LEVEL: u32 = 1;
+const ROLE_ID_LOCK_LEVEL: u32 = 2;
+const SECRET_ID_LOCK_LEVEL: u32 = 3;
+const SECRET_ID_COUNT_LOCK_LEVEL: u32 = 4;
+const SECRET_ID_ACCESSOR_LOCK_LEVEL: u32 = 5;
 
 static APPROLE_BACKEND_HELP: &str = r#"
 Any registered Role can authenticate itself with RustyVault. The credentials
@@ -88,11 +227,32 @@ pub struct AppRoleModule {
 pub struct AppRoleBackendInner {
     pub core: Arc<RwLock<Core>>,
     pub salt: RwLock<Option<Salt>>,
+    // The salt being rotated away from, only set while a rotation is in progress
+    pub previous_salt: RwLock<Option<Salt>>,
     pub role_locks: Locks,
     pub role_id_locks: Locks,
     pub secret_id_locks: Locks,
     pub secret_id_accessor_locks: Locks,
+    pub secret_id_count_locks: Locks,
     pub tidy_secret_id_cas_guard: AtomicU32,
+    pub clock: Arc<dyn Clock>,
+    // The source of the expiration jitter, replaceable with a seeded one in tests
+    pub rng: Mutex<Box<dyn RngCore + Send>>,
+    // The source of the generated secret_ids, accessors, role_ids and hmac keys
+    pub entropy: Arc<dyn EntropySource>,
+    pub accessor_format: RwLock<AccessorFormat>,
+    // How the secret_ids are indexed, and whether older indices are upgraded
+    pub hash_strategy: RwLock<HashStrategy>,
+    pub audit_sink: RwLock<Arc<dyn AuditSink>>,
+    // Told by tidy about the secret_ids about to expire, if set
+    pub expiring_notifier: RwLock<Option<Arc<ExpiringNotifier>>>,
+    pub login_throttle: LoginThrottle,
+    pub registration_limiter: RegistrationLimiter,
+    // The successful logins of each role since the last drain_usage
+    pub login_usage: UsageCounter,
+    pub role_name_hmac_cache: ShardedLruCache<(String, String), String>,
+    pub on_corrupt: RwLock<OnCorrupt>,
+    pub config: RwLock<AppRoleConfig>,
 }
 
 #[derive(Deref)]
@@ -131,11 +291,115 @@ impl AppRoleBackendInner {
         Self {
             core,
             salt: RwLock::new(None),
-            role_locks: Locks::new(),
-            role_id_locks: Locks::new(),
-            secret_id_locks: Locks::new(),
-            secret_id_accessor_locks: Locks::new(),
+            previous_salt: RwLock::new(None),
+            role_locks: Locks::with_level(ROLE_LOCK_LEVEL),
+            role_id_locks: Locks::with_level(ROLE_ID_LOCK_LEVEL),
+            secret_id_locks: Locks::with_level(SECRET_ID_LOCK_LEVEL),
+            secret_id_accessor_locks: Locks::with_level(SECRET_ID_ACCESSOR_LOCK_LEVEL),
+            secret_id_count_locks: Locks::with_level(SECRET_ID_COUNT_LOCK_LEVEL),
             tidy_secret_id_cas_guard: AtomicU32::new(0),
+            clock: Arc::new(SystemClock),
+            rng: Mutex::new(Box::new(StdRng::from_entropy())),
+            entropy: Arc::new(OsEntropy),
+            accessor_format: RwLock::new(AccessorFormat::default()),
+            hash_strategy: RwLock::new(HashStrategy::default()),
+            audit_sink: RwLock::new(Arc::new(NoopAuditSink)),
+            expiring_notifier: RwLock::new(None),
+            login_throttle: LoginThrottle::default(),
+            registration_limiter: RegistrationLimiter::default(),
+            login_usage: UsageCounter::default(),
+            role_name_hmac_cache: ShardedLruCache::new(DEFAULT_ROLE_NAME_HMAC_CACHE_SIZE),
+            on_corrupt: RwLock::new(OnCorrupt::default()),
+            config: RwLock::new(AppRoleConfig::default()),
+        }
+    }
+
+    pub fn set_audit_sink(&self, sink: Arc<dyn AuditSink>) -> Result<(), RvError> {
+        let mut audit_sink = self.audit_sink.write()?;
+        *audit_sink = sink;
+        Ok(())
+    }
+
+    // set_expiring_hook has tidy call hook for the secret_ids expiring within
+    // lead_time, once per secret_id and expiration time.
+    pub fn set_expiring_hook(&self, lead_time: Duration, hook: ExpiringHook) -> Result<(), RvError> {
+        *self.expiring_notifier.write()? = Some(Arc::new(ExpiringNotifier::new(lead_time, hook)));
+        Ok(())
+    }
+
+    // set_role_name_hmac_cache_size bounds the number of memoized role name
+    // HMACs, evicting the oldest ones if the cache shrinks.
+    pub fn set_role_name_hmac_cache_size(&self, size: usize) -> Result<(), RvError> {
+        self.role_name_hmac_cache.set_capacity(size);
+        Ok(())
+    }
+
+    // drain_usage returns the number of successful logins of each role, keyed
+    // by role name hmac, since the previous call. It is meant to be called
+    // periodically, to persist or export the counts.
+    pub fn drain_usage(&self) -> HashMap<String, u64> {
+        self.login_usage.drain_usage()
+    }
+
+    // set_accessor_format changes the format of the accessors generated from
+    // now on. The existing accessors keep working whatever their format.
+    pub fn set_accessor_format(&self, format: AccessorFormat) -> Result<(), RvError> {
+        format.validate()?;
+        *self.accessor_format.write()? = format;
+        Ok(())
+    }
+
+    pub fn set_on_corrupt(&self, policy: OnCorrupt) -> Result<(), RvError> {
+        *self.on_corrupt.write()? = policy;
+        Ok(())
+    }
+
+    // begin_salt_rotation switches to the given salt. The replaced salt is kept
+    // as the previous one, which accessor reads fall back to until the entries
+    // salted with it are rewritten and complete_salt_rotation is called.
+    pub fn begin_salt_rotation(&self, salt: Salt) -> Result<(), RvError> {
+        let mut current = self.salt.write()?;
+        let mut previous = self.previous_salt.write()?;
+        *previous = current.replace(salt);
+        Ok(())
+    }
+
+    // complete_salt_rotation drops the previous salt, accessors are only looked
+    // up under the current one afterwards.
+    pub fn complete_salt_rotation(&self) -> Result<(), RvError> {
+        *self.previous_salt.write()? = None;
+        Ok(())
+    }
+
+    // generate_uuid returns a UUID formatted identifier drawn from the
+    // backend's entropy source.
+    pub fn generate_uuid(&self) -> Result<String, RvError> {
+        entropy::generate_uuid_with(self.entropy.as_ref())
+    }
+
+    // generate_accessor returns a new secret_id accessor in the configured
+    // format, drawn from the backend's entropy source.
+    pub fn generate_accessor(&self) -> Result<String, RvError> {
+        let format = self.accessor_format.read()?.clone();
+        format.generate(self.entropy.as_ref())
+    }
+
+    pub fn config(&self) -> Result<AppRoleConfig, RvError> {
+        Ok(self.config.read()?.clone())
+    }
+
+    // set_config replaces the config, applying the settings of the login
+    // throttle to it as well.
+    pub fn set_config(&self, config: AppRoleConfig) -> Result<(), RvError> {
+        self.login_throttle.set_config(config.login_throttle_config())?;
+        *self.config.write()? = config;
+        Ok(())
+    }
+
+    pub fn audit(&self, event: AuditEvent) {
+        match self.audit_sink.read() {
+            Ok(sink) => sink.log(event),
+            Err(_) => log::error!("approle audit sink lock was poisoned, dropping event"),
         }
     }
 }
@@ -149,6 +413,45 @@ impl AppRoleModule {
     }
 }
 
+impl AppRoleBackendInner {
+    // recover_mounts rolls back, on every approle mount, the registrations a
+    // crash left in the write-ahead log, before the mounts serve requests
+    // rather than on the next tidy. Nothing is in flight yet, so the records
+    // are rolled back whatever their age. A mount failing to recover is left
+    // to tidy.
+    fn recover_mounts(&self, core: &Core) -> Result<(), RvError> {
+        let Some(module) = core.module_manager.get_module("auth") else {
+            return Ok(());
+        };
+        let auth_mod = module.read()?;
+        let Some(auth_module) = auth_mod.as_ref().downcast_ref::<AuthModule>() else {
+            return Ok(());
+        };
+
+        let router_store = auth_module.router_store.read()?;
+        let mounts = router_store.mounts.entries.read()?;
+        for mount_entry in mounts.values() {
+            let entry = mount_entry.read()?;
+            if entry.logical_type != "approle" {
+                continue;
+            }
+
+            let Some(view) = core.router.matching_view(&format!("{}{}", AUTH_ROUTER_PREFIX, entry.path))? else {
+                continue;
+            };
+            match wal::recover(view.as_ref(), self.clock.now(), Duration::ZERO) {
+                Ok(0) => {}
+                Ok(rolled_back) => {
+                    log::info!("recovered the write-ahead log, path: {}, rolled_back: {}", entry.path, rolled_back)
+                }
+                Err(err) => log::error!("error recovering the write-ahead log, path: {}, err: {}", entry.path, err),
+            }
+        }
+
+        Ok(())
+    }
+}
+
 impl Module for AppRoleModule {
     fn name(&self) -> String {
         self.name.clone()
@@ -186,6 +489,10 @@ impl Module for AppRoleModule {
         let mut approle_salt = self.backend.inner.salt.write()?;
         *approle_salt = Some(salt);
 
+        self.backend.inner.set_config(AppRoleConfig::load(core.get_system_storage())?)?;
+
+        self.backend.inner.recover_mounts(core)?;
+
         Ok(())
     }
 
@@ -209,10 +516,11 @@ impl Module for AppRoleModule {
 mod test {
     use serde_json::{json, Value};
 
-    use super::*;
+    use super::{validation::SecretIdStorageEntry, *};
     use crate::{
         core::Core,
         logical::{field::FieldTrait, Operation, Request},
+        storage::{Storage, StorageEntry},
         test_utils::{test_delete_api, test_mount_auth_api, test_read_api, test_rusty_vault_init, test_write_api},
     };
 
@@ -312,6 +620,34 @@ mod test {
         resp
     }
 
+    // new_test_inner returns a backend over a new core, whose salt is kept in
+    // the system view, along with that view, for the tests exercising the
+    // storage routines directly.
+    pub fn new_test_inner(name: &str) -> (AppRoleBackendInner, Arc<dyn Storage>) {
+        let (_root_token, core) = test_rusty_vault_init(name);
+        let storage: Arc<dyn Storage> = core.read().unwrap().get_system_view().unwrap();
+
+        let inner = AppRoleBackendInner {
+            salt: RwLock::new(Some(Salt::new(Some(storage.as_ref()), None).unwrap())),
+            ..AppRoleBackendInner::new(core)
+        };
+
+        (inner, storage)
+    }
+
+    // register_test_secret_id registers secret_id for role_name under the HMAC
+    // key testhmackey, failing the test if it is refused.
+    pub fn register_test_secret_id(
+        inner: &AppRoleBackendInner,
+        storage: &dyn Storage,
+        role_name: &str,
+        secret_id: &str,
+        scope: SecretIdScope,
+        entry: &mut SecretIdStorageEntry,
+    ) {
+        assert!(inner.register_secret_id_entry(storage, role_name, secret_id, "testhmackey", scope, 0, entry).is_ok());
+    }
+
     #[maybe_async::maybe_async]
     async fn test_approle(core: &Core, token: &str, path: &str, role_name: &str) {
         // Create a role
@@ -569,4 +905,28 @@ mod test {
 
         test_approle_role_service(&core, &root_token, "approle", "testrole").await;
     }
+
+    #[maybe_async::test(feature = "sync_handler", async(all(not(feature = "sync_handler")), tokio::test))]
+    async fn test_approle_recover_mounts() {
+        let (root_token, core) = test_rusty_vault_init("test_approle_recover_mounts");
+        let core = core.read().unwrap();
+
+        // Mount approle auth to path: auth/approle
+        test_mount_auth_api(&core, &root_token, "approle", "approle").await;
+
+        // A registration interrupted by a crash, its record just written
+        let storage = core.router.matching_view("auth/approle/").unwrap().unwrap();
+        let wal = wal::WalGuard::begin(storage.as_ref(), SystemClock.now()).unwrap();
+        let entry = StorageEntry { key: "accessor/orphan".to_string(), value: b"{}".to_vec() };
+        assert!(wal.put(&entry).is_ok());
+        drop(wal);
+
+        let module = core.module_manager.get_module("approle").unwrap();
+        let approle_mod = module.read().unwrap();
+        let approle_module = approle_mod.as_ref().downcast_ref::<AppRoleModule>().unwrap();
+        assert!(approle_module.recover_mounts(&core).is_ok());
+
+        assert!(!storage.exists("accessor/orphan").unwrap());
+        assert!(storage.list(WAL_PREFIX).unwrap().is_empty());
+    }
 }
@@ -1,7 +1,11 @@
-use std::{collections::HashMap, mem, sync::Arc, time::SystemTime};
+use std::{collections::HashMap, mem, sync::Arc, time::Duration};
+
+use tracing::Span;
 
 use super::{
+    audit::{AuditEvent, AuditEventType},
     path_role::RoleEntry,
+    trace,
     validation::{create_hmac, verify_cidr_role_secret_id_subset},
     AppRoleBackend, AppRoleBackendInner,
 };
@@ -14,6 +18,10 @@ use crate::{
     utils::cidr,
 };
 
+// How stale the last_login_time of an unlimited-use secret_id may get before a
+// login records it again
+const LAST_LOGIN_TIME_RESOLUTION: Duration = Duration::from_secs(60);
+
 impl AppRoleBackend {
     pub fn login_path(&self) -> Path {
         let approle_backend_ref = Arc::clone(&self.inner);
@@ -30,6 +38,10 @@ impl AppRoleBackend {
                     field_type: FieldType::Str,
                     required: true,
                     description: "SecretID belong to the App role"
+                },
+                "nonce": {
+                    field_type: FieldType::Str,
+                    description: "Single-use nonce. Required when the SecretID was created with 'replay_nonce_ttl'."
                 }
             },
             operations: [
@@ -53,9 +65,56 @@ endpoint."#
 
 impl AppRoleBackendInner {
     pub fn login(&self, _backend: &dyn Backend, req: &mut Request) -> Result<Option<Response>, RvError> {
+        let mut event = AuditEvent::new(AuditEventType::LoginSuccess, self.clock.now());
+
+        let ret = trace::in_span(trace::approle_span("login"), || self.login_with_audit(req, &mut event));
+        if let Err(err) = ret.as_ref() {
+            event.event_type = AuditEventType::LoginFailure;
+            event.error = Some(err.to_string());
+        }
+
+        self.audit(event);
+
+        ret
+    }
+
+    // login_with_audit consults the login throttle before attempting the login,
+    // and records the outcome of the attempt for the role_id afterwards.
+    fn login_with_audit(&self, req: &mut Request, event: &mut AuditEvent) -> Result<Option<Response>, RvError> {
         let role_id = req.get_data_as_str("role_id")?;
 
-        let role_id_entry = self.get_role_id(req, &role_id)?;
+        let now = self.clock.now();
+        self.login_throttle.check(&role_id, now)?;
+
+        let ret = self.login_role_id(req, &role_id, event);
+        match ret.as_ref() {
+            Ok(_) => {
+                self.login_throttle.reset(&role_id)?;
+                self.login_usage.record(&event.role_name_hmac);
+            }
+            // Only count the failures caused by the presented credentials, and
+            // only for the role_ids of an existing role, which the login
+            // resolved before anything else. Counting unknown role_ids would let
+            // random ones crowd the real ones out of the throttle.
+            Err(RvError::ErrResponse(_) | RvError::ErrSecretIdRoleMismatch) if !event.role_name_hmac.is_empty() => {
+                self.login_throttle.record_failure(&role_id, now)?
+            }
+            Err(_) => {}
+        }
+
+        ret
+    }
+
+    // login_role_id performs the actual login. It fills in the audit event with
+    // whatever it learns about the role and the secret_id along the way, so that
+    // failed attempts can be reported as precisely as successful ones.
+    fn login_role_id(
+        &self,
+        req: &mut Request,
+        role_id: &str,
+        event: &mut AuditEvent,
+    ) -> Result<Option<Response>, RvError> {
+        let role_id_entry = self.get_role_id(req, role_id)?;
         if role_id_entry.is_none() {
             return Err(RvError::ErrResponse("invalid role_id".to_string()));
         }
@@ -66,7 +125,7 @@ impl AppRoleBackendInner {
         let role_entry: RoleEntry;
         {
             let lock_entry = self.role_locks.get_lock(&role_name);
-            let _locked = lock_entry.lock.read()?;
+            let _locked = lock_entry.read()?;
 
             role_entry = self
                 .get_role(req, &role_id_entry.name)?
@@ -77,27 +136,58 @@ impl AppRoleBackendInner {
 
         let storage = Arc::as_ref(req.storage.as_ref().unwrap());
 
+        let role_name_hmac = self.role_name_hmac(&role_entry.hmac_key, &role_entry.name)?;
+        event.role_name_hmac.clone_from(&role_name_hmac);
+        Span::current().record("role_name_hmac", role_name_hmac.as_str());
+
+        // The source address is checked before the secret_id is used
+        if !role_entry.secret_id_bound_cidrs.is_empty() {
+            let conn = req
+                .connection
+                .as_ref()
+                .ok_or_else(|| RvError::ErrResponse("failed to get connection information".to_string()))?;
+            if conn.peer_addr.is_empty() {
+                return Err(RvError::ErrResponse("failed to get connection information".to_string()));
+            }
+
+            let bound_cidrs_ref: Vec<&str> = role_entry.secret_id_bound_cidrs.iter().map(AsRef::as_ref).collect();
+            if !cidr::ip_belongs_to_cidrs(&conn.peer_addr, &bound_cidrs_ref)? {
+                return Err(RvError::ErrResponse(format!(
+                    "source address {} unauthorized by CIDR restrictions on the secret ID",
+                    conn.peer_addr
+                )));
+            }
+        }
+
         if role_entry.bind_secret_id {
             let secret_id = req.get_data_as_str("secret_id")?;
 
-            let secret_id_hmac = create_hmac(&role_entry.hmac_key, &secret_id)?;
-            let role_name_hmac = create_hmac(&role_entry.hmac_key, &role_entry.name)?;
+            let scope = role_entry.secret_id_scope()?;
+            let secret_id_hmac = self.resolve_role_secret_id_hmac(storage, &role_entry, &role_name_hmac, &secret_id)?;
+            event.secret_id_hmac.clone_from(&secret_id_hmac);
 
-            let entry_index = format!("{}{}/{}", &role_entry.secret_id_prefix, &role_name_hmac, &secret_id_hmac);
+            let entry_index = format!("{}{}/{}", scope.prefix(), &role_name_hmac, &secret_id_hmac);
 
             let lock_entry = self.secret_id_locks.get_lock(&secret_id_hmac);
-            let locked = lock_entry.lock.read()?;
+            let locked = lock_entry.read()?;
 
             let secret_id_entry = self
-                .get_secret_id_storage_entry(storage, &role_entry.secret_id_prefix, &role_name_hmac, &secret_id_hmac)?
+                .get_secret_id_storage_entry(storage, scope, &role_name_hmac, &secret_id_hmac)?
                 .ok_or(RvError::ErrResponse("invalid secret id".to_string()))?;
+            event.secret_id_accessor.clone_from(&secret_id_entry.secret_id_accessor);
+
+            // The secret_id was found through the role of the role_id, yet it must also have been
+            // created for that role_id. Every secret_id is bound when it is registered, so only
+            // the ones written before the binding existed are unbound, and they are let through.
+            if !secret_id_entry.role_id_hmac.is_empty()
+                && secret_id_entry.role_id_hmac != create_hmac(&role_entry.hmac_key, role_id)?
+            {
+                return Err(RvError::ErrSecretIdRoleMismatch);
+            }
 
             // If a secret ID entry does not have a corresponding accessor entry, revoke the secret ID immediately
-            let accessor_entry = self.get_secret_id_accessor_entry(
-                storage,
-                &secret_id_entry.secret_id_accessor,
-                &role_entry.secret_id_prefix,
-            )?;
+            let accessor_entry =
+                self.get_secret_id_accessor_entry(storage, &secret_id_entry.secret_id_accessor, scope)?;
             if accessor_entry.is_none() {
                 if let Err(err) = storage.delete(&entry_index) {
                     return Err(RvError::ErrResponse(format!(
@@ -105,108 +195,98 @@ impl AppRoleBackendInner {
                         &secret_id_hmac, err
                     )));
                 }
+                self.unindex_secret_id_metadata(
+                    storage,
+                    scope,
+                    &role_name_hmac,
+                    &secret_id_hmac,
+                    &secret_id_entry.metadata,
+                )?;
+                self.decrement_secret_id_count(storage, &role_name_hmac)?;
 
                 return Err(RvError::ErrResponse("invalid secret_id".to_string()));
             }
 
-            if secret_id_entry.secret_id_num_uses == 0 {
-                // secret_id_num_uses will be zero only if the usage limit was not set at all, in which case,
-                // the secret_id will remain to be valid as long as it is not expired.
-
-                // Ensure that the CIDRs on the secret id are still a subset of that of role's
-                verify_cidr_role_secret_id_subset(&secret_id_entry.cidr_list, &role_entry.secret_id_bound_cidrs)?;
-
-                if !secret_id_entry.cidr_list.is_empty() {
-                    let conn = req
-                        .connection
-                        .as_ref()
-                        .ok_or_else(|| RvError::ErrResponse("failed to get connection information".to_string()))?;
-                    if conn.peer_addr.is_empty() {
-                        return Err(RvError::ErrResponse("failed to get connection information".to_string()));
-                    }
+            // The source address is checked before the entry is touched, so that a login refused by the
+            // CIDR restrictions does not use the secret_id up. Ensure that the CIDRs on the secret ID are
+            // still a subset of that of role's.
+            verify_cidr_role_secret_id_subset(&secret_id_entry.cidr_list, &role_entry.secret_id_bound_cidrs)?;
+
+            if !secret_id_entry.cidr_list.is_empty() {
+                let conn = req
+                    .connection
+                    .as_ref()
+                    .ok_or_else(|| RvError::ErrResponse("failed to get connection information".to_string()))?;
+                if conn.peer_addr.is_empty() {
+                    return Err(RvError::ErrResponse("failed to get connection information".to_string()));
+                }
 
-                    let cidr_list_ref: Vec<&str> = secret_id_entry.cidr_list.iter().map(AsRef::as_ref).collect();
-                    if !cidr::ip_belongs_to_cidrs(&conn.peer_addr, &cidr_list_ref)? {
-                        return Err(RvError::ErrResponse(format!(
-                            "source address {} unauthorized through CIDR restrictions on the secret ID",
-                            conn.peer_addr
-                        )));
-                    }
+                let cidr_list_ref: Vec<&str> = secret_id_entry.cidr_list.iter().map(AsRef::as_ref).collect();
+                if !cidr::ip_belongs_to_cidrs(&conn.peer_addr, &cidr_list_ref)? {
+                    return Err(RvError::ErrResponse(format!(
+                        "source address {} unauthorized through CIDR restrictions on the secret ID",
+                        conn.peer_addr
+                    )));
                 }
+            }
+
+            // An unlimited-use secret_id without replay protection has nothing to record but its
+            // last_login_time. If that was recorded less than LAST_LOGIN_TIME_RESOLUTION ago, the login
+            // does not take the write lock nor write the entry.
+            let now = self.clock.now();
+            let recently_recorded = secret_id_entry.last_login_time.is_some_and(|last_login_time| {
+                now.duration_since(last_login_time).map_or(true, |elapsed| elapsed < LAST_LOGIN_TIME_RESOLUTION)
+            });
+            if secret_id_entry.secret_id_num_uses == 0
+                && secret_id_entry.replay_nonce_ttl.is_zero()
+                && recently_recorded
+            {
+                metadata = secret_id_entry.metadata;
             } else {
-                // If the secret_id_num_uses is non-zero, it means that its use-count should be updated in the storage.
+                // Every other login is recorded in the entry: its last_login_time, its use-count if
+                // secret_id_num_uses is non-zero, and the presented nonce if replay protection is enabled.
                 // Switch the lock from a `read` to a `write` and update the storage entry.
                 mem::drop(locked);
-                let _locked = lock_entry.lock.write()?;
+                let (_locked, contended) = lock_entry.write_contended()?;
+                Span::current().record("lock_contended", contended);
 
                 // Lock switching may change the data. Refresh the contents.
                 let mut secret_id_entry = self
-                    .get_secret_id_storage_entry(
-                        storage,
-                        &role_entry.secret_id_prefix,
-                        &role_name_hmac,
-                        &secret_id_hmac,
-                    )?
+                    .get_secret_id_storage_entry(storage, scope, &role_name_hmac, &secret_id_hmac)?
                     .ok_or(RvError::ErrResponse("invalid secret id".to_string()))?;
 
-                // If there exists a single use left, delete the secret_id entry from the storage but do not fail the
-                // validation request. Subsequent requests to use the same secret_id will fail.
+                if !secret_id_entry.replay_nonce_ttl.is_zero() {
+                    let nonce = req.get_data_as_str("nonce").unwrap_or_default();
+                    self.check_secret_id_nonce(&mut secret_id_entry, &nonce)?;
+                }
+
+                // If there exists a single use left, delete the secret_id entry from the storage but do not fail
+                // the validation request. Subsequent requests to use the same secret_id will fail.
                 if secret_id_entry.secret_id_num_uses == 1 {
                     // Delete the secret IDs accessor first
-                    self.delete_secret_id_accessor_entry(
-                        storage,
-                        &secret_id_entry.secret_id_accessor,
-                        &role_entry.secret_id_prefix,
-                    )?;
+                    self.delete_secret_id_accessor_entry(storage, &secret_id_entry.secret_id_accessor, scope)?;
 
                     storage.delete(&entry_index)?;
+                    self.unindex_secret_id_metadata(
+                        storage,
+                        scope,
+                        &role_name_hmac,
+                        &secret_id_hmac,
+                        &secret_id_entry.metadata,
+                    )?;
+                    self.decrement_secret_id_count(storage, &role_name_hmac)?;
                 } else {
-                    secret_id_entry.secret_id_num_uses -= 1;
-                    secret_id_entry.last_updated_time = SystemTime::now();
+                    if secret_id_entry.secret_id_num_uses > 0 {
+                        secret_id_entry.secret_id_num_uses -= 1;
+                    }
+                    let now = self.clock.now();
+                    secret_id_entry.last_updated_time = now;
+                    secret_id_entry.last_login_time = Some(now);
                     let entry = StorageEntry::new(&entry_index, &secret_id_entry)?;
                     storage.put(&entry)?;
                 }
 
-                // Ensure that the CIDRs on the secret ID are still a subset of that of role's
-                verify_cidr_role_secret_id_subset(&secret_id_entry.cidr_list, &role_entry.secret_id_bound_cidrs)?;
-
-                if !secret_id_entry.cidr_list.is_empty() {
-                    let conn = req
-                        .connection
-                        .as_ref()
-                        .ok_or_else(|| RvError::ErrResponse("failed to get connection information".to_string()))?;
-                    if conn.peer_addr.is_empty() {
-                        return Err(RvError::ErrResponse("failed to get connection information".to_string()));
-                    }
-
-                    let cidr_list_ref: Vec<&str> = secret_id_entry.cidr_list.iter().map(AsRef::as_ref).collect();
-                    if !cidr::ip_belongs_to_cidrs(&conn.peer_addr, &cidr_list_ref)? {
-                        return Err(RvError::ErrResponse(format!(
-                            "source address {} unauthorized through CIDR restrictions on the secret ID",
-                            conn.peer_addr
-                        )));
-                    }
-                }
-            }
-
-            metadata = secret_id_entry.metadata;
-        }
-
-        if !role_entry.secret_id_bound_cidrs.is_empty() {
-            let conn = req
-                .connection
-                .as_ref()
-                .ok_or_else(|| RvError::ErrResponse("failed to get connection information".to_string()))?;
-            if conn.peer_addr.is_empty() {
-                return Err(RvError::ErrResponse("failed to get connection information".to_string()));
-            }
-
-            let bound_cidrs_ref: Vec<&str> = role_entry.secret_id_bound_cidrs.iter().map(AsRef::as_ref).collect();
-            if !cidr::ip_belongs_to_cidrs(&conn.peer_addr, &bound_cidrs_ref)? {
-                return Err(RvError::ErrResponse(format!(
-                    "source address {} unauthorized by CIDR restrictions on the secret ID",
-                    conn.peer_addr
-                )));
+                metadata = secret_id_entry.metadata;
             }
         }
 
@@ -249,3 +329,472 @@ impl AppRoleBackendInner {
         Ok(Some(Response { auth: Some(auth), ..Response::default() }))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        fmt,
+        sync::Mutex,
+        time::{Duration, SystemTime},
+    };
+
+    use serde_json::{json, Map};
+    use tracing::{
+        field::{Field as TraceField, Visit},
+        span::{Attributes, Id, Record},
+        Subscriber,
+    };
+    use tracing_subscriber::{
+        layer::{Context as LayerContext, SubscriberExt},
+        Layer,
+    };
+
+    use super::{
+        super::{
+            config::AppRoleConfig,
+            generate::SecretIdOptions,
+            import::SecretIdImport,
+            test::{new_test_inner, register_test_secret_id},
+            validation::SecretIdStorageEntry,
+            SecretIdScope, SECRET_ID_PREFIX,
+        },
+        *,
+    };
+    use crate::{
+        logical::connection::Connection,
+        storage::Storage,
+        utils::{clock::MockClock, ttl::LeaseTtl},
+    };
+
+    // new_test_backend returns a backend with role1, whose role_id is roleid1,
+    // and a login request for it lacking the secret_id.
+    fn new_test_backend(name: &str, clock: Arc<MockClock>) -> (AppRoleBackend, Arc<dyn Storage>, Request) {
+        let (mut inner, storage) = new_test_inner(name);
+        inner.clock = clock;
+        let backend = AppRoleBackend { inner: Arc::new(inner) };
+
+        let mut req = Request::new("auth/approle/login");
+        req.operation = Operation::Write;
+        req.storage = Some(Arc::clone(&storage));
+
+        let role_entry = RoleEntry {
+            name: "role1".to_string(),
+            role_id: "roleid1".to_string(),
+            hmac_key: "testhmackey".to_string(),
+            bind_secret_id: true,
+            secret_id_prefix: SECRET_ID_PREFIX.to_string(),
+            ..Default::default()
+        };
+        assert!(backend.set_role(&mut req, "role1", &role_entry, "").is_ok());
+
+        let mut body = Map::new();
+        body.insert("role_id".to_string(), json!("roleid1"));
+        req.body = Some(body);
+        req.match_path = Some(Arc::new(backend.login_path()));
+
+        (backend, storage, req)
+    }
+
+    fn register(backend: &AppRoleBackend, storage: &dyn Storage, secret_id: &str, mut entry: SecretIdStorageEntry) {
+        register_test_secret_id(backend, storage, "role1", secret_id, SecretIdScope::Global, &mut entry);
+    }
+
+    fn login(backend: &AppRoleBackend, req: &mut Request, secret_id: &str) -> Result<Option<Response>, RvError> {
+        req.body.as_mut().unwrap().insert("secret_id".to_string(), json!(secret_id));
+        backend.login(&backend.new_backend(), req)
+    }
+
+    #[test]
+    fn test_approle_login_last_login_time() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = Arc::new(MockClock::new(start));
+        let (backend, storage, mut req) = new_test_backend("test_approle_login_last_login_time", clock.clone());
+
+        register(
+            &backend,
+            storage.as_ref(),
+            "secret1",
+            SecretIdStorageEntry { secret_id_num_uses: 5, ..Default::default() },
+        );
+
+        let probe = || {
+            backend.probe_secret_id(storage.as_ref(), "role1", "secret1", "testhmackey", SecretIdScope::Global).unwrap()
+        };
+        assert_eq!(probe().last_login_time, None);
+
+        // Every successful login records its time along with the used up use
+        clock.advance(Duration::from_secs(10));
+        assert!(login(&backend, &mut req, "secret1").is_ok());
+        let status = probe();
+        assert_eq!(status.last_login_time, Some(start + Duration::from_secs(10)));
+        assert_eq!(status.remaining_uses, Some(4));
+
+        clock.advance(Duration::from_secs(20));
+        assert!(login(&backend, &mut req, "secret1").is_ok());
+        let status = probe();
+        assert_eq!(status.last_login_time, Some(start + Duration::from_secs(30)));
+        assert_eq!(status.remaining_uses, Some(3));
+
+        // A failed login leaves it untouched
+        clock.advance(Duration::from_secs(20));
+        assert!(login(&backend, &mut req, "secret2").is_err());
+        assert_eq!(probe().last_login_time, Some(start + Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn test_approle_login_unlimited_use_writes() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = Arc::new(MockClock::new(start));
+        let (backend, storage, mut req) = new_test_backend("test_approle_login_unlimited_use_writes", clock.clone());
+
+        register(&backend, storage.as_ref(), "secret1", SecretIdStorageEntry::default());
+        let probe = || {
+            backend.probe_secret_id(storage.as_ref(), "role1", "secret1", "testhmackey", SecretIdScope::Global).unwrap()
+        };
+
+        // The first login is recorded, the ones shortly after are not written
+        assert!(login(&backend, &mut req, "secret1").is_ok());
+        assert_eq!(probe().last_login_time, Some(start));
+        clock.advance(LAST_LOGIN_TIME_RESOLUTION / 2);
+        assert!(login(&backend, &mut req, "secret1").is_ok());
+        assert_eq!(probe().last_login_time, Some(start));
+
+        clock.advance(LAST_LOGIN_TIME_RESOLUTION);
+        assert!(login(&backend, &mut req, "secret1").is_ok());
+        assert_eq!(probe().last_login_time, Some(start + LAST_LOGIN_TIME_RESOLUTION * 3 / 2));
+    }
+
+    #[test]
+    fn test_approle_login_cidr_refusal_keeps_uses() {
+        let clock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+        let (backend, storage, mut req) = new_test_backend("test_approle_login_cidr_refusal_keeps_uses", clock);
+
+        let entry = SecretIdStorageEntry {
+            secret_id_num_uses: 1,
+            cidr_list: vec!["10.0.0.0/8".to_string()],
+            ..Default::default()
+        };
+        register(&backend, storage.as_ref(), "secret1", entry);
+        let probe = || {
+            backend.probe_secret_id(storage.as_ref(), "role1", "secret1", "testhmackey", SecretIdScope::Global).unwrap()
+        };
+
+        // A login from outside the CIDRs neither uses the secret_id up nor records a login
+        req.connection = Some(Connection { peer_addr: "192.168.1.1".to_string(), ..Default::default() });
+        assert!(login(&backend, &mut req, "secret1").is_err());
+        let status = probe();
+        assert!(status.exists);
+        assert_eq!(status.remaining_uses, Some(1));
+        assert_eq!(status.last_login_time, None);
+
+        req.connection = Some(Connection { peer_addr: "10.1.2.3".to_string(), ..Default::default() });
+        assert!(login(&backend, &mut req, "secret1").is_ok());
+        assert!(!probe().exists);
+    }
+
+    #[test]
+    fn test_approle_login_secret_id_role_binding() {
+        let clock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+        let (backend, storage, mut req) = new_test_backend("test_approle_login_secret_id_role_binding", clock);
+
+        let bound_to = |role_id: &str| SecretIdStorageEntry {
+            role_id_hmac: create_hmac("testhmackey", role_id).unwrap(),
+            ..Default::default()
+        };
+        register(&backend, storage.as_ref(), "secret1", bound_to("roleid1"));
+        register(&backend, storage.as_ref(), "secret2", SecretIdStorageEntry::default());
+        // As if secret2 had been written before secret_ids were bound to a role_id
+        let role_name_hmac = create_hmac("testhmackey", "role1").unwrap();
+        let secret_id_hmac = create_hmac("testhmackey", "secret2").unwrap();
+        let scope = SecretIdScope::Global;
+        let entry = backend
+            .get_secret_id_storage_entry(storage.as_ref(), scope, &role_name_hmac, &secret_id_hmac)
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.role_id_hmac, create_hmac("testhmackey", "roleid1").unwrap());
+        let entry = SecretIdStorageEntry { role_id_hmac: String::new(), ..entry };
+        assert!(backend
+            .set_secret_id_storage_entry(storage.as_ref(), scope, &role_name_hmac, &secret_id_hmac, &entry)
+            .is_ok());
+        // As if an index bug had filed another role's secret_id under role1
+        register(&backend, storage.as_ref(), "secret3", bound_to("roleid2"));
+
+        assert!(login(&backend, &mut req, "secret1").is_ok());
+        assert!(login(&backend, &mut req, "secret2").is_ok());
+        assert_eq!(login(&backend, &mut req, "secret3").unwrap_err(), RvError::ErrSecretIdRoleMismatch);
+
+        // Changing the role_id rebinds the secret_ids bound to the previous one only
+        let role = backend.get_role(&mut req, "role1").unwrap().unwrap();
+        let role = RoleEntry { role_id: "roleid3".to_string(), ..role };
+        assert!(backend.set_role(&mut req, "role1", &role, "roleid1").is_ok());
+
+        req.body.as_mut().unwrap().insert("role_id".to_string(), json!("roleid3"));
+        assert!(login(&backend, &mut req, "secret1").is_ok());
+        assert!(login(&backend, &mut req, "secret2").is_ok());
+        assert_eq!(login(&backend, &mut req, "secret3").unwrap_err(), RvError::ErrSecretIdRoleMismatch);
+
+        req.body.as_mut().unwrap().insert("role_id".to_string(), json!("roleid1"));
+        assert!(login(&backend, &mut req, "secret1").is_err());
+    }
+
+    #[test]
+    fn test_approle_login_registered_secret_id_role_binding() {
+        let clock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+        let (backend, storage, mut req) =
+            new_test_backend("test_approle_login_registered_secret_id_role_binding", clock);
+
+        // Neither a generated nor an imported secret_id is left unbound
+        let (generated, _) = backend
+            .generate_secret_id(
+                storage.as_ref(),
+                "role1",
+                "testhmackey",
+                SecretIdScope::Global,
+                SecretIdOptions::default(),
+            )
+            .unwrap();
+        let import = SecretIdImport { secret_id: "imported".to_string(), ..Default::default() };
+        let report = backend
+            .import_secret_ids(storage.as_ref(), "role1", "testhmackey", SecretIdScope::Global, vec![import])
+            .unwrap();
+        assert_eq!(report.imported(), 1);
+
+        // As if an index bug had left role1 with another role_id, without
+        // rebinding its secret_ids
+        let role = backend.get_role(&mut req, "role1").unwrap().unwrap();
+        let role = RoleEntry { role_id: "roleid2".to_string(), ..role };
+        assert!(backend.set_role(&mut req, "role1", &role, "").is_ok());
+
+        req.body.as_mut().unwrap().insert("role_id".to_string(), json!("roleid2"));
+        assert_eq!(login(&backend, &mut req, &generated).unwrap_err(), RvError::ErrSecretIdRoleMismatch);
+        assert_eq!(login(&backend, &mut req, "imported").unwrap_err(), RvError::ErrSecretIdRoleMismatch);
+
+        // They log in with the role_id they were created for
+        let role = RoleEntry { role_id: "roleid1".to_string(), ..role };
+        assert!(backend.set_role(&mut req, "role1", &role, "").is_ok());
+        req.body.as_mut().unwrap().insert("role_id".to_string(), json!("roleid1"));
+        assert!(login(&backend, &mut req, &generated).is_ok());
+        assert!(login(&backend, &mut req, "imported").is_ok());
+    }
+
+    #[test]
+    fn test_approle_generate_secret_id() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let (backend, storage, mut req) =
+            new_test_backend("test_approle_generate_secret_id", Arc::new(MockClock::new(start)));
+        let generate = |opts: SecretIdOptions| {
+            backend.generate_secret_id(storage.as_ref(), "role1", "testhmackey", SecretIdScope::Global, opts)
+        };
+
+        let opts = SecretIdOptions {
+            num_uses: 2,
+            ttl: LeaseTtl::from_secs(600),
+            metadata: HashMap::from([("env".to_string(), "prod".to_string())]),
+            role_id: "roleid1".to_string(),
+            ..Default::default()
+        };
+        let (secret_id, registration) = generate(opts.clone()).unwrap();
+        assert_eq!(registration.secret_id_num_uses, 2);
+        assert_eq!(registration.secret_id_ttl, LeaseTtl::from_secs(600));
+        assert_eq!(registration.expiration_time, start + Duration::from_secs(600));
+
+        // Every secret_id is new, and usable right away
+        let (other_secret_id, other_registration) = generate(opts.clone()).unwrap();
+        assert_ne!(other_secret_id, secret_id);
+        assert_ne!(other_registration.secret_id_accessor, registration.secret_id_accessor);
+
+        assert!(login(&backend, &mut req, &secret_id).is_ok());
+        let status = backend
+            .probe_secret_id(storage.as_ref(), "role1", &secret_id, "testhmackey", SecretIdScope::Global)
+            .unwrap();
+        assert_eq!(status.remaining_uses, Some(1));
+
+        let secret_id_hmac = create_hmac("testhmackey", &secret_id).unwrap();
+        let entry = backend
+            .get_secret_id_storage_entry(
+                storage.as_ref(),
+                SecretIdScope::Global,
+                &create_hmac("testhmackey", "role1").unwrap(),
+                &secret_id_hmac,
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.metadata, opts.metadata);
+        assert_eq!(entry.secret_id_accessor, registration.secret_id_accessor);
+        assert_eq!(entry.role_id_hmac, create_hmac("testhmackey", "roleid1").unwrap());
+
+        // The options are validated
+        assert!(generate(SecretIdOptions { num_uses: -1, ..Default::default() }).is_err());
+        assert!(generate(SecretIdOptions { name: Some(String::new()), ..Default::default() }).is_err());
+        let metadata = (0..100).map(|i| (format!("key{}", i), "value".to_string())).collect();
+        assert!(generate(SecretIdOptions { metadata, ..Default::default() }).is_err());
+    }
+
+    #[test]
+    fn test_approle_login_usage() {
+        let clock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+        let (backend, storage, mut req) = new_test_backend("test_approle_login_usage", clock);
+
+        let role_entry = RoleEntry {
+            name: "role2".to_string(),
+            role_id: "roleid2".to_string(),
+            hmac_key: "testhmackey".to_string(),
+            bind_secret_id: true,
+            secret_id_prefix: SECRET_ID_PREFIX.to_string(),
+            ..Default::default()
+        };
+        assert!(backend.set_role(&mut req, "role2", &role_entry, "").is_ok());
+        register(&backend, storage.as_ref(), "secret1", SecretIdStorageEntry::default());
+        let mut entry = SecretIdStorageEntry::default();
+        register_test_secret_id(&backend, storage.as_ref(), "role2", "secret2", SecretIdScope::Global, &mut entry);
+
+        assert!(backend.drain_usage().is_empty());
+
+        let mut req2 = Request::new("auth/approle/login");
+        req2.operation = Operation::Write;
+        req2.storage = Some(Arc::clone(&storage));
+        req2.body = Some(Map::from_iter([("role_id".to_string(), json!("roleid2"))]));
+        req2.match_path = req.match_path.clone();
+
+        for _ in 0..5 {
+            assert!(login(&backend, &mut req, "secret1").is_ok());
+        }
+        for _ in 0..3 {
+            assert!(login(&backend, &mut req2, "secret2").is_ok());
+        }
+        // Failed logins are not counted
+        assert!(login(&backend, &mut req, "secret2").is_err());
+
+        let role1_hmac = create_hmac("testhmackey", "role1").unwrap();
+        let role2_hmac = create_hmac("testhmackey", "role2").unwrap();
+        assert_eq!(backend.drain_usage(), HashMap::from([(role1_hmac.clone(), 5), (role2_hmac, 3)]));
+
+        // Every drain starts over
+        assert!(backend.drain_usage().is_empty());
+        assert!(login(&backend, &mut req, "secret1").is_ok());
+        assert_eq!(backend.drain_usage(), HashMap::from([(role1_hmac, 1)]));
+    }
+
+    #[test]
+    fn test_approle_secret_id_inherits_role_cidrs() {
+        let clock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+        let (backend, storage, mut req) = new_test_backend("test_approle_secret_id_inherits_role_cidrs", clock);
+
+        let mut role_entry = backend.get_role(&mut req, "role1").unwrap().unwrap();
+        role_entry.secret_id_bound_cidrs = vec!["10.0.0.0/8".to_string()];
+        assert!(backend.set_role(&mut req, "role1", &role_entry, "roleid1").is_ok());
+
+        let stored_cidrs = |secret_id: &str| {
+            backend
+                .get_secret_id_storage_entry(
+                    storage.as_ref(),
+                    SecretIdScope::Global,
+                    &create_hmac("testhmackey", "role1").unwrap(),
+                    &create_hmac("testhmackey", secret_id).unwrap(),
+                )
+                .unwrap()
+                .unwrap()
+                .cidr_list
+        };
+
+        // Inheriting is opt-in
+        register(&backend, storage.as_ref(), "secret1", SecretIdStorageEntry::default());
+        assert!(stored_cidrs("secret1").is_empty());
+
+        let config = AppRoleConfig { inherit_role_secret_id_cidrs: true, ..Default::default() };
+        assert!(backend.set_config(config).is_ok());
+        register(&backend, storage.as_ref(), "secret2", SecretIdStorageEntry::default());
+        assert_eq!(stored_cidrs("secret2"), vec!["10.0.0.0/8"]);
+
+        // An explicit cidr_list is kept as is
+        let entry = SecretIdStorageEntry { cidr_list: vec!["10.1.0.0/16".to_string()], ..Default::default() };
+        register(&backend, storage.as_ref(), "secret3", entry);
+        assert_eq!(stored_cidrs("secret3"), vec!["10.1.0.0/16"]);
+
+        // The inherited CIDRs stay on the secret_id when the role drops its own
+        role_entry.secret_id_bound_cidrs.clear();
+        assert!(backend.set_role(&mut req, "role1", &role_entry, "roleid1").is_ok());
+
+        let mut connect_from = |peer_addr: &str, secret_id: &str| {
+            req.connection = Some(Connection { peer_addr: peer_addr.to_string(), ..Default::default() });
+            login(&backend, &mut req, secret_id)
+        };
+        assert!(connect_from("192.168.1.1", "secret1").is_ok());
+        let err = connect_from("192.168.1.1", "secret2").unwrap_err();
+        assert!(err.to_string().contains("unauthorized through CIDR restrictions on the secret ID"));
+        assert!(connect_from("10.1.2.3", "secret2").is_ok());
+    }
+
+    // Collects the fields of every span, by span id
+    #[derive(Clone, Default)]
+    struct SpanCapture(Arc<Mutex<HashMap<u64, HashMap<String, String>>>>);
+
+    struct FieldVisitor<'a>(&'a mut HashMap<String, String>);
+
+    impl Visit for FieldVisitor<'_> {
+        fn record_str(&mut self, field: &TraceField, value: &str) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &TraceField, value: &dyn fmt::Debug) {
+            self.0.insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    impl<S: Subscriber> Layer<S> for SpanCapture {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, _ctx: LayerContext<'_, S>) {
+            let mut spans = self.0.lock().unwrap();
+            attrs.record(&mut FieldVisitor(spans.entry(id.into_u64()).or_default()));
+        }
+
+        fn on_record(&self, id: &Id, values: &Record<'_>, _ctx: LayerContext<'_, S>) {
+            let mut spans = self.0.lock().unwrap();
+            values.record(&mut FieldVisitor(spans.entry(id.into_u64()).or_default()));
+        }
+    }
+
+    #[test]
+    fn test_approle_operation_spans() {
+        let clock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+        let (backend, storage, mut req) = new_test_backend("test_approle_operation_spans", clock);
+
+        let capture = SpanCapture::default();
+        let subscriber = tracing_subscriber::registry().with(capture.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            register(&backend, storage.as_ref(), "raw-secret-id-1", SecretIdStorageEntry::default());
+            assert!(login(&backend, &mut req, "raw-secret-id-1").is_ok());
+            assert!(login(&backend, &mut req, "raw-secret-id-2").is_err());
+            assert!(backend
+                .flush_role_secrets(storage.as_ref(), "role1", "testhmackey", SecretIdScope::Global)
+                .is_ok());
+        });
+
+        let role_name_hmac = create_hmac("testhmackey", "role1").unwrap();
+        let spans: Vec<HashMap<String, String>> = capture.0.lock().unwrap().values().cloned().collect();
+        let span_of = |operation: &str, outcome: &str| {
+            spans
+                .iter()
+                .find(|span| span["operation"] == operation && span.get("outcome").map(String::as_str) == Some(outcome))
+                .unwrap_or_else(|| panic!("no {} span with outcome {}", operation, outcome))
+        };
+
+        let register_span = span_of("register_secret_id", "success");
+        assert_eq!(register_span["role_name_hmac"], role_name_hmac);
+        assert_eq!(register_span["lock_contended"], "false");
+
+        let login_span = span_of("login", "success");
+        assert_eq!(login_span["role_name_hmac"], role_name_hmac);
+        assert_eq!(login_span["lock_contended"], "false");
+        span_of("login", "failure");
+
+        let flush_span = span_of("flush_role_secrets", "success");
+        assert_eq!(flush_span["entries"], "1");
+
+        // Every span has an outcome, and no secret material shows up in any of them
+        assert!(spans.iter().all(|span| span.contains_key("outcome")));
+        for value in spans.iter().flat_map(|span| span.values()) {
+            assert!(!value.contains("raw-secret-id") && !value.contains("roleid1"), "leaked in span: {}", value);
+        }
+    }
+}
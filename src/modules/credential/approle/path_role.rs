@@ -1,26 +1,39 @@
-use std::{collections::HashMap, mem, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    mem,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use better_default::Default;
 use derive_more::{Deref, DerefMut};
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
+use serde_json::{json, Map, Value};
 
 use super::{
-    validation::{create_hmac, verify_cidr_role_secret_id_subset, SecretIdStorageEntry},
-    AppRoleBackend, AppRoleBackendInner, HMAC_INPUT_LEN_MAX, SECRET_ID_LOCAL_PREFIX, SECRET_ID_PREFIX,
+    audit::{AuditEvent, AuditEventType},
+    check_unreserved_key,
+    metadata_index::metadata_index_prefix,
+    validation::{
+        create_hmac, secret_id_entry_index, validate_secret_id_metadata, validate_secret_id_name,
+        verify_cidr_role_secret_id_subset, SecretIdStorageEntry,
+    },
+    AppRoleBackend, AppRoleBackendInner, SecretIdScope, HMAC_INPUT_LEN_MAX, ROLE_ID_PREFIX, SECRET_ID_COUNT_PREFIX,
+    SECRET_ID_LOCAL_PREFIX, SECRET_ID_PREFIX,
 };
 use crate::{
     context::Context,
     errors::RvError,
     logical::{field::FieldTrait, Backend, Field, FieldType, Operation, Path, PathOperation, Request, Response},
     new_fields, new_fields_internal, new_path, new_path_internal,
-    storage::StorageEntry,
+    storage::{Storage, StorageEntry},
     utils::{
         self, deserialize_duration,
         policy::sanitize_policies,
         serialize_duration,
         sock_addr::SockAddrMarshaler,
         token_util::{token_fields, TokenParams},
+        ttl::LeaseTtl,
     },
 };
 
@@ -38,6 +51,16 @@ pub struct RoleEntry {
     // UUID that serves as the HMAC key for the hashing the 'secret_id's of the role
     pub hmac_key: String,
 
+    // The hmac_key the role had before its last rotation. The secret_ids created before it are
+    // still indexed under it until they are used or expire, see rotate_hmac_key.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub previous_hmac_key: String,
+
+    // When the last secret_id indexed under previous_hmac_key expires, None if one of them never
+    // does
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_hmac_key_expiration: Option<SystemTime>,
+
     // Policies that are to be required by the token to access this role. Deprecated.
     pub policies: Vec<String>,
 
@@ -71,8 +94,17 @@ pub struct RoleEntry {
 
     // Duration (less than the backend mount's max TTL) after which a secret_id generated against
     // the role will expire
-    #[serde(serialize_with = "serialize_duration", deserialize_with = "deserialize_duration")]
-    pub secret_id_ttl: Duration,
+    pub secret_id_ttl: LeaseTtl,
+
+    // Keeps the secret_ids generated without a TTL from expiring, instead of them getting the
+    // backend's default_secret_id_ttl
+    #[serde(default)]
+    pub secret_id_no_expiry: bool,
+
+    // Maximum number of live secret_ids that can be registered against the role at the same time.
+    // Zero means no limit.
+    #[serde(default)]
+    pub secret_id_count_limit: i64,
     #[serde(serialize_with = "serialize_duration", deserialize_with = "deserialize_duration")]
     // Period, if set, indicates that the token generated using this role should never expire. The
     // token should be renewed within the duration specified by this value. The renewal duration
@@ -102,6 +134,17 @@ impl RoleEntry {
 
         Err(RvError::ErrResponse("at least one constraint should be enabled on the role".to_string()))
     }
+
+    // secret_id_scope returns the scope the role's secret_ids are stored under.
+    pub fn secret_id_scope(&self) -> Result<SecretIdScope, RvError> {
+        SecretIdScope::from_prefix(&self.secret_id_prefix)
+    }
+}
+
+// role_storage_key returns the storage key of the role with the given
+// normalized name. Role names are case insensitive.
+pub fn role_storage_key(name: &str) -> String {
+    format!("role/{}", name.to_lowercase())
 }
 
 impl AppRoleBackend {
@@ -163,7 +206,20 @@ impl AppRoleBackend {
                 "secret_id_ttl": {
                     field_type: FieldType::DurationSecond,
                     required: false,
-                    description: r#"Duration in seconds after which the issued SecretID should expire. Defaults to 0, meaning no expiration."#
+                    description: r#"Duration in seconds after which the issued SecretID should expire. Defaults to 0, meaning
+        the backend's default_secret_id_ttl, or no expiration if that is not configured."#
+                },
+                "secret_id_no_expiry": {
+                    field_type: FieldType::Bool,
+                    required: false,
+                    description: r#"If set, SecretIDs issued without a TTL never expire, even if the backend has a
+        default_secret_id_ttl configured."#
+                },
+                "secret_id_count_limit": {
+                    field_type: FieldType::Int,
+                    required: false,
+                    description: r#"Maximum number of live SecretIDs that can exist for this role at the same time.
+        Defaults to 0 meaning that the number of SecretIDs is not limited."#
                 },
                 "policies": {
                     field_type: FieldType::CommaStringSlice,
@@ -188,7 +244,7 @@ impl AppRoleBackend {
             operations: [
                 {op: Operation::Read, handler: approle_backend_ref1.read_role},
                 {op: Operation::Write, handler: approle_backend_ref2.write_role},
-                {op: Operation::Delete, handler: approle_backend_ref3.delete_role}
+                {op: Operation::Delete, handler: approle_backend_ref3.handle_delete_role}
             ],
             help: r#"
 A role can represent a service, a machine or anything that can be IDed.
@@ -695,6 +751,15 @@ the role."#
                     field_type: FieldType::DurationSecond,
                     description: r#"Duration in seconds after which this SecretID expires.
         Overrides secret_id_ttl role option when supplied. May not be longer than role's secret_id_ttl."#
+                },
+                "replay_nonce_ttl": {
+                    field_type: FieldType::DurationSecond,
+                    description: r#"If set, every login with this SecretID must present a 'nonce' that was not
+        presented in the last 'replay_nonce_ttl' seconds."#
+                },
+                "name": {
+                    field_type: FieldType::Str,
+                    description: "Optional short display name of the SecretID, shown when listing SecretIDs."
                 }
             },
             operations: [
@@ -871,6 +936,15 @@ the role."#
                     field_type: FieldType::DurationSecond,
                     description: r#"Duration in seconds after which this SecretID expires.
         Overrides secret_id_ttl role option when supplied. May not be longer than role's secret_id_ttl."#
+                },
+                "replay_nonce_ttl": {
+                    field_type: FieldType::DurationSecond,
+                    description: r#"If set, every login with this SecretID must present a 'nonce' that was not
+        presented in the last 'replay_nonce_ttl' seconds."#
+                },
+                "name": {
+                    field_type: FieldType::Str,
+                    description: "Optional short display name of the SecretID, shown when listing SecretIDs."
                 }
             },
             operations: [
@@ -929,7 +1003,7 @@ impl AppRoleBackendInner {
         }
 
         let salt_id = salt.as_ref().unwrap().salt_id(role_id)?;
-        let storage_entry = req.storage_get(format!("role_id/{}", salt_id).as_str())?;
+        let storage_entry = req.storage_get(format!("{}{}", ROLE_ID_PREFIX, salt_id).as_str())?;
         if storage_entry.is_none() {
             return Ok(None);
         }
@@ -948,7 +1022,7 @@ impl AppRoleBackendInner {
 
         let salt_id = salt.as_ref().unwrap().salt_id(role_id)?;
 
-        let entry = StorageEntry::new(format!("role_id/{}", salt_id).as_str(), role_id_entry)?;
+        let entry = StorageEntry::new(format!("{}{}", ROLE_ID_PREFIX, salt_id).as_str(), role_id_entry)?;
 
         req.storage_put(&entry)
     }
@@ -965,14 +1039,24 @@ impl AppRoleBackendInner {
 
         let salt_id = salt.as_ref().unwrap().salt_id(role_id)?;
 
-        req.storage_delete(format!("role_id/{}", salt_id).as_str())?;
+        req.storage_delete(format!("{}{}", ROLE_ID_PREFIX, salt_id).as_str())?;
 
         Ok(())
     }
 
     pub fn get_role(&self, req: &mut Request, name: &str) -> Result<Option<RoleEntry>, RvError> {
-        let key = format!("role/{}", name.to_lowercase());
-        let storage_entry = req.storage_get(&key)?;
+        if req.storage.is_none() {
+            return Err(RvError::ErrRequestNotReady);
+        }
+
+        self.load_role(Arc::as_ref(req.storage.as_ref().unwrap()), name)
+    }
+
+    // load_role reads the role from the given storage, for the callers that
+    // are handed a storage rather than a request.
+    pub fn load_role(&self, storage: &dyn Storage, name: &str) -> Result<Option<RoleEntry>, RvError> {
+        let name = utils::normalize_role_name(name)?;
+        let storage_entry = storage.get(&role_storage_key(&name))?;
         if storage_entry.is_none() {
             return Ok(None);
         }
@@ -980,7 +1064,7 @@ impl AppRoleBackendInner {
         let entry = storage_entry.unwrap();
         let mut role_entry: RoleEntry = serde_json::from_slice(entry.value.as_slice())?;
 
-        role_entry.name = name.to_string();
+        role_entry.name.clone_from(&name);
         if role_entry.lower_case_role_name {
             role_entry.name = name.to_lowercase();
         }
@@ -1011,6 +1095,38 @@ impl AppRoleBackendInner {
         Ok(Some(role_entry))
     }
 
+    // list_roles returns the sorted names of the roles starting with the
+    // given prefix, all of them for an empty one. Role names are stored in
+    // plaintext, lowercased, so the prefix is case insensitive.
+    pub fn list_roles(&self, storage: &dyn Storage, prefix: &str) -> Result<Vec<String>, RvError> {
+        let prefix = prefix.to_lowercase();
+        let mut roles: Vec<String> =
+            storage.list("role/")?.into_iter().filter(|name| name.starts_with(&prefix)).collect();
+        roles.sort();
+        Ok(roles)
+    }
+
+    // check_role_count_limit fails if creating one more role would exceed
+    // the configured max_roles. The role lock only covers the role being
+    // created, so concurrent creations of distinct roles can overshoot the
+    // limit by their number.
+    fn check_role_count_limit(&self, storage: &dyn Storage) -> Result<(), RvError> {
+        let max_roles = self.config()?.max_roles;
+        if max_roles == 0 {
+            return Ok(());
+        }
+
+        let count = self.list_roles(storage, "")?.len();
+        if count >= max_roles {
+            return Err(RvError::ErrResponse(format!(
+                "cannot create a new role, the number of roles has reached the limit of {}",
+                max_roles
+            )));
+        }
+
+        Ok(())
+    }
+
     pub fn set_role(
         &self,
         req: &mut Request,
@@ -1040,10 +1156,25 @@ impl AppRoleBackendInner {
             }
         }
 
-        let entry = StorageEntry::new(format!("role/{}", name.to_lowercase()).as_str(), role_entry)?;
+        let key = role_storage_key(&utils::normalize_role_name(name)?);
+        check_unreserved_key(&key)?;
+
+        let entry = StorageEntry::new(&key, role_entry)?;
 
         req.storage_put(&entry)?;
 
+        // The secret_ids stay valid across a role_id change, bind them to the new one
+        if !previous_role_id.is_empty() && previous_role_id != role_entry.role_id.as_str() {
+            let storage = Arc::as_ref(req.storage.as_ref().unwrap());
+            self.rebind_secret_ids(
+                storage,
+                role_entry.secret_id_scope()?,
+                &self.role_name_hmac(&role_entry.hmac_key, &role_entry.name)?,
+                &create_hmac(&role_entry.hmac_key, previous_role_id)?,
+                &create_hmac(&role_entry.hmac_key, &role_entry.role_id)?,
+            )?;
+        }
+
         if create_role_id {
             return self.set_role_id(req, &role_entry.role_id, &RoleIdEntry { name: name.to_string() });
         }
@@ -1059,6 +1190,8 @@ impl AppRoleBackendInner {
     pub fn write_role(&self, _backend: &dyn Backend, req: &mut Request) -> Result<Option<Response>, RvError> {
         let role_name_value = req.get_data("role_name")?;
         let role_name = role_name_value.as_str().ok_or(RvError::ErrRequestFieldInvalid)?;
+        let role_name = utils::normalize_role_name(role_name)?;
+        let role_name = role_name.as_str();
 
         if role_name.len() > HMAC_INPUT_LEN_MAX {
             return Err(RvError::ErrResponse(
@@ -1070,15 +1203,16 @@ impl AppRoleBackendInner {
         let mut create = false;
 
         let lock_entry = self.role_locks.get_lock(role_name);
-        let _locked = lock_entry.lock.write()?;
+        let _locked = lock_entry.write()?;
 
         let entry = self.get_role(req, role_name)?;
         if entry.is_some() {
             role_entry = entry.unwrap();
         } else {
+            self.check_role_count_limit(Arc::as_ref(req.storage.as_ref().unwrap()))?;
             role_entry.name = role_name.to_lowercase();
             role_entry.lower_case_role_name = true;
-            role_entry.hmac_key = utils::generate_uuid();
+            role_entry.hmac_key = self.generate_uuid()?;
             create = true;
         }
 
@@ -1120,7 +1254,7 @@ impl AppRoleBackendInner {
         if let Ok(role_id_value) = req.get_data("role_id") {
             role_entry.role_id = role_id_value.as_str().ok_or(RvError::ErrRequestFieldInvalid)?.to_string();
         } else if create {
-            role_entry.role_id = utils::generate_uuid();
+            role_entry.role_id = self.generate_uuid()?;
         }
 
         if role_entry.role_id.is_empty() {
@@ -1158,22 +1292,49 @@ impl AppRoleBackendInner {
         }
 
         if let Ok(secret_id_ttl_value) = req.get_data("secret_id_ttl") {
-            role_entry.secret_id_ttl = secret_id_ttl_value.as_duration().ok_or(RvError::ErrRequestFieldInvalid)?;
-        } else if create {
             role_entry.secret_id_ttl =
-                req.get_data_or_default("secret_id_ttl")?.as_duration().ok_or(RvError::ErrRequestFieldInvalid)?;
+                secret_id_ttl_value.as_duration().map(LeaseTtl::from).ok_or(RvError::ErrRequestFieldInvalid)?;
+        } else if create {
+            role_entry.secret_id_ttl = req
+                .get_data_or_default("secret_id_ttl")?
+                .as_duration()
+                .map(LeaseTtl::from)
+                .ok_or(RvError::ErrRequestFieldInvalid)?;
+        }
+
+        if let Ok(secret_id_no_expiry_value) = req.get_data("secret_id_no_expiry") {
+            role_entry.secret_id_no_expiry =
+                secret_id_no_expiry_value.as_bool().ok_or(RvError::ErrRequestFieldInvalid)?;
+        }
+
+        if let Ok(secret_id_count_limit_value) = req.get_data("secret_id_count_limit") {
+            role_entry.secret_id_count_limit =
+                secret_id_count_limit_value.as_int().ok_or(RvError::ErrRequestFieldInvalid)?;
         }
 
+        if role_entry.secret_id_count_limit < 0 {
+            return Err(RvError::ErrResponse("secret_id_count_limit cannot be negative".to_string()));
+        }
+
+        let mut warnings = Vec::new();
+        self.warn_secret_id_ttl(role_entry.secret_id_ttl, &mut warnings);
+
         self.set_role(req, &role_entry.name, &role_entry, &previous_role_id)?;
 
-        Ok(None)
+        if warnings.is_empty() {
+            return Ok(None);
+        }
+
+        let mut resp = Response::new();
+        resp.warnings = warnings;
+        Ok(Some(resp))
     }
 
     pub fn read_role(&self, _backend: &dyn Backend, req: &mut Request) -> Result<Option<Response>, RvError> {
-        let role_name = req.get_data_as_str("role_name")?;
+        let role_name = utils::normalize_role_name(&req.get_data_as_str("role_name")?)?;
 
         let lock_entry = self.role_locks.get_lock(&role_name);
-        let locked = lock_entry.lock.read()?;
+        let locked = lock_entry.read()?;
 
         if let Some(entry) = self.get_role(req, &role_name)? {
             let mut data = serde_json::json!({
@@ -1195,6 +1356,14 @@ impl AppRoleBackendInner {
                 data.insert("period".to_string(), Value::from(entry.period.as_secs()));
             }
 
+            if entry.secret_id_count_limit != 0 {
+                data.insert("secret_id_count_limit".to_string(), Value::from(entry.secret_id_count_limit));
+            }
+
+            if entry.secret_id_no_expiry {
+                data.insert("secret_id_no_expiry".to_string(), Value::from(true));
+            }
+
             if !entry.policies.is_empty() {
                 data.insert("policies".to_string(), Value::from(entry.policies.clone()));
             }
@@ -1215,7 +1384,7 @@ impl AppRoleBackendInner {
             if self.get_role_id(req, &entry.role_id)?.is_none() {
                 // Switch to a write lock
                 mem::drop(locked);
-                let _locked = lock_entry.lock.write()?;
+                let _locked = lock_entry.write()?;
 
                 // Check again if the index is missing
                 if self.get_role_id(req, &entry.role_id)?.is_none() {
@@ -1231,30 +1400,297 @@ impl AppRoleBackendInner {
         Ok(None)
     }
 
-    pub fn delete_role(&self, _backend: &dyn Backend, req: &mut Request) -> Result<Option<Response>, RvError> {
-        let role_name = req.get_data_as_str("role_name")?;
+    pub fn handle_delete_role(&self, _backend: &dyn Backend, req: &mut Request) -> Result<Option<Response>, RvError> {
+        let role_name = utils::normalize_role_name(&req.get_data_as_str("role_name")?)?;
 
         let lock_entry = self.role_locks.get_lock(&role_name);
-        let _locked = lock_entry.lock.write()?;
+        let _locked = lock_entry.write()?;
 
         if let Some(entry) = self.get_role(req, &role_name)? {
-            let storage = req.storage.as_ref().unwrap();
+            let storage = Arc::clone(req.storage.as_ref().unwrap());
+            self.purge_role_entries(
+                storage.as_ref(),
+                &entry.name,
+                Some(&entry),
+                &entry.hmac_key,
+                entry.secret_id_scope()?,
+            )?;
+        }
+
+        Ok(None)
+    }
+
+    // purge_role deletes everything stored for a role: its secret_ids along
+    // with their accessors, its role_id index and finally the role itself.
+    // hmac_key and scope are those of the role, so that the secret_ids left
+    // behind by an earlier, interrupted purge are still found once the role
+    // entry is gone. Purging a role that does not exist is not an error.
+    //
+    // The write-ahead log only rolls back the keys an operation created, so
+    // it can not restore a half deleted role. The role entry, which holds the
+    // role_id, is deleted last instead, and a purge interrupted at any point
+    // is completed by running it again.
+    pub fn purge_role(
+        &self,
+        storage: &dyn Storage,
+        role_name: &str,
+        hmac_key: &str,
+        scope: SecretIdScope,
+    ) -> Result<(), RvError> {
+        let role_name = utils::normalize_role_name(role_name)?;
+
+        let lock_entry = self.role_locks.get_lock(&role_name);
+        let _locked = lock_entry.write()?;
+
+        let role = self.load_role(storage, &role_name)?;
+        let role_name = role.as_ref().map_or(role_name, |role| role.name.clone());
+        self.purge_role_entries(storage, &role_name, role.as_ref(), hmac_key, scope)
+    }
 
-            self.flush_role_secrets(Arc::as_ref(storage), &entry.name, &entry.hmac_key, &entry.secret_id_prefix)?;
+    // delete_role is purge_role for a caller knowing the secret_id prefix of
+    // the role rather than its scope.
+    pub fn delete_role(
+        &self,
+        storage: &dyn Storage,
+        role_name: &str,
+        hmac_key: &str,
+        role_secret_id_prefix: &str,
+    ) -> Result<(), RvError> {
+        self.purge_role(storage, role_name, hmac_key, SecretIdScope::from_prefix(role_secret_id_prefix)?)
+    }
 
-            self.delete_role_id(req, &entry.role_id)?;
+    // purge_role_entries is purge_role for a caller holding the role lock,
+    // with the role already loaded.
+    fn purge_role_entries(
+        &self,
+        storage: &dyn Storage,
+        role_name: &str,
+        role: Option<&RoleEntry>,
+        hmac_key: &str,
+        scope: SecretIdScope,
+    ) -> Result<(), RvError> {
+        self.flush_role_secrets(storage, role_name, hmac_key, scope)?;
 
-            req.storage_delete(format!("role/{}", role_name.to_lowercase()).as_str())?;
+        if let Some(role) = role {
+            if !role.role_id.is_empty() {
+                let salt = self.salt.read()?;
+                let salt = salt.as_ref().ok_or(RvError::ErrResponse("salt not found".to_string()))?;
+                storage.delete(&format!("{}{}", ROLE_ID_PREFIX, salt.salt_id(&role.role_id)?))?;
+            }
         }
 
-        Ok(None)
+        storage.delete(&role_storage_key(&utils::normalize_role_name(role_name)?))
+    }
+
+    // rename_role moves a role to a new name, along with its secret_ids and
+    // their metadata index, and points its role_id at the new name. It returns the number of secret_ids
+    // moved. The accessor entries only refer to the secret_ids, so they stay.
+    //
+    // The new role entry is written first and the old one deleted last, so an
+    // interrupted rename leaves the old name working and can be retried. The
+    // locks of both names are held, taken together through write_all so two
+    // renames crossing each other can not deadlock, and the new name is only
+    // checked to be free once they are.
+    pub fn rename_role(&self, req: &mut Request, old_name: &str, new_name: &str) -> Result<usize, RvError> {
+        let old_name = utils::normalize_role_name(old_name)?;
+        let new_name = utils::normalize_role_name(new_name)?;
+        if new_name.is_empty() {
+            return Err(RvError::ErrResponse("missing role name".to_string()));
+        }
+        if new_name.len() > HMAC_INPUT_LEN_MAX {
+            return Err(RvError::ErrResponse(format!(
+                "role_name is longer than maximum of {} bytes",
+                HMAC_INPUT_LEN_MAX
+            )));
+        }
+
+        let new_key = role_storage_key(&new_name);
+        check_unreserved_key(&new_key)?;
+
+        let _locked = self.role_locks.write_all(&[old_name.as_str(), new_name.as_str()])?;
+
+        let mut role = self
+            .get_role(req, &old_name)?
+            .ok_or_else(|| RvError::ErrResponse(format!("role {} does not exist", old_name)))?;
+        if self.get_role(req, &new_name)?.is_some() {
+            return Err(RvError::ErrResponse(format!("role {} already exists", new_name)));
+        }
+
+        let storage = Arc::clone(req.storage.as_ref().unwrap());
+        let scope = role.secret_id_scope()?;
+        let old_role_name_hmac = self.role_name_hmac(&role.hmac_key, &role.name)?;
+
+        role.name = if role.lower_case_role_name { new_name.to_lowercase() } else { new_name.clone() };
+        let new_role_name_hmac = self.role_name_hmac(&role.hmac_key, &role.name)?;
+
+        req.storage_put(&StorageEntry::new(&new_key, &role)?)?;
+
+        let moved = storage.move_prefix(
+            &format!("{}{}/", scope.prefix(), old_role_name_hmac),
+            &format!("{}{}/", scope.prefix(), new_role_name_hmac),
+        )?;
+        storage.move_prefix(
+            &metadata_index_prefix(scope, &old_role_name_hmac),
+            &metadata_index_prefix(scope, &new_role_name_hmac),
+        )?;
+
+        // The counter of the new name is rebuilt from the moved entries on its next use
+        storage.delete(&format!("{}{}", SECRET_ID_COUNT_PREFIX, old_role_name_hmac))?;
+
+        self.set_role_id(req, &role.role_id, &RoleIdEntry { name: new_name })?;
+
+        req.storage_delete(&role_storage_key(&old_name))?;
+
+        Ok(moved)
+    }
+
+    // rotate_hmac_key replaces the hmac_key of a role, returning the number of
+    // secret_ids re-indexed under the new key. The index of a secret_id is made
+    // of the HMAC of the role name, which is recomputed, and the HMAC of the
+    // secret_id itself, which can not be: the secret_id is not stored. That
+    // part moves to the new key the first time the secret_id is used, see
+    // resolve_role_secret_id_hmac, and until then the old key is kept on the
+    // role as previous_hmac_key. Another rotation is refused until the last
+    // secret_id created under the old key expires.
+    //
+    // The secret_ids are copied under the new key before the role switches to
+    // it, and the old entries are deleted after, so an interrupted rotation is
+    // completed by running it again with the same keys.
+    pub fn rotate_hmac_key(
+        &self,
+        storage: &dyn Storage,
+        role_name: &str,
+        old_key: &str,
+        new_key: &str,
+        role_secret_id_prefix: &str,
+    ) -> Result<usize, RvError> {
+        if new_key.is_empty() || new_key == old_key {
+            return Err(RvError::ErrResponse("the new hmac_key must be set and differ from the old one".to_string()));
+        }
+
+        let scope = SecretIdScope::from_prefix(role_secret_id_prefix)?;
+        let role_name = utils::normalize_role_name(role_name)?;
+
+        let lock_entry = self.role_locks.get_lock(&role_name);
+        let _locked = lock_entry.write()?;
+
+        let mut role = self
+            .load_role(storage, &role_name)?
+            .ok_or_else(|| RvError::ErrResponse(format!("role {} does not exist", role_name)))?;
+        if role.secret_id_scope()? != scope {
+            return Err(RvError::ErrResponse(format!(
+                "secret id prefix {} is not the one of role {}",
+                role_secret_id_prefix, role_name
+            )));
+        }
+
+        // The role already switched to the new key, only the old entries are left to move
+        let resumed = role.hmac_key == new_key && role.previous_hmac_key == old_key;
+        if !resumed {
+            if role.hmac_key != old_key {
+                return Err(RvError::ErrResponse(format!("hmac_key of role {} does not match", role_name)));
+            }
+
+            if !role.previous_hmac_key.is_empty() {
+                let in_use = match role.previous_hmac_key_expiration {
+                    Some(expiration) => !self.is_past_expiration(expiration, self.clock.now())?,
+                    None => true,
+                };
+                if in_use {
+                    return Err(RvError::ErrResponse(format!(
+                        "role {} still has secret_ids indexed under its previous hmac_key",
+                        role_name
+                    )));
+                }
+            }
+        }
+
+        let old_role_name_hmac = self.role_name_hmac(old_key, &role.name)?;
+        let new_role_name_hmac = self.role_name_hmac(new_key, &role.name)?;
+        let old_role_id_hmac = create_hmac(old_key, &role.role_id)?;
+        let new_role_id_hmac = create_hmac(new_key, &role.role_id)?;
+        let old_prefix = format!("{}{}/", scope.prefix(), old_role_name_hmac);
+
+        // copy writes the entry of secret_id_hmac under the new key, unless the
+        // copy there is as recent. Logins keep going on during the rotation,
+        // on the old entry until the role switches and on the copy after. A
+        // secret_id gone from under the old key meanwhile was used up or
+        // destroyed, its copy goes as well.
+        let copy = |secret_id_hmac: &str| -> Result<Option<SecretIdStorageEntry>, RvError> {
+            let copied = self.get_secret_id_storage_entry(storage, scope, &new_role_name_hmac, secret_id_hmac)?;
+            let Some(mut entry) =
+                self.get_secret_id_storage_entry(storage, scope, &old_role_name_hmac, secret_id_hmac)?
+            else {
+                if let Some(copied) = copied {
+                    storage.delete(&secret_id_entry_index(scope, &new_role_name_hmac, secret_id_hmac)?)?;
+                    self.unindex_secret_id_metadata(
+                        storage,
+                        scope,
+                        &new_role_name_hmac,
+                        secret_id_hmac,
+                        &copied.metadata,
+                    )?;
+                }
+                return Ok(None);
+            };
+
+            if copied.map_or(true, |copied| copied.last_updated_time < entry.last_updated_time) {
+                if entry.role_id_hmac == old_role_id_hmac {
+                    entry.role_id_hmac.clone_from(&new_role_id_hmac);
+                }
+                self.set_secret_id_storage_entry(storage, scope, &new_role_name_hmac, secret_id_hmac, &entry)?;
+                self.index_secret_id_metadata(storage, scope, &new_role_name_hmac, secret_id_hmac, &entry.metadata)?;
+            }
+
+            Ok(Some(entry))
+        };
+
+        if !resumed {
+            let mut expiration = Some(self.clock.now());
+            for secret_id_hmac in storage.list(&old_prefix)?.iter() {
+                let lock_entry = self.secret_id_locks.get_lock(secret_id_hmac);
+                let _locked = lock_entry.write()?;
+
+                if let Some(entry) = copy(secret_id_hmac)? {
+                    expiration = match entry.secret_id_ttl.is_zero() {
+                        true => None,
+                        false => expiration.map(|expiration| expiration.max(entry.expiration_time)),
+                    };
+                }
+            }
+
+            role.previous_hmac_key = old_key.to_string();
+            role.previous_hmac_key_expiration = expiration;
+            role.hmac_key = new_key.to_string();
+            storage.put(&StorageEntry::new(&role_storage_key(&role_name), &role)?)?;
+        }
+
+        let mut reindexed = 0;
+        for secret_id_hmac in storage.list(&old_prefix)?.iter() {
+            let lock_entry = self.secret_id_locks.get_lock(secret_id_hmac);
+            let _locked = lock_entry.write()?;
+
+            let Some(entry) = copy(secret_id_hmac)? else {
+                continue;
+            };
+
+            self.unindex_secret_id_metadata(storage, scope, &old_role_name_hmac, secret_id_hmac, &entry.metadata)?;
+            storage.delete(&secret_id_entry_index(scope, &old_role_name_hmac, secret_id_hmac)?)?;
+            reindexed += 1;
+        }
+
+        // The counter of the new key is rebuilt from the moved entries on its next use
+        storage.delete(&format!("{}{}", SECRET_ID_COUNT_PREFIX, old_role_name_hmac))?;
+        storage.delete(&format!("{}{}", SECRET_ID_COUNT_PREFIX, new_role_name_hmac))?;
+
+        Ok(reindexed)
     }
 
     pub fn read_role_policies(&self, _backend: &dyn Backend, req: &mut Request) -> Result<Option<Response>, RvError> {
-        let role_name = req.get_data_as_str("role_name")?;
+        let role_name = utils::normalize_role_name(&req.get_data_as_str("role_name")?)?;
 
         let lock_entry = self.role_locks.get_lock(&role_name);
-        let _locked = lock_entry.lock.read()?;
+        let _locked = lock_entry.read()?;
 
         if let Some(role) = self.get_role(req, &role_name)? {
             let mut data = serde_json::json!({
@@ -1275,13 +1711,13 @@ impl AppRoleBackendInner {
     }
 
     pub fn write_role_policies(&self, _backend: &dyn Backend, req: &mut Request) -> Result<Option<Response>, RvError> {
-        let role_name = req.get_data_as_str("role_name")?;
+        let role_name = utils::normalize_role_name(&req.get_data_as_str("role_name")?)?;
 
         let token_policies_value = req.get_data_or_next(&["token_policies", "policies"])?;
         let mut token_policies = token_policies_value.as_comma_string_slice().ok_or(RvError::ErrRequestFieldInvalid)?;
 
         let lock_entry = self.role_locks.get_lock(&role_name);
-        let _locked = lock_entry.lock.write()?;
+        let _locked = lock_entry.write()?;
 
         if let Some(mut role) = self.get_role(req, &role_name)? {
             sanitize_policies(&mut token_policies, false);
@@ -1296,10 +1732,10 @@ impl AppRoleBackendInner {
     }
 
     pub fn delete_role_policies(&self, _backend: &dyn Backend, req: &mut Request) -> Result<Option<Response>, RvError> {
-        let role_name = req.get_data_as_str("role_name")?;
+        let role_name = utils::normalize_role_name(&req.get_data_as_str("role_name")?)?;
 
         let lock_entry = self.role_locks.get_lock(&role_name);
-        let _locked = lock_entry.lock.write()?;
+        let _locked = lock_entry.write()?;
 
         if let Some(mut role) = self.get_role(req, &role_name)? {
             role.token_policies.clear();
@@ -1321,10 +1757,10 @@ impl AppRoleBackendInner {
     }
 
     pub fn read_role_field(&self, req: &mut Request, field: &str) -> Result<Option<Response>, RvError> {
-        let role_name = req.get_data_as_str("role_name")?;
+        let role_name = utils::normalize_role_name(&req.get_data_as_str("role_name")?)?;
 
         let lock_entry = self.role_locks.get_lock(&role_name);
-        let _locked = lock_entry.lock.read()?;
+        let _locked = lock_entry.read()?;
 
         if let Some(role) = self.get_role(req, &role_name)? {
             let data = match field {
@@ -1406,7 +1842,7 @@ impl AppRoleBackendInner {
     }
 
     pub fn update_role_field(&self, req: &mut Request, field: &str) -> Result<Option<Response>, RvError> {
-        let role_name = req.get_data_as_str("role_name")?;
+        let role_name = utils::normalize_role_name(&req.get_data_as_str("role_name")?)?;
 
         let field_value = match field {
             "token_period" | "period" => req.get_data_or_next(&["token_period", "period"])?,
@@ -1431,7 +1867,7 @@ impl AppRoleBackendInner {
         }
 
         let lock_entry = self.role_locks.get_lock(&role_name);
-        let _locked = lock_entry.lock.write()?;
+        let _locked = lock_entry.write()?;
 
         let mut previous_role_id = "".to_string();
 
@@ -1463,7 +1899,8 @@ impl AppRoleBackendInner {
                     }
                 }
                 "secret_id_ttl" => {
-                    role.secret_id_ttl = field_value.as_duration().ok_or(RvError::ErrLogicalOperationUnsupported)?;
+                    role.secret_id_ttl =
+                        field_value.as_duration().map(LeaseTtl::from).ok_or(RvError::ErrLogicalOperationUnsupported)?;
                 }
                 "token_period" | "period" => {
                     role.token_period = field_value.as_duration().ok_or(RvError::ErrLogicalOperationUnsupported)?;
@@ -1502,10 +1939,10 @@ impl AppRoleBackendInner {
     }
 
     pub fn delete_role_field(&self, req: &mut Request, field: &str) -> Result<Option<Response>, RvError> {
-        let role_name = req.get_data_as_str("role_name")?;
+        let role_name = utils::normalize_role_name(&req.get_data_as_str("role_name")?)?;
 
         let lock_entry = self.role_locks.get_lock(&role_name);
-        let _locked = lock_entry.lock.write()?;
+        let _locked = lock_entry.write()?;
 
         if let Some(mut role) = self.get_role(req, &role_name)? {
             match field {
@@ -1534,6 +1971,7 @@ impl AppRoleBackendInner {
                     role.secret_id_ttl = req
                         .get_field_default_or_zero("secret_id_ttl")?
                         .as_duration()
+                        .map(LeaseTtl::from)
                         .ok_or(RvError::ErrLogicalOperationUnsupported)?;
                 }
                 "token_period" | "period" => {
@@ -1798,40 +2236,40 @@ impl AppRoleBackendInner {
     }
 
     pub fn list_role_secret_id(&self, _backend: &dyn Backend, req: &mut Request) -> Result<Option<Response>, RvError> {
-        let role_name = req.get_data_as_str("role_name")?;
+        let role_name = utils::normalize_role_name(&req.get_data_as_str("role_name")?)?;
 
         let lock_entry = self.role_locks.get_lock(&role_name);
-        let _locked = lock_entry.lock.read()?;
+        let _locked = lock_entry.read()?;
 
         if let Some(role) = self.get_role(req, &role_name)? {
-            let role_name_hmac = create_hmac(&role.hmac_key, &role.name)?;
-            let key = format!("{}{}/", role.secret_id_prefix, role_name_hmac);
-            let secret_id_hmacs = req.storage_list(&key)?;
-
-            let mut list_items: Vec<String> = Vec::with_capacity(secret_id_hmacs.len());
-            for secret_id_hmac in secret_id_hmacs.iter() {
-                let entry_index = format!("{}{}/{}", role.secret_id_prefix, role_name_hmac, secret_id_hmac);
-
-                // secret_id locks are not indexed by secret_id itself.
-                // This is because secret_id are not stored in plaintext
-                // form anywhere in the backend, and hence accessing its
-                // corresponding lock many times using secret_id is not
-                // possible. Also, indexing it everywhere using secret_id_hmacs
-                // makes listing operation easier.
-                let lock_entry = self.secret_id_locks.get_lock(secret_id_hmac);
-                let _locked = lock_entry.lock.read()?;
-                let storage_entry = req.storage_get(&entry_index)?;
-                if storage_entry.is_none() {
-                    return Err(RvError::ErrResponse(
-                        "storage entry for SecretID is present but no content found at the index".to_string(),
-                    ));
+            let role_name_hmac = self.role_name_hmac(&role.hmac_key, &role.name)?;
+            let storage = Arc::as_ref(req.storage.as_ref().unwrap());
+            let entries = self.list_secret_id_entries(storage, role.secret_id_scope()?, &role_name_hmac, None)?;
+
+            let list_items: Vec<String> = entries.iter().map(|entry| entry.secret_id_accessor.clone()).collect();
+            let mut resp = Response::list_response(&list_items);
+
+            // Surface the display names of the named secret_ids and when the used ones last logged in
+            let mut key_info = Map::new();
+            for entry in entries.iter() {
+                let mut info = Map::new();
+                if let Some(name) = entry.name.as_ref() {
+                    info.insert("name".to_string(), json!(name));
+                }
+                if let Some(last_login_time) = entry.last_login_time {
+                    info.insert("last_login_time".to_string(), json!(utils::format_system_time(last_login_time)?));
+                }
+                if !info.is_empty() {
+                    key_info.insert(entry.secret_id_accessor.clone(), Value::Object(info));
+                }
+            }
+            if !key_info.is_empty() {
+                if let Some(data) = resp.data.as_mut() {
+                    data.insert("key_info".to_string(), Value::Object(key_info));
                 }
-                let entry = storage_entry.unwrap();
-                let secret_id_entry: SecretIdStorageEntry = serde_json::from_slice(entry.value.as_slice())?;
-                list_items.push(secret_id_entry.secret_id_accessor);
             }
 
-            return Ok(Some(Response::list_response(&list_items)));
+            return Ok(Some(resp));
         }
 
         Err(RvError::ErrResponse(format!("role {} does not exist", role_name)))
@@ -1842,14 +2280,14 @@ impl AppRoleBackendInner {
         req: &mut Request,
         secret_id: &str,
     ) -> Result<Option<Response>, RvError> {
-        let role_name = req.get_data_as_str("role_name")?;
+        let role_name = utils::normalize_role_name(&req.get_data_as_str("role_name")?)?;
 
         if secret_id.is_empty() {
             return Err(RvError::ErrResponse("missing secret_id".to_string()));
         }
 
         let lock_entry = self.role_locks.get_lock(&role_name);
-        let _locked = lock_entry.lock.read()?;
+        let _locked = lock_entry.read()?;
 
         let role = self.get_role(req, &role_name)?;
         if role.is_none() {
@@ -1909,28 +2347,41 @@ impl AppRoleBackendInner {
         }
 
         // Check whether or not specified ttl is defined, otherwise fallback to role's secret_id_ttl
-        let ttl: Duration;
+        let ttl: LeaseTtl;
         if let Ok(ttl_value) = req.get_data("ttl") {
-            ttl = ttl_value.as_duration().ok_or(RvError::ErrRequestFieldInvalid)?;
-            if (ttl.as_secs() == 0 && role.secret_id_ttl.as_secs() > 0)
-                || (role.secret_id_ttl.as_secs() > 0 && ttl.as_secs() > role.secret_id_ttl.as_secs())
-            {
+            ttl = ttl_value.as_duration().map(LeaseTtl::from).ok_or(RvError::ErrRequestFieldInvalid)?;
+            if !role.secret_id_ttl.is_zero() && (ttl.is_zero() || ttl.exceeds(role.secret_id_ttl)) {
                 return Err(RvError::ErrResponse("ttl cannot be longer than the role's secret_id_ttl".to_string()));
             }
         } else {
             ttl = role.secret_id_ttl;
         }
 
+        let replay_nonce_ttl = match req.get_data("replay_nonce_ttl") {
+            Ok(value) => value.as_duration().ok_or(RvError::ErrRequestFieldInvalid)?,
+            Err(_) => Duration::ZERO,
+        };
+
         let mut secret_id_storage = SecretIdStorageEntry {
             secret_id_num_uses: num_uses,
             secret_id_ttl: ttl,
             cidr_list,
             token_cidr_list: token_bound_cidrs,
+            replay_nonce_ttl,
+            no_expiry: role.secret_id_no_expiry,
+            role_id_hmac: create_hmac(&role.hmac_key, &role.role_id)?,
             ..Default::default()
         };
 
+        if let Ok(name_value) = req.get_data("name") {
+            let name = name_value.as_str().ok_or(RvError::ErrRequestFieldInvalid)?.trim().to_string();
+            validate_secret_id_name(&name)?;
+            secret_id_storage.name = Some(name);
+        }
+
         if let Ok(metadata_value) = req.get_data("metadata") {
             secret_id_storage.metadata = metadata_value.as_map().ok_or(RvError::ErrRequestFieldInvalid)?;
+            validate_secret_id_metadata(&secret_id_storage.metadata, &self.config()?)?;
         }
 
         let storage = Arc::as_ref(req.storage.as_ref().unwrap());
@@ -1939,22 +2390,37 @@ impl AppRoleBackendInner {
             &role.name,
             secret_id,
             &role.hmac_key,
-            &role.secret_id_prefix,
+            role.secret_id_scope()?,
+            self.config()?.secret_id_count_limit(role.secret_id_count_limit),
             &mut secret_id_storage,
         )?;
 
+        // A zero TTL left by the registration means that the secret_id never
+        // expires, it must not be reported as the default TTL
+        let secret_id_ttl = match secret_id_storage.secret_id_ttl {
+            ttl if ttl.is_zero() => ttl,
+            ttl => self.derive_secret_id_ttl(ttl),
+        };
+
         let resp_data = json!({
             "secret_id": secret_id,
             "secret_id_accessor": secret_id_storage.secret_id_accessor,
-            "secret_id_ttl": self.derive_secret_id_ttl(secret_id_storage.secret_id_ttl).as_secs(),
+            "secret_id_ttl": secret_id_ttl.as_secs(),
             "secret_id_num_uses": secret_id_storage.secret_id_num_uses,
+            "replay_nonce_ttl": secret_id_storage.replay_nonce_ttl.as_secs(),
         });
 
-        Ok(Some(Response::data_response(Some(resp_data.as_object().unwrap().clone()))))
+        let mut resp = Response::data_response(Some(resp_data.as_object().unwrap().clone()));
+        self.warn_secret_id_ttl(secret_id_storage.secret_id_ttl, &mut resp.warnings);
+        if secret_id_ttl.is_zero() {
+            resp.add_warning("the secret_id never expires, it stays valid until it is destroyed");
+        }
+
+        Ok(Some(resp))
     }
 
     pub fn write_role_secret_id(&self, _backend: &dyn Backend, req: &mut Request) -> Result<Option<Response>, RvError> {
-        let secret_id = utils::generate_uuid();
+        let secret_id = self.generate_uuid()?;
         self.update_role_secret_id_common(req, &secret_id)
     }
 
@@ -1963,11 +2429,11 @@ impl AppRoleBackendInner {
         _backend: &dyn Backend,
         req: &mut Request,
     ) -> Result<Option<Response>, RvError> {
-        let role_name = req.get_data_as_str("role_name")?;
+        let role_name = utils::normalize_role_name(&req.get_data_as_str("role_name")?)?;
         let secret_id = req.get_data_as_str("secret_id")?;
 
         let lock_entry = self.role_locks.get_lock(&role_name);
-        let _locked = lock_entry.lock.read()?;
+        let _locked = lock_entry.read()?;
 
         let role = self.get_role(req, &role_name)?;
         if role.is_none() {
@@ -1976,28 +2442,35 @@ impl AppRoleBackendInner {
 
         let role = role.unwrap();
 
-        let role_name_hmac = create_hmac(&role.hmac_key, &role.name)?;
-        let secret_id_hmac = create_hmac(&role.hmac_key, &secret_id)?;
+        let storage = Arc::as_ref(req.storage.as_ref().unwrap());
+
+        let role_name_hmac = self.role_name_hmac(&role.hmac_key, &role.name)?;
+        let secret_id_hmac = self.resolve_role_secret_id_hmac(storage, &role, &role_name_hmac, &secret_id)?;
 
         let entry_index = format!("{}{}/{}", role.secret_id_prefix, role_name_hmac, secret_id_hmac);
 
         let lock_entry = self.secret_id_locks.get_lock(&secret_id_hmac);
-        let _locked = lock_entry.lock.write()?;
-
-        let storage = Arc::as_ref(req.storage.as_ref().unwrap());
+        let _locked = lock_entry.write()?;
 
         if let Some(secret_id_entry) =
-            self.get_secret_id_storage_entry(storage, &role.secret_id_prefix, &role_name_hmac, &secret_id_hmac)?
+            self.get_secret_id_storage_entry(storage, role.secret_id_scope()?, &role_name_hmac, &secret_id_hmac)?
         {
             // If a secret ID entry does not have a corresponding accessor
             // entry, revoke the secret ID immediately
             let accessor_entry = self.get_secret_id_accessor_entry(
                 storage,
                 &secret_id_entry.secret_id_accessor,
-                &role.secret_id_prefix,
+                role.secret_id_scope()?,
             )?;
             if accessor_entry.is_none() {
                 req.storage_delete(&entry_index)?;
+                self.unindex_secret_id_metadata(
+                    storage,
+                    role.secret_id_scope()?,
+                    &role_name_hmac,
+                    &secret_id_hmac,
+                    &secret_id_entry.metadata,
+                )?;
                 return Err(RvError::ErrResponse("invalid secret_id".to_string()));
             }
 
@@ -2021,11 +2494,11 @@ impl AppRoleBackendInner {
         _backend: &dyn Backend,
         req: &mut Request,
     ) -> Result<Option<Response>, RvError> {
-        let role_name = req.get_data_as_str("role_name")?;
+        let role_name = utils::normalize_role_name(&req.get_data_as_str("role_name")?)?;
         let secret_id = req.get_data_as_str("secret_id")?;
 
         let lock_entry = self.role_locks.get_lock(&role_name);
-        let _locked = lock_entry.lock.read()?;
+        let _locked = lock_entry.read()?;
 
         let role = self.get_role(req, &role_name)?;
         if role.is_none() {
@@ -2034,24 +2507,42 @@ impl AppRoleBackendInner {
 
         let role = role.unwrap();
 
-        let role_name_hmac = create_hmac(&role.hmac_key, &role.name)?;
-        let secret_id_hmac = create_hmac(&role.hmac_key, &secret_id)?;
+        let storage = Arc::as_ref(req.storage.as_ref().unwrap());
+
+        let role_name_hmac = self.role_name_hmac(&role.hmac_key, &role.name)?;
+        let secret_id_hmac = self.resolve_role_secret_id_hmac(storage, &role, &role_name_hmac, &secret_id)?;
 
         let entry_index = format!("{}{}/{}", role.secret_id_prefix, role_name_hmac, secret_id_hmac);
 
         let lock_entry = self.secret_id_locks.get_lock(&secret_id_hmac);
-        let _locked = lock_entry.lock.write()?;
-
-        let storage = Arc::as_ref(req.storage.as_ref().unwrap());
+        let _locked = lock_entry.write()?;
 
         if let Some(secret_id_entry) =
-            self.get_secret_id_storage_entry(storage, &role.secret_id_prefix, &role_name_hmac, &secret_id_hmac)?
+            self.get_secret_id_storage_entry(storage, role.secret_id_scope()?, &role_name_hmac, &secret_id_hmac)?
         {
             // Delete the accessor of the secret_id first
-            self.delete_secret_id_accessor_entry(storage, &secret_id_entry.secret_id_accessor, &role.secret_id_prefix)?;
+            self.delete_secret_id_accessor_entry(
+                storage,
+                &secret_id_entry.secret_id_accessor,
+                role.secret_id_scope()?,
+            )?;
 
             // Delete the storage entry that corresponds to the secret_id
-            storage.delete(&entry_index)?;
+            storage.delete_and_confirm(&entry_index)?;
+            self.unindex_secret_id_metadata(
+                storage,
+                role.secret_id_scope()?,
+                &role_name_hmac,
+                &secret_id_hmac,
+                &secret_id_entry.metadata,
+            )?;
+            self.decrement_secret_id_count(storage, &role_name_hmac)?;
+
+            let mut event = AuditEvent::new(AuditEventType::SecretIdDelete, self.clock.now());
+            event.role_name_hmac = role_name_hmac;
+            event.secret_id_hmac = secret_id_hmac;
+            event.secret_id_accessor = secret_id_entry.secret_id_accessor;
+            self.audit(event);
         }
 
         Ok(None)
@@ -2062,11 +2553,11 @@ impl AppRoleBackendInner {
         _backend: &dyn Backend,
         req: &mut Request,
     ) -> Result<Option<Response>, RvError> {
-        let role_name = req.get_data_as_str("role_name")?;
+        let role_name = utils::normalize_role_name(&req.get_data_as_str("role_name")?)?;
         let secret_id_accessor = req.get_data_as_str("secret_id_accessor")?;
 
         let lock_entry = self.role_locks.get_lock(&role_name);
-        let _locked = lock_entry.lock.read()?;
+        let _locked = lock_entry.read()?;
 
         let role = self.get_role(req, &role_name)?;
         if role.is_none() {
@@ -2078,16 +2569,16 @@ impl AppRoleBackendInner {
         let storage = Arc::as_ref(req.storage.as_ref().unwrap());
 
         if let Some(accessor_entry) =
-            self.get_secret_id_accessor_entry(storage, &secret_id_accessor, &role.secret_id_prefix)?
+            self.get_secret_id_accessor_entry(storage, &secret_id_accessor, role.secret_id_scope()?)?
         {
-            let role_name_hmac = create_hmac(&role.hmac_key, &role.name)?;
+            let role_name_hmac = self.role_name_hmac(&role.hmac_key, &role.name)?;
 
             let lock_entry = self.secret_id_locks.get_lock(&accessor_entry.secret_id_hmac);
-            let _locked = lock_entry.lock.read()?;
+            let _locked = lock_entry.read()?;
 
             if let Some(secret_id_entry) = self.get_secret_id_storage_entry(
                 storage,
-                &role.secret_id_prefix,
+                role.secret_id_scope()?,
                 &role_name_hmac,
                 &accessor_entry.secret_id_hmac,
             )? {
@@ -2117,11 +2608,11 @@ impl AppRoleBackendInner {
         _backend: &dyn Backend,
         req: &mut Request,
     ) -> Result<Option<Response>, RvError> {
-        let role_name = req.get_data_as_str("role_name")?;
+        let role_name = utils::normalize_role_name(&req.get_data_as_str("role_name")?)?;
         let secret_id_accessor = req.get_data_as_str("secret_id_accessor")?;
 
         let lock_entry = self.role_locks.get_lock(&role_name);
-        let _locked = lock_entry.lock.write()?;
+        let _locked = lock_entry.write()?;
 
         // secret_id is indexed based on HMACed role_name and HMACed secret_id.
         // Get the role details to fetch the role_id and accessor to get
@@ -2137,37 +2628,49 @@ impl AppRoleBackendInner {
         let storage = Arc::as_ref(req.storage.as_ref().unwrap());
 
         if let Some(accessor_entry) =
-            self.get_secret_id_accessor_entry(storage, &secret_id_accessor, &role.secret_id_prefix)?
+            self.get_secret_id_accessor_entry(storage, &secret_id_accessor, role.secret_id_scope()?)?
         {
-            let role_name_hmac = create_hmac(&role.hmac_key, &role.name)?;
+            let role_name_hmac = self.role_name_hmac(&role.hmac_key, &role.name)?;
 
             let lock_entry = self.secret_id_locks.get_lock(&accessor_entry.secret_id_hmac);
-            let _locked = lock_entry.lock.write()?;
+            let _locked = lock_entry.write()?;
 
             // Verify we have a valid secret_id storage entry
-            if self
-                .get_secret_id_storage_entry(
-                    storage,
-                    &role.secret_id_prefix,
-                    &role_name_hmac,
-                    &accessor_entry.secret_id_hmac,
-                )?
-                .is_none()
-            {
+            let Some(secret_id_entry) = self.get_secret_id_storage_entry(
+                storage,
+                role.secret_id_scope()?,
+                &role_name_hmac,
+                &accessor_entry.secret_id_hmac,
+            )?
+            else {
                 return Err(RvError::ErrResponseStatus(
                     403,
                     format!("invalid secret_id_accessor: {}", secret_id_accessor),
                 ));
-            }
+            };
 
             let entry_index = format!("{}{}/{}", role.secret_id_prefix, role_name_hmac, &accessor_entry.secret_id_hmac);
 
             let storage = Arc::as_ref(req.storage.as_ref().unwrap());
 
             // Delete the accessor of the secret_id first
-            self.delete_secret_id_accessor_entry(storage, &secret_id_accessor, &role.secret_id_prefix)?;
+            self.delete_secret_id_accessor_entry(storage, &secret_id_accessor, role.secret_id_scope()?)?;
+
+            storage.delete_and_confirm(&entry_index)?;
+            self.unindex_secret_id_metadata(
+                storage,
+                role.secret_id_scope()?,
+                &role_name_hmac,
+                &accessor_entry.secret_id_hmac,
+                &secret_id_entry.metadata,
+            )?;
+            self.decrement_secret_id_count(storage, &role_name_hmac)?;
 
-            storage.delete(&entry_index)?;
+            let mut event = AuditEvent::new(AuditEventType::SecretIdDelete, self.clock.now());
+            event.role_name_hmac = role_name_hmac;
+            event.secret_id_hmac = accessor_entry.secret_id_hmac;
+            event.secret_id_accessor = secret_id_accessor;
+            self.audit(event);
         } else {
             return Err(RvError::ErrResponseStatus(
                 404,
@@ -2196,8 +2699,9 @@ mod test {
 
     use super::{
         super::{
+            config::AppRoleConfig,
             test::{generate_secret_id, test_delete_role, test_login, test_write_role},
-            AppRoleModule, SECRET_ID_PREFIX,
+            AppRoleModule, RESERVED_PREFIXES, SECRET_ID_ACCESSOR_PREFIX, SECRET_ID_PREFIX,
         },
         *,
     };
@@ -2527,7 +3031,7 @@ mod test {
         req.body = None;
         let _resp = core.handle_request(&mut req).await;
         req.storage = core.get_system_view().map(|arc| arc as Arc<dyn Storage>);
-        let resp = approle_module.delete_role(&mock_backend, &mut req);
+        let resp = approle_module.handle_delete_role(&mock_backend, &mut req);
         assert!(resp.is_ok());
 
         let data = json!({
@@ -3073,6 +3577,56 @@ mod test {
         assert_eq!(expect.as_array().unwrap().clone(), keys);
     }
 
+    #[maybe_async::test(feature = "sync_handler", async(all(not(feature = "sync_handler")), tokio::test))]
+    async fn test_approle_list_roles_max_roles() {
+        let (root_token, core) = test_rusty_vault_init("test_approle_list_roles_max_roles");
+        let core = core.read().unwrap();
+
+        // Mount approle auth to path: auth/approle
+        test_mount_auth_api(&core, &root_token, "approle", "approle").await;
+
+        let module = core.module_manager.get_module("approle").unwrap();
+        let approle_mod = module.read().unwrap();
+        let approle_module = approle_mod.as_ref().downcast_ref::<AppRoleModule>().unwrap();
+        let storage: Arc<dyn Storage> = core.router.matching_view("auth/approle/").unwrap().unwrap();
+
+        assert!(approle_module.list_roles(storage.as_ref(), "").unwrap().is_empty());
+
+        for role_name in ["web-2", "Web-1", "db-1"] {
+            let resp =
+                test_write_api(&core, &root_token, format!("auth/approle/role/{}", role_name).as_str(), true, None)
+                    .await;
+            assert!(resp.is_ok());
+        }
+
+        // The names come back lowercased and sorted, the prefix is case insensitive
+        assert_eq!(approle_module.list_roles(storage.as_ref(), "").unwrap(), vec!["db-1", "web-1", "web-2"]);
+        assert_eq!(approle_module.list_roles(storage.as_ref(), "WEB").unwrap(), vec!["web-1", "web-2"]);
+        assert!(approle_module.list_roles(storage.as_ref(), "app").unwrap().is_empty());
+
+        // At the cap, a new role is rejected while the existing ones can still be updated
+        let config = AppRoleConfig { max_roles: 3, ..Default::default() };
+        assert!(approle_module.set_config(config).is_ok());
+        let resp = test_write_api(&core, &root_token, "auth/approle/role/db-2", false, None).await;
+        assert!(resp.is_err());
+        assert!(approle_module.load_role(storage.as_ref(), "db-2").unwrap().is_none());
+        let data = json!({ "token_ttl": 60 }).as_object().unwrap().clone();
+        let resp = test_write_api(&core, &root_token, "auth/approle/role/web-1", true, Some(data)).await;
+        assert!(resp.is_ok());
+
+        // Deleting a role makes room for another
+        test_delete_role(&core, &root_token, "approle", "web-2").await;
+        let resp = test_write_api(&core, &root_token, "auth/approle/role/db-2", true, None).await;
+        assert!(resp.is_ok());
+        assert_eq!(approle_module.list_roles(storage.as_ref(), "").unwrap(), vec!["db-1", "db-2", "web-1"]);
+
+        // Zero lifts the cap
+        assert!(approle_module.set_config(AppRoleConfig::default()).is_ok());
+        let resp = test_write_api(&core, &root_token, "auth/approle/role/db-3", true, None).await;
+        assert!(resp.is_ok());
+        assert_eq!(approle_module.list_roles(storage.as_ref(), "").unwrap().len(), 4);
+    }
+
     #[maybe_async::test(feature = "sync_handler", async(all(not(feature = "sync_handler")), tokio::test))]
     async fn test_approle_role_secret_id_without_fields() {
         let (root_token, core) = test_rusty_vault_init("test_approle_role_secret_id_without_fields");
@@ -4057,16 +4611,65 @@ mod test {
             )
             .await;
             assert!(resp.is_ok());
-            let resp_data = resp.unwrap().unwrap().data.unwrap();
+            let resp = resp.unwrap().unwrap();
+            let resp_data = resp.data.unwrap();
             let secret_id_ttl = resp_data["secret_id_ttl"].as_duration().unwrap();
             if case["sys_ttl_cap"].as_bool().unwrap() {
                 assert_eq!(secret_id_ttl, MAX_LEASE_DURATION_SECS);
+                assert_eq!(resp.warnings.len(), 1);
+                assert!(resp.warnings[0].contains("capped"));
+            } else if secret_id_ttl.is_zero() {
+                assert_eq!(resp.warnings.len(), 1);
+                assert!(resp.warnings[0].contains("never expires"));
             } else {
                 assert_eq!(secret_id_ttl, case["ttl"].as_duration().unwrap());
+                assert!(resp.warnings.is_empty());
             }
         }
     }
 
+    #[maybe_async::test(feature = "sync_handler", async(all(not(feature = "sync_handler")), tokio::test))]
+    async fn test_approle_role_secret_id_no_expiry() {
+        let (root_token, core) = test_rusty_vault_init("test_approle_role_secret_id_no_expiry");
+        let core = core.read().unwrap();
+
+        // Mount approle auth to path: auth/approle
+        test_mount_auth_api(&core, &root_token, "approle", "approle").await;
+
+        let module = core.module_manager.get_module("approle").unwrap();
+        let approle_mod = module.read().unwrap();
+        let approle_module = approle_mod.as_ref().downcast_ref::<AppRoleModule>().unwrap();
+
+        for (role_name, secret_id_ttl) in [("role-no-ttl", 0), ("role-ttl", 60)] {
+            let role_data = json!({ "secret_id_ttl": secret_id_ttl }).as_object().unwrap().clone();
+            let resp = test_write_api(
+                &core,
+                &root_token,
+                format!("auth/approle/role/{}", role_name).as_str(),
+                true,
+                Some(role_data),
+            )
+            .await;
+            assert!(resp.is_ok());
+        }
+
+        // Forbidden, a secret_id without a TTL is rejected, one with a TTL is not
+        let config = AppRoleConfig { allow_infinite_secret_id_ttl: false, ..Default::default() };
+        assert!(approle_module.set_config(config).is_ok());
+        let resp = test_write_api(&core, &root_token, "auth/approle/role/role-no-ttl/secret-id", false, None).await;
+        assert!(resp.is_err());
+        let resp = test_write_api(&core, &root_token, "auth/approle/role/role-ttl/secret-id", true, None).await;
+        assert!(resp.unwrap().unwrap().warnings.is_empty());
+
+        // Permitted, the secret_id is registered with a warning
+        assert!(approle_module.set_config(AppRoleConfig::default()).is_ok());
+        let resp = test_write_api(&core, &root_token, "auth/approle/role/role-no-ttl/secret-id", true, None).await;
+        let resp = resp.unwrap().unwrap();
+        assert_eq!(resp.data.unwrap()["secret_id_ttl"].as_u64(), Some(0));
+        assert_eq!(resp.warnings.len(), 1);
+        assert!(resp.warnings[0].contains("never expires"));
+    }
+
     #[maybe_async::test(feature = "sync_handler", async(all(not(feature = "sync_handler")), tokio::test))]
     async fn test_approle_role_secret_id_accessor_cross_delete() {
         let (root_token, core) = test_rusty_vault_init("test_approle_role_secret_id_accessor_cross_delete");
@@ -4108,4 +4711,409 @@ mod test {
         )
         .await;
     }
+
+    #[maybe_async::test(feature = "sync_handler", async(all(not(feature = "sync_handler")), tokio::test))]
+    async fn test_approle_rename_role() {
+        let (root_token, core) = test_rusty_vault_init("test_approle_rename_role");
+        let core = core.read().unwrap();
+
+        // Mount approle auth to path: auth/approle
+        test_mount_auth_api(&core, &root_token, "approle", "approle").await;
+
+        let data = json!({ "role_id": "role-id-123" }).as_object().unwrap().clone();
+        let resp = test_write_api(&core, &root_token, "auth/approle/role/role1", true, Some(data)).await;
+        assert!(resp.is_ok());
+        let resp = test_write_api(&core, &root_token, "auth/approle/role/role2", true, None).await;
+        assert!(resp.is_ok());
+
+        let mut secret_ids = Vec::new();
+        for _ in 0..3 {
+            secret_ids.push(generate_secret_id(&core, &root_token, "approle", "role1").await);
+        }
+
+        let module = core.module_manager.get_module("approle").unwrap();
+        let approle_mod = module.read().unwrap();
+        let approle_module = approle_mod.as_ref().downcast_ref::<AppRoleModule>().unwrap();
+
+        let mut req = Request::new("auth/approle/role/role1");
+        req.storage = core.router.matching_view("auth/approle/").unwrap().map(|arc| arc as Arc<dyn Storage>);
+
+        // Neither a missing role nor a name already taken can be renamed to
+        assert!(approle_module.rename_role(&mut req, "role3", "role4").is_err());
+        assert!(approle_module.rename_role(&mut req, "role1", "role2").is_err());
+        let resp = test_read_api(&core, &root_token, "auth/approle/role/role1/role-id", true).await;
+        assert_eq!(resp.unwrap().unwrap().data.unwrap()["role_id"], "role-id-123");
+        let resp = test_read_api(&core, &root_token, "auth/approle/role/role2", true).await;
+        assert!(resp.unwrap().is_some());
+
+        assert_eq!(approle_module.rename_role(&mut req, "role1", "renamed").unwrap(), 3);
+
+        let resp = test_read_api(&core, &root_token, "auth/approle/role/role1", true).await;
+        assert!(resp.unwrap().is_none());
+        let resp = test_read_api(&core, &root_token, "auth/approle/role/renamed/role-id", true).await;
+        assert_eq!(resp.unwrap().unwrap().data.unwrap()["role_id"], "role-id-123");
+
+        // The secret_ids moved along, accessors included
+        let resp = test_list_api(&core, &root_token, "auth/approle/role/renamed/secret-id", true).await;
+        let mut accessors: Vec<String> = resp.unwrap().unwrap().data.unwrap()["keys"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|key| key.as_str().unwrap().to_string())
+            .collect();
+        accessors.sort();
+        let mut expected: Vec<String> = secret_ids.iter().map(|(_, accessor)| accessor.clone()).collect();
+        expected.sort();
+        assert_eq!(accessors, expected);
+
+        let data = json!({ "secret_id_accessor": secret_ids[0].1 }).as_object().unwrap().clone();
+        let resp =
+            test_write_api(&core, &root_token, "auth/approle/role/renamed/secret-id-accessor/lookup", true, Some(data))
+                .await;
+        assert!(resp.unwrap().is_some());
+
+        // The role_id now logs in to the renamed role
+        for (secret_id, _) in secret_ids.iter() {
+            let resp = test_login(&core, "approle", "role-id-123", secret_id, true).await;
+            let auth = resp.unwrap().unwrap().auth.unwrap();
+            assert_eq!(auth.metadata["role_name"], "renamed");
+        }
+    }
+
+    #[maybe_async::test(feature = "sync_handler", async(all(not(feature = "sync_handler")), tokio::test))]
+    async fn test_approle_rename_role_conflict() {
+        let (root_token, core) = test_rusty_vault_init("test_approle_rename_role_conflict");
+        let core = core.read().unwrap();
+
+        // Mount approle auth to path: auth/approle
+        test_mount_auth_api(&core, &root_token, "approle", "approle").await;
+
+        let module = core.module_manager.get_module("approle").unwrap();
+        let approle_mod = module.read().unwrap();
+        let approle_module = approle_mod.as_ref().downcast_ref::<AppRoleModule>().unwrap();
+        let storage = core.router.matching_view("auth/approle/").unwrap().map(|arc| arc as Arc<dyn Storage>);
+
+        for round in 0..20 {
+            let target = format!("target{}", round);
+            let names = [format!("first{}", round), format!("second{}", round)];
+            for name in names.iter() {
+                let data = json!({ "role_id": format!("{}-role-id", name) }).as_object().unwrap().clone();
+                let resp =
+                    test_write_api(&core, &root_token, &format!("auth/approle/role/{}", name), true, Some(data)).await;
+                assert!(resp.is_ok());
+            }
+
+            // Both renames race for the same new name, only one of them may take it
+            let results: Vec<bool> = std::thread::scope(|s| {
+                let handles: Vec<_> = names
+                    .iter()
+                    .map(|name| {
+                        let storage = storage.clone();
+                        let target = target.as_str();
+                        s.spawn(move || {
+                            let mut req = Request::new(&format!("auth/approle/role/{}", name));
+                            req.storage = storage;
+                            approle_module.rename_role(&mut req, name, target).is_ok()
+                        })
+                    })
+                    .collect();
+                handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+            });
+            assert_eq!(results.iter().filter(|renamed| **renamed).count(), 1);
+
+            let (renamed, kept) = if results[0] { (&names[0], &names[1]) } else { (&names[1], &names[0]) };
+            let resp = test_read_api(&core, &root_token, &format!("auth/approle/role/{}", renamed), true).await;
+            assert!(resp.unwrap().is_none());
+            let resp = test_read_api(&core, &root_token, &format!("auth/approle/role/{}/role-id", target), true).await;
+            assert_eq!(resp.unwrap().unwrap().data.unwrap()["role_id"], format!("{}-role-id", renamed));
+            let resp = test_read_api(&core, &root_token, &format!("auth/approle/role/{}/role-id", kept), true).await;
+            assert_eq!(resp.unwrap().unwrap().data.unwrap()["role_id"], format!("{}-role-id", kept));
+        }
+    }
+
+    #[maybe_async::test(feature = "sync_handler", async(all(not(feature = "sync_handler")), tokio::test))]
+    async fn test_approle_secret_id_count_limit_deletions() {
+        let (root_token, core) = test_rusty_vault_init("test_approle_secret_id_count_limit_deletions");
+        let core = core.read().unwrap();
+
+        // Mount approle auth to path: auth/approle
+        test_mount_auth_api(&core, &root_token, "approle", "approle").await;
+
+        let data = json!({ "role_id": "role-id-123", "secret_id_count_limit": 2, "secret_id_num_uses": 1 })
+            .as_object()
+            .unwrap()
+            .clone();
+        let resp = test_write_api(&core, &root_token, "auth/approle/role/role1", true, Some(data)).await;
+        assert!(resp.is_ok());
+
+        let (secret_id1, _) = generate_secret_id(&core, &root_token, "approle", "role1").await;
+        let (_, accessor2) = generate_secret_id(&core, &root_token, "approle", "role1").await;
+        let resp = test_write_api(&core, &root_token, "auth/approle/role/role1/secret-id", false, None).await;
+        assert!(resp.is_err());
+
+        // Destroying a secret_id makes room for another
+        let data = json!({ "secret_id": secret_id1 }).as_object().unwrap().clone();
+        let resp =
+            test_write_api(&core, &root_token, "auth/approle/role/role1/secret-id/destroy", true, Some(data)).await;
+        assert!(resp.is_ok());
+        let (secret_id3, _) = generate_secret_id(&core, &root_token, "approle", "role1").await;
+        let resp = test_write_api(&core, &root_token, "auth/approle/role/role1/secret-id", false, None).await;
+        assert!(resp.is_err());
+
+        // So does destroying one by its accessor
+        let data = json!({ "secret_id_accessor": accessor2 }).as_object().unwrap().clone();
+        let resp =
+            test_write_api(&core, &root_token, "auth/approle/role/role1/secret-id-accessor/destroy", true, Some(data))
+                .await;
+        assert!(resp.is_ok());
+        let _ = generate_secret_id(&core, &root_token, "approle", "role1").await;
+        let resp = test_write_api(&core, &root_token, "auth/approle/role/role1/secret-id", false, None).await;
+        assert!(resp.is_err());
+
+        // And using up the last use of one at login
+        let resp = test_login(&core, "approle", "role-id-123", &secret_id3, true).await;
+        assert!(resp.is_ok());
+        let _ = generate_secret_id(&core, &root_token, "approle", "role1").await;
+        let resp = test_write_api(&core, &root_token, "auth/approle/role/role1/secret-id", false, None).await;
+        assert!(resp.is_err());
+    }
+
+    #[maybe_async::test(feature = "sync_handler", async(all(not(feature = "sync_handler")), tokio::test))]
+    async fn test_approle_purge_role() {
+        let (root_token, core) = test_rusty_vault_init("test_approle_purge_role");
+        let core = core.read().unwrap();
+
+        // Mount approle auth to path: auth/approle
+        test_mount_auth_api(&core, &root_token, "approle", "approle").await;
+
+        let data = json!({ "role_id": "role-id-123" }).as_object().unwrap().clone();
+        let resp = test_write_api(&core, &root_token, "auth/approle/role/role1", true, Some(data)).await;
+        assert!(resp.is_ok());
+        let data = json!({ "role_id": "role-id-456" }).as_object().unwrap().clone();
+        let resp = test_write_api(&core, &root_token, "auth/approle/role/role2", true, Some(data)).await;
+        assert!(resp.is_ok());
+
+        let mut secret_ids = Vec::new();
+        for _ in 0..3 {
+            secret_ids.push(generate_secret_id(&core, &root_token, "approle", "role1").await);
+        }
+        let (other_secret_id, _) = generate_secret_id(&core, &root_token, "approle", "role2").await;
+
+        let module = core.module_manager.get_module("approle").unwrap();
+        let approle_mod = module.read().unwrap();
+        let approle_module = approle_mod.as_ref().downcast_ref::<AppRoleModule>().unwrap();
+        let storage: Arc<dyn Storage> = core.router.matching_view("auth/approle/").unwrap().unwrap();
+
+        let role = approle_module.load_role(storage.as_ref(), "role1").unwrap().unwrap();
+        let scope = role.secret_id_scope().unwrap();
+        let role_name_hmac = approle_module.role_name_hmac(&role.hmac_key, &role.name).unwrap();
+        assert_eq!(storage.list(scope.accessor_prefix()).unwrap().len(), 4);
+
+        // An earlier purge interrupted after the role_id index went, the rest is still found
+        let mut req = Request::new("auth/approle/role/role1");
+        req.storage = Some(Arc::clone(&storage));
+        assert!(approle_module.delete_role_id(&mut req, &role.role_id).is_ok());
+
+        assert!(approle_module.purge_role(storage.as_ref(), "role1", &role.hmac_key, scope).is_ok());
+
+        // Nothing is left of the role, its secret_ids or their accessors
+        assert!(storage.get("role/role1").unwrap().is_none());
+        assert!(storage.list(&format!("{}{}/", scope.prefix(), role_name_hmac)).unwrap().is_empty());
+        assert!(storage.get(&format!("{}{}", SECRET_ID_COUNT_PREFIX, role_name_hmac)).unwrap().is_none());
+        assert_eq!(storage.list(scope.accessor_prefix()).unwrap().len(), 1);
+        assert_eq!(storage.list(ROLE_ID_PREFIX).unwrap().len(), 1);
+        for (secret_id, _) in secret_ids.iter() {
+            assert!(test_login(&core, "approle", "role-id-123", secret_id, false).await.is_err());
+        }
+
+        // The other role is untouched
+        let resp = test_login(&core, "approle", "role-id-456", &other_secret_id, true).await;
+        assert!(resp.unwrap().unwrap().auth.is_some());
+
+        // Purging again, or purging a role that never existed, succeeds
+        assert!(approle_module.purge_role(storage.as_ref(), "role1", &role.hmac_key, scope).is_ok());
+        assert!(approle_module.purge_role(storage.as_ref(), "role3", &role.hmac_key, scope).is_ok());
+        assert!(approle_module.delete_role(storage.as_ref(), "role1", &role.hmac_key, scope.prefix()).is_ok());
+        assert!(approle_module.delete_role(storage.as_ref(), "role1", &role.hmac_key, "no_such_prefix/").is_err());
+        assert_eq!(approle_module.list_roles(storage.as_ref(), "").unwrap(), vec!["role2"]);
+
+        // delete_role purges a role given its secret_id prefix
+        let role2 = approle_module.load_role(storage.as_ref(), "role2").unwrap().unwrap();
+        let prefix = role2.secret_id_scope().unwrap().prefix();
+        assert!(approle_module.delete_role(storage.as_ref(), "role2", &role2.hmac_key, prefix).is_ok());
+        assert!(test_login(&core, "approle", "role-id-456", &other_secret_id, false).await.is_err());
+        assert!(storage.list(ROLE_ID_PREFIX).unwrap().is_empty());
+        assert!(storage.list(scope.accessor_prefix()).unwrap().is_empty());
+        assert!(approle_module.list_roles(storage.as_ref(), "").unwrap().is_empty());
+    }
+
+    #[maybe_async::test(feature = "sync_handler", async(all(not(feature = "sync_handler")), tokio::test))]
+    async fn test_approle_rotate_hmac_key() {
+        let (root_token, core) = test_rusty_vault_init("test_approle_rotate_hmac_key");
+        let core = core.read().unwrap();
+
+        // Mount approle auth to path: auth/approle
+        test_mount_auth_api(&core, &root_token, "approle", "approle").await;
+
+        let data = json!({ "role_id": "role-id-123" }).as_object().unwrap().clone();
+        let resp = test_write_api(&core, &root_token, "auth/approle/role/role1", true, Some(data)).await;
+        assert!(resp.is_ok());
+
+        let mut secret_ids = Vec::new();
+        for _ in 0..3 {
+            secret_ids.push(generate_secret_id(&core, &root_token, "approle", "role1").await);
+        }
+
+        let module = core.module_manager.get_module("approle").unwrap();
+        let approle_mod = module.read().unwrap();
+        let approle_module = approle_mod.as_ref().downcast_ref::<AppRoleModule>().unwrap();
+        let storage: Arc<dyn Storage> = core.router.matching_view("auth/approle/").unwrap().unwrap();
+
+        let role = approle_module.load_role(storage.as_ref(), "role1").unwrap().unwrap();
+        let scope = role.secret_id_scope().unwrap();
+        let old_key = role.hmac_key.clone();
+        let old_role_name_hmac = approle_module.role_name_hmac(&old_key, &role.name).unwrap();
+        let new_role_name_hmac = approle_module.role_name_hmac("new-hmac-key", &role.name).unwrap();
+        let old_prefix = format!("{}{}/", scope.prefix(), old_role_name_hmac);
+        let new_prefix = format!("{}{}/", scope.prefix(), new_role_name_hmac);
+        let secret_id_hmacs = storage.list(&old_prefix).unwrap();
+        let entries: Vec<SecretIdStorageEntry> = secret_id_hmacs
+            .iter()
+            .map(|hmac| {
+                approle_module
+                    .get_secret_id_storage_entry(storage.as_ref(), scope, &old_role_name_hmac, hmac)
+                    .unwrap()
+                    .unwrap()
+            })
+            .collect();
+
+        // A wrong key, or the same one, is refused
+        assert!(approle_module
+            .rotate_hmac_key(storage.as_ref(), "role1", "wrong-key", "new-hmac-key", scope.prefix())
+            .is_err());
+        assert!(approle_module.rotate_hmac_key(storage.as_ref(), "role1", &old_key, &old_key, scope.prefix()).is_err());
+
+        assert_eq!(
+            approle_module
+                .rotate_hmac_key(storage.as_ref(), "role1", &old_key, "new-hmac-key", scope.prefix())
+                .unwrap(),
+            3
+        );
+
+        // Every secret_id moved under the new key with its data, bound to the new role_id_hmac
+        let role = approle_module.load_role(storage.as_ref(), "role1").unwrap().unwrap();
+        assert_eq!((role.hmac_key.as_str(), role.previous_hmac_key.as_str()), ("new-hmac-key", old_key.as_str()));
+        assert!(storage.list(&old_prefix).unwrap().is_empty());
+        assert_eq!(storage.list(&new_prefix).unwrap(), secret_id_hmacs);
+        for (hmac, entry) in secret_id_hmacs.iter().zip(entries.iter()) {
+            let moved = approle_module
+                .get_secret_id_storage_entry(storage.as_ref(), scope, &new_role_name_hmac, hmac)
+                .unwrap()
+                .unwrap();
+            assert_eq!(moved.role_id_hmac, create_hmac("new-hmac-key", "role-id-123").unwrap());
+            assert_eq!(SecretIdStorageEntry { role_id_hmac: entry.role_id_hmac.clone(), ..moved }, *entry);
+        }
+
+        // The secret_ids created before the rotation still log in, and are
+        // moved to the new key on the way
+        let (secret_id, accessor) = &secret_ids[0];
+        let resp = test_login(&core, "approle", "role-id-123", secret_id, true).await;
+        assert!(resp.unwrap().unwrap().auth.is_some());
+        let upgraded = approle_module
+            .find_secret_id_hmac(storage.as_ref(), scope, &new_role_name_hmac, "new-hmac-key", secret_id)
+            .unwrap();
+        assert!(approle_module
+            .get_secret_id_storage_entry(storage.as_ref(), scope, &new_role_name_hmac, &upgraded)
+            .unwrap()
+            .is_some());
+        assert_eq!(storage.list(&new_prefix).unwrap().len(), 3);
+        let resp = test_write_api(
+            &core,
+            &root_token,
+            "auth/approle/role/role1/secret-id-accessor/lookup",
+            true,
+            Some(json!({ "secret_id_accessor": accessor }).as_object().unwrap().clone()),
+        )
+        .await;
+        assert!(resp.unwrap().unwrap().data.is_some());
+
+        // An interrupted rotation left an old entry behind, running it again moves it
+        let used = create_hmac(&old_key, secret_id).unwrap();
+        let (hmac, entry) = secret_id_hmacs.iter().zip(entries.iter()).find(|(hmac, _)| **hmac != used).unwrap();
+        approle_module.set_secret_id_storage_entry(storage.as_ref(), scope, &old_role_name_hmac, hmac, entry).unwrap();
+        assert_eq!(
+            approle_module
+                .rotate_hmac_key(storage.as_ref(), "role1", &old_key, "new-hmac-key", scope.prefix())
+                .unwrap(),
+            1
+        );
+        assert!(storage.list(&old_prefix).unwrap().is_empty());
+        for (secret_id, _) in secret_ids.iter() {
+            let resp = test_login(&core, "approle", "role-id-123", secret_id, true).await;
+            assert!(resp.unwrap().unwrap().auth.is_some());
+        }
+
+        // New secret_ids use the new key
+        let (secret_id, _) = generate_secret_id(&core, &root_token, "approle", "role1").await;
+        let resp = test_login(&core, "approle", "role-id-123", &secret_id, true).await;
+        assert!(resp.unwrap().unwrap().auth.is_some());
+        assert_eq!(storage.list(&new_prefix).unwrap().len(), 4);
+
+        // The secret_ids created under the old key never expire, it can not be dropped yet
+        assert!(approle_module
+            .rotate_hmac_key(storage.as_ref(), "role1", "new-hmac-key", "newer-hmac-key", scope.prefix())
+            .is_err());
+    }
+
+    #[maybe_async::test(feature = "sync_handler", async(all(not(feature = "sync_handler")), tokio::test))]
+    async fn test_approle_reserved_prefixes() {
+        let (root_token, core) = test_rusty_vault_init("test_approle_reserved_prefixes");
+        let core = core.read().unwrap();
+
+        // Mount approle auth to path: auth/approle
+        test_mount_auth_api(&core, &root_token, "approle", "approle").await;
+
+        let data = json!({ "role_id": "role-id-123" }).as_object().unwrap().clone();
+        let resp = test_write_api(&core, &root_token, "auth/approle/role/role1", true, Some(data)).await;
+        assert!(resp.is_ok());
+        let (secret_id, _) = generate_secret_id(&core, &root_token, "approle", "role1").await;
+
+        let module = core.module_manager.get_module("approle").unwrap();
+        let approle_mod = module.read().unwrap();
+        let approle_module = approle_mod.as_ref().downcast_ref::<AppRoleModule>().unwrap();
+
+        let storage: Arc<dyn Storage> = core.router.matching_view("auth/approle/").unwrap().unwrap();
+        let mut req = Request::new("auth/approle/role/role1");
+        req.storage = Some(Arc::clone(&storage));
+
+        // A role named after the hashed index key of the existing accessor
+        let accessor_hmacs = storage.list(SECRET_ID_ACCESSOR_PREFIX).unwrap();
+        assert_eq!(accessor_hmacs.len(), 1);
+        let accessor_key = format!("{}{}", SECRET_ID_ACCESSOR_PREFIX, accessor_hmacs[0]);
+        let accessor_entry = storage.get(&accessor_key).unwrap().unwrap();
+
+        let mut role = approle_module.get_role(&mut req, "role1").unwrap().unwrap();
+        role.role_id = "role-id-456".to_string();
+        for name in [
+            format!("../{}", accessor_key),
+            format!("a/../../{}", accessor_key),
+            format!("./{}", accessor_key),
+            format!("../{}roleid", ROLE_ID_PREFIX),
+        ] {
+            assert!(approle_module.set_role(&mut req, &name, &role, "").is_err());
+            assert!(approle_module.rename_role(&mut req, "role1", &name).is_err());
+        }
+
+        // The index was left alone and the role still logs in
+        assert_eq!(storage.get(&accessor_key).unwrap().unwrap().value, accessor_entry.value);
+        assert!(approle_module.get_role_id(&mut req, "role-id-456").unwrap().is_none());
+        let resp = test_login(&core, "approle", "role-id-123", &secret_id, true).await;
+        assert!(resp.unwrap().unwrap().auth.is_some());
+
+        // The guard itself, on keys that resolve under each reserved prefix
+        for prefix in RESERVED_PREFIXES.iter() {
+            assert!(check_unreserved_key(&format!("{}abc", prefix)).is_err());
+            assert!(check_unreserved_key(&format!("role/{}abc", prefix)).is_ok());
+        }
+    }
 }
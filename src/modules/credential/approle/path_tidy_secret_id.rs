@@ -1,24 +1,25 @@
 use std::{
     collections::HashMap,
+    mem,
     sync::{
         atomic::{AtomicU32, Ordering},
-        Arc,
+        Arc, PoisonError,
     },
-    time::SystemTime,
 };
 
 use go_defer::defer;
 
 use super::{
-    validation::SecretIdAccessorStorageEntry, AppRoleBackend, AppRoleBackendInner, SECRET_ID_ACCESSOR_LOCAL_PREFIX,
-    SECRET_ID_ACCESSOR_PREFIX, SECRET_ID_LOCAL_PREFIX, SECRET_ID_PREFIX,
+    audit::{AuditEvent, AuditEventType},
+    validation::SecretIdAccessorStorageEntry,
+    AppRoleBackend, AppRoleBackendInner, SecretIdScope, WAL_RECOVERY_MIN_AGE,
 };
 use crate::{
     context::Context,
     errors::RvError,
     logical::{Backend, Operation, Path, PathOperation, Request, Response, CTX_KEY_BACKEND_PATH},
     new_path, new_path_internal,
-    storage::Storage,
+    storage::{wal, Storage},
 };
 
 pub const CTX_KEY_BACKEND_PATH_INNER: &str = "backend.path.inner";
@@ -55,6 +56,28 @@ impl AppRoleBackendInner {
             log::info!("done checking entries, num_entries: {}", check_count.load(Ordering::SeqCst));
         );
 
+        // Roll back the registrations interrupted by a crash first, so that
+        // their leftovers are not mistaken for live entries
+        if let Err(err) = wal::recover(storage.as_ref(), self.clock.now(), WAL_RECOVERY_MIN_AGE) {
+            log::error!("error recovering the write-ahead log, err: {}", err);
+        }
+
+        // Give the secret_ids stored without an expiration the one their TTL implies,
+        // before the expiration checks below rely on it
+        match self.migrate_secret_id_expiration(storage.as_ref()) {
+            Ok(Some(repaired)) => log::info!("migrated secret ID expirations, repaired: {}", repaired),
+            Ok(None) => {}
+            Err(err) => log::error!("error migrating secret ID expirations, err: {}", err),
+        }
+
+        // Move the roles stored under a name that is not normalized, so that
+        // their secret_ids are found under the HMAC of the normalized name
+        match self.migrate_role_names(storage.as_ref()) {
+            Ok(Some(migrated)) => log::info!("migrated role names, migrated: {}", migrated),
+            Ok(None) => {}
+            Err(err) => log::error!("error migrating role names, err: {}", err),
+        }
+
         let salt = self.salt.read();
         if salt.is_err() {
             log::error!("error tidying secret IDs, err: {}", salt.unwrap_err());
@@ -63,7 +86,11 @@ impl AppRoleBackendInner {
 
         let salt = salt.unwrap();
 
-        let tidy_func = |secret_id_prefix_to_use: &str, accessor_id_prefix_to_use: &str| -> Result<(), RvError> {
+        let expiring_notifier = self.expiring_notifier.read().unwrap_or_else(PoisonError::into_inner).clone();
+
+        let tidy_func = |scope: SecretIdScope| -> Result<(), RvError> {
+            let secret_id_prefix_to_use = scope.prefix();
+            let accessor_id_prefix_to_use = scope.accessor_prefix();
             log::info!("listing accessors, prefix: {}", accessor_id_prefix_to_use);
             // List all the accessors and add them all to a map
             // These hashes are the result of salting the accessor id.
@@ -78,59 +105,90 @@ impl AppRoleBackendInner {
                 }
 
                 let entry = storage_entry.unwrap();
-                let ret: SecretIdAccessorStorageEntry = serde_json::from_slice(entry.value.as_slice())?;
-                accessor_entry_by_hash.insert(accessor_hash.clone(), ret);
+                match serde_json::from_slice::<SecretIdAccessorStorageEntry>(entry.value.as_slice()) {
+                    Ok(ret) => {
+                        accessor_entry_by_hash.insert(accessor_hash.clone(), ret);
+                    }
+                    Err(err) => self.handle_corrupt_entry(Arc::as_ref(&storage), &entry_index, err.into())?,
+                }
             }
 
-            let mut secret_id_cleanup_func = |secret_id_hmac: &str,
-                                              role_name_hmac: &str,
-                                              secret_id_prefix_to_use: &str|
-             -> Result<(), RvError> {
+            let mut expiring_events = Vec::new();
+
+            let mut secret_id_cleanup_func = |secret_id_hmac: &str, role_name_hmac: &str| -> Result<(), RvError> {
                 check_count.fetch_add(1, Ordering::SeqCst);
 
                 let s = Arc::as_ref(&storage);
 
                 let lock_entry = self.secret_id_locks.get_lock(secret_id_hmac);
-                let _locked = lock_entry.lock.write()?;
-
-                let secret_id_storage_entry = self
-                    .get_secret_id_storage_entry(s, secret_id_prefix_to_use, role_name_hmac, secret_id_hmac)?
-                    .ok_or(RvError::ErrResponse(format!(
-                        "entry for secret id was nil, secret_id_hmac: {}",
-                        secret_id_hmac
-                    )))?;
+                let _locked = lock_entry.write()?;
+
+                let secret_id_storage_entry =
+                    match self.get_secret_id_storage_entry(s, scope, role_name_hmac, secret_id_hmac) {
+                        Err(err @ RvError::SerdeJson { .. }) => {
+                            let entry_index =
+                                format!("{}{}/{}", secret_id_prefix_to_use, role_name_hmac, secret_id_hmac);
+                            self.handle_corrupt_entry(s, &entry_index, err)?;
+                            return Ok(());
+                        }
+                        ret => ret?.ok_or(RvError::ErrResponse(format!(
+                            "entry for secret id was nil, secret_id_hmac: {}",
+                            secret_id_hmac
+                        )))?,
+                    };
 
                 // If a secret ID entry does not have a corresponding accessor
                 // entry, revoke the secret ID immediately
-                if self
-                    .get_secret_id_accessor_entry(
-                        s,
-                        &secret_id_storage_entry.secret_id_accessor,
-                        secret_id_prefix_to_use,
-                    )?
-                    .is_none()
-                {
-                    self.delete_secret_id_storage_entry(s, secret_id_prefix_to_use, role_name_hmac, secret_id_hmac)?;
+                if self.get_secret_id_accessor_entry(s, &secret_id_storage_entry.secret_id_accessor, scope)?.is_none() {
+                    self.delete_secret_id_storage_entry(s, scope, role_name_hmac, secret_id_hmac)?;
                     return Ok(());
                 }
 
                 // ExpirationTime not being set indicates non-expiring SecretIDs
-                if SystemTime::now() > secret_id_storage_entry.expiration_time {
+                let now = self.clock.now();
+                let expired = self.is_past_expiration(secret_id_storage_entry.expiration_time, now)?;
+                if expired && self.is_created_in_future(secret_id_storage_entry.creation_time, now)? {
+                    // The clock went back since the secret ID was created, and
+                    // its expiration can not be trusted. It is left for a later
+                    // tidy rather than deleted.
+                    log::warn!(
+                        "skipping expired secret ID created in the future, the clock may have gone back, \
+                         secret_id_hmac: {}, creation_time: {:?}, now: {:?}",
+                        secret_id_hmac,
+                        secret_id_storage_entry.creation_time,
+                        now
+                    );
+                } else if expired {
                     log::info!("found expired secret ID");
                     // Clean up the accessor of the secret ID first
-                    self.delete_secret_id_accessor_entry(
-                        s,
-                        &secret_id_storage_entry.secret_id_accessor,
-                        secret_id_prefix_to_use,
-                    )?;
+                    self.delete_secret_id_accessor_entry(s, &secret_id_storage_entry.secret_id_accessor, scope)?;
+
+                    self.delete_secret_id_storage_entry(s, scope, role_name_hmac, secret_id_hmac)?;
 
-                    self.delete_secret_id_storage_entry(s, secret_id_prefix_to_use, role_name_hmac, secret_id_hmac)?;
+                    let mut event = AuditEvent::new(AuditEventType::SecretIdDelete, self.clock.now());
+                    event.role_name_hmac = role_name_hmac.to_string();
+                    event.secret_id_hmac = secret_id_hmac.to_string();
+                    event.secret_id_accessor = secret_id_storage_entry.secret_id_accessor;
+                    self.audit(event);
 
                     return Ok(());
                 }
 
                 // At this point, the secret ID is not expired and is valid. Flag
                 // the corresponding accessor as not needing attention.
+                if let Some(notifier) = expiring_notifier.as_ref() {
+                    if !secret_id_storage_entry.secret_id_ttl.is_zero() {
+                        let event = notifier.check(
+                            role_name_hmac,
+                            secret_id_hmac,
+                            &secret_id_storage_entry.secret_id_accessor,
+                            secret_id_storage_entry.expiration_time,
+                            now,
+                        );
+                        expiring_events.extend(event.map(|event| (secret_id_hmac.to_string(), event)));
+                    }
+                }
+
                 let salt_id = salt.as_ref().unwrap().salt_id(&secret_id_storage_entry.secret_id_accessor)?;
                 skip_hashes.insert(salt_id, true);
 
@@ -146,8 +204,22 @@ impl AppRoleBackendInner {
                 let key = format!("{}{}/", secret_id_prefix_to_use, role_name_hmac);
                 let secret_id_hmacs = storage.list(&key)?;
                 for secret_id_hmac in secret_id_hmacs.iter() {
-                    secret_id_cleanup_func(secret_id_hmac, role_name_hmac, secret_id_prefix_to_use)?;
+                    secret_id_cleanup_func(secret_id_hmac, role_name_hmac)?;
                 }
+
+                // Reconcile the live secret_id counter of the role with what is
+                // actually left. The entries are counted under the count lock,
+                // which registrations hold while writing theirs, so none of
+                // them is missed.
+                let count_lock_entry = self.secret_id_count_locks.get_lock(role_name_hmac);
+                let _count_locked = count_lock_entry.write()?;
+                let live_count = self.count_live_secret_ids(Arc::as_ref(&storage), scope, role_name_hmac)?;
+                self.set_secret_id_count(Arc::as_ref(&storage), role_name_hmac, live_count)?;
+            }
+
+            // Raised once the secret ID locks are released, the hook may act on them
+            if let Some(notifier) = expiring_notifier.as_ref() {
+                notifier.notify(expiring_events);
             }
 
             if accessor_hashes.len() > skip_hashes.len() {
@@ -167,7 +239,7 @@ impl AppRoleBackendInner {
 
                 for (accessor_hash, accessor_entry) in accessor_entry_by_hash.iter() {
                     let lock_entry = self.secret_id_locks.get_lock(&accessor_entry.secret_id_hmac);
-                    let _locked = lock_entry.lock.write()?;
+                    let _locked = lock_entry.write()?;
 
                     // Don't clean up accessor index entry if secretid cleanup func
                     // determined that it should stay.
@@ -189,14 +261,35 @@ impl AppRoleBackendInner {
             Ok(())
         };
 
-        if let Err(err) = tidy_func(SECRET_ID_PREFIX, SECRET_ID_ACCESSOR_PREFIX) {
+        if let Err(err) = tidy_func(SecretIdScope::Global) {
             log::error!("error tidying global secret IDs, error: {}", err);
             return;
         }
 
-        if let Err(err) = tidy_func(SECRET_ID_LOCAL_PREFIX, SECRET_ID_ACCESSOR_LOCAL_PREFIX) {
+        if let Err(err) = tidy_func(SecretIdScope::Local) {
             log::error!("error tidying local secret IDs, error: {}", err);
         }
+
+        if let Some(notifier) = expiring_notifier.as_ref() {
+            notifier.forget_expired(self.clock.now());
+        }
+
+        // Catch the orphans left behind by registrations that raced with the tidy
+        mem::drop(salt);
+        for scope in SecretIdScope::ALL {
+            match self.reconcile(storage.as_ref(), scope) {
+                Ok(report) if !report.is_consistent() => log::warn!(
+                    "reconciled secret IDs, prefix: {}, dangling accessors: {}, orphaned secret IDs: {}, metadata \
+                     index repairs: {}",
+                    scope.prefix(),
+                    report.dangling_accessors.len(),
+                    report.orphaned_secret_ids.len(),
+                    report.metadata_index_repairs
+                ),
+                Ok(_) => {}
+                Err(err) => log::error!("error reconciling secret IDs, prefix: {}, error: {}", scope.prefix(), err),
+            }
+        }
     }
 
     pub fn tidy_secret_id(&self, backend: &dyn Backend, req: &mut Request) -> Result<Option<Response>, RvError> {
@@ -253,13 +346,20 @@ mod test {
     use as_any::Downcast;
 
     use super::{
-        super::{path_role::RoleEntry, AppRoleModule},
+        super::{
+            expiring::SecretIdExpiringEvent,
+            path_role::RoleEntry,
+            test::{new_test_inner, register_test_secret_id},
+            validation::{create_hmac, OnCorrupt, SecretIdStorageEntry},
+            AppRoleModule, CORRUPT_PREFIX, SECRET_ID_ACCESSOR_PREFIX, SECRET_ID_COUNT_PREFIX, SECRET_ID_PREFIX,
+        },
         *,
     };
     use crate::{
         logical::{Operation, Request},
         storage::{Storage, StorageEntry},
         test_utils::{test_mount_auth_api, test_rusty_vault_init},
+        utils::{clock::MockClock, ttl::LeaseTtl},
     };
 
     #[actix_rt::test]
@@ -286,7 +386,7 @@ mod test {
             role_id: "testroleid".to_string(),
             hmac_key: "testhmackey".to_string(),
             bind_secret_id: true,
-            secret_id_ttl: Duration::from_secs(300),
+            secret_id_ttl: LeaseTtl::from_secs(300),
             policies: vec!["a".to_string(), "b".to_string(), "c".to_string()],
             ..Default::default()
         };
@@ -377,7 +477,7 @@ mod test {
             role_id: "testroleid".to_string(),
             hmac_key: "testhmackey".to_string(),
             bind_secret_id: true,
-            secret_id_ttl: Duration::from_secs(300),
+            secret_id_ttl: LeaseTtl::from_secs(300),
             policies: vec!["a".to_string(), "b".to_string(), "c".to_string()],
             ..Default::default()
         };
@@ -483,4 +583,253 @@ mod test {
         let secret_ids = secret_ids.unwrap();
         assert_eq!(secret_ids.len(), *num);
     }
+
+    #[actix_rt::test]
+    async fn test_approle_tidy_expired_secret_id_with_mock_clock() {
+        let start = std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = Arc::new(MockClock::new(start));
+
+        let (mut inner, storage) = new_test_inner("test_approle_tidy_expired_secret_id_with_mock_clock");
+        inner.clock = clock.clone();
+
+        let mut secret_entry = SecretIdStorageEntry { secret_id_ttl: LeaseTtl::from_secs(60), ..Default::default() };
+        let ret = inner.register_secret_id_entry(
+            storage.as_ref(),
+            "role1",
+            "secret1",
+            "testhmackey",
+            SecretIdScope::Global,
+            0,
+            &mut secret_entry,
+        );
+        assert!(ret.is_ok());
+        assert_eq!(secret_entry.creation_time, start);
+        assert_eq!(secret_entry.expiration_time, start + Duration::from_secs(60));
+
+        let role_hmacs = storage.list(SECRET_ID_PREFIX).unwrap();
+        assert_eq!(role_hmacs.len(), 1);
+        let key = format!("{}{}", SECRET_ID_PREFIX, role_hmacs[0]);
+
+        // Exactly at the expiration time the secret_id must be kept
+        clock.advance(Duration::from_secs(60));
+        inner.tidy_secret_id_routine(Arc::clone(&storage)).await;
+        assert_eq!(storage.list(&key).unwrap().len(), 1);
+        assert_eq!(storage.list(SECRET_ID_ACCESSOR_PREFIX).unwrap().len(), 1);
+
+        // One second later it is tidied along with its accessor
+        clock.advance(Duration::from_secs(1));
+        inner.tidy_secret_id_routine(Arc::clone(&storage)).await;
+        assert_eq!(storage.list(&key).unwrap().len(), 0);
+        assert_eq!(storage.list(SECRET_ID_ACCESSOR_PREFIX).unwrap().len(), 0);
+    }
+
+    #[actix_rt::test]
+    async fn test_approle_tidy_expiring_hook() {
+        let start = std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = Arc::new(MockClock::new(start));
+
+        let (mut inner, storage) = new_test_inner("test_approle_tidy_expiring_hook");
+        inner.clock = clock.clone();
+
+        let events: Arc<Mutex<Vec<SecretIdExpiringEvent>>> = Arc::new(Mutex::new(Vec::new()));
+        let events_ref = Arc::clone(&events);
+        let hook = Box::new(move |event: &SecretIdExpiringEvent| events_ref.lock().unwrap().push(event.clone()));
+        assert!(inner.set_expiring_hook(Duration::from_secs(300), hook).is_ok());
+
+        // One secret_id expiring within the lead time, one later, and one never
+        let accessors: Vec<String> = [("secret1", 120), ("secret2", 600), ("secret3", 0)]
+            .iter()
+            .map(|(secret_id, ttl)| {
+                let mut entry = SecretIdStorageEntry { secret_id_ttl: LeaseTtl::from_secs(*ttl), ..Default::default() };
+                inner
+                    .register_secret_id_entry(
+                        storage.as_ref(),
+                        "role1",
+                        secret_id,
+                        "testhmackey",
+                        SecretIdScope::Global,
+                        0,
+                        &mut entry,
+                    )
+                    .unwrap();
+                entry.secret_id_accessor
+            })
+            .collect();
+        let role_name_hmac = create_hmac("testhmackey", "role1").unwrap();
+        let take_events = || mem::take(&mut *events.lock().unwrap());
+
+        inner.tidy_secret_id_routine(Arc::clone(&storage)).await;
+        let fired = take_events();
+        assert_eq!(fired.len(), 1);
+        assert_eq!(fired[0].secret_id_accessor, accessors[0]);
+        assert_eq!(fired[0].role_name_hmac, role_name_hmac);
+        assert_eq!(fired[0].expiration_time, start + Duration::from_secs(120));
+        assert_eq!(fired[0].remaining, Duration::from_secs(120));
+
+        // Still in its window at the next pass, it is not reported again
+        clock.advance(Duration::from_secs(30));
+        inner.tidy_secret_id_routine(Arc::clone(&storage)).await;
+        assert!(take_events().is_empty());
+
+        // The first one expires and is tidied without an event, the second one
+        // enters its window
+        clock.advance(Duration::from_secs(300));
+        inner.tidy_secret_id_routine(Arc::clone(&storage)).await;
+        let fired = take_events();
+        assert_eq!(fired.len(), 1);
+        assert_eq!(fired[0].secret_id_accessor, accessors[1]);
+        assert_eq!(fired[0].remaining, Duration::from_secs(270));
+        let key = format!("{}{}/", SECRET_ID_PREFIX, role_name_hmac);
+        assert_eq!(storage.list(&key).unwrap().len(), 2);
+
+        inner.tidy_secret_id_routine(Arc::clone(&storage)).await;
+        assert!(take_events().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_approle_tidy_clock_jumped_back() {
+        let start = std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = Arc::new(MockClock::new(start));
+
+        let (mut inner, storage) = new_test_inner("test_approle_tidy_clock_jumped_back");
+        inner.clock = clock.clone();
+
+        let mut secret_entry = SecretIdStorageEntry { secret_id_ttl: LeaseTtl::from_secs(600), ..Default::default() };
+        register_test_secret_id(&inner, storage.as_ref(), "role1", "secret1", SecretIdScope::Global, &mut secret_entry);
+
+        let role_name_hmac = create_hmac("testhmackey", "role1").unwrap();
+        let secret_id_hmac = create_hmac("testhmackey", "secret1").unwrap();
+        let key = format!("{}{}/", SECRET_ID_PREFIX, role_name_hmac);
+
+        // The clock goes back an hour, and a renewal sets the expiration from
+        // there, before the creation time
+        clock.set(start - Duration::from_secs(3600));
+        assert!(inner
+            .renew_secret_id(
+                storage.as_ref(),
+                SecretIdScope::Global,
+                &role_name_hmac,
+                &secret_id_hmac,
+                Duration::from_secs(60),
+                LeaseTtl::ZERO,
+            )
+            .is_ok());
+
+        // Expired by the expiration time, but created in the future: tidy
+        // keeps it
+        clock.advance(Duration::from_secs(61));
+        inner.tidy_secret_id_routine(Arc::clone(&storage)).await;
+        assert_eq!(storage.list(&key).unwrap().len(), 1);
+        assert_eq!(storage.list(SECRET_ID_ACCESSOR_PREFIX).unwrap().len(), 1);
+
+        // Once the clock has caught up, it is tidied
+        clock.set(start + Duration::from_secs(1));
+        inner.tidy_secret_id_routine(Arc::clone(&storage)).await;
+        assert_eq!(storage.list(&key).unwrap().len(), 0);
+        assert_eq!(storage.list(SECRET_ID_ACCESSOR_PREFIX).unwrap().len(), 0);
+    }
+
+    #[actix_rt::test]
+    async fn test_approle_secret_id_count_limit() {
+        let clock = Arc::new(MockClock::new(std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+
+        let (mut inner, storage) = new_test_inner("test_approle_secret_id_count_limit");
+        inner.clock = clock.clone();
+
+        let register = |secret_id: &str, ttl: u64| {
+            let mut secret_entry =
+                SecretIdStorageEntry { secret_id_ttl: LeaseTtl::from_secs(ttl), ..Default::default() };
+            inner.register_secret_id_entry(
+                storage.as_ref(),
+                "role1",
+                secret_id,
+                "testhmackey",
+                SecretIdScope::Global,
+                2,
+                &mut secret_entry,
+            )
+        };
+
+        assert!(register("secret1", 60).is_ok());
+        assert!(register("secret2", 3600).is_ok());
+
+        // The limit is reached
+        let ret = register("secret3", 3600);
+        assert!(ret.is_err());
+        assert!(ret.unwrap_err().to_string().contains("maximum number of 2 live secret_ids"));
+
+        // Once the first one expired it no longer counts, even before tidy has run
+        clock.advance(Duration::from_secs(61));
+        assert!(register("secret3", 3600).is_ok());
+        assert!(register("secret4", 3600).is_err());
+
+        // Tidy removes the expired secret_id and reconciles the counter
+        inner.tidy_secret_id_routine(Arc::clone(&storage)).await;
+        assert!(register("secret4", 3600).is_err());
+
+        // A lost counter is rebuilt from the entries, counting the secret_ids
+        // that never expire as live
+        let role_name_hmac = inner.role_name_hmac("testhmackey", "role2").unwrap();
+        let mut secret_entry = SecretIdStorageEntry::default();
+        register_test_secret_id(&inner, storage.as_ref(), "role2", "secret5", SecretIdScope::Global, &mut secret_entry);
+        assert!(secret_entry.secret_id_ttl.is_zero());
+        storage.delete(&format!("{}{}", SECRET_ID_COUNT_PREFIX, role_name_hmac)).unwrap();
+        clock.advance(Duration::from_secs(86400));
+        assert_eq!(inner.get_secret_id_count(storage.as_ref(), SecretIdScope::Global, &role_name_hmac).unwrap(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_approle_tidy_corrupt_entries() {
+        let clock = Arc::new(MockClock::new(std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+
+        let (mut inner, storage) = new_test_inner("test_approle_tidy_corrupt_entries");
+        inner.clock = clock.clone();
+
+        for (secret_id, ttl) in [("secret1", 60), ("secret2", 3600)] {
+            let mut secret_entry =
+                SecretIdStorageEntry { secret_id_ttl: LeaseTtl::from_secs(ttl), ..Default::default() };
+            register_test_secret_id(
+                &inner,
+                storage.as_ref(),
+                "role1",
+                secret_id,
+                SecretIdScope::Global,
+                &mut secret_entry,
+            );
+        }
+
+        let role_hmacs = storage.list(SECRET_ID_PREFIX).unwrap();
+        let key = format!("{}{}", SECRET_ID_PREFIX, role_hmacs[0]);
+
+        // Inject garbage next to the valid entries
+        let garbage_secret_id = format!("{}garbage", key);
+        let garbage_accessor = format!("{}garbage", SECRET_ID_ACCESSOR_PREFIX);
+        for k in [&garbage_secret_id, &garbage_accessor] {
+            assert!(storage.put(&StorageEntry { key: k.clone(), value: b"{not json".to_vec() }).is_ok());
+        }
+
+        // The expired secret_id is still tidied, the garbage is left alone
+        clock.advance(Duration::from_secs(61));
+        inner.tidy_secret_id_routine(Arc::clone(&storage)).await;
+        assert_eq!(storage.list(&key).unwrap().len(), 2);
+        assert!(storage.get(&garbage_secret_id).unwrap().is_some());
+        assert!(storage.get(&garbage_accessor).unwrap().is_some());
+        assert_eq!(storage.list(SECRET_ID_ACCESSOR_PREFIX).unwrap().len(), 2);
+
+        // Quarantine moves the garbage away and keeps the valid secret_id
+        inner.set_on_corrupt(OnCorrupt::Quarantine).unwrap();
+        inner.tidy_secret_id_routine(Arc::clone(&storage)).await;
+        assert_eq!(storage.list(&key).unwrap().len(), 1);
+        assert!(storage.get(&garbage_secret_id).unwrap().is_none());
+        assert!(storage.get(&garbage_accessor).unwrap().is_none());
+        assert_eq!(storage.list(SECRET_ID_ACCESSOR_PREFIX).unwrap().len(), 1);
+        let quarantined = storage.get(&format!("{}{}", CORRUPT_PREFIX, garbage_secret_id)).unwrap().unwrap();
+        assert_eq!(quarantined.value, b"{not json".to_vec());
+        assert!(storage.get(&format!("{}{}", CORRUPT_PREFIX, garbage_accessor)).unwrap().is_some());
+
+        // Fail surfaces the deserialization error to the caller
+        inner.set_on_corrupt(OnCorrupt::Fail).unwrap();
+        let err = serde_json::from_slice::<SecretIdStorageEntry>(b"{not json").unwrap_err();
+        assert!(inner.handle_corrupt_entry(storage.as_ref(), &garbage_secret_id, err.into()).is_err());
+    }
 }
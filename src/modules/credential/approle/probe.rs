@@ -0,0 +1,157 @@
+//! Probe of a secret_id, checking it without using it.
+//!
+//! Monitoring wants to know whether a known secret_id still exists and is valid, but a login would
+//! consume one of its uses. `probe_secret_id` looks the secret_id up the way a login does and
+//! reports a `SecretIdStatus`, without writing anything. The metadata is not part of the report,
+//! a probe only learns whether the secret_id can still be used.
+
+use std::time::{Duration, SystemTime};
+
+use super::{AppRoleBackendInner, SecretIdScope};
+use crate::{errors::RvError, storage::Storage};
+
+// SecretIdStatus is what probe_secret_id reports about a secret_id. It says
+// whether the secret_id can still be used, and leaves out everything else,
+// the metadata in particular.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SecretIdStatus {
+    pub exists: bool,
+    pub expired: bool,
+    // None when the secret_id has no usage limit
+    pub remaining_uses: Option<i64>,
+    // None when the secret_id never expires, zero once it has expired
+    pub remaining_ttl: Option<Duration>,
+    // None until the secret_id is used for the first time
+    pub last_login_time: Option<SystemTime>,
+}
+
+impl AppRoleBackendInner {
+    // probe_secret_id checks a secret_id the way a login would, without using
+    // it up. Only the read lock is taken and the entry is left untouched, so
+    // monitoring can call it as often as it wants. An unknown secret_id is
+    // reported as not existing rather than as an error.
+    pub fn probe_secret_id(
+        &self,
+        storage: &dyn Storage,
+        role_name: &str,
+        secret_id: &str,
+        hmac_key: &str,
+        scope: SecretIdScope,
+    ) -> Result<SecretIdStatus, RvError> {
+        let role_name_hmac = self.role_name_hmac(hmac_key, role_name)?;
+        // Not resolve_secret_id_hmac, a probe does not move the entry
+        let secret_id_hmac = self.find_secret_id_hmac(storage, scope, &role_name_hmac, hmac_key, secret_id)?;
+
+        let lock_entry = self.secret_id_locks.get_lock(&secret_id_hmac);
+        let _locked = lock_entry.read()?;
+
+        let Some(entry) = self.get_secret_id_storage_entry(storage, scope, &role_name_hmac, &secret_id_hmac)? else {
+            return Ok(SecretIdStatus::default());
+        };
+
+        let remaining_uses = match entry.secret_id_num_uses {
+            0 => None,
+            num_uses => Some(num_uses.max(0)),
+        };
+
+        let last_login_time = entry.last_login_time;
+        if entry.secret_id_ttl.is_zero() {
+            return Ok(SecretIdStatus {
+                exists: true,
+                expired: false,
+                remaining_uses,
+                remaining_ttl: None,
+                last_login_time,
+            });
+        }
+
+        let now = self.clock.now();
+        Ok(SecretIdStatus {
+            exists: true,
+            expired: self.is_past_expiration(entry.expiration_time, now)?,
+            remaining_uses,
+            remaining_ttl: Some(entry.expiration_time.duration_since(now).unwrap_or(Duration::ZERO)),
+            last_login_time,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, sync::Arc};
+
+    use super::{
+        super::{
+            test::{new_test_inner, register_test_secret_id},
+            validation::{create_hmac, secret_id_entry_index, SecretIdStorageEntry},
+        },
+        *,
+    };
+    use crate::utils::{clock::MockClock, ttl::LeaseTtl};
+
+    #[test]
+    fn test_approle_probe_secret_id() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = Arc::new(MockClock::new(start));
+
+        let (mut inner, storage) = new_test_inner("test_approle_probe_secret_id");
+        inner.clock = clock.clone();
+
+        let register = |secret_id: &str, mut entry: SecretIdStorageEntry| {
+            register_test_secret_id(&inner, storage.as_ref(), "role1", secret_id, SecretIdScope::Global, &mut entry);
+        };
+        let probe = |secret_id: &str| {
+            inner.probe_secret_id(storage.as_ref(), "role1", secret_id, "testhmackey", SecretIdScope::Global).unwrap()
+        };
+
+        register(
+            "secret1",
+            SecretIdStorageEntry {
+                secret_id_num_uses: 3,
+                secret_id_ttl: LeaseTtl::from_secs(600),
+                metadata: HashMap::from([("team".to_string(), "ops".to_string())]),
+                ..Default::default()
+            },
+        );
+        register("secret2", SecretIdStorageEntry::default());
+
+        // A live secret_id, probing it any number of times does not use it up
+        let live = SecretIdStatus {
+            exists: true,
+            expired: false,
+            remaining_uses: Some(3),
+            remaining_ttl: Some(Duration::from_secs(600)),
+            last_login_time: None,
+        };
+        assert_eq!(probe("secret1"), live);
+        assert_eq!(probe("secret1"), live);
+
+        let role_name_hmac = create_hmac("testhmackey", "role1").unwrap();
+        let secret_id_hmac = create_hmac("testhmackey", "secret1").unwrap();
+        let index = secret_id_entry_index(SecretIdScope::Global, &role_name_hmac, &secret_id_hmac).unwrap();
+        let before = storage.get(&index).unwrap().unwrap();
+
+        // No limits at all
+        assert_eq!(
+            probe("secret2"),
+            SecretIdStatus {
+                exists: true,
+                expired: false,
+                remaining_uses: None,
+                remaining_ttl: None,
+                last_login_time: None
+            }
+        );
+
+        // An expired secret_id is still reported as existing until tidied
+        clock.advance(Duration::from_secs(601));
+        assert_eq!(probe("secret1"), SecretIdStatus { expired: true, remaining_ttl: Some(Duration::ZERO), ..live });
+
+        // A secret_id that was never registered
+        assert_eq!(probe("secret3"), SecretIdStatus::default());
+        assert!(!probe("secret3").exists);
+
+        // The probes did not touch the stored entry
+        assert_eq!(storage.get(&index).unwrap().unwrap(), before);
+    }
+}
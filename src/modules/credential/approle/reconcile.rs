@@ -0,0 +1,305 @@
+//! Consistency check between the secret_id entries and their accessor entries.
+//!
+//! The two entries of a secret_id are written separately, so a partial failure can leave an
+//! accessor pointing at a secret_id that does not exist, or a secret_id without an accessor.
+//! `reconcile` finds both kinds of orphans. Dangling accessors are deleted. A secret_id missing its
+//! accessor is left in place, where login and tidy revoke it, unless `reconcile_with` is asked to
+//! give it a new accessor instead. The metadata index of the secret_ids is rebuilt along the way.
+//! `gc_accessors` only does the first part, collecting the dangling accessors, which is cheaper.
+
+use std::collections::HashSet;
+
+use super::{validation::SecretIdAccessorStorageEntry, AppRoleBackendInner, SecretIdScope};
+use crate::{errors::RvError, storage::Storage};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReconcileReport {
+    // The salted accessors whose secret_id does not exist. They were deleted.
+    pub dangling_accessors: Vec<String>,
+    // The HMACs of the secret_ids without a matching accessor
+    pub orphaned_secret_ids: Vec<String>,
+    // How many of the orphaned secret_ids were given a new accessor
+    pub recreated_accessors: usize,
+    // How many metadata index markers were missing or stale. They were fixed.
+    pub metadata_index_repairs: usize,
+}
+
+impl ReconcileReport {
+    pub fn is_consistent(&self) -> bool {
+        self.dangling_accessors.is_empty() && self.orphaned_secret_ids.is_empty() && self.metadata_index_repairs == 0
+    }
+}
+
+impl AppRoleBackendInner {
+    // reconcile checks the secret_ids of the given scope against their
+    // accessors, deleting the dangling accessors.
+    pub fn reconcile(&self, storage: &dyn Storage, scope: SecretIdScope) -> Result<ReconcileReport, RvError> {
+        self.reconcile_with(storage, scope, false)
+    }
+
+    // reconcile_with is reconcile, additionally creating a new accessor for the
+    // secret_ids missing one when recreate_accessors is set.
+    pub fn reconcile_with(
+        &self,
+        storage: &dyn Storage,
+        scope: SecretIdScope,
+        recreate_accessors: bool,
+    ) -> Result<ReconcileReport, RvError> {
+        let mut report = ReconcileReport::default();
+
+        let mut live_secret_id_hmacs: HashSet<String> = HashSet::new();
+        for item in storage.list(scope.prefix())?.iter() {
+            let role_name_hmac = item.trim_end_matches('/');
+            let key = format!("{}{}/", scope.prefix(), role_name_hmac);
+            for secret_id_hmac in storage.list(&key)?.iter() {
+                live_secret_id_hmacs.insert(secret_id_hmac.clone());
+
+                let lock_entry = self.secret_id_locks.get_lock(secret_id_hmac);
+                let _locked = lock_entry.write()?;
+
+                let mut entry = match self.get_secret_id_storage_entry(storage, scope, role_name_hmac, secret_id_hmac) {
+                    Ok(Some(entry)) => entry,
+                    Ok(None) => continue,
+                    Err(err @ RvError::SerdeJson { .. }) => {
+                        self.handle_corrupt_entry(storage, &format!("{}{}", key, secret_id_hmac), err)?;
+                        continue;
+                    }
+                    Err(err) => return Err(err),
+                };
+
+                let accessor_entry = if entry.secret_id_accessor.is_empty() {
+                    None
+                } else {
+                    self.get_secret_id_accessor_entry(storage, &entry.secret_id_accessor, scope)?
+                };
+                if accessor_entry.is_some_and(|accessor_entry| accessor_entry.secret_id_hmac == *secret_id_hmac) {
+                    continue;
+                }
+
+                log::warn!("found secret ID without an accessor, secret_id_hmac: {}", secret_id_hmac);
+                report.orphaned_secret_ids.push(secret_id_hmac.clone());
+
+                if recreate_accessors {
+                    self.create_secret_id_accessor_entry(storage, &mut entry, secret_id_hmac, scope)?;
+                    self.set_secret_id_storage_entry(storage, scope, role_name_hmac, secret_id_hmac, &entry)?;
+                    report.recreated_accessors += 1;
+                }
+            }
+        }
+
+        report.dangling_accessors = self.delete_dangling_accessors(storage, scope, &live_secret_id_hmacs)?;
+
+        report.metadata_index_repairs = self.rebuild_metadata_index(storage, scope)?;
+
+        Ok(report)
+    }
+
+    // gc_accessors deletes the accessors of the given scope whose secret_id
+    // does not exist, and returns how many it deleted. Unlike reconcile, it
+    // only reads the secret_id entries it has to, and leaves the secret_ids
+    // and the metadata index alone, so it is cheap enough to run often.
+    pub fn gc_accessors(&self, storage: &dyn Storage, scope: SecretIdScope) -> Result<usize, RvError> {
+        let mut live_secret_id_hmacs: HashSet<String> = HashSet::new();
+        for item in storage.list(scope.prefix())?.iter() {
+            let key = format!("{}{}/", scope.prefix(), item.trim_end_matches('/'));
+            live_secret_id_hmacs.extend(storage.list(&key)?);
+        }
+
+        let collected = self.delete_dangling_accessors(storage, scope, &live_secret_id_hmacs)?;
+        if !collected.is_empty() {
+            log::info!(
+                "collected dangling secret ID accessors, prefix: {}, count: {}",
+                scope.accessor_prefix(),
+                collected.len()
+            );
+        }
+
+        Ok(collected.len())
+    }
+
+    // delete_dangling_accessors deletes the accessors pointing at a secret_id
+    // that is neither in live_secret_id_hmacs nor found in storage, and returns
+    // their salted hashes.
+    //
+    // The raw accessor can not be recovered from its salted hash, so its own
+    // lock can not be looked up. An accessor entry is only ever written or
+    // deleted under the write lock of the secret_id it points at, which is
+    // taken instead. Under it, the secret_id not existing means no
+    // registration or rotation is about to use the accessor.
+    fn delete_dangling_accessors(
+        &self,
+        storage: &dyn Storage,
+        scope: SecretIdScope,
+        live_secret_id_hmacs: &HashSet<String>,
+    ) -> Result<Vec<String>, RvError> {
+        let mut dangling_accessors = Vec::new();
+        let accessor_prefix = scope.accessor_prefix();
+        for accessor_hash in storage.list(accessor_prefix)?.iter() {
+            let entry_index = format!("{}{}", accessor_prefix, accessor_hash);
+            let Some(storage_entry) = storage.get(&entry_index)? else {
+                continue;
+            };
+
+            let accessor_entry: SecretIdAccessorStorageEntry = match serde_json::from_slice(&storage_entry.value) {
+                Ok(accessor_entry) => accessor_entry,
+                Err(err) => {
+                    self.handle_corrupt_entry(storage, &entry_index, err.into())?;
+                    continue;
+                }
+            };
+
+            if live_secret_id_hmacs.contains(&accessor_entry.secret_id_hmac) {
+                continue;
+            }
+
+            // A registration may have written the accessor after the secret_ids
+            // were listed, check again under the secret_id lock.
+            let lock_entry = self.secret_id_locks.get_lock(&accessor_entry.secret_id_hmac);
+            let _locked = lock_entry.write()?;
+
+            if self.secret_id_exists_in_any_role(storage, scope, &accessor_entry.secret_id_hmac)? {
+                continue;
+            }
+
+            log::warn!("deleting dangling secret ID accessor, accessor_hash: {}", accessor_hash);
+            storage.delete(&entry_index)?;
+            dangling_accessors.push(accessor_hash.clone());
+        }
+
+        Ok(dangling_accessors)
+    }
+
+    pub fn secret_id_exists_in_any_role(
+        &self,
+        storage: &dyn Storage,
+        scope: SecretIdScope,
+        secret_id_hmac: &str,
+    ) -> Result<bool, RvError> {
+        for item in storage.list(scope.prefix())?.iter() {
+            let role_name_hmac = item.trim_end_matches('/');
+            let key = format!("{}{}/{}", scope.prefix(), role_name_hmac, secret_id_hmac);
+            if storage.exists(&key)? {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        super::{
+            test::{new_test_inner, register_test_secret_id},
+            validation::{create_hmac, secret_id_entry_index, SecretIdStorageEntry},
+        },
+        *,
+    };
+    use crate::utils::ttl::LeaseTtl;
+
+    #[test]
+    fn test_approle_reconcile() {
+        let (inner, storage) = new_test_inner("test_approle_reconcile");
+
+        let register = |secret_id: &str| -> SecretIdStorageEntry {
+            let mut entry = SecretIdStorageEntry { secret_id_ttl: LeaseTtl::from_secs(600), ..Default::default() };
+            register_test_secret_id(&inner, storage.as_ref(), "role1", secret_id, SecretIdScope::Global, &mut entry);
+            entry
+        };
+
+        let role_name_hmac = create_hmac("testhmackey", "role1").unwrap();
+        let secret_id_hmac = |secret_id: &str| create_hmac("testhmackey", secret_id).unwrap();
+
+        register("secret1");
+        let secret2 = register("secret2");
+        let secret3 = register("secret3");
+        assert!(inner.reconcile(storage.as_ref(), SecretIdScope::Global).unwrap().is_consistent());
+
+        // secret2 loses its secret_id entry, secret3 its accessor
+        let secret2_index =
+            secret_id_entry_index(SecretIdScope::Global, &role_name_hmac, &secret_id_hmac("secret2")).unwrap();
+        assert!(storage.delete(&secret2_index).is_ok());
+        assert!(inner
+            .delete_secret_id_accessor_entry(storage.as_ref(), &secret3.secret_id_accessor, SecretIdScope::Global)
+            .is_ok());
+
+        let (secret2_accessor_index, _) =
+            inner.accessor_index(&secret2.secret_id_accessor, SecretIdScope::Global).unwrap();
+        let report = inner.reconcile(storage.as_ref(), SecretIdScope::Global).unwrap();
+        assert_eq!(report.dangling_accessors.len(), 1);
+        assert!(secret2_accessor_index.ends_with(&report.dangling_accessors[0]));
+        assert_eq!(report.orphaned_secret_ids, vec![secret_id_hmac("secret3")]);
+        assert_eq!(report.recreated_accessors, 0);
+
+        // The dangling accessor is gone, the orphaned secret_id is only reported
+        assert!(storage.get(&secret2_accessor_index).unwrap().is_none());
+        let report = inner.reconcile(storage.as_ref(), SecretIdScope::Global).unwrap();
+        assert!(report.dangling_accessors.is_empty());
+        assert_eq!(report.orphaned_secret_ids, vec![secret_id_hmac("secret3")]);
+
+        // Recreating gives secret3 a new, working accessor
+        let report = inner.reconcile_with(storage.as_ref(), SecretIdScope::Global, true).unwrap();
+        assert_eq!(report.orphaned_secret_ids, vec![secret_id_hmac("secret3")]);
+        assert_eq!(report.recreated_accessors, 1);
+        assert!(inner.reconcile(storage.as_ref(), SecretIdScope::Global).unwrap().is_consistent());
+
+        let entry = inner
+            .get_secret_id_storage_entry(
+                storage.as_ref(),
+                SecretIdScope::Global,
+                &role_name_hmac,
+                &secret_id_hmac("secret3"),
+            )
+            .unwrap()
+            .unwrap();
+        assert_ne!(entry.secret_id_accessor, secret3.secret_id_accessor);
+        let info = inner
+            .resolve_accessor(storage.as_ref(), &entry.secret_id_accessor, SecretIdScope::Global, &role_name_hmac)
+            .unwrap();
+        assert!(info.is_some());
+
+        // The local secret_ids are checked separately
+        assert!(inner.reconcile(storage.as_ref(), SecretIdScope::Local).unwrap().is_consistent());
+    }
+
+    #[test]
+    fn test_approle_gc_accessors() {
+        let (inner, storage) = new_test_inner("test_approle_gc_accessors");
+
+        let role_name_hmac = create_hmac("testhmackey", "role1").unwrap();
+        let mut accessor_indexes = Vec::new();
+        for (i, scope) in [SecretIdScope::Global, SecretIdScope::Global, SecretIdScope::Global, SecretIdScope::Local]
+            .into_iter()
+            .enumerate()
+        {
+            let secret_id = format!("secret{}", i);
+            let mut entry = SecretIdStorageEntry { secret_id_ttl: LeaseTtl::from_secs(600), ..Default::default() };
+            register_test_secret_id(&inner, storage.as_ref(), "role1", &secret_id, scope, &mut entry);
+            let (accessor_index, _) = inner.accessor_index(&entry.secret_id_accessor, scope).unwrap();
+            accessor_indexes.push(accessor_index);
+        }
+        assert_eq!(inner.gc_accessors(storage.as_ref(), SecretIdScope::Global).unwrap(), 0);
+
+        // secret0 and secret1 lose their secret_id entry as an interrupted
+        // delete would leave them, as does the local secret3
+        for (i, scope) in [(0, SecretIdScope::Global), (1, SecretIdScope::Global), (3, SecretIdScope::Local)] {
+            let secret_id_hmac = create_hmac("testhmackey", &format!("secret{}", i)).unwrap();
+            let index = secret_id_entry_index(scope, &role_name_hmac, &secret_id_hmac).unwrap();
+            assert!(storage.delete(&index).is_ok());
+        }
+
+        // Only the dangling accessors of the scope are collected
+        assert_eq!(inner.gc_accessors(storage.as_ref(), SecretIdScope::Global).unwrap(), 2);
+        assert!(storage.get(&accessor_indexes[0]).unwrap().is_none());
+        assert!(storage.get(&accessor_indexes[1]).unwrap().is_none());
+        assert!(storage.get(&accessor_indexes[2]).unwrap().is_some());
+        assert!(storage.get(&accessor_indexes[3]).unwrap().is_some());
+        assert_eq!(inner.gc_accessors(storage.as_ref(), SecretIdScope::Global).unwrap(), 0);
+
+        assert_eq!(inner.gc_accessors(storage.as_ref(), SecretIdScope::Local).unwrap(), 1);
+        assert!(storage.get(&accessor_indexes[3]).unwrap().is_none());
+
+        assert_eq!(storage.list(SecretIdScope::Global.accessor_prefix()).unwrap().len(), 1);
+    }
+}
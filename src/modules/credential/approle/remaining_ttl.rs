@@ -0,0 +1,111 @@
+//! Query of the remaining lifetime of a secret_id.
+//!
+//! `secret_id_remaining_ttl` tells a client holding a secret_id how long it stays valid. It only
+//! reads the entry, under the read lock, so asking never extends nor otherwise changes it.
+
+use std::time::Duration;
+
+use super::{AppRoleBackendInner, SecretIdScope};
+use crate::{errors::RvError, storage::Storage};
+
+impl AppRoleBackendInner {
+    // secret_id_remaining_ttl reports how long the secret_id stays valid, without
+    // touching the entry. None means the secret_id never expires. An absent or
+    // already expired secret_id is an error.
+    pub fn secret_id_remaining_ttl(
+        &self,
+        storage: &dyn Storage,
+        scope: SecretIdScope,
+        role_name_hmac: &str,
+        secret_id_hmac: &str,
+    ) -> Result<Option<Duration>, RvError> {
+        let lock_entry = self.secret_id_locks.get_lock(secret_id_hmac);
+        let _locked = lock_entry.read()?;
+
+        let entry = self
+            .get_secret_id_storage_entry(storage, scope, role_name_hmac, secret_id_hmac)?
+            .ok_or(RvError::ErrResponse("invalid secret id".to_string()))?;
+
+        if entry.secret_id_ttl.is_zero() {
+            return Ok(None);
+        }
+
+        let now = self.clock.now();
+        if self.is_past_expiration(entry.expiration_time, now)? {
+            return Err(RvError::ErrResponse("secret id has expired".to_string()));
+        }
+
+        Ok(Some(entry.expiration_time.duration_since(now).unwrap_or(Duration::ZERO)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::SystemTime};
+
+    use super::{
+        super::{
+            test::{new_test_inner, register_test_secret_id},
+            validation::{create_hmac, SecretIdStorageEntry},
+        },
+        *,
+    };
+    use crate::utils::{clock::MockClock, ttl::LeaseTtl};
+
+    #[test]
+    fn test_approle_secret_id_remaining_ttl() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = Arc::new(MockClock::new(start));
+
+        let (mut inner, storage) = new_test_inner("test_approle_secret_id_remaining_ttl");
+        inner.clock = clock.clone();
+
+        for (secret_id, ttl) in [("secret1", 0), ("secret2", 60)] {
+            let mut secret_entry =
+                SecretIdStorageEntry { secret_id_ttl: LeaseTtl::from_secs(ttl), ..Default::default() };
+            register_test_secret_id(
+                &inner,
+                storage.as_ref(),
+                "role1",
+                secret_id,
+                SecretIdScope::Global,
+                &mut secret_entry,
+            );
+        }
+
+        let role_name_hmac = create_hmac("testhmackey", "role1").unwrap();
+        let remaining_ttl = |secret_id: &str| {
+            let secret_id_hmac = create_hmac("testhmackey", secret_id).unwrap();
+            inner.secret_id_remaining_ttl(storage.as_ref(), SecretIdScope::Global, &role_name_hmac, &secret_id_hmac)
+        };
+        let entry_of = |secret_id: &str| {
+            let secret_id_hmac = create_hmac("testhmackey", secret_id).unwrap();
+            inner
+                .get_secret_id_storage_entry(storage.as_ref(), SecretIdScope::Global, &role_name_hmac, &secret_id_hmac)
+                .unwrap()
+                .unwrap()
+        };
+        let before = entry_of("secret2");
+
+        // Never expiring
+        assert_eq!(remaining_ttl("secret1").unwrap(), None);
+
+        // Live
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(remaining_ttl("secret2").unwrap(), Some(Duration::from_secs(40)));
+        clock.advance(Duration::from_secs(40));
+        assert_eq!(remaining_ttl("secret2").unwrap(), Some(Duration::ZERO));
+
+        // Just expired
+        clock.advance(Duration::from_secs(1));
+        assert!(remaining_ttl("secret2").is_err());
+        assert_eq!(remaining_ttl("secret1").unwrap(), None);
+        assert!(remaining_ttl("no-such-secret").is_err());
+
+        // The query never modifies the entry
+        let after = entry_of("secret2");
+        assert_eq!(after.expiration_time, before.expiration_time);
+        assert_eq!(after.last_updated_time, before.last_updated_time);
+        assert_eq!(after.secret_id_num_uses, before.secret_id_num_uses);
+    }
+}
@@ -0,0 +1,138 @@
+//! Renewal of the secret_ids.
+//!
+//! Long-lived agents can extend the expiration of the secret_id they hold instead of having a new
+//! one issued. `renew_secret_id` pushes the expiration of a live secret_id out from now, never past
+//! the maximum TTL of its role, and leaves the number of uses as it is.
+
+use std::time::Duration;
+
+use super::{AppRoleBackendInner, SecretIdScope};
+use crate::{errors::RvError, storage::Storage, utils::ttl::LeaseTtl};
+
+impl AppRoleBackendInner {
+    // renew_secret_id extends the expiration of a live secret_id to now plus
+    // increment, clamped to max_ttl, and returns the new remaining TTL. max_ttl
+    // is the role's secret_id_ttl, zero meaning that only the max_secret_id_ttl
+    // of the approle config applies. Secret_ids that never expire, have expired
+    // or have no uses left cannot be renewed.
+    pub fn renew_secret_id(
+        &self,
+        storage: &dyn Storage,
+        scope: SecretIdScope,
+        role_name_hmac: &str,
+        secret_id_hmac: &str,
+        increment: Duration,
+        max_ttl: LeaseTtl,
+    ) -> Result<Duration, RvError> {
+        let lock_entry = self.secret_id_locks.get_lock(secret_id_hmac);
+        let _locked = lock_entry.write()?;
+
+        let mut entry = self
+            .get_secret_id_storage_entry(storage, scope, role_name_hmac, secret_id_hmac)?
+            .ok_or(RvError::ErrResponse("invalid secret id".to_string()))?;
+
+        if entry.secret_id_ttl.is_zero() {
+            return Err(RvError::ErrResponse("secret id does not expire".to_string()));
+        }
+
+        let now = self.clock.now();
+        if self.is_past_expiration(entry.expiration_time, now)? {
+            return Err(RvError::ErrResponse("secret id has expired".to_string()));
+        }
+
+        if entry.secret_id_num_uses < 0 {
+            return Err(RvError::ErrResponse("secret id has no uses left".to_string()));
+        }
+
+        let max_ttl = self.derive_secret_id_ttl(if max_ttl.is_zero() { LeaseTtl::MAX } else { max_ttl });
+        let ttl = increment.min(max_ttl.as_duration());
+
+        entry.expiration_time = now + ttl;
+        entry.last_updated_time = now;
+        self.set_secret_id_storage_entry(storage, scope, role_name_hmac, secret_id_hmac, &entry)?;
+
+        Ok(ttl)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::SystemTime};
+
+    use super::{
+        super::{
+            test::{new_test_inner, register_test_secret_id},
+            validation::{create_hmac, SecretIdStorageEntry},
+        },
+        *,
+    };
+    use crate::utils::clock::MockClock;
+
+    #[test]
+    fn test_approle_renew_secret_id() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = Arc::new(MockClock::new(start));
+
+        let (mut inner, storage) = new_test_inner("test_approle_renew_secret_id");
+        inner.clock = clock.clone();
+
+        for (secret_id, ttl, num_uses) in [("secret1", 0, 0), ("secret2", 60, 0), ("secret3", 60, -1)] {
+            let mut secret_entry = SecretIdStorageEntry {
+                secret_id_ttl: LeaseTtl::from_secs(ttl),
+                secret_id_num_uses: num_uses,
+                ..Default::default()
+            };
+            register_test_secret_id(
+                &inner,
+                storage.as_ref(),
+                "role1",
+                secret_id,
+                SecretIdScope::Global,
+                &mut secret_entry,
+            );
+        }
+
+        let role_name_hmac = create_hmac("testhmackey", "role1").unwrap();
+        let renew = |secret_id: &str, increment: u64, max_ttl: u64| {
+            let secret_id_hmac = create_hmac("testhmackey", secret_id).unwrap();
+            inner.renew_secret_id(
+                storage.as_ref(),
+                SecretIdScope::Global,
+                &role_name_hmac,
+                &secret_id_hmac,
+                Duration::from_secs(increment),
+                LeaseTtl::from_secs(max_ttl),
+            )
+        };
+        let entry_of = |secret_id: &str| {
+            let secret_id_hmac = create_hmac("testhmackey", secret_id).unwrap();
+            inner
+                .get_secret_id_storage_entry(storage.as_ref(), SecretIdScope::Global, &role_name_hmac, &secret_id_hmac)
+                .unwrap()
+                .unwrap()
+        };
+
+        // The increment counts from now and is clamped to the max TTL
+        clock.advance(Duration::from_secs(50));
+        assert_eq!(renew("secret2", 30, 120).unwrap(), Duration::from_secs(30));
+        let entry = entry_of("secret2");
+        assert_eq!(entry.expiration_time, start + Duration::from_secs(80));
+        assert_eq!(entry.last_updated_time, start + Duration::from_secs(50));
+
+        assert_eq!(renew("secret2", 3600, 120).unwrap(), Duration::from_secs(120));
+        assert_eq!(entry_of("secret2").expiration_time, start + Duration::from_secs(170));
+
+        // Without a role max TTL, the max_secret_id_ttl of the approle config applies
+        let max_secret_id_ttl = inner.config().unwrap().max_secret_id_ttl;
+        assert_eq!(renew("secret2", u64::MAX / 2, 0).unwrap(), max_secret_id_ttl);
+
+        assert!(renew("secret1", 30, 120).is_err());
+        assert!(renew("secret3", 30, 120).is_err());
+        assert!(renew("no-such-secret", 30, 120).is_err());
+
+        // An expired secret_id cannot be brought back
+        clock.advance(max_secret_id_ttl + Duration::from_secs(1));
+        assert!(renew("secret2", 30, 120).is_err());
+        assert_eq!(entry_of("secret2").last_updated_time, start + Duration::from_secs(50));
+    }
+}
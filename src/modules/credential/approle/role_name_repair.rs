@@ -0,0 +1,190 @@
+//! Migration of the roles created before their names were normalized.
+//!
+//! Role names are normalized to NFC before they are hashed or made into a storage key, see
+//! `utils::normalize_role_name`. A role created before that under a name not in NFC, such as a
+//! decomposed `café`, is stored under its raw name, and its secret_ids under the HMAC of the raw
+//! name, where lookups by the normalized name do not find them. `migrate_role_names` moves each
+//! such role, along with its secret_ids and their metadata index, to its normalized name, once per
+//! mount, recording that it did under `MIGRATION_PREFIX`. It is called by the tidy routine.
+
+use super::{
+    metadata_index::metadata_index_prefix,
+    path_role::{role_storage_key, RoleEntry, RoleIdEntry},
+    validation::create_hmac,
+    AppRoleBackendInner, MIGRATION_PREFIX, ROLE_ID_PREFIX, SECRET_ID_COUNT_PREFIX, SECRET_ID_PREFIX,
+};
+use crate::{
+    errors::RvError,
+    storage::{Storage, StorageEntry},
+    utils,
+};
+
+// The key marking that migrate_role_names ran
+const ROLE_NAME_MIGRATION: &str = "role_name_nfc";
+
+impl AppRoleBackendInner {
+    // migrate_role_names moves the roles stored under a name that is not
+    // normalized to their normalized name, unless it already completed on this
+    // storage. It returns the number of roles moved, or None if there was
+    // nothing left to migrate.
+    pub fn migrate_role_names(&self, storage: &dyn Storage) -> Result<Option<usize>, RvError> {
+        let marker_key = format!("{}{}", MIGRATION_PREFIX, ROLE_NAME_MIGRATION);
+        if storage.get(&marker_key)?.is_some() {
+            return Ok(None);
+        }
+
+        let mut migrated = 0;
+        for raw_name in storage.list("role/")?.iter() {
+            let name = match utils::normalize_role_name(raw_name) {
+                Ok(name) if name == *raw_name => continue,
+                Ok(name) => name,
+                Err(err) => {
+                    log::warn!("skipping role with an invalid name, role_name: {:?}, err: {}", raw_name, err);
+                    continue;
+                }
+            };
+
+            if self.migrate_role_name(storage, raw_name, &name)? {
+                migrated += 1;
+            }
+        }
+
+        // Only recorded once every role went through, a failed run is retried
+        storage.put(&StorageEntry::new(&marker_key, &self.clock.now())?)?;
+
+        Ok(Some(migrated))
+    }
+
+    // migrate_role_name moves the role stored under raw_name to name, its
+    // normalized form. The role is written under the new name first and
+    // deleted from the old one last, so an interrupted move is completed by
+    // the next one. A distinct role already holding the normalized name is
+    // left alone, both stay as they are.
+    fn migrate_role_name(&self, storage: &dyn Storage, raw_name: &str, name: &str) -> Result<bool, RvError> {
+        let lock_entry = self.role_locks.get_lock(name);
+        let _locked = lock_entry.write()?;
+
+        let raw_key = role_storage_key(raw_name);
+        let Some(entry) = storage.get(&raw_key)? else {
+            return Ok(false);
+        };
+        let mut role: RoleEntry = serde_json::from_slice(entry.value.as_slice())?;
+        if role.secret_id_prefix.is_empty() {
+            role.secret_id_prefix = SECRET_ID_PREFIX.to_string();
+        }
+
+        let key = role_storage_key(name);
+        if let Some(existing) = storage.get(&key)? {
+            let existing: RoleEntry = serde_json::from_slice(existing.value.as_slice())?;
+            if existing.role_id != role.role_id {
+                log::warn!("skipping role whose normalized name is taken by another role, role_name: {:?}", raw_name);
+                return Ok(false);
+            }
+        }
+
+        // The secret_ids were indexed under the HMAC of the name the role was
+        // loaded with: the raw key, or the stored name for a role keeping its case
+        let raw_hmac_name = if role.lower_case_role_name || role.name.is_empty() { raw_name } else { &role.name };
+        let raw_role_name_hmac = create_hmac(&role.hmac_key, raw_hmac_name)?;
+
+        role.name =
+            if role.lower_case_role_name { name.to_lowercase() } else { utils::normalize_role_name(&role.name)? };
+        let role_name_hmac = self.role_name_hmac(&role.hmac_key, &role.name)?;
+        let scope = role.secret_id_scope()?;
+
+        storage.put(&StorageEntry::new(&key, &role)?)?;
+
+        if raw_role_name_hmac != role_name_hmac {
+            storage.move_prefix(
+                &format!("{}{}/", scope.prefix(), raw_role_name_hmac),
+                &format!("{}{}/", scope.prefix(), role_name_hmac),
+            )?;
+            storage.move_prefix(
+                &metadata_index_prefix(scope, &raw_role_name_hmac),
+                &metadata_index_prefix(scope, &role_name_hmac),
+            )?;
+            // The counter of the new name is rebuilt from the moved entries on its next use
+            storage.delete(&format!("{}{}", SECRET_ID_COUNT_PREFIX, raw_role_name_hmac))?;
+        }
+
+        if !role.role_id.is_empty() {
+            let salt = self.salt.read()?;
+            let salt = salt.as_ref().ok_or(RvError::ErrResponse("salt not found".to_string()))?;
+            let role_id_key = format!("{}{}", ROLE_ID_PREFIX, salt.salt_id(&role.role_id)?);
+            storage.put(&StorageEntry::new(&role_id_key, &RoleIdEntry { name: name.to_string() })?)?;
+        }
+
+        storage.delete(&raw_key)?;
+
+        log::info!("migrated role to its normalized name, role_name: {:?}", name);
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use as_any::Downcast;
+    use serde_json::json;
+
+    use super::{
+        super::{
+            test::{generate_secret_id, test_login},
+            AppRoleModule,
+        },
+        *,
+    };
+    use crate::test_utils::{test_mount_auth_api, test_rusty_vault_init, test_write_api};
+
+    #[maybe_async::test(feature = "sync_handler", async(all(not(feature = "sync_handler")), tokio::test))]
+    async fn test_approle_migrate_role_names() {
+        let (root_token, core) = test_rusty_vault_init("test_approle_migrate_role_names");
+        let core = core.read().unwrap();
+
+        // Mount approle auth to path: auth/approle
+        test_mount_auth_api(&core, &root_token, "approle", "approle").await;
+
+        let data = json!({ "role_id": "role-id-123" }).as_object().unwrap().clone();
+        let resp = test_write_api(&core, &root_token, "auth/approle/role/caf\u{e9}", true, Some(data)).await;
+        assert!(resp.is_ok());
+        let (secret_id, _) = generate_secret_id(&core, &root_token, "approle", "caf\u{e9}").await;
+
+        let module = core.module_manager.get_module("approle").unwrap();
+        let approle_mod = module.read().unwrap();
+        let approle_module = approle_mod.as_ref().downcast_ref::<AppRoleModule>().unwrap();
+        let storage: Arc<dyn Storage> = core.router.matching_view("auth/approle/").unwrap().unwrap();
+
+        // Store the role the way it was before the names were normalized: under
+        // its decomposed name, with its secret_ids under the HMAC of that name
+        let role = approle_module.load_role(storage.as_ref(), "caf\u{e9}").unwrap().unwrap();
+        let scope = role.secret_id_scope().unwrap();
+        let role_name_hmac = approle_module.role_name_hmac(&role.hmac_key, "caf\u{e9}").unwrap();
+        let raw_role_name_hmac = create_hmac(&role.hmac_key, "cafe\u{301}").unwrap();
+        assert_ne!(role_name_hmac, raw_role_name_hmac);
+
+        let raw_role = RoleEntry { name: "cafe\u{301}".to_string(), ..role.clone() };
+        storage.put(&StorageEntry::new(&role_storage_key("cafe\u{301}"), &raw_role).unwrap()).unwrap();
+        storage.delete(&role_storage_key("caf\u{e9}")).unwrap();
+        storage
+            .move_prefix(
+                &format!("{}{}/", scope.prefix(), role_name_hmac),
+                &format!("{}{}/", scope.prefix(), raw_role_name_hmac),
+            )
+            .unwrap();
+        assert!(test_login(&core, "approle", "role-id-123", &secret_id, false).await.is_err());
+
+        assert_eq!(approle_module.migrate_role_names(storage.as_ref()).unwrap(), Some(1));
+
+        // The role and its secret_ids are found again through the normalized name
+        assert_eq!(approle_module.list_roles(storage.as_ref(), "").unwrap(), vec!["caf\u{e9}"]);
+        assert!(storage.list(&format!("{}{}/", scope.prefix(), raw_role_name_hmac)).unwrap().is_empty());
+        assert_eq!(storage.list(&format!("{}{}/", scope.prefix(), role_name_hmac)).unwrap().len(), 1);
+        let resp = test_login(&core, "approle", "role-id-123", &secret_id, true).await;
+        assert!(resp.unwrap().unwrap().auth.is_some());
+
+        // The migration only runs once
+        assert_eq!(approle_module.migrate_role_names(storage.as_ref()).unwrap(), None);
+    }
+}
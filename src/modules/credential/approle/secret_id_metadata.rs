@@ -0,0 +1,155 @@
+//! Updates of the metadata of an existing secret_id.
+//!
+//! The metadata of a secret_id is set when it is registered. `update_secret_id_metadata` changes it
+//! afterwards, merging new pairs in or replacing it entirely, and records when it did in the
+//! `last_updated_time` of the entry. The same limits as at registration apply, and the metadata
+//! index is kept in step with the entry.
+
+use std::collections::HashMap;
+
+use super::{
+    validation::{validate_secret_id_metadata, SecretIdStorageEntry},
+    AppRoleBackendInner, SecretIdScope,
+};
+use crate::{errors::RvError, storage::Storage};
+
+impl AppRoleBackendInner {
+    // update_secret_id_metadata merges new_metadata into the metadata of an
+    // existing secret_id, or replaces it entirely when replace is set, and bumps
+    // last_updated_time. The number of uses and the expiration are left as they
+    // are. Returns None if the secret_id does not exist.
+    pub fn update_secret_id_metadata(
+        &self,
+        storage: &dyn Storage,
+        scope: SecretIdScope,
+        role_name_hmac: &str,
+        secret_id_hmac: &str,
+        new_metadata: HashMap<String, String>,
+        replace: bool,
+    ) -> Result<Option<SecretIdStorageEntry>, RvError> {
+        let lock_entry = self.secret_id_locks.get_lock(secret_id_hmac);
+        let _locked = lock_entry.write()?;
+
+        let Some(mut entry) = self.get_secret_id_storage_entry(storage, scope, role_name_hmac, secret_id_hmac)? else {
+            return Ok(None);
+        };
+
+        let previous_metadata = entry.metadata.clone();
+        if replace {
+            entry.metadata = new_metadata;
+        } else {
+            entry.metadata.extend(new_metadata);
+        }
+        validate_secret_id_metadata(&entry.metadata, &self.config()?)?;
+
+        // The new pairs are indexed before the entry is written, and the old
+        // ones unindexed after it, so the index never misses a pair
+        let added: HashMap<String, String> = entry
+            .metadata
+            .iter()
+            .filter(|(key, value)| previous_metadata.get(*key) != Some(*value))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        let removed: HashMap<String, String> =
+            previous_metadata.into_iter().filter(|(key, value)| entry.metadata.get(key) != Some(value)).collect();
+
+        self.index_secret_id_metadata(storage, scope, role_name_hmac, secret_id_hmac, &added)?;
+
+        entry.last_updated_time = self.clock.now();
+        self.set_secret_id_storage_entry(storage, scope, role_name_hmac, secret_id_hmac, &entry)?;
+
+        self.unindex_secret_id_metadata(storage, scope, role_name_hmac, secret_id_hmac, &removed)?;
+
+        Ok(Some(entry))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use super::{
+        super::{
+            config::AppRoleConfig,
+            test::{new_test_inner, register_test_secret_id},
+            validation::create_hmac,
+        },
+        *,
+    };
+    use crate::utils::{clock::MockClock, ttl::LeaseTtl};
+
+    #[test]
+    fn test_approle_update_secret_id_metadata() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = Arc::new(MockClock::new(start));
+
+        let (mut inner, storage) = new_test_inner("test_approle_update_secret_id_metadata");
+        inner.clock = clock.clone();
+
+        let mut secret_entry = SecretIdStorageEntry {
+            secret_id_ttl: LeaseTtl::from_secs(600),
+            secret_id_num_uses: 5,
+            metadata: HashMap::from([("env".to_string(), "prod".to_string())]),
+            ..Default::default()
+        };
+        register_test_secret_id(&inner, storage.as_ref(), "role1", "secret1", SecretIdScope::Global, &mut secret_entry);
+
+        let role_name_hmac = create_hmac("testhmackey", "role1").unwrap();
+        let secret_id_hmac = create_hmac("testhmackey", "secret1").unwrap();
+        let update = |metadata: HashMap<String, String>, replace: bool| {
+            inner.update_secret_id_metadata(
+                storage.as_ref(),
+                SecretIdScope::Global,
+                &role_name_hmac,
+                &secret_id_hmac,
+                metadata,
+                replace,
+            )
+        };
+
+        clock.advance(Duration::from_secs(30));
+        let entry = update(HashMap::from([("team".to_string(), "infra".to_string())]), false).unwrap().unwrap();
+        assert_eq!(entry.metadata.len(), 2);
+        assert_eq!(entry.metadata.get("env").unwrap(), "prod");
+        assert_eq!(entry.creation_time, start);
+        assert_eq!(entry.last_updated_time, start + Duration::from_secs(30));
+        assert_eq!(entry.secret_id_num_uses, 5);
+        assert_eq!(entry.expiration_time, start + Duration::from_secs(600));
+
+        clock.advance(Duration::from_secs(30));
+        let entry = update(HashMap::from([("team".to_string(), "db".to_string())]), true).unwrap().unwrap();
+        assert_eq!(entry.metadata, HashMap::from([("team".to_string(), "db".to_string())]));
+        assert_eq!(entry.last_updated_time, start + Duration::from_secs(60));
+
+        let stored = inner
+            .get_secret_id_storage_entry(storage.as_ref(), SecretIdScope::Global, &role_name_hmac, &secret_id_hmac)
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.metadata, entry.metadata);
+        assert_eq!(stored.creation_time, start);
+        assert_eq!(stored.last_updated_time, start + Duration::from_secs(60));
+
+        // Oversized metadata is rejected and leaves the entry untouched
+        let too_long =
+            HashMap::from([("team".to_string(), "x".repeat(AppRoleConfig::default().max_metadata_value_length + 1))]);
+        assert!(update(too_long, false).is_err());
+        let stored = inner
+            .get_secret_id_storage_entry(storage.as_ref(), SecretIdScope::Global, &role_name_hmac, &secret_id_hmac)
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.metadata.get("team").unwrap(), "db");
+
+        let missing = inner.update_secret_id_metadata(
+            storage.as_ref(),
+            SecretIdScope::Global,
+            &role_name_hmac,
+            "no-such-hmac",
+            HashMap::new(),
+            false,
+        );
+        assert!(missing.unwrap().is_none());
+    }
+}
@@ -0,0 +1,324 @@
+//! Throttling of failed approle logins.
+//!
+//! To slow down brute-forcing of secret_ids, the `LoginThrottle` counts the failed login attempts
+//! made with each role_id. Once `max_failures` failures happen within `window`, every further
+//! attempt with that role_id is rejected until `cooldown` has elapsed. A successful login clears
+//! the counters of its role_id.
+//!
+//! The counters are kept in memory only, keyed by a hash of the role_id, and bounded by an LRU
+//! of `max_entries` role_ids so that attempts with random role_ids can not exhaust memory. The
+//! backend applies the `login_throttle_*` settings of its `AppRoleConfig` when it is set up.
+
+use std::{
+    collections::VecDeque,
+    sync::RwLock,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    errors::RvError,
+    utils::{crypto::blake2b256_hash, lru::LruCache},
+};
+
+pub const DEFAULT_MAX_FAILURES: usize = 10;
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(60);
+pub const DEFAULT_COOLDOWN: Duration = Duration::from_secs(300);
+pub const DEFAULT_MAX_ENTRIES: usize = 4096;
+
+#[derive(Debug, Clone)]
+pub struct LoginThrottleConfig {
+    /// Number of failures within `window` that triggers the lockout. Zero disables throttling.
+    pub max_failures: usize,
+    pub window: Duration,
+    pub cooldown: Duration,
+    /// Maximum number of role_ids tracked at the same time.
+    pub max_entries: usize,
+}
+
+impl Default for LoginThrottleConfig {
+    fn default() -> Self {
+        Self {
+            max_failures: DEFAULT_MAX_FAILURES,
+            window: DEFAULT_WINDOW,
+            cooldown: DEFAULT_COOLDOWN,
+            max_entries: DEFAULT_MAX_ENTRIES,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ThrottleState {
+    failures: VecDeque<SystemTime>,
+    locked_until: Option<SystemTime>,
+}
+
+impl ThrottleState {
+    fn is_locked(&self, now: SystemTime) -> bool {
+        self.locked_until.is_some_and(|locked_until| locked_until > now)
+    }
+}
+
+#[derive(Debug)]
+pub struct LoginThrottle {
+    config: RwLock<LoginThrottleConfig>,
+    entries: RwLock<LruCache<String, ThrottleState>>,
+}
+
+impl Default for LoginThrottle {
+    fn default() -> Self {
+        Self::new(LoginThrottleConfig::default())
+    }
+}
+
+impl LoginThrottle {
+    pub fn new(config: LoginThrottleConfig) -> Self {
+        let entries = RwLock::new(LruCache::new(config.max_entries));
+        Self { config: RwLock::new(config), entries }
+    }
+
+    pub fn config(&self) -> Result<LoginThrottleConfig, RvError> {
+        Ok(self.config.read()?.clone())
+    }
+
+    pub fn set_config(&self, config: LoginThrottleConfig) -> Result<(), RvError> {
+        let mut entries = self.entries.write()?;
+        entries.set_capacity(config.max_entries);
+        if config.max_failures == 0 {
+            entries.clear();
+        }
+        *self.config.write()? = config;
+        Ok(())
+    }
+
+    // check returns an error carrying a retry-after hint if the role_id is
+    // currently locked out.
+    pub fn check(&self, role_id: &str, now: SystemTime) -> Result<(), RvError> {
+        if self.config.read()?.max_failures == 0 {
+            return Ok(());
+        }
+
+        let key = Self::key(role_id);
+        let mut entries = self.entries.write()?;
+        let Some(state) = entries.get_mut(&key) else {
+            return Ok(());
+        };
+
+        if let Some(locked_until) = state.locked_until {
+            if let Ok(remaining) = locked_until.duration_since(now) {
+                if !remaining.is_zero() {
+                    let retry_after = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+                    return Err(RvError::ErrResponse(format!(
+                        "too many failed login attempts for this role_id, retry after {}s",
+                        retry_after
+                    )));
+                }
+            }
+            state.locked_until = None;
+        }
+
+        Ok(())
+    }
+
+    pub fn record_failure(&self, role_id: &str, now: SystemTime) -> Result<(), RvError> {
+        let config = self.config.read()?.clone();
+        if config.max_failures == 0 {
+            return Ok(());
+        }
+
+        let key = Self::key(role_id);
+        let mut entries = self.entries.write()?;
+        // A role_id that is locked out is never evicted to make room, or
+        // flooding the throttle would lift its lockout
+        if !entries.contains_key(&key)
+            && entries
+                .try_insert_evicting(key.clone(), ThrottleState::default(), |state| !state.is_locked(now))
+                .is_err()
+        {
+            log::warn!("login throttle is full of locked out role_ids, not tracking another one");
+            return Ok(());
+        }
+        let state = entries.get_mut(&key).unwrap();
+
+        let window_start = now.checked_sub(config.window).unwrap_or(SystemTime::UNIX_EPOCH);
+        while state.failures.front().is_some_and(|t| *t <= window_start) {
+            state.failures.pop_front();
+        }
+
+        state.failures.push_back(now);
+        if state.failures.len() >= config.max_failures {
+            state.failures.clear();
+            state.locked_until = Some(now + config.cooldown);
+        }
+
+        Ok(())
+    }
+
+    pub fn reset(&self, role_id: &str) -> Result<(), RvError> {
+        self.entries.write()?.remove(&Self::key(role_id));
+        Ok(())
+    }
+
+    fn key(role_id: &str) -> String {
+        hex::encode(blake2b256_hash(role_id))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use as_any::Downcast;
+
+    use super::{
+        super::{
+            config::AppRoleConfig,
+            test::{generate_secret_id, test_login},
+            AppRoleModule,
+        },
+        *,
+    };
+    use crate::test_utils::{test_mount_auth_api, test_read_api, test_rusty_vault_init, test_write_api};
+
+    fn new_throttle() -> LoginThrottle {
+        LoginThrottle::new(LoginThrottleConfig {
+            max_failures: 3,
+            window: Duration::from_secs(60),
+            cooldown: Duration::from_secs(300),
+            max_entries: 2,
+        })
+    }
+
+    #[test]
+    fn test_login_throttle_trip_and_recover() {
+        let throttle = new_throttle();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        // A burst of failures trips the lockout
+        for i in 0..3 {
+            let now = start + Duration::from_secs(i);
+            assert!(throttle.check("role1", now).is_ok());
+            assert!(throttle.record_failure("role1", now).is_ok());
+        }
+        let err = throttle.check("role1", start + Duration::from_secs(3)).unwrap_err();
+        assert!(err.to_string().contains("retry after 299s"));
+
+        // Other role_ids are not affected
+        assert!(throttle.check("role2", start + Duration::from_secs(3)).is_ok());
+
+        // The lockout ends after the cooldown
+        assert!(throttle.check("role1", start + Duration::from_secs(301)).is_err());
+        assert!(throttle.check("role1", start + Duration::from_secs(302)).is_ok());
+
+        // Failures spread wider than the window never trip the lockout
+        let start = start + Duration::from_secs(1000);
+        for i in 0..6 {
+            let now = start + Duration::from_secs(i * 31);
+            assert!(throttle.check("role1", now).is_ok());
+            assert!(throttle.record_failure("role1", now).is_ok());
+        }
+
+        // A successful login resets the counters
+        let now = start + Duration::from_secs(200);
+        assert!(throttle.record_failure("role1", now).is_ok());
+        assert!(throttle.record_failure("role1", now).is_ok());
+        assert!(throttle.reset("role1").is_ok());
+        assert!(throttle.record_failure("role1", now).is_ok());
+        assert!(throttle.check("role1", now).is_ok());
+    }
+
+    #[test]
+    fn test_login_throttle_bounded() {
+        let throttle = new_throttle();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        for _ in 0..3 {
+            assert!(throttle.record_failure("role1", now).is_ok());
+        }
+        assert!(throttle.check("role1", now).is_err());
+
+        // Flooding with other role_ids evicts the oldest entry that is not
+        // locked out, never the locked out one
+        assert!(throttle.record_failure("role2", now).is_ok());
+        assert!(throttle.record_failure("role3", now).is_ok());
+        assert_eq!(throttle.entries.read().unwrap().len(), 2);
+        assert!(throttle.check("role1", now).is_err());
+        assert!(throttle.entries.read().unwrap().contains_key(&LoginThrottle::key("role3")));
+
+        // With every entry locked out, further role_ids are not tracked
+        for _ in 0..2 {
+            assert!(throttle.record_failure("role3", now).is_ok());
+        }
+        assert!(throttle.record_failure("role4", now).is_ok());
+        assert!(!throttle.entries.read().unwrap().contains_key(&LoginThrottle::key("role4")));
+        assert!(throttle.check("role1", now).is_err());
+        assert!(throttle.check("role3", now).is_err());
+
+        // Once the lockouts are over, their entries can be evicted again
+        let later = now + Duration::from_secs(301);
+        assert!(throttle.record_failure("role4", later).is_ok());
+        assert!(throttle.entries.read().unwrap().contains_key(&LoginThrottle::key("role4")));
+
+        // Disabled throttling never rejects
+        let throttle = LoginThrottle::new(LoginThrottleConfig { max_failures: 0, ..Default::default() });
+        for _ in 0..10 {
+            assert!(throttle.record_failure("role1", now).is_ok());
+        }
+        assert!(throttle.check("role1", now).is_ok());
+    }
+
+    #[maybe_async::test(feature = "sync_handler", async(all(not(feature = "sync_handler")), tokio::test))]
+    async fn test_approle_login_throttle() {
+        let (root_token, core) = test_rusty_vault_init("test_approle_login_throttle");
+        let core = core.read().unwrap();
+
+        // Mount approle auth to path: auth/approle
+        test_mount_auth_api(&core, &root_token, "approle", "approle/").await;
+
+        let resp = test_write_api(&core, &root_token, "auth/approle/role/throttle", true, None).await;
+        assert!(resp.is_ok());
+        let resp = test_read_api(&core, &root_token, "auth/approle/role/throttle/role-id", true).await;
+        let role_id = resp.unwrap().unwrap().data.unwrap()["role_id"].as_str().unwrap().to_string();
+        let (secret_id, _) = generate_secret_id(&core, &root_token, "approle", "throttle").await;
+
+        // The throttle is off out of the box
+        for _ in 0..DEFAULT_MAX_FAILURES + 1 {
+            let resp = test_login(&core, "approle", &role_id, "wrong-secret-id", false).await;
+            assert!(!resp.unwrap_err().to_string().contains("retry after"));
+        }
+        let _ = test_login(&core, "approle", &role_id, &secret_id, true).await;
+
+        {
+            let module = core.module_manager.get_module("approle").unwrap();
+            let approle_mod = module.read().unwrap();
+            let approle_module = approle_mod.as_ref().downcast_ref::<AppRoleModule>().unwrap();
+
+            // It follows the approle config
+            assert_eq!(approle_module.login_throttle.config().unwrap().max_failures, 0);
+            assert!(approle_module.login_throttle.entries.read().unwrap().is_empty());
+            let config = AppRoleConfig { login_throttle_max_failures: 3, ..Default::default() };
+            approle_module.set_config(config).unwrap();
+            assert_eq!(approle_module.login_throttle.config().unwrap().max_failures, 3);
+        }
+
+        // A successful login in between resets the failure count
+        let _ = test_login(&core, "approle", &role_id, "wrong-secret-id", false).await;
+        let _ = test_login(&core, "approle", &role_id, "wrong-secret-id", false).await;
+        let _ = test_login(&core, "approle", &role_id, &secret_id, true).await;
+
+        for _ in 0..3 {
+            let resp = test_login(&core, "approle", &role_id, "wrong-secret-id", false).await;
+            assert!(!resp.unwrap_err().to_string().contains("retry after"));
+        }
+
+        // Once tripped, even the valid secret_id is rejected
+        let resp = test_login(&core, "approle", &role_id, &secret_id, false).await;
+        assert!(resp.unwrap_err().to_string().contains("retry after"));
+
+        // Failures with role_ids of no role are not tracked
+        for i in 0..5 {
+            let _ = test_login(&core, "approle", &format!("unknown-role-id-{}", i), "wrong-secret-id", false).await;
+        }
+        let module = core.module_manager.get_module("approle").unwrap();
+        let approle_mod = module.read().unwrap();
+        let approle_module = approle_mod.as_ref().downcast_ref::<AppRoleModule>().unwrap();
+        assert_eq!(approle_module.login_throttle.entries.read().unwrap().len(), 1);
+    }
+}
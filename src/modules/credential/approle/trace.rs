@@ -0,0 +1,33 @@
+//! Tracing spans around the approle operations.
+//!
+//! Every span is named `approle` and carries the `operation`, the `role_name_hmac` once it is
+//! known, the `outcome` and, where the operation takes secret_id locks, `lock_contended`. Roles are
+//! only identified by their HMAC, and no field ever carries a secret_id, an accessor or a role_id.
+
+use tracing::{field::Empty, Span};
+
+use crate::errors::RvError;
+
+pub fn approle_span(operation: &'static str) -> Span {
+    tracing::info_span!(
+        "approle",
+        operation,
+        role_name_hmac = Empty,
+        outcome = Empty,
+        lock_contended = Empty,
+        entries = Empty
+    )
+}
+
+// record_outcome records whether the operation of the span succeeded. The
+// error itself is left out, some errors quote the values they reject.
+pub fn record_outcome<T>(span: &Span, ret: &Result<T, RvError>) {
+    span.record("outcome", if ret.is_ok() { "success" } else { "failure" });
+}
+
+// in_span runs f in the span and records its outcome.
+pub fn in_span<T>(span: Span, f: impl FnOnce() -> Result<T, RvError>) -> Result<T, RvError> {
+    let ret = span.in_scope(f);
+    record_outcome(&span, &ret);
+    ret
+}
@@ -0,0 +1,102 @@
+//! Accounting of the successful approle logins, for billing and quotas.
+//!
+//! The `UsageCounter` counts the successful logins of each role, keyed by the role name HMAC so
+//! that no role name is kept. `drain_usage` returns the counts accumulated since the previous
+//! drain and resets them in one step, for a periodic task to persist or export.
+//!
+//! The counts are kept in memory only, and at most `max_entries` roles are counted between two
+//! drains. The logins of the roles beyond are not lost, they are counted together under
+//! `USAGE_OVERFLOW_KEY`.
+
+use std::{
+    collections::HashMap,
+    mem,
+    sync::{Mutex, PoisonError},
+};
+
+const DEFAULT_MAX_ENTRIES: usize = 4096;
+
+/// The key the logins of the roles over the bound are counted under. Role name HMACs are hex
+/// encoded, so it can not be mistaken for one.
+pub const USAGE_OVERFLOW_KEY: &str = "_overflow";
+
+#[derive(Debug)]
+pub struct UsageCounter {
+    max_entries: usize,
+    counts: Mutex<HashMap<String, u64>>,
+}
+
+impl Default for UsageCounter {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_ENTRIES)
+    }
+}
+
+impl UsageCounter {
+    pub fn new(max_entries: usize) -> Self {
+        Self { max_entries, counts: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn record(&self, role_name_hmac: &str) {
+        let mut counts = self.counts.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(count) = counts.get_mut(role_name_hmac) {
+            *count += 1;
+            return;
+        }
+
+        // The overflow key is not a role, it does not take up one of the entries
+        let roles = counts.len() - usize::from(counts.contains_key(USAGE_OVERFLOW_KEY));
+        let key = if roles < self.max_entries { role_name_hmac } else { USAGE_OVERFLOW_KEY };
+        *counts.entry(key.to_string()).or_insert(0) += 1;
+    }
+
+    // drain_usage returns the logins counted since the previous drain, and
+    // starts counting from zero again.
+    pub fn drain_usage(&self) -> HashMap<String, u64> {
+        mem::take(&mut *self.counts.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, thread};
+
+    use super::*;
+
+    #[test]
+    fn test_usage_counter() {
+        let counter = Arc::new(UsageCounter::new(2));
+        assert!(counter.drain_usage().is_empty());
+
+        let handles: Vec<_> = ["aaaa", "bbbb", "aaaa", "aaaa"]
+            .into_iter()
+            .map(|role_name_hmac| {
+                let counter = Arc::clone(&counter);
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        counter.record(role_name_hmac);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // Roles over the bound go to the overflow
+        counter.record("cccc");
+        counter.record("dddd");
+        counter.record("aaaa");
+
+        let usage = counter.drain_usage();
+        assert_eq!(
+            usage,
+            HashMap::from([("aaaa".to_string(), 301), ("bbbb".to_string(), 100), (USAGE_OVERFLOW_KEY.to_string(), 2)])
+        );
+
+        // Draining reset the counts, and freed the entries
+        assert!(counter.drain_usage().is_empty());
+        counter.record("cccc");
+        assert_eq!(counter.drain_usage(), HashMap::from([("cccc".to_string(), 1)]));
+    }
+}
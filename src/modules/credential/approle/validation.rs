@@ -2,28 +2,108 @@
 //! https://github.com/hashicorp/vault/blob/main/builtin/credential/approle/validation.go
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    fmt,
+    str::FromStr,
+    sync::{Arc, PoisonError},
+    thread,
     time::{Duration, SystemTime},
 };
 
 use better_default::Default;
 use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use tracing::Span;
 
-use super::{AppRoleBackendInner, SECRET_ID_ACCESSOR_LOCAL_PREFIX, SECRET_ID_ACCESSOR_PREFIX, SECRET_ID_LOCAL_PREFIX};
+use super::{
+    audit::{AuditEvent, AuditEventType},
+    config::AppRoleConfig,
+    hmac_encoding::{create_hmac_encoded, HmacEncoding},
+    trace, AppRoleBackendInner, SecretIdScope, CORRUPT_PREFIX, SECRET_ID_COUNT_PREFIX,
+};
 use crate::{
     errors::RvError,
-    modules::auth::expiration::MAX_LEASE_DURATION_SECS,
-    storage::{Storage, StorageEntry},
-    utils::{self, deserialize_duration, deserialize_system_time, serialize_duration, serialize_system_time},
+    storage::{wal::WalGuard, ReadConsistency, Storage, StorageEntry, DELETE_CONFIRM_ATTEMPTS, DELETE_CONFIRM_BACKOFF},
+    utils::{
+        self,
+        cidr::Cidr,
+        crypto::{check_digest_permitted, hkdf_sha256},
+        deserialize_duration, deserialize_option_system_time, deserialize_system_time,
+        locks::LockEntry,
+        serialize_duration, serialize_option_system_time, serialize_system_time,
+        ttl::LeaseTtl,
+    },
 };
 
 const MAX_HMAC_INPUT_LENGTH: usize = 4096;
+const MAX_SEEN_NONCES: usize = 256;
+const MAX_SECRET_ID_NAME_LENGTH: usize = 128;
+const DERIVED_HMAC_KEY_LENGTH: usize = 32;
+
+// ACCESSOR_GENERATE_ATTEMPTS bounds the accessors drawn for a secret_id when
+// the previous ones are already in use.
+const ACCESSOR_GENERATE_ATTEMPTS: usize = 4;
+
+// The number of secret_ids flush_role_secrets deletes per delete_batch by
+// default.
+pub const FLUSH_BATCH_SIZE: usize = 128;
+
+// The contexts the hmac keys for hashing role names and secret_ids are derived
+// under, see derive_hmac_key.
+pub const ROLE_NAME_HMAC_CONTEXT: &str = "approle/role_name";
+pub const SECRET_ID_HMAC_CONTEXT: &str = "approle/secret_id";
+
+// FlushStrategy is how flush_role_secrets deletes the entries of the
+// secret_ids and their accessors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushStrategy {
+    // One secret_id at a time, confirming every delete
+    Sequential,
+    // The entries of up to this many secret_ids per delete_batch, for the
+    // backends where every delete is a round trip
+    Batched(usize),
+}
+
+impl Default for FlushStrategy {
+    fn default() -> Self {
+        FlushStrategy::Batched(FLUSH_BATCH_SIZE)
+    }
+}
+
+// RoleFlushResult is the outcome of flushing the secret_ids of one role in
+// flush_roles_secrets. A role that failed counts no secret_id, though some of
+// them may have been deleted before the error.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RoleFlushResult {
+    pub role_name: String,
+    pub flushed: usize,
+    pub error: Option<String>,
+}
+
+// MultiFlushReport lists the outcome of every role of a flush_roles_secrets
+// call, in the order the roles were given.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct MultiFlushReport {
+    pub roles: Vec<RoleFlushResult>,
+}
+
+impl MultiFlushReport {
+    // flushed is the number of secret_ids flushed across all the roles.
+    pub fn flushed(&self) -> usize {
+        self.roles.iter().map(|role| role.flushed).sum()
+    }
+
+    // failed returns the results of the roles that could not be flushed.
+    pub fn failed(&self) -> Vec<&RoleFlushResult> {
+        self.roles.iter().filter(|role| role.error.is_some()).collect()
+    }
+}
 
 // secretIDStorageEntry represents the information stored in storage
 // when a secret_id is created. The structure of the secret_id storage
 // entry is the same for all the types of secret_ids generated.
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Default, Serialize, Deserialize)]
 pub struct SecretIdStorageEntry {
     // Accessor for the secret_id. It is a random uuid serving as
     // a secondary index for the secret_id. This uniquely identifies
@@ -32,14 +112,17 @@ pub struct SecretIdStorageEntry {
     // secret_id during login.
     pub secret_id_accessor: String,
 
+    // Optional short display name of the secret_id, shown in listings
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
     // Number of times this secret_id can be used to perform the login
     // operation
     pub secret_id_num_uses: i64,
 
     // Duration after which this secret_id should expire. This is capped by
     // the backend mount's max TTL value.
-    #[serde(serialize_with = "serialize_duration", deserialize_with = "deserialize_duration")]
-    pub secret_id_ttl: Duration,
+    pub secret_id_ttl: LeaseTtl,
 
     // The time when the secret_id was created
     #[serde(serialize_with = "serialize_system_time", deserialize_with = "deserialize_system_time")]
@@ -68,19 +151,156 @@ pub struct SecretIdStorageEntry {
     // token_cidr_list is a set of CIDR blocks that impose source address
     // restrictions on the usage of the token generated by this secret_id
     pub token_cidr_list: Vec<String>,
+
+    // When non-zero, every login with this secret_id must present a nonce,
+    // and a nonce can not be presented again until this long after its
+    // first use
+    #[serde(default, serialize_with = "serialize_duration", deserialize_with = "deserialize_duration")]
+    pub replay_nonce_ttl: Duration,
+
+    // The nonces presented during login, mapped to the time they expire
+    #[serde(default)]
+    pub seen_nonces: HashMap<String, SystemTime>,
+
+    // HMAC of the role_id the secret_id was created for, checked against the
+    // presented role_id at login. Empty for the secret_ids created before the
+    // binding existed and for imported ones, which are not checked.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub role_id_hmac: String,
+
+    // The time of the last successful login with this secret_id, None until
+    // its first use
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_option_system_time",
+        deserialize_with = "deserialize_option_system_time"
+    )]
+    pub last_login_time: Option<SystemTime>,
+
+    // Set by the caller of register_secret_id_entry to keep a secret_id
+    // without a TTL from getting the configured default TTL. Not persisted, a
+    // stored secret_id_ttl of zero always means that it never expires.
+    #[serde(skip)]
+    pub no_expiry: bool,
 }
 
 // Represents the payload of the storage entry of the accessor that maps to a
 // unique secret_id. Note that secret_id should never be stored in plaintext
 // anywhere in the backend. secret_id_hmac will be used as an index to fetch the
 // properties of the secret_id and to delete the secret_id.
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct SecretIdAccessorStorageEntry {
     // Hash of the secret_id which can be used to find the storage index at which
     // properties of secret_id is stored.
     pub secret_id_hmac: String,
 }
 
+// OnCorrupt decides what the list-and-load scans (tidy, secret_id counting) do
+// with an entry whose value can not be deserialized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnCorrupt {
+    // Abort the scan with the deserialization error.
+    Fail,
+    // Log the entry and continue with the next one.
+    #[default]
+    Skip,
+    // Move the raw value under the corrupt/ prefix, then continue.
+    Quarantine,
+}
+
+// SecretIdInfo is a non-sensitive projection of a secret_id storage entry. It
+// is meant to be displayed by tooling, so it carries neither the secret_id nor
+// any of the HMAC indexes pointing at it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SecretIdInfo {
+    #[serde(serialize_with = "serialize_system_time", deserialize_with = "deserialize_system_time")]
+    #[default(SystemTime::UNIX_EPOCH)]
+    pub creation_time: SystemTime,
+    #[serde(serialize_with = "serialize_system_time", deserialize_with = "deserialize_system_time")]
+    #[default(SystemTime::UNIX_EPOCH)]
+    pub expiration_time: SystemTime,
+    pub secret_id_num_uses: i64,
+    pub cidr_list: Vec<String>,
+    pub metadata: HashMap<String, String>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_option_system_time",
+        deserialize_with = "deserialize_option_system_time"
+    )]
+    pub last_login_time: Option<SystemTime>,
+}
+
+impl From<&SecretIdStorageEntry> for SecretIdInfo {
+    fn from(entry: &SecretIdStorageEntry) -> Self {
+        Self {
+            creation_time: entry.creation_time,
+            expiration_time: entry.expiration_time,
+            secret_id_num_uses: entry.secret_id_num_uses,
+            cidr_list: entry.cidr_list.clone(),
+            metadata: entry.metadata.clone(),
+            last_login_time: entry.last_login_time,
+        }
+    }
+}
+
+const REDACTED: &str = "<redacted>";
+
+// The accessor is as good as the secret_id for destroying it, so it is not printed. Neither
+// are the HMAC binding the secret_id to its role_id, nor the nonces it was presented with.
+impl fmt::Debug for SecretIdStorageEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecretIdStorageEntry")
+            .field("secret_id_accessor", &REDACTED)
+            .field("name", &self.name)
+            .field("secret_id_num_uses", &self.secret_id_num_uses)
+            .field("secret_id_ttl", &self.secret_id_ttl)
+            .field("creation_time", &self.creation_time)
+            .field("expiration_time", &self.expiration_time)
+            .field("last_updated_time", &self.last_updated_time)
+            .field("metadata", &self.metadata)
+            .field("cidr_list", &self.cidr_list)
+            .field("token_cidr_list", &self.token_cidr_list)
+            .field("replay_nonce_ttl", &self.replay_nonce_ttl)
+            .field("seen_nonces", &REDACTED)
+            .field("last_login_time", &self.last_login_time)
+            .field("role_id_hmac", &REDACTED)
+            .finish()
+    }
+}
+
+impl fmt::Debug for SecretIdAccessorStorageEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecretIdAccessorStorageEntry").field("secret_id_hmac", &REDACTED).finish()
+    }
+}
+
+#[cfg(test)]
+impl SecretIdStorageEntry {
+    pub fn debug_full(&self) -> String {
+        format!(
+            "{:?} secret_id_accessor: {:?}, seen_nonces: {:?}, role_id_hmac: {:?}",
+            self, self.secret_id_accessor, self.seen_nonces, self.role_id_hmac
+        )
+    }
+}
+
+#[cfg(test)]
+impl SecretIdAccessorStorageEntry {
+    pub fn debug_full(&self) -> String {
+        format!("SecretIdAccessorStorageEntry {{ secret_id_hmac: {:?} }}", self.secret_id_hmac)
+    }
+}
+
+// Represents the payload of the storage entry that keeps track of the number of
+// live secret_ids of a role. It is bumped on every registration and reconciled
+// against the real entries by the tidy operation.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SecretIdCountStorageEntry {
+    pub count: i64,
+}
+
 impl AppRoleBackendInner {
     // get_secret_id_storage_entry fetches the secret ID properties from physical
     // storage. The entry will be indexed based on the given HMACs of both role
@@ -89,7 +309,7 @@ impl AppRoleBackendInner {
     pub fn get_secret_id_storage_entry(
         &self,
         storage: &dyn Storage,
-        role_secret_id_prefix: &str,
+        scope: SecretIdScope,
         role_name_hmac: &str,
         secret_id_hmac: &str,
     ) -> Result<Option<SecretIdStorageEntry>, RvError> {
@@ -101,7 +321,7 @@ impl AppRoleBackendInner {
             return Err(RvError::ErrResponse("missing role name hmac".to_string()));
         }
 
-        let entry_index = format!("{}{}/{}", role_secret_id_prefix, role_name_hmac, secret_id_hmac);
+        let entry_index = secret_id_entry_index(scope, role_name_hmac, secret_id_hmac)?;
         let storage_entry = storage.get(&entry_index)?;
         if storage_entry.is_none() {
             return Ok(None);
@@ -113,6 +333,193 @@ impl AppRoleBackendInner {
         Ok(Some(ret))
     }
 
+    // handle_corrupt_entry applies the configured OnCorrupt policy to the entry
+    // at the given key, which failed to deserialize with err. It returns Ok when
+    // the caller should carry on with the scan.
+    pub fn handle_corrupt_entry(&self, storage: &dyn Storage, key: &str, err: RvError) -> Result<(), RvError> {
+        match *self.on_corrupt.read()? {
+            OnCorrupt::Fail => Err(err),
+            OnCorrupt::Skip => {
+                log::warn!("skipping corrupt approle entry, key: {}, err: {}", key, err);
+                Ok(())
+            }
+            OnCorrupt::Quarantine => {
+                if let Some(quarantined) = self.quarantine_entry(storage, key)? {
+                    log::warn!(
+                        "quarantined corrupt approle entry, key: {}, moved to: {}, err: {}",
+                        key,
+                        quarantined,
+                        err
+                    );
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // quarantine_entry moves the raw value of an entry under the corrupt/
+    // prefix, and returns the key it was moved to, or None if there was no
+    // such entry.
+    pub fn quarantine_entry(&self, storage: &dyn Storage, key: &str) -> Result<Option<String>, RvError> {
+        let Some(entry) = storage.get(key)? else {
+            return Ok(None);
+        };
+
+        let quarantined = format!("{}{}", CORRUPT_PREFIX, key);
+        storage.put(&StorageEntry { key: quarantined.clone(), value: entry.value })?;
+        storage.delete(key)?;
+
+        Ok(Some(quarantined))
+    }
+
+    // list_secret_id_entries returns the storage entries of the role's live
+    // secret_ids. When a filter is given, only the secret_ids whose metadata
+    // it accepts are returned.
+    pub fn list_secret_id_entries(
+        &self,
+        storage: &dyn Storage,
+        scope: SecretIdScope,
+        role_name_hmac: &str,
+        filter: Option<&dyn Fn(&HashMap<String, String>) -> bool>,
+    ) -> Result<Vec<SecretIdStorageEntry>, RvError> {
+        let key = format!("{}{}/", scope.prefix(), role_name_hmac);
+        let secret_id_hmacs = storage.list(&key)?;
+        let now = self.clock.now();
+
+        let mut entries: Vec<SecretIdStorageEntry> = Vec::with_capacity(secret_id_hmacs.len());
+        for secret_id_hmac in secret_id_hmacs.iter() {
+            // secret_id locks are not indexed by secret_id itself.
+            // This is because secret_id are not stored in plaintext
+            // form anywhere in the backend, and hence accessing its
+            // corresponding lock many times using secret_id is not
+            // possible. Also, indexing it everywhere using secret_id_hmacs
+            // makes listing operation easier.
+            let lock_entry = self.secret_id_locks.get_lock(secret_id_hmac);
+            let _locked = lock_entry.read()?;
+
+            let entry = self.get_secret_id_storage_entry(storage, scope, role_name_hmac, secret_id_hmac)?.ok_or(
+                RvError::ErrResponse(
+                    "storage entry for SecretID is present but no content found at the index".to_string(),
+                ),
+            )?;
+
+            if !entry.secret_id_ttl.is_zero() && self.is_past_expiration(entry.expiration_time, now)? {
+                continue;
+            }
+
+            if filter.map_or(true, |f| f(&entry.metadata)) {
+                entries.push(entry);
+            }
+        }
+
+        Ok(entries)
+    }
+
+    // list_secret_id_accessors returns the accessors of the role's live
+    // secret_ids, filtered the same way as list_secret_id_entries.
+    pub fn list_secret_id_accessors(
+        &self,
+        storage: &dyn Storage,
+        scope: SecretIdScope,
+        role_name_hmac: &str,
+        filter: Option<&dyn Fn(&HashMap<String, String>) -> bool>,
+    ) -> Result<Vec<String>, RvError> {
+        let entries = self.list_secret_id_entries(storage, scope, role_name_hmac, filter)?;
+        Ok(entries.into_iter().map(|entry| entry.secret_id_accessor).collect())
+    }
+
+    // list_secret_ids_by_metadata returns the accessors of the role's live
+    // secret_ids whose metadata maps key to value. Only the secret_ids found in
+    // the metadata index are read, and each is checked against its entry, as
+    // the index may still list a secret_id that no longer has the pair.
+    pub fn list_secret_ids_by_metadata(
+        &self,
+        storage: &dyn Storage,
+        scope: SecretIdScope,
+        role_name_hmac: &str,
+        key: &str,
+        value: &str,
+    ) -> Result<Vec<String>, RvError> {
+        let now = self.clock.now();
+
+        let mut accessors = Vec::new();
+        for secret_id_hmac in self.indexed_secret_id_hmacs(storage, scope, role_name_hmac, key, value)?.iter() {
+            let lock_entry = self.secret_id_locks.get_lock(secret_id_hmac);
+            let _locked = lock_entry.read()?;
+
+            let Some(entry) = self.get_secret_id_storage_entry(storage, scope, role_name_hmac, secret_id_hmac)? else {
+                continue;
+            };
+
+            if entry.metadata.get(key).map_or(true, |v| v != value) {
+                continue;
+            }
+
+            if !entry.secret_id_ttl.is_zero() && self.is_past_expiration(entry.expiration_time, now)? {
+                continue;
+            }
+
+            accessors.push(entry.secret_id_accessor);
+        }
+
+        Ok(accessors)
+    }
+
+    // check_secret_id_nonce rejects a nonce that was already presented with the
+    // secret_id and has not expired yet, and records it otherwise. Expired
+    // nonces are dropped on the way, and at most MAX_SEEN_NONCES are kept, so
+    // a secret_id with too many live nonces can not be used until some expire.
+    // The caller is responsible for holding the secret_id's write lock and
+    // persisting the entry.
+    pub fn check_secret_id_nonce(&self, entry: &mut SecretIdStorageEntry, nonce: &str) -> Result<(), RvError> {
+        if nonce.is_empty() {
+            return Err(RvError::ErrResponse("missing nonce".to_string()));
+        }
+
+        let now = self.clock.now();
+        entry.seen_nonces.retain(|_, expiration| *expiration > now);
+
+        if entry.seen_nonces.contains_key(nonce) {
+            return Err(RvError::ErrResponse("nonce has already been used with this secret_id".to_string()));
+        }
+
+        if entry.seen_nonces.len() >= MAX_SEEN_NONCES {
+            return Err(RvError::ErrResponse("too many outstanding nonces for this secret_id".to_string()));
+        }
+
+        entry.seen_nonces.insert(nonce.to_string(), now + entry.replay_nonce_ttl);
+
+        Ok(())
+    }
+
+    // secret_id_storage_entry_exists checks whether a secret ID entry is present
+    // without loading and deserializing it. Like get_secret_id_storage_entry,
+    // the caller is responsible for holding the secret ID lock. A check that a
+    // write depends on must ask for a strong read, or a stale replica could
+    // report an existing entry as absent.
+    pub fn secret_id_storage_entry_exists(
+        &self,
+        storage: &dyn Storage,
+        scope: SecretIdScope,
+        role_name_hmac: &str,
+        secret_id_hmac: &str,
+        consistency: ReadConsistency,
+    ) -> Result<bool, RvError> {
+        if secret_id_hmac.is_empty() {
+            return Err(RvError::ErrResponse("missing secret id hmac".to_string()));
+        }
+
+        if role_name_hmac.is_empty() {
+            return Err(RvError::ErrResponse("missing role name hmac".to_string()));
+        }
+
+        let entry_index = secret_id_entry_index(scope, role_name_hmac, secret_id_hmac)?;
+        match consistency {
+            ReadConsistency::Eventual => storage.exists(&entry_index),
+            ReadConsistency::Strong => Ok(storage.get_consistent(&entry_index, consistency)?.is_some()),
+        }
+    }
+
     // set_secret_id_storage_entry creates or updates a secret ID entry at the
     // physical storage. The entry will be indexed based on the given HMACs of both
     // role name and the secret ID. This method will not acquire secret ID lock to
@@ -121,15 +528,11 @@ impl AppRoleBackendInner {
     pub fn set_secret_id_storage_entry(
         &self,
         storage: &dyn Storage,
-        role_secret_id_prefix: &str,
+        scope: SecretIdScope,
         role_name_hmac: &str,
         secret_id_hmac: &str,
         secret_entry: &SecretIdStorageEntry,
     ) -> Result<(), RvError> {
-        if role_secret_id_prefix.is_empty() {
-            return Err(RvError::ErrResponse("missing secret id prefix".to_string()));
-        }
-
         if secret_id_hmac.is_empty() {
             return Err(RvError::ErrResponse("missing secret id hmac".to_string()));
         }
@@ -138,7 +541,7 @@ impl AppRoleBackendInner {
             return Err(RvError::ErrResponse("missing role name hmac".to_string()));
         }
 
-        let entry_index = format!("{}{}/{}", role_secret_id_prefix, role_name_hmac, secret_id_hmac);
+        let entry_index = secret_id_entry_index(scope, role_name_hmac, secret_id_hmac)?;
         let entry = StorageEntry::new(&entry_index, secret_entry)?;
 
         storage.put(&entry)
@@ -147,7 +550,7 @@ impl AppRoleBackendInner {
     pub fn delete_secret_id_storage_entry(
         &self,
         storage: &dyn Storage,
-        role_secret_id_prefix: &str,
+        scope: SecretIdScope,
         role_name_hmac: &str,
         secret_id_hmac: &str,
     ) -> Result<(), RvError> {
@@ -159,8 +562,21 @@ impl AppRoleBackendInner {
             return Err(RvError::ErrResponse("missing role name hmac".to_string()));
         }
 
-        let entry_index = format!("{}{}/{}", role_secret_id_prefix, role_name_hmac, secret_id_hmac);
-        storage.delete(&entry_index)
+        let entry_index = secret_id_entry_index(scope, role_name_hmac, secret_id_hmac)?;
+        // A corrupt entry is still deleted, its markers are left to the rebuild
+        let entry = match self.get_secret_id_storage_entry(storage, scope, role_name_hmac, secret_id_hmac) {
+            Ok(entry) => entry,
+            Err(RvError::SerdeJson { .. }) => None,
+            Err(err) => return Err(err),
+        };
+        storage.delete(&entry_index)?;
+
+        match entry {
+            Some(entry) => {
+                self.unindex_secret_id_metadata(storage, scope, role_name_hmac, secret_id_hmac, &entry.metadata)
+            }
+            None => Ok(()),
+        }
     }
 
     // register_secret_id_entry creates a new storage entry for the given secret_id.
@@ -170,65 +586,351 @@ impl AppRoleBackendInner {
         role_name: &str,
         secret_id: &str,
         hmac_key: &str,
-        role_secret_id_prefix: &str,
+        scope: SecretIdScope,
+        secret_id_count_limit: i64,
+        secret_entry: &mut SecretIdStorageEntry,
+    ) -> Result<(), RvError> {
+        trace::in_span(trace::approle_span("register_secret_id"), || {
+            let _permit = self.registration_limiter.acquire(role_name)?;
+            self.register_secret_id_entry_in_span(
+                storage,
+                role_name,
+                secret_id,
+                hmac_key,
+                scope,
+                secret_id_count_limit,
+                secret_entry,
+            )
+        })
+    }
+
+    fn register_secret_id_entry_in_span(
+        &self,
+        storage: &dyn Storage,
+        role_name: &str,
+        secret_id: &str,
+        hmac_key: &str,
+        scope: SecretIdScope,
+        secret_id_count_limit: i64,
         secret_entry: &mut SecretIdStorageEntry,
     ) -> Result<(), RvError> {
-        let role_name_hmac = create_hmac(hmac_key, role_name)?;
-        let secret_id_hmac = create_hmac(hmac_key, secret_id)?;
+        let role_name_hmac = self.role_name_hmac(hmac_key, role_name)?;
+        Span::current().record("role_name_hmac", role_name_hmac.as_str());
+        // A secret_id still indexed under an older hash version is found, and
+        // reported as a duplicate, as well
+        let secret_id_hmac = self.resolve_secret_id_hmac(storage, scope, &role_name_hmac, hmac_key, secret_id)?;
+
+        let config = self.config()?;
+        let inherit_cidrs = config.inherit_role_secret_id_cidrs && secret_entry.cidr_list.is_empty();
+        if secret_entry.role_id_hmac.is_empty() || inherit_cidrs {
+            if let Some(role) = self.load_role(storage, role_name)? {
+                // A secret_id not bound by the caller is bound to the role_id of
+                // its role, so only entries written before the binding are unbound.
+                if secret_entry.role_id_hmac.is_empty() && !role.role_id.is_empty() {
+                    secret_entry.role_id_hmac = create_hmac(hmac_key, &role.role_id)?;
+                }
+                // A secret_id registered without a cidr_list may inherit the bound CIDRs of
+                // its role, so that they stay enforced on it if the role drops them.
+                if inherit_cidrs {
+                    secret_entry.cidr_list = role.secret_id_bound_cidrs;
+                }
+            }
+        }
+        secret_entry.cidr_list = canonicalize_secret_id_cidrs("cidr_list", &secret_entry.cidr_list, &config)?;
+        secret_entry.token_cidr_list =
+            canonicalize_secret_id_cidrs("token_cidr_list", &secret_entry.token_cidr_list, &config)?;
 
         let lock_entry = self.secret_id_locks.get_lock(&secret_id_hmac);
         {
-            let _locked = lock_entry.lock.read()?;
+            let _locked = lock_entry.read()?;
 
-            let entry =
-                self.get_secret_id_storage_entry(storage, role_secret_id_prefix, &role_name_hmac, &secret_id_hmac)?;
-            if entry.is_some() {
-                return Err(RvError::ErrResponse("secret_id is already registered".to_string()));
+            if self.secret_id_storage_entry_exists(
+                storage,
+                scope,
+                &role_name_hmac,
+                &secret_id_hmac,
+                ReadConsistency::Eventual,
+            )? {
+                return Err(self.secret_id_already_exists(storage, scope, &role_name_hmac, &secret_id_hmac));
             }
         }
         {
-            let _locked = lock_entry.lock.write()?;
+            let (_locked, contended) = lock_entry.write_contended()?;
+            Span::current().record("lock_contended", contended);
+
+            // The entry is written right after this check, so it must not be
+            // answered by a stale replica
+            if self.secret_id_storage_entry_exists(
+                storage,
+                scope,
+                &role_name_hmac,
+                &secret_id_hmac,
+                ReadConsistency::Strong,
+            )? {
+                return Err(self.secret_id_already_exists(storage, scope, &role_name_hmac, &secret_id_hmac));
+            }
+
+            let count_lock_entry = self.secret_id_count_locks.get_lock(&role_name_hmac);
+            let _count_locked = count_lock_entry.write()?;
 
-            let entry =
-                self.get_secret_id_storage_entry(storage, role_secret_id_prefix, &role_name_hmac, &secret_id_hmac)?;
-            if entry.is_some() {
-                return Err(RvError::ErrResponse("secret_id is already registered".to_string()));
+            let mut count = self.get_secret_id_count(storage, scope, &role_name_hmac)?;
+            // The counter is only lowered when a secret_id is deleted, so the
+            // secret_ids that expired since the last tidy are discounted by
+            // recounting the entries before turning the registration down
+            if secret_id_count_limit > 0 && count >= secret_id_count_limit {
+                count = self.count_live_secret_ids(storage, scope, &role_name_hmac)?;
+                self.set_secret_id_count(storage, &role_name_hmac, count)?;
+            }
+            if secret_id_count_limit > 0 && count >= secret_id_count_limit {
+                return Err(RvError::ErrResponse(format!(
+                    "role has reached the maximum number of {} live secret_ids",
+                    secret_id_count_limit
+                )));
             }
 
-            let now = SystemTime::now();
+            let now = self.clock.now();
             secret_entry.creation_time = now;
             secret_entry.last_updated_time = now;
 
-            let ttl = self.derive_secret_id_ttl(secret_entry.secret_id_ttl);
+            let ttl = if secret_entry.secret_id_ttl.is_zero() && secret_entry.no_expiry {
+                LeaseTtl::ZERO
+            } else {
+                self.derive_secret_id_ttl(secret_entry.secret_id_ttl)
+            };
+            if ttl.is_zero() && !config.allow_infinite_secret_id_ttl {
+                return Err(RvError::ErrResponse(
+                    "secret_id_ttl of 0 would create a secret_id that never expires, which is not allowed".to_string(),
+                ));
+            }
+            // A defaulted TTL is recorded, so the entry is not mistaken for
+            // one that never expires
+            if secret_entry.secret_id_ttl.is_zero() {
+                secret_entry.secret_id_ttl = ttl;
+            }
             if ttl.as_secs() != 0 {
-                secret_entry.expiration_time = now + ttl;
+                secret_entry.expiration_time = now + self.jitter_secret_id_ttl(ttl)?.as_duration();
             }
 
-            self.create_secret_id_accessor_entry(storage, secret_entry, &secret_id_hmac, role_secret_id_prefix)?;
+            // The accessor and the secret_id entries are written under a
+            // write-ahead log, so a crash in between does not orphan the accessor.
+            let wal = WalGuard::begin(storage, now)?;
+
+            self.create_secret_id_accessor_entry(&wal, secret_entry, &secret_id_hmac, scope)?;
+
+            self.set_secret_id_storage_entry(&wal, scope, &role_name_hmac, &secret_id_hmac, secret_entry)?;
+
+            self.index_secret_id_metadata(&wal, scope, &role_name_hmac, &secret_id_hmac, &secret_entry.metadata)?;
+
+            wal.commit()?;
+
+            // The secret_id is registered at this point, a failure to count it
+            // must not be reported as a failed registration. The counter is
+            // dropped instead, to be rebuilt on its next use.
+            if let Err(err) = self.set_secret_id_count(storage, &role_name_hmac, count + 1) {
+                log::warn!("failed to update the secret ID count, role_name_hmac: {}, err: {}", role_name_hmac, err);
+                if let Err(err) = storage.delete(&format!("{}{}", SECRET_ID_COUNT_PREFIX, role_name_hmac)) {
+                    log::warn!("failed to reset the secret ID count, role_name_hmac: {}, err: {}", role_name_hmac, err);
+                }
+            }
+
+            let mut event = AuditEvent::new(AuditEventType::SecretIdCreate, now);
+            event.role_name_hmac = role_name_hmac;
+            event.secret_id_hmac = secret_id_hmac;
+            event.secret_id_accessor = secret_entry.secret_id_accessor.clone();
+            let no_expiry_event =
+                ttl.is_zero().then(|| AuditEvent { event_type: AuditEventType::SecretIdNoExpiry, ..event.clone() });
+            self.audit(event);
+            if let Some(event) = no_expiry_event {
+                log::warn!("registered a secret ID that never expires, accessor: {}", event.secret_id_accessor);
+                self.audit(event);
+            }
 
-            self.set_secret_id_storage_entry(
-                storage,
-                role_secret_id_prefix,
-                &role_name_hmac,
-                &secret_id_hmac,
-                secret_entry,
-            )?;
             Ok(())
         }
     }
 
+    // secret_id_already_exists builds the error returned when registering a
+    // secret_id that is already registered. It carries the accessor of the
+    // existing entry, so that a client retrying a registration it lost can go on
+    // with the existing secret_id. The accessor is left out if the entry cannot
+    // be read. The caller should hold the secret_id lock.
+    fn secret_id_already_exists(
+        &self,
+        storage: &dyn Storage,
+        scope: SecretIdScope,
+        role_name_hmac: &str,
+        secret_id_hmac: &str,
+    ) -> RvError {
+        let accessor = secret_id_entry_index(scope, role_name_hmac, secret_id_hmac)
+            .and_then(|entry_index| storage.get_consistent(&entry_index, ReadConsistency::Strong))
+            .ok()
+            .flatten()
+            .and_then(|entry| serde_json::from_slice::<SecretIdStorageEntry>(entry.value.as_slice()).ok())
+            .map(|entry| entry.secret_id_accessor)
+            .filter(|accessor| !accessor.is_empty());
+
+        RvError::ErrSecretIdAlreadyExists { accessor }
+    }
+
+    // get_secret_id_count returns the number of live secret_ids of the role. The
+    // value is read from the counter entry, and when the counter does not exist
+    // yet it is rebuilt by counting the non-expired entries under the role's
+    // prefix. The caller should hold the count lock of the role.
+    pub fn get_secret_id_count(
+        &self,
+        storage: &dyn Storage,
+        scope: SecretIdScope,
+        role_name_hmac: &str,
+    ) -> Result<i64, RvError> {
+        let entry_index = format!("{}{}", SECRET_ID_COUNT_PREFIX, role_name_hmac);
+        if let Some(entry) = storage.get(&entry_index)? {
+            let count_entry: SecretIdCountStorageEntry = serde_json::from_slice(entry.value.as_slice())?;
+            return Ok(count_entry.count);
+        }
+
+        self.count_live_secret_ids(storage, scope, role_name_hmac)
+    }
+
+    // count_live_secret_ids counts the non-expired entries under the role's
+    // prefix, ignoring the counter. The secret_id locks are not acquired here,
+    // the caller may already hold one of them. A slightly stale count is
+    // corrected by the next tidy.
+    pub fn count_live_secret_ids(
+        &self,
+        storage: &dyn Storage,
+        scope: SecretIdScope,
+        role_name_hmac: &str,
+    ) -> Result<i64, RvError> {
+        let now = self.clock.now();
+        let key = format!("{}{}/", scope.prefix(), role_name_hmac);
+        let mut count = 0;
+        for secret_id_hmac in storage.list(&key)?.iter() {
+            match self.get_secret_id_storage_entry(storage, scope, role_name_hmac, secret_id_hmac) {
+                Ok(Some(entry)) => {
+                    // Secret_ids without a TTL never expire, whatever their expiration_time
+                    let expired =
+                        !entry.secret_id_ttl.is_zero() && self.is_past_expiration(entry.expiration_time, now)?;
+                    if !expired {
+                        count += 1;
+                    }
+                }
+                Ok(None) => {}
+                Err(err @ RvError::SerdeJson { .. }) => {
+                    self.handle_corrupt_entry(storage, &format!("{}{}", key, secret_id_hmac), err)?
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(count)
+    }
+
+    // set_secret_id_count persists the number of live secret_ids of the role.
+    // The caller should hold the count lock of the role.
+    pub fn set_secret_id_count(&self, storage: &dyn Storage, role_name_hmac: &str, count: i64) -> Result<(), RvError> {
+        let entry_index = format!("{}{}", SECRET_ID_COUNT_PREFIX, role_name_hmac);
+        let entry = StorageEntry::new(&entry_index, &SecretIdCountStorageEntry { count })?;
+        storage.put(&entry)
+    }
+
+    // decrement_secret_id_count lowers the number of live secret_ids of the
+    // role after one of them was deleted. A role without a counter is left
+    // alone, the counter is rebuilt from the entries when it is next needed.
+    // The caller may hold the secret_id lock, but not the count lock.
+    pub fn decrement_secret_id_count(&self, storage: &dyn Storage, role_name_hmac: &str) -> Result<(), RvError> {
+        let count_lock_entry = self.secret_id_count_locks.get_lock(role_name_hmac);
+        let _count_locked = count_lock_entry.write()?;
+
+        let entry_index = format!("{}{}", SECRET_ID_COUNT_PREFIX, role_name_hmac);
+        let Some(entry) = storage.get(&entry_index)? else {
+            return Ok(());
+        };
+
+        let count_entry: SecretIdCountStorageEntry = serde_json::from_slice(entry.value.as_slice())?;
+        self.set_secret_id_count(storage, role_name_hmac, (count_entry.count - 1).max(0))
+    }
+
     // derive_secret_id_ttl determines the secret id TTL to use based on the system's
     // max lease TTL.
     //
-    // If secret_id_ttl is negative or if it crosses the backend mount's limit,
-    // return to backend's max lease TTL. Otherwise, return the provided secret_id_ttl
-    // value.
-    pub fn derive_secret_id_ttl(&self, secret_id_ttl: Duration) -> Duration {
-        if secret_id_ttl > MAX_LEASE_DURATION_SECS {
-            return MAX_LEASE_DURATION_SECS;
+    // If secret_id_ttl is zero, the configured default_secret_id_ttl is used
+    // in its place. If secret_id_ttl is negative or if it crosses the
+    // configured limit, return the configured max_secret_id_ttl. Otherwise,
+    // return the provided secret_id_ttl value.
+    pub fn derive_secret_id_ttl(&self, secret_id_ttl: LeaseTtl) -> LeaseTtl {
+        let (max_secret_id_ttl, default_secret_id_ttl) = match self.config.read() {
+            Ok(config) => (config.max_secret_id_ttl, config.default_secret_id_ttl),
+            Err(_) => {
+                let config = AppRoleConfig::default();
+                (config.max_secret_id_ttl, config.default_secret_id_ttl)
+            }
+        };
+
+        let secret_id_ttl = if secret_id_ttl.is_zero() { LeaseTtl::from(default_secret_id_ttl) } else { secret_id_ttl };
+
+        secret_id_ttl.capped_to(LeaseTtl::from(max_secret_id_ttl))
+    }
+
+    // is_past_expiration reports whether a secret_id expiring at expiration_time
+    // has expired at now, allowing for the configured clock_skew_tolerance.
+    pub fn is_past_expiration(&self, expiration_time: SystemTime, now: SystemTime) -> Result<bool, RvError> {
+        let tolerance = self.config()?.clock_skew_tolerance;
+        Ok(expiration_time.checked_add(tolerance).map_or(false, |deadline| now > deadline))
+    }
+
+    // is_created_in_future reports whether a secret_id created at creation_time
+    // is further in the future of now than the clock_skew_tolerance, meaning
+    // that the clock went back since it was created.
+    pub fn is_created_in_future(&self, creation_time: SystemTime, now: SystemTime) -> Result<bool, RvError> {
+        let tolerance = self.config()?.clock_skew_tolerance;
+        Ok(now.checked_add(tolerance).map_or(false, |horizon| creation_time > horizon))
+    }
+
+    // jitter_secret_id_ttl adds a random offset of up to expiration_jitter_percent
+    // of the TTL to a TTL returned by derive_secret_id_ttl. The result never goes
+    // past the max TTL.
+    pub fn jitter_secret_id_ttl(&self, ttl: LeaseTtl) -> Result<LeaseTtl, RvError> {
+        let config = self.config()?;
+        let window = ttl.as_secs().saturating_mul(config.expiration_jitter_percent.min(100).into()) / 100;
+        if window == 0 {
+            return Ok(ttl);
+        }
+
+        let offset = self.rng.lock().unwrap_or_else(PoisonError::into_inner).gen_range(0..=window);
+        let max_ttl = LeaseTtl::from(config.max_secret_id_ttl).max(ttl);
+        Ok(LeaseTtl::from(ttl.as_duration().saturating_add(Duration::from_secs(offset))).capped_to(max_ttl))
+    }
+
+    // warn_secret_id_ttl pushes a warning for the client when derive_secret_id_ttl
+    // would clamp the given TTL. The operation itself still succeeds.
+    pub fn warn_secret_id_ttl(&self, secret_id_ttl: LeaseTtl, warnings: &mut Vec<String>) {
+        let derived = self.derive_secret_id_ttl(secret_id_ttl);
+        if !secret_id_ttl.is_zero() && derived != secret_id_ttl {
+            warnings.push(format!(
+                "secret_id_ttl of {}s is greater than the max_secret_id_ttl of the approle config, it is capped to {}s",
+                secret_id_ttl.as_secs(),
+                derived.as_secs()
+            ));
         }
+    }
+
+    // accessor_index returns the storage index of the accessor entry, built from
+    // the salted accessor, along with the lock guarding it. The lock is not taken,
+    // the caller locks it for reading or writing as needed.
+    pub fn accessor_index(
+        &self,
+        secret_id_accessor: &str,
+        scope: SecretIdScope,
+    ) -> Result<(String, Arc<LockEntry>), RvError> {
+        let salt = self.salt.read()?;
+        let Some(salt) = salt.as_ref() else {
+            return Err(RvError::ErrModuleNotInitialized("approle"));
+        };
 
-        secret_id_ttl
+        let salt_id = salt.salt_id(secret_id_accessor)?;
+        let entry_index = accessor_entry_index(scope, &salt_id)?;
+
+        Ok((entry_index, self.secret_id_accessor_locks.get_lock(secret_id_accessor)))
     }
 
     // secret_id_accessor_entry is used to read the storage entry that maps an
@@ -237,30 +939,19 @@ impl AppRoleBackendInner {
         &self,
         storage: &dyn Storage,
         secret_id_accessor: &str,
-        role_secret_id_prefix: &str,
+        scope: SecretIdScope,
     ) -> Result<Option<SecretIdAccessorStorageEntry>, RvError> {
         if secret_id_accessor.is_empty() {
             return Err(RvError::ErrResponse("missing secret id accessor".to_string()));
         }
 
-        let salt = self.salt.read()?;
-        if salt.is_none() {
-            return Err(RvError::ErrResponse("approle module not initialized".to_string()));
-        }
-
-        let salt_id = salt.as_ref().unwrap().salt_id(secret_id_accessor)?;
+        let (entry_index, lock_entry) = self.accessor_index(secret_id_accessor, scope)?;
+        let _locked = lock_entry.read()?;
 
-        let mut accessor_prefix = SECRET_ID_ACCESSOR_PREFIX;
-        if role_secret_id_prefix == SECRET_ID_LOCAL_PREFIX {
-            accessor_prefix = SECRET_ID_ACCESSOR_LOCAL_PREFIX;
+        let mut storage_entry = storage.get(&entry_index)?;
+        if storage_entry.is_none() {
+            storage_entry = self.get_previous_salt_accessor_entry(storage, secret_id_accessor, scope)?;
         }
-
-        let entry_index = format!("{}{}", accessor_prefix, salt_id);
-
-        let lock_entry = self.secret_id_accessor_locks.get_lock(secret_id_accessor);
-        let _locked = lock_entry.lock.read()?;
-
-        let storage_entry = storage.get(&entry_index)?;
         if storage_entry.is_none() {
             return Ok(None);
         }
@@ -271,6 +962,49 @@ impl AppRoleBackendInner {
         Ok(Some(ret))
     }
 
+    // get_previous_salt_accessor_entry reads the accessor entry salted with the
+    // previous salt, so that the accessors created before a salt rotation still
+    // resolve while it is in progress. None is returned when no rotation is.
+    fn get_previous_salt_accessor_entry(
+        &self,
+        storage: &dyn Storage,
+        secret_id_accessor: &str,
+        scope: SecretIdScope,
+    ) -> Result<Option<StorageEntry>, RvError> {
+        let previous_salt = self.previous_salt.read()?;
+        let Some(salt) = previous_salt.as_ref() else {
+            return Ok(None);
+        };
+
+        let entry_index = accessor_entry_index(scope, &salt.salt_id(secret_id_accessor)?)?;
+        storage.get(&entry_index)
+    }
+
+    // resolve_accessor follows the accessor to the secret_id it belongs to and
+    // returns a non-sensitive view of the secret_id properties. None is returned
+    // when either the accessor or the secret_id entry does not exist.
+    pub fn resolve_accessor(
+        &self,
+        storage: &dyn Storage,
+        secret_id_accessor: &str,
+        scope: SecretIdScope,
+        role_name_hmac: &str,
+    ) -> Result<Option<SecretIdInfo>, RvError> {
+        let accessor_entry = self.get_secret_id_accessor_entry(storage, secret_id_accessor, scope)?;
+        if accessor_entry.is_none() {
+            return Ok(None);
+        }
+
+        let secret_id_hmac = accessor_entry.unwrap().secret_id_hmac;
+
+        let lock_entry = self.secret_id_locks.get_lock(&secret_id_hmac);
+        let _locked = lock_entry.read()?;
+
+        let entry = self.get_secret_id_storage_entry(storage, scope, role_name_hmac, &secret_id_hmac)?;
+
+        Ok(entry.as_ref().map(SecretIdInfo::from))
+    }
+
     // create_secret_id_accessor_entry creates an identifier for the secret_id.
     // A storage index, mapping the accessor to the secret_id is also created.
     // This method should be called when the lock for the corresponding secret_id is held.
@@ -279,86 +1013,346 @@ impl AppRoleBackendInner {
         storage: &dyn Storage,
         entry: &mut SecretIdStorageEntry,
         secret_id_hmac: &str,
-        role_secret_id_prefix: &str,
+        scope: SecretIdScope,
     ) -> Result<(), RvError> {
-        entry.secret_id_accessor = utils::generate_uuid();
-
-        let salt = self.salt.read()?;
-        if salt.is_none() {
-            return Err(RvError::ErrResponse("approle module not initialized".to_string()));
-        }
+        for _ in 0..ACCESSOR_GENERATE_ATTEMPTS {
+            let secret_id_accessor = self.generate_accessor()?;
+
+            let (entry_index, lock_entry) = self.accessor_index(&secret_id_accessor, scope)?;
+            let _locked = lock_entry.write()?;
+
+            // An accessor format is required to draw enough entropy for this
+            // to never happen, but an accessor pointing at another secret_id
+            // must not be overwritten regardless.
+            if storage.get(&entry_index)?.is_some() {
+                log::warn!("generated secret ID accessor already exists, drawing another one");
+                continue;
+            }
 
-        let salt_id = salt.as_ref().unwrap().salt_id(&entry.secret_id_accessor)?;
+            let storage_entry = StorageEntry::new(
+                &entry_index,
+                &SecretIdAccessorStorageEntry { secret_id_hmac: secret_id_hmac.to_string() },
+            )?;
+            storage.put(&storage_entry)?;
 
-        let mut accessor_prefix = SECRET_ID_ACCESSOR_PREFIX;
-        if role_secret_id_prefix == SECRET_ID_LOCAL_PREFIX {
-            accessor_prefix = SECRET_ID_ACCESSOR_LOCAL_PREFIX;
+            entry.secret_id_accessor = secret_id_accessor;
+            return Ok(());
         }
 
-        let entry_index = format!("{}{}", accessor_prefix, salt_id);
-
-        let lock_entry = self.secret_id_accessor_locks.get_lock(&entry.secret_id_accessor);
-        let _locked = lock_entry.lock.write()?;
-
-        let entry = StorageEntry::new(
-            &entry_index,
-            &SecretIdAccessorStorageEntry { secret_id_hmac: secret_id_hmac.to_string() },
-        )?;
-
-        storage.put(&entry)
+        Err(RvError::ErrString("failed to generate a unique secret ID accessor".to_string()))
     }
 
     // delete_secret_id_accessor_entry deletes the storage index mapping the accessor to a secret_id.
+    // The deletion is confirmed, so a revoked accessor cannot be resolved anymore once this returns.
     pub fn delete_secret_id_accessor_entry(
         &self,
         storage: &dyn Storage,
         secret_id_accessor: &str,
-        role_secret_id_prefix: &str,
+        scope: SecretIdScope,
     ) -> Result<(), RvError> {
-        let salt = self.salt.read()?;
-        if salt.is_none() {
-            return Err(RvError::ErrResponse("approle module not initialized".to_string()));
-        }
+        let (entry_index, lock_entry) = self.accessor_index(secret_id_accessor, scope)?;
+        let _locked = lock_entry.write()?;
 
-        let salt_id = salt.as_ref().unwrap().salt_id(secret_id_accessor)?;
+        storage.delete_and_confirm(&entry_index)
+    }
 
-        let mut accessor_prefix = SECRET_ID_ACCESSOR_PREFIX;
-        if role_secret_id_prefix == SECRET_ID_LOCAL_PREFIX {
-            accessor_prefix = SECRET_ID_ACCESSOR_LOCAL_PREFIX;
-        }
+    // rotate_secret_id_accessor assigns a new accessor to the secret_id, for when
+    // the current one has leaked. The new accessor index is written before the
+    // secret_id entry is switched over to it, and the old index is deleted last,
+    // so the secret_id is always reachable from one of them. The new accessor is
+    // returned.
+    pub fn rotate_secret_id_accessor(
+        &self,
+        storage: &dyn Storage,
+        scope: SecretIdScope,
+        role_name_hmac: &str,
+        secret_id_hmac: &str,
+    ) -> Result<String, RvError> {
+        let lock_entry = self.secret_id_locks.get_lock(secret_id_hmac);
+        let _locked = lock_entry.write()?;
 
-        let entry_index = format!("{}{}", accessor_prefix, salt_id);
+        let Some(mut entry) = self.get_secret_id_storage_entry(storage, scope, role_name_hmac, secret_id_hmac)? else {
+            return Err(RvError::ErrResponse("invalid secret id".to_string()));
+        };
+        let old_accessor = entry.secret_id_accessor.clone();
 
-        let lock_entry = self.secret_id_accessor_locks.get_lock(secret_id_accessor);
-        let _locked = lock_entry.lock.write()?;
+        self.create_secret_id_accessor_entry(storage, &mut entry, secret_id_hmac, scope)?;
+        entry.last_updated_time = self.clock.now();
+        self.set_secret_id_storage_entry(storage, scope, role_name_hmac, secret_id_hmac, &entry)?;
+
+        if !old_accessor.is_empty() {
+            self.delete_secret_id_accessor_entry(storage, &old_accessor, scope)?;
+        }
 
-        storage.delete(&entry_index)
+        Ok(entry.secret_id_accessor)
     }
 
     // flush_role_secrets deletes all the secret_id that belong to the given
-    // role_id.
+    // role_id, along with their accessors, and returns the number of secret_ids
+    // deleted.
     pub fn flush_role_secrets(
         &self,
         storage: &dyn Storage,
         role_name: &str,
         hmac_key: &str,
-        role_secret_id_prefix: &str,
-    ) -> Result<(), RvError> {
-        let role_name_hmac = create_hmac(hmac_key, role_name)?;
-        let key = format!("{}{}/", role_secret_id_prefix, role_name_hmac);
-        let secret_id_hmacs = storage.list(&key)?;
-        for secret_id_hmac in secret_id_hmacs.iter() {
-            let entry_index = format!("{}{}/{}", role_secret_id_prefix, role_name_hmac, secret_id_hmac);
+        scope: SecretIdScope,
+    ) -> Result<usize, RvError> {
+        self.flush_role_secrets_with(storage, role_name, hmac_key, scope, FlushStrategy::default())
+    }
+
+    // flush_role_secrets_with is flush_role_secrets with the given strategy.
+    // Either way the entry of a secret_id is deleted before its accessor, or
+    // along with it if the backend deletes batches atomically, so a flush
+    // failing part way may leave accessors behind but no secret_id without its
+    // accessor.
+    pub fn flush_role_secrets_with(
+        &self,
+        storage: &dyn Storage,
+        role_name: &str,
+        hmac_key: &str,
+        scope: SecretIdScope,
+        strategy: FlushStrategy,
+    ) -> Result<usize, RvError> {
+        let span = trace::approle_span("flush_role_secrets");
+        trace::in_span(span.clone(), || {
+            let role_name_hmac = self.role_name_hmac(hmac_key, role_name)?;
+            span.record("role_name_hmac", role_name_hmac.as_str());
+
+            let key = format!("{}{}/", scope.prefix(), role_name_hmac);
+            let secret_id_hmacs = storage.list(&key)?;
+            span.record("entries", 0);
+            match strategy {
+                FlushStrategy::Sequential => {
+                    let mut any_contended = false;
+                    for (flushed, secret_id_hmac) in secret_id_hmacs.iter().enumerate() {
+                        let lock_entry = self.secret_id_locks.get_lock(secret_id_hmac);
+                        let (_locked, contended) = lock_entry.write_contended()?;
+                        any_contended |= contended;
+                        span.record("lock_contended", any_contended);
+                        for key in self.flushed_keys(storage, scope, &role_name_hmac, secret_id_hmac)?.iter() {
+                            storage.delete_and_confirm(key)?;
+                        }
+                        span.record("entries", flushed + 1);
+                    }
+                }
+                FlushStrategy::Batched(batch_size) => {
+                    let mut flushed = 0;
+                    for chunk in secret_id_hmacs.chunks(batch_size.max(1)) {
+                        let chunk: Vec<&str> = chunk.iter().map(String::as_str).collect();
+                        let _locked = self.secret_id_locks.write_all(&chunk)?;
+
+                        let mut keys = Vec::with_capacity(chunk.len() * 2);
+                        for secret_id_hmac in chunk.iter() {
+                            keys.extend(self.flushed_keys(storage, scope, &role_name_hmac, secret_id_hmac)?);
+                        }
+                        storage.delete_batch(&keys)?;
+
+                        flushed += chunk.len();
+                        span.record("entries", flushed);
+                    }
+                    confirm_flushed(storage, &key, &secret_id_hmacs)?;
+                }
+            }
+
+            let count_lock_entry = self.secret_id_count_locks.get_lock(&role_name_hmac);
+            let _count_locked = count_lock_entry.write()?;
+            storage.delete(&format!("{}{}", SECRET_ID_COUNT_PREFIX, role_name_hmac))?;
+
+            self.delete_metadata_index(storage, scope, &role_name_hmac)?;
+
+            Ok(secret_id_hmacs.len())
+        })
+    }
+
+    // flush_roles_secrets flushes the secret_ids of several roles sharing an
+    // hmac_key, each independently of the others. The error of a role is
+    // recorded in the report and the next role is flushed all the same. Only
+    // an error of the storage itself, which the next roles would run into too,
+    // aborts the whole call.
+    pub fn flush_roles_secrets(
+        &self,
+        storage: &dyn Storage,
+        roles: &[&str],
+        hmac_key: &str,
+        scope: SecretIdScope,
+    ) -> Result<MultiFlushReport, RvError> {
+        let mut report = MultiFlushReport::default();
+        for role_name in roles.iter() {
+            let mut result = RoleFlushResult { role_name: role_name.to_string(), ..Default::default() };
+            match self.flush_role_secrets(storage, role_name, hmac_key, scope) {
+                Ok(flushed) => result.flushed = flushed,
+                Err(err) if is_systemic_error(&err) => return Err(err),
+                Err(err) => {
+                    log::warn!("failed to flush the secret_ids of role {}, err: {}", role_name, err);
+                    result.error = Some(err.to_string());
+                }
+            }
+            report.roles.push(result);
+        }
+
+        Ok(report)
+    }
+
+    // flushed_keys returns the keys deleted to flush a secret_id: its entry,
+    // then its accessor entry. The accessor of a corrupt entry cannot be read,
+    // it is left to reconcile. The caller should hold the secret_id lock.
+    fn flushed_keys(
+        &self,
+        storage: &dyn Storage,
+        scope: SecretIdScope,
+        role_name_hmac: &str,
+        secret_id_hmac: &str,
+    ) -> Result<Vec<String>, RvError> {
+        let mut keys = vec![secret_id_entry_index(scope, role_name_hmac, secret_id_hmac)?];
+        match self.get_secret_id_storage_entry(storage, scope, role_name_hmac, secret_id_hmac) {
+            Ok(Some(entry)) if !entry.secret_id_accessor.is_empty() => {
+                keys.push(self.accessor_index(&entry.secret_id_accessor, scope)?.0);
+            }
+            Ok(_) | Err(RvError::SerdeJson { .. }) => {}
+            Err(err) => return Err(err),
+        }
+
+        Ok(keys)
+    }
+
+    // rebind_secret_ids moves the secret_ids of a role bound to
+    // previous_role_id_hmac over to role_id_hmac, after its role_id changed.
+    // The secret_ids that are unbound, or bound to anything else, are left
+    // alone. It returns the number of secret_ids rebound.
+    pub fn rebind_secret_ids(
+        &self,
+        storage: &dyn Storage,
+        scope: SecretIdScope,
+        role_name_hmac: &str,
+        previous_role_id_hmac: &str,
+        role_id_hmac: &str,
+    ) -> Result<usize, RvError> {
+        let key = format!("{}{}/", scope.prefix(), role_name_hmac);
+        let mut rebound = 0;
+        for secret_id_hmac in storage.list(&key)?.iter() {
             let lock_entry = self.secret_id_locks.get_lock(secret_id_hmac);
-            let _locked = lock_entry.lock.write()?;
-            storage.delete(&entry_index)?
+            let _locked = lock_entry.write()?;
+
+            let Some(mut entry) = self.get_secret_id_storage_entry(storage, scope, role_name_hmac, secret_id_hmac)?
+            else {
+                continue;
+            };
+            if entry.role_id_hmac != previous_role_id_hmac {
+                continue;
+            }
+
+            entry.role_id_hmac = role_id_hmac.to_string();
+            let entry_index = secret_id_entry_index(scope, role_name_hmac, secret_id_hmac)?;
+            storage.put(&StorageEntry::new(&entry_index, &entry)?)?;
+            rebound += 1;
         }
 
-        Ok(())
+        Ok(rebound)
+    }
+
+    // role_name_hmac returns create_hmac(hmac_key, role_name), memoized in a
+    // bounded, sharded LRU. The cache is keyed by the hmac_key along with the
+    // role name, so rotating a role's hmac_key never hits a stale entry, and a
+    // hit only takes the read lock of its shard.
+    // The role name is normalized first, see utils::normalize_role_name, the
+    // roles stored under a name that is not are moved by migrate_role_names.
+    pub fn role_name_hmac(&self, hmac_key: &str, role_name: &str) -> Result<String, RvError> {
+        if role_name.is_empty() {
+            return Err(RvError::ErrResponse("missing role_name".to_string()));
+        }
+
+        let role_name = utils::normalize_role_name(role_name)?;
+        let cache_key = (hmac_key.to_string(), role_name);
+        if let Some(hmac) = self.role_name_hmac_cache.get(&cache_key) {
+            return Ok(hmac);
+        }
+
+        let hmac = hmac_required_field(hmac_key, "role_name", &cache_key.1)?;
+        self.role_name_hmac_cache.insert(cache_key, hmac.clone());
+        Ok(hmac)
+    }
+}
+
+// validate_secret_id_name checks the optional display name of a secret_id.
+pub fn validate_secret_id_name(name: &str) -> Result<(), RvError> {
+    if name.is_empty() {
+        return Err(RvError::ErrResponse("secret_id name cannot be empty".to_string()));
     }
+
+    if name.len() > MAX_SECRET_ID_NAME_LENGTH {
+        return Err(RvError::ErrResponse(format!(
+            "secret_id name is {} bytes long, exceeding the limit of {}",
+            name.len(),
+            MAX_SECRET_ID_NAME_LENGTH
+        )));
+    }
+
+    Ok(())
+}
+
+// secret_id_entry_index builds the storage index of a secret_id entry.
+pub fn secret_id_entry_index(
+    scope: SecretIdScope,
+    role_name_hmac: &str,
+    secret_id_hmac: &str,
+) -> Result<String, RvError> {
+    let entry_index = format!("{}{}/{}", scope.prefix(), role_name_hmac, secret_id_hmac);
+    utils::validate_storage_key(&entry_index)?;
+    Ok(entry_index)
+}
+
+// accessor_entry_index builds the storage index of a secret_id accessor entry
+// from the salted accessor.
+pub fn accessor_entry_index(scope: SecretIdScope, salt_id: &str) -> Result<String, RvError> {
+    let entry_index = format!("{}{}", scope.accessor_prefix(), salt_id);
+    utils::validate_storage_key(&entry_index)?;
+    Ok(entry_index)
+}
+
+// is_systemic_error tells whether an error comes from the storage or the
+// process as a whole, rather than from the request at hand, so that retrying
+// with another request would fail the same way.
+fn is_systemic_error(err: &RvError) -> bool {
+    match err {
+        RvError::IO { .. }
+        | RvError::ErrStorageTimeout(_)
+        | RvError::ErrBarrierSealed
+        | RvError::ErrRwLockReadPoison
+        | RvError::ErrRwLockWritePoison => true,
+        #[cfg(feature = "storage_mysql")]
+        RvError::ErrDatabaseExecuteEntry { .. } => true,
+        _ => false,
+    }
+}
+
+// confirm_flushed waits until none of the flushed secret_ids is listed under
+// the prefix anymore, like Storage::delete_and_confirm does for a single key,
+// but with one list for all of them.
+fn confirm_flushed(storage: &dyn Storage, prefix: &str, secret_id_hmacs: &[String]) -> Result<(), RvError> {
+    let mut backoff = DELETE_CONFIRM_BACKOFF;
+    for attempt in 1..=DELETE_CONFIRM_ATTEMPTS {
+        let listed = storage.list(prefix)?;
+        let Some(remaining) = secret_id_hmacs.iter().find(|hmac| listed.binary_search(*hmac).is_ok()) else {
+            return Ok(());
+        };
+
+        if attempt == DELETE_CONFIRM_ATTEMPTS {
+            return Err(RvError::ErrStorageDeleteNotConfirmed(format!("{}{}", prefix, remaining)));
+        }
+        thread::sleep(backoff);
+        backoff *= 2;
+    }
+
+    Ok(())
 }
 
 pub fn create_hmac(key: &str, value: &str) -> Result<String, RvError> {
+    create_hmac_encoded(key, value, HmacEncoding::Hex)
+}
+
+// hmac_sha256 computes the raw HMAC-SHA256 of value, the one create_hmac
+// encodes. The key must not be empty and the value is bounded in length.
+pub fn hmac_sha256(key: &str, value: &str) -> Result<Vec<u8>, RvError> {
     if key.is_empty() {
         return Err(RvError::ErrResponse("invalid hmac key".to_string()));
     }
@@ -367,11 +1361,111 @@ pub fn create_hmac(key: &str, value: &str) -> Result<String, RvError> {
         return Err(RvError::ErrResponse(format!("value is longer than maximum of {} bytes", MAX_HMAC_INPUT_LENGTH)));
     }
 
+    hmac_bytes(MessageDigest::sha256(), key, value)
+}
+
+// derive_hmac_key derives a hex encoded hmac key from the master secret with
+// HKDF-SHA256, using the context as the info label. The derivation is
+// deterministic, and keys derived under different contexts are independent, so
+// a single master secret can back the keys of every purpose.
+pub fn derive_hmac_key(master: &[u8], context: &str) -> Result<String, RvError> {
+    if master.is_empty() {
+        return Err(RvError::ErrResponse("missing master secret".to_string()));
+    }
+
+    if context.is_empty() {
+        return Err(RvError::ErrResponse("missing hmac key context".to_string()));
+    }
+
+    let key = hkdf_sha256(master, &[], context.as_bytes(), DERIVED_HMAC_KEY_LENGTH)?;
+    Ok(hex::encode(key))
+}
+
+// create_hmac_with computes the hex encoded HMAC of value with the given digest.
+// Unlike create_hmac, the length of the value is not limited.
+pub fn create_hmac_with(digest: MessageDigest, key: &str, value: &str) -> Result<String, RvError> {
+    if key.is_empty() {
+        return Err(RvError::ErrResponse("invalid hmac key".to_string()));
+    }
+
+    Ok(hex::encode(hmac_bytes(digest, key, value)?))
+}
+
+fn hmac_bytes(digest: MessageDigest, key: &str, value: &str) -> Result<Vec<u8>, RvError> {
+    check_digest_permitted(digest)?;
+
     let pkey = PKey::hmac(key.as_bytes())?;
-    let mut signer = Signer::new(MessageDigest::sha256(), &pkey)?;
+    let mut signer = Signer::new(digest, &pkey)?;
     signer.update(value.as_bytes())?;
-    let hmac = signer.sign_to_vec()?;
-    Ok(hex::encode(hmac.as_slice()))
+    Ok(signer.sign_to_vec()?)
+}
+
+// hmac_required_field is create_hmac for the values approle indexes its
+// entries with, such as the role_name and the secret_id. Those are never
+// legitimately empty, so an empty value is rejected, naming the field.
+pub fn hmac_required_field(key: &str, field_name: &str, value: &str) -> Result<String, RvError> {
+    if value.is_empty() {
+        return Err(RvError::ErrResponse(format!("missing {}", field_name)));
+    }
+
+    create_hmac(key, value)
+}
+
+// validate_secret_id_metadata bounds the number and the size of the metadata
+// pairs attached to a secret_id.
+pub fn validate_secret_id_metadata(metadata: &HashMap<String, String>, config: &AppRoleConfig) -> Result<(), RvError> {
+    if metadata.len() > config.max_metadata_pairs {
+        return Err(RvError::ErrResponse(format!(
+            "metadata cannot contain more than {} pairs",
+            config.max_metadata_pairs
+        )));
+    }
+
+    for (key, value) in metadata.iter() {
+        if key.len() > config.max_metadata_key_length {
+            return Err(RvError::ErrResponse(format!(
+                "metadata key is longer than maximum of {} bytes",
+                config.max_metadata_key_length
+            )));
+        }
+
+        if value.len() > config.max_metadata_value_length {
+            return Err(RvError::ErrResponse(format!(
+                "metadata value for key {} is longer than maximum of {} bytes",
+                key, config.max_metadata_value_length
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+// canonicalize_secret_id_cidrs parses the CIDR blocks of a secret_id, rewrites
+// them to their network address, drops the duplicates and bounds their number.
+// The order of the first occurrences is kept.
+pub fn canonicalize_secret_id_cidrs(
+    field_name: &str,
+    cidrs: &[String],
+    config: &AppRoleConfig,
+) -> Result<Vec<String>, RvError> {
+    let mut seen = HashSet::new();
+    let mut canonical = Vec::new();
+    for cidr in cidrs.iter() {
+        let block = Cidr::from_str(cidr)
+            .map_err(|_| RvError::ErrResponse(format!("invalid CIDR block {} in {}", cidr, field_name)))?;
+        if seen.insert(block) {
+            canonical.push(block.to_string());
+        }
+    }
+
+    if config.max_secret_id_cidr_blocks > 0 && canonical.len() > config.max_secret_id_cidr_blocks {
+        return Err(RvError::ErrResponse(format!(
+            "{} cannot contain more than {} CIDR blocks",
+            field_name, config.max_secret_id_cidr_blocks
+        )));
+    }
+
+    Ok(canonical)
 }
 
 pub fn verify_cidr_role_secret_id_subset(
@@ -398,3 +1492,1563 @@ pub fn verify_cidr_role_secret_id_subset(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex, RwLock,
+        },
+        thread,
+        time::Instant,
+    };
+
+    use actix_web::http::StatusCode;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::{
+        super::{
+            accessor::AccessorFormat,
+            concurrency::{OnLimit, RegistrationLimiterConfig},
+            metadata_index::metadata_index_prefix,
+            test::{new_test_inner, register_test_secret_id},
+            METADATA_INDEX_PREFIX, SECRET_ID_ACCESSOR_LOCAL_PREFIX, SECRET_ID_ACCESSOR_PREFIX, SECRET_ID_LOCAL_PREFIX,
+            SECRET_ID_PREFIX,
+        },
+        *,
+    };
+    use crate::{
+        storage::{
+            legacy_prefix::LegacyPrefixShim,
+            retry::test::FaultInjectStorage,
+            wal::{self, WAL_PREFIX},
+        },
+        test_utils::test_rusty_vault_init,
+        utils::{clock::MockClock, entropy::EntropySource, salt::Salt},
+    };
+
+    #[test]
+    fn test_approle_resolve_accessor() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        let (mut inner, storage) = new_test_inner("test_approle_resolve_accessor");
+        inner.clock = Arc::new(MockClock::new(start));
+
+        let mut secret_entry = SecretIdStorageEntry {
+            secret_id_ttl: LeaseTtl::from_secs(600),
+            secret_id_num_uses: 5,
+            cidr_list: vec!["127.0.0.1/32".to_string()],
+            metadata: HashMap::from([("env".to_string(), "prod".to_string())]),
+            ..Default::default()
+        };
+        register_test_secret_id(&inner, storage.as_ref(), "role1", "secret1", SecretIdScope::Global, &mut secret_entry);
+
+        let role_name_hmac = create_hmac("testhmackey", "role1").unwrap();
+        let info = inner
+            .resolve_accessor(
+                storage.as_ref(),
+                &secret_entry.secret_id_accessor,
+                SecretIdScope::Global,
+                &role_name_hmac,
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.creation_time, start);
+        assert_eq!(info.expiration_time, start + Duration::from_secs(600));
+        assert_eq!(info.secret_id_num_uses, 5);
+        assert_eq!(info.cidr_list, vec!["127.0.0.1/32".to_string()]);
+        assert_eq!(info.metadata.get("env").unwrap(), "prod");
+
+        // The HMAC index must not leak through the projection
+        let secret_id_hmac = create_hmac("testhmackey", "secret1").unwrap();
+        let data = serde_json::to_string(&info).unwrap();
+        assert!(!data.contains(&secret_id_hmac));
+        assert!(!data.contains(&role_name_hmac));
+
+        let info = inner.resolve_accessor(storage.as_ref(), "no-such-accessor", SecretIdScope::Global, &role_name_hmac);
+        assert!(info.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_approle_accessor_salt_rotation() {
+        let (inner, storage) = new_test_inner("test_approle_accessor_salt_rotation");
+
+        // The accessor is created under the old salt
+        let mut secret_entry = SecretIdStorageEntry { secret_id_ttl: LeaseTtl::from_secs(600), ..Default::default() };
+        register_test_secret_id(&inner, storage.as_ref(), "role1", "secret1", SecretIdScope::Global, &mut secret_entry);
+        let accessor = secret_entry.secret_id_accessor.clone();
+        let (old_index, _) = inner.accessor_index(&accessor, SecretIdScope::Global).unwrap();
+
+        // Half-rotated, the accessor is missing under the new salt but still resolves
+        assert!(inner.begin_salt_rotation(Salt::new_nonpersistent()).is_ok());
+        let (new_index, _) = inner.accessor_index(&accessor, SecretIdScope::Global).unwrap();
+        assert_ne!(new_index, old_index);
+        assert!(storage.get(&new_index).unwrap().is_none());
+
+        let entry = inner.get_secret_id_accessor_entry(storage.as_ref(), &accessor, SecretIdScope::Global).unwrap();
+        assert_eq!(entry.unwrap().secret_id_hmac, create_hmac("testhmackey", "secret1").unwrap());
+        let role_name_hmac = create_hmac("testhmackey", "role1").unwrap();
+        assert!(inner
+            .resolve_accessor(storage.as_ref(), &accessor, SecretIdScope::Global, &role_name_hmac)
+            .unwrap()
+            .is_some());
+
+        // A missing accessor is still reported as such
+        let entry = inner.get_secret_id_accessor_entry(storage.as_ref(), "no-such-accessor", SecretIdScope::Global);
+        assert!(entry.unwrap().is_none());
+
+        // Once the rotation completes, the old salt is no longer consulted
+        assert!(inner.complete_salt_rotation().is_ok());
+        assert!(inner.previous_salt.read().unwrap().is_none());
+        let entry = inner.get_secret_id_accessor_entry(storage.as_ref(), &accessor, SecretIdScope::Global).unwrap();
+        assert!(entry.is_none());
+    }
+
+    #[test]
+    fn test_approle_rotate_secret_id_accessor() {
+        let (inner, storage) = new_test_inner("test_approle_rotate_secret_id_accessor");
+
+        let mut secret_entry = SecretIdStorageEntry { secret_id_ttl: LeaseTtl::from_secs(600), ..Default::default() };
+        register_test_secret_id(&inner, storage.as_ref(), "role1", "secret1", SecretIdScope::Global, &mut secret_entry);
+        let old_accessor = secret_entry.secret_id_accessor.clone();
+
+        let role_name_hmac = create_hmac("testhmackey", "role1").unwrap();
+        let secret_id_hmac = create_hmac("testhmackey", "secret1").unwrap();
+        let new_accessor = inner
+            .rotate_secret_id_accessor(storage.as_ref(), SecretIdScope::Global, &role_name_hmac, &secret_id_hmac)
+            .unwrap();
+        assert_ne!(new_accessor, old_accessor);
+
+        // Only the new accessor resolves, and the secret_id entry points at it
+        assert!(inner
+            .resolve_accessor(storage.as_ref(), &old_accessor, SecretIdScope::Global, &role_name_hmac)
+            .unwrap()
+            .is_none());
+        assert!(inner
+            .resolve_accessor(storage.as_ref(), &new_accessor, SecretIdScope::Global, &role_name_hmac)
+            .unwrap()
+            .is_some());
+        let entry = inner
+            .get_secret_id_storage_entry(storage.as_ref(), SecretIdScope::Global, &role_name_hmac, &secret_id_hmac)
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.secret_id_accessor, new_accessor);
+        assert_eq!(entry.expiration_time, secret_entry.expiration_time);
+        assert_eq!(storage.list(SECRET_ID_ACCESSOR_PREFIX).unwrap().len(), 1);
+
+        // Rotating the accessor of an unknown secret_id fails
+        let unknown_hmac = create_hmac("testhmackey", "secret2").unwrap();
+        assert!(inner
+            .rotate_secret_id_accessor(storage.as_ref(), SecretIdScope::Global, &role_name_hmac, &unknown_hmac)
+            .is_err());
+    }
+
+    // Fills buffers with consecutive byte values, starting from its seed
+    struct SequenceEntropy(Mutex<u8>);
+
+    impl EntropySource for SequenceEntropy {
+        fn fill_bytes(&self, buf: &mut [u8]) -> Result<(), RvError> {
+            let mut next = self.0.lock().unwrap();
+            for byte in buf.iter_mut() {
+                *byte = *next;
+                *next = next.wrapping_add(1);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_approle_entropy_source() {
+        let (mut inner, storage) = new_test_inner("test_approle_entropy_source");
+        inner.entropy = Arc::new(SequenceEntropy(Mutex::new(0)));
+
+        let accessors: Vec<String> = ["secret1", "secret2"]
+            .iter()
+            .map(|secret_id| {
+                let mut entry = SecretIdStorageEntry { secret_id_ttl: LeaseTtl::from_secs(600), ..Default::default() };
+                inner
+                    .register_secret_id_entry(
+                        storage.as_ref(),
+                        "role1",
+                        secret_id,
+                        "testhmackey",
+                        SecretIdScope::Global,
+                        0,
+                        &mut entry,
+                    )
+                    .unwrap();
+                entry.secret_id_accessor
+            })
+            .collect();
+        assert_eq!(accessors, vec!["00010203-0405-0607-0809-0a0b0c0d0e0f", "10111213-1415-1617-1819-1a1b1c1d1e1f"]);
+        assert_eq!(inner.generate_uuid().unwrap(), "20212223-2425-2627-2829-2a2b2c2d2e2f");
+    }
+
+    #[test]
+    fn test_approle_accessor_index() {
+        let (inner, storage) = new_test_inner("test_approle_accessor_index");
+
+        for scope in SecretIdScope::ALL {
+            // The create, get and delete paths all agree on the index
+            let mut secret_entry = SecretIdStorageEntry::default();
+            assert!(inner.create_secret_id_accessor_entry(storage.as_ref(), &mut secret_entry, "hmac1", scope).is_ok());
+            let accessor = secret_entry.secret_id_accessor.as_str();
+
+            let (entry_index, lock_entry) = inner.accessor_index(accessor, scope).unwrap();
+            assert!(entry_index.starts_with(scope.accessor_prefix()));
+            assert!(!entry_index.contains(accessor));
+            assert!(Arc::ptr_eq(&lock_entry, &inner.secret_id_accessor_locks.get_lock(accessor)));
+            assert!(storage.get(&entry_index).unwrap().is_some());
+
+            let accessor_entry = inner.get_secret_id_accessor_entry(storage.as_ref(), accessor, scope).unwrap();
+            assert_eq!(accessor_entry.unwrap().secret_id_hmac, "hmac1");
+
+            assert!(inner.delete_secret_id_accessor_entry(storage.as_ref(), accessor, scope).is_ok());
+            assert!(storage.get(&entry_index).unwrap().is_none());
+        }
+
+        *inner.salt.write().unwrap() = None;
+        let err = inner.accessor_index("accessor1", SecretIdScope::Global).unwrap_err();
+        assert_eq!(err, RvError::ErrModuleNotInitialized("approle"));
+    }
+
+    #[test]
+    fn test_approle_accessor_format() {
+        let (_root_token, core) = test_rusty_vault_init("test_approle_accessor_format");
+        let c = core.read().unwrap();
+
+        let storage: Arc<dyn Storage> = c.get_system_view().unwrap();
+        let new_inner = || AppRoleBackendInner {
+            salt: RwLock::new(Some(Salt::new(Some(storage.as_ref()), None).unwrap())),
+            entropy: Arc::new(SequenceEntropy(Mutex::new(0))),
+            ..AppRoleBackendInner::new(Arc::clone(&core))
+        };
+        let format = AccessorFormat::PrefixedBase32 { prefix: "app-".to_string(), bytes: 16 };
+        let inner = new_inner();
+        assert!(inner.set_accessor_format(format.clone()).is_ok());
+        let weak = AccessorFormat::PrefixedBase32 { prefix: "app-".to_string(), bytes: 8 };
+        assert!(inner.set_accessor_format(weak).is_err());
+        assert_eq!(*inner.accessor_format.read().unwrap(), format);
+
+        for scope in SecretIdScope::ALL {
+            // A custom accessor round-trips through the salted index
+            let mut secret_entry = SecretIdStorageEntry::default();
+            assert!(inner.create_secret_id_accessor_entry(storage.as_ref(), &mut secret_entry, "hmac1", scope).is_ok());
+            let accessor = secret_entry.secret_id_accessor.clone();
+            assert!(accessor.starts_with("app-"));
+            assert_eq!(accessor.len(), 4 + 26);
+
+            let (entry_index, _) = inner.accessor_index(&accessor, scope).unwrap();
+            assert!(!entry_index.contains(&accessor));
+            let accessor_entry = inner.get_secret_id_accessor_entry(storage.as_ref(), &accessor, scope).unwrap();
+            assert_eq!(accessor_entry.unwrap().secret_id_hmac, "hmac1");
+
+            // Changing the format does not affect the existing accessors
+            assert!(inner.set_accessor_format(AccessorFormat::Uuid).is_ok());
+            let accessor_entry = inner.get_secret_id_accessor_entry(storage.as_ref(), &accessor, scope).unwrap();
+            assert_eq!(accessor_entry.unwrap().secret_id_hmac, "hmac1");
+            assert!(inner.delete_secret_id_accessor_entry(storage.as_ref(), &accessor, scope).is_ok());
+            assert!(storage.get(&entry_index).unwrap().is_none());
+            assert!(inner.set_accessor_format(format.clone()).is_ok());
+        }
+
+        // Two backends replaying the same entropy draw the same accessor, the
+        // second one notices it is taken and draws another one
+        let (first, second) = (new_inner(), new_inner());
+        assert!(first.set_accessor_format(format.clone()).is_ok());
+        assert!(second.set_accessor_format(format.clone()).is_ok());
+        let scope = SecretIdScope::Global;
+        let mut first_entry = SecretIdStorageEntry::default();
+        assert!(first.create_secret_id_accessor_entry(storage.as_ref(), &mut first_entry, "hmac1", scope).is_ok());
+        let mut second_entry = SecretIdStorageEntry::default();
+        assert!(second.create_secret_id_accessor_entry(storage.as_ref(), &mut second_entry, "hmac2", scope).is_ok());
+        assert_ne!(first_entry.secret_id_accessor, second_entry.secret_id_accessor);
+        assert_eq!(second_entry.secret_id_accessor, format.generate(&SequenceEntropy(Mutex::new(16))).unwrap());
+
+        let resolve = |accessor: &str| {
+            first.get_secret_id_accessor_entry(storage.as_ref(), accessor, scope).unwrap().unwrap().secret_id_hmac
+        };
+        assert_eq!(resolve(&first_entry.secret_id_accessor), "hmac1");
+        assert_eq!(resolve(&second_entry.secret_id_accessor), "hmac2");
+    }
+
+    #[test]
+    fn test_approle_secret_id_entry_bounded() {
+        let entry = SecretIdStorageEntry {
+            cidr_list: (0..256).map(|i| format!("10.0.{}.0/24", i)).collect(),
+            metadata: (0..64).map(|i| (format!("key{}", i), "v".repeat(512))).collect(),
+            ..Default::default()
+        };
+        let size = serde_json::to_string(&entry).unwrap().len();
+
+        let err = StorageEntry::new_bounded("secret_id/role/secret", &entry, 4096).unwrap_err();
+        assert_eq!(err, RvError::ErrValueTooLarge { size, max: 4096 });
+        assert_eq!(err.response_status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let storage_entry = StorageEntry::new_bounded("secret_id/role/secret", &entry, size).unwrap();
+        let decoded: SecretIdStorageEntry = serde_json::from_slice(&storage_entry.value).unwrap();
+        assert!(decoded == entry);
+    }
+
+    #[test]
+    fn test_approle_secret_id_expiration_jitter() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        let (mut inner, storage) = new_test_inner("test_approle_secret_id_expiration_jitter");
+        inner.clock = Arc::new(MockClock::new(start));
+        inner.rng = Mutex::new(Box::new(StdRng::seed_from_u64(0x7177e2)));
+        inner.config = RwLock::new(AppRoleConfig { expiration_jitter_percent: 10, ..Default::default() });
+
+        let register_batch = |role_name: &str| -> Vec<SystemTime> {
+            (0..32)
+                .map(|i| {
+                    let mut entry =
+                        SecretIdStorageEntry { secret_id_ttl: LeaseTtl::from_secs(1000), ..Default::default() };
+                    register_test_secret_id(
+                        &inner,
+                        storage.as_ref(),
+                        role_name,
+                        &format!("secret{}", i),
+                        SecretIdScope::Global,
+                        &mut entry,
+                    );
+                    assert_eq!(entry.secret_id_ttl, LeaseTtl::from_secs(1000));
+                    entry.expiration_time
+                })
+                .collect()
+        };
+
+        // The expirations spread over the jitter window
+        let expirations = register_batch("role1");
+        assert!(expirations
+            .iter()
+            .all(|t| (start + Duration::from_secs(1000)..=start + Duration::from_secs(1100)).contains(t)));
+        let distinct: HashSet<&SystemTime> = expirations.iter().collect();
+        assert!(distinct.len() > 8);
+
+        // The jitter does not extend past the max TTL
+        inner
+            .set_config(AppRoleConfig {
+                expiration_jitter_percent: 10,
+                max_secret_id_ttl: Duration::from_secs(1050),
+                ..Default::default()
+            })
+            .unwrap();
+        let expirations = register_batch("role2");
+        assert!(expirations
+            .iter()
+            .all(|t| (start + Duration::from_secs(1000)..=start + Duration::from_secs(1050)).contains(t)));
+
+        // No jitter by default
+        inner.set_config(AppRoleConfig::default()).unwrap();
+        let expirations = register_batch("role3");
+        assert!(expirations.iter().all(|t| *t == start + Duration::from_secs(1000)));
+    }
+
+    #[test]
+    fn test_approle_default_secret_id_ttl() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        let (mut inner, storage) = new_test_inner("test_approle_default_secret_id_ttl");
+        inner.clock = Arc::new(MockClock::new(start));
+        inner.config =
+            RwLock::new(AppRoleConfig { default_secret_id_ttl: Duration::from_secs(3600), ..Default::default() });
+
+        let register = |secret_id: &str, entry: &mut SecretIdStorageEntry| {
+            register_test_secret_id(&inner, storage.as_ref(), "role1", secret_id, SecretIdScope::Global, entry);
+        };
+        let role_name_hmac = create_hmac("testhmackey", "role1").unwrap();
+
+        // A registration without a TTL gets the default one, and is recorded with it
+        let mut entry = SecretIdStorageEntry::default();
+        register("secret1", &mut entry);
+        assert_eq!(entry.secret_id_ttl, LeaseTtl::from_secs(3600));
+        assert_eq!(entry.expiration_time, start + Duration::from_secs(3600));
+        let remaining = inner
+            .secret_id_remaining_ttl(
+                storage.as_ref(),
+                SecretIdScope::Global,
+                &role_name_hmac,
+                &create_hmac("testhmackey", "secret1").unwrap(),
+            )
+            .unwrap();
+        assert_eq!(remaining, Some(Duration::from_secs(3600)));
+
+        // An explicit TTL wins over the default
+        let mut entry = SecretIdStorageEntry { secret_id_ttl: LeaseTtl::from_secs(60), ..Default::default() };
+        register("secret2", &mut entry);
+        assert_eq!(entry.expiration_time, start + Duration::from_secs(60));
+
+        // A role opting into no expiry keeps the secret_id from expiring
+        let mut entry = SecretIdStorageEntry { no_expiry: true, ..Default::default() };
+        register("secret3", &mut entry);
+        assert!(entry.secret_id_ttl.is_zero());
+        let remaining = inner
+            .secret_id_remaining_ttl(
+                storage.as_ref(),
+                SecretIdScope::Global,
+                &role_name_hmac,
+                &create_hmac("testhmackey", "secret3").unwrap(),
+            )
+            .unwrap();
+        assert_eq!(remaining, None);
+
+        // Without a default, secret_ids without a TTL never expire as before
+        inner.set_config(AppRoleConfig::default()).unwrap();
+        let mut entry = SecretIdStorageEntry::default();
+        register("secret4", &mut entry);
+        assert!(entry.secret_id_ttl.is_zero());
+        assert_eq!(inner.derive_secret_id_ttl(LeaseTtl::ZERO), LeaseTtl::ZERO);
+    }
+
+    #[cfg(feature = "fips")]
+    #[test]
+    fn test_approle_hmac_fips() {
+        assert!(create_hmac_with(MessageDigest::sha256(), "testhmackey", "role1").is_ok());
+        assert!(create_hmac_with(MessageDigest::sha512(), "testhmackey", "role1").is_ok());
+        assert_eq!(
+            create_hmac_with(MessageDigest::sha1(), "testhmackey", "role1").unwrap_err(),
+            RvError::ErrAlgorithmNotPermitted("SHA1".to_string())
+        );
+
+        let config = crate::utils::salt::Config { hmac_type: MessageDigest::sha1(), ..Default::default() };
+        assert!(matches!(Salt::new(None, Some(&config)), Err(RvError::ErrAlgorithmNotPermitted(_))));
+    }
+
+    #[test]
+    fn test_approle_derive_hmac_key() {
+        let role_name_key = derive_hmac_key(b"master-secret", ROLE_NAME_HMAC_CONTEXT).unwrap();
+        let secret_id_key = derive_hmac_key(b"master-secret", SECRET_ID_HMAC_CONTEXT).unwrap();
+        assert_eq!(role_name_key, "b3abf0623907e01fab3ac792268f5240f3937dc312397df5d362ab3f0b2afcc8");
+        assert_eq!(secret_id_key, "3169ca48cbaada85ee67d8f4fd8ea35784aa6b54928710e3adaf0579115cdf56");
+
+        // The derivation is deterministic
+        assert_eq!(derive_hmac_key(b"master-secret", ROLE_NAME_HMAC_CONTEXT).unwrap(), role_name_key);
+
+        // Different contexts or masters yield different keys, and so different hmacs
+        assert_ne!(role_name_key, secret_id_key);
+        assert_ne!(derive_hmac_key(b"other-secret", ROLE_NAME_HMAC_CONTEXT).unwrap(), role_name_key);
+        assert_ne!(create_hmac(&role_name_key, "role1").unwrap(), create_hmac(&secret_id_key, "role1").unwrap());
+
+        assert!(derive_hmac_key(b"", ROLE_NAME_HMAC_CONTEXT).is_err());
+        assert!(derive_hmac_key(b"master-secret", "").is_err());
+    }
+
+    #[test]
+    fn test_approle_salt_not_initialized() {
+        let (_root_token, core) = test_rusty_vault_init("test_approle_salt_not_initialized");
+        let c = core.read().unwrap();
+
+        let storage: Arc<dyn Storage> = c.get_system_view().unwrap();
+        let inner = AppRoleBackendInner::new(Arc::clone(&core));
+
+        let err = inner.get_secret_id_accessor_entry(storage.as_ref(), "accessor1", SecretIdScope::Global).unwrap_err();
+        assert_eq!(err, RvError::ErrModuleNotInitialized("approle"));
+        assert_eq!(err.response_status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let mut secret_entry = SecretIdStorageEntry::default();
+        let err =
+            inner.create_secret_id_accessor_entry(storage.as_ref(), &mut secret_entry, "hmac1", SecretIdScope::Global);
+        assert_eq!(err.unwrap_err(), RvError::ErrModuleNotInitialized("approle"));
+
+        let err = inner.delete_secret_id_accessor_entry(storage.as_ref(), "accessor1", SecretIdScope::Global);
+        assert_eq!(err.unwrap_err(), RvError::ErrModuleNotInitialized("approle"));
+
+        // Input errors are still reported as such before the salt is needed
+        let err = inner.get_secret_id_accessor_entry(storage.as_ref(), "", SecretIdScope::Global).unwrap_err();
+        assert!(matches!(err, RvError::ErrResponse(_)));
+    }
+
+    #[test]
+    fn test_secret_id_entries_debug_redacted() {
+        let accessor = "5c8b1c6e-3e0a-4d4e-9b3b-0f5a9a1f6d21";
+        let hmac = "a7c3e1f0b2d4c6e8a7c3e1f0b2d4c6e8a7c3e1f0b2d4c6e8a7c3e1f0b2d4c6e8";
+
+        let entry = SecretIdStorageEntry {
+            secret_id_accessor: accessor.to_string(),
+            secret_id_num_uses: 3,
+            seen_nonces: [("nonce-1234".to_string(), SystemTime::UNIX_EPOCH)].into(),
+            role_id_hmac: hmac.to_string(),
+            ..Default::default()
+        };
+        let debug = format!("{:?}", entry);
+        assert!(!debug.contains(accessor));
+        assert!(!debug.contains("nonce-1234"));
+        assert!(!debug.contains(hmac));
+        assert!(debug.contains("secret_id_num_uses: 3"));
+        let debug_full = entry.debug_full();
+        assert!(debug_full.contains(accessor) && debug_full.contains("nonce-1234") && debug_full.contains(hmac));
+
+        let entry = SecretIdAccessorStorageEntry { secret_id_hmac: hmac.to_string() };
+        let debug = format!("{:?}", entry);
+        assert!(!debug.contains(hmac));
+        assert!(entry.debug_full().contains(hmac));
+    }
+
+    #[test]
+    fn test_approle_role_name_hmac_cache() {
+        let (_root_token, core) = test_rusty_vault_init("test_approle_role_name_hmac_cache");
+        let inner = AppRoleBackendInner::new(Arc::clone(&core));
+        inner.set_role_name_hmac_cache_size(2).unwrap();
+
+        let hmac = inner.role_name_hmac("key1", "role1").unwrap();
+        assert_eq!(hmac, create_hmac("key1", "role1").unwrap());
+        assert_eq!(inner.role_name_hmac("key1", "role1").unwrap(), hmac);
+        assert_eq!(inner.role_name_hmac_cache.len(), 1);
+
+        // A rotated hmac_key must produce a fresh HMAC rather than the cached one
+        let rotated = inner.role_name_hmac("key2", "role1").unwrap();
+        assert_ne!(rotated, hmac);
+        assert_eq!(rotated, create_hmac("key2", "role1").unwrap());
+
+        // The cache stays bounded by its configured size
+        inner.role_name_hmac("key1", "role2").unwrap();
+        assert!(inner.role_name_hmac_cache.len() <= 2);
+        assert!(inner.role_name_hmac("", "role1").is_err());
+        assert!(inner.role_name_hmac_cache.len() <= 2);
+        assert_eq!(inner.role_name_hmac("key1", "role2").unwrap(), create_hmac("key1", "role2").unwrap());
+
+        inner.set_role_name_hmac_cache_size(1).unwrap();
+        assert!(inner.role_name_hmac_cache.len() <= 1);
+    }
+
+    #[test]
+    fn test_approle_role_name_hmac_normalized() {
+        let (_root_token, core) = test_rusty_vault_init("test_approle_role_name_hmac_normalized");
+        let inner = AppRoleBackendInner::new(Arc::clone(&core));
+
+        // The composed and decomposed spellings hash the same, whichever comes first
+        let decomposed = inner.role_name_hmac("key1", "cafe\u{301}").unwrap();
+        let composed = inner.role_name_hmac("key1", "caf\u{e9}").unwrap();
+        assert_eq!(composed, decomposed);
+        assert_eq!(composed, create_hmac("key1", "caf\u{e9}").unwrap());
+        assert_eq!(inner.role_name_hmac_cache.len(), 1);
+
+        assert!(inner.role_name_hmac("key1", "   ").is_err());
+        assert!(inner.role_name_hmac("key1", "role\u{0}").is_err());
+    }
+
+    #[test]
+    fn test_approle_secret_id_clock_skew() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = Arc::new(MockClock::new(start));
+
+        let (mut inner, storage) = new_test_inner("test_approle_secret_id_clock_skew");
+        inner.clock = clock.clone();
+
+        let mut entry = SecretIdStorageEntry { secret_id_ttl: LeaseTtl::from_secs(60), ..Default::default() };
+        register_test_secret_id(&inner, storage.as_ref(), "role1", "secret1", SecretIdScope::Global, &mut entry);
+
+        let role_name_hmac = create_hmac("testhmackey", "role1").unwrap();
+        let secret_id_hmac = create_hmac("testhmackey", "secret1").unwrap();
+        let probe = || {
+            inner.probe_secret_id(storage.as_ref(), "role1", "secret1", "testhmackey", SecretIdScope::Global).unwrap()
+        };
+        let remaining =
+            || inner.secret_id_remaining_ttl(storage.as_ref(), SecretIdScope::Global, &role_name_hmac, &secret_id_hmac);
+        let listed = || {
+            inner
+                .list_secret_id_accessors(storage.as_ref(), SecretIdScope::Global, &role_name_hmac, None)
+                .unwrap()
+                .len()
+        };
+
+        // The clock jumps 30s ahead of the one that set the expiration, which
+        // expires the secret_id without a tolerance
+        clock.set(start + Duration::from_secs(90));
+        assert!(probe().expired);
+        assert!(remaining().is_err());
+        assert_eq!(listed(), 0);
+
+        // Within the tolerance it is still valid, with no time left
+        let config = AppRoleConfig { clock_skew_tolerance: Duration::from_secs(60), ..Default::default() };
+        assert!(inner.set_config(config).is_ok());
+        assert!(!probe().expired);
+        assert_eq!(probe().remaining_ttl, Some(Duration::ZERO));
+        assert_eq!(remaining().unwrap(), Some(Duration::ZERO));
+        assert_eq!(listed(), 1);
+
+        // Past the tolerance it has expired
+        clock.set(start + Duration::from_secs(121));
+        assert!(probe().expired);
+        assert!(remaining().is_err());
+        assert_eq!(listed(), 0);
+
+        assert!(!inner.is_created_in_future(start, start - Duration::from_secs(60)).unwrap());
+        assert!(inner.is_created_in_future(start, start - Duration::from_secs(61)).unwrap());
+    }
+
+    #[test]
+    fn test_approle_list_secret_ids_by_metadata() {
+        let clock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+
+        let (mut inner, storage) = new_test_inner("test_approle_list_secret_ids_by_metadata");
+        inner.clock = clock.clone();
+
+        let mut accessors: HashMap<&str, String> = HashMap::new();
+        for (secret_id, env, ttl) in
+            [("secret1", "prod", 3600), ("secret2", "prod", 60), ("secret3", "dev", 3600), ("secret4", "", 0)]
+        {
+            let mut secret_entry =
+                SecretIdStorageEntry { secret_id_ttl: LeaseTtl::from_secs(ttl), ..Default::default() };
+            if !env.is_empty() {
+                secret_entry.metadata.insert("env".to_string(), env.to_string());
+            }
+            register_test_secret_id(
+                &inner,
+                storage.as_ref(),
+                "role1",
+                secret_id,
+                SecretIdScope::Global,
+                &mut secret_entry,
+            );
+            accessors.insert(secret_id, secret_entry.secret_id_accessor);
+        }
+
+        let role_name_hmac = create_hmac("testhmackey", "role1").unwrap();
+        let sorted = |mut list: Vec<String>| {
+            list.sort();
+            list
+        };
+        let expected = |secret_ids: &[&str]| sorted(secret_ids.iter().map(|s| accessors[s].clone()).collect());
+
+        let all =
+            inner.list_secret_id_accessors(storage.as_ref(), SecretIdScope::Global, &role_name_hmac, None).unwrap();
+        assert_eq!(sorted(all), expected(&["secret1", "secret2", "secret3", "secret4"]));
+
+        let prod = inner
+            .list_secret_ids_by_metadata(storage.as_ref(), SecretIdScope::Global, &role_name_hmac, "env", "prod")
+            .unwrap();
+        assert_eq!(sorted(prod), expected(&["secret1", "secret2"]));
+
+        let dev = inner
+            .list_secret_ids_by_metadata(storage.as_ref(), SecretIdScope::Global, &role_name_hmac, "env", "dev")
+            .unwrap();
+        assert_eq!(dev, expected(&["secret3"]));
+
+        let untagged = |metadata: &HashMap<String, String>| metadata.is_empty();
+        let list = inner
+            .list_secret_id_accessors(storage.as_ref(), SecretIdScope::Global, &role_name_hmac, Some(&untagged))
+            .unwrap();
+        assert_eq!(list, expected(&["secret4"]));
+
+        // Expired secret_ids are skipped
+        clock.advance(Duration::from_secs(61));
+        let prod = inner
+            .list_secret_ids_by_metadata(storage.as_ref(), SecretIdScope::Global, &role_name_hmac, "env", "prod")
+            .unwrap();
+        assert_eq!(prod, expected(&["secret1"]));
+    }
+
+    #[test]
+    fn test_approle_metadata_index() {
+        let (inner, storage) = new_test_inner("test_approle_metadata_index");
+        let scope = SecretIdScope::Global;
+
+        let mut accessors: HashMap<&str, String> = HashMap::new();
+        for (secret_id, env, team) in [("secret1", "prod", "a"), ("secret2", "prod", "b"), ("secret3", "dev", "a")] {
+            let mut secret_entry = SecretIdStorageEntry::default();
+            secret_entry.metadata.insert("env".to_string(), env.to_string());
+            secret_entry.metadata.insert("team".to_string(), team.to_string());
+            register_test_secret_id(&inner, storage.as_ref(), "role1", secret_id, scope, &mut secret_entry);
+            accessors.insert(secret_id, secret_entry.secret_id_accessor);
+        }
+
+        let role_name_hmac = create_hmac("testhmackey", "role1").unwrap();
+        let hmac = |secret_id: &str| create_hmac("testhmackey", secret_id).unwrap();
+        let pair = |key: &str, value: &str| HashMap::from([(key.to_string(), value.to_string())]);
+        let query = |key: &str, value: &str| {
+            let mut list =
+                inner.list_secret_ids_by_metadata(storage.as_ref(), scope, &role_name_hmac, key, value).unwrap();
+            list.sort();
+            list
+        };
+        let expected = |secret_ids: &[&str]| {
+            let mut list: Vec<String> = secret_ids.iter().map(|s| accessors[s].clone()).collect();
+            list.sort();
+            list
+        };
+        let marker_count = || {
+            let mut count = 0;
+            storage
+                .walk(&metadata_index_prefix(scope, &role_name_hmac), &mut |_: &str| {
+                    count += 1;
+                    Ok(())
+                })
+                .unwrap();
+            count
+        };
+
+        // Every pair of every secret_id is indexed
+        assert_eq!(marker_count(), 6);
+        assert_eq!(query("env", "prod"), expected(&["secret1", "secret2"]));
+        assert_eq!(query("env", "dev"), expected(&["secret3"]));
+        assert_eq!(query("team", "a"), expected(&["secret1", "secret3"]));
+        assert!(query("env", "staging").is_empty());
+        assert!(query("prod", "env").is_empty());
+
+        // Updating the metadata moves the secret_id between the sets
+        assert!(inner
+            .update_secret_id_metadata(
+                storage.as_ref(),
+                scope,
+                &role_name_hmac,
+                &hmac("secret2"),
+                pair("env", "dev"),
+                true
+            )
+            .unwrap()
+            .is_some());
+        assert_eq!(marker_count(), 5);
+        assert_eq!(query("env", "prod"), expected(&["secret1"]));
+        assert_eq!(query("env", "dev"), expected(&["secret2", "secret3"]));
+        assert!(query("team", "b").is_empty());
+
+        // Deleting a secret_id removes its markers
+        inner.delete_secret_id_storage_entry(storage.as_ref(), scope, &role_name_hmac, &hmac("secret3")).unwrap();
+        assert_eq!(marker_count(), 3);
+        assert_eq!(query("env", "dev"), expected(&["secret2"]));
+        assert_eq!(query("team", "a"), expected(&["secret1"]));
+        assert_eq!(inner.rebuild_metadata_index(storage.as_ref(), scope).unwrap(), 0);
+
+        // A stale marker never shows in the lookups, and the rebuild deletes it
+        // along with writing the markers that went missing
+        inner
+            .index_secret_id_metadata(storage.as_ref(), scope, &role_name_hmac, &hmac("secret2"), &pair("env", "prod"))
+            .unwrap();
+        inner
+            .unindex_secret_id_metadata(storage.as_ref(), scope, &role_name_hmac, &hmac("secret1"), &pair("team", "a"))
+            .unwrap();
+        assert_eq!(query("env", "prod"), expected(&["secret1"]));
+        assert!(query("team", "a").is_empty());
+
+        let report = inner.reconcile(storage.as_ref(), scope).unwrap();
+        assert_eq!(report.metadata_index_repairs, 2);
+        assert!(!report.is_consistent());
+        assert_eq!(marker_count(), 3);
+        assert_eq!(query("env", "prod"), expected(&["secret1"]));
+        assert_eq!(query("team", "a"), expected(&["secret1"]));
+        assert!(inner.reconcile(storage.as_ref(), scope).unwrap().is_consistent());
+
+        // Flushing the role drops its whole index
+        inner.flush_role_secrets(storage.as_ref(), "role1", "testhmackey", scope).unwrap();
+        assert_eq!(marker_count(), 0);
+        assert!(storage.list(METADATA_INDEX_PREFIX).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_approle_check_secret_id_nonce() {
+        let (_root_token, core) = test_rusty_vault_init("test_approle_check_secret_id_nonce");
+
+        let clock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+        let inner = AppRoleBackendInner { clock: clock.clone(), ..AppRoleBackendInner::new(Arc::clone(&core)) };
+
+        let mut entry = SecretIdStorageEntry { replay_nonce_ttl: Duration::from_secs(60), ..Default::default() };
+
+        assert!(inner.check_secret_id_nonce(&mut entry, "").is_err());
+        assert!(inner.check_secret_id_nonce(&mut entry, "nonce1").is_ok());
+
+        // Replaying the same nonce fails while a fresh one succeeds
+        assert!(inner.check_secret_id_nonce(&mut entry, "nonce1").is_err());
+        assert!(inner.check_secret_id_nonce(&mut entry, "nonce2").is_ok());
+        assert_eq!(entry.seen_nonces.len(), 2);
+
+        // The nonces are forgotten once they expire
+        clock.advance(Duration::from_secs(61));
+        assert!(inner.check_secret_id_nonce(&mut entry, "nonce1").is_ok());
+        assert_eq!(entry.seen_nonces.len(), 1);
+
+        // The set of live nonces is bounded
+        for i in 1..MAX_SEEN_NONCES {
+            assert!(inner.check_secret_id_nonce(&mut entry, &format!("bulk{}", i)).is_ok());
+        }
+        assert!(inner.check_secret_id_nonce(&mut entry, "one-too-many").is_err());
+        clock.advance(Duration::from_secs(61));
+        assert!(inner.check_secret_id_nonce(&mut entry, "one-too-many").is_ok());
+        assert_eq!(entry.seen_nonces.len(), 1);
+    }
+
+    #[test]
+    fn test_approle_secret_id_scope() {
+        assert_eq!(SecretIdScope::Global.prefix(), SECRET_ID_PREFIX);
+        assert_eq!(SecretIdScope::Global.accessor_prefix(), SECRET_ID_ACCESSOR_PREFIX);
+        assert_eq!(SecretIdScope::Local.prefix(), SECRET_ID_LOCAL_PREFIX);
+        assert_eq!(SecretIdScope::Local.accessor_prefix(), SECRET_ID_ACCESSOR_LOCAL_PREFIX);
+
+        // The prefix recorded in a role maps back to its scope
+        for scope in SecretIdScope::ALL {
+            assert_eq!(SecretIdScope::from_prefix(scope.prefix()).unwrap(), scope);
+        }
+        assert!(SecretIdScope::from_prefix("").is_err());
+        assert!(SecretIdScope::from_prefix(SECRET_ID_ACCESSOR_PREFIX).is_err());
+        assert!(SecretIdScope::from_prefix("secret_id").is_err());
+
+        // The entries of both scopes never share a prefix
+        let role_name_hmac = create_hmac("testhmackey", "role1").unwrap();
+        let secret_id_hmac = create_hmac("testhmackey", "secret1").unwrap();
+        let global = secret_id_entry_index(SecretIdScope::Global, &role_name_hmac, &secret_id_hmac).unwrap();
+        let local = secret_id_entry_index(SecretIdScope::Local, &role_name_hmac, &secret_id_hmac).unwrap();
+        assert!(global.starts_with(SECRET_ID_PREFIX));
+        assert!(local.starts_with(SECRET_ID_LOCAL_PREFIX));
+        assert_ne!(global, local);
+        assert!(accessor_entry_index(SecretIdScope::Local, "abc")
+            .unwrap()
+            .starts_with(SECRET_ID_ACCESSOR_LOCAL_PREFIX));
+    }
+
+    #[test]
+    fn test_approle_secret_id_entry_name_serde() {
+        // Entries written before the name existed still deserialize
+        let legacy = r#"{
+            "secret_id_accessor": "accessor1",
+            "secret_id_num_uses": 0,
+            "secret_id_ttl": 600,
+            "creation_time": "2024-01-01T00:00:00Z",
+            "expiration_time": "2024-01-01T00:10:00Z",
+            "last_updated_time": "2024-01-01T00:00:00Z",
+            "metadata": {},
+            "cidr_list": [],
+            "token_cidr_list": []
+        }"#;
+        let entry: SecretIdStorageEntry = serde_json::from_str(legacy).unwrap();
+        assert_eq!(entry.secret_id_accessor, "accessor1");
+        assert!(entry.name.is_none());
+
+        // An unnamed entry is written without the field
+        let value = serde_json::to_value(&entry).unwrap();
+        assert!(value.get("name").is_none());
+
+        let entry = SecretIdStorageEntry { name: Some("ci-runner".to_string()), ..entry };
+        let serialized = serde_json::to_vec(&entry).unwrap();
+        let entry: SecretIdStorageEntry = serde_json::from_slice(&serialized).unwrap();
+        assert_eq!(entry.name.as_deref(), Some("ci-runner"));
+
+        assert!(validate_secret_id_name("ci-runner").is_ok());
+        assert!(validate_secret_id_name("").is_err());
+        assert!(validate_secret_id_name(&"a".repeat(MAX_SECRET_ID_NAME_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn test_approle_register_secret_id_wal_recovery() {
+        let clock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+
+        let (mut inner, storage) = new_test_inner("test_approle_register_secret_id_wal_recovery");
+        inner.clock = clock.clone();
+
+        // A completed registration leaves nothing to recover
+        let mut secret_entry = SecretIdStorageEntry::default();
+        register_test_secret_id(&inner, storage.as_ref(), "role1", "secret1", SecretIdScope::Global, &mut secret_entry);
+        assert!(storage.list(WAL_PREFIX).unwrap().is_empty());
+
+        // Crash after the accessor was written, but before the secret_id entry
+        let mut orphan_entry = SecretIdStorageEntry::default();
+        let secret_id_hmac = create_hmac("testhmackey", "secret2").unwrap();
+        let wal = WalGuard::begin(storage.as_ref(), clock.now()).unwrap();
+        assert!(inner
+            .create_secret_id_accessor_entry(&wal, &mut orphan_entry, &secret_id_hmac, SecretIdScope::Global)
+            .is_ok());
+        drop(wal);
+
+        let accessor = orphan_entry.secret_id_accessor.as_str();
+        assert!(inner
+            .get_secret_id_accessor_entry(storage.as_ref(), accessor, SecretIdScope::Global)
+            .unwrap()
+            .is_some());
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(wal::recover(storage.as_ref(), clock.now(), Duration::from_secs(60)).unwrap(), 1);
+        assert!(inner
+            .get_secret_id_accessor_entry(storage.as_ref(), accessor, SecretIdScope::Global)
+            .unwrap()
+            .is_none());
+
+        // The completed registration is untouched
+        let accessor = secret_entry.secret_id_accessor.as_str();
+        assert!(inner
+            .get_secret_id_accessor_entry(storage.as_ref(), accessor, SecretIdScope::Global)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_approle_hmac_required_field() {
+        let (inner, storage) = new_test_inner("test_approle_hmac_required_field");
+
+        assert_eq!(hmac_required_field("key1", "role_name", "role1").unwrap(), create_hmac("key1", "role1").unwrap());
+        assert_eq!(
+            hmac_required_field("key1", "role_name", "").unwrap_err(),
+            RvError::ErrResponse("missing role_name".to_string())
+        );
+        assert!(hmac_required_field("", "role_name", "role1").is_err());
+
+        // create_hmac itself still accepts empty values
+        assert!(create_hmac("key1", "").is_ok());
+
+        assert_eq!(
+            inner.role_name_hmac("key1", "").unwrap_err(),
+            RvError::ErrResponse("missing role_name".to_string())
+        );
+
+        let mut secret_entry = SecretIdStorageEntry::default();
+        let err = inner.register_secret_id_entry(
+            storage.as_ref(),
+            "role1",
+            "",
+            "testhmackey",
+            SecretIdScope::Global,
+            0,
+            &mut secret_entry,
+        );
+        assert_eq!(err.unwrap_err(), RvError::ErrResponse("missing secret_id".to_string()));
+        let err = inner.register_secret_id_entry(
+            storage.as_ref(),
+            "",
+            "secret1",
+            "testhmackey",
+            SecretIdScope::Global,
+            0,
+            &mut secret_entry,
+        );
+        assert_eq!(err.unwrap_err(), RvError::ErrResponse("missing role_name".to_string()));
+    }
+
+    // A replica that has not seen any write yet, unless asked for a strong read.
+    struct StaleReadStorage(Arc<dyn Storage>);
+
+    impl Storage for StaleReadStorage {
+        fn list(&self, _prefix: &str) -> Result<Vec<String>, RvError> {
+            Ok(Vec::new())
+        }
+
+        fn get(&self, _key: &str) -> Result<Option<StorageEntry>, RvError> {
+            Ok(None)
+        }
+
+        fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+            self.0.put(entry)
+        }
+
+        fn delete(&self, key: &str) -> Result<(), RvError> {
+            self.0.delete(key)
+        }
+
+        fn get_consistent(&self, key: &str, consistency: ReadConsistency) -> Result<Option<StorageEntry>, RvError> {
+            match consistency {
+                ReadConsistency::Eventual => self.get(key),
+                ReadConsistency::Strong => self.0.get(key),
+            }
+        }
+    }
+
+    #[test]
+    fn test_approle_register_secret_id_strong_read() {
+        let (inner, storage) = new_test_inner("test_approle_register_secret_id_strong_read");
+
+        let register = |storage: &dyn Storage| {
+            let mut secret_entry = SecretIdStorageEntry::default();
+            inner
+                .register_secret_id_entry(
+                    storage,
+                    "role1",
+                    "secret1",
+                    "testhmackey",
+                    SecretIdScope::Global,
+                    0,
+                    &mut secret_entry,
+                )
+                .map(|()| secret_entry.secret_id_accessor)
+        };
+        let accessor = register(storage.as_ref()).unwrap();
+
+        // The stale replica misses the entry on eventual reads, but the check
+        // made under the write lock still sees it
+        let stale = StaleReadStorage(Arc::clone(&storage));
+        let role_name_hmac = create_hmac("testhmackey", "role1").unwrap();
+        let secret_id_hmac = create_hmac("testhmackey", "secret1").unwrap();
+        let exists = |consistency| {
+            inner
+                .secret_id_storage_entry_exists(
+                    &stale,
+                    SecretIdScope::Global,
+                    &role_name_hmac,
+                    &secret_id_hmac,
+                    consistency,
+                )
+                .unwrap()
+        };
+        assert!(!exists(ReadConsistency::Eventual));
+        assert!(exists(ReadConsistency::Strong));
+
+        assert_eq!(register(&stale).unwrap_err(), RvError::ErrSecretIdAlreadyExists { accessor: Some(accessor) });
+    }
+
+    #[test]
+    fn test_approle_register_secret_id_race() {
+        let (inner, storage) = new_test_inner("test_approle_register_secret_id_race");
+        let inner = Arc::new(inner);
+
+        let barrier = Arc::new(std::sync::Barrier::new(8));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let inner = Arc::clone(&inner);
+                let storage = Arc::clone(&storage);
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || {
+                    let mut entry =
+                        SecretIdStorageEntry { secret_id_ttl: LeaseTtl::from_secs(600), ..Default::default() };
+                    barrier.wait();
+                    inner
+                        .register_secret_id_entry(
+                            storage.as_ref(),
+                            "role1",
+                            "secret1",
+                            "testhmackey",
+                            SecretIdScope::Global,
+                            0,
+                            &mut entry,
+                        )
+                        .map(|()| entry.secret_id_accessor)
+                })
+            })
+            .collect();
+        let results: Vec<_> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+
+        // Exactly one registration wins, the others are told its accessor
+        let winners: Vec<&String> = results.iter().filter_map(|ret| ret.as_ref().ok()).collect();
+        assert_eq!(winners.len(), 1);
+        let accessor = winners[0].clone();
+        for ret in results.iter().filter(|ret| ret.is_err()) {
+            assert_eq!(
+                ret.as_ref().unwrap_err(),
+                &RvError::ErrSecretIdAlreadyExists { accessor: Some(accessor.clone()) }
+            );
+        }
+        assert_eq!(storage.list(SECRET_ID_ACCESSOR_PREFIX).unwrap().len(), 1);
+    }
+
+    // A backend without atomic batches, deleting the keys of a batch one by
+    // one. The check runs on the state left by every single delete.
+    struct StepwiseDeleteStorage<'a> {
+        inner: Arc<dyn Storage>,
+        check: Box<dyn Fn(&dyn Storage) + Send + Sync + 'a>,
+        batches: AtomicUsize,
+    }
+
+    impl Storage for StepwiseDeleteStorage<'_> {
+        fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+            self.inner.list(prefix)
+        }
+
+        fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+            self.inner.get(key)
+        }
+
+        fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+            self.inner.put(entry)
+        }
+
+        fn delete(&self, key: &str) -> Result<(), RvError> {
+            self.inner.delete(key)?;
+            (self.check)(self.inner.as_ref());
+            Ok(())
+        }
+
+        fn delete_batch(&self, keys: &[String]) -> Result<(), RvError> {
+            self.batches.fetch_add(1, Ordering::SeqCst);
+            keys.iter().try_for_each(|key| self.delete(key))
+        }
+    }
+
+    #[test]
+    fn test_approle_flush_role_secrets_ordering() {
+        let (inner, storage) = new_test_inner("test_approle_flush_role_secrets_ordering");
+        let scope = SecretIdScope::Global;
+        let role_name_hmac = create_hmac("testhmackey", "role1").unwrap();
+        let secret_id_prefix = format!("{}{}/", SECRET_ID_PREFIX, role_name_hmac);
+
+        // Every secret_id still listed must be reachable through its accessor
+        let check = |storage: &dyn Storage| {
+            for secret_id_hmac in storage.list(&secret_id_prefix).unwrap() {
+                let entry = inner
+                    .get_secret_id_storage_entry(storage, scope, &role_name_hmac, &secret_id_hmac)
+                    .unwrap()
+                    .unwrap();
+                let (accessor_index, _) = inner.accessor_index(&entry.secret_id_accessor, scope).unwrap();
+                assert!(storage.exists(&accessor_index).unwrap(), "secret_id {} outlived its accessor", secret_id_hmac);
+            }
+        };
+
+        // The metadata index is deleted in one more batch
+        for (strategy, batches) in [(FlushStrategy::Sequential, 1), (FlushStrategy::Batched(4), 6)] {
+            for i in 0..20 {
+                let mut secret_entry = SecretIdStorageEntry::default();
+                secret_entry.metadata.insert("shard".to_string(), (i % 2).to_string());
+                register_test_secret_id(
+                    &inner,
+                    storage.as_ref(),
+                    "role1",
+                    &format!("secret{}", i),
+                    scope,
+                    &mut secret_entry,
+                );
+            }
+
+            let stepwise = StepwiseDeleteStorage {
+                inner: Arc::clone(&storage),
+                check: Box::new(check),
+                batches: AtomicUsize::new(0),
+            };
+            assert!(inner.flush_role_secrets_with(&stepwise, "role1", "testhmackey", scope, strategy).is_ok());
+            assert_eq!(stepwise.batches.load(Ordering::SeqCst), batches);
+
+            assert!(storage.list(&secret_id_prefix).unwrap().is_empty());
+            assert!(storage.list(SECRET_ID_ACCESSOR_PREFIX).unwrap().is_empty());
+            assert!(storage.list(&metadata_index_prefix(scope, &role_name_hmac)).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_approle_flush_roles_secrets() {
+        let (inner, storage) = new_test_inner("test_approle_flush_roles_secrets");
+        let scope = SecretIdScope::Global;
+
+        let register = |role_name: &str, count: usize| {
+            for i in 0..count {
+                let mut secret_entry = SecretIdStorageEntry::default();
+                register_test_secret_id(
+                    &inner,
+                    storage.as_ref(),
+                    role_name,
+                    &format!("{}-secret{}", role_name, i),
+                    scope,
+                    &mut secret_entry,
+                );
+            }
+        };
+        register("role1", 3);
+        register("role2", 2);
+
+        // The invalid role name fails on its own, the roles around it are flushed
+        let roles = ["role1", "bad\u{7}role", "role2"];
+        let report = inner.flush_roles_secrets(storage.as_ref(), &roles, "testhmackey", scope).unwrap();
+        assert_eq!(report.roles.len(), 3);
+        assert_eq!((report.roles[0].flushed, report.roles[0].error.is_none()), (3, true));
+        assert_eq!((report.roles[2].flushed, report.roles[2].error.is_none()), (2, true));
+        assert_eq!(report.flushed(), 5);
+        let failed = report.failed();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].role_name, roles[1]);
+        assert!(failed[0].error.as_ref().unwrap().contains("control characters"));
+
+        for role_name in ["role1", "role2"] {
+            let role_name_hmac = create_hmac("testhmackey", role_name).unwrap();
+            assert!(storage.list(&format!("{}{}/", SECRET_ID_PREFIX, role_name_hmac)).unwrap().is_empty());
+        }
+        assert!(storage.list(SECRET_ID_ACCESSOR_PREFIX).unwrap().is_empty());
+
+        // A storage error is not a problem of one role, it fails the call
+        register("role1", 1);
+        let faulty =
+            FaultInjectStorage::new(Arc::clone(&storage), 1, || RvError::ErrStorageTimeout("list".to_string()));
+        let err = inner.flush_roles_secrets(&faulty, &["role1", "role2"], "testhmackey", scope).unwrap_err();
+        assert_eq!(err, RvError::ErrStorageTimeout("list".to_string()));
+    }
+
+    #[test]
+    fn test_approle_secret_id_lock_poison_recovery() {
+        let (inner, storage) = new_test_inner("test_approle_secret_id_lock_poison_recovery");
+        let scope = SecretIdScope::Global;
+
+        let mut secret_entry = SecretIdStorageEntry::default();
+        register_test_secret_id(&inner, storage.as_ref(), "role1", "secret1", scope, &mut secret_entry);
+
+        // A thread panics while holding the lock of secret1
+        let secret_id_hmac = create_hmac("testhmackey", "secret1").unwrap();
+        thread::scope(|s| {
+            let poisoner = s.spawn(|| {
+                let _locked = inner.secret_id_locks.get_lock(&secret_id_hmac).write().unwrap();
+                panic!("panicking while holding a secret_id lock");
+            });
+            assert!(poisoner.join().is_err());
+        });
+        assert!(inner.secret_id_locks.get_lock(&secret_id_hmac).lock.is_poisoned());
+
+        // The operations on secret1 still go through
+        let status = inner.probe_secret_id(storage.as_ref(), "role1", "secret1", "testhmackey", scope).unwrap();
+        assert!(status.exists);
+        assert!(!inner.secret_id_locks.get_lock(&secret_id_hmac).lock.is_poisoned());
+        let mut secret_entry = SecretIdStorageEntry::default();
+        let err = inner
+            .register_secret_id_entry(storage.as_ref(), "role1", "secret1", "testhmackey", scope, 0, &mut secret_entry)
+            .unwrap_err();
+        assert!(matches!(err, RvError::ErrSecretIdAlreadyExists { .. }));
+        assert_eq!(inner.flush_role_secrets(storage.as_ref(), "role1", "testhmackey", scope).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_approle_entry_index() {
+        let role_name_hmac = create_hmac("testhmackey", "role1").unwrap();
+        let secret_id_hmac = create_hmac("testhmackey", "secret1").unwrap();
+        assert_eq!(
+            secret_id_entry_index(SecretIdScope::Global, &role_name_hmac, &secret_id_hmac).unwrap(),
+            format!("{}{}/{}", SECRET_ID_PREFIX, role_name_hmac, secret_id_hmac)
+        );
+        assert!(secret_id_entry_index(SecretIdScope::Global, "..", &secret_id_hmac).is_err());
+        assert!(secret_id_entry_index(SecretIdScope::Global, &role_name_hmac, "").is_err());
+        assert!(accessor_entry_index(SecretIdScope::Global, "abc").is_ok());
+        assert!(accessor_entry_index(SecretIdScope::Global, "abc\u{0}").is_err());
+    }
+
+    #[test]
+    fn test_approle_accessor_legacy_prefix() {
+        let (inner, storage) = new_test_inner("test_approle_accessor_legacy_prefix");
+
+        // Seed an accessor entry under an old layout
+        let accessor = "5c8b1c6e-3e0a-4d4e-9b3b-0f5a9a1f6d21";
+        let salt_id = inner.salt.read().unwrap().as_ref().unwrap().salt_id(accessor).unwrap();
+        let legacy_key = format!("accessor_v0/{}", salt_id);
+        let legacy_entry =
+            StorageEntry::new(&legacy_key, &SecretIdAccessorStorageEntry { secret_id_hmac: "hmac1".to_string() })
+                .unwrap();
+        assert!(storage.put(&legacy_entry).is_ok());
+
+        // Not found without the shim
+        assert!(inner
+            .get_secret_id_accessor_entry(storage.as_ref(), accessor, SecretIdScope::Global)
+            .unwrap()
+            .is_none());
+
+        // Read transparently through the shim, and moved to the current layout
+        let shim = LegacyPrefixShim::new(Arc::clone(&storage)).with_mapping(SECRET_ID_ACCESSOR_PREFIX, "accessor_v0/");
+        let entry = inner.get_secret_id_accessor_entry(&shim, accessor, SecretIdScope::Global).unwrap().unwrap();
+        assert_eq!(entry.secret_id_hmac, "hmac1");
+
+        assert!(storage.get(&legacy_key).unwrap().is_none());
+        let entry =
+            inner.get_secret_id_accessor_entry(storage.as_ref(), accessor, SecretIdScope::Global).unwrap().unwrap();
+        assert_eq!(entry.secret_id_hmac, "hmac1");
+    }
+
+    #[test]
+    fn test_approle_config_max_secret_id_ttl() {
+        let (_root_token, core) = test_rusty_vault_init("test_approle_config_max_secret_id_ttl");
+        let c = core.read().unwrap();
+        let storage: Arc<dyn Storage> = c.get_system_view().unwrap();
+
+        let inner = AppRoleBackendInner::new(Arc::clone(&core));
+        let default_max = LeaseTtl::from(AppRoleConfig::default().max_secret_id_ttl);
+        assert_eq!(inner.derive_secret_id_ttl(LeaseTtl::from_secs(3600)), LeaseTtl::from_secs(3600));
+        assert_eq!(inner.derive_secret_id_ttl(LeaseTtl::from_secs(default_max.as_secs() + 1)), default_max);
+
+        // A config that was never written loads as the defaults
+        assert_eq!(AppRoleConfig::load(storage.as_ref()).unwrap(), AppRoleConfig::default());
+
+        let config = AppRoleConfig { max_secret_id_ttl: Duration::from_secs(600), ..Default::default() };
+        assert!(config.save(storage.as_ref()).is_ok());
+        assert!(inner.set_config(AppRoleConfig::load(storage.as_ref()).unwrap()).is_ok());
+
+        assert_eq!(inner.derive_secret_id_ttl(LeaseTtl::from_secs(300)), LeaseTtl::from_secs(300));
+        assert_eq!(inner.derive_secret_id_ttl(LeaseTtl::from_human("1h").unwrap()), LeaseTtl::from_secs(600));
+
+        let mut warnings = Vec::new();
+        inner.warn_secret_id_ttl(LeaseTtl::from_secs(3600), &mut warnings);
+        assert_eq!(
+            warnings,
+            vec!["secret_id_ttl of 3600s is greater than the max_secret_id_ttl of the approle config, it is capped to 600s"]
+        );
+    }
+
+    #[test]
+    fn test_approle_secret_id_entry_serde_round_trip() {
+        // 0000-01-01T00:00:00Z to 9999-12-31T23:59:59Z, the range RFC 3339 can represent
+        const MIN_SECS: i64 = -62_167_219_200;
+        const MAX_SECS: i64 = 253_402_300_799;
+
+        let mut rng = StdRng::seed_from_u64(0x5ec2e7);
+
+        let gen_time = |rng: &mut StdRng| -> SystemTime {
+            let secs = match rng.gen_range(0..4) {
+                0 => MIN_SECS,
+                1 => MAX_SECS,
+                _ => rng.gen_range(MIN_SECS..=MAX_SECS),
+            };
+            let nanos = Duration::from_nanos(rng.gen_range(0..1_000_000_000));
+            if secs >= 0 {
+                SystemTime::UNIX_EPOCH + Duration::from_secs(secs as u64) + nanos
+            } else {
+                SystemTime::UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()) + nanos
+            }
+        };
+        let gen_duration = |rng: &mut StdRng| -> Duration {
+            // Durations are stored in whole seconds
+            match rng.gen_range(0..4) {
+                0 => Duration::from_secs(u64::MAX),
+                1 => Duration::from_secs(i64::MAX as u64 + 1),
+                _ => Duration::from_secs(rng.gen()),
+            }
+        };
+        let gen_string = |rng: &mut StdRng| -> String { (0..rng.gen_range(0..8)).map(|_| rng.gen::<char>()).collect() };
+
+        for _ in 0..256 {
+            let entry = SecretIdStorageEntry {
+                secret_id_accessor: gen_string(&mut rng),
+                name: if rng.gen() { Some(gen_string(&mut rng)) } else { None },
+                secret_id_num_uses: rng.gen(),
+                secret_id_ttl: gen_duration(&mut rng).into(),
+                creation_time: gen_time(&mut rng),
+                expiration_time: gen_time(&mut rng),
+                last_updated_time: gen_time(&mut rng),
+                metadata: (0..rng.gen_range(0..4)).map(|_| (gen_string(&mut rng), gen_string(&mut rng))).collect(),
+                cidr_list: (0..rng.gen_range(0..4)).map(|_| gen_string(&mut rng)).collect(),
+                token_cidr_list: (0..rng.gen_range(0..4)).map(|_| gen_string(&mut rng)).collect(),
+                replay_nonce_ttl: gen_duration(&mut rng),
+                seen_nonces: (0..rng.gen_range(0..4)).map(|_| (gen_string(&mut rng), gen_time(&mut rng))).collect(),
+                last_login_time: if rng.gen() { Some(gen_time(&mut rng)) } else { None },
+                role_id_hmac: gen_string(&mut rng),
+                no_expiry: false,
+            };
+
+            let storage_entry = StorageEntry::new("secret_id/role/secret", &entry).unwrap();
+            let decoded: SecretIdStorageEntry = serde_json::from_slice(&storage_entry.value).unwrap();
+            assert!(decoded == entry, "round trip failed for {}", entry.debug_full());
+        }
+    }
+
+    #[test]
+    fn test_approle_secret_id_lock_ordering() {
+        let (inner, storage) = new_test_inner("test_approle_secret_id_lock_ordering");
+        let inner = Arc::new(inner);
+        let role_name_hmac = create_hmac("testhmackey", "role1").unwrap();
+
+        // Every thread works on the same few secret_ids, so that registrations,
+        // updates and deletions keep contending for the same locks. An ordering
+        // violation panics the thread, a deadlock trips the deadline.
+        let handles: Vec<_> = (0..8)
+            .map(|t| {
+                let inner = Arc::clone(&inner);
+                let storage = Arc::clone(&storage);
+                let role_name_hmac = role_name_hmac.clone();
+                thread::spawn(move || {
+                    let storage = storage.as_ref();
+                    for i in 0..100 {
+                        let secret_id = format!("secret{}", i % 4);
+                        let secret_id_hmac = create_hmac("testhmackey", &secret_id).unwrap();
+                        match (t + i) % 5 {
+                            0 => {
+                                let mut entry = SecretIdStorageEntry {
+                                    secret_id_ttl: LeaseTtl::from_secs(600),
+                                    ..Default::default()
+                                };
+                                let ret = inner.register_secret_id_entry(
+                                    storage,
+                                    "role1",
+                                    &secret_id,
+                                    "testhmackey",
+                                    SecretIdScope::Global,
+                                    0,
+                                    &mut entry,
+                                );
+                                if ret.is_ok() {
+                                    let _ = inner.resolve_accessor(
+                                        storage,
+                                        &entry.secret_id_accessor,
+                                        SecretIdScope::Global,
+                                        &role_name_hmac,
+                                    );
+                                }
+                            }
+                            1 => {
+                                let _ = inner.update_secret_id_metadata(
+                                    storage,
+                                    SecretIdScope::Global,
+                                    &role_name_hmac,
+                                    &secret_id_hmac,
+                                    HashMap::from([("thread".to_string(), t.to_string())]),
+                                    false,
+                                );
+                            }
+                            2 => {
+                                let _ = inner.renew_secret_id(
+                                    storage,
+                                    SecretIdScope::Global,
+                                    &role_name_hmac,
+                                    &secret_id_hmac,
+                                    Duration::from_secs(30),
+                                    LeaseTtl::ZERO,
+                                );
+                            }
+                            3 => {
+                                let _ = inner.secret_id_remaining_ttl(
+                                    storage,
+                                    SecretIdScope::Global,
+                                    &role_name_hmac,
+                                    &secret_id_hmac,
+                                );
+                            }
+                            _ => {
+                                let _ =
+                                    inner.flush_role_secrets(storage, "role1", "testhmackey", SecretIdScope::Global);
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        let deadline = Instant::now() + Duration::from_secs(60);
+        for handle in handles {
+            while !handle.is_finished() {
+                assert!(Instant::now() < deadline, "secret_id operations deadlocked");
+                thread::sleep(Duration::from_millis(10));
+            }
+            assert!(handle.join().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_approle_secret_id_cidr_limits() {
+        let (inner, storage) = new_test_inner("test_approle_secret_id_cidr_limits");
+        assert!(inner.set_config(AppRoleConfig { max_secret_id_cidr_blocks: 4, ..Default::default() }).is_ok());
+
+        let register = |secret_id: &str, cidr_list: &[&str], token_cidr_list: &[&str]| {
+            let mut entry = SecretIdStorageEntry {
+                cidr_list: cidr_list.iter().map(|s| s.to_string()).collect(),
+                token_cidr_list: token_cidr_list.iter().map(|s| s.to_string()).collect(),
+                ..Default::default()
+            };
+            inner
+                .register_secret_id_entry(
+                    storage.as_ref(),
+                    "role1",
+                    secret_id,
+                    "testhmackey",
+                    SecretIdScope::Global,
+                    0,
+                    &mut entry,
+                )
+                .map(|_| entry)
+        };
+
+        // Blocks are rewritten to their network address and the duplicates dropped
+        let entry = register("secret1", &["10.1.2.3/8", "10.0.0.0/8", "192.168.1.7", "192.168.1.7/32"], &[]).unwrap();
+        assert_eq!(entry.cidr_list, vec!["10.0.0.0/8", "192.168.1.7/32"]);
+        let stored = inner
+            .get_secret_id_storage_entry(
+                storage.as_ref(),
+                SecretIdScope::Global,
+                &create_hmac("testhmackey", "role1").unwrap(),
+                &create_hmac("testhmackey", "secret1").unwrap(),
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.cidr_list, entry.cidr_list);
+
+        // The cap applies to the distinct blocks, to each list separately
+        let five = ["10.0.0.0/24", "10.0.1.0/24", "10.0.2.0/24", "10.0.3.0/24", "10.0.4.0/24"];
+        let entry = register(
+            "secret2",
+            &five[..4],
+            &["10.0.0.1/24", "10.0.0.0/24", "10.0.0.2/24", "10.0.0.3/24", "10.0.0.4/24"],
+        );
+        assert_eq!(entry.unwrap().token_cidr_list, vec!["10.0.0.0/24"]);
+
+        let err = register("secret3", &five, &[]).unwrap_err();
+        assert_eq!(err, RvError::ErrResponse("cidr_list cannot contain more than 4 CIDR blocks".to_string()));
+        let err = register("secret3", &[], &five).unwrap_err();
+        assert_eq!(err, RvError::ErrResponse("token_cidr_list cannot contain more than 4 CIDR blocks".to_string()));
+
+        assert!(register("secret3", &["10.0.0.0/33"], &[]).is_err());
+
+        // Zero lifts the cap
+        assert!(inner.set_config(AppRoleConfig { max_secret_id_cidr_blocks: 0, ..Default::default() }).is_ok());
+        assert_eq!(register("secret3", &five, &[]).unwrap().cidr_list, five);
+    }
+
+    // Forwards to the inner storage, tracking the most puts ever in flight
+    struct ConcurrencyProbe {
+        inner: Arc<dyn Storage>,
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+    }
+
+    impl Storage for ConcurrencyProbe {
+        fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+            self.inner.list(prefix)
+        }
+
+        fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+            self.inner.get(key)
+        }
+
+        fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+            let current = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(current, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(1));
+            let ret = self.inner.put(entry);
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            ret
+        }
+
+        fn delete(&self, key: &str) -> Result<(), RvError> {
+            self.inner.delete(key)
+        }
+    }
+
+    #[test]
+    fn test_approle_register_secret_id_concurrency_limit() {
+        let (inner, system_view) = new_test_inner("test_approle_register_secret_id_concurrency_limit");
+        let inner = Arc::new(inner);
+        let storage = Arc::new(ConcurrencyProbe {
+            inner: Arc::clone(&system_view),
+            in_flight: AtomicUsize::new(0),
+            max_in_flight: AtomicUsize::new(0),
+        });
+        let config = RegistrationLimiterConfig { max_concurrent: 1, ..Default::default() };
+        assert!(inner.registration_limiter.set_config(config).is_ok());
+
+        let register = |inner: &AppRoleBackendInner, storage: &dyn Storage, secret_id: &str| {
+            let mut entry = SecretIdStorageEntry::default();
+            inner.register_secret_id_entry(
+                storage,
+                "role1",
+                secret_id,
+                "testhmackey",
+                SecretIdScope::Global,
+                0,
+                &mut entry,
+            )
+        };
+
+        // The registrations of the role queue up, their writes never overlap
+        let handles: Vec<_> = (0..6)
+            .map(|i| {
+                let (inner, storage) = (Arc::clone(&inner), Arc::clone(&storage));
+                thread::spawn(move || register(&inner, storage.as_ref(), &format!("secret{}", i)))
+            })
+            .collect();
+        for handle in handles {
+            assert!(handle.join().unwrap().is_ok());
+        }
+        assert_eq!(storage.max_in_flight.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            storage
+                .inner
+                .list(&format!("{}{}/", SECRET_ID_PREFIX, create_hmac("testhmackey", "role1").unwrap()))
+                .unwrap()
+                .len(),
+            6
+        );
+
+        // Rejecting fails the registrations over the limit with a retryable error
+        let config = RegistrationLimiterConfig { max_concurrent: 1, on_limit: OnLimit::Reject, ..Default::default() };
+        assert!(inner.registration_limiter.set_config(config).is_ok());
+        let permit = inner.registration_limiter.acquire("role1").unwrap();
+        let err = register(&inner, storage.as_ref(), "secret6").unwrap_err();
+        assert_eq!(err, RvError::ErrRoleRegistrationBusy);
+        assert_eq!(err.response_status(), StatusCode::TOO_MANY_REQUESTS);
+
+        drop(permit);
+        assert!(register(&inner, storage.as_ref(), "secret6").is_ok());
+    }
+}
@@ -223,9 +223,9 @@ impl PolicyStore {
             return Err(RvError::ErrBarrierSealed);
         }
 
-        let acl_view = core.system_view.as_ref().unwrap().new_sub_view(POLICY_ACL_SUB_PATH);
-        let rgp_view = core.system_view.as_ref().unwrap().new_sub_view(POLICY_RGP_SUB_PATH);
-        let egp_view = core.system_view.as_ref().unwrap().new_sub_view(POLICY_EGP_SUB_PATH);
+        let acl_view = core.system_view.as_ref().unwrap().sub_view(POLICY_ACL_SUB_PATH);
+        let rgp_view = core.system_view.as_ref().unwrap().sub_view(POLICY_RGP_SUB_PATH);
+        let egp_view = core.system_view.as_ref().unwrap().sub_view(POLICY_EGP_SUB_PATH);
 
         let keys = acl_view.get_keys()?;
 
@@ -0,0 +1,255 @@
+//! An asynchronous counterpart of the `Storage` trait.
+//!
+//! Network backed stores can implement `AsyncStorage` to avoid blocking a thread per request. Two
+//! adapters bridge the sync and async worlds, so that both kinds of implementations can be used
+//! wherever the other trait is expected:
+//!
+//! - `BlockingStorageAdapter` runs an `AsyncStorage` on a tokio runtime and exposes it as a
+//!   `Storage`, which keeps the existing sync call sites working.
+//! - `SyncToAsync` exposes a `Storage` as an `AsyncStorage`, running every call on tokio's
+//!   blocking thread pool.
+
+use std::{future::Future, sync::Arc};
+
+use async_trait::async_trait;
+use tokio::runtime::{Handle, RuntimeFlavor};
+
+use super::{physical::inmem::InmemBackend, Backend, BackendEntry, Storage, StorageEntry};
+use crate::{errors::RvError, rv_error_string};
+
+#[async_trait]
+pub trait AsyncStorage: Send + Sync {
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, RvError>;
+    async fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError>;
+    async fn put(&self, entry: &StorageEntry) -> Result<(), RvError>;
+    async fn delete(&self, key: &str) -> Result<(), RvError>;
+
+    async fn exists(&self, key: &str) -> Result<bool, RvError> {
+        self.get(key).await.map(|entry| entry.is_some())
+    }
+}
+
+#[async_trait]
+impl<T: AsyncStorage + ?Sized> AsyncStorage for Arc<T> {
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+        self.as_ref().list(prefix).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+        self.as_ref().get(key).await
+    }
+
+    async fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+        self.as_ref().put(entry).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), RvError> {
+        self.as_ref().delete(key).await
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, RvError> {
+        self.as_ref().exists(key).await
+    }
+}
+
+/// Exposes an `AsyncStorage` through the sync `Storage` trait by blocking on a runtime handle.
+///
+/// When called from a worker of a multi-threaded runtime, the worker is handed over with
+/// `block_in_place` first. Calling it from within a current-thread runtime is not supported and
+/// returns an error instead of deadlocking.
+pub struct BlockingStorageAdapter<S> {
+    inner: S,
+    handle: Handle,
+}
+
+impl<S: AsyncStorage> BlockingStorageAdapter<S> {
+    pub fn new(inner: S, handle: Handle) -> Self {
+        Self { inner, handle }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn block_on<F: Future>(&self, future: F) -> Result<F::Output, RvError> {
+        match Handle::try_current() {
+            Ok(current) if current.runtime_flavor() == RuntimeFlavor::CurrentThread => {
+                Err(rv_error_string!("blocking storage adapter cannot be used within a current-thread runtime"))
+            }
+            Ok(_) => Ok(tokio::task::block_in_place(|| self.handle.block_on(future))),
+            Err(_) => Ok(self.handle.block_on(future)),
+        }
+    }
+}
+
+impl<S: AsyncStorage> Storage for BlockingStorageAdapter<S> {
+    fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+        self.block_on(self.inner.list(prefix))?
+    }
+
+    fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+        self.block_on(self.inner.get(key))?
+    }
+
+    fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+        self.block_on(self.inner.put(entry))?
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RvError> {
+        self.block_on(self.inner.delete(key))?
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, RvError> {
+        self.block_on(self.inner.exists(key))?
+    }
+}
+
+/// Exposes a sync `Storage` through the `AsyncStorage` trait. Every call runs on tokio's blocking
+/// thread pool, so a slow storage never stalls the async workers.
+pub struct SyncToAsync<S: ?Sized> {
+    inner: Arc<S>,
+}
+
+impl<S: Storage + ?Sized + 'static> SyncToAsync<S> {
+    pub fn new(inner: Arc<S>) -> Self {
+        Self { inner }
+    }
+
+    pub fn inner(&self) -> &Arc<S> {
+        &self.inner
+    }
+
+    async fn spawn<T, F>(&self, f: F) -> Result<T, RvError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&S) -> Result<T, RvError> + Send + 'static,
+    {
+        let inner = Arc::clone(&self.inner);
+        tokio::task::spawn_blocking(move || f(inner.as_ref()))
+            .await
+            .map_err(|err| rv_error_string!(format!("storage task failed, err: {}", err)))?
+    }
+}
+
+#[async_trait]
+impl<S: Storage + ?Sized + 'static> AsyncStorage for SyncToAsync<S> {
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+        let prefix = prefix.to_string();
+        self.spawn(move |s| s.list(&prefix)).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+        let key = key.to_string();
+        self.spawn(move |s| s.get(&key)).await
+    }
+
+    async fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+        let entry = entry.clone();
+        self.spawn(move |s| s.put(&entry)).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), RvError> {
+        let key = key.to_string();
+        self.spawn(move |s| s.delete(&key)).await
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, RvError> {
+        let key = key.to_string();
+        self.spawn(move |s| s.exists(&key)).await
+    }
+}
+
+/// An `AsyncStorage` that keeps everything in memory, mostly useful for tests.
+#[derive(Debug, Default)]
+pub struct AsyncInmemStorage {
+    entries: InmemBackend,
+}
+
+impl AsyncInmemStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl AsyncStorage for AsyncInmemStorage {
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+        self.entries.list(prefix)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+        Ok(self.entries.get(key)?.map(|e| StorageEntry { key: e.key, value: e.value }))
+    }
+
+    async fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+        self.entries.put(&BackendEntry { key: entry.key.clone(), value: entry.value.clone() })
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), RvError> {
+        self.entries.delete(key)
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, RvError> {
+        self.entries.exists(key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn check_async_storage(storage: &dyn AsyncStorage) {
+        assert!(storage.list("").await.unwrap().is_empty());
+        assert!(storage.get("bar").await.unwrap().is_none());
+        assert!(!storage.exists("bar").await.unwrap());
+
+        let entry = StorageEntry { key: "bar/foo".to_string(), value: "test".as_bytes().to_vec() };
+        assert!(storage.put(&entry).await.is_ok());
+        assert_eq!(storage.get("bar/foo").await.unwrap().unwrap(), entry);
+        assert!(storage.exists("bar/foo").await.unwrap());
+        assert_eq!(storage.list("").await.unwrap(), vec!["bar/".to_string()]);
+        assert_eq!(storage.list("bar/").await.unwrap(), vec!["foo".to_string()]);
+
+        assert!(storage.delete("bar/foo").await.is_ok());
+        assert!(storage.get("bar/foo").await.unwrap().is_none());
+        assert!(storage.get("/bar").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_async_inmem_storage() {
+        check_async_storage(&AsyncInmemStorage::new()).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_storage_adapters_round_trip() {
+        // async -> sync -> async
+        let blocking = BlockingStorageAdapter::new(AsyncInmemStorage::new(), Handle::current());
+        check_async_storage(&SyncToAsync::new(Arc::new(blocking))).await;
+
+        // The sync view can be used directly from a worker of a multi-threaded runtime
+        let blocking = BlockingStorageAdapter::new(AsyncInmemStorage::new(), Handle::current());
+        let entry = StorageEntry { key: "foo".to_string(), value: "test".as_bytes().to_vec() };
+        assert!(blocking.put(&entry).is_ok());
+        assert!(blocking.exists("foo").unwrap());
+        assert_eq!(blocking.inner().get("foo").await.unwrap().unwrap(), entry);
+    }
+
+    #[test]
+    fn test_blocking_storage_adapter_outside_runtime() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let blocking = BlockingStorageAdapter::new(AsyncInmemStorage::new(), runtime.handle().clone());
+
+        let entry = StorageEntry { key: "foo".to_string(), value: "test".as_bytes().to_vec() };
+        assert!(blocking.put(&entry).is_ok());
+        assert_eq!(blocking.get("foo").unwrap().unwrap(), entry);
+        assert_eq!(blocking.list("").unwrap(), vec!["foo".to_string()]);
+        assert!(blocking.delete("foo").is_ok());
+        assert!(!blocking.exists("foo").unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_blocking_storage_adapter_current_thread() {
+        let blocking = BlockingStorageAdapter::new(AsyncInmemStorage::new(), Handle::current());
+        assert!(blocking.get("foo").is_err());
+    }
+}
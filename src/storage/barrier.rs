@@ -5,12 +5,24 @@
 //! It usually means a different symmetric encryption algorithm is going to be supported,
 //! if a new barrier is under development.
 
+use serde::{Deserialize, Serialize};
 use zeroize::Zeroizing;
 
 use super::Storage;
 use crate::errors::RvError;
 
 pub const BARRIER_INIT_PATH: &str = "barrier/init";
+pub const BARRIER_CANARY_PATH: &str = "barrier/canary";
+pub const BARRIER_CANARY_VALUE: &str = "rusty_vault_barrier_canary";
+
+/// The initialization state of a barrier, along with the key share configuration recorded at init.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct InitStatus {
+    pub initialized: bool,
+    pub sealed: bool,
+    pub shares_total: u8,
+    pub shares_threshold: u8,
+}
 
 pub trait SecurityBarrier: Storage + Send + Sync {
     fn inited(&self) -> Result<bool, RvError>;
@@ -21,5 +33,12 @@ pub trait SecurityBarrier: Storage + Send + Sync {
     fn unseal(&self, key: &[u8]) -> Result<(), RvError>;
     fn seal(&self) -> Result<(), RvError>;
     fn derive_hmac_key(&self) -> Result<Vec<u8>, RvError>;
+    /// Checks that the canary written at init still decrypts to its known value, proving that the
+    /// key material matches the stored data. Returns `ErrBarrierCanaryNotFound` if there is no
+    /// canary and `ErrBarrierIntegrityCheckFailed` if it cannot be decrypted.
+    fn verify_integrity(&self) -> Result<(), RvError>;
+    /// Reports the initialization state of the barrier. Only the unencrypted init metadata is read,
+    /// so it can be called while sealed. The share counts are zero when no seal config was recorded.
+    fn init_status(&self) -> Result<InitStatus, RvError>;
     fn as_storage(&self) -> &dyn Storage;
 }
@@ -1,7 +1,44 @@
 //! This is the implementation of aes-gcm barrier, which uses aes-gcm block cipher to encrypt or
 //! decrypt data before writing or reading data to or from specific storage backend.
+//!
+//! ChaCha20-Poly1305 can be selected instead of AES-256-GCM when the barrier is initialized, which
+//! suits hardware without AES acceleration. The choice is kept in the barrier's init metadata, and
+//! every ciphertext carries a byte identifying its algorithm, so reads always dispatch to the
+//! right cipher and data written before the option existed stays readable.
+//!
+//! Every ciphertext starts with a header, laid out as follows, multi-byte fields being big-endian:
+//!
+//! | offset | size | field                                                    |
+//! |--------|------|----------------------------------------------------------|
+//! | 0      | 2    | magic, `CIPHERTEXT_MAGIC`                                |
+//! | 2      | 1    | format version, `FORMAT_VERSION`                         |
+//! | 3      | 1    | algorithm id, the same values as the legacy version byte |
+//! | 4      | 4    | key term                                                 |
+//! | 8      |      | nonce, followed by the sealed value and the tag          |
+//!
+//! The whole header is authenticated along with the path. Ciphertexts written before the header
+//! existed start with a four byte epoch and a version byte instead. Their first byte is always
+//! zero, which the magic never starts with, so both formats are told apart and the old one stays
+//! readable. A header with a format version this build does not know is rejected with
+//! `ErrBarrierUnknownFormat` rather than being mistaken for corrupt data.
+//!
+//! Binding the path means a ciphertext copied or swapped to another key by someone with access to
+//! the backend fails authentication instead of being served as the value of that key. Only the
+//! legacy `AES_GCM_VERSION1` entries are not bound. They stay readable during a migration window,
+//! in which `rebind_unbound_entries` reseals them under their path. `set_allow_unbound_entries`
+//! then closes the window, after which reading an unbound entry fails with
+//! `ErrBarrierEntryUnbound`.
+//!
+//! Values too large to be buffered, such as a big CA bundle, can be sealed with `encrypt_stream`
+//! instead. The stream starts with the same header as a ciphertext and a random stream id, followed
+//! by frames holding at most `STREAM_CHUNK_SIZE` bytes of plaintext each. Every frame has its own
+//! nonce and tag, and carries its sequence number and whether it is the last one. Both are
+//! authenticated together with the stream id and the path, so frames cannot be reordered, dropped
+//! or spliced in from another stream, and a stream cut short is detected. Streams written before
+//! they carried the header start with the epoch and the version byte instead, and stay readable.
 
 use std::{
+    io::{self, Read, Write},
     ops::{Deref, DerefMut},
     sync::{Arc, RwLock},
 };
@@ -16,16 +53,222 @@ use serde::{Deserialize, Serialize};
 use zeroize::{Zeroize, Zeroizing};
 
 use super::{
-    barrier::{SecurityBarrier, BARRIER_INIT_PATH},
-    Backend, BackendEntry, Storage, StorageEntry,
+    barrier::{InitStatus, SecurityBarrier, BARRIER_CANARY_PATH, BARRIER_CANARY_VALUE, BARRIER_INIT_PATH},
+    check_value_size, Backend, BackendEntry, ReadConsistency, Storage, StorageEntry, DEFAULT_MAX_VALUE_SIZE,
+};
+use crate::{
+    core::{SealConfig, SEAL_CONFIG_PATH},
+    errors::RvError,
 };
-use crate::errors::RvError;
 
 const EPOCH_SIZE: usize = 4;
 const KEY_EPOCH: u8 = 1;
+const KEY_TERM: u32 = KEY_EPOCH as u32;
+const CIPHERTEXT_MAGIC: [u8; 2] = [0x52, 0x56];
+const FORMAT_VERSION: u8 = 1;
+const HEADER_SIZE: usize = CIPHERTEXT_MAGIC.len() + 1 + 1 + 4;
+const HEADER_ALGORITHM_OFFSET: usize = 3;
 const AES_GCM_VERSION1: u8 = 0x1;
 const AES_GCM_VERSION2: u8 = 0x2;
+const CHACHA20_POLY1305_VERSION1: u8 = 0x3;
 const AES_BLOCK_SIZE: usize = 16;
+const AEAD_TAG_SIZE: usize = 16;
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+const STREAM_ID_SIZE: usize = 16;
+const STREAM_HEADER_SIZE: usize = HEADER_SIZE + STREAM_ID_SIZE;
+const LEGACY_STREAM_HEADER_SIZE: usize = EPOCH_SIZE + 1 + STREAM_ID_SIZE;
+// sequence number (8 bytes), flags (1 byte), payload length (4 bytes)
+const STREAM_FRAME_HEADER_SIZE: usize = 13;
+const STREAM_FRAME_LAST: u8 = 0x1;
+
+/// The AEAD algorithm used by the barrier to seal entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BarrierAlgorithm {
+    #[default]
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl BarrierAlgorithm {
+    fn version_byte(&self) -> u8 {
+        match self {
+            BarrierAlgorithm::Aes256Gcm => AES_GCM_VERSION2,
+            BarrierAlgorithm::ChaCha20Poly1305 => CHACHA20_POLY1305_VERSION1,
+        }
+    }
+
+    fn key_length_range(&self) -> (usize, usize) {
+        match self {
+            BarrierAlgorithm::Aes256Gcm => (AES_BLOCK_SIZE, 2 * AES_BLOCK_SIZE),
+            BarrierAlgorithm::ChaCha20Poly1305 => (32, 32),
+        }
+    }
+
+    /// With the `fips` feature, only AES-GCM is permitted. Checked whenever a cipher is picked, so
+    /// a disallowed algorithm is rejected even if a config or an existing barrier asks for it.
+    pub fn check_permitted(&self) -> Result<(), RvError> {
+        #[cfg(feature = "fips")]
+        if *self == BarrierAlgorithm::ChaCha20Poly1305 {
+            return Err(RvError::ErrAlgorithmNotPermitted("chacha20_poly1305".to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+// cipher_for_version maps the version byte of a ciphertext to its cipher and
+// tells whether the entry path is bound as additional authenticated data.
+fn cipher_for_version(version: u8) -> Result<(Cipher, bool), RvError> {
+    match version {
+        AES_GCM_VERSION1 => Ok((Cipher::aes_256_gcm(), false)),
+        AES_GCM_VERSION2 => Ok((Cipher::aes_256_gcm(), true)),
+        CHACHA20_POLY1305_VERSION1 => {
+            BarrierAlgorithm::ChaCha20Poly1305.check_permitted()?;
+            Ok((Cipher::chacha20_poly1305(), true))
+        }
+        _ => Err(RvError::ErrBarrierVersionMismatch),
+    }
+}
+
+// ciphertext_header encodes the header of a ciphertext sealed with the given
+// algorithm id.
+fn ciphertext_header(algorithm_id: u8) -> [u8; HEADER_SIZE] {
+    let mut header = [0u8; HEADER_SIZE];
+    header[..CIPHERTEXT_MAGIC.len()].copy_from_slice(&CIPHERTEXT_MAGIC);
+    header[2] = FORMAT_VERSION;
+    header[HEADER_ALGORITHM_OFFSET] = algorithm_id;
+    header[4..].copy_from_slice(&KEY_TERM.to_be_bytes());
+    header
+}
+
+// parse_ciphertext_header returns the algorithm id of a ciphertext, the length
+// of its header and whether the header is authenticated, which is only the
+// case for the current format.
+fn parse_ciphertext_header(ciphertext: &[u8]) -> Result<(u8, usize, bool), RvError> {
+    if ciphertext.starts_with(&CIPHERTEXT_MAGIC) {
+        if ciphertext.len() < HEADER_SIZE || ciphertext[2] != FORMAT_VERSION {
+            return Err(RvError::ErrBarrierUnknownFormat);
+        }
+
+        let mut term = [0u8; 4];
+        term.copy_from_slice(&ciphertext[4..HEADER_SIZE]);
+        if u32::from_be_bytes(term) != KEY_TERM {
+            return Err(RvError::ErrBarrierEpochMismatch);
+        }
+
+        return Ok((ciphertext[HEADER_ALGORITHM_OFFSET], HEADER_SIZE, true));
+    }
+
+    // The legacy format, a big-endian epoch followed by the version byte
+    if ciphertext.len() < EPOCH_SIZE + 1 || ciphertext[0] != 0 {
+        return Err(RvError::ErrBarrierUnknownFormat);
+    }
+
+    if ciphertext[..EPOCH_SIZE] != [0, 0, 0, KEY_EPOCH] {
+        return Err(RvError::ErrBarrierEpochMismatch);
+    }
+
+    Ok((ciphertext[EPOCH_SIZE], EPOCH_SIZE + 1, false))
+}
+
+// is_unbound tells whether a ciphertext was sealed without its path as
+// additional authenticated data. Values in no known format, such as the
+// plaintext seal config, are not ciphertexts and are not reported.
+fn is_unbound(ciphertext: &[u8]) -> bool {
+    match parse_ciphertext_header(ciphertext) {
+        Ok((version, _, _)) => cipher_for_version(version).is_ok_and(|(_, with_aad)| !with_aad),
+        Err(_) => false,
+    }
+}
+
+// frame_header encodes the part of a stream frame preceding its nonce.
+fn frame_header(seq: u64, last: bool, len: usize) -> [u8; STREAM_FRAME_HEADER_SIZE] {
+    let mut header = [0u8; STREAM_FRAME_HEADER_SIZE];
+    header[..8].copy_from_slice(&seq.to_be_bytes());
+    header[8] = if last { STREAM_FRAME_LAST } else { 0 };
+    header[9..].copy_from_slice(&(len as u32).to_be_bytes());
+    header
+}
+
+// read_chunk reads up to STREAM_CHUNK_SIZE bytes, less only at the end of the
+// reader.
+fn read_chunk(reader: &mut impl Read) -> Result<Zeroizing<Vec<u8>>, RvError> {
+    let mut chunk = Zeroizing::new(Vec::with_capacity(STREAM_CHUNK_SIZE));
+    reader.take(STREAM_CHUNK_SIZE as u64).read_to_end(chunk.deref_mut())?;
+    Ok(chunk)
+}
+
+// read_stream_part fills buf, reporting a premature end of the stream as a
+// truncation.
+fn read_stream_part(reader: &mut impl Read, buf: &mut [u8]) -> Result<(), RvError> {
+    reader.read_exact(buf).map_err(|err| match err.kind() {
+        io::ErrorKind::UnexpectedEof => RvError::ErrBarrierStreamTruncated,
+        _ => err.into(),
+    })
+}
+
+fn seal_frame(
+    cipher: Cipher,
+    key: &[u8],
+    aad: &[u8],
+    seq: u64,
+    last: bool,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, RvError> {
+    let iv_len = cipher.iv_len().unwrap_or(0);
+    let header = frame_header(seq, last, plaintext.len());
+
+    let mut nonce = vec![0u8; iv_len];
+    thread_rng().fill(nonce.as_mut_slice());
+
+    let offset = STREAM_FRAME_HEADER_SIZE + iv_len;
+    let mut out = vec![0u8; offset + plaintext.len() + AEAD_TAG_SIZE + cipher.block_size()];
+    out[..STREAM_FRAME_HEADER_SIZE].copy_from_slice(&header);
+    out[STREAM_FRAME_HEADER_SIZE..offset].copy_from_slice(&nonce);
+
+    let mut encrypter = Crypter::new(cipher, Mode::Encrypt, key, Some(&nonce))?;
+    encrypter.pad(false);
+    encrypter.aad_update(aad)?;
+    encrypter.aad_update(&header)?;
+
+    let mut count = encrypter.update(plaintext, &mut out[offset..])?;
+    count += encrypter.finalize(&mut out[offset + count..])?;
+    out.truncate(offset + count + AEAD_TAG_SIZE);
+
+    encrypter.get_tag(&mut out[offset + count..])?;
+
+    Ok(out)
+}
+
+// open_frame decrypts the nonce, payload and tag of a stream frame whose header
+// was already read.
+fn open_frame(
+    cipher: Cipher,
+    key: &[u8],
+    aad: &[u8],
+    header: &[u8],
+    frame: &[u8],
+) -> Result<Zeroizing<Vec<u8>>, RvError> {
+    let iv_len = cipher.iv_len().unwrap_or(0);
+    let (nonce, rest) = frame.split_at(iv_len);
+    let (raw, tag) = rest.split_at(rest.len() - AEAD_TAG_SIZE);
+
+    let mut decrypter = Crypter::new(cipher, Mode::Decrypt, key, Some(nonce))?;
+    decrypter.pad(false);
+    decrypter.aad_update(aad)?;
+    decrypter.aad_update(header)?;
+
+    let mut out = Zeroizing::new(vec![0u8; raw.len() + cipher.block_size()]);
+    let mut count = decrypter.update(raw, out.as_mut_slice())?;
+
+    decrypter.set_tag(tag)?;
+
+    count += decrypter.finalize(&mut out[count..])?;
+    out.truncate(count);
+
+    Ok(out)
+}
 
 // the BarrierInit structure contains the encryption key, so it's zeroized anyway
 // when it's dropped
@@ -35,6 +278,9 @@ const AES_BLOCK_SIZE: usize = 16;
 struct BarrierInit {
     version: u32,
     key: Vec<u8>,
+    #[serde(default)]
+    #[zeroize(skip)]
+    algorithm: BarrierAlgorithm,
 }
 
 #[derive(Debug, Clone, Default, Zeroize)]
@@ -44,6 +290,16 @@ struct BarrierInfo {
     key: Option<Vec<u8>>,
     #[default(AES_GCM_VERSION2)]
     aes_gcm_version_byte: u8,
+    #[zeroize(skip)]
+    algorithm: BarrierAlgorithm,
+    // Plaintext values longer than this are rejected by put, zero means unlimited
+    #[zeroize(skip)]
+    #[default(DEFAULT_MAX_VALUE_SIZE)]
+    max_value_size: usize,
+    // Whether the entries not bound to their path are still readable
+    #[zeroize(skip)]
+    #[default(true)]
+    allow_unbound_entries: bool,
 }
 
 pub struct AESGCMBarrier {
@@ -64,20 +320,44 @@ impl Storage for AESGCMBarrier {
         Ok(ret)
     }
 
+    // The names are not encrypted, they stream straight from the backend, which
+    // already yields them sorted. The seal state is only checked up front, the
+    // callback may read through the barrier.
+    fn list_stream(
+        &self,
+        prefix: &str,
+        callback: &mut dyn FnMut(&str) -> Result<bool, RvError>,
+    ) -> Result<(), RvError> {
+        if self.barrier_info.read()?.sealed {
+            return Err(RvError::ErrBarrierSealed);
+        }
+
+        self.backend.list_stream(prefix, callback)
+    }
+
     fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+        self.get_consistent(key, ReadConsistency::Eventual)
+    }
+
+    fn get_consistent(&self, key: &str, consistency: ReadConsistency) -> Result<Option<StorageEntry>, RvError> {
         let barrier_info = self.barrier_info.read()?;
         if barrier_info.sealed {
             return Err(RvError::ErrBarrierSealed);
         }
 
         // Read the key from the backend
-        let pe = self.backend.get(key)?;
+        let pe = self.backend.get_consistent(key, consistency)?;
         if pe.is_none() {
             return Ok(None);
         }
 
+        let ciphertext = pe.as_ref().unwrap().value.as_slice();
+        if !barrier_info.allow_unbound_entries && is_unbound(ciphertext) {
+            return Err(RvError::ErrBarrierEntryUnbound);
+        }
+
         // Decrypt the ciphertext
-        let plain = self.decrypt(key, pe.as_ref().unwrap().value.as_slice())?;
+        let plain = self.decrypt(key, ciphertext)?;
         let entry = StorageEntry { key: key.to_string(), value: plain };
 
         Ok(Some(entry))
@@ -89,6 +369,8 @@ impl Storage for AESGCMBarrier {
             return Err(RvError::ErrBarrierSealed);
         }
 
+        check_value_size(entry, barrier_info.max_value_size)?;
+
         let ciphertext = self.encrypt(&entry.key, entry.value.as_slice())?;
 
         let be = BackendEntry { key: entry.key.clone(), value: ciphertext };
@@ -105,6 +387,47 @@ impl Storage for AESGCMBarrier {
         }
         self.backend.delete(key)
     }
+
+    fn delete_batch(&self, keys: &[String]) -> Result<(), RvError> {
+        let barrier_info = self.barrier_info.read()?;
+        if barrier_info.sealed {
+            return Err(RvError::ErrBarrierSealed);
+        }
+        self.backend.delete_batch(keys)
+    }
+
+    // Presence does not depend on the plaintext, so skip the decryption.
+    fn exists(&self, key: &str) -> Result<bool, RvError> {
+        let barrier_info = self.barrier_info.read()?;
+        if barrier_info.sealed {
+            return Err(RvError::ErrBarrierSealed);
+        }
+        self.backend.exists(key)
+    }
+
+    fn count(&self, prefix: &str) -> Result<usize, RvError> {
+        let barrier_info = self.barrier_info.read()?;
+        if barrier_info.sealed {
+            return Err(RvError::ErrBarrierSealed);
+        }
+        self.backend.count(prefix)
+    }
+
+    // The snapshot shares the keys and the seal state of the barrier, only the
+    // backend underneath is frozen.
+    fn snapshot_view(&self) -> Result<Arc<dyn Storage>, RvError> {
+        if self.barrier_info.read()?.sealed {
+            return Err(RvError::ErrBarrierSealed);
+        }
+
+        let backend = self.backend.snapshot_view()?;
+        Ok(Arc::new(AESGCMBarrier { barrier_info: Arc::clone(&self.barrier_info), backend }))
+    }
+
+    // Reachability of the backend does not depend on the seal state.
+    fn health(&self) -> Result<(), RvError> {
+        self.backend.exists(BARRIER_INIT_PATH).map(|_| ())
+    }
 }
 
 impl SecurityBarrier for AESGCMBarrier {
@@ -117,6 +440,8 @@ impl SecurityBarrier for AESGCMBarrier {
     // encryption key, which is generated during the init() process.
     // The kek's zerization is handled in the caller.
     fn init(&self, kek: &[u8]) -> Result<(), RvError> {
+        self.algorithm()?.check_permitted()?;
+
         let (min, max) = self.key_length_range();
         if kek.len() < min || kek.len() > max {
             return Err(RvError::ErrBarrierKeyInvalid);
@@ -131,7 +456,8 @@ impl SecurityBarrier for AESGCMBarrier {
         // the encrypt_key variable will be zeroized automatically on drop
         let encrypt_key = self.generate_key()?;
 
-        let barrier_init = BarrierInit { version: 1, key: encrypt_key.to_vec() };
+        let algorithm = self.algorithm()?;
+        let barrier_init = BarrierInit { version: 1, key: encrypt_key.to_vec(), algorithm };
 
         let serialized_barrier_init = serde_json::to_string(&barrier_init)?;
 
@@ -143,6 +469,15 @@ impl SecurityBarrier for AESGCMBarrier {
 
         self.backend.put(&be)?;
 
+        // Write the integrity canary with the actual encryption key
+        self.init_cipher(encrypt_key.deref().as_slice())?;
+
+        let value = self.encrypt(BARRIER_CANARY_PATH, BARRIER_CANARY_VALUE.as_bytes())?;
+
+        let be = BackendEntry { key: BARRIER_CANARY_PATH.to_string(), value };
+
+        self.backend.put(&be)?;
+
         self.reset_cipher()?;
 
         Ok(())
@@ -158,7 +493,7 @@ impl SecurityBarrier for AESGCMBarrier {
     }
 
     fn key_length_range(&self) -> (usize, usize) {
-        (AES_BLOCK_SIZE, 2 * AES_BLOCK_SIZE)
+        self.barrier_info.read().map(|info| info.algorithm).unwrap_or_default().key_length_range()
     }
 
     fn sealed(&self) -> Result<bool, RvError> {
@@ -191,6 +526,7 @@ impl SecurityBarrier for AESGCMBarrier {
         self.init_cipher(barrier_init.key.as_slice())?;
 
         let mut barrier_info = self.barrier_info.write()?;
+        barrier_info.algorithm = barrier_init.algorithm;
         barrier_info.sealed = false;
 
         Ok(())
@@ -219,6 +555,38 @@ impl SecurityBarrier for AESGCMBarrier {
         Ok(ret.to_vec())
     }
 
+    fn verify_integrity(&self) -> Result<(), RvError> {
+        if self.sealed()? {
+            return Err(RvError::ErrBarrierSealed);
+        }
+
+        let entry = self.backend.get(BARRIER_CANARY_PATH)?;
+        if entry.is_none() {
+            return Err(RvError::ErrBarrierCanaryNotFound);
+        }
+
+        match self.decrypt(BARRIER_CANARY_PATH, entry.unwrap().value.as_slice()) {
+            Ok(value) if value == BARRIER_CANARY_VALUE.as_bytes() => Ok(()),
+            _ => Err(RvError::ErrBarrierIntegrityCheckFailed),
+        }
+    }
+
+    fn init_status(&self) -> Result<InitStatus, RvError> {
+        let mut status = InitStatus { initialized: self.inited()?, sealed: self.sealed()?, ..Default::default() };
+        if !status.initialized {
+            return Ok(status);
+        }
+
+        // The seal config is kept unencrypted next to the barrier init entry
+        if let Some(entry) = self.backend.get(SEAL_CONFIG_PATH)? {
+            let seal_config: SealConfig = serde_json::from_slice(entry.value.as_slice())?;
+            status.shares_total = seal_config.secret_shares;
+            status.shares_threshold = seal_config.secret_threshold;
+        }
+
+        Ok(status)
+    }
+
     fn as_storage(&self) -> &dyn Storage {
         self
     }
@@ -229,6 +597,179 @@ impl AESGCMBarrier {
         Self { backend: physical, barrier_info: Arc::new(RwLock::new(BarrierInfo::default())) }
     }
 
+    /// Creates a barrier that uses `algorithm` when it gets initialized. An already initialized
+    /// barrier keeps the algorithm recorded at init time, which is restored by `unseal`.
+    pub fn new_with_algorithm(physical: Arc<dyn Backend>, algorithm: BarrierAlgorithm) -> Self {
+        let barrier_info = BarrierInfo { algorithm, ..Default::default() };
+        Self { backend: physical, barrier_info: Arc::new(RwLock::new(barrier_info)) }
+    }
+
+    pub fn algorithm(&self) -> Result<BarrierAlgorithm, RvError> {
+        Ok(self.barrier_info.read()?.algorithm)
+    }
+
+    /// Sets the largest plaintext value `put` accepts, zero meaning unlimited. This guards the
+    /// backend against a bug serializing a huge structure into a single entry. Defaults to
+    /// `DEFAULT_MAX_VALUE_SIZE`.
+    pub fn set_max_value_size(&self, max_value_size: usize) -> Result<(), RvError> {
+        self.barrier_info.write()?.max_value_size = max_value_size;
+        Ok(())
+    }
+
+    /// Sets whether the legacy entries not bound to their path are still readable. Defaults to
+    /// true, for the migration window. Once `rebind_unbound_entries` has run, setting it to false
+    /// ensures a legacy ciphertext planted at another key can not be read.
+    pub fn set_allow_unbound_entries(&self, allow: bool) -> Result<(), RvError> {
+        self.barrier_info.write()?.allow_unbound_entries = allow;
+        Ok(())
+    }
+
+    /// Reseals every entry under `prefix` that is not bound to its path, so that it is, and returns
+    /// how many were. A legacy ciphertext that was already moved to another key before it runs
+    /// gets bound to that key, so the migration should run before the backend could be tampered
+    /// with, and the window be closed right after.
+    pub fn rebind_unbound_entries(&self, prefix: &str) -> Result<usize, RvError> {
+        let mut rebound = 0;
+        self.walk(prefix, &mut |key| {
+            // The init entry is sealed with the key encryption key, which is not held here
+            if key == BARRIER_INIT_PATH {
+                return Ok(());
+            }
+
+            let Some(entry) = self.backend.get(key)? else {
+                return Ok(());
+            };
+            if !is_unbound(&entry.value) {
+                return Ok(());
+            }
+
+            let plaintext = Zeroizing::new(self.decrypt(key, &entry.value)?);
+            let value = self.encrypt(key, plaintext.as_slice())?;
+            self.backend.put(&BackendEntry { key: key.to_string(), value })?;
+            rebound += 1;
+            Ok(())
+        })?;
+
+        Ok(rebound)
+    }
+
+    /// Encrypts everything read from `reader` into `writer` as a sequence of authenticated frames,
+    /// so memory use stays bounded whatever the size of the value. Like `put`, the ciphertext is
+    /// bound to `path`. Returns the number of plaintext bytes encrypted.
+    pub fn encrypt_stream(&self, path: &str, reader: &mut impl Read, writer: &mut impl Write) -> Result<u64, RvError> {
+        let (version, key) = self.stream_key()?;
+        let (cipher, _) = cipher_for_version(version)?;
+
+        let mut header = [0u8; STREAM_HEADER_SIZE];
+        header[..HEADER_SIZE].copy_from_slice(&ciphertext_header(version));
+        thread_rng().fill(&mut header[HEADER_SIZE..]);
+        writer.write_all(&header)?;
+
+        let mut aad = header.to_vec();
+        aad.extend_from_slice(path.as_bytes());
+
+        let mut total = 0u64;
+        let mut seq = 0u64;
+        let mut chunk = read_chunk(reader)?;
+        loop {
+            // A full chunk is only known to be the last one once the next read comes back empty
+            let next = match chunk.len() {
+                STREAM_CHUNK_SIZE => read_chunk(reader)?,
+                _ => Zeroizing::new(Vec::new()),
+            };
+            let last = next.is_empty();
+
+            writer.write_all(&seal_frame(cipher, key.as_slice(), &aad, seq, last, chunk.as_slice())?)?;
+            total += chunk.len() as u64;
+
+            if last {
+                break;
+            }
+            chunk = next;
+            seq += 1;
+        }
+
+        writer.flush()?;
+
+        Ok(total)
+    }
+
+    /// Decrypts a stream produced by `encrypt_stream` for the same `path`. Frames are written out as
+    /// soon as they are authenticated, so on error the caller must discard whatever was written.
+    /// Returns the number of plaintext bytes decrypted.
+    pub fn decrypt_stream(&self, path: &str, reader: &mut impl Read, writer: &mut impl Write) -> Result<u64, RvError> {
+        let (_, key) = self.stream_key()?;
+
+        // Streams written before the header was versioned start with the epoch rather than the magic
+        let mut header = vec![0u8; CIPHERTEXT_MAGIC.len()];
+        read_stream_part(reader, &mut header)?;
+        let header_size =
+            if header.starts_with(&CIPHERTEXT_MAGIC) { STREAM_HEADER_SIZE } else { LEGACY_STREAM_HEADER_SIZE };
+        header.resize(header_size, 0);
+        read_stream_part(reader, &mut header[CIPHERTEXT_MAGIC.len()..])?;
+        let (version, _, _) = parse_ciphertext_header(&header)?;
+
+        // Streams always bind the path, the legacy version without it is not accepted
+        let (cipher, with_aad) = cipher_for_version(version)?;
+        if !with_aad {
+            return Err(RvError::ErrBarrierVersionMismatch);
+        }
+        let iv_len = cipher.iv_len().unwrap_or(0);
+
+        let mut aad = header.to_vec();
+        aad.extend_from_slice(path.as_bytes());
+
+        let mut total = 0u64;
+        let mut seq = 0u64;
+        let mut frame = Vec::with_capacity(iv_len + STREAM_CHUNK_SIZE + AEAD_TAG_SIZE);
+        loop {
+            let mut frame_header = [0u8; STREAM_FRAME_HEADER_SIZE];
+            read_stream_part(reader, &mut frame_header)?;
+
+            let frame_seq = u64::from_be_bytes(frame_header[..8].try_into().unwrap());
+            let flags = frame_header[8];
+            let len = u32::from_be_bytes(frame_header[9..].try_into().unwrap()) as usize;
+            if frame_seq != seq || flags & !STREAM_FRAME_LAST != 0 || len > STREAM_CHUNK_SIZE {
+                return Err(RvError::ErrBarrierStreamFrameInvalid);
+            }
+
+            frame.resize(iv_len + len + AEAD_TAG_SIZE, 0);
+            read_stream_part(reader, &mut frame)?;
+
+            let plaintext = open_frame(cipher, key.as_slice(), &aad, &frame_header, &frame)?;
+            writer.write_all(plaintext.as_slice())?;
+            total += plaintext.len() as u64;
+
+            if flags & STREAM_FRAME_LAST != 0 {
+                break;
+            }
+            seq += 1;
+        }
+
+        // Nothing may follow the last frame
+        if reader.read(&mut [0u8; 1])? != 0 {
+            return Err(RvError::ErrBarrierStreamFrameInvalid);
+        }
+
+        writer.flush()?;
+
+        Ok(total)
+    }
+
+    // stream_key returns the version byte to write streams with and a copy of
+    // the key, so that the lock is not held during I/O.
+    fn stream_key(&self) -> Result<(u8, Zeroizing<Vec<u8>>), RvError> {
+        let barrier_info = self.barrier_info.read()?;
+        if barrier_info.sealed {
+            return Err(RvError::ErrBarrierSealed);
+        }
+
+        match barrier_info.key.as_ref() {
+            Some(key) => Ok((barrier_info.algorithm.version_byte(), Zeroizing::new(key.clone()))),
+            None => Err(RvError::ErrBarrierNotInit),
+        }
+    }
+
     fn init_cipher(&self, key: &[u8]) -> Result<(), RvError> {
         let mut barrier_info = self.barrier_info.write()?;
         barrier_info.key = Some(key.to_vec());
@@ -249,18 +790,22 @@ impl AESGCMBarrier {
             return Err(RvError::ErrBarrierNotInit);
         }
 
-        let cipher = Cipher::aes_256_gcm();
+        let version = match barrier_info.algorithm {
+            BarrierAlgorithm::Aes256Gcm => barrier_info.aes_gcm_version_byte,
+            algorithm => algorithm.version_byte(),
+        };
+        let (cipher, with_aad) = cipher_for_version(version)?;
         let iv_len = cipher.iv_len().unwrap_or(0);
-        let tag_len = 16;
+        let tag_len = AEAD_TAG_SIZE;
         let block_size = cipher.block_size();
 
         // XXX: the cloned variable 'key' will be zeroized automatically on drop
         let key = Zeroizing::new(barrier_info.key.clone().unwrap());
 
-        let size: usize = EPOCH_SIZE + 1 + iv_len + plaintext.len() + tag_len;
+        let header = ciphertext_header(version);
+        let size: usize = HEADER_SIZE + iv_len + plaintext.len() + tag_len;
         let mut out = vec![0u8; size + block_size];
-        out[3] = KEY_EPOCH;
-        out[4] = barrier_info.aes_gcm_version_byte;
+        out[..HEADER_SIZE].copy_from_slice(&header);
 
         // Generate a random nonce
         let mut nonce = Zeroizing::new(vec![0u8; iv_len]);
@@ -268,7 +813,7 @@ impl AESGCMBarrier {
             0 => None,
             _ => {
                 thread_rng().fill(nonce.deref_mut().as_mut_slice());
-                out[5..5 + iv_len].copy_from_slice(nonce.deref().as_slice());
+                out[HEADER_SIZE..HEADER_SIZE + iv_len].copy_from_slice(nonce.deref().as_slice());
                 Some(nonce.deref().as_slice())
             }
         };
@@ -277,15 +822,16 @@ impl AESGCMBarrier {
 
         encrypter.pad(false);
 
-        if barrier_info.aes_gcm_version_byte == AES_GCM_VERSION2 {
+        encrypter.aad_update(&header)?;
+        if with_aad {
             encrypter.aad_update(path.as_bytes())?;
         }
 
-        let mut count = encrypter.update(plaintext, &mut out[EPOCH_SIZE + 1 + iv_len..])?;
-        count += encrypter.finalize(&mut out[EPOCH_SIZE + 1 + iv_len + count..])?;
-        out.truncate(EPOCH_SIZE + 1 + iv_len + count + tag_len);
+        let mut count = encrypter.update(plaintext, &mut out[HEADER_SIZE + iv_len..])?;
+        count += encrypter.finalize(&mut out[HEADER_SIZE + iv_len + count..])?;
+        out.truncate(HEADER_SIZE + iv_len + count + tag_len);
 
-        encrypter.get_tag(&mut out[EPOCH_SIZE + 1 + iv_len + count..])?;
+        encrypter.get_tag(&mut out[HEADER_SIZE + iv_len + count..])?;
 
         Ok(out)
     }
@@ -296,39 +842,37 @@ impl AESGCMBarrier {
             return Err(RvError::ErrBarrierNotInit);
         }
 
-        if ciphertext[0] != 0 || ciphertext[1] != 0 || ciphertext[2] != 0 || ciphertext[3] != KEY_EPOCH {
-            return Err(RvError::ErrBarrierEpochMismatch);
-        }
-
-        let cipher = Cipher::aes_256_gcm();
+        let (version, header_len, with_header_aad) = parse_ciphertext_header(ciphertext)?;
+        let (cipher, with_aad) = cipher_for_version(version)?;
         let block_size = cipher.block_size();
         let iv_len = cipher.iv_len().unwrap_or(0);
-        let tag_len = 16;
+        let tag_len = AEAD_TAG_SIZE;
+
+        if ciphertext.len() < header_len + iv_len + tag_len {
+            return Err(RvError::ErrBarrierUnknownFormat);
+        }
 
         let key = Zeroizing::new(barrier_info.key.clone().unwrap());
 
         let iv = match iv_len {
             0 => None,
-            _ => Some(&ciphertext[5..5 + iv_len]),
+            _ => Some(&ciphertext[header_len..header_len + iv_len]),
         };
 
         let mut decrypter = Crypter::new(cipher, Mode::Decrypt, key.deref().as_slice(), iv)?;
 
         decrypter.pad(false);
 
-        match ciphertext[4] {
-            AES_GCM_VERSION1 => {}
-            AES_GCM_VERSION2 => {
-                decrypter.aad_update(path.as_bytes())?;
-            }
-            _ => {
-                return Err(RvError::ErrBarrierVersionMismatch);
-            }
-        };
+        if with_header_aad {
+            decrypter.aad_update(&ciphertext[..header_len])?;
+        }
+        if with_aad {
+            decrypter.aad_update(path.as_bytes())?;
+        }
 
-        let raw = &ciphertext[5 + iv_len..ciphertext.len() - tag_len];
+        let raw = &ciphertext[header_len + iv_len..ciphertext.len() - tag_len];
         let tag = &ciphertext[ciphertext.len() - tag_len..ciphertext.len()];
-        let size = ciphertext.len() - 5 - iv_len - tag_len;
+        let size = ciphertext.len() - header_len - iv_len - tag_len;
         let mut out = vec![0u8; size + block_size];
 
         let mut count = decrypter.update(raw, &mut out)?;
@@ -407,6 +951,148 @@ mod test {
         assert_eq!(plaintext.as_bytes(), res.unwrap());
     }
 
+    #[test]
+    fn test_barrier_ciphertext_format() {
+        let backend = test_backend("test_barrier_ciphertext_format");
+
+        let mut key = vec![0u8; 32];
+        thread_rng().fill(key.as_mut_slice());
+
+        let barrier = AESGCMBarrier {
+            backend,
+            barrier_info: Arc::new(RwLock::new(BarrierInfo { sealed: true, key: Some(key), ..Default::default() })),
+        };
+
+        let path = "test/";
+        let plaintext = "rusty vault test";
+        let ciphertext = barrier.encrypt(path, plaintext.as_bytes()).unwrap();
+        assert_eq!(&ciphertext[..2], &CIPHERTEXT_MAGIC);
+        assert_eq!(ciphertext[2], FORMAT_VERSION);
+        assert_eq!(ciphertext[HEADER_ALGORITHM_OFFSET], AES_GCM_VERSION2);
+        assert_eq!(&ciphertext[4..HEADER_SIZE], &1u32.to_be_bytes());
+        assert_eq!(barrier.decrypt(path, &ciphertext).unwrap(), plaintext.as_bytes());
+
+        // A format version from the future is rejected as such, not as corruption
+        let mut bumped = ciphertext.clone();
+        bumped[2] = FORMAT_VERSION + 1;
+        assert_eq!(barrier.decrypt(path, &bumped).unwrap_err(), RvError::ErrBarrierUnknownFormat);
+
+        // So is anything that is neither format, or too short to hold a header
+        let mut garbage = ciphertext.clone();
+        garbage[0] = 0xff;
+        assert_eq!(barrier.decrypt(path, &garbage).unwrap_err(), RvError::ErrBarrierUnknownFormat);
+        assert_eq!(barrier.decrypt(path, &[]).unwrap_err(), RvError::ErrBarrierUnknownFormat);
+        assert_eq!(barrier.decrypt(path, &ciphertext[..HEADER_SIZE]).unwrap_err(), RvError::ErrBarrierUnknownFormat);
+
+        // A different key term is an epoch mismatch
+        let mut term = ciphertext.clone();
+        term[4..HEADER_SIZE].copy_from_slice(&2u32.to_be_bytes());
+        assert_eq!(barrier.decrypt(path, &term).unwrap_err(), RvError::ErrBarrierEpochMismatch);
+
+        // The header is authenticated, relabeling the algorithm does not decrypt
+        let mut relabeled = ciphertext;
+        relabeled[HEADER_ALGORITHM_OFFSET] = AES_GCM_VERSION1;
+        assert!(barrier.decrypt(path, &relabeled).is_err());
+    }
+
+    #[test]
+    fn test_barrier_chacha20_poly1305_encrypt_decrypt() {
+        let backend = test_backend("test_chacha20_poly1305_encrypt_decrypt");
+
+        let mut key = vec![0u8; 32];
+        thread_rng().fill(key.as_mut_slice());
+
+        let new_barrier = |algorithm: BarrierAlgorithm| AESGCMBarrier {
+            backend: Arc::clone(&backend),
+            barrier_info: Arc::new(RwLock::new(BarrierInfo {
+                sealed: true,
+                key: Some(key.clone()),
+                algorithm,
+                ..Default::default()
+            })),
+        };
+        let chacha = new_barrier(BarrierAlgorithm::ChaCha20Poly1305);
+        let aes = new_barrier(BarrierAlgorithm::Aes256Gcm);
+
+        let path = "test/";
+        let plaintext = "rusty vault test";
+        let chacha_ciphertext = chacha.encrypt(path, plaintext.as_bytes()).unwrap();
+        let aes_ciphertext = aes.encrypt(path, plaintext.as_bytes()).unwrap();
+        assert_eq!(chacha_ciphertext[HEADER_ALGORITHM_OFFSET], CHACHA20_POLY1305_VERSION1);
+        assert_eq!(aes_ciphertext[HEADER_ALGORITHM_OFFSET], AES_GCM_VERSION2);
+
+        // Reads dispatch on the version byte, whatever algorithm the barrier writes with
+        for barrier in [&chacha, &aes] {
+            assert_eq!(barrier.decrypt(path, &chacha_ciphertext).unwrap(), plaintext.as_bytes());
+            assert_eq!(barrier.decrypt(path, &aes_ciphertext).unwrap(), plaintext.as_bytes());
+        }
+
+        // The path is bound as additional authenticated data
+        assert!(chacha.decrypt("test2/", &chacha_ciphertext).is_err());
+
+        // Relabeling a ciphertext as the other algorithm must not decrypt
+        let mut tampered = chacha_ciphertext.clone();
+        tampered[HEADER_ALGORITHM_OFFSET] = AES_GCM_VERSION2;
+        assert!(chacha.decrypt(path, &tampered).is_err());
+        let mut tampered = aes_ciphertext.clone();
+        tampered[HEADER_ALGORITHM_OFFSET] = CHACHA20_POLY1305_VERSION1;
+        assert!(chacha.decrypt(path, &tampered).is_err());
+
+        // Flipping a bit of the payload or the tag is detected
+        let mut tampered = chacha_ciphertext.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 0x1;
+        assert!(chacha.decrypt(path, &tampered).is_err());
+        let mut tampered = chacha_ciphertext.clone();
+        tampered[HEADER_SIZE + 12] ^= 0x1;
+        assert!(chacha.decrypt(path, &tampered).is_err());
+
+        let mut tampered = chacha_ciphertext;
+        tampered[HEADER_ALGORITHM_OFFSET] = 0xff;
+        assert!(chacha.decrypt(path, &tampered).is_err());
+    }
+
+    #[test]
+    fn test_barrier_chacha20_poly1305() {
+        let backend = test_backend("test_barrier_chacha20_poly1305");
+
+        let barrier = AESGCMBarrier::new_with_algorithm(Arc::clone(&backend), BarrierAlgorithm::ChaCha20Poly1305);
+        assert_eq!(barrier.key_length_range(), (32, 32));
+
+        // ChaCha20-Poly1305 only accepts 256-bit keys
+        let mut short_key = vec![0u8; 16];
+        thread_rng().fill(short_key.as_mut_slice());
+        assert!(barrier.init(short_key.as_slice()).is_err());
+
+        let mut key = vec![0u8; 32];
+        thread_rng().fill(key.as_mut_slice());
+        assert!(barrier.init(key.as_slice()).is_ok());
+        assert!(barrier.unseal(key.as_slice()).is_ok());
+
+        let entry = StorageEntry { key: "bar".to_string(), value: "test1".as_bytes().to_vec() };
+        assert!(barrier.put(&entry).is_ok());
+        assert_eq!(barrier.get("bar").unwrap().unwrap().value, "test1".as_bytes());
+        assert_eq!(
+            barrier.backend.get("bar").unwrap().unwrap().value[HEADER_ALGORITHM_OFFSET],
+            CHACHA20_POLY1305_VERSION1
+        );
+        assert!(barrier.seal().is_ok());
+
+        // A barrier created with the default algorithm picks up the one recorded at init
+        let barrier = AESGCMBarrier::new(Arc::clone(&backend));
+        assert_eq!(barrier.algorithm().unwrap(), BarrierAlgorithm::Aes256Gcm);
+        assert!(barrier.unseal(key.as_slice()).is_ok());
+        assert_eq!(barrier.algorithm().unwrap(), BarrierAlgorithm::ChaCha20Poly1305);
+        assert_eq!(barrier.get("bar").unwrap().unwrap().value, "test1".as_bytes());
+
+        let entry = StorageEntry { key: "bar/foo".to_string(), value: "test2".as_bytes().to_vec() };
+        assert!(barrier.put(&entry).is_ok());
+        assert_eq!(
+            barrier.backend.get("bar/foo").unwrap().unwrap().value[HEADER_ALGORITHM_OFFSET],
+            CHACHA20_POLY1305_VERSION1
+        );
+    }
+
     #[test]
     fn test_barrier_aes256_gcm() {
         let backend = test_backend("test_barriew_aes256_gcm");
@@ -561,4 +1247,275 @@ mod test {
         let delete = barrier.delete("bar/foo");
         assert!(delete.is_err());
     }
+
+    #[test]
+    fn test_barrier_stream() {
+        let backend = test_backend("test_barrier_stream");
+
+        let mut key = vec![0u8; 32];
+        thread_rng().fill(key.as_mut_slice());
+
+        let barrier = AESGCMBarrier {
+            backend,
+            barrier_info: Arc::new(RwLock::new(BarrierInfo { sealed: false, key: Some(key), ..Default::default() })),
+        };
+
+        let path = "test/";
+        let round_trip = |plaintext: &[u8]| {
+            let mut ciphertext = Vec::new();
+            assert_eq!(
+                barrier.encrypt_stream(path, &mut &plaintext[..], &mut ciphertext).unwrap(),
+                plaintext.len() as u64
+            );
+            let mut decrypted = Vec::new();
+            assert_eq!(
+                barrier.decrypt_stream(path, &mut ciphertext.as_slice(), &mut decrypted).unwrap(),
+                plaintext.len() as u64
+            );
+            assert_eq!(decrypted, plaintext);
+            ciphertext
+        };
+
+        // Multi-megabyte values, with a partial last frame or not, and the empty value
+        let mut plaintext = vec![0u8; 3 * 1024 * 1024 + 17];
+        thread_rng().fill(plaintext.as_mut_slice());
+        round_trip(&plaintext);
+        round_trip(&plaintext[..2 * STREAM_CHUNK_SIZE]);
+        round_trip(&plaintext[..0]);
+
+        let ciphertext = round_trip(&plaintext[..2 * STREAM_CHUNK_SIZE + 1]);
+        let frame_size = STREAM_FRAME_HEADER_SIZE + 12 + STREAM_CHUNK_SIZE + AEAD_TAG_SIZE;
+        let decrypt = |ciphertext: &[u8]| barrier.decrypt_stream(path, &mut &ciphertext[..], &mut Vec::new());
+
+        // A stream cut short is rejected, including at a frame boundary
+        let truncated_lens = [
+            0,
+            STREAM_HEADER_SIZE,
+            STREAM_HEADER_SIZE + frame_size,
+            STREAM_HEADER_SIZE + 2 * frame_size,
+            ciphertext.len() - 1,
+        ];
+        for len in truncated_lens {
+            assert_eq!(decrypt(&ciphertext[..len]).unwrap_err(), RvError::ErrBarrierStreamTruncated);
+        }
+
+        // Marking a frame as the last one breaks its authentication
+        let mut tampered = ciphertext.clone();
+        tampered[STREAM_HEADER_SIZE + frame_size + 8] = STREAM_FRAME_LAST;
+        assert!(decrypt(&tampered).is_err());
+
+        // Reordered frames are rejected
+        let mut tampered = ciphertext[..STREAM_HEADER_SIZE].to_vec();
+        tampered.extend_from_slice(&ciphertext[STREAM_HEADER_SIZE + frame_size..STREAM_HEADER_SIZE + 2 * frame_size]);
+        tampered.extend_from_slice(&ciphertext[STREAM_HEADER_SIZE..STREAM_HEADER_SIZE + frame_size]);
+        tampered.extend_from_slice(&ciphertext[STREAM_HEADER_SIZE + 2 * frame_size..]);
+        assert_eq!(decrypt(&tampered).unwrap_err(), RvError::ErrBarrierStreamFrameInvalid);
+
+        // So are frames spliced in from another stream, and trailing data
+        let other = round_trip(&plaintext[..2 * STREAM_CHUNK_SIZE + 1]);
+        let mut tampered = ciphertext.clone();
+        tampered[STREAM_HEADER_SIZE..STREAM_HEADER_SIZE + frame_size]
+            .copy_from_slice(&other[STREAM_HEADER_SIZE..STREAM_HEADER_SIZE + frame_size]);
+        assert!(decrypt(&tampered).is_err());
+        let mut tampered = ciphertext.clone();
+        tampered.push(0);
+        assert_eq!(decrypt(&tampered).unwrap_err(), RvError::ErrBarrierStreamFrameInvalid);
+
+        // The path is bound as additional authenticated data
+        assert!(barrier.decrypt_stream("test2/", &mut ciphertext.as_slice(), &mut Vec::new()).is_err());
+
+        // Streams carry the versioned header, and one from a later format is told apart from corruption
+        assert_eq!(&ciphertext[..HEADER_SIZE], &ciphertext_header(AES_GCM_VERSION2));
+        let mut bumped = ciphertext.clone();
+        bumped[2] = FORMAT_VERSION + 1;
+        assert_eq!(decrypt(&bumped).unwrap_err(), RvError::ErrBarrierUnknownFormat);
+
+        // Streams written with the legacy header, the epoch and the version byte, stay readable
+        let key = barrier.stream_key().unwrap().1;
+        let mut legacy = vec![0u8; LEGACY_STREAM_HEADER_SIZE];
+        legacy[3] = KEY_EPOCH;
+        legacy[4] = AES_GCM_VERSION2;
+        thread_rng().fill(&mut legacy[EPOCH_SIZE + 1..]);
+        let mut aad = legacy.clone();
+        aad.extend_from_slice(path.as_bytes());
+        let frame = seal_frame(Cipher::aes_256_gcm(), key.as_slice(), &aad, 0, true, b"legacy").unwrap();
+        legacy.extend_from_slice(&frame);
+        let mut decrypted = Vec::new();
+        assert_eq!(barrier.decrypt_stream(path, &mut legacy.as_slice(), &mut decrypted).unwrap(), 6);
+        assert_eq!(decrypted, b"legacy");
+        legacy[3] = KEY_EPOCH + 1;
+        assert_eq!(decrypt(&legacy).unwrap_err(), RvError::ErrBarrierEpochMismatch);
+
+        assert!(barrier.seal().is_ok());
+        assert_eq!(decrypt(&ciphertext).unwrap_err(), RvError::ErrBarrierSealed);
+    }
+
+    #[test]
+    fn test_barrier_init_status() {
+        let backend = test_backend("test_barrier_init_status");
+        let barrier = AESGCMBarrier::new(Arc::clone(&backend));
+
+        let uninitialized = InitStatus { initialized: false, sealed: true, shares_total: 0, shares_threshold: 0 };
+        assert_eq!(barrier.init_status().unwrap(), uninitialized);
+
+        let seal_config = SealConfig { secret_shares: 5, secret_threshold: 3 };
+        let entry = BackendEntry {
+            key: SEAL_CONFIG_PATH.to_string(),
+            value: serde_json::to_string(&seal_config).unwrap().into_bytes(),
+        };
+        assert!(backend.put(&entry).is_ok());
+
+        let mut key = vec![0u8; 32];
+        thread_rng().fill(key.as_mut_slice());
+        assert!(barrier.init(key.as_slice()).is_ok());
+
+        // Readable while sealed
+        let sealed = InitStatus { initialized: true, sealed: true, shares_total: 5, shares_threshold: 3 };
+        assert_eq!(barrier.init_status().unwrap(), sealed);
+
+        assert!(barrier.unseal(key.as_slice()).is_ok());
+        assert_eq!(barrier.init_status().unwrap(), InitStatus { sealed: false, ..sealed });
+    }
+
+    #[cfg(feature = "fips")]
+    #[test]
+    fn test_barrier_fips() {
+        let mut key = vec![0u8; 32];
+        thread_rng().fill(key.as_mut_slice());
+
+        let backend = test_backend("test_barrier_fips_chacha");
+        let barrier = AESGCMBarrier::new_with_algorithm(Arc::clone(&backend), BarrierAlgorithm::ChaCha20Poly1305);
+        assert_eq!(
+            barrier.init(key.as_slice()).unwrap_err(),
+            RvError::ErrAlgorithmNotPermitted("chacha20_poly1305".to_string())
+        );
+        assert!(!barrier.inited().unwrap());
+
+        let backend = test_backend("test_barrier_fips_aes");
+        let barrier = AESGCMBarrier::new_with_algorithm(Arc::clone(&backend), BarrierAlgorithm::Aes256Gcm);
+        assert!(barrier.init(key.as_slice()).is_ok());
+        assert!(barrier.unseal(key.as_slice()).is_ok());
+        assert_eq!(barrier.algorithm().unwrap(), BarrierAlgorithm::Aes256Gcm);
+
+        // Existing ChaCha20 ciphertexts cannot be opened either
+        let ciphertext = [0u8, 0, 0, 1, CHACHA20_POLY1305_VERSION1, 0, 0, 0];
+        assert_eq!(
+            barrier.decrypt("test/", &ciphertext).unwrap_err(),
+            RvError::ErrAlgorithmNotPermitted("chacha20_poly1305".to_string())
+        );
+    }
+
+    #[test]
+    fn test_barrier_max_value_size() {
+        let backend = test_backend("test_barrier_max_value_size");
+        let barrier = AESGCMBarrier::new(Arc::clone(&backend));
+
+        let mut key = vec![0u8; 32];
+        thread_rng().fill(key.as_mut_slice());
+        assert!(barrier.init(key.as_slice()).is_ok());
+        assert!(barrier.unseal(key.as_slice()).is_ok());
+
+        let entry = StorageEntry { key: "test/large".to_string(), value: vec![0u8; DEFAULT_MAX_VALUE_SIZE + 1] };
+        let err = barrier.put(&entry).unwrap_err();
+        assert_eq!(err, RvError::ErrValueTooLarge { size: DEFAULT_MAX_VALUE_SIZE + 1, max: DEFAULT_MAX_VALUE_SIZE });
+        assert!(backend.get("test/large").unwrap().is_none());
+
+        assert!(barrier.set_max_value_size(0).is_ok());
+        assert!(barrier.put(&entry).is_ok());
+        assert_eq!(barrier.get("test/large").unwrap().unwrap().value.len(), DEFAULT_MAX_VALUE_SIZE + 1);
+
+        assert!(barrier.set_max_value_size(16).is_ok());
+        let entry = StorageEntry { key: "test/small".to_string(), value: vec![0u8; 16] };
+        assert!(barrier.put(&entry).is_ok());
+    }
+
+    #[test]
+    fn test_barrier_verify_integrity() {
+        let backend = test_backend("test_barrier_verify_integrity");
+        let barrier = AESGCMBarrier::new(Arc::clone(&backend));
+
+        let mut key = vec![0u8; 32];
+        thread_rng().fill(key.as_mut_slice());
+        assert!(barrier.init(key.as_slice()).is_ok());
+        assert!(backend.get(BARRIER_CANARY_PATH).unwrap().is_some());
+
+        assert_eq!(barrier.verify_integrity().unwrap_err(), RvError::ErrBarrierSealed);
+        assert!(barrier.unseal(key.as_slice()).is_ok());
+        assert!(barrier.verify_integrity().is_ok());
+
+        // A barrier/init from another barrier wrapped with the same kek unseals fine, but its
+        // encryption key cannot decrypt the canary
+        let other_backend = test_backend("test_barrier_verify_integrity_other");
+        let other_barrier = AESGCMBarrier::new(Arc::clone(&other_backend));
+        assert!(other_barrier.init(key.as_slice()).is_ok());
+        assert!(barrier.seal().is_ok());
+        assert!(backend.put(&other_backend.get(BARRIER_INIT_PATH).unwrap().unwrap()).is_ok());
+        assert!(barrier.unseal(key.as_slice()).is_ok());
+        assert_eq!(barrier.verify_integrity().unwrap_err(), RvError::ErrBarrierIntegrityCheckFailed);
+
+        // A missing canary is reported separately
+        assert!(backend.delete(BARRIER_CANARY_PATH).is_ok());
+        assert_eq!(barrier.verify_integrity().unwrap_err(), RvError::ErrBarrierCanaryNotFound);
+    }
+
+    #[test]
+    fn test_barrier_entry_bound_to_key() {
+        let backend = test_backend("test_barrier_entry_bound_to_key");
+        let barrier = AESGCMBarrier::new(Arc::clone(&backend));
+
+        let mut key = vec![0u8; 32];
+        thread_rng().fill(key.as_mut_slice());
+        assert!(barrier.init(key.as_slice()).is_ok());
+        assert!(barrier.unseal(key.as_slice()).is_ok());
+
+        let entry_a = StorageEntry { key: "test/a".to_string(), value: "value a".as_bytes().to_vec() };
+        let entry_b = StorageEntry { key: "test/b".to_string(), value: "value b".as_bytes().to_vec() };
+        assert!(barrier.put(&entry_a).is_ok());
+        assert!(barrier.put(&entry_b).is_ok());
+
+        // Copying the ciphertext of A over B does not make B read as A
+        let copy_a_to_b = || {
+            let raw_a = backend.get("test/a").unwrap().unwrap();
+            assert!(backend.put(&BackendEntry { key: "test/b".to_string(), value: raw_a.value }).is_ok());
+        };
+        copy_a_to_b();
+        assert!(barrier.get("test/b").is_err());
+        assert_eq!(barrier.get("test/a").unwrap().unwrap(), entry_a);
+
+        // The legacy entries are not bound, and can be swapped while they are readable
+        barrier.barrier_info.write().unwrap().aes_gcm_version_byte = AES_GCM_VERSION1;
+        assert!(barrier.put(&entry_a).is_ok());
+        assert!(barrier.put(&entry_b).is_ok());
+        let legacy_b = backend.get("test/b").unwrap().unwrap();
+        barrier.barrier_info.write().unwrap().aes_gcm_version_byte = AES_GCM_VERSION2;
+        copy_a_to_b();
+        assert_eq!(barrier.get("test/b").unwrap().unwrap().value, entry_a.value);
+
+        // Closing the migration window rejects them
+        assert!(barrier.set_allow_unbound_entries(false).is_ok());
+        assert_eq!(barrier.get("test/a").unwrap_err(), RvError::ErrBarrierEntryUnbound);
+        assert_eq!(barrier.get("test/b").unwrap_err(), RvError::ErrBarrierEntryUnbound);
+
+        // Rebinding reseals the legacy entries only, the canary and the plaintext seal config
+        // next to them are left alone
+        assert!(backend.put(&legacy_b).is_ok());
+        let seal_config = BackendEntry { key: SEAL_CONFIG_PATH.to_string(), value: b"{}".to_vec() };
+        assert!(backend.put(&seal_config).is_ok());
+        let canary = backend.get(BARRIER_CANARY_PATH).unwrap().unwrap();
+        assert_eq!(barrier.rebind_unbound_entries("").unwrap(), 2);
+        assert_eq!(barrier.rebind_unbound_entries("").unwrap(), 0);
+        assert_eq!(backend.get(BARRIER_CANARY_PATH).unwrap().unwrap(), canary);
+        assert_eq!(backend.get(SEAL_CONFIG_PATH).unwrap().unwrap(), seal_config);
+        assert!(barrier.verify_integrity().is_ok());
+
+        assert_eq!(barrier.get("test/a").unwrap().unwrap(), entry_a);
+        assert_eq!(barrier.get("test/b").unwrap().unwrap(), entry_b);
+        copy_a_to_b();
+        assert!(barrier.get("test/b").is_err());
+
+        // The barrier still unseals, its init entry is not subject to the window
+        assert!(barrier.seal().is_ok());
+        assert!(barrier.unseal(key.as_slice()).is_ok());
+        assert_eq!(barrier.get("test/a").unwrap().unwrap(), entry_a);
+    }
 }
@@ -1,6 +1,6 @@
 use std::sync::Arc;
 
-use super::{barrier::SecurityBarrier, Storage, StorageEntry};
+use super::{barrier::SecurityBarrier, ReadConsistency, Storage, StorageEntry};
 use crate::errors::RvError;
 
 pub struct BarrierView {
@@ -14,9 +14,22 @@ impl Storage for BarrierView {
         self.barrier.list(self.expand_key(prefix).as_str())
     }
 
+    fn list_stream(
+        &self,
+        prefix: &str,
+        callback: &mut dyn FnMut(&str) -> Result<bool, RvError>,
+    ) -> Result<(), RvError> {
+        self.sanity_check(prefix)?;
+        self.barrier.list_stream(self.expand_key(prefix).as_str(), callback)
+    }
+
     fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+        self.get_consistent(key, ReadConsistency::Eventual)
+    }
+
+    fn get_consistent(&self, key: &str, consistency: ReadConsistency) -> Result<Option<StorageEntry>, RvError> {
         self.sanity_check(key)?;
-        let storage_entry = self.barrier.get(self.expand_key(key).as_str())?;
+        let storage_entry = self.barrier.get_consistent(self.expand_key(key).as_str(), consistency)?;
         if let Some(entry) = storage_entry {
             Ok(Some(StorageEntry { key: self.truncate_key(entry.key.as_str()), value: entry.value }))
         } else {
@@ -34,6 +47,90 @@ impl Storage for BarrierView {
         self.sanity_check(key)?;
         self.barrier.delete(self.expand_key(key).as_str())
     }
+
+    fn delete_batch(&self, keys: &[String]) -> Result<(), RvError> {
+        for key in keys.iter() {
+            self.sanity_check(key)?;
+        }
+        let keys: Vec<String> = keys.iter().map(|key| self.expand_key(key)).collect();
+        self.barrier.delete_batch(&keys)
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, RvError> {
+        self.sanity_check(key)?;
+        self.barrier.exists(self.expand_key(key).as_str())
+    }
+
+    fn count(&self, prefix: &str) -> Result<usize, RvError> {
+        self.sanity_check(prefix)?;
+        self.barrier.count(self.expand_key(prefix).as_str())
+    }
+
+    fn health(&self) -> Result<(), RvError> {
+        self.barrier.health()
+    }
+
+    // The snapshot of the whole barrier, scoped to the prefix of the view.
+    fn snapshot_view(&self) -> Result<Arc<dyn Storage>, RvError> {
+        let snapshot = self.barrier.snapshot_view()?;
+        if self.prefix.is_empty() {
+            return Ok(snapshot);
+        }
+
+        Ok(Arc::new(SnapshotView { snapshot, prefix: self.prefix.clone() }))
+    }
+}
+
+// SnapshotView scopes a snapshot of the barrier to the prefix of a view, with
+// the keys relative to it and checked like the view's own.
+struct SnapshotView {
+    snapshot: Arc<dyn Storage>,
+    prefix: String,
+}
+
+impl SnapshotView {
+    fn expand_key(&self, key: &str) -> Result<String, RvError> {
+        if key.contains("..") || key.starts_with('/') {
+            return Err(RvError::ErrBarrierKeySanityCheckFailed);
+        }
+
+        Ok(format!("{}{}", self.prefix, key))
+    }
+}
+
+impl Storage for SnapshotView {
+    fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+        self.snapshot.list(&self.expand_key(prefix)?)
+    }
+
+    fn list_stream(
+        &self,
+        prefix: &str,
+        callback: &mut dyn FnMut(&str) -> Result<bool, RvError>,
+    ) -> Result<(), RvError> {
+        self.snapshot.list_stream(&self.expand_key(prefix)?, callback)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+        let entry = self.snapshot.get(&self.expand_key(key)?)?;
+        Ok(entry.map(|entry| StorageEntry { key: key.to_string(), value: entry.value }))
+    }
+
+    fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+        self.snapshot.put(&StorageEntry { key: self.expand_key(&entry.key)?, value: entry.value.clone() })
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RvError> {
+        self.snapshot.delete(&self.expand_key(key)?)
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, RvError> {
+        self.snapshot.exists(&self.expand_key(key)?)
+    }
+
+    fn count(&self, prefix: &str) -> Result<usize, RvError> {
+        self.snapshot.count(&self.expand_key(prefix)?)
+    }
 }
 
 impl BarrierView {
@@ -41,28 +138,23 @@ impl BarrierView {
         Self { barrier, prefix: prefix.to_string() }
     }
 
-    pub fn new_sub_view(&self, prefix: &str) -> Self {
+    /// Derives a view scoped to `prefix` within this one, sharing its barrier. Keys and list results
+    /// of the sub-view are relative to its own prefix, and sub-views nest to any depth. The prefix
+    /// should end with a '/', or the sub-view also sees the siblings sharing the same start.
+    pub fn sub_view(&self, prefix: &str) -> Self {
         Self { barrier: Arc::clone(&self.barrier), prefix: self.expand_key(prefix) }
     }
 
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
     pub fn get_keys(&self) -> Result<Vec<String>, RvError> {
-        let mut paths = vec!["".to_string()];
         let mut keys = Vec::new();
-        while !paths.is_empty() {
-            let n = paths.len();
-            let curr = paths[n - 1].to_owned();
-            paths.pop();
-
-            let items = self.list(curr.as_str())?;
-            for p in items {
-                let path = format!("{}{}", curr, p);
-                if p.ends_with('/') {
-                    paths.push(path);
-                } else {
-                    keys.push(path.to_owned());
-                }
-            }
-        }
+        self.walk("", &mut |key: &str| {
+            keys.push(key.to_string());
+            Ok(())
+        })?;
         keys.sort();
         Ok(keys)
     }
@@ -127,4 +219,99 @@ mod test {
         assert!(view.sanity_check("../foo").is_err());
         assert!(view.sanity_check("foo/../").is_err());
     }
+
+    #[test]
+    fn test_barrier_sub_view() {
+        let backend = test_backend("test_barrier_sub_view");
+
+        let mut key = vec![0u8; 32];
+        thread_rng().fill(key.as_mut_slice());
+
+        let barrier = barrier_aes_gcm::AESGCMBarrier::new(Arc::clone(&backend));
+        assert!(barrier.init(key.as_slice()).is_ok());
+        assert!(barrier.unseal(key.as_slice()).is_ok());
+
+        let view = BarrierView::new(Arc::new(barrier), "logical/");
+        let mount = view.sub_view("auth/approle/");
+        let roles = mount.sub_view("role/");
+        let secret_ids = mount.sub_view("secret_id/");
+        assert_eq!(roles.prefix(), "logical/auth/approle/role/");
+
+        let entry = StorageEntry { key: "role1".to_string(), value: "test".as_bytes().to_vec() };
+        assert!(roles.put(&entry).is_ok());
+        let entry = StorageEntry { key: "role1/hmac".to_string(), value: "test".as_bytes().to_vec() };
+        assert!(secret_ids.put(&entry).is_ok());
+
+        // Keys and listings are relative to each view
+        assert_eq!(roles.get("role1").unwrap().unwrap().key, "role1");
+        assert_eq!(mount.get("role/role1").unwrap().unwrap().key, "role/role1");
+        assert!(view.exists("auth/approle/role/role1").unwrap());
+        assert_eq!(roles.list("").unwrap(), vec!["role1"]);
+        assert_eq!(secret_ids.list("role1/").unwrap(), vec!["hmac"]);
+        let mut listed = mount.list("").unwrap();
+        listed.sort();
+        assert_eq!(listed, vec!["role/", "secret_id/"]);
+
+        // Sibling views do not see each other's keys
+        assert!(secret_ids.get("role1").unwrap().is_none());
+        assert!(roles.get("role1/hmac").unwrap().is_none());
+        assert_eq!(roles.get_keys().unwrap(), vec!["role1"]);
+
+        assert!(roles.clear().is_ok());
+        assert!(roles.list("").unwrap().is_empty());
+        assert_eq!(secret_ids.count("").unwrap(), 1);
+    }
+
+    #[test]
+    fn test_barrier_view_snapshot() {
+        let mut key = vec![0u8; 32];
+        thread_rng().fill(key.as_mut_slice());
+
+        // The file backend can not take snapshots
+        let barrier = barrier_aes_gcm::AESGCMBarrier::new(test_backend("test_barrier_view_snapshot"));
+        assert!(barrier.init(key.as_slice()).is_ok());
+        assert!(barrier.unseal(key.as_slice()).is_ok());
+        let view = BarrierView::new(Arc::new(barrier), "logical/");
+        assert_eq!(view.snapshot_view().err(), Some(RvError::ErrStorageSnapshotUnsupported));
+
+        let barrier = Arc::new(barrier_aes_gcm::AESGCMBarrier::new(Arc::new(physical::inmem::InmemBackend::new())));
+        assert!(barrier.init(key.as_slice()).is_ok());
+        assert!(barrier.unseal(key.as_slice()).is_ok());
+        let view = BarrierView::new(barrier.clone(), "logical/");
+
+        let entry = |key: &str, value: &str| StorageEntry { key: key.to_string(), value: value.as_bytes().to_vec() };
+        for i in 0..10 {
+            assert!(view.put(&entry(&format!("scan/{}", i), "before")).is_ok());
+        }
+
+        // Writes made while a scan walks the snapshot are invisible to it
+        let snapshot = view.snapshot_view().unwrap();
+        let mut scanned = Vec::new();
+        let ret = snapshot.walk("scan/", &mut |key: &str| {
+            view.put(&entry(&format!("scan/new-{}", key.len() + scanned.len()), "during"))?;
+            view.put(&entry(key, "changed"))?;
+            view.delete("scan/0")?;
+
+            scanned.push((key.to_string(), snapshot.get(key)?.unwrap().value));
+            Ok(())
+        });
+        assert!(ret.is_ok());
+        scanned.sort();
+        let expected: Vec<(String, Vec<u8>)> = (0..10).map(|i| (format!("scan/{}", i), b"before".to_vec())).collect();
+        assert_eq!(scanned, expected);
+        assert_eq!(snapshot.count("scan/").unwrap(), 10);
+        assert!(snapshot.get("scan/0").unwrap().is_some());
+
+        // The main store has them all
+        assert_eq!(view.count("scan/").unwrap(), 19);
+        assert!(view.get("scan/0").unwrap().is_none());
+        assert_eq!(view.get("scan/1").unwrap().unwrap().value, b"changed");
+
+        // The snapshot is read-only, and follows the seal of the barrier
+        assert_eq!(snapshot.put(&entry("scan/x", "x")).err(), Some(RvError::ErrStorageSnapshotReadOnly));
+        assert_eq!(snapshot.delete("scan/1").err(), Some(RvError::ErrStorageSnapshotReadOnly));
+        assert!(snapshot.get("../logical/scan/1").is_err());
+        assert!(barrier.seal().is_ok());
+        assert_eq!(snapshot.get("scan/1").err(), Some(RvError::ErrBarrierSealed));
+    }
 }
@@ -0,0 +1,225 @@
+//! The `CachedStorage` wrapper keeps the results of recent `get` calls in a bounded LRU cache, so
+//! that hot entries, such as the config of a heavily used role, are not read from the physical
+//! backend over and over.
+//!
+//! Writes go to the inner storage first and invalidate the cached key before returning, so a read
+//! following a write never sees the old value. A read racing with a write does not populate the
+//! cache, which keeps a stale value from being cached after the invalidation. `list` and
+//! `list_stream` are always forwarded, and so is `snapshot_view`: a snapshot reads the inner
+//! storage at a point in time and never goes through the cache.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, RwLock,
+};
+
+use super::{ReadConsistency, Storage, StorageEntry};
+use crate::{errors::RvError, utils::lru::LruCache};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+pub struct CachedStorage<S> {
+    inner: S,
+    // Absent keys are cached as None
+    cache: RwLock<LruCache<String, Option<StorageEntry>>>,
+    // Bumped by every write, under the cache lock
+    generation: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<S: Storage> CachedStorage<S> {
+    pub fn new(inner: S, capacity: usize) -> Self {
+        Self {
+            inner,
+            cache: RwLock::new(LruCache::new(capacity)),
+            generation: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats { hits: self.hits.load(Ordering::Relaxed), misses: self.misses.load(Ordering::Relaxed) }
+    }
+
+    /// Drops every cached entry, e.g. after the inner storage was modified behind the cache's back.
+    pub fn purge(&self) -> Result<(), RvError> {
+        let mut cache = self.cache.write()?;
+        cache.clear();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn invalidate(&self, key: &str) -> Result<(), RvError> {
+        let mut cache = self.cache.write()?;
+        cache.remove(&key.to_string());
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+impl<S: Storage> Storage for CachedStorage<S> {
+    fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+        self.inner.list(prefix)
+    }
+
+    fn list_stream(
+        &self,
+        prefix: &str,
+        callback: &mut dyn FnMut(&str) -> Result<bool, RvError>,
+    ) -> Result<(), RvError> {
+        self.inner.list_stream(prefix, callback)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+        // LruCache::get marks the entry as recently used, hence the write lock
+        if let Some(entry) = self.cache.write()?.get(&key.to_string()) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(entry.clone());
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        let generation = self.generation.load(Ordering::SeqCst);
+        let entry = self.inner.get(key)?;
+
+        let mut cache = self.cache.write()?;
+        if self.generation.load(Ordering::SeqCst) == generation {
+            cache.insert(key.to_string(), entry.clone());
+        }
+
+        Ok(entry)
+    }
+
+    // Strong reads always go to the inner storage, which may have been written
+    // behind the cache's back. They do not populate the cache either.
+    fn get_consistent(&self, key: &str, consistency: ReadConsistency) -> Result<Option<StorageEntry>, RvError> {
+        match consistency {
+            ReadConsistency::Eventual => self.get(key),
+            ReadConsistency::Strong => self.inner.get_consistent(key, consistency),
+        }
+    }
+
+    fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+        let ret = self.inner.put(entry);
+        self.invalidate(&entry.key)?;
+        ret
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RvError> {
+        let ret = self.inner.delete(key);
+        self.invalidate(key)?;
+        ret
+    }
+
+    // Every key is invalidated even if the batch failed, a part of it may
+    // have been deleted.
+    fn delete_batch(&self, keys: &[String]) -> Result<(), RvError> {
+        let ret = self.inner.delete_batch(keys);
+        let mut cache = self.cache.write()?;
+        for key in keys.iter() {
+            cache.remove(key);
+        }
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        ret
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, RvError> {
+        if let Some(entry) = self.cache.write()?.get(&key.to_string()) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(entry.is_some());
+        }
+
+        self.inner.exists(key)
+    }
+
+    fn health(&self) -> Result<(), RvError> {
+        self.inner.health()
+    }
+
+    fn count(&self, prefix: &str) -> Result<usize, RvError> {
+        self.inner.count(prefix)
+    }
+
+    fn snapshot_view(&self) -> Result<Arc<dyn Storage>, RvError> {
+        self.inner.snapshot_view()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        super::{
+            physical::inmem::InmemBackend,
+            retry::test::MapStorage,
+            test_suite::{run_conformance, run_snapshot_conformance, BackendStorage},
+        },
+        *,
+    };
+
+    #[test]
+    fn test_cached_storage() {
+        let storage = CachedStorage::new(MapStorage::default(), 2);
+
+        let entry = StorageEntry { key: "foo".to_string(), value: "test1".as_bytes().to_vec() };
+        assert!(storage.get("foo").unwrap().is_none());
+        assert!(!storage.exists("foo").unwrap());
+        assert_eq!(storage.stats(), CacheStats { hits: 1, misses: 1 });
+
+        // A put followed by a get returns the new value, not the cached absence
+        assert!(storage.put(&entry).is_ok());
+        assert_eq!(storage.get("foo").unwrap().unwrap(), entry);
+        assert_eq!(storage.stats(), CacheStats { hits: 1, misses: 2 });
+        assert_eq!(storage.get("foo").unwrap().unwrap(), entry);
+        assert_eq!(storage.stats(), CacheStats { hits: 2, misses: 2 });
+
+        let entry = StorageEntry { key: "foo".to_string(), value: "test2".as_bytes().to_vec() };
+        assert!(storage.put(&entry).is_ok());
+        assert_eq!(storage.get("foo").unwrap().unwrap().value, "test2".as_bytes());
+
+        assert!(storage.delete("foo").is_ok());
+        assert!(storage.get("foo").unwrap().is_none());
+        assert_eq!(storage.list("").unwrap(), Vec::<String>::new());
+
+        // Writes behind the cache's back are only seen after a purge
+        assert!(storage.inner().put(&entry).is_ok());
+        assert!(storage.get("foo").unwrap().is_none());
+        assert_eq!(storage.get_consistent("foo", ReadConsistency::Strong).unwrap().unwrap(), entry);
+        assert!(storage.get("foo").unwrap().is_none());
+        assert!(storage.purge().is_ok());
+        assert_eq!(storage.get("foo").unwrap().unwrap(), entry);
+    }
+
+    #[test]
+    fn test_cached_storage_eviction() {
+        let storage = CachedStorage::new(MapStorage::default(), 2);
+        for key in ["a", "b", "c"] {
+            let entry = StorageEntry { key: key.to_string(), value: key.as_bytes().to_vec() };
+            assert!(storage.put(&entry).is_ok());
+            assert!(storage.get(key).unwrap().is_some());
+        }
+        assert_eq!(storage.stats(), CacheStats { hits: 0, misses: 3 });
+
+        // "a" was evicted, the other two are still cached
+        assert!(storage.get("c").unwrap().is_some());
+        assert!(storage.get("b").unwrap().is_some());
+        assert!(storage.get("a").unwrap().is_some());
+        assert_eq!(storage.stats(), CacheStats { hits: 2, misses: 4 });
+    }
+
+    #[test]
+    fn test_cached_storage_conformance() {
+        let backend = InmemBackend::new();
+        run_conformance(&CachedStorage::new(BackendStorage(&backend), 4));
+        run_snapshot_conformance(&CachedStorage::new(BackendStorage(&backend), 4));
+    }
+}
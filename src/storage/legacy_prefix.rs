@@ -0,0 +1,229 @@
+//! The `LegacyPrefixShim` wrapper keeps entries written under an old key layout readable after the
+//! layout changed.
+//!
+//! Each mapping pairs the current prefix with the prefix the same entries used to live under. A
+//! `get` that misses under the current prefix is retried under the old one, and an entry found
+//! there is moved to the current layout (read-repair). Wrapping a storage in the shim is what opts
+//! into this behavior, so layouts that never changed pay nothing for it.
+//!
+//! The snapshot of a shim maps the legacy prefixes the same way, but being read-only it returns
+//! the legacy entries under their current key without moving them.
+
+use std::sync::Arc;
+
+use super::{ReadConsistency, Storage, StorageEntry};
+use crate::errors::RvError;
+
+pub struct LegacyPrefixShim<S> {
+    inner: S,
+    // (current prefix, legacy prefix)
+    mappings: Vec<(String, String)>,
+    // Off for snapshots, which cannot be written
+    read_repair: bool,
+}
+
+impl<S: Storage> LegacyPrefixShim<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner, mappings: Vec::new(), read_repair: true }
+    }
+
+    /// Adds a mapping from the current prefix to the legacy one its entries used to live under.
+    pub fn with_mapping(mut self, prefix: &str, legacy_prefix: &str) -> Self {
+        self.mappings.push((prefix.to_string(), legacy_prefix.to_string()));
+        self
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn legacy_key(&self, key: &str) -> Option<String> {
+        self.mappings.iter().find_map(|(prefix, legacy_prefix)| {
+            key.strip_prefix(prefix.as_str()).map(|rest| legacy_prefix.clone() + rest)
+        })
+    }
+}
+
+impl<S: Storage> Storage for LegacyPrefixShim<S> {
+    fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+        let mut keys = self.inner.list(prefix)?;
+        if let Some(legacy_prefix) = self.legacy_key(prefix) {
+            for key in self.inner.list(&legacy_prefix)? {
+                if !keys.contains(&key) {
+                    keys.push(key);
+                }
+            }
+            keys.sort();
+        }
+
+        Ok(keys)
+    }
+
+    // The listings of the prefixes with a legacy counterpart have to be merged, only the others
+    // can be streamed from the inner storage.
+    fn list_stream(
+        &self,
+        prefix: &str,
+        callback: &mut dyn FnMut(&str) -> Result<bool, RvError>,
+    ) -> Result<(), RvError> {
+        if self.legacy_key(prefix).is_none() {
+            return self.inner.list_stream(prefix, callback);
+        }
+
+        for key in self.list(prefix)? {
+            if !callback(&key)? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+        self.get_consistent(key, ReadConsistency::Eventual)
+    }
+
+    fn get_consistent(&self, key: &str, consistency: ReadConsistency) -> Result<Option<StorageEntry>, RvError> {
+        if let Some(entry) = self.inner.get_consistent(key, consistency)? {
+            return Ok(Some(entry));
+        }
+
+        let Some(legacy_key) = self.legacy_key(key) else {
+            return Ok(None);
+        };
+        let Some(legacy_entry) = self.inner.get_consistent(&legacy_key, consistency)? else {
+            return Ok(None);
+        };
+
+        let entry = StorageEntry { key: key.to_string(), value: legacy_entry.value };
+        if !self.read_repair {
+            return Ok(Some(entry));
+        }
+
+        self.inner.put(&entry)?;
+        self.inner.delete(&legacy_key)?;
+        log::info!("moved storage entry from legacy key {} to {}", legacy_key, key);
+
+        Ok(Some(entry))
+    }
+
+    fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+        self.inner.put(entry)
+    }
+
+    // The legacy entry is deleted as well, or a later get would bring it back.
+    fn delete(&self, key: &str) -> Result<(), RvError> {
+        self.inner.delete(key)?;
+        if let Some(legacy_key) = self.legacy_key(key) {
+            self.inner.delete(&legacy_key)?;
+        }
+
+        Ok(())
+    }
+
+    // Each key is followed by its legacy key in the batch, as delete does.
+    fn delete_batch(&self, keys: &[String]) -> Result<(), RvError> {
+        let mut batch = Vec::with_capacity(keys.len());
+        for key in keys.iter() {
+            batch.push(key.clone());
+            batch.extend(self.legacy_key(key));
+        }
+
+        self.inner.delete_batch(&batch)
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, RvError> {
+        if self.inner.exists(key)? {
+            return Ok(true);
+        }
+
+        match self.legacy_key(key) {
+            Some(legacy_key) => self.inner.exists(&legacy_key),
+            None => Ok(false),
+        }
+    }
+
+    fn health(&self) -> Result<(), RvError> {
+        self.inner.health()
+    }
+
+    fn snapshot_view(&self) -> Result<Arc<dyn Storage>, RvError> {
+        Ok(Arc::new(LegacyPrefixShim {
+            inner: self.inner.snapshot_view()?,
+            mappings: self.mappings.clone(),
+            read_repair: false,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        super::{
+            physical::inmem::InmemBackend,
+            retry::test::MapStorage,
+            test_suite::{run_conformance, run_snapshot_conformance, BackendStorage},
+        },
+        *,
+    };
+
+    #[test]
+    fn test_legacy_prefix_shim() {
+        let storage = LegacyPrefixShim::new(MapStorage::default()).with_mapping("v2/foo/", "foo/");
+
+        let legacy = StorageEntry { key: "foo/a".to_string(), value: "test1".as_bytes().to_vec() };
+        let current = StorageEntry { key: "v2/foo/b".to_string(), value: "test2".as_bytes().to_vec() };
+        assert!(storage.inner().put(&legacy).is_ok());
+        assert!(storage.put(&current).is_ok());
+
+        assert_eq!(storage.list("v2/foo/").unwrap(), vec!["a".to_string(), "b".to_string()]);
+        assert!(storage.exists("v2/foo/a").unwrap());
+
+        // Unmapped keys are not retried
+        assert!(storage.get("bar/a").unwrap().is_none());
+
+        // Deleting under the current layout also removes the legacy entry
+        assert!(storage.delete("v2/foo/a").is_ok());
+        assert!(storage.get("v2/foo/a").unwrap().is_none());
+        assert!(storage.inner().get("foo/a").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_legacy_prefix_shim_snapshot() {
+        let backend = InmemBackend::new();
+        let storage = LegacyPrefixShim::new(BackendStorage(&backend)).with_mapping("v2/foo/", "foo/");
+
+        let legacy = StorageEntry { key: "foo/a".to_string(), value: "test1".as_bytes().to_vec() };
+        let current = StorageEntry { key: "v2/foo/b".to_string(), value: "test2".as_bytes().to_vec() };
+        assert!(storage.inner().put(&legacy).is_ok());
+        assert!(storage.put(&current).is_ok());
+
+        // The streamed listing merges the legacy prefix too
+        let mut names = Vec::new();
+        let ret = storage.list_stream("v2/foo/", &mut |name: &str| {
+            names.push(name.to_string());
+            Ok(true)
+        });
+        assert!(ret.is_ok());
+        assert_eq!(names, vec!["a".to_string(), "b".to_string()]);
+
+        // The snapshot reads the legacy entry without moving it
+        let snapshot = storage.snapshot_view().unwrap();
+        assert_eq!(snapshot.list("v2/foo/").unwrap(), vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(snapshot.get("v2/foo/a").unwrap().unwrap().value, b"test1");
+        assert!(storage.inner().get("foo/a").unwrap().is_some());
+        assert!(storage.inner().get("v2/foo/a").unwrap().is_none());
+
+        // While the storage still repairs it
+        assert_eq!(storage.get("v2/foo/a").unwrap().unwrap().value, b"test1");
+        assert!(storage.inner().get("foo/a").unwrap().is_none());
+        assert_eq!(snapshot.get("v2/foo/a").unwrap().unwrap().value, b"test1");
+    }
+
+    #[test]
+    fn test_legacy_prefix_shim_conformance() {
+        let backend = InmemBackend::new();
+        run_conformance(&LegacyPrefixShim::new(BackendStorage(&backend)).with_mapping("a/", "legacy/"));
+        run_snapshot_conformance(&LegacyPrefixShim::new(BackendStorage(&backend)).with_mapping("a/", "legacy/"));
+    }
+}
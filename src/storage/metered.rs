@@ -0,0 +1,236 @@
+//! The `MeteredStorage` wrapper records the number, the failures and the duration of the storage
+//! operations going through it in a `StorageMetrics`, which can be registered with the metrics
+//! registry or rendered on its own with `StorageMetrics::render_prometheus`.
+//!
+//! It wraps a `Storage` or a physical `Backend` alike. The server wraps the physical backend with
+//! the storage metrics of its `MetricsManager`, below the barrier, so that every read and write of
+//! the vault is recorded once whichever view it went through. The reads of a snapshot are recorded
+//! with the storage's.
+
+use std::{sync::Arc, time::Instant};
+
+use super::{Backend, BackendEntry, ReadConsistency, Storage, StorageEntry};
+use crate::{
+    errors::RvError,
+    metrics::storage_metrics::{StorageMetrics, StorageOp},
+};
+
+pub struct MeteredStorage<S> {
+    inner: S,
+    metrics: Arc<StorageMetrics>,
+}
+
+impl<S> MeteredStorage<S> {
+    pub fn new(inner: S, metrics: Arc<StorageMetrics>) -> Self {
+        Self { inner, metrics }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn metrics(&self) -> &StorageMetrics {
+        &self.metrics
+    }
+
+    fn measure<T>(&self, op: StorageOp, f: impl FnOnce(&S) -> Result<T, RvError>) -> Result<T, RvError> {
+        let start = Instant::now();
+        let ret = f(&self.inner);
+        self.metrics.observe(op, start.elapsed(), ret.is_err());
+        ret
+    }
+}
+
+impl<S: Storage> Storage for MeteredStorage<S> {
+    fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+        self.measure(StorageOp::List, |s| s.list(prefix))
+    }
+
+    fn list_stream(
+        &self,
+        prefix: &str,
+        callback: &mut dyn FnMut(&str) -> Result<bool, RvError>,
+    ) -> Result<(), RvError> {
+        self.measure(StorageOp::List, |s| s.list_stream(prefix, callback))
+    }
+
+    fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+        self.measure(StorageOp::Get, |s| s.get(key))
+    }
+
+    fn get_consistent(&self, key: &str, consistency: ReadConsistency) -> Result<Option<StorageEntry>, RvError> {
+        self.measure(StorageOp::Get, |s| s.get_consistent(key, consistency))
+    }
+
+    fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+        self.measure(StorageOp::Put, |s| s.put(entry))
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RvError> {
+        self.measure(StorageOp::Delete, |s| s.delete(key))
+    }
+
+    fn delete_batch(&self, keys: &[String]) -> Result<(), RvError> {
+        self.measure(StorageOp::DeleteBatch, |s| s.delete_batch(keys))
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, RvError> {
+        self.measure(StorageOp::Exists, |s| s.exists(key))
+    }
+
+    fn count(&self, prefix: &str) -> Result<usize, RvError> {
+        self.measure(StorageOp::Count, |s| s.count(prefix))
+    }
+
+    // Health probes are not storage traffic, they are not recorded.
+    fn health(&self) -> Result<(), RvError> {
+        self.inner.health()
+    }
+
+    fn snapshot_view(&self) -> Result<Arc<dyn Storage>, RvError> {
+        Ok(Arc::new(MeteredStorage::new(self.inner.snapshot_view()?, Arc::clone(&self.metrics))))
+    }
+}
+
+impl<S: Backend> Backend for MeteredStorage<S> {
+    fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+        self.measure(StorageOp::List, |s| s.list(prefix))
+    }
+
+    fn list_stream(
+        &self,
+        prefix: &str,
+        callback: &mut dyn FnMut(&str) -> Result<bool, RvError>,
+    ) -> Result<(), RvError> {
+        self.measure(StorageOp::List, |s| s.list_stream(prefix, callback))
+    }
+
+    fn get(&self, key: &str) -> Result<Option<BackendEntry>, RvError> {
+        self.measure(StorageOp::Get, |s| s.get(key))
+    }
+
+    fn get_consistent(&self, key: &str, consistency: ReadConsistency) -> Result<Option<BackendEntry>, RvError> {
+        self.measure(StorageOp::Get, |s| s.get_consistent(key, consistency))
+    }
+
+    fn put(&self, entry: &BackendEntry) -> Result<(), RvError> {
+        self.measure(StorageOp::Put, |s| s.put(entry))
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RvError> {
+        self.measure(StorageOp::Delete, |s| s.delete(key))
+    }
+
+    fn delete_batch(&self, keys: &[String]) -> Result<(), RvError> {
+        self.measure(StorageOp::DeleteBatch, |s| s.delete_batch(keys))
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, RvError> {
+        self.measure(StorageOp::Exists, |s| s.exists(key))
+    }
+
+    fn count(&self, prefix: &str) -> Result<usize, RvError> {
+        self.measure(StorageOp::Count, |s| s.count(prefix))
+    }
+
+    fn snapshot_view(&self) -> Result<Arc<dyn Backend>, RvError> {
+        Ok(Arc::new(MeteredStorage::new(self.inner.snapshot_view()?, Arc::clone(&self.metrics))))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use prometheus_client::registry::Registry;
+    use regex::Regex;
+
+    use super::{
+        super::{
+            physical::inmem::InmemBackend,
+            retry::test::{FaultInjectStorage, MapStorage},
+        },
+        *,
+    };
+    use crate::metrics::storage_metrics::{STORAGE_ERRORS, STORAGE_OPERATIONS, STORAGE_OPERATION_DURATION_SECONDS};
+
+    // assert_valid_exposition checks every line of the output against the text
+    // exposition format.
+    fn assert_valid_exposition(text: &str) {
+        let comment_re = Regex::new(r"^# (HELP|TYPE|UNIT) [a-zA-Z_:][a-zA-Z0-9_:]* .*$|^# EOF$").unwrap();
+        let sample_re = Regex::new(
+            r#"^[a-zA-Z_:][a-zA-Z0-9_:]*(\{[a-zA-Z_][a-zA-Z0-9_]*="(\\.|[^"\\])*"(,[a-zA-Z_][a-zA-Z0-9_]*="(\\.|[^"\\])*")*\})? (?P<value>\S+)$"#,
+        )
+        .unwrap();
+
+        for line in text.lines() {
+            if line.starts_with('#') {
+                assert!(comment_re.is_match(line), "invalid comment line: {}", line);
+                continue;
+            }
+            let caps = sample_re.captures(line).unwrap_or_else(|| panic!("invalid sample line: {}", line));
+            assert!(caps["value"].parse::<f64>().is_ok(), "invalid sample value: {}", line);
+        }
+    }
+
+    #[test]
+    fn test_metered_storage() {
+        let metrics = Arc::new(StorageMetrics::new(&mut Registry::default()));
+        let storage = MeteredStorage::new(
+            FaultInjectStorage::new(MapStorage::default(), 0, || io::Error::from(io::ErrorKind::TimedOut).into()),
+            Arc::clone(&metrics),
+        );
+
+        let entry = StorageEntry { key: "foo".to_string(), value: "test".as_bytes().to_vec() };
+        assert!(storage.put(&entry).is_ok());
+        assert_eq!(storage.get("foo").unwrap().unwrap(), entry);
+        assert!(storage.get("bar").unwrap().is_none());
+        storage.inner().failures.store(1, std::sync::atomic::Ordering::SeqCst);
+        assert!(storage.delete("foo").is_err());
+
+        let text = metrics.render_prometheus();
+        assert_valid_exposition(&text);
+
+        assert!(text.contains(&format!("# TYPE {} counter", STORAGE_OPERATIONS)));
+        assert!(text.contains(&format!("# TYPE {} counter", STORAGE_ERRORS)));
+        assert!(text.contains(&format!("# TYPE {} histogram", STORAGE_OPERATION_DURATION_SECONDS)));
+        assert!(text.contains("rustyvault_storage_operations_total{op=\"get\"} 2"));
+        assert!(text.contains("rustyvault_storage_operations_total{op=\"put\"} 1"));
+        assert!(text.contains("rustyvault_storage_operations_total{op=\"delete\"} 1"));
+        assert!(text.contains("rustyvault_storage_errors_total{op=\"delete\"} 1"));
+        assert!(!text.contains("rustyvault_storage_errors_total{op=\"get\"}"));
+        assert!(text.contains("rustyvault_storage_operation_duration_seconds_count{op=\"get\"} 2"));
+
+        // Health probes are not recorded
+        assert!(storage.health().is_ok());
+        assert!(!metrics.render_prometheus().contains("op=\"list\""));
+
+        // Counting is recorded as such, not as a listing
+        assert_eq!(storage.count("").unwrap(), 0);
+        let text = metrics.render_prometheus();
+        assert!(text.contains("rustyvault_storage_operations_total{op=\"count\"} 1"));
+        assert!(!text.contains("op=\"list\""));
+    }
+
+    #[test]
+    fn test_metered_backend() {
+        let metrics = Arc::new(StorageMetrics::new(&mut Registry::default()));
+        let backend: Arc<dyn Backend> =
+            Arc::new(MeteredStorage::new(Arc::new(InmemBackend::new()) as Arc<dyn Backend>, Arc::clone(&metrics)));
+
+        let entry = BackendEntry { key: "foo".to_string(), value: "test".as_bytes().to_vec() };
+        assert!(backend.put(&entry).is_ok());
+        assert_eq!(backend.get("foo").unwrap().unwrap(), entry);
+        assert_eq!(backend.list("").unwrap(), vec!["foo"]);
+
+        // The reads of a snapshot are recorded too
+        let snapshot = backend.snapshot_view().unwrap();
+        assert!(snapshot.get("foo").unwrap().is_some());
+
+        let text = metrics.render_prometheus();
+        assert_valid_exposition(&text);
+        assert!(text.contains("rustyvault_storage_operations_total{op=\"put\"} 1"));
+        assert!(text.contains("rustyvault_storage_operations_total{op=\"get\"} 2"));
+        assert!(text.contains("rustyvault_storage_operations_total{op=\"list\"} 1"));
+    }
+}
@@ -0,0 +1,382 @@
+//! The `MirroredStorage` wrapper keeps a warm standby copy of a storage: every write goes to the
+//! primary and is then replicated to the mirror, while every read is answered by the primary.
+//!
+//! In `MirrorMode::Sync` the mirror is written before the operation returns, and a mirror failure
+//! is returned to the caller, although the primary has already been written. In `MirrorMode::Async`
+//! the writes are queued to a background thread, and a mirror failure never reaches the caller.
+//! Either way the failures are counted and reported to the failure hook. A mirror that missed
+//! writes is caught up with `reconcile_mirror`.
+
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Sender},
+        Arc, Mutex, PoisonError,
+    },
+    thread::{self, JoinHandle},
+};
+
+use super::{ReadConsistency, Storage, StorageEntry};
+use crate::errors::RvError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MirrorMode {
+    #[default]
+    Sync,
+    Async,
+}
+
+/// Called with the operation, the key and the error of every failed mirror write.
+pub type MirrorFailureHook = Arc<dyn Fn(&str, &str, &RvError) + Send + Sync>;
+
+enum MirrorWrite {
+    Put(StorageEntry),
+    Delete(String),
+    DeleteBatch(Vec<String>),
+    // Answered once every write queued before it has been applied
+    Flush(Sender<()>),
+}
+
+struct MirrorState<M> {
+    mirror: M,
+    failures: AtomicU64,
+    on_failure: Option<MirrorFailureHook>,
+}
+
+impl<M: Storage> MirrorState<M> {
+    fn apply(&self, write: &MirrorWrite) -> Result<(), RvError> {
+        let (op, key, ret) = match write {
+            MirrorWrite::Put(entry) => ("put", entry.key.as_str(), self.mirror.put(entry)),
+            MirrorWrite::Delete(key) => ("delete", key.as_str(), self.mirror.delete(key)),
+            MirrorWrite::DeleteBatch(keys) => {
+                let first = keys.first().map(String::as_str).unwrap_or("");
+                ("delete_batch", first, self.mirror.delete_batch(keys))
+            }
+            MirrorWrite::Flush(_) => return Ok(()),
+        };
+
+        if let Err(err) = ret.as_ref() {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+            log::warn!("failed to mirror storage write, op: {}, key: {}, err: {}", op, key, err);
+            if let Some(on_failure) = self.on_failure.as_ref() {
+                on_failure(op, key, err);
+            }
+        }
+
+        ret
+    }
+}
+
+pub struct MirroredStorage<P, M> {
+    primary: P,
+    state: Arc<MirrorState<M>>,
+    mode: MirrorMode,
+    queue: Option<Mutex<Sender<MirrorWrite>>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl<P: Storage, M: Storage + 'static> MirroredStorage<P, M> {
+    pub fn new(primary: P, mirror: M, mode: MirrorMode) -> Self {
+        Self::with_failure_hook(primary, mirror, mode, None)
+    }
+
+    pub fn with_failure_hook(primary: P, mirror: M, mode: MirrorMode, on_failure: Option<MirrorFailureHook>) -> Self {
+        let state = Arc::new(MirrorState { mirror, failures: AtomicU64::new(0), on_failure });
+
+        let (queue, worker) = match mode {
+            MirrorMode::Sync => (None, None),
+            MirrorMode::Async => {
+                let (sender, receiver) = mpsc::channel::<MirrorWrite>();
+                let worker_state = Arc::clone(&state);
+                let worker = thread::spawn(move || {
+                    for write in receiver.iter() {
+                        // The failure is already counted and reported
+                        let _ = worker_state.apply(&write);
+                        if let MirrorWrite::Flush(done) = write {
+                            let _ = done.send(());
+                        }
+                    }
+                });
+                (Some(Mutex::new(sender)), Some(worker))
+            }
+        };
+
+        Self { primary, state, mode, queue, worker }
+    }
+
+    pub fn primary(&self) -> &P {
+        &self.primary
+    }
+
+    pub fn mirror(&self) -> &M {
+        &self.state.mirror
+    }
+
+    pub fn mode(&self) -> MirrorMode {
+        self.mode
+    }
+
+    /// Returns the number of mirror writes that failed so far.
+    pub fn failures(&self) -> u64 {
+        self.state.failures.load(Ordering::Relaxed)
+    }
+
+    /// Waits until the mirror has applied every write queued so far. Returns immediately in sync
+    /// mode.
+    pub fn flush(&self) -> Result<(), RvError> {
+        let (done, wait) = mpsc::channel();
+        if self.send(MirrorWrite::Flush(done))? {
+            wait.recv().map_err(|_| RvError::ErrString("the mirror worker has stopped".to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Catches the mirror up with the primary from a snapshot of its keys: every entry of the
+    /// primary is copied over, and the mirror entries missing from the primary are deleted. Writes
+    /// made meanwhile are mirrored as usual. Returns the number of entries copied.
+    pub fn reconcile_mirror(&self) -> Result<usize, RvError> {
+        self.flush()?;
+
+        let mut keys = Vec::new();
+        self.primary.walk("", &mut |key: &str| {
+            keys.push(key.to_string());
+            Ok(())
+        })?;
+
+        let mut copied = 0;
+        for key in keys.iter() {
+            if let Some(entry) = self.primary.get(key)? {
+                self.state.mirror.put(&entry)?;
+                copied += 1;
+            }
+        }
+
+        let keys: HashSet<String> = keys.into_iter().collect();
+        let mut stale = Vec::new();
+        self.state.mirror.walk("", &mut |key: &str| {
+            if !keys.contains(key) {
+                stale.push(key.to_string());
+            }
+            Ok(())
+        })?;
+        for key in stale.iter() {
+            self.state.mirror.delete(key)?;
+        }
+
+        Ok(copied)
+    }
+
+    // send applies the write to the mirror in sync mode, and queues it in async
+    // mode. It returns whether the write was queued.
+    fn send(&self, write: MirrorWrite) -> Result<bool, RvError> {
+        match self.queue.as_ref() {
+            Some(queue) => {
+                queue
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .send(write)
+                    .map_err(|_| RvError::ErrString("the mirror worker has stopped".to_string()))?;
+                Ok(true)
+            }
+            None => self.state.apply(&write).map(|_| false),
+        }
+    }
+}
+
+impl<P, M> Drop for MirroredStorage<P, M> {
+    // Closing the queue lets the worker apply the pending writes and exit.
+    fn drop(&mut self) {
+        self.queue.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl<P: Storage, M: Storage + 'static> Storage for MirroredStorage<P, M> {
+    fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+        self.primary.list(prefix)
+    }
+
+    fn list_stream(
+        &self,
+        prefix: &str,
+        callback: &mut dyn FnMut(&str) -> Result<bool, RvError>,
+    ) -> Result<(), RvError> {
+        self.primary.list_stream(prefix, callback)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+        self.primary.get(key)
+    }
+
+    fn get_consistent(&self, key: &str, consistency: ReadConsistency) -> Result<Option<StorageEntry>, RvError> {
+        self.primary.get_consistent(key, consistency)
+    }
+
+    fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+        self.primary.put(entry)?;
+        self.send(MirrorWrite::Put(entry.clone())).map(|_| ())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RvError> {
+        self.primary.delete(key)?;
+        self.send(MirrorWrite::Delete(key.to_string())).map(|_| ())
+    }
+
+    fn delete_batch(&self, keys: &[String]) -> Result<(), RvError> {
+        self.primary.delete_batch(keys)?;
+        self.send(MirrorWrite::DeleteBatch(keys.to_vec())).map(|_| ())
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, RvError> {
+        self.primary.exists(key)
+    }
+
+    // The mirror is a standby, only the primary has to be reachable.
+    fn health(&self) -> Result<(), RvError> {
+        self.primary.health()
+    }
+
+    fn count(&self, prefix: &str) -> Result<usize, RvError> {
+        self.primary.count(prefix)
+    }
+
+    // A snapshot is only read, and the reads are answered by the primary.
+    fn snapshot_view(&self) -> Result<Arc<dyn Storage>, RvError> {
+        self.primary.snapshot_view()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::AtomicBool;
+
+    use super::{
+        super::{
+            physical::inmem::InmemBackend,
+            retry::test::MapStorage,
+            test_suite::{run_snapshot_conformance, BackendStorage},
+        },
+        *,
+    };
+
+    // A mirror whose writes fail while `down` is set
+    #[derive(Default)]
+    struct FlakyStorage {
+        inner: MapStorage,
+        down: AtomicBool,
+    }
+
+    impl FlakyStorage {
+        fn check(&self) -> Result<(), RvError> {
+            if self.down.load(Ordering::SeqCst) {
+                return Err(RvError::ErrString("mirror is down".to_string()));
+            }
+            Ok(())
+        }
+    }
+
+    impl Storage for FlakyStorage {
+        fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+            self.inner.list(prefix)
+        }
+
+        fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+            self.inner.get(key)
+        }
+
+        fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+            self.check()?;
+            self.inner.put(entry)
+        }
+
+        fn delete(&self, key: &str) -> Result<(), RvError> {
+            self.check()?;
+            self.inner.delete(key)
+        }
+    }
+
+    fn entry(key: &str) -> StorageEntry {
+        StorageEntry { key: key.to_string(), value: key.as_bytes().to_vec() }
+    }
+
+    fn new_mirrored(mode: MirrorMode) -> (MirroredStorage<MapStorage, FlakyStorage>, Arc<Mutex<Vec<String>>>) {
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let hook_reported = Arc::clone(&reported);
+        let hook: MirrorFailureHook = Arc::new(move |op: &str, key: &str, _: &RvError| {
+            hook_reported.lock().unwrap().push(format!("{} {}", op, key));
+        });
+        let storage =
+            MirroredStorage::with_failure_hook(MapStorage::default(), FlakyStorage::default(), mode, Some(hook));
+        (storage, reported)
+    }
+
+    #[test]
+    fn test_mirrored_storage_sync() {
+        let (storage, reported) = new_mirrored(MirrorMode::Sync);
+
+        assert!(storage.put(&entry("a")).is_ok());
+        assert!(storage.put(&entry("b")).is_ok());
+        assert!(storage.delete("b").is_ok());
+        assert_eq!(storage.mirror().get("a").unwrap().unwrap().value, b"a");
+        assert!(!storage.mirror().exists("b").unwrap());
+
+        // A mirror failure is returned, after the primary was written
+        storage.mirror().down.store(true, Ordering::SeqCst);
+        assert_eq!(storage.put(&entry("c")).unwrap_err(), RvError::ErrString("mirror is down".to_string()));
+        assert!(storage.delete("a").is_err());
+        assert!(storage.get("c").unwrap().is_some());
+        assert!(storage.get("a").unwrap().is_none());
+        assert_eq!(storage.failures(), 2);
+        assert_eq!(*reported.lock().unwrap(), vec!["put c", "delete a"]);
+
+        // Reconciling catches the mirror up
+        storage.mirror().down.store(false, Ordering::SeqCst);
+        assert_eq!(storage.reconcile_mirror().unwrap(), 1);
+        assert!(storage.mirror().exists("c").unwrap());
+        assert!(!storage.mirror().exists("a").unwrap());
+    }
+
+    #[test]
+    fn test_mirrored_storage_async() {
+        let (storage, reported) = new_mirrored(MirrorMode::Async);
+
+        assert!(storage.put(&entry("a")).is_ok());
+        assert!(storage.flush().is_ok());
+        assert!(storage.mirror().exists("a").unwrap());
+
+        // Failures do not reach the writer, they are only counted and reported
+        storage.mirror().down.store(true, Ordering::SeqCst);
+        for key in ["b", "c", "d"] {
+            assert!(storage.put(&entry(key)).is_ok());
+        }
+        assert!(storage.delete("a").is_ok());
+        assert!(storage.flush().is_ok());
+        assert_eq!(storage.failures(), 4);
+        assert_eq!(*reported.lock().unwrap(), vec!["put b", "put c", "put d", "delete a"]);
+        assert_eq!(storage.count("").unwrap(), 3);
+        assert_eq!(storage.mirror().count("").unwrap(), 1);
+
+        storage.mirror().down.store(false, Ordering::SeqCst);
+        assert_eq!(storage.reconcile_mirror().unwrap(), 3);
+        let mut keys = storage.mirror().list("").unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["b", "c", "d"]);
+        assert_eq!(storage.failures(), 4);
+    }
+
+    #[test]
+    fn test_mirrored_storage_snapshot() {
+        let (storage, _) = new_mirrored(MirrorMode::Sync);
+        assert_eq!(storage.snapshot_view().err(), Some(RvError::ErrStorageSnapshotUnsupported));
+
+        // The snapshot is the primary's
+        let backend = InmemBackend::new();
+        let storage = MirroredStorage::new(BackendStorage(&backend), MapStorage::default(), MirrorMode::Sync);
+        run_snapshot_conformance(&storage);
+        assert!(storage.mirror().list("").unwrap().is_empty());
+    }
+}
@@ -14,30 +14,282 @@
 //! Typical storage types may be direct file, databases, remote network filesystem and etc.
 //! Different strage types are all as sub-module of this module.
 
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, fmt, sync::Arc, thread, time::Duration};
 
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::errors::RvError;
+use crate::{errors::RvError, utils};
 
+pub mod async_storage;
 pub mod barrier;
 pub mod barrier_aes_gcm;
 pub mod barrier_view;
+pub mod cache;
+pub mod legacy_prefix;
+pub mod metered;
+pub mod mirrored;
 #[cfg(feature = "storage_mysql")]
 pub mod mysql;
+pub mod namespaced;
+pub mod observable;
 pub mod physical;
+pub mod retry;
+pub mod sharded;
+#[cfg(test)]
+pub mod test_suite;
+pub mod timeout;
+pub mod wal;
+
+/// The key probed by the default `Storage::health`. Nothing is ever written to it.
+pub const HEALTH_PROBE_KEY: &str = "core/health-probe";
+
+/// The number of times `Storage::delete_and_confirm` checks for the deleted key, and the wait
+/// before the first check, doubled before each of the following ones.
+pub const DELETE_CONFIRM_ATTEMPTS: u32 = 5;
+pub const DELETE_CONFIRM_BACKOFF: Duration = Duration::from_millis(10);
+
+/// The number of names the native `list_stream` implementations read per round trip, which bounds
+/// the memory a listing holds whatever the number of names under the prefix.
+pub const LIST_STREAM_PAGE_SIZE: usize = 1000;
+
+/// The largest value the barrier accepts by default, see `AESGCMBarrier::set_max_value_size`.
+pub const DEFAULT_MAX_VALUE_SIZE: usize = 1024 * 1024;
+
+/// The consistency requested from a read.
+///
+/// A replicated backend may serve an `Eventual` read from a replica that has not seen the latest
+/// writes yet. A `Strong` read is guaranteed to observe every write that completed before it
+/// started, which is what check-then-write sequences under a lock rely on. Backends without
+/// replication serve both the same way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReadConsistency {
+    #[default]
+    Eventual,
+    Strong,
+}
 
 /// A trait that abstracts core methods for all storage barrier types.
 pub trait Storage: Send + Sync {
+    /// Returns the names directly under `prefix`, relative to it. Deeper keys are collapsed into
+    /// their folder, listed once with a trailing `/`, and a folder disappears with its last key. The
+    /// names must be sorted lexicographically by bytes, the pagination cursors and tidy rely on it.
+    /// A prefix nothing lives under yields an empty list, and the empty prefix lists the root. See
+    /// `test_suite::run_conformance` for the suite an implementation must pass.
     fn list(&self, prefix: &str) -> Result<Vec<String>, RvError>;
     fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError>;
     fn put(&self, entry: &StorageEntry) -> Result<(), RvError>;
     fn delete(&self, key: &str) -> Result<(), RvError>;
+
+    /// Calls `callback` with the names `list` returns, in the same order, until it returns
+    /// `Ok(false)` or fails. Unlike `list`, a backend with server-side cursors does not hold all the
+    /// names at once, see `LIST_STREAM_PAGE_SIZE`, so a flat prefix with millions of children can be
+    /// scanned in bounded memory, and abandoned part way without reading the rest. The default
+    /// falls back to `list`.
+    fn list_stream(
+        &self,
+        prefix: &str,
+        callback: &mut dyn FnMut(&str) -> Result<bool, RvError>,
+    ) -> Result<(), RvError> {
+        for name in self.list(prefix)? {
+            if !callback(&name)? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Like `get`, with the requested read consistency. `get` is an `Eventual` read. Wrappers must
+    /// forward the consistency, and must not answer a `Strong` read from anything that may be stale,
+    /// such as a cache.
+    fn get_consistent(&self, key: &str, consistency: ReadConsistency) -> Result<Option<StorageEntry>, RvError> {
+        let _ = consistency;
+        self.get(key)
+    }
+
+    /// Checks whether an entry exists without returning its value. Implementations should
+    /// override this when presence can be answered more cheaply than a full `get`.
+    fn exists(&self, key: &str) -> Result<bool, RvError> {
+        self.get(key).map(|entry| entry.is_some())
+    }
+
+    /// Deletes `key` and waits until the deletion is visible, for the deletions that must not be
+    /// reported done while the key can still be read, such as revoking a secret. The default checks
+    /// `exists` after the delete, up to `DELETE_CONFIRM_ATTEMPTS` times with a growing wait in
+    /// between, and fails with `ErrStorageDeleteNotConfirmed` if the key never disappears. A
+    /// strongly consistent storage passes the first check, and may override this with a plain delete.
+    fn delete_and_confirm(&self, key: &str) -> Result<(), RvError> {
+        self.delete(key)?;
+
+        let mut backoff = DELETE_CONFIRM_BACKOFF;
+        for attempt in 1..=DELETE_CONFIRM_ATTEMPTS {
+            if !self.exists(key)? {
+                return Ok(());
+            }
+
+            if attempt < DELETE_CONFIRM_ATTEMPTS {
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+        }
+
+        Err(RvError::ErrStorageDeleteNotConfirmed(key.to_string()))
+    }
+
+    /// Deletes the keys in the given order. If it fails part way, the keys before the failing one
+    /// are deleted and the later ones are not, so callers order the keys such that every prefix of
+    /// the batch leaves a consistent state. The default deletes the keys one by one, backends that
+    /// can delete several keys in one round trip should override it.
+    fn delete_batch(&self, keys: &[String]) -> Result<(), RvError> {
+        for key in keys.iter() {
+            self.delete(key)?;
+        }
+
+        Ok(())
+    }
+
+    /// Moves every entry under `from` to the same relative key under `to`, and returns the number
+    /// of entries moved. The default copies all the entries before deleting any of them, so if it
+    /// fails part way every entry is still readable under `from`, and moving again completes the
+    /// move. Backends with a native rename should override it.
+    fn move_prefix(&self, from: &str, to: &str) -> Result<usize, RvError> {
+        if to.starts_with(from) || from.starts_with(to) {
+            return Err(RvError::ErrString(format!("cannot move prefix {} to the overlapping prefix {}", from, to)));
+        }
+
+        let mut keys = Vec::new();
+        self.walk(from, &mut |key: &str| {
+            keys.push(key.to_string());
+            Ok(())
+        })?;
+
+        let mut moved = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(entry) = self.get(&key)? {
+                self.put(&StorageEntry { key: format!("{}{}", to, &key[from.len()..]), value: entry.value })?;
+                moved.push(key);
+            }
+        }
+
+        for key in moved.iter() {
+            self.delete(key)?;
+        }
+
+        Ok(moved.len())
+    }
+
+    /// Checks that the storage is reachable without mutating it. The default probes a reserved
+    /// key, which is safe on an empty store; network backed implementations can override it with
+    /// a cheaper ping.
+    fn health(&self) -> Result<(), RvError> {
+        self.exists(HEALTH_PROBE_KEY).map(|_| ())
+    }
+
+    /// Calls `f` with the full key of every entry under `prefix`, descending into the nested
+    /// prefixes returned by `list_stream`. The order of the keys is unspecified.
+    fn walk(&self, prefix: &str, f: &mut dyn FnMut(&str) -> Result<(), RvError>) -> Result<(), RvError> {
+        let mut paths = vec![prefix.to_string()];
+        while let Some(curr) = paths.pop() {
+            self.list_stream(&curr, &mut |item: &str| {
+                let path = format!("{}{}", curr, item);
+                if item.ends_with('/') {
+                    paths.push(path);
+                } else {
+                    f(&path)?;
+                }
+                Ok(true)
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns a read-only view of the storage as it is now. The writes made to the storage
+    /// afterwards are not visible through the view, and writing to the view fails with
+    /// `ErrStorageSnapshotReadOnly`, so scans such as tidy can run on a consistent state while the
+    /// writes go on. The default fails with `ErrStorageSnapshotUnsupported`, as do the wrappers
+    /// over a backend that can not take snapshots.
+    fn snapshot_view(&self) -> Result<Arc<dyn Storage>, RvError> {
+        Err(RvError::ErrStorageSnapshotUnsupported)
+    }
+
+    /// Returns the number of entries under `prefix`, at any depth. The nested prefixes themselves
+    /// are not entries and are not counted. The default walks the keys, backends that can count
+    /// without listing should override it.
+    fn count(&self, prefix: &str) -> Result<usize, RvError> {
+        let mut count = 0;
+        self.walk(prefix, &mut |_: &str| {
+            count += 1;
+            Ok(())
+        })?;
+
+        Ok(count)
+    }
+
+    /// Returns the sorted full keys matching `pattern`, see `glob_match`. The default walks the
+    /// keys under the literal prefix of the pattern, which is where the first wildcard's segment
+    /// starts.
+    fn list_glob(&self, pattern: &str) -> Result<Vec<String>, RvError> {
+        let literal = &pattern[..pattern.find('*').unwrap_or(pattern.len())];
+        let prefix = &literal[..literal.rfind('/').map_or(0, |i| i + 1)];
+
+        let mut keys = Vec::new();
+        self.walk(prefix, &mut |key: &str| {
+            if glob_match(pattern, key) {
+                keys.push(key.to_string());
+            }
+            Ok(())
+        })?;
+        keys.sort();
+
+        Ok(keys)
+    }
+}
+
+/// Fails with `ErrValueTooLarge` if the value of the entry is longer than `max_bytes`. Zero means
+/// unlimited.
+pub fn check_value_size(entry: &StorageEntry, max_bytes: usize) -> Result<(), RvError> {
+    if max_bytes != 0 && entry.value.len() > max_bytes {
+        return Err(RvError::ErrValueTooLarge { size: entry.value.len(), max: max_bytes });
+    }
+
+    Ok(())
+}
+
+/// Matches a storage key against a glob pattern. A `**` segment matches any number of segments,
+/// including none, and `*` matches any run of characters within a segment.
+pub fn glob_match(pattern: &str, key: &str) -> bool {
+    let pattern: Vec<&str> = pattern.split('/').collect();
+    let key: Vec<&str> = key.split('/').collect();
+    glob_match_segments(&pattern, &key)
+}
+
+fn glob_match_segments(pattern: &[&str], key: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => key.is_empty(),
+        Some((&"**", rest)) => (0..=key.len()).any(|i| glob_match_segments(rest, &key[i..])),
+        Some((segment, rest)) => match key.split_first() {
+            Some((first, key_rest)) => glob_match_segment(segment, first) && glob_match_segments(rest, key_rest),
+            None => false,
+        },
+    }
+}
+
+fn glob_match_segment(pattern: &str, segment: &str) -> bool {
+    let Some((head, tail)) = pattern.split_once('*') else {
+        return pattern == segment;
+    };
+    let Some(rest) = segment.strip_prefix(head) else {
+        return false;
+    };
+
+    // Try every split point for the run matched by the '*'
+    rest.char_indices().map(|(i, _)| i).chain(Some(rest.len())).any(|i| glob_match_segment(tail, &rest[i..]))
 }
 
 /// This struct is used to describe a specific storage entry
-#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Default, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct StorageEntry {
     pub key: String,
@@ -46,18 +298,187 @@ pub struct StorageEntry {
 
 impl StorageEntry {
     pub fn new(k: &str, v: &impl Serialize) -> Result<StorageEntry, RvError> {
+        utils::validate_storage_key(k)?;
+
         let data = serde_json::to_string(v)?;
 
         Ok(StorageEntry { key: k.to_string(), value: data.into_bytes() })
     }
+
+    /// Like `new`, but fails with `ErrValueTooLarge` if the serialized value is longer than
+    /// `max_bytes`.
+    pub fn new_bounded(k: &str, v: &impl Serialize, max_bytes: usize) -> Result<StorageEntry, RvError> {
+        let entry = Self::new(k, v)?;
+        check_value_size(&entry, max_bytes)?;
+        Ok(entry)
+    }
+
+    /// Dumps the entry including its value. Only meant for debugging tests.
+    #[cfg(test)]
+    pub fn debug_full(&self) -> String {
+        format!("StorageEntry {{ key: {:?}, value: {:?} }}", self.key, self.value)
+    }
+}
+
+// The value of an entry may hold secrets, so only its length is printed.
+impl fmt::Debug for StorageEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StorageEntry").field("key", &self.key).field("value_len", &self.value.len()).finish()
+    }
 }
 
 pub trait Backend: Send + Sync {
     //! This trait decsribes the generic methods that a storage backend needs to implement.
+    /// Lists the names under `prefix` as `Storage::list` does, sorted lexicographically by bytes.
     fn list(&self, prefix: &str) -> Result<Vec<String>, RvError>;
     fn get(&self, key: &str) -> Result<Option<BackendEntry>, RvError>;
     fn put(&self, entry: &BackendEntry) -> Result<(), RvError>;
     fn delete(&self, key: &str) -> Result<(), RvError>;
+    /// Streams the names under `prefix` as `Storage::list_stream` does. The default falls back to
+    /// `list`.
+    fn list_stream(
+        &self,
+        prefix: &str,
+        callback: &mut dyn FnMut(&str) -> Result<bool, RvError>,
+    ) -> Result<(), RvError> {
+        for name in self.list(prefix)? {
+            if !callback(&name)? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+    /// Deletes the keys in order, as `Storage::delete_batch` does.
+    fn delete_batch(&self, keys: &[String]) -> Result<(), RvError> {
+        for key in keys.iter() {
+            self.delete(key)?;
+        }
+
+        Ok(())
+    }
+    fn get_consistent(&self, key: &str, consistency: ReadConsistency) -> Result<Option<BackendEntry>, RvError> {
+        let _ = consistency;
+        self.get(key)
+    }
+    fn exists(&self, key: &str) -> Result<bool, RvError> {
+        self.get(key).map(|entry| entry.is_some())
+    }
+    /// Returns a read-only point-in-time view of the backend, as `Storage::snapshot_view` does.
+    fn snapshot_view(&self) -> Result<Arc<dyn Backend>, RvError> {
+        Err(RvError::ErrStorageSnapshotUnsupported)
+    }
+    fn count(&self, prefix: &str) -> Result<usize, RvError> {
+        let mut count = 0;
+        let mut paths = vec![prefix.to_string()];
+        while let Some(curr) = paths.pop() {
+            for item in self.list(&curr)? {
+                if item.ends_with('/') {
+                    paths.push(format!("{}{}", curr, item));
+                } else {
+                    count += 1;
+                }
+            }
+        }
+
+        Ok(count)
+    }
+}
+
+impl<T: Storage + ?Sized> Storage for Arc<T> {
+    fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+        self.as_ref().list(prefix)
+    }
+
+    fn list_stream(
+        &self,
+        prefix: &str,
+        callback: &mut dyn FnMut(&str) -> Result<bool, RvError>,
+    ) -> Result<(), RvError> {
+        self.as_ref().list_stream(prefix, callback)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+        self.as_ref().get(key)
+    }
+
+    fn get_consistent(&self, key: &str, consistency: ReadConsistency) -> Result<Option<StorageEntry>, RvError> {
+        self.as_ref().get_consistent(key, consistency)
+    }
+
+    fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+        self.as_ref().put(entry)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RvError> {
+        self.as_ref().delete(key)
+    }
+
+    fn delete_batch(&self, keys: &[String]) -> Result<(), RvError> {
+        self.as_ref().delete_batch(keys)
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, RvError> {
+        self.as_ref().exists(key)
+    }
+
+    fn health(&self) -> Result<(), RvError> {
+        self.as_ref().health()
+    }
+
+    fn snapshot_view(&self) -> Result<Arc<dyn Storage>, RvError> {
+        self.as_ref().snapshot_view()
+    }
+
+    fn count(&self, prefix: &str) -> Result<usize, RvError> {
+        self.as_ref().count(prefix)
+    }
+}
+
+impl<T: Backend + ?Sized> Backend for Arc<T> {
+    fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+        self.as_ref().list(prefix)
+    }
+
+    fn list_stream(
+        &self,
+        prefix: &str,
+        callback: &mut dyn FnMut(&str) -> Result<bool, RvError>,
+    ) -> Result<(), RvError> {
+        self.as_ref().list_stream(prefix, callback)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<BackendEntry>, RvError> {
+        self.as_ref().get(key)
+    }
+
+    fn get_consistent(&self, key: &str, consistency: ReadConsistency) -> Result<Option<BackendEntry>, RvError> {
+        self.as_ref().get_consistent(key, consistency)
+    }
+
+    fn put(&self, entry: &BackendEntry) -> Result<(), RvError> {
+        self.as_ref().put(entry)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RvError> {
+        self.as_ref().delete(key)
+    }
+
+    fn delete_batch(&self, keys: &[String]) -> Result<(), RvError> {
+        self.as_ref().delete_batch(keys)
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, RvError> {
+        self.as_ref().exists(key)
+    }
+
+    fn snapshot_view(&self) -> Result<Arc<dyn Backend>, RvError> {
+        self.as_ref().snapshot_view()
+    }
+
+    fn count(&self, prefix: &str) -> Result<usize, RvError> {
+        self.as_ref().count(prefix)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -79,6 +500,12 @@ pub fn new_backend(t: &str, conf: &HashMap<String, Value>) -> Result<Arc<dyn Bac
             let backend = mysql::mysql_backend::MysqlBackend::new(conf)?;
             Ok(Arc::new(backend))
         }
+        #[cfg(feature = "storage_s3")]
+        "s3" => {
+            let backend = physical::s3::S3Backend::new(conf)?;
+            Ok(Arc::new(backend))
+        }
+        "inmem" => Ok(Arc::new(physical::inmem::InmemBackend::new())),
         "mock" => Ok(Arc::new(physical::mock::MockBackend::new())),
         _ => Err(RvError::ErrPhysicalTypeInvalid),
     }
@@ -86,15 +513,255 @@ pub fn new_backend(t: &str, conf: &HashMap<String, Value>) -> Result<Arc<dyn Bac
 
 #[cfg(test)]
 pub mod test {
-    use std::{collections::HashMap, env, fs};
+    use std::{
+        collections::HashMap,
+        env, fs,
+        sync::atomic::{AtomicU32, Ordering},
+    };
 
     use serde_json::Value;
 
+    use super::{retry, DELETE_CONFIRM_ATTEMPTS};
     use crate::{
-        storage::{new_backend, Backend, BackendEntry},
-        test_utils::TEST_DIR,
+        errors::RvError,
+        storage::{glob_match, new_backend, Backend, BackendEntry, Storage, StorageEntry},
+        test_utils::{test_rusty_vault_init, TEST_DIR},
     };
 
+    #[test]
+    fn test_storage_entry_debug_redacted() {
+        let secret = "s3cr3t-v4lu3";
+        let entry = StorageEntry { key: "foo/bar".to_string(), value: secret.as_bytes().to_vec() };
+
+        let debug = format!("{:?}", entry);
+        assert!(debug.contains("foo/bar"));
+        assert!(debug.contains(&format!("value_len: {}", secret.len())));
+        assert!(!debug.contains(secret));
+        assert!(!debug.contains(&format!("{:?}", secret.as_bytes())[1..12]));
+
+        let full = entry.debug_full();
+        assert!(full.contains(&format!("{:?}", secret.as_bytes())));
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("auth/approle/role/*/secret-id", "auth/approle/role/role1/secret-id"));
+        assert!(!glob_match("auth/approle/role/*/secret-id", "auth/approle/role/a/b/secret-id"));
+        assert!(!glob_match("auth/approle/role/*/secret-id", "auth/approle/role/role1/secret-id/x"));
+        assert!(glob_match("role/r*1/*", "role/role1/x"));
+        assert!(glob_match("role/*1*/x", "role/1/x"));
+        assert!(!glob_match("role/r*1/x", "role/role2/x"));
+        assert!(glob_match("**", "a/b/c"));
+        assert!(glob_match("a/**/c", "a/c"));
+        assert!(glob_match("a/**/c", "a/b1/b2/c"));
+        assert!(!glob_match("a/**/c", "a/b/d"));
+        assert!(glob_match("a/**", "a/b/c"));
+        assert!(glob_match("a/b", "a/b"));
+        assert!(!glob_match("a/b", "a/bc"));
+    }
+
+    #[test]
+    fn test_storage_list_glob() {
+        let (_root_token, core) = test_rusty_vault_init("test_storage_list_glob");
+        let core = core.read().unwrap();
+        let storage = core.get_system_view().unwrap();
+
+        for key in [
+            "glob/role/role1/secret-id",
+            "glob/role/role2/secret-id",
+            "glob/role/role2/role-id",
+            "glob/role/role3/nested/secret-id",
+            "glob/other/secret-id",
+        ] {
+            let entry = StorageEntry { key: key.to_string(), value: "test".as_bytes().to_vec() };
+            assert!(storage.put(&entry).is_ok());
+        }
+
+        // Single segment wildcards
+        assert_eq!(
+            storage.list_glob("glob/role/*/secret-id").unwrap(),
+            vec!["glob/role/role1/secret-id".to_string(), "glob/role/role2/secret-id".to_string()]
+        );
+        assert_eq!(storage.list_glob("glob/role/role2/*-id").unwrap().len(), 2);
+        assert_eq!(storage.list_glob("glob/role/role1/secret-id").unwrap(), vec!["glob/role/role1/secret-id"]);
+        assert!(storage.list_glob("glob/role/*/no-such-key").unwrap().is_empty());
+
+        // Multi segment wildcards
+        assert_eq!(
+            storage.list_glob("glob/**/secret-id").unwrap(),
+            vec![
+                "glob/other/secret-id".to_string(),
+                "glob/role/role1/secret-id".to_string(),
+                "glob/role/role2/secret-id".to_string(),
+                "glob/role/role3/nested/secret-id".to_string(),
+            ]
+        );
+        assert_eq!(storage.list_glob("glob/role/**").unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_storage_count() {
+        let (_root_token, core) = test_rusty_vault_init("test_storage_count");
+        let core = core.read().unwrap();
+        let storage = core.get_system_view().unwrap();
+
+        for key in ["count/a", "count/b", "count/c/d", "count/c/e/f", "count/c/e/g", "countless/h"] {
+            let entry = StorageEntry { key: key.to_string(), value: "test".as_bytes().to_vec() };
+            assert!(storage.put(&entry).is_ok());
+        }
+
+        // Only the entries count, not the nested prefixes listed along with them
+        assert_eq!(storage.list("count/").unwrap().len(), 3);
+        assert_eq!(storage.count("count/").unwrap(), 5);
+        assert_eq!(storage.count("count/c/").unwrap(), 3);
+        assert_eq!(storage.count("count/c/e/").unwrap(), storage.list("count/c/e/").unwrap().len());
+        assert_eq!(storage.count("count/no-such-prefix/").unwrap(), 0);
+
+        // The walking default agrees with the backend's count
+        let expected = storage.list_glob("count/**").unwrap().len();
+        assert_eq!(storage.count("count/").unwrap(), expected);
+        let map = retry::test::MapStorage::default();
+        for key in ["count/a", "count/c/d", "count/c/e/f"] {
+            assert!(map.put(&StorageEntry { key: key.to_string(), value: vec![] }).is_ok());
+        }
+        assert_eq!(map.count("count/").unwrap(), 3);
+    }
+
+    // Keeps reporting a deleted key as existing for a number of checks, like a
+    // replica catching up with the delete
+    struct LaggingStorage {
+        inner: retry::test::MapStorage,
+        lag: u32,
+        pending: AtomicU32,
+        checks: AtomicU32,
+    }
+
+    impl Storage for LaggingStorage {
+        fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+            self.inner.list(prefix)
+        }
+
+        fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+            self.inner.get(key)
+        }
+
+        fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+            self.inner.put(entry)
+        }
+
+        fn delete(&self, key: &str) -> Result<(), RvError> {
+            self.pending.store(self.lag, Ordering::SeqCst);
+            self.inner.delete(key)
+        }
+
+        fn exists(&self, key: &str) -> Result<bool, RvError> {
+            self.checks.fetch_add(1, Ordering::SeqCst);
+            if self.pending.load(Ordering::SeqCst) > 0 {
+                self.pending.fetch_sub(1, Ordering::SeqCst);
+                return Ok(true);
+            }
+            self.inner.exists(key)
+        }
+    }
+
+    #[test]
+    fn test_storage_delete_and_confirm() {
+        let lagging = |lag| LaggingStorage {
+            inner: retry::test::MapStorage::default(),
+            lag,
+            pending: AtomicU32::new(0),
+            checks: AtomicU32::new(0),
+        };
+        let entry = StorageEntry { key: "foo".to_string(), value: "test".as_bytes().to_vec() };
+
+        // The confirmation waits the lag out
+        let storage = lagging(2);
+        assert!(storage.put(&entry).is_ok());
+        assert!(storage.delete_and_confirm("foo").is_ok());
+        assert_eq!(storage.checks.load(Ordering::SeqCst), 3);
+        assert!(!storage.exists("foo").unwrap());
+
+        // A consistent storage is confirmed by the first check
+        let storage = lagging(0);
+        assert!(storage.put(&entry).is_ok());
+        assert!(storage.delete_and_confirm("foo").is_ok());
+        assert_eq!(storage.checks.load(Ordering::SeqCst), 1);
+
+        // A deletion that never shows up is reported
+        let storage = lagging(DELETE_CONFIRM_ATTEMPTS);
+        assert!(storage.put(&entry).is_ok());
+        assert_eq!(
+            storage.delete_and_confirm("foo").unwrap_err(),
+            RvError::ErrStorageDeleteNotConfirmed("foo".to_string())
+        );
+        assert_eq!(storage.checks.load(Ordering::SeqCst), DELETE_CONFIRM_ATTEMPTS);
+    }
+
+    // Fails every put under a prefix, like a backend going away in the middle of a move
+    struct FailingPutStorage {
+        inner: retry::test::MapStorage,
+        fail_prefix: String,
+    }
+
+    impl Storage for FailingPutStorage {
+        fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+            self.inner.list(prefix)
+        }
+
+        fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+            self.inner.get(key)
+        }
+
+        fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+            if !self.fail_prefix.is_empty() && entry.key.starts_with(&self.fail_prefix) {
+                return Err(RvError::ErrString("put failed".to_string()));
+            }
+            self.inner.put(entry)
+        }
+
+        fn delete(&self, key: &str) -> Result<(), RvError> {
+            self.inner.delete(key)
+        }
+    }
+
+    #[test]
+    fn test_storage_move_prefix() {
+        let (_root_token, core) = test_rusty_vault_init("test_storage_move_prefix");
+        let core = core.read().unwrap();
+        let storage = core.get_system_view().unwrap();
+
+        let keys = ["move/from/a", "move/from/b", "move/from/c/d", "move/from/c/e/f", "move/fromage"];
+        for key in keys {
+            let entry = StorageEntry { key: key.to_string(), value: key.as_bytes().to_vec() };
+            assert!(storage.put(&entry).is_ok());
+        }
+
+        assert_eq!(storage.move_prefix("move/from/", "move/to/").unwrap(), 4);
+        assert_eq!(storage.count("move/from/").unwrap(), 0);
+        assert_eq!(storage.get("move/to/c/e/f").unwrap().unwrap().value, b"move/from/c/e/f");
+        assert_eq!(storage.list_glob("move/to/**").unwrap().len(), 4);
+        assert!(storage.exists("move/fromage").unwrap());
+
+        // Nothing left to move, and prefixes nested in each other are refused
+        assert_eq!(storage.move_prefix("move/from/", "move/to/").unwrap(), 0);
+        assert!(storage.move_prefix("move/to/", "move/to/nested/").is_err());
+        assert!(storage.move_prefix("move/to/", "move/").is_err());
+
+        // A failed copy leaves every entry in place, and moving again completes the move
+        let mut storage =
+            FailingPutStorage { inner: retry::test::MapStorage::default(), fail_prefix: "dst/c/".to_string() };
+        for key in ["src/a", "src/b", "src/c/d"] {
+            assert!(storage.put(&StorageEntry { key: key.to_string(), value: vec![] }).is_ok());
+        }
+        assert!(storage.move_prefix("src/", "dst/").is_err());
+        assert_eq!(storage.count("src/").unwrap(), 3);
+
+        storage.fail_prefix.clear();
+        assert_eq!(storage.move_prefix("src/", "dst/").unwrap(), 3);
+        assert_eq!(storage.count("src/").unwrap(), 0);
+        assert_eq!(storage.count("dst/").unwrap(), 3);
+    }
+
     #[test]
     fn test_new_backend() {
         let dir = env::temp_dir().join(*TEST_DIR).join("new_backend");
@@ -168,6 +835,31 @@ pub mod test {
         assert_eq!(res.unwrap(), None);
     }
 
+    pub fn test_backend_exists(backend: &dyn Backend) {
+        let keys = ["bar", "bar/", "bar/foo", "bar/foo/goo", "baz"];
+        let check = |backend: &dyn Backend| {
+            for key in keys.iter() {
+                assert_eq!(backend.exists(key).unwrap(), backend.get(key).unwrap().is_some(), "key: {}", key);
+            }
+        };
+
+        check(backend);
+        assert!(!backend.exists("bar").unwrap());
+
+        let entry1 = BackendEntry { key: "bar".to_string(), value: "test".as_bytes().to_vec() };
+        let entry2 = BackendEntry { key: "bar/foo".to_string(), value: "test".as_bytes().to_vec() };
+        assert!(backend.put(&entry1).is_ok());
+        assert!(backend.put(&entry2).is_ok());
+        check(backend);
+        assert!(backend.exists("bar").unwrap());
+        assert!(backend.exists("bar/foo").unwrap());
+
+        assert!(backend.delete("bar").is_ok());
+        assert!(backend.delete("bar/foo").is_ok());
+        check(backend);
+        assert!(backend.exists("/bar").is_err());
+    }
+
     pub fn test_backend_list_prefix(backend: &dyn Backend) {
         let entry1 = BackendEntry { key: "bar".to_string(), value: "test".as_bytes().to_vec() };
         let entry2 = BackendEntry { key: "bar/foo".to_string(), value: "test".as_bytes().to_vec() };
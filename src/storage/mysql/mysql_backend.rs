@@ -15,9 +15,13 @@ use crate::{
         vault,
         vault::{dsl::*, vault_key},
     },
-    storage::{Backend, BackendEntry},
+    storage::{Backend, BackendEntry, LIST_STREAM_PAGE_SIZE},
 };
 
+// The number of keys deleted by one statement of delete_batch, keeping the
+// IN list well within the placeholder limit of MySQL.
+const DELETE_BATCH_SIZE: usize = 1000;
+
 pub struct MysqlBackend {
     pool: Arc<Mutex<Pool<ConnectionManager<MysqlConnection>>>>,
 }
@@ -58,6 +62,7 @@ impl Backend for MysqlBackend {
                         }
                     }
                 }
+                keys.sort();
                 return Ok(keys);
             }
             Err(e) => return Err(RvError::ErrDatabaseExecuteEntry { source: (e) }),
@@ -85,6 +90,97 @@ impl Backend for MysqlBackend {
         }
     }
 
+    fn exists(&self, key: &str) -> Result<bool, RvError> {
+        if key.starts_with("/") {
+            return Err(RvError::ErrPhysicalBackendKeyInvalid);
+        }
+
+        let conn: &mut MysqlConnection = &mut self.pool.lock().unwrap().get().unwrap();
+
+        match diesel::select(diesel::dsl::exists(vault.filter(vault_key.eq(key)))).get_result::<bool>(conn) {
+            Ok(found) => return Ok(found),
+            Err(e) => return Err(RvError::ErrDatabaseExecuteEntry { source: (e) }),
+        }
+    }
+
+    // Pages through the keys in the order of the primary key, which compares bytes as vault_key is
+    // a varbinary, each page resuming after the last name of the previous one. The connection goes
+    // back to the pool while the callback runs.
+    fn list_stream(
+        &self,
+        prefix: &str,
+        callback: &mut dyn FnMut(&str) -> Result<bool, RvError>,
+    ) -> Result<(), RvError> {
+        if prefix.starts_with("/") {
+            return Err(RvError::ErrPhysicalBackendPrefixInvalid);
+        }
+
+        let mut cursor = prefix.to_string();
+        let mut inclusive = true;
+        loop {
+            let keys: Vec<String> = {
+                let conn: &mut MysqlConnection = &mut self.pool.lock().unwrap().get().unwrap();
+
+                let query = vault.select(vault_key).filter(vault_key.like(format!("{}%", prefix))).into_boxed();
+                let query = if inclusive {
+                    query.filter(vault_key.ge(cursor.clone()))
+                } else {
+                    query.filter(vault_key.gt(cursor.clone()))
+                };
+
+                match query.order(vault_key.asc()).limit(LIST_STREAM_PAGE_SIZE as i64).load::<String>(conn) {
+                    Ok(keys) => keys,
+                    Err(e) => return Err(RvError::ErrDatabaseExecuteEntry { source: (e) }),
+                }
+            };
+
+            let mut names: Vec<&str> = Vec::new();
+            for key in keys.iter() {
+                let key = &key[prefix.len()..];
+                let name = match key.find('/') {
+                    Some(i) => &key[0..i + 1],
+                    None => key,
+                };
+
+                if names.last() != Some(&name) {
+                    names.push(name);
+                }
+            }
+
+            let Some(last) = names.last() else {
+                return Ok(());
+            };
+            (cursor, inclusive) = match last.strip_suffix('/') {
+                // '0' follows '/', this skips every key of the folder
+                Some(folder) => (format!("{}{}0", prefix, folder), true),
+                None => (format!("{}{}", prefix, last), false),
+            };
+
+            for name in names.iter() {
+                if !callback(name)? {
+                    return Ok(());
+                }
+            }
+
+            if keys.len() < LIST_STREAM_PAGE_SIZE {
+                return Ok(());
+            }
+        }
+    }
+
+    fn count(&self, prefix: &str) -> Result<usize, RvError> {
+        if prefix.starts_with("/") {
+            return Err(RvError::ErrPhysicalBackendPrefixInvalid);
+        }
+
+        let conn: &mut MysqlConnection = &mut self.pool.lock().unwrap().get().unwrap();
+
+        match vault.filter(vault_key.like(format!("{}%", prefix))).count().get_result::<i64>(conn) {
+            Ok(count) => return Ok(count as usize),
+            Err(e) => return Err(RvError::ErrDatabaseExecuteEntry { source: (e) }),
+        }
+    }
+
     fn put(&self, entry: &BackendEntry) -> Result<(), RvError> {
         if entry.key.as_str().starts_with("/") {
             return Err(RvError::ErrPhysicalBackendKeyInvalid);
@@ -112,6 +208,26 @@ impl Backend for MysqlBackend {
             Err(e) => return Err(RvError::ErrDatabaseExecuteEntry { source: (e) }),
         }
     }
+
+    // Every chunk of the batch is one DELETE ... WHERE vault_key IN (...)
+    // statement, and the chunks are deleted in order.
+    fn delete_batch(&self, keys: &[String]) -> Result<(), RvError> {
+        if keys.iter().any(|key| key.starts_with("/")) {
+            return Err(RvError::ErrPhysicalBackendKeyInvalid);
+        }
+
+        let conn: &mut MysqlConnection = &mut self.pool.lock().unwrap().get().unwrap();
+
+        for chunk in keys.chunks(DELETE_BATCH_SIZE) {
+            if let Err(e) =
+                diesel::delete(vault.filter(vault_key.eq_any(chunk.iter().map(String::as_str)))).execute(conn)
+            {
+                return Err(RvError::ErrDatabaseExecuteEntry { source: (e) });
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl MysqlBackend {
@@ -0,0 +1,218 @@
+//! The `NamespacedStorage` wrapper transparently prefixes every key with a namespace, so that
+//! several logically separated vaults can share one physical backend without seeing each other's
+//! data.
+//!
+//! It works for both the `Storage` and the `Backend` traits. When used on a `Backend`, it sits
+//! below the barrier and can be combined with `BarrierView` above it.
+
+use std::sync::Arc;
+
+use super::{Backend, BackendEntry, ReadConsistency, Storage, StorageEntry};
+use crate::errors::RvError;
+
+pub struct NamespacedStorage<S> {
+    inner: S,
+    namespace: String,
+}
+
+impl<S> NamespacedStorage<S> {
+    pub fn new(inner: S, namespace: &str) -> Result<Self, RvError> {
+        if namespace.is_empty() || namespace.starts_with('/') || namespace.contains("..") {
+            return Err(RvError::ErrPhysicalBackendPrefixInvalid);
+        }
+
+        let mut namespace = namespace.to_string();
+        if !namespace.ends_with('/') {
+            namespace.push('/');
+        }
+
+        Ok(Self { inner, namespace })
+    }
+
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn expand_key(&self, key: &str) -> Result<String, RvError> {
+        if key.starts_with('/') {
+            return Err(RvError::ErrPhysicalBackendKeyInvalid);
+        }
+
+        Ok(format!("{}{}", self.namespace, key))
+    }
+
+    fn truncate_key(&self, full: &str) -> String {
+        full.strip_prefix(self.namespace.as_str()).unwrap_or(full).to_string()
+    }
+}
+
+impl<S: Storage> Storage for NamespacedStorage<S> {
+    fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+        let keys = self.inner.list(&self.expand_key(prefix)?)?;
+        Ok(keys.iter().map(|k| self.truncate_key(k)).collect())
+    }
+
+    fn list_stream(
+        &self,
+        prefix: &str,
+        callback: &mut dyn FnMut(&str) -> Result<bool, RvError>,
+    ) -> Result<(), RvError> {
+        self.inner.list_stream(&self.expand_key(prefix)?, &mut |k: &str| callback(&self.truncate_key(k)))
+    }
+
+    fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+        self.get_consistent(key, ReadConsistency::Eventual)
+    }
+
+    fn get_consistent(&self, key: &str, consistency: ReadConsistency) -> Result<Option<StorageEntry>, RvError> {
+        let entry = self.inner.get_consistent(&self.expand_key(key)?, consistency)?;
+        Ok(entry.map(|e| StorageEntry { key: self.truncate_key(&e.key), value: e.value }))
+    }
+
+    fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+        let nested = StorageEntry { key: self.expand_key(&entry.key)?, value: entry.value.clone() };
+        self.inner.put(&nested)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RvError> {
+        self.inner.delete(&self.expand_key(key)?)
+    }
+
+    fn delete_batch(&self, keys: &[String]) -> Result<(), RvError> {
+        let keys = keys.iter().map(|key| self.expand_key(key)).collect::<Result<Vec<_>, _>>()?;
+        self.inner.delete_batch(&keys)
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, RvError> {
+        self.inner.exists(&self.expand_key(key)?)
+    }
+
+    fn count(&self, prefix: &str) -> Result<usize, RvError> {
+        self.inner.count(&self.expand_key(prefix)?)
+    }
+
+    fn health(&self) -> Result<(), RvError> {
+        self.inner.health()
+    }
+
+    fn snapshot_view(&self) -> Result<Arc<dyn Storage>, RvError> {
+        Ok(Arc::new(NamespacedStorage::new(self.inner.snapshot_view()?, &self.namespace)?))
+    }
+}
+
+impl<S: Backend> Backend for NamespacedStorage<S> {
+    fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+        let keys = self.inner.list(&self.expand_key(prefix)?)?;
+        Ok(keys.iter().map(|k| self.truncate_key(k)).collect())
+    }
+
+    fn list_stream(
+        &self,
+        prefix: &str,
+        callback: &mut dyn FnMut(&str) -> Result<bool, RvError>,
+    ) -> Result<(), RvError> {
+        self.inner.list_stream(&self.expand_key(prefix)?, &mut |k: &str| callback(&self.truncate_key(k)))
+    }
+
+    fn get(&self, key: &str) -> Result<Option<BackendEntry>, RvError> {
+        self.get_consistent(key, ReadConsistency::Eventual)
+    }
+
+    fn get_consistent(&self, key: &str, consistency: ReadConsistency) -> Result<Option<BackendEntry>, RvError> {
+        let entry = self.inner.get_consistent(&self.expand_key(key)?, consistency)?;
+        Ok(entry.map(|e| BackendEntry { key: self.truncate_key(&e.key), value: e.value }))
+    }
+
+    fn put(&self, entry: &BackendEntry) -> Result<(), RvError> {
+        let nested = BackendEntry { key: self.expand_key(&entry.key)?, value: entry.value.clone() };
+        self.inner.put(&nested)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RvError> {
+        self.inner.delete(&self.expand_key(key)?)
+    }
+
+    fn delete_batch(&self, keys: &[String]) -> Result<(), RvError> {
+        let keys = keys.iter().map(|key| self.expand_key(key)).collect::<Result<Vec<_>, _>>()?;
+        self.inner.delete_batch(&keys)
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, RvError> {
+        self.inner.exists(&self.expand_key(key)?)
+    }
+
+    fn count(&self, prefix: &str) -> Result<usize, RvError> {
+        self.inner.count(&self.expand_key(prefix)?)
+    }
+
+    fn snapshot_view(&self) -> Result<Arc<dyn Backend>, RvError> {
+        Ok(Arc::new(NamespacedStorage::new(self.inner.snapshot_view()?, &self.namespace)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::{
+        super::{
+            physical::inmem::InmemBackend,
+            test::{test_backend_curd, test_backend_exists, test_backend_list_prefix},
+            test_suite::run_backend_conformance,
+        },
+        *,
+    };
+
+    #[test]
+    fn test_namespaced_backend_isolation() {
+        let physical = Arc::new(InmemBackend::new());
+
+        let ns1 = NamespacedStorage::new(Arc::clone(&physical), "tenant1").unwrap();
+        let ns2 = NamespacedStorage::new(Arc::clone(&physical), "tenant2/").unwrap();
+        assert_eq!(ns1.namespace(), "tenant1/");
+        assert_eq!(ns2.namespace(), "tenant2/");
+
+        // Each namespace behaves like a complete backend on its own
+        test_backend_curd(&ns1);
+        test_backend_list_prefix(&ns1);
+        test_backend_exists(&ns1);
+
+        assert_eq!(ns2.list("").unwrap().len(), 0);
+        assert!(ns2.get("bar").unwrap().is_none());
+        assert!(ns2.get("bar/foo").unwrap().is_none());
+
+        let entry = BackendEntry { key: "bar".to_string(), value: "tenant2".as_bytes().to_vec() };
+        assert!(ns2.put(&entry).is_ok());
+        assert_eq!(ns2.get("bar").unwrap().unwrap(), entry);
+        assert_eq!(ns1.get("bar").unwrap().unwrap().value, "test".as_bytes());
+
+        // Deleting in one namespace leaves the other one untouched
+        assert!(ns2.delete("bar").is_ok());
+        assert!(ns1.get("bar").unwrap().is_some());
+        assert_eq!(ns2.list("").unwrap().len(), 0);
+
+        // Everything lives under the namespace prefix of the shared backend
+        let mut roots = physical.list("").unwrap();
+        roots.sort();
+        assert_eq!(roots, vec!["tenant1/".to_string()]);
+
+        assert!(NamespacedStorage::new(Arc::clone(&physical), "").is_err());
+        assert!(NamespacedStorage::new(Arc::clone(&physical), "/abs").is_err());
+        assert!(NamespacedStorage::new(Arc::clone(&physical), "../up").is_err());
+        assert!(ns1.get("/bar").is_err());
+    }
+
+    #[test]
+    fn test_namespaced_backend_conformance() {
+        let physical = Arc::new(InmemBackend::new());
+        let outside = BackendEntry { key: "tenant0".to_string(), value: Vec::new() };
+        assert!(physical.put(&outside).is_ok());
+
+        run_backend_conformance(&NamespacedStorage::new(Arc::clone(&physical), "tenant1").unwrap());
+        assert_eq!(physical.list("").unwrap(), vec!["tenant0"]);
+    }
+}
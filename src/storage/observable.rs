@@ -0,0 +1,288 @@
+//! The `ObservableStorage` wrapper publishes a `StorageEvent` to an `EventBus` for every `put` and
+//! `delete` going through it, so that integrations such as caches, replicators or indexers can
+//! react to storage mutations.
+//!
+//! An event is only published once the inner storage has accepted the mutation, a failed write
+//! publishes nothing. Events are delivered to the subscribers by a background thread, through a
+//! bounded queue: a slow subscriber never blocks the writers, and when the queue is full the new
+//! events are dropped and counted in `EventBus::dropped`.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Sender, SyncSender, TrySendError},
+        Arc, Mutex, PoisonError, RwLock,
+    },
+    thread::{self, JoinHandle},
+};
+
+use super::{ReadConsistency, Storage, StorageEntry};
+use crate::errors::RvError;
+
+/// The number of events the bus queues for its subscribers by default.
+pub const DEFAULT_EVENT_QUEUE_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageEventKind {
+    Put,
+    Delete,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageEvent {
+    pub key: String,
+    pub kind: StorageEventKind,
+}
+
+pub type EventSubscriber = Box<dyn Fn(&StorageEvent) + Send + Sync>;
+
+enum BusMessage {
+    Event(StorageEvent),
+    // Answered once every event queued before it has been delivered
+    Flush(Sender<()>),
+}
+
+pub struct EventBus {
+    subscribers: Arc<RwLock<Vec<EventSubscriber>>>,
+    queue: Mutex<Option<SyncSender<BusMessage>>>,
+    dropped: AtomicU64,
+    worker: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new(DEFAULT_EVENT_QUEUE_CAPACITY)
+    }
+}
+
+impl EventBus {
+    /// Creates a bus queueing up to `capacity` events for its subscribers.
+    pub fn new(capacity: usize) -> Self {
+        let subscribers: Arc<RwLock<Vec<EventSubscriber>>> = Arc::new(RwLock::new(Vec::new()));
+        let (sender, receiver) = mpsc::sync_channel::<BusMessage>(capacity);
+
+        let worker_subscribers = Arc::clone(&subscribers);
+        let worker = thread::spawn(move || {
+            for message in receiver.iter() {
+                match message {
+                    BusMessage::Event(event) => {
+                        let subscribers = worker_subscribers.read().unwrap_or_else(PoisonError::into_inner);
+                        for subscriber in subscribers.iter() {
+                            subscriber(&event);
+                        }
+                    }
+                    BusMessage::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+
+        Self {
+            subscribers,
+            queue: Mutex::new(Some(sender)),
+            dropped: AtomicU64::new(0),
+            worker: Mutex::new(Some(worker)),
+        }
+    }
+
+    /// Registers a subscriber, which receives the events published from now on.
+    pub fn subscribe(&self, subscriber: EventSubscriber) {
+        self.subscribers.write().unwrap_or_else(PoisonError::into_inner).push(subscriber);
+    }
+
+    /// Queues `event` for the subscribers without waiting, the event is dropped if the queue is full.
+    pub fn publish(&self, event: StorageEvent) {
+        let queue = self.queue.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(sender) = queue.as_ref() else {
+            return;
+        };
+
+        match sender.try_send(BusMessage::Event(event)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(BusMessage::Event(event))) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                log::warn!("storage event queue is full, dropping event, key: {}", event.key);
+            }
+            Err(_) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Returns the number of events dropped so far because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Waits until the subscribers have received every event queued so far.
+    pub fn flush(&self) -> Result<(), RvError> {
+        let stopped = || RvError::ErrString("the storage event bus has stopped".to_string());
+
+        let (done, wait) = mpsc::channel();
+        let sender = self.queue.lock().unwrap_or_else(PoisonError::into_inner).clone().ok_or_else(stopped)?;
+        sender.send(BusMessage::Flush(done)).map_err(|_| stopped())?;
+        wait.recv().map_err(|_| stopped())
+    }
+}
+
+impl Drop for EventBus {
+    // Closing the queue lets the worker deliver the pending events and exit.
+    fn drop(&mut self) {
+        self.queue.lock().unwrap_or_else(PoisonError::into_inner).take();
+        if let Some(worker) = self.worker.lock().unwrap_or_else(PoisonError::into_inner).take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+pub struct ObservableStorage<S> {
+    inner: S,
+    bus: Arc<EventBus>,
+}
+
+impl<S: Storage> ObservableStorage<S> {
+    pub fn new(inner: S, bus: Arc<EventBus>) -> Self {
+        Self { inner, bus }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn bus(&self) -> &EventBus {
+        &self.bus
+    }
+}
+
+impl<S: Storage> Storage for ObservableStorage<S> {
+    fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+        self.inner.list(prefix)
+    }
+
+    fn list_stream(
+        &self,
+        prefix: &str,
+        callback: &mut dyn FnMut(&str) -> Result<bool, RvError>,
+    ) -> Result<(), RvError> {
+        self.inner.list_stream(prefix, callback)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+        self.inner.get(key)
+    }
+
+    fn get_consistent(&self, key: &str, consistency: ReadConsistency) -> Result<Option<StorageEntry>, RvError> {
+        self.inner.get_consistent(key, consistency)
+    }
+
+    fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+        self.inner.put(entry)?;
+        self.bus.publish(StorageEvent { key: entry.key.clone(), kind: StorageEventKind::Put });
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RvError> {
+        self.inner.delete(key)?;
+        self.bus.publish(StorageEvent { key: key.to_string(), kind: StorageEventKind::Delete });
+        Ok(())
+    }
+
+    // One event per key, once the whole batch is deleted.
+    fn delete_batch(&self, keys: &[String]) -> Result<(), RvError> {
+        self.inner.delete_batch(keys)?;
+        for key in keys.iter() {
+            self.bus.publish(StorageEvent { key: key.clone(), kind: StorageEventKind::Delete });
+        }
+        Ok(())
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, RvError> {
+        self.inner.exists(key)
+    }
+
+    fn health(&self) -> Result<(), RvError> {
+        self.inner.health()
+    }
+
+    fn count(&self, prefix: &str) -> Result<usize, RvError> {
+        self.inner.count(prefix)
+    }
+
+    // A snapshot is read-only, so there are no mutations to publish from it.
+    fn snapshot_view(&self) -> Result<Arc<dyn Storage>, RvError> {
+        self.inner.snapshot_view()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use super::{
+        super::retry::test::{FaultInjectStorage, MapStorage},
+        *,
+    };
+
+    fn entry(key: &str) -> StorageEntry {
+        StorageEntry { key: key.to_string(), value: key.as_bytes().to_vec() }
+    }
+
+    #[test]
+    fn test_observable_storage_events() {
+        let bus = Arc::new(EventBus::default());
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let subscriber_seen = Arc::clone(&seen);
+        bus.subscribe(Box::new(move |event: &StorageEvent| subscriber_seen.lock().unwrap().push(event.clone())));
+
+        let storage = ObservableStorage::new(
+            FaultInjectStorage::new(MapStorage::default(), 0, || io::Error::from(io::ErrorKind::TimedOut).into()),
+            Arc::clone(&bus),
+        );
+
+        assert!(storage.put(&entry("foo")).is_ok());
+        assert!(storage.get("foo").unwrap().is_some());
+        assert!(storage.delete("foo").is_ok());
+
+        // A failed write publishes nothing
+        storage.inner().failures.store(1, Ordering::SeqCst);
+        assert!(storage.put(&entry("bar")).is_err());
+
+        assert!(bus.flush().is_ok());
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                StorageEvent { key: "foo".to_string(), kind: StorageEventKind::Put },
+                StorageEvent { key: "foo".to_string(), kind: StorageEventKind::Delete },
+            ]
+        );
+        assert_eq!(bus.dropped(), 0);
+    }
+
+    #[test]
+    fn test_observable_storage_slow_subscriber() {
+        let bus = Arc::new(EventBus::new(1));
+        let (release, gate) = mpsc::channel::<()>();
+        let gate = Mutex::new(gate);
+        let delivered = Arc::new(AtomicU64::new(0));
+        let subscriber_delivered = Arc::clone(&delivered);
+        bus.subscribe(Box::new(move |_: &StorageEvent| {
+            // Blocks until the gate is released
+            let _ = gate.lock().unwrap().recv();
+            subscriber_delivered.fetch_add(1, Ordering::SeqCst);
+        }));
+
+        // The writes go through while the subscriber is stuck, at most one
+        // event is being delivered and one is queued
+        let storage = ObservableStorage::new(MapStorage::default(), Arc::clone(&bus));
+        for i in 0..10 {
+            assert!(storage.put(&entry(&format!("key{}", i))).is_ok());
+        }
+        assert!(bus.dropped() >= 8);
+        assert_eq!(storage.count("").unwrap(), 10);
+
+        drop(release);
+        assert!(bus.flush().is_ok());
+        assert_eq!(delivered.load(Ordering::SeqCst) + bus.dropped(), 10);
+    }
+}
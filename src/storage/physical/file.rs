@@ -1,5 +1,5 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap},
     fs::{self, File},
     io::{self, Read, Write},
     path::PathBuf,
@@ -47,6 +47,7 @@ impl Backend for FileBackend {
                 names.push(name + "/");
             }
         }
+        names.sort();
         Ok(names)
     }
 
@@ -77,6 +78,19 @@ impl Backend for FileBackend {
         }
     }
 
+    fn exists(&self, k: &str) -> Result<bool, RvError> {
+        if k.starts_with('/') {
+            return Err(RvError::ErrPhysicalBackendKeyInvalid);
+        }
+
+        let (path, key) = self.path_key(k);
+        let path = path.join(key);
+
+        let _lock = self.lock.lock().unwrap();
+
+        Ok(path.is_file())
+    }
+
     fn put(&self, entry: &BackendEntry) -> Result<(), RvError> {
         let k = entry.key.as_str();
         if k.starts_with('/') {
@@ -108,8 +122,39 @@ impl Backend for FileBackend {
                 return Err(RvError::from(err));
             }
         }
+
+        self.remove_empty_dirs(path);
         Ok(())
     }
+
+    // The whole batch is deleted under one lock, and the emptied folders are
+    // only removed once it is done, deepest first.
+    fn delete_batch(&self, keys: &[String]) -> Result<(), RvError> {
+        if keys.iter().any(|k| k.starts_with('/')) {
+            return Err(RvError::ErrPhysicalBackendKeyInvalid);
+        }
+
+        let _lock = self.lock.lock().unwrap();
+        let mut dirs: BTreeSet<PathBuf> = BTreeSet::new();
+        let mut ret = Ok(());
+        for k in keys.iter() {
+            let (path, key) = self.path_key(k);
+            match fs::remove_file(path.join(key)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                    ret = Err(RvError::from(err));
+                    break;
+                }
+                _ => {
+                    dirs.insert(path);
+                }
+            }
+        }
+
+        for dir in dirs.into_iter().rev() {
+            self.remove_empty_dirs(dir);
+        }
+        ret
+    }
 }
 
 impl FileBackend {
@@ -127,6 +172,18 @@ impl FileBackend {
         }
     }
 
+    // remove_empty_dirs removes dir and its parents up to the root of the
+    // backend, as long as they are empty, so that a folder is no longer listed
+    // once its last key is deleted.
+    fn remove_empty_dirs(&self, mut dir: PathBuf) {
+        while dir.starts_with(&self.path) && dir != self.path {
+            if fs::remove_dir(&dir).is_err() {
+                break;
+            }
+            dir.pop();
+        }
+    }
+
     fn path_key(&self, k: &str) -> (PathBuf, String) {
         let path = self.path.join(k);
         let parent = path.parent().unwrap().to_owned();
@@ -137,7 +194,10 @@ impl FileBackend {
 
 #[cfg(test)]
 mod test {
-    use super::super::super::test::{test_backend_curd, test_backend_list_prefix};
+    use super::super::super::{
+        test::{test_backend_curd, test_backend_exists, test_backend_list_prefix},
+        test_suite::run_backend_conformance,
+    };
     use crate::test_utils::test_backend;
 
     #[test]
@@ -146,5 +206,12 @@ mod test {
 
         test_backend_curd(backend.as_ref());
         test_backend_list_prefix(backend.as_ref());
+        test_backend_exists(backend.as_ref());
+    }
+
+    #[test]
+    fn test_file_backend_conformance() {
+        let backend = test_backend("test_file_backend_conformance");
+        run_backend_conformance(backend.as_ref());
     }
 }
@@ -0,0 +1,239 @@
+use std::{
+    collections::BTreeMap,
+    ops::Bound,
+    sync::{Arc, RwLock},
+};
+
+use crate::{
+    errors::RvError,
+    storage::{Backend, BackendEntry, LIST_STREAM_PAGE_SIZE},
+};
+
+/// A physical backend that keeps everything in memory. The data is lost once the backend is
+/// dropped, so it is mostly useful for tests and ephemeral setups.
+///
+/// The entries are shared copy-on-write: `snapshot_view` takes a reference to them in constant
+/// time, and the next write after it copies them once before changing anything, leaving the
+/// snapshot as it was.
+#[derive(Debug, Default)]
+pub struct InmemBackend {
+    entries: RwLock<Arc<BTreeMap<String, Vec<u8>>>>,
+    // Set on the snapshots, which reject every write
+    read_only: bool,
+}
+
+impl Backend for InmemBackend {
+    fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+        if prefix.starts_with('/') {
+            return Err(RvError::ErrPhysicalBackendPrefixInvalid);
+        }
+
+        let entries = self.entries.read()?;
+
+        let mut names: Vec<String> = Vec::new();
+        for key in entries.keys().filter(|k| k.starts_with(prefix)) {
+            let name = match key[prefix.len()..].find('/') {
+                Some(i) => &key[prefix.len()..prefix.len() + i + 1],
+                None => &key[prefix.len()..],
+            };
+
+            if names.last().map(|n| n.as_str()) != Some(name) {
+                names.push(name.to_string());
+            }
+        }
+
+        Ok(names)
+    }
+
+    // Reads the names a page at a time, and releases the lock while the callback
+    // runs, so that it may write to the backend. Each page resumes right after
+    // the last name of the previous one, which for a folder is past all of its
+    // keys: '0' is the character following '/'.
+    fn list_stream(
+        &self,
+        prefix: &str,
+        callback: &mut dyn FnMut(&str) -> Result<bool, RvError>,
+    ) -> Result<(), RvError> {
+        if prefix.starts_with('/') {
+            return Err(RvError::ErrPhysicalBackendPrefixInvalid);
+        }
+
+        let mut cursor = Bound::Included(prefix.to_string());
+        loop {
+            let mut names: Vec<String> = Vec::with_capacity(LIST_STREAM_PAGE_SIZE);
+            {
+                let entries = self.entries.read()?;
+                for key in entries.range((cursor.clone(), Bound::Unbounded)).map(|(k, _)| k) {
+                    if !key.starts_with(prefix) || names.len() == LIST_STREAM_PAGE_SIZE {
+                        break;
+                    }
+
+                    let name = match key[prefix.len()..].find('/') {
+                        Some(i) => &key[prefix.len()..prefix.len() + i + 1],
+                        None => &key[prefix.len()..],
+                    };
+
+                    if names.last().map(|n| n.as_str()) != Some(name) {
+                        names.push(name.to_string());
+                    }
+                }
+            }
+
+            let Some(last) = names.last() else {
+                return Ok(());
+            };
+            cursor = match last.strip_suffix('/') {
+                Some(folder) => Bound::Included(format!("{}{}0", prefix, folder)),
+                None => Bound::Excluded(format!("{}{}", prefix, last)),
+            };
+
+            let full = names.len() == LIST_STREAM_PAGE_SIZE;
+            for name in names.iter() {
+                if !callback(name)? {
+                    return Ok(());
+                }
+            }
+
+            if !full {
+                return Ok(());
+            }
+        }
+    }
+
+    fn get(&self, key: &str) -> Result<Option<BackendEntry>, RvError> {
+        if key.starts_with('/') {
+            return Err(RvError::ErrPhysicalBackendKeyInvalid);
+        }
+
+        let entries = self.entries.read()?;
+        Ok(entries.get(key).map(|value| BackendEntry { key: key.to_string(), value: value.clone() }))
+    }
+
+    fn put(&self, entry: &BackendEntry) -> Result<(), RvError> {
+        if entry.key.starts_with('/') {
+            return Err(RvError::ErrPhysicalBackendKeyInvalid);
+        }
+
+        self.check_writable()?;
+        let mut entries = self.entries.write()?;
+        Arc::make_mut(&mut entries).insert(entry.key.clone(), entry.value.clone());
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RvError> {
+        if key.starts_with('/') {
+            return Err(RvError::ErrPhysicalBackendKeyInvalid);
+        }
+
+        self.check_writable()?;
+        let mut entries = self.entries.write()?;
+        Arc::make_mut(&mut entries).remove(key);
+        Ok(())
+    }
+
+    // The whole batch is deleted under one write lock, so no reader observes a part of it.
+    fn delete_batch(&self, keys: &[String]) -> Result<(), RvError> {
+        if keys.iter().any(|key| key.starts_with('/')) {
+            return Err(RvError::ErrPhysicalBackendKeyInvalid);
+        }
+
+        self.check_writable()?;
+        let mut entries = self.entries.write()?;
+        let entries = Arc::make_mut(&mut entries);
+        for key in keys.iter() {
+            entries.remove(key);
+        }
+        Ok(())
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, RvError> {
+        if key.starts_with('/') {
+            return Err(RvError::ErrPhysicalBackendKeyInvalid);
+        }
+
+        Ok(self.entries.read()?.contains_key(key))
+    }
+
+    fn snapshot_view(&self) -> Result<Arc<dyn Backend>, RvError> {
+        let entries = Arc::clone(&self.entries.read()?);
+        Ok(Arc::new(Self { entries: RwLock::new(entries), read_only: true }))
+    }
+}
+
+impl InmemBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn check_writable(&self) -> Result<(), RvError> {
+        if self.read_only {
+            return Err(RvError::ErrStorageSnapshotReadOnly);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        super::super::{
+            test::{test_backend_curd, test_backend_exists, test_backend_list_prefix},
+            test_suite::run_backend_conformance,
+        },
+        *,
+    };
+
+    #[test]
+    fn test_inmem_backend() {
+        let backend = InmemBackend::new();
+
+        test_backend_curd(&backend);
+        test_backend_list_prefix(&backend);
+        test_backend_exists(&backend);
+    }
+
+    #[test]
+    fn test_inmem_backend_list_stream() {
+        let backend = InmemBackend::new();
+
+        // More than a page of names, with folders of several keys straddling
+        // the page boundaries
+        let count = LIST_STREAM_PAGE_SIZE * 2 + 10;
+        for i in 0..count {
+            let key = if i % 7 == 0 { format!("flat/{:05}/{}", i, i % 3) } else { format!("flat/{:05}", i) };
+            assert!(backend.put(&BackendEntry { key, value: vec![] }).is_ok());
+            if i % 7 == 0 {
+                let key = format!("flat/{:05}/x", i);
+                assert!(backend.put(&BackendEntry { key, value: vec![] }).is_ok());
+            }
+        }
+
+        let mut names = Vec::new();
+        let ret = backend.list_stream("flat/", &mut |name: &str| {
+            names.push(name.to_string());
+            Ok(true)
+        });
+        assert!(ret.is_ok());
+        assert_eq!(names.len(), count);
+        assert_eq!(names, backend.list("flat/").unwrap());
+
+        // Stopping early reads no further, and the callback may write to the
+        // backend it streams from
+        let mut seen = 0;
+        let ret = backend.list_stream("flat/", &mut |name: &str| {
+            seen += 1;
+            backend.put(&BackendEntry { key: format!("seen/{}", name), value: vec![] })?;
+            Ok(seen < 5)
+        });
+        assert!(ret.is_ok());
+        assert_eq!(seen, 5);
+        assert_eq!(backend.list("seen/").unwrap().len(), 5);
+    }
+
+    // The in-memory backend is the reference implementation of the suite.
+    #[test]
+    fn test_inmem_backend_conformance() {
+        run_backend_conformance(&InmemBackend::new());
+    }
+}
@@ -1,3 +1,6 @@
 //! The `rusty_vault::storage::physical` module supports to physical file storage.
 pub mod file;
+pub mod inmem;
 pub mod mock;
+#[cfg(feature = "storage_s3")]
+pub mod s3;
@@ -0,0 +1,400 @@
+//! A physical backend keeping every entry as an object of an S3 compatible bucket, such as AWS S3
+//! or MinIO, so that the vault itself can run stateless.
+//!
+//! A key maps to the object of the same name under the configured `prefix`. `list` is a
+//! `ListObjectsV2` request with the `/` delimiter, the common prefixes it returns being the folders.
+//!
+//! `delete_batch` sends a `DeleteObjects` request per 1000 keys. rust-s3 has no multi-object delete,
+//! so the backend signs that request itself, and falls back to one `delete` per key when it has no
+//! credentials to sign with.
+//!
+//! S3 only returns an object as missing with a 404, which `get` and `exists` report as absent. AWS
+//! S3 is strongly consistent, but some compatible stores are not, and may answer a read right
+//! after a write with a 404. On those, set `strong_read_retries` so that `Strong` reads retry a 404
+//! a few times before trusting it.
+//!
+//! The config, from the `storage "s3"` stanza of the server config:
+//!
+//! - `bucket`: the bucket name, required.
+//! - `region`: the region of the bucket, `us-east-1` by default.
+//! - `endpoint`: the endpoint of an S3 compatible store, e.g. `http://127.0.0.1:9000` for MinIO.
+//! - `access_key`, `secret_key`: the credentials. If unset, they are read from the environment or
+//!   the shared AWS credentials file.
+//! - `prefix`: the prefix of every object, so that a bucket can be shared.
+//! - `path_style`: whether to address the bucket in the path rather than in the host name, the
+//!   default when an `endpoint` is set.
+//! - `strong_read_retries`: see above, 0 by default.
+
+use std::{collections::HashMap, thread, time::Duration};
+
+use ::s3::{creds::Credentials, Bucket, Region};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chrono::Utc;
+use openssl::{
+    hash::{hash, MessageDigest},
+    pkey::PKey,
+    sha::sha256,
+    sign::Signer,
+};
+use serde_json::Value;
+use url::Url;
+
+use crate::{
+    errors::RvError,
+    storage::{Backend, BackendEntry, ReadConsistency},
+};
+
+// The wait before the first retry of a strong read, doubled before each of
+// the following ones.
+const STRONG_READ_BACKOFF: Duration = Duration::from_millis(50);
+
+// The number of keys of one DeleteObjects request, the most S3 accepts.
+const DELETE_BATCH_SIZE: usize = 1000;
+
+#[derive(Debug)]
+pub struct S3Backend {
+    bucket: Bucket,
+    // Kept to sign the requests rust-s3 has no support for
+    region: String,
+    credentials: Credentials,
+    prefix: String,
+    strong_read_retries: u32,
+}
+
+impl Backend for S3Backend {
+    fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+        if prefix.starts_with('/') {
+            return Err(RvError::ErrPhysicalBackendPrefixInvalid);
+        }
+
+        let object_prefix = self.object_key(prefix);
+        let pages = self.bucket.list_blocking(object_prefix.clone(), Some("/".to_string()))?;
+
+        let mut names: Vec<String> = Vec::new();
+        for page in pages.iter() {
+            let objects = page.contents.iter().map(|object| object.key.as_str());
+            let folders = page.common_prefixes.iter().flatten().map(|folder| folder.prefix.as_str());
+            for name in objects.chain(folders) {
+                match name.strip_prefix(object_prefix.as_str()) {
+                    Some(name) if !name.is_empty() => names.push(name.to_string()),
+                    _ => {}
+                }
+            }
+        }
+
+        // The objects and the folders come in separate lists, and in pages
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<BackendEntry>, RvError> {
+        self.get_consistent(key, ReadConsistency::Eventual)
+    }
+
+    fn get_consistent(&self, key: &str, consistency: ReadConsistency) -> Result<Option<BackendEntry>, RvError> {
+        if key.starts_with('/') {
+            return Err(RvError::ErrPhysicalBackendKeyInvalid);
+        }
+
+        let retries = match consistency {
+            ReadConsistency::Eventual => 0,
+            ReadConsistency::Strong => self.strong_read_retries,
+        };
+
+        let object_key = self.object_key(key);
+        let mut backoff = STRONG_READ_BACKOFF;
+        for attempt in 0..=retries {
+            let response = self.bucket.get_object_blocking(&object_key)?;
+            match response.status_code() {
+                200 => return Ok(Some(BackendEntry { key: key.to_string(), value: response.as_slice().to_vec() })),
+                404 if attempt < retries => {
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                }
+                404 => return Ok(None),
+                status => return Err(unexpected_status("get", key, status)),
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn put(&self, entry: &BackendEntry) -> Result<(), RvError> {
+        if entry.key.starts_with('/') {
+            return Err(RvError::ErrPhysicalBackendKeyInvalid);
+        }
+
+        let response = self.bucket.put_object_blocking(self.object_key(&entry.key), &entry.value)?;
+        match response.status_code() {
+            200 => Ok(()),
+            status => Err(unexpected_status("put", &entry.key, status)),
+        }
+    }
+
+    // Deleting a missing object is not an error for S3 either.
+    fn delete(&self, key: &str) -> Result<(), RvError> {
+        if key.starts_with('/') {
+            return Err(RvError::ErrPhysicalBackendKeyInvalid);
+        }
+
+        let response = self.bucket.delete_object_blocking(self.object_key(key))?;
+        match response.status_code() {
+            200 | 204 | 404 => Ok(()),
+            status => Err(unexpected_status("delete", key, status)),
+        }
+    }
+
+    // The chunks of the batch are deleted in order, but S3 deletes the objects
+    // of one request in no particular order, so when a request fails any of
+    // its keys may be left.
+    fn delete_batch(&self, keys: &[String]) -> Result<(), RvError> {
+        if keys.iter().any(|key| key.starts_with('/')) {
+            return Err(RvError::ErrPhysicalBackendKeyInvalid);
+        }
+
+        for chunk in keys.chunks(DELETE_BATCH_SIZE) {
+            match (self.credentials.access_key.as_ref(), self.credentials.secret_key.as_ref()) {
+                (Some(access_key), Some(secret_key)) => self.delete_objects(chunk, access_key, secret_key)?,
+                _ => chunk.iter().try_for_each(|key| self.delete(key))?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, RvError> {
+        if key.starts_with('/') {
+            return Err(RvError::ErrPhysicalBackendKeyInvalid);
+        }
+
+        let (_, status) = self.bucket.head_object_blocking(self.object_key(key))?;
+        match status {
+            200 => Ok(true),
+            404 => Ok(false),
+            status => Err(unexpected_status("head", key, status)),
+        }
+    }
+}
+
+impl S3Backend {
+    pub fn new(conf: &HashMap<String, Value>) -> Result<Self, RvError> {
+        let get_str = |name: &str| conf.get(name).and_then(|value| value.as_str()).filter(|value| !value.is_empty());
+
+        let bucket_name = get_str("bucket").ok_or(RvError::ErrPhysicalConfigItemMissing)?;
+        let region_name = get_str("region").unwrap_or("us-east-1");
+        let endpoint = get_str("endpoint");
+
+        let region = match endpoint {
+            Some(endpoint) => Region::Custom { region: region_name.to_string(), endpoint: endpoint.to_string() },
+            None => region_name
+                .parse::<Region>()
+                .map_err(|err| RvError::ErrString(format!("invalid S3 region {}: {}", region_name, err)))?,
+        };
+
+        let credentials = match (get_str("access_key"), get_str("secret_key")) {
+            (Some(access_key), Some(secret_key)) => {
+                Credentials::new(Some(access_key), Some(secret_key), None, None, None)
+            }
+            (None, None) => Credentials::default(),
+            _ => return Err(RvError::ErrPhysicalConfigItemMissing),
+        }
+        .map_err(|err| RvError::ErrString(format!("invalid S3 credentials: {}", err)))?;
+
+        let mut bucket = Bucket::new(bucket_name, region, credentials.clone())?;
+        let path_style = conf.get("path_style").and_then(|value| value.as_bool()).unwrap_or(endpoint.is_some());
+        if path_style {
+            bucket = bucket.with_path_style();
+        }
+
+        let mut prefix = get_str("prefix").unwrap_or_default().trim_start_matches('/').to_string();
+        if !prefix.is_empty() && !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+
+        let strong_read_retries = match conf.get("strong_read_retries") {
+            Some(value) => {
+                value.as_u64().and_then(|n| u32::try_from(n).ok()).ok_or(RvError::ErrPhysicalConfigItemMissing)?
+            }
+            None => 0,
+        };
+
+        Ok(S3Backend { bucket, region: region_name.to_string(), credentials, prefix, strong_read_retries })
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    fn object_key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    // delete_objects deletes keys with one quiet DeleteObjects request, signed
+    // with AWS Signature Version 4. A quiet response only lists the keys that
+    // could not be deleted.
+    fn delete_objects(&self, keys: &[String], access_key: &str, secret_key: &str) -> Result<(), RvError> {
+        let Some(first) = keys.first() else {
+            return Ok(());
+        };
+
+        let mut body = String::from("<Delete><Quiet>true</Quiet>");
+        for key in keys.iter() {
+            body.push_str(&format!("<Object><Key>{}</Key></Object>", xml_escape(&self.object_key(key))));
+        }
+        body.push_str("</Delete>");
+
+        let mut url = Url::parse(&self.bucket.url())?;
+        url.set_query(Some("delete"));
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(RvError::ErrPhysicalConfigItemMissing),
+        };
+
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex::encode(sha256(body.as_bytes()));
+
+        // Sorted by name, as the canonical request requires
+        let mut headers = vec![
+            ("content-md5", STANDARD.encode(hash(MessageDigest::md5(), body.as_bytes())?)),
+            ("host", host),
+            ("x-amz-content-sha256", payload_hash.clone()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = self.credentials.session_token.as_ref().or(self.credentials.security_token.as_ref()) {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+
+        let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+        let canonical_headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value)).collect();
+        let canonical_request =
+            format!("POST\n{}\ndelete=\n{}\n{}\n{}", url.path(), canonical_headers, signed_headers, payload_hash);
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign =
+            format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope, hex::encode(sha256(canonical_request.as_bytes())));
+
+        let mut signing_key = format!("AWS4{}", secret_key).into_bytes();
+        for part in [date.as_str(), self.region.as_str(), "s3", "aws4_request"] {
+            signing_key = hmac_sha256(&signing_key, part)?;
+        }
+        let signature = hex::encode(hmac_sha256(&signing_key, &string_to_sign)?);
+
+        let mut request = ureq::post(url.as_str()).set("content-type", "application/xml").set(
+            "authorization",
+            &format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                access_key, scope, signed_headers, signature
+            ),
+        );
+        for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+            request = request.set(name, value);
+        }
+
+        let response = match request.send_string(&body) {
+            Ok(response) => response,
+            Err(ureq::Error::Status(status, _)) => return Err(unexpected_status("delete_batch", first, status)),
+            Err(err) => return Err(err.into()),
+        };
+        let result = response.into_string()?;
+        if result.contains("<Error>") {
+            return Err(RvError::ErrString(format!("S3 delete_batch of {} failed: {}", first, result)));
+        }
+
+        Ok(())
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Result<Vec<u8>, RvError> {
+    let pkey = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &pkey)?;
+    signer.update(data.as_bytes())?;
+    Ok(signer.sign_to_vec()?)
+}
+
+fn xml_escape(value: &str) -> String {
+    value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+}
+
+fn unexpected_status(op: &str, key: &str, status: u16) -> RvError {
+    RvError::ErrString(format!("S3 {} of {} failed with status {}", op, key, status))
+}
+
+// These tests need an S3 compatible store, a local MinIO by default:
+//
+//   docker run -p 9000:9000 minio/minio server /data
+//
+// with a bucket named rusty-vault-test. RUSTY_VAULT_S3_TEST_ENDPOINT,
+// RUSTY_VAULT_S3_TEST_BUCKET, RUSTY_VAULT_S3_TEST_ACCESS_KEY and
+// RUSTY_VAULT_S3_TEST_SECRET_KEY point them elsewhere.
+#[cfg(test)]
+mod test {
+    use std::{
+        env,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use super::*;
+    use crate::storage::{
+        new_backend,
+        test::{test_backend_curd, test_backend_exists, test_backend_list_prefix},
+        test_suite::run_backend_conformance,
+    };
+
+    fn test_conf(name: &str) -> HashMap<String, Value> {
+        let var = |name: &str, default: &str| env::var(name).unwrap_or_else(|_| default.to_string());
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+
+        let mut conf: HashMap<String, Value> = HashMap::new();
+        conf.insert(
+            "endpoint".to_string(),
+            Value::String(var("RUSTY_VAULT_S3_TEST_ENDPOINT", "http://127.0.0.1:9000")),
+        );
+        conf.insert("bucket".to_string(), Value::String(var("RUSTY_VAULT_S3_TEST_BUCKET", "rusty-vault-test")));
+        conf.insert("access_key".to_string(), Value::String(var("RUSTY_VAULT_S3_TEST_ACCESS_KEY", "minioadmin")));
+        conf.insert("secret_key".to_string(), Value::String(var("RUSTY_VAULT_S3_TEST_SECRET_KEY", "minioadmin")));
+        // Every test gets a fresh, empty prefix
+        conf.insert("prefix".to_string(), Value::String(format!("{}-{}", name, now)));
+        conf
+    }
+
+    #[test]
+    fn test_s3_backend_config() {
+        let conf = test_conf("test_s3_backend_config");
+        let backend = S3Backend::new(&conf).unwrap();
+        assert!(backend.prefix().starts_with("test_s3_backend_config-"));
+        assert!(backend.prefix().ends_with('/'));
+        assert!(new_backend("s3", &conf).is_ok());
+
+        let mut missing_bucket = conf.clone();
+        missing_bucket.remove("bucket");
+        assert!(S3Backend::new(&missing_bucket).is_err());
+
+        let mut half_credentials = conf.clone();
+        half_credentials.remove("secret_key");
+        assert!(S3Backend::new(&half_credentials).is_err());
+    }
+
+    #[test]
+    fn test_s3_backend() {
+        let backend = S3Backend::new(&test_conf("test_s3_backend")).unwrap();
+
+        test_backend_curd(&backend);
+        test_backend_list_prefix(&backend);
+        test_backend_exists(&backend);
+    }
+
+    #[test]
+    fn test_s3_backend_conformance() {
+        let mut conf = test_conf("test_s3_backend_conformance");
+        conf.insert("strong_read_retries".to_string(), Value::from(3));
+        let backend = S3Backend::new(&conf).unwrap();
+
+        run_backend_conformance(&backend);
+
+        // Strong reads of a missing key give up after the retries
+        assert!(backend.get_consistent("missing", ReadConsistency::Strong).unwrap().is_none());
+    }
+}
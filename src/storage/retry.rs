@@ -0,0 +1,397 @@
+//! The `RetryingStorage` wrapper retries storage operations that failed with a transient error,
+//! such as a connection reset by a network backed store.
+//!
+//! Whether an error is transient is decided by a classifier, which defaults to
+//! `is_transient_error` and can be replaced with `with_classifier`. Retries are spaced with an
+//! exponential backoff and capped by `RetryConfig::max_attempts`.
+
+use std::{
+    cell::{Cell, RefCell},
+    io,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use super::{ReadConsistency, Storage, StorageEntry};
+use crate::errors::RvError;
+
+pub type RetryClassifier = Arc<dyn Fn(&RvError) -> bool + Send + Sync>;
+
+#[derive(Debug, Clone)]
+pub struct RetryConfig {
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self { max_attempts: 3, initial_backoff: Duration::from_millis(50), max_backoff: Duration::from_secs(2) }
+    }
+}
+
+impl RetryConfig {
+    // backoff returns the delay to wait after the given failed attempt, starting from 1.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.initial_backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+}
+
+/// The default classifier: only I/O errors of the kinds that usually go away on their own are
+/// retried. Everything else, including not-found and validation errors, is returned as is.
+pub fn is_transient_error(err: &RvError) -> bool {
+    match err {
+        RvError::IO { source } => matches!(
+            source.kind(),
+            io::ErrorKind::Interrupted
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::WouldBlock
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::BrokenPipe
+        ),
+        _ => false,
+    }
+}
+
+pub struct RetryingStorage<S> {
+    inner: S,
+    config: RetryConfig,
+    classify: RetryClassifier,
+}
+
+impl<S: Storage> RetryingStorage<S> {
+    pub fn new(inner: S, config: RetryConfig) -> Self {
+        Self { inner, config, classify: Arc::new(is_transient_error) }
+    }
+
+    /// Replaces the classifier deciding which errors are retried.
+    pub fn with_classifier(mut self, classify: impl Fn(&RvError) -> bool + Send + Sync + 'static) -> Self {
+        self.classify = Arc::new(classify);
+        self
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn config(&self) -> &RetryConfig {
+        &self.config
+    }
+
+    fn retry<T>(&self, op: &str, key: &str, f: impl Fn(&S) -> Result<T, RvError>) -> Result<T, RvError> {
+        let mut attempt = 1;
+        loop {
+            match f(&self.inner) {
+                Err(err) if attempt < self.config.max_attempts && (self.classify)(&err) => {
+                    let backoff = self.config.backoff(attempt);
+                    log::warn!(
+                        "transient storage error, op: {}, key: {}, attempt: {}, retrying in {:?}, err: {}",
+                        op,
+                        key,
+                        attempt,
+                        backoff,
+                        err
+                    );
+                    thread::sleep(backoff);
+                    attempt += 1;
+                }
+                ret => return ret,
+            }
+        }
+    }
+}
+
+impl<S: Storage> Storage for RetryingStorage<S> {
+    fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+        self.retry("list", prefix, |s| s.list(prefix))
+    }
+
+    // Only a failure before the first name reached the callback is retried, a
+    // retry past it would yield the names already seen again.
+    fn list_stream(
+        &self,
+        prefix: &str,
+        callback: &mut dyn FnMut(&str) -> Result<bool, RvError>,
+    ) -> Result<(), RvError> {
+        let delivered = Cell::new(false);
+        let callback = RefCell::new(callback);
+        self.retry("list_stream", prefix, |s| {
+            let ret = s.list_stream(prefix, &mut |name: &str| {
+                delivered.set(true);
+                (*callback.borrow_mut())(name)
+            });
+            match ret {
+                Err(err) if delivered.get() => Ok(Err(err)),
+                ret => ret.map(Ok),
+            }
+        })?
+    }
+
+    fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+        self.retry("get", key, |s| s.get(key))
+    }
+
+    fn get_consistent(&self, key: &str, consistency: ReadConsistency) -> Result<Option<StorageEntry>, RvError> {
+        self.retry("get", key, |s| s.get_consistent(key, consistency))
+    }
+
+    fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+        self.retry("put", &entry.key, |s| s.put(entry))
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RvError> {
+        self.retry("delete", key, |s| s.delete(key))
+    }
+
+    // Deleting a key again is harmless, so a failed batch is retried as a whole.
+    fn delete_batch(&self, keys: &[String]) -> Result<(), RvError> {
+        let first = keys.first().map(String::as_str).unwrap_or("");
+        self.retry("delete_batch", first, |s| s.delete_batch(keys))
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, RvError> {
+        self.retry("exists", key, |s| s.exists(key))
+    }
+
+    fn count(&self, prefix: &str) -> Result<usize, RvError> {
+        self.retry("count", prefix, |s| s.count(prefix))
+    }
+
+    fn health(&self) -> Result<(), RvError> {
+        self.retry("health", "", |s| s.health())
+    }
+
+    // The reads of the snapshot are retried like those of the storage.
+    fn snapshot_view(&self) -> Result<Arc<dyn Storage>, RvError> {
+        let snapshot = self.retry("snapshot_view", "", |s| s.snapshot_view())?;
+        Ok(Arc::new(RetryingStorage {
+            inner: snapshot,
+            config: self.config.clone(),
+            classify: Arc::clone(&self.classify),
+        }))
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::{
+        collections::BTreeMap,
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Mutex,
+        },
+    };
+
+    use super::{
+        super::{
+            physical::inmem::InmemBackend,
+            test_suite::{run_snapshot_conformance, BackendStorage},
+        },
+        *,
+    };
+
+    /// A storage that fails the next `failures` calls with the error produced by `fault`, then
+    /// forwards to the inner storage.
+    pub struct FaultInjectStorage<S> {
+        pub inner: S,
+        pub failures: AtomicU32,
+        pub calls: AtomicU32,
+        pub fault: Mutex<Box<dyn Fn() -> RvError + Send>>,
+    }
+
+    impl<S: Storage> FaultInjectStorage<S> {
+        pub fn new(inner: S, failures: u32, fault: impl Fn() -> RvError + Send + 'static) -> Self {
+            Self {
+                inner,
+                failures: AtomicU32::new(failures),
+                calls: AtomicU32::new(0),
+                fault: Mutex::new(Box::new(fault)),
+            }
+        }
+
+        fn inject(&self) -> Result<(), RvError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if self.failures.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok() {
+                return Err((self.fault.lock().unwrap())());
+            }
+            Ok(())
+        }
+    }
+
+    impl<S: Storage> Storage for FaultInjectStorage<S> {
+        fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+            self.inject()?;
+            self.inner.list(prefix)
+        }
+
+        fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+            self.inject()?;
+            self.inner.get(key)
+        }
+
+        fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+            self.inject()?;
+            self.inner.put(entry)
+        }
+
+        fn delete(&self, key: &str) -> Result<(), RvError> {
+            self.inject()?;
+            self.inner.delete(key)
+        }
+
+        fn delete_batch(&self, keys: &[String]) -> Result<(), RvError> {
+            self.inject()?;
+            self.inner.delete_batch(keys)
+        }
+    }
+
+    #[derive(Default)]
+    pub struct MapStorage(Mutex<BTreeMap<String, Vec<u8>>>);
+
+    impl Storage for MapStorage {
+        fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+            Ok(self.0.lock().unwrap().keys().filter_map(|k| k.strip_prefix(prefix)).map(String::from).collect())
+        }
+
+        fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+            let entries = self.0.lock().unwrap();
+            Ok(entries.get(key).map(|value| StorageEntry { key: key.to_string(), value: value.clone() }))
+        }
+
+        fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+            self.0.lock().unwrap().insert(entry.key.clone(), entry.value.clone());
+            Ok(())
+        }
+
+        fn delete(&self, key: &str) -> Result<(), RvError> {
+            self.0.lock().unwrap().remove(key);
+            Ok(())
+        }
+    }
+
+    fn config(max_attempts: u32) -> RetryConfig {
+        RetryConfig { max_attempts, initial_backoff: Duration::from_millis(1), max_backoff: Duration::from_millis(4) }
+    }
+
+    fn transient() -> RvError {
+        io::Error::from(io::ErrorKind::ConnectionReset).into()
+    }
+
+    #[test]
+    fn test_retrying_storage_recovers() {
+        let storage = RetryingStorage::new(FaultInjectStorage::new(MapStorage::default(), 2, transient), config(3));
+
+        let entry = StorageEntry { key: "foo".to_string(), value: "test".as_bytes().to_vec() };
+        assert!(storage.put(&entry).is_ok());
+        assert_eq!(storage.inner().calls.load(Ordering::SeqCst), 3);
+        assert_eq!(storage.get("foo").unwrap().unwrap(), entry);
+
+        // Attempts are capped
+        storage.inner().failures.store(3, Ordering::SeqCst);
+        storage.inner().calls.store(0, Ordering::SeqCst);
+        assert!(storage.get("foo").is_err());
+        assert_eq!(storage.inner().calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_retrying_storage_non_transient() {
+        let storage = RetryingStorage::new(
+            FaultInjectStorage::new(MapStorage::default(), 2, || RvError::ErrPhysicalBackendKeyInvalid),
+            config(5),
+        );
+        assert!(storage.list("").is_err());
+        assert_eq!(storage.inner().calls.load(Ordering::SeqCst), 1);
+
+        // The classifier can opt such errors in
+        let storage = storage.with_classifier(|err| matches!(err, RvError::ErrPhysicalBackendKeyInvalid));
+        assert!(storage.list("").is_ok());
+        assert_eq!(storage.inner().calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_retrying_storage_list_stream() {
+        let storage = RetryingStorage::new(FaultInjectStorage::new(MapStorage::default(), 2, transient), config(3));
+        for key in ["a", "b"] {
+            assert!(storage.inner().inner.put(&StorageEntry { key: key.to_string(), value: Vec::new() }).is_ok());
+        }
+
+        // Failing before any name was streamed is retried
+        let mut names = Vec::new();
+        let ret = storage.list_stream("", &mut |name: &str| {
+            names.push(name.to_string());
+            Ok(true)
+        });
+        assert!(ret.is_ok());
+        assert_eq!(names, vec!["a", "b"]);
+        assert_eq!(storage.inner().calls.load(Ordering::SeqCst), 3);
+
+        // Failing after is not, even with a transient error
+        storage.inner().calls.store(0, Ordering::SeqCst);
+        let mut names = Vec::new();
+        let ret = storage.list_stream("", &mut |name: &str| {
+            names.push(name.to_string());
+            Err(transient())
+        });
+        assert!(ret.is_err());
+        assert_eq!(names, vec!["a"]);
+        assert_eq!(storage.inner().calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_retrying_storage_delete_batch() {
+        let storage = RetryingStorage::new(FaultInjectStorage::new(MapStorage::default(), 1, transient), config(3));
+        let keys: Vec<String> = ["a", "b", "c"].iter().map(|key| key.to_string()).collect();
+        for key in keys.iter() {
+            assert!(storage.inner().inner.put(&StorageEntry { key: key.clone(), value: Vec::new() }).is_ok());
+        }
+
+        // The batch reaches the inner storage in one call, retried as a whole
+        assert!(storage.delete_batch(&keys).is_ok());
+        assert_eq!(storage.inner().calls.load(Ordering::SeqCst), 2);
+        assert!(storage.inner().inner.list("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_retrying_storage_snapshot() {
+        let storage = RetryingStorage::new(MapStorage::default(), config(3));
+        assert_eq!(storage.snapshot_view().err(), Some(RvError::ErrStorageSnapshotUnsupported));
+
+        let backend = InmemBackend::new();
+        run_snapshot_conformance(&RetryingStorage::new(BackendStorage(&backend), config(3)));
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let config = RetryConfig {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(50),
+        };
+        assert_eq!(config.backoff(1), Duration::from_millis(10));
+        assert_eq!(config.backoff(2), Duration::from_millis(20));
+        assert_eq!(config.backoff(3), Duration::from_millis(40));
+        assert_eq!(config.backoff(4), Duration::from_millis(50));
+        assert_eq!(config.backoff(100), Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_storage_health() {
+        // The default probe is safe on an empty store
+        let storage = MapStorage::default();
+        assert!(storage.health().is_ok());
+        assert!(storage.list("").unwrap().is_empty());
+
+        let storage = FaultInjectStorage::new(MapStorage::default(), 1, transient);
+        assert!(storage.health().is_err());
+        assert!(storage.health().is_ok());
+
+        let storage = RetryingStorage::new(FaultInjectStorage::new(MapStorage::default(), 5, transient), config(3));
+        assert!(storage.health().is_err());
+        assert_eq!(storage.inner().calls.load(Ordering::SeqCst), 3);
+    }
+}
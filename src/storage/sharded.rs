@@ -0,0 +1,309 @@
+//! The `ShardedStorage` adapter distributes the keyspace across several storages, so that the data
+//! of a single vault can outgrow one physical backend.
+//!
+//! Keys are routed with consistent hashing: every shard owns `VIRTUAL_NODES` points on a hash ring,
+//! and a key belongs to the shard owning the first point at or after the key's hash. The ring only
+//! depends on the number of shards and the hash function, so a key never moves unless shards are
+//! added or removed. Shards are identified by their position, appending a shard only moves the keys
+//! it takes over, while removing or reordering shards reshuffles the others too.
+//!
+//! `get`, `put`, `delete` and `exists` go to the owning shard, and `delete_batch` sends every run of
+//! consecutive keys owned by the same shard to it as one batch. `list`, `walk` and `count` fan out
+//! to every shard and merge the results. A folder can hold keys living on several shards, so `list`
+//! returns every folder once, however many shards it was found on. `list_stream` streams that merged
+//! listing, and a single shard's own. A `snapshot_view` is sharded like the storage, over the
+//! snapshots of the shards.
+
+use std::{collections::BTreeSet, sync::Arc};
+
+use blake2b_simd::Params;
+
+use super::{ReadConsistency, Storage, StorageEntry};
+use crate::errors::RvError;
+
+/// The number of points every shard owns on the ring. More points spread the keys more evenly.
+pub const VIRTUAL_NODES: usize = 64;
+
+pub type ShardHasher = Box<dyn Fn(&[u8]) -> u64 + Send + Sync>;
+
+/// Hashes with BLAKE2b, which unlike the std hashers is stable across processes and releases, as
+/// the placement of persisted keys requires.
+pub fn default_shard_hash(data: &[u8]) -> u64 {
+    let hash = Params::new().hash_length(8).hash(data);
+    u64::from_be_bytes(hash.as_bytes().try_into().unwrap_or_default())
+}
+
+pub struct ShardedStorage {
+    shards: Vec<Box<dyn Storage>>,
+    // Shared with the snapshots
+    hasher: Arc<dyn Fn(&[u8]) -> u64 + Send + Sync>,
+    // The points of the ring and the shard owning each of them, sorted by point
+    ring: Vec<(u64, usize)>,
+}
+
+impl ShardedStorage {
+    pub fn new(shards: Vec<Box<dyn Storage>>, hasher: ShardHasher) -> Result<Self, RvError> {
+        if shards.is_empty() {
+            return Err(RvError::ErrPhysicalConfigItemMissing);
+        }
+
+        let mut ring: Vec<(u64, usize)> = (0..shards.len())
+            .flat_map(|shard| (0..VIRTUAL_NODES).map(move |node| (shard, node)))
+            .map(|(shard, node)| (hasher(format!("shard-{}-{}", shard, node).as_bytes()), shard))
+            .collect();
+        ring.sort_unstable();
+
+        Ok(Self { shards, hasher: Arc::from(hasher), ring })
+    }
+
+    pub fn with_default_hash(shards: Vec<Box<dyn Storage>>) -> Result<Self, RvError> {
+        Self::new(shards, Box::new(default_shard_hash))
+    }
+
+    pub fn shards(&self) -> &[Box<dyn Storage>] {
+        &self.shards
+    }
+
+    /// Returns the index of the shard owning `key`.
+    pub fn shard_index(&self, key: &str) -> usize {
+        let hash = (self.hasher)(key.as_bytes());
+        let i = self.ring.partition_point(|(point, _)| *point < hash);
+        self.ring[i % self.ring.len()].1
+    }
+
+    fn shard(&self, key: &str) -> &dyn Storage {
+        self.shards[self.shard_index(key)].as_ref()
+    }
+}
+
+impl Storage for ShardedStorage {
+    fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+        let mut items = BTreeSet::new();
+        for shard in self.shards.iter() {
+            items.extend(shard.list(prefix)?);
+        }
+
+        Ok(items.into_iter().collect())
+    }
+
+    // The names of several shards have to be merged before any is yielded,
+    // only a single shard can stream its own.
+    fn list_stream(
+        &self,
+        prefix: &str,
+        callback: &mut dyn FnMut(&str) -> Result<bool, RvError>,
+    ) -> Result<(), RvError> {
+        if let [shard] = self.shards.as_slice() {
+            return shard.list_stream(prefix, callback);
+        }
+
+        for name in self.list(prefix)? {
+            if !callback(&name)? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+        self.shard(key).get(key)
+    }
+
+    fn get_consistent(&self, key: &str, consistency: ReadConsistency) -> Result<Option<StorageEntry>, RvError> {
+        self.shard(key).get_consistent(key, consistency)
+    }
+
+    fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+        self.shard(&entry.key).put(entry)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RvError> {
+        self.shard(key).delete(key)
+    }
+
+    // Splitting the batch in runs rather than grouping it by shard keeps the
+    // keys deleted in order.
+    fn delete_batch(&self, keys: &[String]) -> Result<(), RvError> {
+        let mut start = 0;
+        while start < keys.len() {
+            let shard = self.shard_index(&keys[start]);
+            let end =
+                keys[start..].iter().position(|key| self.shard_index(key) != shard).map_or(keys.len(), |n| start + n);
+            self.shards[shard].delete_batch(&keys[start..end])?;
+            start = end;
+        }
+
+        Ok(())
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, RvError> {
+        self.shard(key).exists(key)
+    }
+
+    fn health(&self) -> Result<(), RvError> {
+        self.shards.iter().try_for_each(|shard| shard.health())
+    }
+
+    // Every key lives on exactly one shard, so walking the shards one after
+    // the other visits each key once.
+    fn walk(&self, prefix: &str, f: &mut dyn FnMut(&str) -> Result<(), RvError>) -> Result<(), RvError> {
+        self.shards.iter().try_for_each(|shard| shard.walk(prefix, f))
+    }
+
+    fn count(&self, prefix: &str) -> Result<usize, RvError> {
+        self.shards.iter().map(|shard| shard.count(prefix)).sum()
+    }
+
+    // The shards are snapshotted one after the other, a write landing on a
+    // shard in between is seen by the snapshot of that shard only.
+    fn snapshot_view(&self) -> Result<Arc<dyn Storage>, RvError> {
+        let mut shards: Vec<Box<dyn Storage>> = Vec::with_capacity(self.shards.len());
+        for shard in self.shards.iter() {
+            shards.push(Box::new(shard.snapshot_view()?));
+        }
+
+        Ok(Arc::new(ShardedStorage { shards, hasher: Arc::clone(&self.hasher), ring: self.ring.clone() }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, sync::Mutex};
+
+    use super::{
+        super::{
+            physical::inmem::InmemBackend,
+            test_suite::{run_conformance, run_snapshot_conformance, SharedBackendStorage},
+        },
+        *,
+    };
+
+    // A storage listing like the physical backends do, collapsing the nested
+    // keys into their folder.
+    #[derive(Default)]
+    struct FolderStorage(Mutex<BTreeMap<String, Vec<u8>>>);
+
+    impl Storage for FolderStorage {
+        fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+            let entries = self.0.lock().unwrap();
+            let items: BTreeSet<String> = entries
+                .keys()
+                .filter_map(|k| k.strip_prefix(prefix))
+                .map(|rest| match rest.find('/') {
+                    Some(i) => rest[..=i].to_string(),
+                    None => rest.to_string(),
+                })
+                .collect();
+            Ok(items.into_iter().collect())
+        }
+
+        fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+            let entries = self.0.lock().unwrap();
+            Ok(entries.get(key).map(|value| StorageEntry { key: key.to_string(), value: value.clone() }))
+        }
+
+        fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+            self.0.lock().unwrap().insert(entry.key.clone(), entry.value.clone());
+            Ok(())
+        }
+
+        fn delete(&self, key: &str) -> Result<(), RvError> {
+            self.0.lock().unwrap().remove(key);
+            Ok(())
+        }
+    }
+
+    fn new_sharded(count: usize) -> ShardedStorage {
+        let shards = (0..count).map(|_| Box::new(FolderStorage::default()) as Box<dyn Storage>).collect();
+        ShardedStorage::with_default_hash(shards).unwrap()
+    }
+
+    #[test]
+    fn test_sharded_storage_routing() {
+        assert!(ShardedStorage::with_default_hash(Vec::new()).is_err());
+
+        let storage = new_sharded(4);
+        let keys: Vec<String> = (0..200).map(|i| format!("foo/{}", i)).collect();
+
+        // Routing only depends on the key and the shard count
+        let placement: Vec<usize> = keys.iter().map(|key| storage.shard_index(key)).collect();
+        assert_eq!(keys.iter().map(|key| new_sharded(4).shard_index(key)).collect::<Vec<_>>(), placement);
+        for shard in 0..4 {
+            assert!(placement.contains(&shard));
+        }
+
+        for key in keys.iter() {
+            let entry = StorageEntry { key: key.clone(), value: key.as_bytes().to_vec() };
+            assert!(storage.put(&entry).is_ok());
+        }
+        for (key, shard) in keys.iter().zip(placement.iter()) {
+            assert_eq!(storage.get(key).unwrap().unwrap().value, key.as_bytes());
+            assert!(storage.shards()[*shard].exists(key).unwrap());
+            assert_eq!(storage.shards().iter().filter(|s| s.exists(key).unwrap()).count(), 1);
+        }
+
+        assert!(storage.delete("foo/0").is_ok());
+        assert!(!storage.exists("foo/0").unwrap());
+
+        // Appending a shard only moves keys to the new shard
+        let grown = new_sharded(5);
+        for (key, shard) in keys.iter().zip(placement.iter()) {
+            let moved = grown.shard_index(key);
+            assert!(moved == *shard || moved == 4);
+        }
+    }
+
+    #[test]
+    fn test_sharded_storage_merged_listing() {
+        let storage = new_sharded(3);
+        let keys = ["a", "b", "c", "d", "e", "f", "dir/a", "dir/b", "dir/c", "dir/d", "dir/sub/a", "dir/sub/b"];
+        for key in keys.iter() {
+            let entry = StorageEntry { key: key.to_string(), value: Vec::new() };
+            assert!(storage.put(&entry).is_ok());
+        }
+
+        // The folders are spread over several shards, yet listed once
+        let dir_shards: BTreeSet<usize> =
+            keys.iter().filter(|key| key.starts_with("dir/")).map(|key| storage.shard_index(key)).collect();
+        assert!(dir_shards.len() > 1);
+        assert_eq!(storage.list("").unwrap(), vec!["a", "b", "c", "d", "dir/", "e", "f"]);
+        assert_eq!(storage.list("dir/").unwrap(), vec!["a", "b", "c", "d", "sub/"]);
+        assert_eq!(storage.list("dir/sub/").unwrap(), vec!["a", "b"]);
+
+        let mut walked = Vec::new();
+        assert!(storage
+            .walk("dir/", &mut |key: &str| {
+                walked.push(key.to_string());
+                Ok(())
+            })
+            .is_ok());
+        walked.sort();
+        assert_eq!(walked, vec!["dir/a", "dir/b", "dir/c", "dir/d", "dir/sub/a", "dir/sub/b"]);
+
+        assert_eq!(storage.count("").unwrap(), keys.len());
+        assert_eq!(storage.count("dir/sub/").unwrap(), 2);
+        assert_eq!(storage.list_glob("dir/*/a").unwrap(), vec!["dir/sub/a"]);
+    }
+
+    #[test]
+    fn test_sharded_storage_conformance() {
+        run_conformance(&new_sharded(3));
+    }
+
+    #[test]
+    fn test_sharded_storage_snapshot() {
+        assert_eq!(new_sharded(3).snapshot_view().err(), Some(RvError::ErrStorageSnapshotUnsupported));
+
+        let shards =
+            (0..3).map(|_| Box::new(SharedBackendStorage(Arc::new(InmemBackend::new()))) as Box<dyn Storage>).collect();
+        let storage = ShardedStorage::with_default_hash(shards).unwrap();
+        run_snapshot_conformance(&storage);
+
+        // The snapshot routes the keys like the storage
+        let snapshot = storage.snapshot_view().unwrap();
+        assert!(storage.put(&StorageEntry { key: "foo".to_string(), value: Vec::new() }).is_ok());
+        assert!(storage.snapshot_view().unwrap().exists("foo").unwrap());
+        assert!(!snapshot.exists("foo").unwrap());
+    }
+}
@@ -0,0 +1,293 @@
+//! The conformance suite every `Storage` implementation must pass, so that the physical backends
+//! and the wrappers around them can not drift apart in the semantics the rest of the vault relies
+//! on. A backend's test module constructs an empty instance and calls `run_conformance` on it, or
+//! `run_backend_conformance` for a physical `Backend`.
+//!
+//! The semantics the suite checks, in order:
+//!
+//! 1. An empty storage lists nothing, from the root or from any prefix, counts no entry, and
+//!    returns `None` for any key.
+//! 2. A `put` is visible to the next `get` and `exists`, and a second `put` of the same key replaces
+//!    the value instead of adding an entry.
+//! 3. `list` returns the names directly under the prefix, relative to it and sorted by bytes. The
+//!    keys nested deeper are collapsed into their folder, listed once with a trailing `/`. A key and
+//!    a folder of the same name are both listed. A prefix nothing lives under lists nothing.
+//!    `list_stream` yields the same names in the same order, and stops at the first name its
+//!    callback returns `false` for.
+//! 4. `walk` visits every key under the prefix, at any depth, exactly once and by its full key, and
+//!    `count` agrees with it.
+//! 5. Deleting a missing key succeeds. Deleting a key leaves the folder of the same name alone, and
+//!    a folder is no longer listed once its last key is deleted. `delete_batch` deletes every key of
+//!    the batch, the missing ones included.
+//!
+//! The storages able to take snapshots also run `run_snapshot_conformance`: a `snapshot_view` keeps
+//! returning the entries as they were when it was taken, whatever is written to the storage since,
+//! lists and streams them like the storage does, and refuses writes with
+//! `ErrStorageSnapshotReadOnly`.
+
+use std::sync::Arc;
+
+use super::{Backend, BackendEntry, Storage, StorageEntry};
+use crate::errors::RvError;
+
+/// Adapts a physical backend to the `Storage` trait, to run the suite on it.
+pub struct BackendStorage<'a>(pub &'a dyn Backend);
+
+impl Storage for BackendStorage<'_> {
+    fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+        self.0.list(prefix)
+    }
+
+    fn list_stream(
+        &self,
+        prefix: &str,
+        callback: &mut dyn FnMut(&str) -> Result<bool, RvError>,
+    ) -> Result<(), RvError> {
+        self.0.list_stream(prefix, callback)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+        Ok(self.0.get(key)?.map(|entry| StorageEntry { key: entry.key, value: entry.value }))
+    }
+
+    fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+        self.0.put(&BackendEntry { key: entry.key.clone(), value: entry.value.clone() })
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RvError> {
+        self.0.delete(key)
+    }
+
+    fn delete_batch(&self, keys: &[String]) -> Result<(), RvError> {
+        self.0.delete_batch(keys)
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, RvError> {
+        self.0.exists(key)
+    }
+
+    fn snapshot_view(&self) -> Result<Arc<dyn Storage>, RvError> {
+        Ok(Arc::new(SharedBackendStorage(self.0.snapshot_view()?)))
+    }
+}
+
+/// `BackendStorage` owning a share of the backend, for the wrappers that need a `'static` storage
+/// and for the snapshots of a backend.
+pub struct SharedBackendStorage(pub Arc<dyn Backend>);
+
+impl Storage for SharedBackendStorage {
+    fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+        BackendStorage(self.0.as_ref()).list(prefix)
+    }
+
+    fn list_stream(
+        &self,
+        prefix: &str,
+        callback: &mut dyn FnMut(&str) -> Result<bool, RvError>,
+    ) -> Result<(), RvError> {
+        BackendStorage(self.0.as_ref()).list_stream(prefix, callback)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+        BackendStorage(self.0.as_ref()).get(key)
+    }
+
+    fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+        BackendStorage(self.0.as_ref()).put(entry)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RvError> {
+        BackendStorage(self.0.as_ref()).delete(key)
+    }
+
+    fn delete_batch(&self, keys: &[String]) -> Result<(), RvError> {
+        BackendStorage(self.0.as_ref()).delete_batch(keys)
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, RvError> {
+        BackendStorage(self.0.as_ref()).exists(key)
+    }
+
+    fn snapshot_view(&self) -> Result<Arc<dyn Storage>, RvError> {
+        BackendStorage(self.0.as_ref()).snapshot_view()
+    }
+}
+
+// Written out of order, with names sorting on both sides of the `/` separator
+const KEYS: [&str; 10] = ["b", "a0", "a/z", "a-b", "a/x/1", "a/x/0", "a", "c/d/e", "a/y", "B"];
+
+/// Runs the whole suite on `storage`, which must be empty.
+pub fn run_conformance(storage: &dyn Storage) {
+    check_empty(storage);
+    check_put_get(storage);
+    check_list(storage);
+    check_walk(storage);
+    check_delete(storage);
+}
+
+/// Checks the snapshots of `storage`, which must be empty and able to take them.
+pub fn run_snapshot_conformance(storage: &dyn Storage) {
+    for key in KEYS.iter() {
+        assert!(storage.put(&entry(key, "before")).is_ok(), "key: {}", key);
+    }
+
+    let snapshot = storage.snapshot_view().unwrap();
+    assert!(storage.put(&entry("a/x/2", "after")).is_ok());
+    assert!(storage.put(&entry("a", "after")).is_ok());
+    assert!(storage.delete("b").is_ok());
+
+    // The snapshot does not see the writes made since it was taken
+    assert_eq!(snapshot.get("a").unwrap().unwrap().value, b"before");
+    assert!(snapshot.get("a/x/2").unwrap().is_none());
+    assert!(snapshot.exists("b").unwrap());
+    assert_eq!(snapshot.list("a/x/").unwrap(), vec!["0", "1"]);
+    assert_eq!(snapshot.list("").unwrap(), vec!["B", "a", "a-b", "a/", "a0", "b", "c/"]);
+    for prefix in ["", "a/", "a/x/", "c/", "missing/"] {
+        assert_eq!(
+            streamed(snapshot.as_ref(), prefix, usize::MAX),
+            snapshot.list(prefix).unwrap(),
+            "prefix: {}",
+            prefix
+        );
+    }
+    assert_eq!(streamed(snapshot.as_ref(), "", 3), vec!["B", "a", "a-b"]);
+    let mut all: Vec<String> = KEYS.iter().map(|key| key.to_string()).collect();
+    all.sort();
+    assert_eq!(walked(snapshot.as_ref(), ""), all);
+    assert_eq!(snapshot.count("").unwrap(), KEYS.len());
+
+    // While the storage does
+    assert_eq!(storage.get("a").unwrap().unwrap().value, b"after");
+    assert_eq!(storage.list("a/x/").unwrap(), vec!["0", "1", "2"]);
+    assert!(!storage.exists("b").unwrap());
+
+    assert_eq!(snapshot.put(&entry("x", "x")).err(), Some(RvError::ErrStorageSnapshotReadOnly));
+    assert_eq!(snapshot.delete("a").err(), Some(RvError::ErrStorageSnapshotReadOnly));
+
+    let batch: Vec<String> = KEYS.iter().map(|key| key.to_string()).chain(Some("a/x/2".to_string())).collect();
+    assert!(storage.delete_batch(&batch).is_ok());
+    check_empty(storage);
+}
+
+/// `run_conformance`, for physical backends.
+pub fn run_backend_conformance(backend: &dyn Backend) {
+    run_conformance(&BackendStorage(backend));
+}
+
+fn entry(key: &str, value: &str) -> StorageEntry {
+    StorageEntry { key: key.to_string(), value: value.as_bytes().to_vec() }
+}
+
+fn walked(storage: &dyn Storage, prefix: &str) -> Vec<String> {
+    let mut keys = Vec::new();
+    storage
+        .walk(prefix, &mut |key: &str| {
+            keys.push(key.to_string());
+            Ok(())
+        })
+        .unwrap();
+    keys.sort();
+    keys
+}
+
+// streamed returns the names list_stream yields until the callback has seen
+// limit of them.
+fn streamed(storage: &dyn Storage, prefix: &str, limit: usize) -> Vec<String> {
+    let mut names = Vec::new();
+    storage
+        .list_stream(prefix, &mut |name: &str| {
+            names.push(name.to_string());
+            Ok(names.len() < limit)
+        })
+        .unwrap();
+    names
+}
+
+fn check_empty(storage: &dyn Storage) {
+    assert_eq!(storage.list("").unwrap(), Vec::<String>::new());
+    assert_eq!(storage.list("missing/").unwrap(), Vec::<String>::new());
+    assert_eq!(storage.count("").unwrap(), 0);
+    assert!(walked(storage, "").is_empty());
+    assert!(storage.get("missing").unwrap().is_none());
+    assert!(!storage.exists("missing").unwrap());
+}
+
+fn check_put_get(storage: &dyn Storage) {
+    for key in KEYS.iter() {
+        assert!(storage.put(&entry(key, "first")).is_ok(), "key: {}", key);
+    }
+    for key in KEYS.iter() {
+        assert_eq!(storage.get(key).unwrap().unwrap().value, b"first", "key: {}", key);
+        assert!(storage.exists(key).unwrap(), "key: {}", key);
+    }
+
+    // Overwriting replaces the value in place
+    for key in KEYS.iter() {
+        assert!(storage.put(&entry(key, key)).is_ok(), "key: {}", key);
+    }
+    for key in KEYS.iter() {
+        let stored = storage.get(key).unwrap().unwrap();
+        assert_eq!(stored.key, *key);
+        assert_eq!(stored.value, key.as_bytes());
+    }
+    assert_eq!(storage.count("").unwrap(), KEYS.len());
+}
+
+fn check_list(storage: &dyn Storage) {
+    assert_eq!(storage.list("").unwrap(), vec!["B", "a", "a-b", "a/", "a0", "b", "c/"]);
+    assert_eq!(storage.list("a/").unwrap(), vec!["x/", "y", "z"]);
+    assert_eq!(storage.list("a/x/").unwrap(), vec!["0", "1"]);
+    assert_eq!(storage.list("c/").unwrap(), vec!["d/"]);
+    assert_eq!(storage.list("c/d/").unwrap(), vec!["e"]);
+    assert_eq!(storage.list("b/").unwrap(), Vec::<String>::new());
+    assert_eq!(storage.list("missing/").unwrap(), Vec::<String>::new());
+
+    for prefix in ["", "a/", "a/x/", "c/", "missing/"] {
+        assert_eq!(streamed(storage, prefix, usize::MAX), storage.list(prefix).unwrap(), "prefix: {}", prefix);
+    }
+    assert_eq!(streamed(storage, "", 3), vec!["B", "a", "a-b"]);
+    assert_eq!(streamed(storage, "a/", 1), vec!["x/"]);
+}
+
+fn check_walk(storage: &dyn Storage) {
+    let mut all: Vec<String> = KEYS.iter().map(|key| key.to_string()).collect();
+    all.sort();
+    assert_eq!(walked(storage, ""), all);
+    assert_eq!(walked(storage, "a/"), vec!["a/x/0", "a/x/1", "a/y", "a/z"]);
+    assert_eq!(walked(storage, "c/d/"), vec!["c/d/e"]);
+    assert!(walked(storage, "missing/").is_empty());
+
+    assert_eq!(storage.count("a/").unwrap(), 4);
+    assert_eq!(storage.count("a/x/").unwrap(), 2);
+    assert_eq!(storage.count("missing/").unwrap(), 0);
+}
+
+fn check_delete(storage: &dyn Storage) {
+    assert!(storage.delete("missing").is_ok());
+    assert!(storage.delete("missing/nested").is_ok());
+
+    // A folder goes away with its last key
+    assert!(storage.delete("c/d/e").is_ok());
+    assert!(storage.get("c/d/e").unwrap().is_none());
+    assert_eq!(storage.list("").unwrap(), vec!["B", "a", "a-b", "a/", "a0", "b"]);
+    assert_eq!(storage.list("c/").unwrap(), Vec::<String>::new());
+
+    // Deleting a key leaves the folder of the same name alone
+    assert!(storage.delete("a").is_ok());
+    assert!(!storage.exists("a").unwrap());
+    assert_eq!(storage.list("").unwrap(), vec!["B", "a-b", "a/", "a0", "b"]);
+    assert_eq!(storage.list("a/").unwrap(), vec!["x/", "y", "z"]);
+
+    // A batch deletes all of its keys, missing or not
+    let batch: Vec<String> = KEYS.iter().map(|key| key.to_string()).chain(Some("missing".to_string())).collect();
+    assert!(storage.delete_batch(&batch).is_ok());
+    assert!(storage.delete_batch(&[]).is_ok());
+    check_empty(storage);
+
+    // Deleting twice is fine too
+    for key in KEYS.iter() {
+        assert!(storage.delete(key).is_ok(), "key: {}", key);
+    }
+    assert!(storage.delete("a/x/0").is_ok());
+    check_empty(storage);
+}
@@ -0,0 +1,413 @@
+//! The `TimeoutStorage` wrapper bounds how long a storage operation may take, so that a hung
+//! network backend fails the request with `ErrStorageTimeout` instead of blocking it forever.
+//!
+//! The `Storage` trait is synchronous and a call in progress can not be cancelled, so every call
+//! runs on a worker thread while the caller waits on a channel for at most the timeout of the
+//! operation. This comes with tradeoffs:
+//!
+//! - Every call spawns a thread. Next to a network round trip that is cheap, but not free, so the
+//!   wrapper belongs in front of remote backends. A zero timeout runs the call inline instead.
+//! - A call that timed out keeps running on its worker until the inner storage returns, and its
+//!   result is dropped. A read has no effect of its own, so giving up on it leaves nothing behind.
+//!   A write that timed out may still be applied afterwards: its outcome is unknown rather than
+//!   failed, and the caller should read the key back or repeat the write, which is idempotent for
+//!   `put` and `delete`, rather than assume it did not happen.
+//! - Every abandoned call holds a thread. Once `TimeoutConfig::max_abandoned` of them are still
+//!   running, the backend is taken as hung and further calls fail right away instead of piling up
+//!   more threads.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use super::{ReadConsistency, Storage, StorageEntry};
+use crate::errors::RvError;
+
+// The number of names a list_stream worker may run ahead of the caller.
+const LIST_STREAM_BUFFER: usize = 64;
+
+/// The timeout of each operation. A zero timeout disables it for that operation.
+#[derive(Debug, Clone)]
+pub struct TimeoutConfig {
+    /// Bounds the wait for each name of `list_stream`, rather than the whole listing.
+    pub list: Duration,
+    /// Also bounds `snapshot_view`.
+    pub get: Duration,
+    pub put: Duration,
+    /// Also bounds every `delete_batch` as a whole.
+    pub delete: Duration,
+    /// Also bounds `health`.
+    pub exists: Duration,
+    pub count: Duration,
+    /// How many timed out calls may still be running before further calls fail right away.
+    pub max_abandoned: usize,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self::uniform(Duration::from_secs(10))
+    }
+}
+
+impl TimeoutConfig {
+    /// The same timeout for every operation.
+    pub fn uniform(timeout: Duration) -> Self {
+        Self {
+            list: timeout,
+            get: timeout,
+            put: timeout,
+            delete: timeout,
+            exists: timeout,
+            count: timeout,
+            max_abandoned: 64,
+        }
+    }
+}
+
+pub struct TimeoutStorage<S> {
+    inner: Arc<S>,
+    config: TimeoutConfig,
+    abandoned: Arc<AtomicUsize>,
+}
+
+impl<S: Storage + 'static> TimeoutStorage<S> {
+    pub fn new(inner: S, config: TimeoutConfig) -> Self {
+        Self { inner: Arc::new(inner), config, abandoned: Arc::new(AtomicUsize::new(0)) }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn config(&self) -> &TimeoutConfig {
+        &self.config
+    }
+
+    /// The number of calls that timed out and are still running on the inner storage.
+    pub fn abandoned(&self) -> usize {
+        self.abandoned.load(Ordering::SeqCst)
+    }
+
+    fn run<T: Send + 'static>(
+        &self,
+        op: &str,
+        key: &str,
+        timeout: Duration,
+        f: impl FnOnce(&S) -> Result<T, RvError> + Send + 'static,
+    ) -> Result<T, RvError> {
+        if timeout.is_zero() {
+            return f(&self.inner);
+        }
+
+        let timed_out = || RvError::ErrStorageTimeout(format!("op: {}, key: {}, timeout: {:?}", op, key, timeout));
+        if self.abandoned() >= self.config.max_abandoned {
+            return Err(timed_out());
+        }
+
+        // Whichever of the worker finishing and the caller giving up comes
+        // first claims the call, so an abandoned call is counted exactly until
+        // its worker returns.
+        let claimed = Arc::new(AtomicBool::new(false));
+        let (tx, rx) = mpsc::sync_channel(1);
+        let inner = Arc::clone(&self.inner);
+        let worker_claimed = Arc::clone(&claimed);
+        let abandoned = Arc::clone(&self.abandoned);
+        thread::Builder::new().name(format!("storage-{}", op)).spawn(move || {
+            let _ = tx.send(f(&inner));
+            if worker_claimed.swap(true, Ordering::SeqCst) {
+                abandoned.fetch_sub(1, Ordering::SeqCst);
+            }
+        })?;
+
+        match rx.recv_timeout(timeout) {
+            Ok(ret) => ret,
+            Err(RecvTimeoutError::Timeout) => {
+                self.abandoned.fetch_add(1, Ordering::SeqCst);
+                if claimed.swap(true, Ordering::SeqCst) {
+                    // The worker finished meanwhile, its result is in the channel
+                    self.abandoned.fetch_sub(1, Ordering::SeqCst);
+                    return rx.recv().unwrap_or_else(|_| Err(timed_out()));
+                }
+
+                log::warn!("storage operation timed out, op: {}, key: {}, timeout: {:?}", op, key, timeout);
+                Err(timed_out())
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(RvError::ErrString(format!("storage worker panicked, op: {}, key: {}", op, key)))
+            }
+        }
+    }
+}
+
+impl<S: Storage + 'static> Storage for TimeoutStorage<S> {
+    fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+        let owned = prefix.to_string();
+        self.run("list", prefix, self.config.list, move |s| s.list(&owned))
+    }
+
+    // The worker streams the names to the caller over a channel, and stops at
+    // the first one it can not hand over, once the callback stopped or the
+    // caller gave up.
+    fn list_stream(
+        &self,
+        prefix: &str,
+        callback: &mut dyn FnMut(&str) -> Result<bool, RvError>,
+    ) -> Result<(), RvError> {
+        let timeout = self.config.list;
+        if timeout.is_zero() {
+            return self.inner.list_stream(prefix, callback);
+        }
+
+        let timed_out =
+            || RvError::ErrStorageTimeout(format!("op: list_stream, key: {}, timeout: {:?}", prefix, timeout));
+        if self.abandoned() >= self.config.max_abandoned {
+            return Err(timed_out());
+        }
+
+        // Claimed like in run, the worker being done once it sent the result
+        // of the listing.
+        let claimed = Arc::new(AtomicBool::new(false));
+        let (tx, rx) = mpsc::sync_channel::<Result<Option<String>, RvError>>(LIST_STREAM_BUFFER);
+        let inner = Arc::clone(&self.inner);
+        let owned = prefix.to_string();
+        let worker_claimed = Arc::clone(&claimed);
+        let abandoned = Arc::clone(&self.abandoned);
+        thread::Builder::new().name("storage-list_stream".to_string()).spawn(move || {
+            let ret = inner.list_stream(&owned, &mut |name: &str| Ok(tx.send(Ok(Some(name.to_string()))).is_ok()));
+            let _ = tx.send(ret.map(|_| None));
+            if worker_claimed.swap(true, Ordering::SeqCst) {
+                abandoned.fetch_sub(1, Ordering::SeqCst);
+            }
+        })?;
+
+        loop {
+            match rx.recv_timeout(timeout) {
+                Ok(Ok(Some(name))) => {
+                    if !callback(&name)? {
+                        return Ok(());
+                    }
+                }
+                Ok(ret) => return ret.map(|_| ()),
+                Err(RecvTimeoutError::Timeout) => {
+                    self.abandoned.fetch_add(1, Ordering::SeqCst);
+                    if claimed.swap(true, Ordering::SeqCst) {
+                        // The worker finished meanwhile, the rest of the names are in the channel
+                        self.abandoned.fetch_sub(1, Ordering::SeqCst);
+                        continue;
+                    }
+
+                    log::warn!("storage operation timed out, op: list_stream, key: {}, timeout: {:?}", prefix, timeout);
+                    return Err(timed_out());
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(RvError::ErrString(format!(
+                        "storage worker panicked, op: list_stream, key: {}",
+                        prefix
+                    )));
+                }
+            }
+        }
+    }
+
+    fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+        let owned = key.to_string();
+        self.run("get", key, self.config.get, move |s| s.get(&owned))
+    }
+
+    fn get_consistent(&self, key: &str, consistency: ReadConsistency) -> Result<Option<StorageEntry>, RvError> {
+        let owned = key.to_string();
+        self.run("get", key, self.config.get, move |s| s.get_consistent(&owned, consistency))
+    }
+
+    fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+        let owned = entry.clone();
+        self.run("put", &entry.key, self.config.put, move |s| s.put(&owned))
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RvError> {
+        let owned = key.to_string();
+        self.run("delete", key, self.config.delete, move |s| s.delete(&owned))
+    }
+
+    fn delete_batch(&self, keys: &[String]) -> Result<(), RvError> {
+        let owned = keys.to_vec();
+        let first = keys.first().map(String::as_str).unwrap_or("");
+        self.run("delete_batch", first, self.config.delete, move |s| s.delete_batch(&owned))
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, RvError> {
+        let owned = key.to_string();
+        self.run("exists", key, self.config.exists, move |s| s.exists(&owned))
+    }
+
+    fn count(&self, prefix: &str) -> Result<usize, RvError> {
+        let owned = prefix.to_string();
+        self.run("count", prefix, self.config.count, move |s| s.count(&owned))
+    }
+
+    fn health(&self) -> Result<(), RvError> {
+        self.run("health", "", self.config.exists, |s| s.health())
+    }
+
+    // The calls to the snapshot are bounded like those to the storage, and
+    // the abandoned ones count towards the same limit.
+    fn snapshot_view(&self) -> Result<Arc<dyn Storage>, RvError> {
+        let snapshot = self.run("snapshot_view", "", self.config.get, |s| s.snapshot_view())?;
+        Ok(Arc::new(TimeoutStorage {
+            inner: Arc::new(snapshot),
+            config: self.config.clone(),
+            abandoned: Arc::clone(&self.abandoned),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::atomic::AtomicU64, time::Instant};
+
+    use super::{
+        super::{
+            physical::inmem::InmemBackend,
+            retry::test::MapStorage,
+            test_suite::{run_snapshot_conformance, SharedBackendStorage},
+        },
+        *,
+    };
+
+    /// A storage taking `delay_ms` before every call, standing for a hung network backend.
+    #[derive(Default)]
+    struct SlowStorage {
+        inner: MapStorage,
+        delay_ms: AtomicU64,
+        calls: AtomicUsize,
+    }
+
+    impl SlowStorage {
+        fn wait(&self) {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(self.delay_ms.load(Ordering::SeqCst)));
+        }
+    }
+
+    impl Storage for SlowStorage {
+        fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+            self.wait();
+            self.inner.list(prefix)
+        }
+
+        fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+            self.wait();
+            self.inner.get(key)
+        }
+
+        fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+            self.wait();
+            self.inner.put(entry)
+        }
+
+        fn delete(&self, key: &str) -> Result<(), RvError> {
+            self.wait();
+            self.inner.delete(key)
+        }
+    }
+
+    fn wait_until(cond: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !cond() {
+            assert!(Instant::now() < deadline, "condition not reached");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_timeout_storage() {
+        let config = TimeoutConfig { put: Duration::ZERO, ..TimeoutConfig::uniform(Duration::from_millis(50)) };
+        let storage = TimeoutStorage::new(SlowStorage::default(), config);
+
+        let entry = StorageEntry { key: "foo".to_string(), value: "test".as_bytes().to_vec() };
+        assert!(storage.put(&entry).is_ok());
+        assert_eq!(storage.get("foo").unwrap().unwrap(), entry);
+        assert_eq!(storage.list("").unwrap(), vec!["foo"]);
+
+        // A hung read times out without waiting for the backend
+        storage.inner().delay_ms.store(500, Ordering::SeqCst);
+        let start = Instant::now();
+        let ret = storage.get("foo");
+        assert!(matches!(ret, Err(RvError::ErrStorageTimeout(_))));
+        assert!(start.elapsed() < Duration::from_millis(400));
+        assert_eq!(storage.abandoned(), 1);
+
+        // The abandoned read changed nothing once it returns
+        wait_until(|| storage.abandoned() == 0);
+        storage.inner().delay_ms.store(0, Ordering::SeqCst);
+        assert_eq!(storage.get("foo").unwrap().unwrap(), entry);
+
+        // A zero timeout runs the call inline, however long it takes
+        storage.inner().delay_ms.store(100, Ordering::SeqCst);
+        let other = StorageEntry { key: "bar".to_string(), value: "test".as_bytes().to_vec() };
+        assert!(storage.put(&other).is_ok());
+        assert_eq!(storage.abandoned(), 0);
+        storage.inner().delay_ms.store(0, Ordering::SeqCst);
+        assert_eq!(storage.get("bar").unwrap().unwrap(), other);
+    }
+
+    #[test]
+    fn test_timeout_storage_max_abandoned() {
+        let config = TimeoutConfig { max_abandoned: 1, ..TimeoutConfig::uniform(Duration::from_millis(20)) };
+        let storage = TimeoutStorage::new(SlowStorage::default(), config);
+
+        storage.inner().delay_ms.store(300, Ordering::SeqCst);
+        assert!(matches!(storage.exists("foo"), Err(RvError::ErrStorageTimeout(_))));
+        assert_eq!(storage.abandoned(), 1);
+
+        // The backend is taken as hung, the next call does not reach it
+        assert!(matches!(storage.get("foo"), Err(RvError::ErrStorageTimeout(_))));
+        assert_eq!(storage.inner().calls.load(Ordering::SeqCst), 1);
+
+        // Calls go through again once the abandoned one returns
+        wait_until(|| storage.abandoned() == 0);
+        storage.inner().delay_ms.store(0, Ordering::SeqCst);
+        assert!(storage.get("foo").unwrap().is_none());
+        assert_eq!(storage.inner().calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_timeout_storage_list_stream() {
+        let storage = TimeoutStorage::new(SlowStorage::default(), TimeoutConfig::uniform(Duration::from_millis(50)));
+        for key in ["a", "b", "c"] {
+            assert!(storage.put(&StorageEntry { key: key.to_string(), value: Vec::new() }).is_ok());
+        }
+
+        let mut names = Vec::new();
+        let ret = storage.list_stream("", &mut |name: &str| {
+            names.push(name.to_string());
+            Ok(names.len() < 2)
+        });
+        assert!(ret.is_ok());
+        assert_eq!(names, vec!["a", "b"]);
+
+        // A hung listing times out like any other call
+        storage.inner().delay_ms.store(500, Ordering::SeqCst);
+        let start = Instant::now();
+        let ret = storage.list_stream("", &mut |_: &str| Ok(true));
+        assert!(matches!(ret, Err(RvError::ErrStorageTimeout(_))));
+        assert!(start.elapsed() < Duration::from_millis(400));
+        assert_eq!(storage.abandoned(), 1);
+        wait_until(|| storage.abandoned() == 0);
+    }
+
+    #[test]
+    fn test_timeout_storage_snapshot() {
+        let storage = TimeoutStorage::new(SlowStorage::default(), TimeoutConfig::default());
+        assert_eq!(storage.snapshot_view().err(), Some(RvError::ErrStorageSnapshotUnsupported));
+
+        let backend = Arc::new(InmemBackend::new());
+        run_snapshot_conformance(&TimeoutStorage::new(SharedBackendStorage(backend), TimeoutConfig::default()));
+    }
+}
@@ -0,0 +1,265 @@
+//! A minimal write-ahead log that gives multi-key operations crash consistency on storages
+//! without transactions.
+//!
+//! A `WalGuard` writes an intent record under `WAL_PREFIX` when it is created, and adds every key
+//! to that record before writing it. Committing the guard removes the record. If the process dies
+//! in between, or the guard is dropped without being committed, the record stays behind and
+//! `recover` later deletes the keys it lists, rolling the operation back.
+//!
+//! Rolling back deletes keys, so the guard is only meant for operations that create new keys.
+
+use std::{
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{ReadConsistency, Storage, StorageEntry};
+use crate::{
+    errors::RvError,
+    utils::{deserialize_system_time, generate_uuid, serialize_system_time},
+};
+
+pub const WAL_PREFIX: &str = "wal/";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct WalRecord {
+    #[serde(serialize_with = "serialize_system_time", deserialize_with = "deserialize_system_time")]
+    created_time: SystemTime,
+    keys: Vec<String>,
+}
+
+/// Wraps a storage for the duration of a multi-key operation. Writes go through the guard, which
+/// implements `Storage` itself, so existing helpers taking a `&dyn Storage` can be reused as is.
+pub struct WalGuard<'a> {
+    storage: &'a dyn Storage,
+    record_key: String,
+    record: RwLock<WalRecord>,
+    committed: bool,
+}
+
+impl<'a> WalGuard<'a> {
+    pub fn begin(storage: &'a dyn Storage, now: SystemTime) -> Result<Self, RvError> {
+        let record_key = format!("{}{}", WAL_PREFIX, generate_uuid());
+        let record = WalRecord { created_time: now, keys: Vec::new() };
+        storage.put(&StorageEntry::new(&record_key, &record)?)?;
+
+        Ok(Self { storage, record_key, record: RwLock::new(record), committed: false })
+    }
+
+    /// Marks the operation as complete by removing its intent record. If the record can not be
+    /// removed, the operation is rolled back right away rather than by a later `recover`, so that
+    /// it is not undone after its caller got the error and moved on. What a failed rollback leaves
+    /// behind is still rolled back by `recover`.
+    pub fn commit(mut self) -> Result<(), RvError> {
+        let err = match self.storage.delete(&self.record_key) {
+            Ok(()) => {
+                self.committed = true;
+                return Ok(());
+            }
+            Err(err) => err,
+        };
+
+        log::warn!("failed to commit write-ahead log record {}, rolling it back, err: {}", self.record_key, err);
+        let keys = self.record.read()?.keys.clone();
+        for key in keys.iter() {
+            self.storage.delete(key)?;
+        }
+        self.storage.delete(&self.record_key)?;
+        self.committed = true;
+
+        Err(err)
+    }
+}
+
+impl Drop for WalGuard<'_> {
+    fn drop(&mut self) {
+        if !self.committed {
+            log::warn!("write-ahead log record {} was not committed, it will be rolled back", self.record_key);
+        }
+    }
+}
+
+impl Storage for WalGuard<'_> {
+    fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+        self.storage.list(prefix)
+    }
+
+    fn list_stream(
+        &self,
+        prefix: &str,
+        callback: &mut dyn FnMut(&str) -> Result<bool, RvError>,
+    ) -> Result<(), RvError> {
+        self.storage.list_stream(prefix, callback)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+        self.storage.get(key)
+    }
+
+    fn get_consistent(&self, key: &str, consistency: ReadConsistency) -> Result<Option<StorageEntry>, RvError> {
+        self.storage.get_consistent(key, consistency)
+    }
+
+    fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+        {
+            let mut record = self.record.write()?;
+            if !record.keys.contains(&entry.key) {
+                record.keys.push(entry.key.clone());
+                self.storage.put(&StorageEntry::new(&self.record_key, &*record)?)?;
+            }
+        }
+
+        self.storage.put(entry)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), RvError> {
+        self.storage.delete(key)
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, RvError> {
+        self.storage.exists(key)
+    }
+
+    fn count(&self, prefix: &str) -> Result<usize, RvError> {
+        self.storage.count(prefix)
+    }
+
+    // A snapshot is read-only, nothing written through it needs an intent record.
+    fn snapshot_view(&self) -> Result<Arc<dyn Storage>, RvError> {
+        self.storage.snapshot_view()
+    }
+}
+
+/// Rolls back the operations whose intent records are at least `min_age` old, by deleting the keys
+/// they wrote. Younger records may belong to operations still in flight and are left alone.
+/// Returns the number of operations rolled back.
+pub fn recover(storage: &dyn Storage, now: SystemTime, min_age: Duration) -> Result<usize, RvError> {
+    let mut rolled_back = 0;
+
+    for id in storage.list(WAL_PREFIX)? {
+        let record_key = format!("{}{}", WAL_PREFIX, id);
+        let Some(entry) = storage.get(&record_key)? else {
+            continue;
+        };
+
+        let record: WalRecord = match serde_json::from_slice(&entry.value) {
+            Ok(record) => record,
+            Err(err) => {
+                log::error!("invalid write-ahead log record {}, err: {}", record_key, err);
+                continue;
+            }
+        };
+
+        if now < record.created_time + min_age {
+            continue;
+        }
+
+        for key in record.keys.iter() {
+            storage.delete(key)?;
+        }
+        storage.delete(&record_key)?;
+
+        log::info!("rolled back write-ahead log record {}, keys: {:?}", record_key, record.keys);
+        rolled_back += 1;
+    }
+
+    Ok(rolled_back)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::test_utils::test_rusty_vault_init;
+
+    #[test]
+    fn test_wal_guard() {
+        let (_root_token, core) = test_rusty_vault_init("test_wal_guard");
+        let core = core.read().unwrap();
+        let storage: Arc<dyn Storage> = core.get_system_view().unwrap();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        let entry1 = StorageEntry { key: "foo/1".to_string(), value: "test1".as_bytes().to_vec() };
+        let entry2 = StorageEntry { key: "foo/2".to_string(), value: "test2".as_bytes().to_vec() };
+
+        // A committed operation leaves no record behind
+        let wal = WalGuard::begin(storage.as_ref(), now).unwrap();
+        assert!(wal.put(&entry1).is_ok());
+        assert!(wal.commit().is_ok());
+        assert!(storage.list(WAL_PREFIX).unwrap().is_empty());
+        assert_eq!(recover(storage.as_ref(), now, Duration::ZERO).unwrap(), 0);
+        assert!(storage.exists("foo/1").unwrap());
+
+        // An uncommitted one is rolled back once its record is old enough
+        let wal = WalGuard::begin(storage.as_ref(), now).unwrap();
+        assert!(wal.put(&entry2).is_ok());
+        drop(wal);
+        assert_eq!(storage.list(WAL_PREFIX).unwrap().len(), 1);
+
+        assert_eq!(recover(storage.as_ref(), now, Duration::from_secs(60)).unwrap(), 0);
+        assert!(storage.exists("foo/2").unwrap());
+
+        assert_eq!(recover(storage.as_ref(), now + Duration::from_secs(60), Duration::from_secs(60)).unwrap(), 1);
+        assert!(!storage.exists("foo/2").unwrap());
+        assert!(storage.exists("foo/1").unwrap());
+        assert!(storage.list(WAL_PREFIX).unwrap().is_empty());
+    }
+
+    // FailingRecordDelete fails the first deletes of the write-ahead log records.
+    struct FailingRecordDelete {
+        inner: Arc<dyn Storage>,
+        failures: RwLock<usize>,
+    }
+
+    impl Storage for FailingRecordDelete {
+        fn list(&self, prefix: &str) -> Result<Vec<String>, RvError> {
+            self.inner.list(prefix)
+        }
+
+        fn get(&self, key: &str) -> Result<Option<StorageEntry>, RvError> {
+            self.inner.get(key)
+        }
+
+        fn put(&self, entry: &StorageEntry) -> Result<(), RvError> {
+            self.inner.put(entry)
+        }
+
+        fn delete(&self, key: &str) -> Result<(), RvError> {
+            let mut failures = self.failures.write()?;
+            if key.starts_with(WAL_PREFIX) && *failures > 0 {
+                *failures -= 1;
+                return Err(RvError::ErrString("record delete failed".to_string()));
+            }
+            self.inner.delete(key)
+        }
+    }
+
+    #[test]
+    fn test_wal_guard_commit_failure() {
+        let (_root_token, core) = test_rusty_vault_init("test_wal_guard_commit_failure");
+        let core = core.read().unwrap();
+        let inner: Arc<dyn Storage> = core.get_system_view().unwrap();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let entry = StorageEntry { key: "foo/1".to_string(), value: "test1".as_bytes().to_vec() };
+
+        // A failed commit rolls the operation back before reporting the error
+        let storage = FailingRecordDelete { inner: Arc::clone(&inner), failures: RwLock::new(1) };
+        let wal = WalGuard::begin(&storage, now).unwrap();
+        assert!(wal.put(&entry).is_ok());
+        assert!(wal.commit().is_err());
+        assert!(!inner.exists("foo/1").unwrap());
+        assert!(inner.list(WAL_PREFIX).unwrap().is_empty());
+
+        // If the rollback fails as well, recover finishes it
+        let storage = FailingRecordDelete { inner: Arc::clone(&inner), failures: RwLock::new(2) };
+        let wal = WalGuard::begin(&storage, now).unwrap();
+        assert!(wal.put(&entry).is_ok());
+        assert!(wal.commit().is_err());
+        assert!(!inner.exists("foo/1").unwrap());
+        assert_eq!(recover(inner.as_ref(), now, Duration::ZERO).unwrap(), 1);
+        assert!(inner.list(WAL_PREFIX).unwrap().is_empty());
+    }
+}
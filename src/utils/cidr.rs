@@ -3,6 +3,7 @@
 
 use std::{
     collections::HashSet,
+    fmt,
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
     str::FromStr,
 };
@@ -12,6 +13,56 @@ use ipnetwork::IpNetwork;
 use super::sock_addr::{new_sock_addr, SockAddr, SockAddrType};
 use crate::errors::RvError;
 
+/// A parsed and validated CIDR block. The address is normalized to the network address on
+/// parsing, so `10.0.0.5/24` and `10.0.0.0/24` are the same block. A bare address is a block of
+/// a single address. Parse the strings once with `from_str` and reuse the blocks, rather than
+/// going through the string based helpers of this module repeatedly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cidr(IpNetwork);
+
+impl Cidr {
+    fn normalize(ipnet: IpNetwork) -> Result<Self, RvError> {
+        Ok(Cidr(IpNetwork::new(ipnet.network(), ipnet.prefix())?))
+    }
+
+    pub fn network(&self) -> IpAddr {
+        self.0.ip()
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.0.prefix()
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.0.contains(*ip)
+    }
+
+    /// Returns whether every address of this block also belongs to `other`. Blocks of different
+    /// address families are never subsets of each other.
+    pub fn is_subset_of(&self, other: &Cidr) -> bool {
+        other.prefix() <= self.prefix() && other.contains(&self.network())
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = RvError;
+
+    fn from_str(cidr: &str) -> Result<Self, Self::Err> {
+        Cidr::normalize(IpNetwork::from_str(cidr)?)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Parses every block of `cidrs`, failing on the first invalid one.
+pub fn parse_cidrs(cidrs: &[&str]) -> Result<Vec<Cidr>, RvError> {
+    cidrs.iter().map(|cidr| Cidr::from_str(cidr)).collect()
+}
+
 pub fn is_ip_addr(addr: &dyn SockAddr) -> bool {
     (addr.sock_addr_type() as u8 & SockAddrType::IP as u8) != 0
 }
@@ -38,9 +89,7 @@ pub fn ip_belongs_to_cidr(ip_addr: &str, cidr: &str) -> Result<bool, RvError> {
     }
 
     let ip = IpAddr::from_str(ip_addr)?;
-    let ipnet = IpNetwork::from_str(cidr)?;
-
-    Ok(ipnet.contains(ip))
+    Ok(Cidr::from_str(cidr)?.contains(&ip))
 }
 
 pub fn ip_belongs_to_cidrs(ip_addr: &str, cidrs: &[&str]) -> Result<bool, RvError> {
@@ -52,8 +101,9 @@ pub fn ip_belongs_to_cidrs(ip_addr: &str, cidrs: &[&str]) -> Result<bool, RvErro
         return Err(RvError::ErrResponse("missing CIDR blocks to be checked against".to_string()));
     }
 
+    let ip = IpAddr::from_str(ip_addr)?;
     for cidr in cidrs.iter() {
-        if ip_belongs_to_cidr(ip_addr, cidr)? {
+        if Cidr::from_str(cidr)?.contains(&ip) {
             return Ok(true);
         }
     }
@@ -83,9 +133,7 @@ pub fn validate_cidrs(cidrs: &[&str]) -> Result<bool, RvError> {
         return Err(RvError::ErrResponse("missing CIDR blocks that needs validation".to_string()));
     }
 
-    for cidr in cidrs.iter() {
-        let _ = IpNetwork::from_str(cidr)?;
-    }
+    parse_cidrs(cidrs)?;
 
     Ok(true)
 }
@@ -99,31 +147,10 @@ pub fn subset(cidr1: &str, cidr2: &str) -> Result<bool, RvError> {
         return Err(RvError::ErrResponse("missing CIDR that needs to be checked".to_string()));
     }
 
-    let ipnet1 = IpNetwork::from_str(cidr1)?;
-    let mask_len1 = ipnet1.prefix();
-
-    if !is_ip_addr_zero(&ipnet1.ip()) && mask_len1 == 0 {
-        return Err(RvError::ErrResponse("CIDR to be checked against is not in its canonical form".to_string()));
-    }
-
-    let ipnet2 = IpNetwork::from_str(cidr2)?;
-    let mask_len2 = ipnet2.prefix();
+    let cidr1 = parse_canonical(cidr1, "CIDR to be checked against")?;
+    let cidr2 = parse_canonical(cidr2, "CIDR that needs to be checked")?;
 
-    if !is_ip_addr_zero(&ipnet2.ip()) && mask_len2 == 0 {
-        return Err(RvError::ErrResponse("CIDR that needs to be checked is not in its canonical form".to_string()));
-    }
-
-    /*
-     * If the mask length of the CIDR that needs to be checked is smaller
-     * then the mask length of the CIDR to be checked against, then the
-     * former will encompass more IPs than the latter, and hence can't be a
-     * subset of the latter.
-     */
-    if mask_len2 < mask_len1 {
-        return Ok(false);
-    }
-
-    Ok(ipnet1.contains(ipnet2.ip()))
+    Ok(cidr2.is_subset_of(&cidr1))
 }
 
 /*
@@ -141,23 +168,31 @@ pub fn subset_blocks(cidr_blocks1: &[&str], cidr_blocks2: &[&str]) -> Result<boo
         return Err(RvError::ErrResponse("missing CIDR blocks that needs to be checked".to_string()));
     }
 
+    // Each block is parsed once, not once per pair
+    let cidrs1 = cidr_blocks1
+        .iter()
+        .map(|cidr| parse_canonical(cidr, "CIDR to be checked against"))
+        .collect::<Result<Vec<Cidr>, RvError>>()?;
+    let cidrs2 = cidr_blocks2
+        .iter()
+        .map(|cidr| parse_canonical(cidr, "CIDR that needs to be checked"))
+        .collect::<Result<Vec<Cidr>, RvError>>()?;
+
     // Check if all the elements of cidr_blocks2 is a subset of at least one
     // element of cidr_blocks1
-    for cidr_block2 in cidr_blocks2.iter() {
-        let mut is_subset = false;
-        for cidr_block1 in cidr_blocks1.iter() {
-            if subset(cidr_block1, cidr_block2)? {
-                is_subset = true;
-                break;
-            }
-        }
+    Ok(cidrs2.iter().all(|cidr2| cidrs1.iter().any(|cidr1| cidr2.is_subset_of(cidr1))))
+}
 
-        if !is_subset {
-            return Ok(false);
-        }
+// parse_canonical parses a CIDR block for a subset check, rejecting a non-zero
+// address with a zero mask length, which would otherwise silently turn into
+// the block of all addresses.
+fn parse_canonical(cidr: &str, what: &str) -> Result<Cidr, RvError> {
+    let ipnet = IpNetwork::from_str(cidr)?;
+    if !is_ip_addr_zero(&ipnet.ip()) && ipnet.prefix() == 0 {
+        return Err(RvError::ErrResponse(format!("{} is not in its canonical form", what)));
     }
 
-    Ok(true)
+    Cidr::normalize(ipnet)
 }
 
 fn is_ip_addr_zero(ip_addr: &IpAddr) -> bool {
@@ -293,6 +328,53 @@ mod test {
         assert!(!ret.unwrap());
     }
 
+    #[test]
+    fn test_cidr_normalization() {
+        let cidr = Cidr::from_str("10.0.0.5/24").unwrap();
+        assert_eq!(cidr.network(), IpAddr::from_str("10.0.0.0").unwrap());
+        assert_eq!(cidr.prefix(), 24);
+        assert_eq!(cidr.to_string(), "10.0.0.0/24");
+        assert_eq!(cidr, Cidr::from_str("10.0.0.0/24").unwrap());
+
+        // A bare address is a single address block
+        let cidr = Cidr::from_str("10.0.0.5").unwrap();
+        assert_eq!(cidr.to_string(), "10.0.0.5/32");
+        let cidr = Cidr::from_str("2001:db8::1/32").unwrap();
+        assert_eq!(cidr.to_string(), "2001:db8::/32");
+
+        assert!(Cidr::from_str("10.0.0.0.0/24").is_err());
+        assert!(Cidr::from_str("10.0.0.0/33").is_err());
+        assert!(parse_cidrs(&["10.0.0.0/8", "192.168.0.0/16"]).is_ok());
+        assert!(parse_cidrs(&["10.0.0.0/8", "bad"]).is_err());
+    }
+
+    #[test]
+    fn test_cidr_contains_and_subset() {
+        let cidr = Cidr::from_str("10.0.0.5/24").unwrap();
+        assert!(cidr.contains(&IpAddr::from_str("10.0.0.200").unwrap()));
+        assert!(!cidr.contains(&IpAddr::from_str("10.0.1.1").unwrap()));
+        assert!(!cidr.contains(&IpAddr::from_str("::1").unwrap()));
+
+        let wide = Cidr::from_str("10.0.0.0/16").unwrap();
+        let narrow = Cidr::from_str("10.0.0.128/25").unwrap();
+        let other = Cidr::from_str("10.1.0.0/24").unwrap();
+        assert!(narrow.is_subset_of(&cidr));
+        assert!(cidr.is_subset_of(&wide));
+        assert!(narrow.is_subset_of(&wide));
+        assert!(cidr.is_subset_of(&cidr));
+        assert!(!wide.is_subset_of(&cidr));
+        assert!(!other.is_subset_of(&cidr));
+
+        // Different address families
+        let v6 = Cidr::from_str("::/0").unwrap();
+        assert!(!cidr.is_subset_of(&v6));
+        assert!(Cidr::from_str("2001:db8::/48").unwrap().is_subset_of(&v6));
+
+        // The subset helpers still reject non-canonical zero masks
+        assert!(subset("10.0.0.1/0", "10.0.0.0/24").is_err());
+        assert!(subset("0.0.0.0/0", "10.0.0.0/24").unwrap());
+    }
+
     #[test]
     fn test_cidr_remote_addr_is_ok() {
         let addr = new_sock_addr("127.0.0.1/8");
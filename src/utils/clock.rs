@@ -0,0 +1,71 @@
+//! A small time source abstraction. Code that makes decisions based on the current time (TTLs,
+//! expiration, tidy) should ask a `Clock` for it instead of calling `SystemTime::now()` directly,
+//! so that tests can control the passage of time.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The real wall clock.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when told to. Intended for tests.
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<SystemTime>,
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+}
+
+impl MockClock {
+    pub fn new(start: SystemTime) -> Self {
+        Self { now: Mutex::new(start) }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now += duration;
+    }
+
+    pub fn set(&self, time: SystemTime) {
+        let mut now = self.now.lock().unwrap();
+        *now = time;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mock_clock() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let clock = MockClock::new(start);
+        assert_eq!(clock.now(), start);
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(clock.now(), start + Duration::from_secs(60));
+
+        clock.set(start);
+        assert_eq!(clock.now(), start);
+
+        let system = SystemClock;
+        assert!(system.now() > start);
+    }
+}
@@ -1,6 +1,86 @@
 use blake2b_simd::Params;
+use openssl::hash::MessageDigest;
+#[cfg(feature = "fips")]
+use openssl::nid::Nid;
+use openssl::{pkey::PKey, sign::Signer};
+
+use crate::errors::RvError;
+
+const SHA256_LEN: usize = 32;
 
 pub fn blake2b256_hash(key: &str) -> Vec<u8> {
     let hash = Params::new().hash_length(32).to_state().update(key.as_bytes()).finalize();
     hash.as_bytes().to_vec()
 }
+
+/// HKDF with SHA-256, as specified by RFC 5869. An empty `salt` stands for a string of zeros as
+/// long as the digest, and at most 255 digests worth of output keying material can be derived.
+pub fn hkdf_sha256(ikm: &[u8], salt: &[u8], info: &[u8], len: usize) -> Result<Vec<u8>, RvError> {
+    if len > 255 * SHA256_LEN {
+        return Err(RvError::ErrResponse(format!("cannot derive more than {} bytes", 255 * SHA256_LEN)));
+    }
+
+    let zeros = [0u8; SHA256_LEN];
+    let salt = if salt.is_empty() { &zeros[..] } else { salt };
+    let prk = hmac_sha256(salt, &[ikm])?;
+
+    let mut okm = Vec::with_capacity(len);
+    let mut block = Vec::new();
+    for counter in 1..=len.div_ceil(SHA256_LEN) as u8 {
+        block = hmac_sha256(&prk, &[&block, info, &[counter]])?;
+        okm.extend_from_slice(&block);
+    }
+    okm.truncate(len);
+
+    Ok(okm)
+}
+
+fn hmac_sha256(key: &[u8], data: &[&[u8]]) -> Result<Vec<u8>, RvError> {
+    let pkey = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &pkey)?;
+    for part in data.iter() {
+        signer.update(part)?;
+    }
+    Ok(signer.sign_to_vec()?)
+}
+
+/// With the `fips` feature, fails with `ErrAlgorithmNotPermitted` unless `digest` is of the SHA-2
+/// family. Without the feature, every digest is permitted.
+#[cfg(feature = "fips")]
+pub fn check_digest_permitted(digest: MessageDigest) -> Result<(), RvError> {
+    let nid = digest.type_();
+    if [Nid::SHA224, Nid::SHA256, Nid::SHA384, Nid::SHA512].contains(&nid) {
+        return Ok(());
+    }
+
+    Err(RvError::ErrAlgorithmNotPermitted(nid.short_name().unwrap_or("unknown").to_string()))
+}
+
+#[cfg(not(feature = "fips"))]
+pub fn check_digest_permitted(_digest: MessageDigest) -> Result<(), RvError> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hkdf_sha256() {
+        // Test cases 1 and 3 of RFC 5869
+        let ikm = [0x0b; 22];
+        let salt = hex::decode("000102030405060708090a0b0c").unwrap();
+        let info = hex::decode("f0f1f2f3f4f5f6f7f8f9").unwrap();
+        assert_eq!(
+            hex::encode(hkdf_sha256(&ikm, &salt, &info, 42).unwrap()),
+            "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf34007208d5b887185865"
+        );
+        assert_eq!(
+            hex::encode(hkdf_sha256(&ikm, &[], &[], 42).unwrap()),
+            "8da4e775a563c18f715f802a063c5a31b8a11f5c5ee1879ec3454e5f3c738d2d9d201395faa4b61a96c8"
+        );
+
+        assert_eq!(hkdf_sha256(&ikm, &[], &[], 255 * 32).unwrap().len(), 255 * 32);
+        assert!(hkdf_sha256(&ikm, &[], &[], 255 * 32 + 1).is_err());
+    }
+}
@@ -1,15 +1,49 @@
 //! This module is a Rust replica of
 //! https://github.com/hashicorp/vault/blob/main/sdk/helper/locksutil/locks.go
-
-use std::sync::{Arc, RwLock};
+//!
+//! A lock set can be given a level with `Locks::with_level`. Locks acquired through
+//! `LockEntry::read` and `LockEntry::write` then take part in a lock ordering: a thread may only
+//! acquire a lock while every ordered lock it already holds has a lower level. Since the locks of
+//! a set are shared by hashing, two locks of the same level are never held together either, as
+//! both keys may map to the same lock, except through `Locks::write_all`, which takes them in a
+//! fixed order. Violations panic in debug builds, before blocking, so a potential deadlock surfaces
+//! in tests instead of hanging them. Release builds do not track anything.
+//!
+//! A lock whose holder panicked is not left poisoned. The locks of a set guard no data of their
+//! own, only the order of writes to the storage indices hashed to them, and a write cut short is
+//! no worse for the next holder than a crash of the process would be. So acquiring a poisoned lock
+//! logs a warning, clears the poison and hands out the guard, instead of failing every later
+//! operation on the keys hashed to it.
+
+use std::{
+    ops::Deref,
+    sync::{Arc, LockResult, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError},
+};
 
 use super::crypto::blake2b256_hash;
+use crate::errors::RvError;
 
 static LOCK_COUNT: usize = 256;
 
+#[cfg(debug_assertions)]
+thread_local! {
+    // The levels of the ordered locks held by the current thread
+    static HELD_LEVELS: std::cell::RefCell<Vec<u32>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
 #[derive(Debug)]
 pub struct LockEntry {
     pub lock: RwLock<u8>,
+    // Zero means the lock is not ordered
+    pub level: u32,
+}
+
+/// The guard of a lock acquired through `LockEntry::read` or `LockEntry::write`. Dropping it
+/// releases the lock.
+pub struct LockGuard<G> {
+    guard: G,
+    #[cfg(debug_assertions)]
+    level: u32,
 }
 
 #[derive(Debug)]
@@ -17,21 +51,151 @@ pub struct Locks {
     pub locks: Vec<Arc<LockEntry>>,
 }
 
+impl LockEntry {
+    pub fn read(&self) -> Result<LockGuard<RwLockReadGuard<'_, u8>>, RvError> {
+        self.check_order();
+        Ok(self.guard(self.recover(self.lock.read())))
+    }
+
+    pub fn write(&self) -> Result<LockGuard<RwLockWriteGuard<'_, u8>>, RvError> {
+        self.check_order();
+        Ok(self.guard(self.recover(self.lock.write())))
+    }
+
+    /// Like `write`, also telling whether the lock was held by someone else when it was requested.
+    pub fn write_contended(&self) -> Result<(LockGuard<RwLockWriteGuard<'_, u8>>, bool), RvError> {
+        self.check_order();
+        let (guard, contended) = match self.lock.try_write() {
+            Ok(guard) => (guard, false),
+            Err(TryLockError::WouldBlock) => (self.recover(self.lock.write()), true),
+            Err(TryLockError::Poisoned(err)) => (self.recover(Err(err)), false),
+        };
+        Ok((self.guard(guard), contended))
+    }
+
+    fn guard<G>(&self, guard: G) -> LockGuard<G> {
+        LockGuard {
+            guard,
+            #[cfg(debug_assertions)]
+            level: self.level,
+        }
+    }
+
+    // recover returns the guard of a lock acquisition, clearing the poison a
+    // panicking holder left on the lock, see the module documentation.
+    fn recover<G>(&self, ret: LockResult<G>) -> G {
+        ret.unwrap_or_else(|err| {
+            log::warn!("recovering a lock poisoned by a panicking holder, level: {}", self.level);
+            self.lock.clear_poison();
+            err.into_inner()
+        })
+    }
+
+    #[cfg(debug_assertions)]
+    fn check_order(&self) {
+        if self.level == 0 {
+            return;
+        }
+
+        HELD_LEVELS.with(|held| {
+            let mut held = held.borrow_mut();
+            if let Some(highest) = held.iter().max() {
+                assert!(
+                    *highest < self.level,
+                    "lock ordering violated: acquiring a level {} lock while holding a level {} lock",
+                    self.level,
+                    highest
+                );
+            }
+            held.push(self.level);
+        });
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn check_order(&self) {}
+}
+
+#[cfg(debug_assertions)]
+fn release_level(level: u32) {
+    if level == 0 {
+        return;
+    }
+
+    HELD_LEVELS.with(|held| {
+        let mut held = held.borrow_mut();
+        if let Some(pos) = held.iter().rposition(|l| *l == level) {
+            held.remove(pos);
+        }
+    });
+}
+
+impl<G> Deref for LockGuard<G> {
+    type Target = G;
+
+    fn deref(&self) -> &G {
+        &self.guard
+    }
+}
+
+#[cfg(debug_assertions)]
+impl<G> Drop for LockGuard<G> {
+    fn drop(&mut self) {
+        release_level(self.level);
+    }
+}
+
 impl Locks {
     pub fn new() -> Self {
+        Self::with_level(0)
+    }
+
+    // with_level creates a lock set whose locks are ordered at the given level,
+    // see the module documentation.
+    pub fn with_level(level: u32) -> Self {
         let mut locks = Self { locks: Vec::with_capacity(LOCK_COUNT) };
 
         for _ in 0..LOCK_COUNT {
-            locks.locks.push(Arc::new(LockEntry { lock: RwLock::new(0) }));
+            locks.locks.push(Arc::new(LockEntry { lock: RwLock::new(0), level }));
         }
 
         locks
     }
 
     pub fn get_lock(&self, key: &str) -> Arc<LockEntry> {
-        let index: usize = blake2b256_hash(key)[0].into();
-        Arc::clone(&self.locks[index])
+        Arc::clone(&self.locks[lock_index(key)])
     }
+
+    /// Write-locks every lock the keys map to, each once, in the order of the set. Holders of a
+    /// single lock of the set never wait for a second one, so taking several in that order cannot
+    /// deadlock with them. The locks are released when the returned guards are dropped.
+    pub fn write_all(&self, keys: &[&str]) -> Result<Vec<LockGuard<RwLockWriteGuard<'_, u8>>>, RvError> {
+        let mut indexes: Vec<usize> = keys.iter().map(|key| lock_index(key)).collect();
+        indexes.sort_unstable();
+        indexes.dedup();
+
+        let mut guards = Vec::with_capacity(indexes.len());
+        for (i, index) in indexes.into_iter().enumerate() {
+            let entry = &self.locks[index];
+            if i == 0 {
+                guards.push(entry.write()?);
+            } else {
+                // The level is tracked once for the whole set, by the first guard
+                guards.push(LockGuard {
+                    guard: entry.recover(entry.lock.write()),
+                    #[cfg(debug_assertions)]
+                    level: 0,
+                });
+            }
+        }
+
+        // The guards drop in order, the first one has to be released last
+        guards.reverse();
+        Ok(guards)
+    }
+}
+
+fn lock_index(key: &str) -> usize {
+    blake2b256_hash(key)[0].into()
 }
 
 #[cfg(test)]
@@ -140,6 +304,117 @@ mod test {
         assert_eq!(*data.num.read().unwrap(), 44);
     }
 
+    #[test]
+    fn test_locks_write_contended() {
+        let locks = Locks::new();
+        let lock_entry = locks.get_lock("test");
+
+        let (guard, contended) = lock_entry.write_contended().unwrap();
+        assert!(!contended);
+
+        let waiter_entry = Arc::clone(&lock_entry);
+        let waiter = thread::spawn(move || waiter_entry.write_contended().map(|(_, contended)| contended).unwrap());
+        sleep(Duration::from_millis(100));
+        drop(guard);
+        assert!(waiter.join().unwrap());
+    }
+
+    #[test]
+    fn test_locks_ordering() {
+        let outer = Locks::with_level(1);
+        let inner = Locks::with_level(2);
+        let unordered = Locks::new();
+
+        {
+            let _outer_locked = outer.get_lock("test").write().unwrap();
+            let _inner_locked = inner.get_lock("test").read().unwrap();
+            let _locked = unordered.get_lock("test").write().unwrap();
+        }
+
+        // Released levels no longer constrain the thread
+        let inner_locked = inner.get_lock("test").write().unwrap();
+        drop(inner_locked);
+        let _outer_locked = outer.get_lock("test").write().unwrap();
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "lock ordering violated")]
+    fn test_locks_ordering_violation() {
+        let outer = Locks::with_level(1);
+        let inner = Locks::with_level(2);
+
+        let _inner_locked = inner.get_lock("test").read().unwrap();
+        let _outer_locked = outer.get_lock("test").read().unwrap();
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "lock ordering violated")]
+    fn test_locks_ordering_same_level() {
+        let locks = Locks::with_level(1);
+
+        // "foo" and "bar" may share a lock, so holding both is never allowed
+        let _foo_locked = locks.get_lock("foo").read().unwrap();
+        let _bar_locked = locks.get_lock("bar").read().unwrap();
+    }
+
+    #[test]
+    fn test_locks_write_all() {
+        let locks = Arc::new(Locks::with_level(1));
+        let keys: Vec<String> = (0..64).map(|i| format!("key-{}", i)).collect();
+        let key_refs: Vec<&str> = keys.iter().map(String::as_str).collect();
+
+        let guards = locks.write_all(&key_refs).unwrap();
+        assert!(guards.len() <= keys.len());
+        for key in key_refs.iter() {
+            assert!(locks.get_lock(key).lock.try_read().is_err());
+        }
+
+        // A holder of a single lock waits for the whole set to be released
+        let waiter_locks = Arc::clone(&locks);
+        let waiter = thread::spawn(move || waiter_locks.get_lock("key-7").write().map(|_| ()).is_ok());
+        sleep(Duration::from_millis(100));
+        assert!(!waiter.is_finished());
+        drop(guards);
+        assert!(waiter.join().unwrap());
+
+        // The level is released along with the set
+        let _locked = locks.get_lock("key-0").write().unwrap();
+    }
+
+    #[test]
+    fn test_locks_poison_recovery() {
+        let locks = Arc::new(Locks::with_level(1));
+
+        // Each way of taking a lock recovers it once its holder panicked
+        let poison = |key: &'static str| {
+            let poisoner_locks = Arc::clone(&locks);
+            let poisoner = thread::spawn(move || {
+                let _locked = poisoner_locks.get_lock(key).write().unwrap();
+                panic!("panicking while holding the lock");
+            });
+            assert!(poisoner.join().is_err());
+            assert!(locks.get_lock(key).lock.is_poisoned());
+        };
+
+        poison("read");
+        assert!(locks.get_lock("read").read().is_ok());
+        assert!(!locks.get_lock("read").lock.is_poisoned());
+
+        poison("write");
+        assert!(locks.get_lock("write").write().is_ok());
+
+        poison("contended");
+        let (locked, contended) = locks.get_lock("contended").write_contended().unwrap();
+        assert!(!contended);
+        drop(locked);
+
+        poison("all");
+        assert!(locks.write_all(&["all", "other"]).is_ok());
+        assert!(!locks.get_lock("all").lock.is_poisoned());
+    }
+
     #[test]
     fn test_locks_reader_reader() {
         let data = Arc::new(MyTestData { lock: Locks::new(), num: RwLock::new(11) });
@@ -0,0 +1,288 @@
+//! A small bounded map with least-recently-used eviction.
+//!
+//! Unlike the `stretto` caches used by the policy store, inserts are applied synchronously and
+//! never dropped by an admission policy, which makes it suitable for bookkeeping state (counters,
+//! memoized values) that must be read back exactly as it was written. It is not thread-safe on its
+//! own, wrap it in a `Mutex` when sharing, or use `ShardedLruCache` for a cache read far more often
+//! than it is written.
+
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hash},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        PoisonError, RwLock,
+    },
+};
+
+use priority_queue::PriorityQueue;
+
+#[derive(Debug)]
+pub struct LruCache<K: Hash + Eq, V> {
+    capacity: usize,
+    entries: HashMap<K, V>,
+    order: PriorityQueue<K, Reverse<u64>>,
+    tick: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> LruCache<K, V> {
+    /// Creates a cache holding at most `capacity` entries. A capacity of zero is treated as one.
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity: capacity.max(1), entries: HashMap::new(), order: PriorityQueue::new(), tick: 0 }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Changes the capacity, evicting the least recently used entries if the cache shrinks.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+        self.evict();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Checks for a key without marking it as recently used.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        if self.entries.contains_key(key) {
+            self.touch(key);
+        }
+        self.entries.get(key)
+    }
+
+    /// Reads a value without marking it as recently used, which only needs a shared reference.
+    pub fn peek(&self, key: &K) -> Option<&V> {
+        self.entries.get(key)
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        if self.entries.contains_key(key) {
+            self.touch(key);
+        }
+        self.entries.get_mut(key)
+    }
+
+    /// Inserts or replaces a value, returning the previous one.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.tick += 1;
+        if let Some(old) = self.entries.insert(key.clone(), value) {
+            self.order.change_priority(&key, Reverse(self.tick));
+            return Some(old);
+        }
+
+        self.order.push(key, Reverse(self.tick));
+        self.evict();
+        None
+    }
+
+    /// Inserts a value under a new key like `insert`, but when the cache is full only evicts the
+    /// least recently used entry for which `evictable` holds. If there is none, the cache is left
+    /// unchanged and the value is handed back.
+    pub fn try_insert_evicting(&mut self, key: K, value: V, evictable: impl Fn(&V) -> bool) -> Result<(), V> {
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            let victim = self
+                .order
+                .iter()
+                .filter(|(key, _)| self.entries.get(*key).is_some_and(&evictable))
+                .max_by_key(|(_, priority)| **priority)
+                .map(|(key, _)| key.clone());
+            match victim {
+                Some(victim) => {
+                    self.remove(&victim);
+                }
+                None => return Err(value),
+            }
+        }
+
+        self.insert(key, value);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.order.remove(key);
+        self.entries.remove(key)
+    }
+
+    pub fn clear(&mut self) {
+        self.order.clear();
+        self.entries.clear();
+    }
+
+    fn touch(&mut self, key: &K) {
+        self.tick += 1;
+        self.order.change_priority(key, Reverse(self.tick));
+    }
+
+    fn evict(&mut self) {
+        while self.entries.len() > self.capacity {
+            match self.order.pop() {
+                Some((key, _)) => {
+                    self.entries.remove(&key);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+// The most shards a ShardedLruCache is split in
+const MAX_SHARDS: usize = 16;
+
+/// A thread-safe `LruCache` split in shards, each behind its own `RwLock`. A hit only takes the
+/// read lock of its shard and does not mark the entry as recently used, so a shard evicts its
+/// entries in the order they were inserted. The capacity is spread evenly over the shards, and the
+/// cache may hold up to one entry per shard over it.
+#[derive(Debug)]
+pub struct ShardedLruCache<K: Hash + Eq, V> {
+    shards: Vec<RwLock<LruCache<K, V>>>,
+    // The number of shards in use, at most the capacity, so that a small cache stays exact
+    active: AtomicUsize,
+    hasher: RandomState,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> ShardedLruCache<K, V> {
+    /// Creates a cache holding about `capacity` entries. A capacity of zero is treated as one.
+    pub fn new(capacity: usize) -> Self {
+        let cache = Self {
+            shards: (0..MAX_SHARDS).map(|_| RwLock::new(LruCache::new(1))).collect(),
+            active: AtomicUsize::new(1),
+            hasher: RandomState::new(),
+        };
+        cache.set_capacity(capacity);
+        cache
+    }
+
+    /// Changes the capacity, evicting the least recently inserted entries if the cache shrinks. The
+    /// entries of the shards going out of use are dropped.
+    pub fn set_capacity(&self, capacity: usize) {
+        let capacity = capacity.max(1);
+        let active = capacity.min(MAX_SHARDS);
+        for (i, shard) in self.shards.iter().enumerate() {
+            let mut shard = shard.write().unwrap_or_else(PoisonError::into_inner);
+            shard.set_capacity(capacity.div_ceil(active));
+            if i >= active {
+                shard.clear();
+            }
+        }
+        self.active.store(active, Ordering::Release);
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().unwrap_or_else(PoisonError::into_inner).len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.shard(key).read().unwrap_or_else(PoisonError::into_inner).peek(key).cloned()
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        self.shard(&key).write().unwrap_or_else(PoisonError::into_inner).insert(key, value);
+    }
+
+    fn shard(&self, key: &K) -> &RwLock<LruCache<K, V>> {
+        let active = self.active.load(Ordering::Acquire);
+        &self.shards[self.hasher.hash_one(key) as usize % active]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lru_cache_eviction() {
+        let mut cache: LruCache<String, i32> = LruCache::new(2);
+        assert!(cache.is_empty());
+
+        cache.insert("a".to_string(), 1);
+        cache.insert("b".to_string(), 2);
+        assert_eq!(cache.len(), 2);
+
+        // Touching "a" makes "b" the least recently used entry
+        assert_eq!(cache.get(&"a".to_string()), Some(&1));
+        cache.insert("c".to_string(), 3);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.contains_key(&"a".to_string()));
+        assert!(!cache.contains_key(&"b".to_string()));
+        assert!(cache.contains_key(&"c".to_string()));
+
+        // Replacing a value does not evict anything
+        assert_eq!(cache.insert("c".to_string(), 4), Some(3));
+        assert_eq!(cache.len(), 2);
+
+        *cache.get_mut(&"a".to_string()).unwrap() += 10;
+        assert_eq!(cache.get(&"a".to_string()), Some(&11));
+
+        cache.set_capacity(1);
+        assert_eq!(cache.len(), 1);
+        assert!(cache.contains_key(&"a".to_string()));
+
+        assert_eq!(cache.remove(&"a".to_string()), Some(11));
+        assert!(cache.is_empty());
+
+        cache.insert("d".to_string(), 5);
+        cache.clear();
+        assert!(cache.is_empty());
+
+        // Only the entries passing the predicate are evicted to make room
+        let mut cache: LruCache<String, i32> = LruCache::new(2);
+        cache.insert("a".to_string(), 1);
+        cache.insert("b".to_string(), 2);
+        assert_eq!(cache.try_insert_evicting("c".to_string(), 3, |value| *value != 1), Ok(()));
+        assert!(cache.contains_key(&"a".to_string()));
+        assert!(!cache.contains_key(&"b".to_string()));
+        assert_eq!(cache.try_insert_evicting("d".to_string(), 4, |value| *value > 3), Err(4));
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.contains_key(&"d".to_string()));
+        assert_eq!(cache.try_insert_evicting("a".to_string(), 5, |_| false), Ok(()));
+        assert_eq!(cache.get(&"a".to_string()), Some(&5));
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(LruCache::<String, i32>::new(0).capacity(), 1);
+    }
+
+    #[test]
+    fn test_sharded_lru_cache() {
+        let cache: ShardedLruCache<String, i32> = ShardedLruCache::new(2);
+        assert!(cache.is_empty());
+
+        cache.insert("a".to_string(), 1);
+        assert_eq!(cache.get(&"a".to_string()), Some(1));
+        assert_eq!(cache.get(&"b".to_string()), None);
+
+        // A small cache holds no more than its capacity
+        for (i, key) in ["b", "c", "d", "e"].iter().enumerate() {
+            cache.insert(key.to_string(), i as i32);
+        }
+        assert!(cache.len() <= 2);
+
+        // A large one spreads its entries over the shards
+        cache.set_capacity(1024);
+        for i in 0..1024 {
+            cache.insert(i.to_string(), i);
+        }
+        assert!(cache.len() > 1024 - MAX_SHARDS * 16);
+        assert!(cache.len() <= 1024 + MAX_SHARDS);
+        assert_eq!(cache.get(&"1023".to_string()), Some(1023));
+
+        cache.set_capacity(1);
+        assert!(cache.len() <= 1);
+        assert_eq!(ShardedLruCache::<String, i32>::new(0).len(), 0);
+    }
+}
@@ -4,51 +4,36 @@
 use std::time::{Duration, SystemTime};
 
 use chrono::prelude::*;
-use humantime::{format_rfc3339, parse_duration, parse_rfc3339};
+use humantime::parse_duration;
 use openssl::hash::{Hasher, MessageDigest};
-use rand::{thread_rng, Rng};
 use serde::{Deserialize, Deserializer, Serializer};
+use unicode_normalization::UnicodeNormalization;
 
 use crate::errors::RvError;
 
 pub mod cert;
 pub mod cidr;
+pub mod clock;
 pub mod crypto;
+pub mod entropy;
 pub mod ip_sock_addr;
 pub mod key;
 pub mod kv_builder;
 pub mod locks;
+pub mod lru;
 pub mod ocsp;
 pub mod policy;
 pub mod salt;
 pub mod sock_addr;
 pub mod string;
 pub mod token_util;
+pub mod ttl;
 pub mod unix_sock_addr;
 
+// generate_uuid returns a random UUID formatted identifier drawn from the OS CSPRNG. Code that
+// should honor an injected `entropy::EntropySource` calls `entropy::generate_uuid_with` instead.
 pub fn generate_uuid() -> String {
-    let mut buf = [0u8; 16];
-    thread_rng().fill(&mut buf);
-
-    format!(
-        "{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
-        buf[0],
-        buf[1],
-        buf[2],
-        buf[3],
-        buf[4],
-        buf[5],
-        buf[6],
-        buf[7],
-        buf[8],
-        buf[9],
-        buf[10],
-        buf[11],
-        buf[12],
-        buf[13],
-        buf[14],
-        buf[15]
-    )
+    entropy::generate_uuid_with(&entropy::OsEntropy).expect("the OS random number generator failed")
 }
 
 pub fn is_str_subset<T: PartialEq>(sub: &Vec<T>, superset: &Vec<T>) -> bool {
@@ -62,11 +47,40 @@ pub fn sha1(data: &[u8]) -> String {
     hex::encode(result)
 }
 
+// format_system_time formats a time as RFC 3339 in UTC, keeping the nanoseconds if there are any.
+// Times before the epoch are supported, but RFC 3339 can only represent the years 0000 to 9999.
+pub fn format_system_time(time: SystemTime) -> Result<String, RvError> {
+    let (secs, nanos) = match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(since) => (i64::try_from(since.as_secs()).ok(), since.subsec_nanos()),
+        Err(err) => {
+            let before = err.duration();
+            let secs = i64::try_from(before.as_secs()).ok().map(|secs| -secs);
+            match before.subsec_nanos() {
+                0 => (secs, 0),
+                nanos => (secs.and_then(|secs| secs.checked_sub(1)), 1_000_000_000 - nanos),
+            }
+        }
+    };
+
+    let datetime = secs
+        .and_then(|secs| DateTime::<Utc>::from_timestamp(secs, nanos))
+        .filter(|datetime| (0..=9999).contains(&datetime.year()))
+        .ok_or_else(|| RvError::ErrString(format!("time {:?} is out of the RFC 3339 range", time)))?;
+
+    Ok(datetime.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+}
+
+pub fn parse_system_time(value: &str) -> Result<SystemTime, RvError> {
+    let datetime = DateTime::parse_from_rfc3339(value)
+        .map_err(|e| RvError::ErrString(format!("invalid time \"{}\": {}", value, e)))?;
+    Ok(SystemTime::from(datetime))
+}
+
 pub fn serialize_system_time<S>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
 {
-    let formatted = format_rfc3339(*time).to_string();
+    let formatted = format_system_time(*time).map_err(serde::ser::Error::custom)?;
     serializer.serialize_str(&formatted)
 }
 
@@ -75,17 +89,62 @@ where
     D: Deserializer<'de>,
 {
     let input: &str = Deserialize::deserialize(deserializer)?;
-    let parsed_time = parse_rfc3339(input).map_err(serde::de::Error::custom)?;
-    let system_time: SystemTime = parsed_time;
-    Ok(system_time)
+    parse_system_time(input).map_err(serde::de::Error::custom)
+}
+
+pub fn serialize_option_system_time<S>(time: &Option<SystemTime>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match time {
+        Some(time) => serialize_system_time(time, serializer),
+        None => serializer.serialize_none(),
+    }
 }
 
+pub fn deserialize_option_system_time<'de, D>(deserializer: D) -> Result<Option<SystemTime>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let input: Option<&str> = Deserialize::deserialize(deserializer)?;
+    input.map(parse_system_time).transpose().map_err(serde::de::Error::custom)
+}
+
+// serialize_duration stores whole seconds, any fraction of a second is dropped.
 pub fn serialize_duration<S>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: serde::Serializer,
 {
-    let timestamp = duration.as_secs();
-    serializer.serialize_i64(timestamp as i64)
+    serializer.serialize_u64(duration.as_secs())
+}
+
+// serialize_duration_str emits the compact human-readable form (e.g. "1h 30m") instead of raw seconds.
+// It can be used in place of serialize_duration where the stored value is meant to be read by operators.
+pub fn serialize_duration_str<S>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    let formatted = humantime::format_duration(Duration::from_secs(duration.as_secs())).to_string();
+    serializer.serialize_str(&formatted)
+}
+
+// parse_duration_str accepts either an integer number of seconds ("3600") or a Go-style duration
+// string with unit suffixes ("30s", "90m", "24h", "7d", "1h30m").
+pub fn parse_duration_str(value: &str) -> Result<Duration, RvError> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Err(RvError::ErrString("invalid duration: empty string".to_string()));
+    }
+
+    // Plain seconds, with or without the "s" suffix, are parsed here so that the whole u64 range round trips
+    let digits = value.strip_suffix('s').unwrap_or(value);
+    if !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) {
+        let secs =
+            digits.parse::<u64>().map_err(|e| RvError::ErrString(format!("invalid duration \"{}\": {}", value, e)))?;
+        return Ok(Duration::from_secs(secs));
+    }
+
+    parse_duration(value).map_err(|e| RvError::ErrString(format!("invalid duration \"{}\": {}", value, e)))
 }
 
 pub fn deserialize_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
@@ -98,7 +157,7 @@ where
         type Value = Duration;
 
         fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-            formatter.write_str("a number or a string with 's' suffix")
+            formatter.write_str("a number of seconds or a duration string such as \"30m\" or \"24h\"")
         }
 
         fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E>
@@ -108,17 +167,51 @@ where
             Ok(Duration::from_secs(value))
         }
 
+        fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            if value < 0 {
+                return Err(serde::de::Error::custom(format!("invalid duration {}: must not be negative", value)));
+            }
+            Ok(Duration::from_secs(value as u64))
+        }
+
         fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
         where
             E: serde::de::Error,
         {
-            parse_duration(value).map_err(serde::de::Error::custom)
+            parse_duration_str(value).map_err(serde::de::Error::custom)
         }
     }
 
     deserializer.deserialize_any(DurationVisitor)
 }
 
+// duration_secs and duration_str are meant for `#[serde(with = "...")]`, and pick the form a Duration
+// field is written in. Both read either form, so a field can change its form without migrating the
+// entries already stored.
+
+// duration_secs writes whole seconds as a number, like serialize_duration.
+pub mod duration_secs {
+    pub use super::{deserialize_duration as deserialize, serialize_duration as serialize};
+}
+
+// duration_str writes whole seconds as a string with an "s" suffix, such as "3600s", the form Vault
+// exports durations in.
+pub mod duration_str {
+    use std::time::Duration;
+
+    pub use super::deserialize_duration as deserialize;
+
+    pub fn serialize<S>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&format!("{}s", duration.as_secs()))
+    }
+}
+
 pub fn asn1time_to_timestamp(time_str: &str) -> Result<i64, RvError> {
     // Parse the time string
     let dt = NaiveDateTime::parse_from_str(time_str, "%b %e %H:%M:%S %Y %Z")?;
@@ -153,6 +246,226 @@ pub fn is_protect_path(protected: &[&str], paths: &[&str]) -> bool {
     false
 }
 
+// validate_storage_key rejects keys that could escape the prefix they were built under in some
+// backends: empty keys, leading or trailing slashes, `..` segments and control characters.
+pub fn validate_storage_key(key: &str) -> Result<(), RvError> {
+    if key.is_empty() {
+        return Err(RvError::ErrStorageKeyInvalid("the key is empty".to_string()));
+    }
+
+    if let Some((i, c)) = key.char_indices().find(|(_, c)| c.is_control()) {
+        return Err(RvError::ErrStorageKeyInvalid(format!(
+            "control character {} at byte {} in key {:?}",
+            c.escape_unicode(),
+            i,
+            key
+        )));
+    }
+
+    if key.starts_with('/') || key.ends_with('/') {
+        return Err(RvError::ErrStorageKeyInvalid(format!("leading or trailing '/' in key {:?}", key)));
+    }
+
+    if key.split('/').any(|segment| segment == "..") {
+        return Err(RvError::ErrStorageKeyInvalid(format!("'..' path segment in key {:?}", key)));
+    }
+
+    Ok(())
+}
+
+// normalize_role_name brings a role name to Unicode normalization form C, so that canonically
+// equivalent spellings, such as a composed and a decomposed "café", name the same role. Role names
+// are hashed and stored, and so compared, in this form. Empty or blank names and names containing
+// control characters are rejected.
+pub fn normalize_role_name(name: &str) -> Result<String, RvError> {
+    if name.trim().is_empty() {
+        return Err(RvError::ErrResponse("role name cannot be empty".to_string()));
+    }
+
+    if name.chars().any(char::is_control) {
+        return Err(RvError::ErrResponse("role name cannot contain control characters".to_string()));
+    }
+
+    Ok(name.nfc().collect())
+}
+
 pub fn default_system_time() -> SystemTime {
     SystemTime::UNIX_EPOCH
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct DurationHolder {
+        #[serde(serialize_with = "serialize_duration", deserialize_with = "deserialize_duration")]
+        ttl: Duration,
+    }
+
+    #[test]
+    fn test_deserialize_duration() {
+        let holder: DurationHolder = serde_json::from_str(r#"{"ttl": "24h"}"#).unwrap();
+        assert_eq!(holder.ttl, Duration::from_secs(24 * 3600));
+
+        let holder: DurationHolder = serde_json::from_str(r#"{"ttl": "90m"}"#).unwrap();
+        assert_eq!(holder.ttl, Duration::from_secs(90 * 60));
+
+        let holder: DurationHolder = serde_json::from_str(r#"{"ttl": "3600"}"#).unwrap();
+        assert_eq!(holder.ttl, Duration::from_secs(3600));
+
+        let holder: DurationHolder = serde_json::from_str(r#"{"ttl": 3600}"#).unwrap();
+        assert_eq!(holder.ttl, Duration::from_secs(3600));
+
+        let holder: DurationHolder = serde_json::from_str(r#"{"ttl": "2d"}"#).unwrap();
+        assert_eq!(holder.ttl, Duration::from_secs(2 * 86400));
+
+        let ret = serde_json::from_str::<DurationHolder>(r#"{"ttl": "10x"}"#);
+        assert!(ret.is_err());
+        assert!(ret.unwrap_err().to_string().contains("10x"));
+
+        assert!(serde_json::from_str::<DurationHolder>(r#"{"ttl": -1}"#).is_err());
+        assert!(serde_json::from_str::<DurationHolder>(r#"{"ttl": ""}"#).is_err());
+
+        // Serialization stays numeric
+        let holder = DurationHolder { ttl: Duration::from_secs(7200) };
+        assert_eq!(serde_json::to_string(&holder).unwrap(), r#"{"ttl":7200}"#);
+    }
+
+    #[test]
+    fn test_validate_storage_key() {
+        assert!(validate_storage_key("role/foo").is_ok());
+        assert!(validate_storage_key("secret_id/abc/def").is_ok());
+        assert!(validate_storage_key("role/foo..bar").is_ok());
+        assert!(validate_storage_key("role/.../x").is_ok());
+
+        // Traversal attempts
+        assert!(validate_storage_key("").is_err());
+        assert!(validate_storage_key("..").is_err());
+        assert!(validate_storage_key("role/../core/mounts").is_err());
+        assert!(validate_storage_key("role/foo/..").is_err());
+        assert!(validate_storage_key("/etc/passwd").is_err());
+        assert!(validate_storage_key("role/foo/").is_err());
+
+        // Control characters are reported
+        let err = validate_storage_key("role/foo\0bar").unwrap_err();
+        assert_eq!(
+            err,
+            RvError::ErrStorageKeyInvalid(r#"control character \u{0} at byte 8 in key "role/foo\0bar""#.to_string())
+        );
+        assert!(validate_storage_key("role/foo\nbar").is_err());
+        assert!(validate_storage_key("role/\u{7f}").is_err());
+        assert!(validate_storage_key("role/\u{85}").is_err());
+
+        // Unicode that is not a control character is fine, including look-alikes of '.' and '/'
+        assert!(validate_storage_key("role/café/ü").is_ok());
+        assert!(validate_storage_key("role/\u{ff0e}\u{ff0e}").is_ok());
+        assert!(validate_storage_key("role/\u{2215}etc").is_ok());
+        assert!(validate_storage_key("role/🦀").is_ok());
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct TimeHolder {
+        #[serde(serialize_with = "serialize_system_time", deserialize_with = "deserialize_system_time")]
+        time: SystemTime,
+    }
+
+    #[test]
+    fn test_normalize_role_name() {
+        let composed = "caf\u{e9}";
+        let decomposed = "cafe\u{301}";
+        assert_ne!(composed, decomposed);
+        assert_eq!(normalize_role_name(composed).unwrap(), composed);
+        assert_eq!(normalize_role_name(decomposed).unwrap(), composed);
+        assert_eq!(normalize_role_name("role1").unwrap(), "role1");
+
+        assert!(normalize_role_name("").is_err());
+        assert!(normalize_role_name(" \t ").is_err());
+        assert!(normalize_role_name("role\n1").is_err());
+        assert!(normalize_role_name("role\u{7f}").is_err());
+    }
+
+    #[test]
+    fn test_system_time_round_trip() {
+        let round_trip = |time: SystemTime| -> Result<SystemTime, String> {
+            let serialized = serde_json::to_string(&TimeHolder { time }).map_err(|e| e.to_string())?;
+            Ok(serde_json::from_str::<TimeHolder>(&serialized).map_err(|e| e.to_string())?.time)
+        };
+
+        let epoch = SystemTime::UNIX_EPOCH;
+        for time in [
+            epoch,
+            epoch + Duration::new(1_700_000_000, 123_456_789),
+            epoch + Duration::new(0, 1),
+            epoch - Duration::new(0, 1),
+            epoch - Duration::from_secs(86400),
+            epoch - Duration::new(1, 500_000_000),
+            // 0000-01-01T00:00:00Z and 9999-12-31T23:59:59.999999999Z
+            epoch - Duration::from_secs(62_167_219_200),
+            epoch + Duration::new(253_402_300_799, 999_999_999),
+        ] {
+            assert_eq!(round_trip(time), Ok(time));
+        }
+
+        // Values written before the nanoseconds were kept still parse
+        let holder: TimeHolder = serde_json::from_str(r#"{"time": "2024-01-01T00:00:00Z"}"#).unwrap();
+        assert_eq!(holder.time, epoch + Duration::from_secs(1_704_067_200));
+        assert_eq!(format_system_time(holder.time).unwrap(), "2024-01-01T00:00:00Z");
+        assert_eq!(format_system_time(epoch - Duration::from_secs(1)).unwrap(), "1969-12-31T23:59:59Z");
+
+        // Out of the RFC 3339 range
+        assert!(round_trip(epoch + Duration::from_secs(253_402_300_800)).is_err());
+        assert!(round_trip(epoch - Duration::from_secs(62_167_219_201)).is_err());
+        assert!(serde_json::from_str::<TimeHolder>(r#"{"time": "yesterday"}"#).is_err());
+    }
+
+    #[test]
+    fn test_duration_round_trip() {
+        for secs in [0, 1, i64::MAX as u64, i64::MAX as u64 + 1, u64::MAX] {
+            let holder = DurationHolder { ttl: Duration::from_secs(secs) };
+            let serialized = serde_json::to_string(&holder).unwrap();
+            let holder: DurationHolder = serde_json::from_str(&serialized).unwrap();
+            assert_eq!(holder.ttl, Duration::from_secs(secs));
+        }
+
+        // Fractions of a second are dropped
+        let holder = DurationHolder { ttl: Duration::new(5, 999_999_999) };
+        assert_eq!(serde_json::to_string(&holder).unwrap(), r#"{"ttl":5}"#);
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct EncodedDurations {
+        #[serde(with = "duration_secs")]
+        secs: Duration,
+        #[serde(with = "duration_str")]
+        string: Duration,
+    }
+
+    #[test]
+    fn test_duration_encodings() {
+        // Either form is read into the same Duration, whatever the field writes
+        for input in [r#"{"secs": 3600, "string": 3600}"#, r#"{"secs": "3600s", "string": "3600s"}"#] {
+            let holder: EncodedDurations = serde_json::from_str(input).unwrap();
+            assert_eq!(holder.secs, Duration::from_secs(3600));
+            assert_eq!(holder.string, Duration::from_secs(3600));
+        }
+        let holder: EncodedDurations = serde_json::from_str(r#"{"secs": "1h", "string": "60m"}"#).unwrap();
+        assert_eq!((holder.secs, holder.string), (Duration::from_secs(3600), Duration::from_secs(3600)));
+
+        // Each field is written in its own form
+        let holder = EncodedDurations { secs: Duration::from_secs(3600), string: Duration::new(3600, 500) };
+        assert_eq!(serde_json::to_string(&holder).unwrap(), r#"{"secs":3600,"string":"3600s"}"#);
+
+        for secs in [0, 1, i64::MAX as u64 + 1, u64::MAX] {
+            let holder = EncodedDurations { secs: Duration::from_secs(secs), string: Duration::from_secs(secs) };
+            let holder: EncodedDurations = serde_json::from_str(&serde_json::to_string(&holder).unwrap()).unwrap();
+            assert_eq!((holder.secs, holder.string), (Duration::from_secs(secs), Duration::from_secs(secs)));
+        }
+
+        assert!(serde_json::from_str::<EncodedDurations>(r#"{"secs": 1, "string": "s"}"#).is_err());
+    }
+}
@@ -10,7 +10,7 @@ use openssl::{
     sign::Signer,
 };
 
-use super::generate_uuid;
+use super::{crypto::check_digest_permitted, generate_uuid};
 use crate::{
     errors::RvError,
     storage::{Storage, StorageEntry},
@@ -57,6 +57,9 @@ impl Salt {
             }
         }
 
+        check_digest_permitted(salt.config.hash_type)?;
+        check_digest_permitted(salt.config.hmac_type)?;
+
         if let Some(s) = storage {
             if let Some(raw) = s.get(&salt.config.location)? {
                 salt.salt = String::from_utf8_lossy(&raw.value).to_string();
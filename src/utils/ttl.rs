@@ -0,0 +1,117 @@
+//! `LeaseTtl` is a lease TTL whose unit is explicit at the type level.
+//!
+//! A bare `Duration` or integer says nothing about the unit it was built from, and a TTL given in
+//! milliseconds where seconds are meant goes unnoticed until a lease expires a thousand times too
+//! late. A `LeaseTtl` can only be built from seconds or from a human readable duration string such
+//! as `"24h"`, and is stored as whole seconds, in the same form as `serialize_duration`. Stored
+//! entries can therefore switch a `Duration` field to a `LeaseTtl` without being migrated.
+
+use std::{fmt, time::Duration};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::{deserialize_duration, parse_duration_str};
+use crate::errors::RvError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct LeaseTtl(Duration);
+
+impl LeaseTtl {
+    pub const ZERO: LeaseTtl = LeaseTtl(Duration::ZERO);
+    pub const MAX: LeaseTtl = LeaseTtl(Duration::MAX);
+
+    pub const fn from_secs(secs: u64) -> Self {
+        LeaseTtl(Duration::from_secs(secs))
+    }
+
+    /// Parses either a number of seconds (`"3600"`) or a duration with unit suffixes (`"90m"`,
+    /// `"24h"`, `"1h30m"`).
+    pub fn from_human(value: &str) -> Result<Self, RvError> {
+        parse_duration_str(value).map(LeaseTtl)
+    }
+
+    pub const fn as_duration(&self) -> Duration {
+        self.0
+    }
+
+    pub const fn as_secs(&self) -> u64 {
+        self.0.as_secs()
+    }
+
+    pub const fn is_zero(&self) -> bool {
+        self.0.is_zero()
+    }
+
+    /// Whether this TTL is longer than `limit`.
+    pub fn exceeds(&self, limit: LeaseTtl) -> bool {
+        *self > limit
+    }
+
+    /// This TTL, capped to `limit`.
+    pub fn capped_to(self, limit: LeaseTtl) -> LeaseTtl {
+        self.min(limit)
+    }
+}
+
+impl From<Duration> for LeaseTtl {
+    fn from(duration: Duration) -> Self {
+        LeaseTtl(duration)
+    }
+}
+
+impl From<LeaseTtl> for Duration {
+    fn from(ttl: LeaseTtl) -> Self {
+        ttl.0
+    }
+}
+
+impl fmt::Display for LeaseTtl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}s", self.as_secs())
+    }
+}
+
+// Any fraction of a second is dropped, like serialize_duration does.
+impl Serialize for LeaseTtl {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.as_secs())
+    }
+}
+
+impl<'de> Deserialize<'de> for LeaseTtl {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_duration(deserializer).map(LeaseTtl)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_lease_ttl_units() {
+        assert_eq!(LeaseTtl::from_human("24h").unwrap(), LeaseTtl::from_secs(86400));
+        assert_eq!(LeaseTtl::from_human("1h30m").unwrap(), LeaseTtl::from_secs(5400));
+        assert_eq!(LeaseTtl::from_human("600").unwrap(), LeaseTtl::from_secs(600));
+        assert_eq!(LeaseTtl::from_human("600s").unwrap().as_duration(), Duration::from_secs(600));
+        assert!(LeaseTtl::from_human("").is_err());
+        assert!(LeaseTtl::from_human("24x").is_err());
+
+        assert!(LeaseTtl::from_secs(3601).exceeds(LeaseTtl::from_human("1h").unwrap()));
+        assert!(!LeaseTtl::from_secs(3600).exceeds(LeaseTtl::from_human("1h").unwrap()));
+        assert_eq!(LeaseTtl::MAX.capped_to(LeaseTtl::from_secs(60)), LeaseTtl::from_secs(60));
+        assert_eq!(LeaseTtl::from_secs(30).capped_to(LeaseTtl::from_secs(60)), LeaseTtl::from_secs(30));
+        assert!(LeaseTtl::default().is_zero());
+        assert_eq!(LeaseTtl::from_secs(90).to_string(), "90s");
+    }
+
+    #[test]
+    fn test_lease_ttl_serde() {
+        assert_eq!(serde_json::to_value(LeaseTtl::from_human("24h").unwrap()).unwrap(), json!(86400));
+        assert_eq!(serde_json::from_value::<LeaseTtl>(json!(86400)).unwrap(), LeaseTtl::from_secs(86400));
+        assert_eq!(serde_json::from_value::<LeaseTtl>(json!("24h")).unwrap(), LeaseTtl::from_secs(86400));
+        assert!(serde_json::from_value::<LeaseTtl>(json!(-1)).is_err());
+    }
+}